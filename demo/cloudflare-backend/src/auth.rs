@@ -0,0 +1,76 @@
+use worker::*;
+
+/// the secret which admin requests (e.g. draining a document ahead of a
+/// deploy) must carry as a bearer token. admin requests are refused while
+/// it isn't set
+pub const ADMIN_TOKEN_SECRET: &str = "SQLSYNC_ADMIN_TOKEN";
+
+/// the secret which requests for a document's contents (its websocket and
+/// its snapshot) must carry, either as a bearer token or, since browsers
+/// can't set headers on websockets, as the token query parameter. the demo
+/// is open to anyone while it isn't set
+pub const AUTH_TOKEN_SECRET: &str = "SQLSYNC_AUTH_TOKEN";
+
+/// returns an error response unless req is authorized by the auth token
+pub fn require_doc_access(
+    req: &Request,
+    ctx: &RouteContext<()>,
+) -> Option<Response> {
+    let Ok(expected) = ctx.secret(AUTH_TOKEN_SECRET) else {
+        return None;
+    };
+    match request_token(req) {
+        Some(token) if constant_time_eq(&token, &expected.to_string()) => None,
+        Some(_) => Some(forbidden()),
+        None => Some(unauthorized()),
+    }
+}
+
+/// returns an error response unless req is authorized by the admin token
+pub fn require_admin(
+    req: &Request,
+    ctx: &RouteContext<()>,
+) -> Option<Response> {
+    let Ok(expected) = ctx.secret(ADMIN_TOKEN_SECRET) else {
+        return Some(forbidden());
+    };
+    match bearer_token(req) {
+        Some(token) if constant_time_eq(&token, &expected.to_string()) => None,
+        Some(_) => Some(forbidden()),
+        None => Some(unauthorized()),
+    }
+}
+
+/// the token a request carries, as a bearer token or the token query
+/// parameter
+pub fn request_token(req: &Request) -> Option<String> {
+    bearer_token(req).or_else(|| query_token(req))
+}
+
+fn bearer_token(req: &Request) -> Option<String> {
+    let header = req.headers().get("Authorization").ok()??;
+    header.strip_prefix("Bearer ").map(str::to_owned)
+}
+
+fn query_token(req: &Request) -> Option<String> {
+    let url = req.url().ok()?;
+    let token = url.query_pairs().find(|(key, _)| key == "token")?;
+    Some(token.1.into_owned())
+}
+
+/// compare tokens without leaking how much of them matched
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn unauthorized() -> Response {
+    Response::error("Unauthorized", 401).expect("valid status")
+}
+
+fn forbidden() -> Response {
+    Response::error("Forbidden", 403).expect("valid status")
+}
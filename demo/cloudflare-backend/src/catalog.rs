@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use js_sys::Uint8Array;
+use serde::Serialize;
+use sqlsync::{
+    catalog::{Catalog, CatalogEntry, CatalogPage, CatalogQuery},
+    sqlite::Connection,
+    JournalId,
+};
+use wasm_bindgen::JsValue;
+use worker::*;
+
+pub const CATALOG_OBJECT_NAME: &str = "CATALOG";
+
+/// entries are persisted one per key, so recording a document only writes
+/// its own entry
+const ENTRY_PREFIX: &str = "doc:";
+
+/// a CatalogEntry as the admin api returns it, with ids in base58
+#[derive(Serialize)]
+struct EntryJson {
+    doc_id: String,
+    metadata: BTreeMap<String, String>,
+    database_bytes: u64,
+    journal_bytes: u64,
+    last_lsn: Option<u64>,
+    modified_ms: i64,
+    reducer_digest: Option<String>,
+}
+
+impl From<CatalogEntry> for EntryJson {
+    fn from(entry: CatalogEntry) -> Self {
+        Self {
+            doc_id: entry.doc_id.to_base58(),
+            metadata: entry.metadata,
+            database_bytes: entry.database_bytes,
+            journal_bytes: entry.journal_bytes,
+            last_lsn: entry.last_lsn,
+            modified_ms: entry.modified_ms,
+            reducer_digest: entry
+                .reducer_digest
+                .map(|d| bs58::encode(d).into_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PageJson {
+    entries: Vec<EntryJson>,
+    next_cursor: Option<String>,
+}
+
+impl From<CatalogPage> for PageJson {
+    fn from(page: CatalogPage) -> Self {
+        Self {
+            entries: page.entries.into_iter().map(Into::into).collect(),
+            next_cursor: page.next_cursor.map(|id| id.to_base58()),
+        }
+    }
+}
+
+/// send a coordinator's entry to the deployment's DocumentCatalog
+pub async fn record_entry(
+    namespace: &ObjectNamespace,
+    entry: &CatalogEntry,
+) -> Result<()> {
+    let body = bincode::serialize(entry)
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from(Uint8Array::from(&body[..]))));
+    let req = Request::new_with_init("https://catalog/record", &init)?;
+    let stub = namespace.id_from_name("catalog")?.get_stub()?;
+    let resp = stub.fetch_with_request(req).await?;
+    if resp.status_code() >= 400 {
+        return Err(Error::RustError(format!(
+            "catalog responded with {}",
+            resp.status_code()
+        )));
+    }
+    Ok(())
+}
+
+/// DocumentCatalog is the single durable object which keeps the
+/// deployment's Catalog. coordinators record their document whenever it
+/// changes, and the admin api lists and annotates it
+#[durable_object]
+pub struct DocumentCatalog {
+    state: State,
+    catalog: Option<Catalog>,
+}
+
+#[durable_object]
+impl DurableObject for DocumentCatalog {
+    fn new(state: State, _env: Env) -> Self {
+        console_error_panic_hook::set_once();
+        Self { state, catalog: None }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        if self.catalog.is_none() {
+            self.catalog = Some(self.load().await?);
+        }
+        let catalog = self.catalog.as_mut().unwrap();
+
+        let path = req.path();
+        match (req.method(), path.as_str()) {
+            (Method::Post, "/record") => {
+                let entry: CatalogEntry =
+                    bincode::deserialize(&req.bytes().await?)
+                        .map_err(|e| Error::RustError(e.to_string()))?;
+                catalog.upsert(&entry).map_err(sqlsync_err)?;
+                self.save(entry.doc_id).await?;
+                Response::ok("ok")
+            }
+            (Method::Get, "/catalog") => {
+                let query = match parse_query(&req.url()?) {
+                    Ok(query) => query,
+                    Err(reason) => return Response::error(reason, 400),
+                };
+                let page = catalog.list(&query).map_err(sqlsync_err)?;
+                Response::from_json(&PageJson::from(page))
+            }
+            (Method::Put, path) if path.ends_with("/metadata") => {
+                let Some(doc_id) = path
+                    .strip_prefix("/catalog/")
+                    .and_then(|p| p.strip_suffix("/metadata"))
+                    .and_then(|id| JournalId::from_base58(id).ok())
+                else {
+                    return Response::error("Bad Request", 400);
+                };
+                let metadata: BTreeMap<String, String> = match req.json().await
+                {
+                    Ok(metadata) => metadata,
+                    Err(_) => return Response::error("Bad Request", 400),
+                };
+                if !catalog
+                    .set_metadata(doc_id, &metadata)
+                    .map_err(sqlsync_err)?
+                {
+                    return Response::error("Not Found", 404);
+                }
+                self.save(doc_id).await?;
+                Response::ok("ok")
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+}
+
+impl DocumentCatalog {
+    /// restore the catalog into an in memory database
+    async fn load(&self) -> Result<Catalog> {
+        let conn = Connection::open_in_memory().map_err(sqlsync_err)?;
+        let mut catalog = Catalog::open(conn).map_err(sqlsync_err)?;
+        let entries = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix(ENTRY_PREFIX))
+            .await?;
+        for value in entries.values() {
+            let entry: CatalogEntry = serde_wasm_bindgen::from_value(value?)
+                .map_err(|e| {
+                    Error::RustError(format!("invalid catalog entry: {}", e))
+                })?;
+            catalog.restore(&entry).map_err(sqlsync_err)?;
+        }
+        Ok(catalog)
+    }
+
+    /// persist a document's entry after it changes
+    async fn save(&self, doc_id: JournalId) -> Result<()> {
+        let catalog = self.catalog.as_ref().unwrap();
+        let Some(entry) = catalog.get(doc_id).map_err(sqlsync_err)? else {
+            return Ok(());
+        };
+        let key = format!("{}{}", ENTRY_PREFIX, doc_id.to_base58());
+        self.state.storage().put(&key, &entry).await
+    }
+}
+
+/// read a CatalogQuery from the url's query string, where metadata filters
+/// are prefixed with meta., e.g. ?meta.team=a&limit=10
+fn parse_query(url: &Url) -> std::result::Result<CatalogQuery, String> {
+    let mut query = CatalogQuery::default();
+    for (key, value) in url.query_pairs() {
+        let invalid = || format!("invalid {}: {}", key, value);
+        match key.as_ref() {
+            "cursor" => {
+                query.cursor = Some(
+                    JournalId::from_base58(&value).map_err(|_| invalid())?,
+                )
+            }
+            "reducer" => {
+                let digest = bs58::decode(value.as_ref())
+                    .into_vec()
+                    .ok()
+                    .and_then(|d| d.try_into().ok())
+                    .ok_or_else(invalid)?;
+                query.reducer_digest = Some(digest);
+            }
+            "modified_after_ms" => {
+                query.modified_after_ms =
+                    Some(value.parse().map_err(|_| invalid())?)
+            }
+            "min_database_bytes" => {
+                query.min_database_bytes =
+                    Some(value.parse().map_err(|_| invalid())?)
+            }
+            "limit" => {
+                query.limit = Some(value.parse().map_err(|_| invalid())?)
+            }
+            key => {
+                if let Some(meta) = key.strip_prefix("meta.") {
+                    query.metadata.insert(meta.to_owned(), value.into_owned());
+                }
+            }
+        }
+    }
+    Ok(query)
+}
+
+fn sqlsync_err(e: impl std::fmt::Display) -> Error {
+    Error::RustError(e.to_string())
+}
@@ -1,78 +1,196 @@
-use std::{
-    collections::BTreeMap,
-    io::{self, Cursor},
-};
+use std::{collections::HashMap, rc::Rc, time::Duration};
 
-use anyhow::{anyhow, bail};
+use anyhow::anyhow;
 use futures::{
-    channel::mpsc::{self},
-    select_biased,
-    stream::{repeat, SelectAll, SplitSink, SplitStream},
-    FutureExt, SinkExt, StreamExt,
+    channel::{mpsc, oneshot},
+    future::Fuse,
+    select_biased, FutureExt, SinkExt, StreamExt,
 };
 use gloo::timers::future::TimeoutFuture;
-use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
+use gloo_net::websocket::futures::WebSocket;
 use sqlsync::{
+    catalog::CatalogEntry,
+    compaction::{Compactor, FrameThreshold},
     coordinator::CoordinatorDocument,
-    replication::{ReplicationMsg, ReplicationProtocol, ReplicationSource},
-    MemoryJournal, MemoryJournalFactory,
+    quota::StorageQuota,
+    replication::ReplicationSource,
+    server::{self, ClientId, CoordinatorServer, FrameStorage, ServerOutput},
+    webhook::{WebhookConfig, WebhookEvent, Webhooks},
+    JournalId, Lsn, MemoryJournal, MemoryJournalFactory, Serializable,
+};
+use sqlsync_cloudflare::{DurableObjectStorage, SocketEvent, WebSocketSet};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::spawn_local;
+use worker::{
+    console_error, console_log, Bucket, Date, Error, Fetch, Headers, Method,
+    ObjectNamespace, Request, RequestInit, State,
+};
+
+use crate::{
+    catalog::record_entry,
+    object_id_to_journal_id,
+    persistence::load_webhooks,
+    snapshot_key,
+    tenants::{tenant_request, StorageLimit, StorageRequest, TenantClient},
 };
-use worker::{console_error, console_log, Error, State};
 
-use crate::{object_id_to_journal_id, persistence::Persistence};
+type Server = CoordinatorServer<MemoryJournal>;
+
+/// how long clients should wait before reconnecting to a coordinator which
+/// has shut down
+const RECONNECT_AFTER_MS: u32 = 1000;
+
+/// how often to check client heartbeats
+const HEARTBEAT_CHECK_MS: u32 = 1000;
+
+/// how often to delete rows whose ttl has passed
+const EXPIRE_ROWS_MS: i64 = 60_000;
 
-type Document = CoordinatorDocument<MemoryJournal>;
+/// publish a new checkpoint once storage has grown by this many frames
+const CHECKPOINT_FRAMES: Lsn = 100;
+
+/// fold storage once it holds more than this many frames
+const COMPACT_FRAMES: usize = 1000;
+
+fn now_ms() -> i64 {
+    Date::now().as_millis() as i64
+}
 
 pub struct Coordinator {
-    accept_queue: mpsc::Sender<WebSocket>,
+    accept_queue: mpsc::Sender<(WebSocket, Option<TenantClient>)>,
+    shutdown: mpsc::Sender<oneshot::Sender<()>>,
+    webhooks: mpsc::Sender<WebhookConfig>,
 }
 
 impl Coordinator {
     pub async fn init(
         state: &State,
         reducer_bytes: Vec<u8>,
+        snapshots: Bucket,
+        tenants: ObjectNamespace,
+        catalog: ObjectNamespace,
     ) -> worker::Result<(Coordinator, CoordinatorTask)> {
         let id = object_id_to_journal_id(state.id())?;
         let (accept_queue_tx, accept_queue_rx) = mpsc::channel(10);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let (webhooks_tx, webhooks_rx) = mpsc::channel(1);
+        let (limits_tx, limits_rx) = mpsc::channel(1);
 
         console_log!("creating new document with id {}", id);
 
         let mut storage = MemoryJournal::open(id).map_err(|e| Error::RustError(e.to_string()))?;
 
-        // load the persistence layer
-        let persistence = Persistence::init(state.storage()).await?;
         // replay any persisted frames into storage
-        persistence.replay(id, &mut storage).await?;
+        let persistence = DurableObjectStorage::open(state.storage()).await?;
+        server::replay(&persistence, id, &mut storage)
+            .await
+            .map_err(|e| Error::RustError(e.to_string()))?;
 
         let doc = CoordinatorDocument::open(storage, MemoryJournalFactory, &reducer_bytes)
             .map_err(|e| Error::RustError(e.to_string()))?;
 
+        // clients running an older reducer download this one from us
+        let mut server = CoordinatorServer::new(doc);
+        server.serve_reducer(reducer_bytes);
+        server.set_compactor(Compactor::new(vec![Box::new(FrameThreshold(
+            COMPACT_FRAMES,
+        ))]));
+
+        let webhooks =
+            Webhooks::new(id, load_webhooks(&state.storage()).await?);
+
         Ok((
-            Self { accept_queue: accept_queue_tx },
-            CoordinatorTask { accept_queue: accept_queue_rx, persistence, doc },
+            Self {
+                accept_queue: accept_queue_tx,
+                shutdown: shutdown_tx,
+                webhooks: webhooks_tx,
+            },
+            CoordinatorTask {
+                accept_queue: accept_queue_rx,
+                shutdown: shutdown_rx,
+                webhook_configs: webhooks_rx,
+                persistence,
+                server,
+                sockets: WebSocketSet::default(),
+                webhooks,
+                snapshots,
+                checkpoint_lsn: None,
+                tenants: Rc::new(tenants),
+                tenant_doc: None,
+                tenant_clients: HashMap::new(),
+                reported_bytes: None,
+                storage_limits: (limits_tx, limits_rx),
+                catalog: Rc::new(catalog),
+                cataloged: None,
+            },
         ))
     }
 
-    pub async fn accept(&mut self, socket: WebSocket) -> anyhow::Result<()> {
-        Ok(self.accept_queue.send(socket).await?)
+    /// accept a client, which the TenantDirectory has already counted
+    /// against tenant if it's a tenant connection
+    pub async fn accept(
+        &mut self,
+        socket: WebSocket,
+        tenant: Option<TenantClient>,
+    ) -> anyhow::Result<()> {
+        Ok(self.accept_queue.send((socket, tenant)).await?)
+    }
+
+    /// replace the running coordinator's webhook config
+    pub async fn set_webhooks(
+        &mut self,
+        config: WebhookConfig,
+    ) -> anyhow::Result<()> {
+        Ok(self.webhooks.send(config).await?)
+    }
+
+    /// shutdown stops accepting clients, applies and persists any pending
+    /// mutations, and asks every client to reconnect later. resolves once
+    /// the coordinator task has exited
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.shutdown.send(done_tx).await?;
+        Ok(done_rx.await?)
     }
 }
 
 pub struct CoordinatorTask {
-    accept_queue: mpsc::Receiver<WebSocket>,
-    persistence: Persistence,
-    doc: Document,
+    accept_queue: mpsc::Receiver<(WebSocket, Option<TenantClient>)>,
+    shutdown: mpsc::Receiver<oneshot::Sender<()>>,
+    webhook_configs: mpsc::Receiver<WebhookConfig>,
+    persistence: DurableObjectStorage,
+    server: Server,
+    sockets: WebSocketSet,
+    webhooks: Webhooks,
+    snapshots: Bucket,
+    /// the lsn of the last checkpoint we published
+    checkpoint_lsn: Option<Lsn>,
+    tenants: Rc<ObjectNamespace>,
+    /// the document's id in the TenantDirectory, once a tenant has connected
+    tenant_doc: Option<JournalId>,
+    /// the tenant connections the TenantDirectory is counting
+    tenant_clients: HashMap<ClientId, TenantClient>,
+    /// the database size last reported to the TenantDirectory
+    reported_bytes: Option<u64>,
+    /// the database limits the TenantDirectory replies with
+    storage_limits: (mpsc::Sender<Option<u64>>, mpsc::Receiver<Option<u64>>),
+    catalog: Rc<ObjectNamespace>,
+    /// the entry last recorded in the DocumentCatalog
+    cataloged: Option<CatalogEntry>,
 }
 
 impl CoordinatorTask {
     // into_task consumes the Coordinator and runs it as a task
     pub async fn into_task(mut self) {
-        let mut clients: BTreeMap<usize, Client> = BTreeMap::new();
-        let mut messages = SelectAll::new();
-        let mut next_client_idx = 0;
-
         const STEP_MIN_MS: u32 = 100;
+        // keep steps short so that a burst of mutations doesn't starve clients
+        const STEP_BUDGET: Duration = Duration::from_millis(50);
         let mut step_trigger = TimeoutFuture::new(STEP_MIN_MS).fuse();
+        let mut heartbeat_trigger =
+            TimeoutFuture::new(HEARTBEAT_CHECK_MS).fuse();
+        let mut next_expiry_ms = now_ms() + EXPIRE_ROWS_MS;
+        // armed whenever a webhook call is pending
+        let mut webhook_trigger = Fuse::terminated();
 
         // NOTE TO CODE REVIEWERS:
         // `select_biased!` is full of foot guns (see: [1] and [2])
@@ -89,146 +207,343 @@ impl CoordinatorTask {
 
         loop {
             select_biased! {
+                // handle shutdown
+                done = self.shutdown.select_next_some() => {
+                    if let Err(e) = self.drain().await {
+                        console_error!("error draining coordinator: {:?}", e);
+                    }
+                    let _ = done.send(());
+                    return;
+                },
+
                 // handle steps
                 _ = step_trigger => {
                     // apply any pending changes to the document
-                    if let Err(e) = self.step().await {
+                    if let Err(e) = self.step(STEP_BUDGET).await {
                         console_error!("error stepping: {:?}", e);
                         continue;
                     }
 
+                    // we ran out of budget, pick up where we left off once
+                    // we've yielded to the event loop
+                    if self.server.doc().has_pending_work() {
+                        step_trigger = TimeoutFuture::new(0).fuse();
+                    }
+
                     // persist document state to storage
                     if let Err(e) = self.persist().await {
                         console_error!("error persisting: {:?}", e);
                         continue;
                     }
 
-                    // sync all clients
-                    for (_, client) in clients.iter_mut() {
-                        if let Err(e) = client.sync(&self.doc).await {
-                            console_error!("error syncing: {:?}", e);
-                            continue;
+                    if let Err(e) = self.maybe_checkpoint().await {
+                        console_error!("error publishing checkpoint: {:?}", e);
+                    }
+
+                    if let Err(e) = self.report_storage() {
+                        console_error!("error reporting storage: {:?}", e);
+                    }
+
+                    if let Err(e) = self.record_in_catalog() {
+                        console_error!("error recording in catalog: {:?}", e);
+                    }
+
+                    // schedule webhooks now that the changes are durable
+                    if let Some(lsn) = self.server.doc().source_range().last() {
+                        let was_pending = self.webhooks.deadline().is_some();
+                        self.webhooks.storage_advanced(lsn, now_ms());
+                        if !was_pending {
+                            if let Some(deadline) = self.webhooks.deadline() {
+                                webhook_trigger = timeout_until(deadline).fuse();
+                            }
                         }
                     }
+
+                    // sync all clients, turning away the ones whose mutations
+                    // don't fit in the document's quota
+                    let mut outputs = self.server.reject_over_quota();
+                    outputs.extend(self.server.sync());
+                    self.apply(outputs).await;
                 },
 
-                // handle new clients
-                socket = self.accept_queue.select_next_some() => {
-                    let (mut client, reader) = Client::init(socket);
-                    if let Err(e) = client.start_replication(&self.doc).await {
-                        console_error!("error starting replication: {:?}", e);
-                        continue;
+                // ping idle clients and drop the ones which have gone away
+                _ = heartbeat_trigger => {
+                    heartbeat_trigger =
+                        TimeoutFuture::new(HEARTBEAT_CHECK_MS).fuse();
+
+                    let outputs = self.server.heartbeat(now_ms());
+                    self.apply(outputs).await;
+
+                    // only frames which have been persisted are folded
+                    let durable = self.persistence.range().last();
+                    if let Err(e) = self.server.compact(durable, now_ms()) {
+                        console_error!("error compacting: {:?}", e);
+                    }
+
+                    if now_ms() >= next_expiry_ms {
+                        next_expiry_ms = now_ms() + EXPIRE_ROWS_MS;
+                        match self.server.doc_mut().expire_rows() {
+                            Ok(None) => {}
+                            Ok(Some(_)) => step_trigger = TimeoutFuture::new(0).fuse(),
+                            Err(e) => console_error!("error expiring rows: {:?}", e),
+                        }
+                    }
+
+                    // queue any scheduled reducer tasks which are due
+                    match self.server.doc_mut().run_scheduled_tasks() {
+                        Ok(0) => {}
+                        Ok(_) => step_trigger = TimeoutFuture::new(0).fuse(),
+                        Err(e) => console_error!("error scheduling tasks: {:?}", e),
                     }
-                    next_client_idx += 1;
-                    let client_idx = next_client_idx;
-                    clients.insert(client_idx, client);
-                    messages.push(repeat(client_idx).zip(reader));
                 },
 
-                // handle messages from clients
-                (client_idx, msg) = messages.select_next_some() => {
-                    let client = match clients.get_mut(&client_idx) {
-                        Some(client ) => client,
-                        None => {
-                            console_error!("received message from unknown client {}", client_idx);
+                // call webhooks once the debounce period has passed
+                _ = webhook_trigger => {
+                    if let Some(event) = self.webhooks.poll(now_ms()) {
+                        for url in self.webhooks.config().urls.iter() {
+                            spawn_local(call_webhook(url.clone(), event.clone()));
+                        }
+                    }
+                },
+
+                config = self.webhook_configs.select_next_some() => {
+                    self.webhooks.set_config(config);
+                },
+
+                // cap the database at the size which keeps its tenant within
+                // its storage quota
+                limit = self.storage_limits.1.select_next_some() => {
+                    let doc = self.server.doc_mut();
+                    let quota =
+                        StorageQuota { max_database_bytes: limit, ..doc.quota() };
+                    if quota != doc.quota() {
+                        if let Err(e) = doc.set_quota(quota) {
+                            console_error!("error setting quota: {:?}", e);
+                        }
+                        step_trigger = TimeoutFuture::new(0).fuse();
+                    }
+                },
+
+                // handle new clients
+                (socket, tenant) = self.accept_queue.select_next_some() => {
+                    let (client, outputs) = match self.server.accept(now_ms()) {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            console_error!("error starting replication: {:?}", e);
+                            if let Some(tenant) = tenant {
+                                self.release_tenant(tenant);
+                            }
                             continue;
                         }
                     };
-                    if let Err(e) = client.handle_message(&mut self.doc, msg).await {
-                        console_error!("error handling message from client {}: {:?}", client_idx, e);
-                        // remove client; note, we don't have to remove the
-                        // reader from messages because SelectAll handles that
-                        // automatically
-                        clients.remove(&client_idx);
-                    } else {
+                    if let Some(tenant) = tenant {
+                        self.tenant_doc = Some(tenant.doc);
+                        self.tenant_clients.insert(client, tenant);
+                    }
+                    self.sockets.insert(client, socket);
+                    self.apply(outputs).await;
+                },
+
+                // handle messages from clients
+                (client, event) = self.sockets.select_next_some() => match event {
+                    SocketEvent::Message(msg) => {
+                        let outputs = self.server.receive(client, &msg, now_ms());
+                        self.apply(outputs).await;
                         // schedule a step whenever we receive messages from a client
                         step_trigger = TimeoutFuture::new(STEP_MIN_MS).fuse();
                     }
+                    SocketEvent::Closed => self.disconnect(client),
                 },
             }
         }
     }
 
-    async fn step(&mut self) -> anyhow::Result<()> {
-        while self.doc.has_pending_work() {
-            self.doc.step()?;
+    /// send the server's outputs, disconnecting clients whose sockets fail
+    async fn apply(&mut self, outputs: Vec<ServerOutput>) {
+        for client in self.sockets.apply(outputs).await {
+            self.disconnect(client);
+        }
+    }
+
+    fn disconnect(&mut self, client: ClientId) {
+        self.server.disconnect(client);
+        if let Some(tenant) = self.tenant_clients.remove(&client) {
+            self.release_tenant(tenant);
         }
+    }
+
+    /// tell the TenantDirectory to stop counting a connection
+    fn release_tenant(&self, tenant: TenantClient) {
+        let tenants = self.tenants.clone();
+        spawn_local(async move {
+            if let Err(e) =
+                tenant_request(&tenants, "/disconnect", &tenant).await
+            {
+                console_error!("error releasing tenant connection: {:?}", e);
+            }
+        });
+    }
 
+    /// report the database's size to the TenantDirectory whenever it
+    /// changes, if the document belongs to a tenant. the directory replies
+    /// with the database's new limit
+    fn report_storage(&mut self) -> anyhow::Result<()> {
+        let Some(doc) = self.tenant_doc else {
+            return Ok(());
+        };
+        let bytes = self.server.doc().database_size()?;
+        if self.reported_bytes == Some(bytes) {
+            return Ok(());
+        }
+        self.reported_bytes = Some(bytes);
+
+        let tenants = self.tenants.clone();
+        let mut limits = self.storage_limits.0.clone();
+        spawn_local(async move {
+            let result = async {
+                let req = StorageRequest { doc, bytes };
+                let mut resp =
+                    tenant_request(&tenants, "/storage", &req).await?;
+                if resp.status_code() >= 400 {
+                    return Err(Error::RustError(resp.text().await?));
+                }
+                let StorageLimit { limit } = resp.json().await?;
+                Ok(limit)
+            };
+            match result.await {
+                Ok(limit) => {
+                    let _ = limits.send(limit).await;
+                }
+                Err(e) => console_error!("error reporting storage: {:?}", e),
+            }
+        });
         Ok(())
     }
 
-    async fn persist(&mut self) -> anyhow::Result<()> {
-        let mut next_lsn = self.persistence.expected_lsn();
-        while let Some(frame) = self.doc.read_lsn(next_lsn)? {
-            self.persistence
-                .write_lsn(next_lsn, frame.to_owned())
-                .await
-                .map_err(|e| anyhow!(e.to_string()))?;
-            next_lsn = self.persistence.expected_lsn();
+    async fn step(&mut self, budget: Duration) -> anyhow::Result<()> {
+        let deadline = Date::now().as_millis() + budget.as_millis() as u64;
+        let doc = self.server.doc_mut();
+        while doc.has_pending_work() && Date::now().as_millis() < deadline {
+            doc.step_with_budget(budget)?;
         }
 
         Ok(())
     }
-}
 
-struct Client {
-    protocol: ReplicationProtocol,
-    writer: SplitSink<WebSocket, Message>,
-}
+    /// record the document in the DocumentCatalog whenever its entry
+    /// changes. modified_ms is left out of the comparison, since the catalog
+    /// only moves it along with last_lsn
+    fn record_in_catalog(&mut self) -> anyhow::Result<()> {
+        let entry = CatalogEntry::of(self.server.doc())?;
+        if let Some(last) = &self.cataloged {
+            let unchanged =
+                CatalogEntry { modified_ms: last.modified_ms, ..entry.clone() };
+            if unchanged == *last {
+                return Ok(());
+            }
+        }
+        self.cataloged = Some(entry.clone());
 
-impl Client {
-    fn init(socket: WebSocket) -> (Self, SplitStream<WebSocket>) {
-        let (writer, reader) = socket.split();
-        let protocol = ReplicationProtocol::new();
-        (Self { protocol, writer }, reader)
+        let catalog = self.catalog.clone();
+        spawn_local(async move {
+            if let Err(e) = record_entry(&catalog, &entry).await {
+                console_error!("error recording in catalog: {:?}", e);
+            }
+        });
+        Ok(())
     }
 
-    async fn start_replication(&mut self, doc: &Document) -> anyhow::Result<()> {
-        let msg = self.protocol.start(doc);
-        self.send_msg(msg).await
-    }
+    async fn drain(&mut self) -> anyhow::Result<()> {
+        // refuse new clients, turning away any which are already queued
+        self.accept_queue.close();
+        while let Some((socket, tenant)) = self.accept_queue.next().await {
+            let (client, _) = self.server.accept(now_ms())?;
+            if let Some(tenant) = tenant {
+                self.tenant_clients.insert(client, tenant);
+            }
+            self.sockets.insert(client, socket);
+        }
+
+        self.server.doc_mut().drain()?;
+        self.persist().await?;
 
-    async fn sync(&mut self, doc: &Document) -> anyhow::Result<()> {
-        while let Some((msg, mut frame)) = self.protocol.sync(doc)? {
-            console_log!("sending message {:?}", msg);
-            let mut buf = Cursor::new(vec![]);
-            bincode::serialize_into(&mut buf, &msg)?;
-            io::copy(&mut frame, &mut buf)?;
-            self.writer.send(Message::Bytes(buf.into_inner())).await?;
+        // hand every client the final state before sending them away
+        let outputs = self.server.disconnect_all(RECONNECT_AFTER_MS);
+        self.apply(outputs).await;
+        // the task exits before their sockets report closing
+        for (_, tenant) in std::mem::take(&mut self.tenant_clients) {
+            self.release_tenant(tenant);
         }
 
         Ok(())
     }
 
-    async fn send_msg(&mut self, msg: ReplicationMsg) -> anyhow::Result<()> {
-        let data = bincode::serialize(&msg)?;
-        console_log!("sending message {:?}", msg);
-        Ok(self.writer.send(Message::Bytes(data)).await?)
+    async fn persist(&mut self) -> anyhow::Result<()> {
+        server::persist(self.server.doc(), &mut self.persistence)
+            .await
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(())
     }
 
-    async fn handle_message(
-        &mut self,
-        doc: &mut Document,
-        msg: Result<Message, WebSocketError>,
-    ) -> anyhow::Result<()> {
-        match msg {
-            Ok(Message::Bytes(bytes)) => {
-                let mut cursor = Cursor::new(bytes);
-                let msg: ReplicationMsg = bincode::deserialize_from(&mut cursor)?;
-                console_log!("received message {:?}", msg);
-                if let Some(resp) = self.protocol.handle(doc, msg, &mut cursor)? {
-                    self.send_msg(resp).await?;
-                }
-                Ok(())
-            }
+    /// publish a checkpoint for cold clients to bootstrap from, once enough
+    /// frames have been persisted since the last one. the document is
+    /// analyzed first
+    async fn maybe_checkpoint(&mut self) -> anyhow::Result<()> {
+        let Some(lsn) = self.server.doc().source_range().last() else {
+            return Ok(());
+        };
+        let due = match self.checkpoint_lsn {
+            Some(last) => lsn >= last + CHECKPOINT_FRAMES,
+            None => lsn + 1 >= CHECKPOINT_FRAMES,
+        };
+        if !due {
+            return Ok(());
+        }
+        // refresh query planner statistics so that they ship with the
+        // checkpoint, and reach connected clients with the next sync
+        if self.server.doc_mut().optimize()? {
+            self.persist().await?;
+        }
+        let Some(checkpoint) = self.server.doc().checkpoint()? else {
+            return Ok(());
+        };
+        let mut blob = Vec::new();
+        checkpoint.serialize_into(&mut blob)?;
+        self.snapshots
+            .put(snapshot_key(self.server.doc().doc_id()), blob)
+            .execute()
+            .await
+            .map_err(|e| anyhow!(e.to_string()))?;
+        self.checkpoint_lsn = Some(checkpoint.lsn);
+        Ok(())
+    }
+}
 
-            Ok(Message::Text(_)) => {
-                bail!("received unexpected text message")
-            }
+fn timeout_until(deadline_ms: i64) -> TimeoutFuture {
+    TimeoutFuture::new((deadline_ms - now_ms()).max(0) as u32)
+}
 
-            Err(e) => {
-                bail!("websocket error: {:?}", e)
-            }
+/// webhooks are best effort; failures are logged and the next change will
+/// call the webhook again
+async fn call_webhook(url: String, event: WebhookEvent) {
+    let result = async {
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(JsValue::from_str(&event.to_json())));
+        let req = Request::new_with_init(&url, &init)?;
+        let resp = Fetch::Request(req).send().await?;
+        if resp.status_code() >= 400 {
+            return Err(Error::RustError(format!(
+                "status {}",
+                resp.status_code()
+            )));
         }
+        Ok(())
+    };
+    if let Err(e) = result.await {
+        console_error!("error calling webhook {}: {:?}", url, e);
     }
 }
@@ -1,15 +1,48 @@
+use std::time::Duration;
+
+use auth::{request_token, require_admin, require_doc_access};
+use catalog::CATALOG_OBJECT_NAME;
 use coordinator::Coordinator;
+use persistence::save_webhooks;
+use gloo_net::websocket::futures::WebSocket;
 use js_sys::{ArrayBuffer, Reflect, Uint8Array};
-use sqlsync::JournalId;
+use serde::Deserialize;
+use sqlsync::{webhook::WebhookConfig, JournalId, ModuleDigest};
+use sqlsync_cloudflare::relay_multiplexed;
+use tenants::{
+    tenant_request, ConnectRequest, TenantClient, TENANTS_OBJECT_NAME,
+    TENANT_HEADER,
+};
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::{spawn_local, JsFuture};
 use worker::*;
 
+mod auth;
+mod catalog;
 mod coordinator;
 mod persistence;
+mod tenants;
 
 pub const DURABLE_OBJECT_NAME: &str = "COORDINATOR";
 pub const REDUCER_BUCKET: &str = "SQLSYNC_REDUCERS";
+pub const SNAPSHOT_BUCKET: &str = "SQLSYNC_SNAPSHOTS";
+
+/// how long edge caches may serve a checkpoint before fetching the latest;
+/// a stale checkpoint only means a little more incremental replication
+const SNAPSHOT_MAX_AGE_SECS: u32 = 60;
+
+const DEFAULT_WEBHOOK_DEBOUNCE_MS: u64 = 1000;
+const MAX_WEBHOOKS: usize = 8;
+
+/// a comma separated list of the hosts webhooks may call. when it's unset
+/// any public https host may be called
+pub const WEBHOOK_HOSTS_VAR: &str = "SQLSYNC_WEBHOOK_HOSTS";
+
+#[derive(Deserialize)]
+struct WebhooksRequest {
+    urls: Vec<String>,
+    debounce_ms: Option<u64>,
+}
 
 #[durable_object]
 pub struct DocumentCoordinator {
@@ -25,7 +58,57 @@ impl DurableObject for DocumentCoordinator {
         Self { state, env, coordinator: None }
     }
 
-    async fn fetch(&mut self, req: Request) -> Result<Response> {
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        // drain the coordinator ahead of a deploy; the next websocket request
+        // will start a fresh coordinator
+        if req.method() == Method::Post && req.path().ends_with("/drain") {
+            if let Some(coordinator) = self.coordinator.take() {
+                coordinator
+                    .shutdown()
+                    .await
+                    .map_err(|e| Error::RustError(e.to_string()))?;
+            }
+            return Response::ok("drained");
+        }
+
+        // configure the urls which are called whenever the document changes
+        if req.method() == Method::Put && req.path().ends_with("/webhooks") {
+            let WebhooksRequest { urls, debounce_ms } = match req.json().await
+            {
+                Ok(body) => body,
+                Err(_) => return Response::error("Bad Request", 400),
+            };
+            if urls.len() > MAX_WEBHOOKS {
+                return Response::error("Too Many Webhooks", 400);
+            }
+            let allowed_hosts =
+                self.env.var(WEBHOOK_HOSTS_VAR).ok().map(|v| v.to_string());
+            for url in urls.iter() {
+                if let Err(reason) =
+                    check_webhook_url(url, allowed_hosts.as_deref())
+                {
+                    return Response::error(
+                        format!("invalid webhook url {}: {}", url, reason),
+                        400,
+                    );
+                }
+            }
+            let config = WebhookConfig {
+                urls,
+                debounce: Duration::from_millis(
+                    debounce_ms.unwrap_or(DEFAULT_WEBHOOK_DEBOUNCE_MS),
+                ),
+            };
+            save_webhooks(&mut self.state.storage(), &config).await?;
+            if let Some(coordinator) = self.coordinator.as_mut() {
+                coordinator
+                    .set_webhooks(config)
+                    .await
+                    .map_err(|e| Error::RustError(e.to_string()))?;
+            }
+            return Response::ok("ok");
+        }
+
         // check that the Upgrade header is set and == "websocket"
         let is_upgrade_req =
             req.headers().get("Upgrade")?.unwrap_or("".into()) == "websocket";
@@ -70,19 +153,29 @@ impl DurableObject for DocumentCoordinator {
                 }
             };
 
-            let (coordinator, task) =
-                Coordinator::init(&self.state, reducer_bytes).await?;
+            let snapshots = self.env.bucket(SNAPSHOT_BUCKET)?;
+            let tenants = self.env.durable_object(TENANTS_OBJECT_NAME)?;
+            let catalog = self.env.durable_object(CATALOG_OBJECT_NAME)?;
+            let (coordinator, task) = Coordinator::init(
+                &self.state,
+                reducer_bytes,
+                snapshots,
+                tenants,
+                catalog,
+            )
+            .await?;
             spawn_local(task.into_task());
             self.coordinator = Some(coordinator);
         }
         let coordinator = self.coordinator.as_mut().unwrap();
+        let tenant = TenantClient::from_headers(req.headers())?;
 
         let pair = WebSocketPair::new()?;
         let ws = pair.server;
         ws.accept()?;
 
         if let Err(e) = coordinator
-            .accept(ws.as_ref().clone().try_into().unwrap())
+            .accept(ws.as_ref().clone().try_into().unwrap(), tenant)
             .await
         {
             // the only case we get an error here is if the coordinator task has
@@ -172,32 +265,272 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 Response::error("Bad Request", 400)
             }
         })
+        .get_async("/doc/:id/snapshot", |req, ctx| async move {
+            if let Some(denied) = require_doc_access(&req, &ctx) {
+                return Ok(denied);
+            }
+            // checkpoints are published by the coordinator, so they can be
+            // served (and cached) without waking it up. the cache is private
+            // since the snapshot may only be readable with a token
+            let Some(id) = ctx.param("id") else {
+                return Response::error("Bad Request", 400);
+            };
+            let id = JournalId::from_base58(id)
+                .map_err(|e| Error::RustError(e.to_string()))?;
+            let bucket = ctx.bucket(SNAPSHOT_BUCKET)?;
+            let Some(object) = bucket.get(snapshot_key(id)).execute().await?
+            else {
+                return Response::error("Not Found", 404);
+            };
+            let Some(body) = object.body() else {
+                return Response::error("Not Found", 404);
+            };
+            let mut headers = Headers::new();
+            headers.set("Content-Type", "application/octet-stream")?;
+            headers.set(
+                "Cache-Control",
+                &format!("private, max-age={}", SNAPSHOT_MAX_AGE_SECS),
+            )?;
+            Ok(Response::from_bytes(body.bytes().await?)?.with_headers(headers))
+        })
+        .on_async("/mux", |req, ctx| async move {
+            if let Some(denied) = require_doc_access(&req, &ctx) {
+                return Ok(denied);
+            }
+            accept_multiplexed(req, ctx).await
+        })
+        .on_async("/tenant/:tenant/doc/:name", connect_tenant)
         .on_async("/doc/:id", |req, ctx| async move {
-            if let Some(id) = ctx.param("id") {
-                console_log!("forwarding request to document with id: {}", id);
-                let namespace = ctx.durable_object(DURABLE_OBJECT_NAME)?;
-                let id = JournalId::from_base58(&id)
-                    .map_err(|e| Error::RustError(e.to_string()))?;
-                let id = match namespace.id_from_string(&id.to_hex()) {
-                    Ok(id) => id,
-                    Err(e) => {
-                        return Response::error(
-                            format!("Invalid Durable Object ID: {}", e),
-                            400,
-                        )
-                    }
-                };
-                let stub = id.get_stub()?;
-                stub.fetch_with_request(req).await
-            } else {
-                Response::error("Bad Request", 400)
+            if let Some(denied) = require_doc_access(&req, &ctx) {
+                return Ok(denied);
+            }
+            forward_to_doc(req, ctx).await
+        })
+        .post_async("/doc/:id/drain", |req, ctx| async move {
+            if let Some(denied) = require_admin(&req, &ctx) {
+                return Ok(denied);
+            }
+            forward_to_doc(req, ctx).await
+        })
+        .put_async("/doc/:id/webhooks", |req, ctx| async move {
+            if let Some(denied) = require_admin(&req, &ctx) {
+                return Ok(denied);
+            }
+            forward_to_doc(req, ctx).await
+        })
+        // list the document catalog, see catalog::parse_query for its filters
+        .get_async("/catalog", |req, ctx| async move {
+            if let Some(denied) = require_admin(&req, &ctx) {
+                return Ok(denied);
+            }
+            forward_to_catalog(req, ctx).await
+        })
+        // replace a document's catalog metadata with a json object of strings
+        .put_async("/catalog/:id/metadata", |req, ctx| async move {
+            if let Some(denied) = require_admin(&req, &ctx) {
+                return Ok(denied);
             }
+            forward_to_catalog(req, ctx).await
         })
         .run(req, env)
         .await?
         .with_cors(&cors)
 }
 
+async fn forward_to_doc(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    // only connect_tenant may say which tenant a connection belongs to
+    if req.headers().has(TENANT_HEADER)? {
+        return Response::error("Bad Request", 400);
+    }
+    if let Some(id) = ctx.param("id") {
+        console_log!("forwarding request to document with id: {}", id);
+        let namespace = ctx.durable_object(DURABLE_OBJECT_NAME)?;
+        let id = JournalId::from_base58(&id)
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        let id = match namespace.id_from_string(&id.to_hex()) {
+            Ok(id) => id,
+            Err(e) => {
+                return Response::error(
+                    format!("Invalid Durable Object ID: {}", e),
+                    400,
+                )
+            }
+        };
+        let stub = id.get_stub()?;
+        stub.fetch_with_request(req).await
+    } else {
+        Response::error("Bad Request", 400)
+    }
+}
+
+async fn forward_to_catalog(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    let namespace = ctx.durable_object(CATALOG_OBJECT_NAME)?;
+    let stub = namespace.id_from_name("catalog")?.get_stub()?;
+    stub.fetch_with_request(req).await
+}
+
+/// connect to one of a tenant's documents by name, opening it if it's new.
+/// the TenantDirectory authenticates the connection and counts it against
+/// the tenant's quotas before it's forwarded to the document's coordinator
+async fn connect_tenant(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    if req.headers().get("Upgrade")?.unwrap_or_default() != "websocket" {
+        return Response::error("Bad Request", 400);
+    }
+    let (Some(tenant), Some(name)) = (ctx.param("tenant"), ctx.param("name"))
+    else {
+        return Response::error("Bad Request", 400);
+    };
+    let Some(token) = request_token(&req) else {
+        return Response::error("Unauthorized", 401);
+    };
+
+    let tenants = ctx.durable_object(TENANTS_OBJECT_NAME)?;
+    let connect =
+        ConnectRequest { tenant: tenant.clone(), token, name: name.clone() };
+    let mut resp = tenant_request(&tenants, "/connect", &connect).await?;
+    if resp.status_code() != 200 {
+        return Ok(resp);
+    }
+    let client: TenantClient = resp.json().await?;
+
+    // the url carries the reducer digest through to the coordinator
+    let mut headers = Headers::new();
+    headers.set("Upgrade", "websocket")?;
+    client.set_headers(&mut headers)?;
+    let mut init = RequestInit::new();
+    init.with_headers(headers);
+    let forwarded = Request::new_with_init(req.url()?.as_str(), &init)?;
+
+    let namespace = ctx.durable_object(DURABLE_OBJECT_NAME)?;
+    let stub = namespace
+        .id_from_name(&format!("tenant-{}", client.doc.to_base58()))?
+        .get_stub()?;
+    let resp = stub.fetch_with_request(forwarded).await;
+    if !matches!(&resp, Ok(resp) if resp.websocket().is_some()) {
+        // the coordinator never saw the connection, so it won't release it
+        tenant_request(&tenants, "/disconnect", &client).await?;
+    }
+    resp
+}
+
+/// accept a connection which replicates many documents, relaying each one to
+/// its document's durable object
+async fn accept_multiplexed(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    if req.headers().get("Upgrade")?.unwrap_or_default() != "websocket" {
+        return Response::error("Bad Request", 400);
+    }
+    let namespace = ctx.durable_object(DURABLE_OBJECT_NAME)?;
+
+    let pair = WebSocketPair::new()?;
+    let ws = pair.server;
+    ws.accept()?;
+    let client: WebSocket = match ws.as_ref().clone().try_into() {
+        Ok(client) => client,
+        Err(e) => {
+            console_error!("can't accept multiplexed websocket: {:?}", e);
+            return Response::error("Internal Server Error", 500);
+        }
+    };
+    spawn_local(async move {
+        relay_multiplexed(client, |doc, reducer| {
+            connect_to_doc(&namespace, doc, reducer)
+        })
+        .await;
+    });
+
+    Response::from_websocket(pair.client)
+}
+
+/// open a websocket to a document's durable object, like a client would
+async fn connect_to_doc(
+    namespace: &ObjectNamespace,
+    doc: JournalId,
+    reducer: Option<ModuleDigest>,
+) -> Result<WebSocket> {
+    let mut url = format!("https://coordinator/doc/{}", doc.to_base58());
+    if let Some(digest) = reducer {
+        let digest = bs58::encode(digest).into_string();
+        url.push_str(&format!("?reducer={}", digest));
+    }
+    let headers = Headers::new();
+    headers.set("Upgrade", "websocket")?;
+    let req =
+        Request::new_with_init(&url, RequestInit::new().with_headers(headers))?;
+
+    let stub = namespace.id_from_string(&doc.to_hex())?.get_stub()?;
+    let resp = stub.fetch_with_request(req).await?;
+    let Some(ws) = resp.websocket() else {
+        return Err(Error::RustError(format!(
+            "document {} didn't accept a websocket",
+            doc
+        )));
+    };
+    ws.accept()?;
+    ws.as_ref().clone().try_into().map_err(|e| {
+        Error::RustError(format!("bad websocket from {}: {:?}", doc, e))
+    })
+}
+
+/// webhooks are called from the worker, so they must be public https urls
+/// rather than addresses inside cloudflare or the deployment's network, and
+/// one of allowed_hosts (or a subdomain of one) when it's set
+fn check_webhook_url(
+    url: &str,
+    allowed_hosts: Option<&str>,
+) -> std::result::Result<(), &'static str> {
+    let url = Url::parse(url).map_err(|_| "not a url")?;
+    if url.scheme() != "https" {
+        return Err("only https urls may be called");
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err("urls may not carry credentials");
+    }
+    if url.port().is_some() {
+        return Err("urls may not set a port");
+    }
+    // domain is none for ip addresses
+    let Some(host) = url.domain() else {
+        return Err("the host must be a domain name");
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let internal = ["localhost", "local", "internal", "localdomain"];
+    if !host.contains('.')
+        || internal
+            .iter()
+            .any(|&tld| host == tld || host.ends_with(&format!(".{}", tld)))
+    {
+        return Err("the host must be public");
+    }
+    if let Some(allowed) = allowed_hosts {
+        let allowed = allowed
+            .split(',')
+            .map(|h| h.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .any(|h| host == h || host.ends_with(&format!(".{}", h)));
+        if !allowed {
+            return Err("the host isn't allowed");
+        }
+    }
+    Ok(())
+}
+
+/// the key a document's latest checkpoint is stored under
+pub fn snapshot_key(doc_id: JournalId) -> String {
+    format!("{}.snapshot", doc_id.to_base58())
+}
+
 pub fn object_id_to_journal_id(id: ObjectId) -> Result<JournalId> {
     JournalId::from_hex(&id.to_string()).map_err(|e| e.to_string().into())
 }
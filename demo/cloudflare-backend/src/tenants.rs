@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize, Serializer};
+use sqlsync::{
+    tenant::{
+        AuthContext, Authenticator, RegistryState, TenantError, TenantId,
+        TenantQuota, TenantRegistry,
+    },
+    JournalId,
+};
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::auth::constant_time_eq;
+
+pub const TENANTS_OBJECT_NAME: &str = "TENANTS";
+
+/// a json object of the deployment's tenants, keyed by tenant id, e.g.
+/// {"acme": {"token": "...", "max_documents": 10}}. tenant routes are
+/// refused while it isn't set
+pub const TENANTS_SECRET: &str = "SQLSYNC_TENANTS";
+
+/// the headers a tenant connection is forwarded to its coordinator with.
+/// they are refused on every other route, so they can't be forged
+pub const TENANT_HEADER: &str = "X-SQLSync-Tenant";
+pub const SUBJECT_HEADER: &str = "X-SQLSync-Subject";
+pub const TENANT_DOC_HEADER: &str = "X-SQLSync-Tenant-Doc";
+
+const REGISTRY_KEY: &str = "REGISTRY";
+
+#[derive(Deserialize)]
+struct TenantConfig {
+    token: String,
+    max_documents: Option<usize>,
+    max_storage_bytes: Option<u64>,
+    max_connections: Option<usize>,
+}
+
+/// accepts the tenant's token, identifying the connection as the tenant
+struct TokenAuth {
+    tenant: TenantId,
+    token: String,
+}
+
+impl Authenticator for TokenAuth {
+    fn authenticate(&self, credentials: &[u8]) -> Option<String> {
+        let credentials = std::str::from_utf8(credentials).ok()?;
+        constant_time_eq(credentials, &self.token)
+            .then(|| self.tenant.to_string())
+    }
+}
+
+/// a connection which the directory has counted against its tenant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantClient {
+    pub tenant: TenantId,
+    pub subject: String,
+    #[serde(serialize_with = "as_base58")]
+    pub doc: JournalId,
+}
+
+impl TenantClient {
+    /// read the connection the router forwarded, if any
+    pub fn from_headers(headers: &Headers) -> Result<Option<Self>> {
+        let (Some(tenant), Some(subject), Some(doc)) = (
+            headers.get(TENANT_HEADER)?,
+            headers.get(SUBJECT_HEADER)?,
+            headers.get(TENANT_DOC_HEADER)?,
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            tenant: TenantId::new(tenant)
+                .map_err(|e| Error::RustError(e.to_string()))?,
+            subject,
+            doc: JournalId::from_base58(&doc)
+                .map_err(|e| Error::RustError(e.to_string()))?,
+        }))
+    }
+
+    pub fn set_headers(&self, headers: &mut Headers) -> Result<()> {
+        headers.set(TENANT_HEADER, self.tenant.as_str())?;
+        headers.set(SUBJECT_HEADER, &self.subject)?;
+        headers.set(TENANT_DOC_HEADER, &self.doc.to_base58())
+    }
+
+    fn ctx(&self) -> AuthContext {
+        AuthContext {
+            tenant: self.tenant.clone(),
+            subject: self.subject.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectRequest {
+    pub tenant: String,
+    pub token: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageRequest {
+    #[serde(serialize_with = "as_base58")]
+    pub doc: JournalId,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageLimit {
+    /// the most the document's database may grow to, None if unlimited
+    pub limit: Option<u64>,
+}
+
+/// ids travel as json, which has no bytes. they are read back from base58
+/// by JournalId's Deserialize
+fn as_base58<S: Serializer>(
+    doc: &JournalId,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&doc.to_base58())
+}
+
+/// send a request to the deployment's TenantDirectory
+pub async fn tenant_request<T: Serialize>(
+    namespace: &ObjectNamespace,
+    path: &str,
+    body: &T,
+) -> Result<Response> {
+    let body = serde_wasm_bindgen::to_value(body)
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    let body = js_sys::JSON::stringify(&body)?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from(body)));
+    let req =
+        Request::new_with_init(&format!("https://tenants{}", path), &init)?;
+    let stub = namespace.id_from_name("tenants")?.get_stub()?;
+    stub.fetch_with_request(req).await
+}
+
+/// TenantDirectory is the single durable object which owns the deployment's
+/// TenantRegistry. the router asks it to authenticate and count each tenant
+/// connection before forwarding it, and coordinators report back when the
+/// connection closes and whenever their document's size changes
+#[durable_object]
+pub struct TenantDirectory {
+    state: State,
+    env: Env,
+    registry: Option<TenantRegistry>,
+}
+
+#[durable_object]
+impl DurableObject for TenantDirectory {
+    fn new(state: State, env: Env) -> Self {
+        console_error_panic_hook::set_once();
+        Self { state, env, registry: None }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        if self.registry.is_none() {
+            self.registry = Some(self.load().await?);
+        }
+        let registry = self.registry.as_mut().unwrap();
+
+        let resp = match req.path().as_str() {
+            "/connect" => {
+                let ConnectRequest { tenant, token, name } = req.json().await?;
+                let opened = TenantId::new(tenant).and_then(|tenant| {
+                    let ctx =
+                        registry.authenticate(&tenant, token.as_bytes())?;
+                    let doc = registry.open_document(&ctx, &name)?;
+                    registry.connect(&ctx, doc)?;
+                    Ok(TenantClient { tenant, subject: ctx.subject, doc })
+                });
+                match opened {
+                    Ok(client) => Response::from_json(&client),
+                    Err(err) => tenant_error(err),
+                }
+            }
+            "/disconnect" => {
+                let client: TenantClient = req.json().await?;
+                registry.disconnect(&client.ctx());
+                Response::ok("ok")
+            }
+            "/storage" => {
+                let StorageRequest { doc, bytes } = req.json().await?;
+                match registry.record_storage(doc, bytes) {
+                    Ok(limit) => Response::from_json(&StorageLimit { limit }),
+                    Err(err) => tenant_error(err),
+                }
+            }
+            _ => Response::error("Not Found", 404),
+        };
+
+        if let Some(state) = registry.take_changed() {
+            self.state.storage().put(REGISTRY_KEY, &state).await?;
+        }
+        resp
+    }
+}
+
+impl TenantDirectory {
+    /// restore the registry, and add the tenants from the deployment's
+    /// config
+    async fn load(&self) -> Result<TenantRegistry> {
+        let values = self
+            .state
+            .storage()
+            .get_multiple(vec![REGISTRY_KEY])
+            .await?;
+        let value = values.get(&REGISTRY_KEY.into());
+        let state: RegistryState = if value.is_undefined() {
+            RegistryState::default()
+        } else {
+            serde_wasm_bindgen::from_value(value).map_err(|e| {
+                Error::RustError(format!("invalid tenant registry: {}", e))
+            })?
+        };
+        let mut registry = TenantRegistry::restore(state);
+
+        let config = match self.env.secret(TENANTS_SECRET) {
+            Ok(config) => config.to_string(),
+            Err(_) => return Ok(registry),
+        };
+        let config: HashMap<String, TenantConfig> =
+            serde_wasm_bindgen::from_value(js_sys::JSON::parse(&config)?)
+                .map_err(|e| {
+                    Error::RustError(format!("invalid tenant config: {}", e))
+                })?;
+        for (tenant, config) in config {
+            let tenant = TenantId::new(tenant)
+                .map_err(|e| Error::RustError(e.to_string()))?;
+            let quota = TenantQuota {
+                max_documents: config.max_documents,
+                max_storage_bytes: config.max_storage_bytes,
+                max_connections: config.max_connections,
+            };
+            let auth =
+                TokenAuth { tenant: tenant.clone(), token: config.token };
+            registry.add_tenant(tenant, quota, Box::new(auth));
+        }
+        Ok(registry)
+    }
+}
+
+fn tenant_error(err: TenantError) -> Result<Response> {
+    let status = match err {
+        TenantError::InvalidTenantId(_)
+        | TenantError::UnknownTenant(_)
+        | TenantError::AuthFailed => 403,
+        TenantError::DocNotFound(_) => 404,
+        TenantError::QuotaExceeded { .. } => 429,
+    };
+    Response::error(err.to_string(), status)
+}
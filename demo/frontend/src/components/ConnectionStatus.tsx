@@ -10,7 +10,7 @@ export const ConnectionStatus = ({ docId }: { docId: JournalId }) => {
   const setConnectionEnabled = useSetConnectionEnabled(docId);
 
   const handleClick = useCallback(() => {
-    if (status === "disabled") {
+    if (status === "disabled" || status === "failed") {
       setConnectionEnabled(true).catch((err) => {
         console.error("Failed to enable connection", err);
       });
@@ -31,6 +31,10 @@ export const ConnectionStatus = ({ docId }: { docId: JournalId }) => {
       color = "gray";
       icon = <IconWifiOff style={{ width: rem(16), height: rem(16) }} />;
       break;
+    case "failed":
+      color = "red";
+      icon = <IconWifiOff style={{ width: rem(16), height: rem(16) }} />;
+      break;
     case "connecting":
       color = "yellow";
       loading = true;
@@ -4,7 +4,7 @@
 
 use std::cell::Cell;
 use std::ffi::{c_void, CStr, CString};
-use std::mem::{size_of, ManuallyDrop, MaybeUninit};
+use std::mem::{size_of, MaybeUninit};
 use std::os::raw::{c_char, c_int};
 use std::ptr::null_mut;
 use std::rc::Rc;
@@ -71,6 +71,68 @@ pub trait File {
         // information is written to disk in the same order as calls to xWrite()
         ffi::SQLITE_IOCAP_SEQUENTIAL
     }
+
+    /// Map region `region` of the file's shared memory (the wal-index in WAL mode), where every
+    /// region is `size` bytes. Returns None if the region doesn't exist yet and `extend` is false.
+    /// The memory must be shared by every handle of the same file, must be zeroed when first
+    /// mapped, and must stay valid until [File::shm_unmap] is called. The default implementation
+    /// doesn't support shared memory, so the file can't be used in WAL mode.
+    ///
+    /// int (*xShmMap)(sqlite3_file*, int iPg, int pgsz, int, void volatile**);
+    #[allow(unused_variables)]
+    fn shm_map(&mut self, region: usize, size: usize, extend: bool) -> VfsResult<Option<*mut u8>> {
+        Err(ffi::SQLITE_IOERR_SHMMAP)
+    }
+
+    /// Take or release `n` of the shared memory locks starting at `offset`. A lock that conflicts
+    /// with one held by another handle of the same file must fail with SQLITE_BUSY.
+    ///
+    /// int (*xShmLock)(sqlite3_file*, int offset, int n, int flags);
+    #[allow(unused_variables)]
+    fn shm_lock(&mut self, offset: usize, n: usize, lock: ShmLock) -> VfsResult<()> {
+        Err(ffi::SQLITE_IOERR_SHMLOCK)
+    }
+
+    /// Order the memory accesses made before and after the barrier.
+    ///
+    /// void (*xShmBarrier)(sqlite3_file*);
+    fn shm_barrier(&self) {
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Unmap this handle's shared memory, deleting it if `delete` is set and no other handle
+    /// has it mapped.
+    ///
+    /// int (*xShmUnmap)(sqlite3_file*, int deleteFlag);
+    #[allow(unused_variables)]
+    fn shm_unmap(&mut self, delete: bool) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+/// A request to [File::shm_lock].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmLock {
+    LockShared,
+    LockExclusive,
+    UnlockShared,
+    UnlockExclusive,
+}
+
+impl ShmLock {
+    fn from_flags(flags: i32) -> Option<Self> {
+        let lock = flags & ffi::SQLITE_SHM_LOCK > 0;
+        let unlock = flags & ffi::SQLITE_SHM_UNLOCK > 0;
+        let shared = flags & ffi::SQLITE_SHM_SHARED > 0;
+        let exclusive = flags & ffi::SQLITE_SHM_EXCLUSIVE > 0;
+        match (lock, unlock, shared, exclusive) {
+            (true, false, true, false) => Some(Self::LockShared),
+            (true, false, false, true) => Some(Self::LockExclusive),
+            (false, true, true, false) => Some(Self::UnlockShared),
+            (false, true, false, true) => Some(Self::UnlockExclusive),
+            _ => None,
+        }
+    }
 }
 
 /// Allow boxing files, so you can easily return different optimized impls depending on OpenKind
@@ -104,6 +166,22 @@ impl File for Box<dyn File> {
     fn sync(&mut self) -> VfsResult<()> {
         self.as_mut().sync()
     }
+
+    fn shm_map(&mut self, region: usize, size: usize, extend: bool) -> VfsResult<Option<*mut u8>> {
+        self.as_mut().shm_map(region, size, extend)
+    }
+
+    fn shm_lock(&mut self, offset: usize, n: usize, lock: ShmLock) -> VfsResult<()> {
+        self.as_mut().shm_lock(offset, n, lock)
+    }
+
+    fn shm_barrier(&self) {
+        self.as_ref().shm_barrier()
+    }
+
+    fn shm_unmap(&mut self, delete: bool) -> VfsResult<()> {
+        self.as_mut().shm_unmap(delete)
+    }
 }
 
 /// Allow File to be an unsafe pointer
@@ -115,6 +193,14 @@ impl<T: File> FilePtr<T> {
     }
 }
 
+impl<T: File> std::ops::Deref for FilePtr<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
 impl<T: File> Clone for FilePtr<T> {
     fn clone(&self) -> Self {
         Self(self.0)
@@ -149,6 +235,22 @@ impl<T: File> File for FilePtr<T> {
     fn sync(&mut self) -> VfsResult<()> {
         unsafe { (*self.0).sync() }
     }
+
+    fn shm_map(&mut self, region: usize, size: usize, extend: bool) -> VfsResult<Option<*mut u8>> {
+        unsafe { (*self.0).shm_map(region, size, extend) }
+    }
+
+    fn shm_lock(&mut self, offset: usize, n: usize, lock: ShmLock) -> VfsResult<()> {
+        unsafe { (*self.0).shm_lock(offset, n, lock) }
+    }
+
+    fn shm_barrier(&self) {
+        unsafe { (*self.0).shm_barrier() }
+    }
+
+    fn shm_unmap(&mut self, delete: bool) -> VfsResult<()> {
+        unsafe { (*self.0).shm_unmap(delete) }
+    }
 }
 
 /// A sqlite vfs
@@ -263,9 +365,13 @@ struct State<V> {
     last_error: Rc<Cell<Option<VfsError>>>,
 }
 
-/// Register a virtual file system ([Vfs]) to SQLite.
-pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), RegisterError> {
-    let name = ManuallyDrop::new(CString::new(name)?);
+/// Register a virtual file system ([Vfs]) to SQLite. It stays registered until the returned
+/// [Registration] is dropped.
+pub fn register<F: File, V: Vfs<File = F>>(
+    name: &str,
+    vfs: V,
+) -> Result<Registration, RegisterError> {
+    let name = CString::new(name)?.into_raw();
     let io_methods = ffi::sqlite3_io_methods {
         iVersion: 3,
         xClose: Some(io::close::<F>),
@@ -282,7 +388,7 @@ pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), Reg
         xDeviceCharacteristics: Some(io::device_characteristics::<F>),
         xShmMap: Some(io::shm_map::<F>),
         xShmLock: Some(io::shm_lock::<F>),
-        xShmBarrier: Some(io::shm_barrier),
+        xShmBarrier: Some(io::shm_barrier::<F>),
         xShmUnmap: Some(io::shm_unmap::<F>),
         xFetch: Some(io::mem_fetch::<F>),
         xUnfetch: Some(io::mem_unfetch::<F>),
@@ -298,7 +404,7 @@ pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), Reg
         szOsFile: size_of::<FileState<F>>() as i32,
         mxPathname: MAX_PATH_LENGTH as i32, // max path length supported by VFS
         pNext: null_mut(),
-        zName: name.as_ptr(),
+        zName: name,
         pAppData: ptr as _,
         xOpen: Some(vfs::open::<F, V>),
         xDelete: Some(vfs::delete::<V>),
@@ -318,14 +424,57 @@ pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), Reg
         xNextSystemCall: None,
     }));
 
+    // frees the vfs if registering it fails
+    let mut registration = Registration {
+        vfs,
+        free_state: free_state::<V>,
+        registered: false,
+    };
     let result = unsafe { ffi::sqlite3_vfs_register(vfs, false as i32) };
     if result != ffi::SQLITE_OK {
         return Err(RegisterError::Register(result));
     }
+    registration.registered = true;
+    Ok(registration)
+}
+
+/// A [Vfs] registered with [register]. Dropping it unregisters the vfs and frees it, so every
+/// connection using the vfs must be closed (or have detached the databases it opened with it)
+/// first.
+#[must_use = "the vfs is unregistered when the registration is dropped"]
+pub struct Registration {
+    vfs: *mut ffi::sqlite3_vfs,
+    free_state: unsafe fn(*mut c_void),
+    registered: bool,
+}
 
-    // TODO: return object that allows to unregister (and cleanup the memory)?
+// the vfs is only used by sqlite connections, which may be sent between threads
+unsafe impl Send for Registration {}
 
-    Ok(())
+impl Registration {
+    /// The name the vfs is registered under.
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr((*self.vfs).zName) }
+            .to_str()
+            .expect("vfs names are registered from a &str")
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        unsafe {
+            if self.registered {
+                ffi::sqlite3_vfs_unregister(self.vfs);
+            }
+            let vfs = Box::from_raw(self.vfs);
+            drop(CString::from_raw(vfs.zName as *mut c_char));
+            (self.free_state)(vfs.pAppData);
+        }
+    }
+}
+
+unsafe fn free_state<V>(state: *mut c_void) {
+    drop(Box::from_raw(state as *mut State<V>));
 }
 
 // TODO: add to [Vfs]?
@@ -357,10 +506,13 @@ mod vfs {
         flags: c_int,
         _p_out_flags: *mut c_int,
     ) -> c_int {
-        if z_name.is_null() {
-            return ffi::SQLITE_CANTOPEN;
-        }
-        let name = CStr::from_ptr(z_name).to_string_lossy();
+        // sqlite passes a null name when it wants an anonymous temporary file
+        let path = if z_name.is_null() {
+            CStr::from_bytes_with_nul_unchecked(b"\0")
+        } else {
+            CStr::from_ptr(z_name)
+        };
+        let name = path.to_string_lossy();
         log::trace!("open z_name={:?} flags={}", name, flags);
 
         let state = match vfs_state::<V>(p_vfs) {
@@ -369,8 +521,6 @@ mod vfs {
         };
         state.last_error.take();
 
-        let path = CStr::from_ptr(z_name);
-
         let opts = match OpenOptions::from_flags(flags) {
             Some(opts) => opts,
             None => {
@@ -684,6 +834,10 @@ mod io {
         let data = slice::from_raw_parts(z as *mut u8, i_amt as usize);
         if let Err(err) = state.file.write(i_ofst as u64, data) {
             state.set_last_error(err);
+            // let sqlite distinguish a full file from a failed write
+            if err == ffi::SQLITE_FULL {
+                return ffi::SQLITE_FULL;
+            }
             return ffi::SQLITE_IOERR_WRITE;
         }
 
@@ -848,58 +1002,97 @@ mod io {
     }
 
     /// Create a shared memory file mapping.
-    pub unsafe extern "C" fn shm_map<F>(
+    pub unsafe extern "C" fn shm_map<F: File>(
         p_file: *mut ffi::sqlite3_file,
         i_pg: i32,
         pgsz: i32,
         b_extend: i32,
-        _pp: *mut *mut c_void,
+        pp: *mut *mut c_void,
     ) -> i32 {
         log::trace!("shm_map pg={} sz={} extend={}", i_pg, pgsz, b_extend);
 
-        // reset last error
-        if file_state::<F>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
-        }
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMMAP,
+        };
+        let pp = match pp.as_mut() {
+            Some(pp) => pp,
+            None => {
+                state.set_last_error(null_ptr_error());
+                return ffi::SQLITE_IOERR_SHMMAP;
+            }
+        };
 
-        ffi::SQLITE_IOERR_SHMMAP
+        let extend = b_extend != 0;
+        match state.file.shm_map(i_pg as usize, pgsz as usize, extend) {
+            Ok(region) => {
+                *pp = region.map_or(null_mut(), |region| region as *mut c_void);
+                ffi::SQLITE_OK
+            }
+            Err(err) => {
+                *pp = null_mut();
+                state.set_last_error(err);
+                err
+            }
+        }
     }
 
     /// Perform locking on a shared-memory segment.
-    pub unsafe extern "C" fn shm_lock<F>(
+    pub unsafe extern "C" fn shm_lock<F: File>(
         p_file: *mut ffi::sqlite3_file,
-        _offset: i32,
-        _n: i32,
-        _flags: i32,
+        offset: i32,
+        n: i32,
+        flags: i32,
     ) -> i32 {
-        log::trace!("shm_lock");
+        log::trace!("shm_lock offset={} n={} flags={}", offset, n, flags);
 
-        // reset last error
-        if file_state::<F>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
-        }
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMLOCK,
+        };
+        let Some(lock) = ShmLock::from_flags(flags) else {
+            return ffi::SQLITE_IOERR_SHMLOCK;
+        };
 
-        ffi::SQLITE_IOERR_SHMLOCK
+        match state.file.shm_lock(offset as usize, n as usize, lock) {
+            Ok(()) => ffi::SQLITE_OK,
+            // sqlite retries busy locks, so they aren't errors
+            Err(ffi::SQLITE_BUSY) => ffi::SQLITE_BUSY,
+            Err(err) => {
+                state.set_last_error(err);
+                err
+            }
+        }
     }
 
     /// Memory barrier operation on shared memory.
-    pub unsafe extern "C" fn shm_barrier(_p_file: *mut ffi::sqlite3_file) {
+    pub unsafe extern "C" fn shm_barrier<F: File>(p_file: *mut ffi::sqlite3_file) {
         log::trace!("shm_barrier");
+
+        if let Ok(state) = file_state::<F>(p_file, false) {
+            state.file.shm_barrier();
+        }
     }
 
     /// Unmap a shared memory segment.
-    pub unsafe extern "C" fn shm_unmap<F>(
+    pub unsafe extern "C" fn shm_unmap<F: File>(
         p_file: *mut ffi::sqlite3_file,
-        _delete_flags: i32,
+        delete_flags: i32,
     ) -> i32 {
-        log::trace!("shm_unmap");
+        log::trace!("shm_unmap delete={}", delete_flags);
 
-        // reset last error
-        if file_state::<F>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_SHMMAP;
-        }
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_SHMMAP,
+        };
 
-        ffi::SQLITE_OK
+        match state.file.shm_unmap(delete_flags != 0) {
+            Ok(()) => ffi::SQLITE_OK,
+            Err(err) => {
+                state.set_last_error(err);
+                ffi::SQLITE_IOERR_SHMMAP
+            }
+        }
     }
 
     /// Fetch a page of a memory-mapped file.
@@ -0,0 +1,75 @@
+import { ColumnData, SqlValue, ValueKind } from "@orbitinghail/sqlsync-worker";
+
+const UTF8Decoder = new TextDecoder();
+
+// a query's results as the typed arrays the worker built them in. reading a
+// value decodes just that value, so large results can be scanned or handed
+// to a chart without building an object per row
+export class ColumnarResult {
+  readonly columns: string[];
+  readonly numRows: number;
+  // whether the results include the effects of drafts, see mutateDraft
+  readonly hasDrafts: boolean;
+  readonly #data: ColumnData[];
+
+  constructor(columns: string[], numRows: number, data: ColumnData[], hasDrafts = false) {
+    this.columns = columns;
+    this.numRows = numRows;
+    this.hasDrafts = hasDrafts;
+    this.#data = data;
+  }
+
+  value(row: number, col: number | string): SqlValue {
+    const data = this.#column(col);
+    const bytes = () => data.bytes.subarray(data.offsets[row], data.offsets[row + 1]);
+    switch (data.kinds[row]) {
+      case ValueKind.Null:
+        return null;
+      case ValueKind.Number:
+        return data.numbers[row];
+      case ValueKind.BigInt:
+        return BigInt(UTF8Decoder.decode(bytes()));
+      case ValueKind.Text:
+        return UTF8Decoder.decode(bytes());
+      case ValueKind.Blob:
+        return bytes();
+      default:
+        throw new Error(`unknown value kind ${data.kinds[row]}`);
+    }
+  }
+
+  // a column's numbers without copying them; any other value reads as NaN
+  numbers(col: number | string): Float64Array {
+    return this.#column(col).numbers;
+  }
+
+  column(col: number | string): SqlValue[] {
+    const values = new Array<SqlValue>(this.numRows);
+    for (let row = 0; row < this.numRows; row++) {
+      values[row] = this.value(row, col);
+    }
+    return values;
+  }
+
+  // an object per row, like SQLSync.query returns
+  rows<T extends Record<string, SqlValue> = Record<string, SqlValue>>(): T[] {
+    const rows = new Array<T>(this.numRows);
+    for (let row = 0; row < this.numRows; row++) {
+      const obj: Record<string, SqlValue> = {};
+      this.columns.forEach((name, col) => {
+        obj[name] = this.value(row, col);
+      });
+      rows[row] = obj as T;
+    }
+    return rows;
+  }
+
+  #column(col: number | string): ColumnData {
+    const idx = typeof col === "number" ? col : this.columns.indexOf(col);
+    const data = this.#data[idx];
+    if (!data) {
+      throw new Error(`no column ${col}`);
+    }
+    return data;
+  }
+}
@@ -0,0 +1,18 @@
+import { ErrorCode, ErrorInfo } from "@orbitinghail/sqlsync-worker";
+
+// the worker's errors reach the app as SQLSyncErrors. branch on code, which
+// stays stable across releases, rather than on the message; info holds the
+// context fields for codes which have them
+export class SQLSyncError extends Error {
+  readonly code: ErrorCode;
+  readonly retryable: boolean;
+  readonly info: ErrorInfo;
+
+  constructor(info: ErrorInfo) {
+    super(info.message);
+    this.name = "SQLSyncError";
+    this.code = info.code;
+    this.retryable = info.retryable;
+    this.info = info;
+  }
+}
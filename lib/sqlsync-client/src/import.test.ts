@@ -0,0 +1,199 @@
+import { DocId, ErrorInfo, SqlValue, randomJournalId } from "@orbitinghail/sqlsync-worker";
+import { describe, expect, it } from "vitest";
+import { SQLSyncError } from "./error";
+import { ImportBatch, ImportError, ImportFormat, ImportSource, importBatches } from "./import";
+import { DocType, SQLSync } from "./sqlsync";
+
+const docType = {} as DocType<unknown>;
+
+const collect = async (
+  format: ImportFormat,
+  source: ImportSource,
+  opts?: { maxBytes?: number; columns?: string[] },
+): Promise<ImportBatch[]> => {
+  const batches: ImportBatch[] = [];
+  const maxBytes = opts?.maxBytes ?? Infinity;
+  for await (const batch of importBatches("t", format, source, () => maxBytes, opts?.columns)) {
+    batches.push(batch);
+  }
+  return batches;
+};
+
+const rows = async (format: ImportFormat, source: ImportSource, columns?: string[]) =>
+  (await collect(format, source, { columns })).flatMap((batch) => batch.rows);
+
+// a stream which splits its text into chunks of chunkBytes, so records and
+// characters straddle chunks
+const chunked = (text: string, chunkBytes: number): ReadableStream<Uint8Array> => {
+  const bytes = new TextEncoder().encode(text);
+  let offset = 0;
+  return new ReadableStream({
+    pull(controller) {
+      if (offset >= bytes.length) {
+        controller.close();
+        return;
+      }
+      controller.enqueue(bytes.slice(offset, offset + chunkBytes));
+      offset += chunkBytes;
+    },
+  });
+};
+
+describe("importBatches", () => {
+  it("reads csv records under their header", async () => {
+    const [batch] = await collect("csv", "id,name\r\n1,ada\n2,\n\n3,grace\n");
+    expect(batch.table).toBe("t");
+    expect(batch.columns).toEqual(["id", "name"]);
+    // fields are text, and empty unquoted fields are null
+    expect(batch.rows).toEqual([
+      ["1", "ada"],
+      ["2", null],
+      ["3", "grace"],
+    ]);
+  });
+
+  it("reads quoted csv fields", async () => {
+    const csv = 'a,b\n"x, y","say ""hi"""\n"",\n"two\nlines",z';
+    expect(await rows("csv", csv)).toEqual([
+      ["x, y", 'say "hi"'],
+      ["", null],
+      ["two\nlines", "z"],
+    ]);
+  });
+
+  it("reads csv without a header when columns are given", async () => {
+    const [batch] = await collect("csv", "1,2\n", { columns: ["a", "b"] });
+    expect(batch.columns).toEqual(["a", "b"]);
+    expect(batch.rows).toEqual([["1", "2"]]);
+  });
+
+  it("refuses malformed csv", async () => {
+    await expect(rows("csv", "a,b\n1,2,3\n")).rejects.toThrow("csv record has 3 fields, expected 2");
+    await expect(rows("csv", 'a\n"open')).rejects.toThrow("csv ends inside a quoted field");
+  });
+
+  it("converts ndjson values", async () => {
+    const ndjson = [
+      '{"n": 1, "s": "a", "b": true, "o": {"k": [1]}, "z": null}',
+      "",
+      '{"n": 2.5, "b": false}',
+    ].join("\n");
+    const [batch] = await collect("ndjson", ndjson);
+    // the first record's keys are the columns, and missing keys are null
+    expect(batch.columns).toEqual(["n", "s", "b", "o", "z"]);
+    expect(batch.rows).toEqual([
+      [1, "a", 1, '{"k":[1]}', null],
+      [2.5, null, 0, null, null],
+    ]);
+  });
+
+  it("refuses ndjson keys which aren't columns", async () => {
+    const ndjson = '{"a": 1}\n{"a": 2, "b": 3}\n';
+    await expect(rows("ndjson", ndjson)).rejects.toThrow('ndjson record has key "b"');
+    await expect(rows("ndjson", '{"a": 1, "c": 2}', ["a", "b"])).rejects.toThrow(
+      'ndjson record has key "c"',
+    );
+    expect(await rows("ndjson", '{"b": 1}', ["a", "b"])).toEqual([[null, 1]]);
+  });
+
+  it("refuses ndjson records which aren't objects", async () => {
+    await expect(rows("ndjson", "[1, 2]")).rejects.toThrow("ndjson records must be objects");
+  });
+
+  it("reads records which straddle chunks", async () => {
+    const csv = "name,city\nzoë,zürich\n\"a,\n\",b\n";
+    const ndjson = '{"name": "zoë"}\n{"name": "café"}\n';
+    for (const chunkBytes of [1, 2, 3, 7]) {
+      expect(await rows("csv", chunked(csv, chunkBytes))).toEqual([
+        ["zoë", "zürich"],
+        ["a,\n", "b"],
+      ]);
+      expect(await rows("ndjson", chunked(ndjson, chunkBytes))).toEqual([["zoë"], ["café"]]);
+    }
+  });
+
+  it("cuts batches at maxBytes", async () => {
+    const csv = `v\n${Array.from({ length: 10 }, (_, i) => `row${i}`).join("\n")}\n`;
+    // each value is about 12 bytes, so three fit in a batch
+    const batches = await collect("csv", new Blob([csv]), { maxBytes: 36 });
+    expect(batches.map((batch) => batch.rows.length)).toEqual([3, 3, 3, 1]);
+    expect(batches.flatMap((batch) => batch.rows).map(([v]) => v)).toEqual(
+      Array.from({ length: 10 }, (_, i) => `row${i}`),
+    );
+    // a row larger than maxBytes is a batch of its own
+    expect((await collect("csv", "v\nlong value\nx\n", { maxBytes: 1 })).length).toBe(2);
+  });
+});
+
+// a SQLSync which only records mutations, refusing those with more than
+// maxRows rows as too large
+class FakeSQLSync {
+  mutations: SqlValue[][][] = [];
+  maxRows = Infinity;
+  failAfter = Infinity;
+
+  mutate(_docId: DocId, _docType: DocType<unknown>, batch: ImportBatch): Promise<undefined> {
+    if (batch.rows.length > this.maxRows) {
+      return Promise.reject(
+        new SQLSyncError({
+          code: "MUTATION_TOO_LARGE",
+          message: "mutation is too large",
+          retryable: false,
+          limit: 64,
+        } as ErrorInfo),
+      );
+    }
+    if (this.mutations.length >= this.failAfter) {
+      return Promise.reject(new Error("storage is full"));
+    }
+    this.mutations.push(batch.rows);
+    return Promise.resolve(undefined);
+  }
+}
+
+const runImport = (fake: FakeSQLSync, source: ImportSource) =>
+  SQLSync.prototype.import.call(
+    fake as unknown as SQLSync,
+    randomJournalId(),
+    docType,
+    "t",
+    "csv",
+    source,
+    { mutation: (batch: ImportBatch) => batch },
+  );
+
+describe("SQLSync.import", () => {
+  const csv = `v\n${Array.from({ length: 8 }, (_, i) => i).join("\n")}\n`;
+
+  it("sends a mutation per batch", async () => {
+    const fake = new FakeSQLSync();
+    expect(await runImport(fake, csv)).toEqual({ rows: 8, mutations: 1 });
+    expect(fake.mutations).toHaveLength(1);
+  });
+
+  it("splits batches which are too large", async () => {
+    const fake = new FakeSQLSync();
+    fake.maxRows = 3;
+    expect(await runImport(fake, csv)).toEqual({ rows: 8, mutations: 4 });
+    expect(fake.mutations.flat().map(([v]) => v)).toEqual(
+      Array.from({ length: 8 }, (_, i) => `${i}`),
+    );
+  });
+
+  it("counts the rows imported before a failure", async () => {
+    const fake = new FakeSQLSync();
+    fake.maxRows = 2;
+    fake.failAfter = 1;
+    const err = await runImport(fake, csv).catch((e) => e);
+    expect(err).toBeInstanceOf(ImportError);
+    expect(err.imported).toEqual({ rows: 2, mutations: 1 });
+    expect(err.cause).toEqual(new Error("storage is full"));
+  });
+
+  it("stops when the source is malformed, keeping what was sent", async () => {
+    const fake = new FakeSQLSync();
+    const err = await runImport(fake, "v\n1\n2,3\n").catch((e) => e);
+    expect(err).toBeInstanceOf(ImportError);
+    expect(err.imported).toEqual({ rows: 0, mutations: 0 });
+  });
+});
@@ -0,0 +1,244 @@
+import { SqlValue } from "@orbitinghail/sqlsync-worker";
+import type { AbortOptions } from "./sqlsync";
+
+export type ImportFormat = "csv" | "ndjson";
+
+export type ImportSource = ReadableStream<Uint8Array> | Blob | string;
+
+// rows for the reducer to insert into table, in the order of columns
+export interface ImportBatch {
+  table: string;
+  columns: string[];
+  rows: SqlValue[][];
+}
+
+export interface ImportOptions<M> extends AbortOptions {
+  // the mutation which inserts a batch of rows. reducers can insert them
+  // with sqlsync_reducer::guest_reactor::insert_rows
+  mutation: (batch: ImportBatch) => M;
+  // the columns of each record. by default they are read from the csv
+  // header, or the keys of the first ndjson record. an ndjson record with a
+  // key which isn't a column fails the import, and missing keys are null
+  columns?: string[];
+  // batches are cut once their rows are about this large. a batch which
+  // turns out larger than the document's mutation limit is split and retried
+  maxMutationBytes?: number;
+}
+
+export interface ImportResult {
+  rows: number;
+  mutations: number;
+}
+
+// an import is many mutations, each of which is applied on its own. so an
+// import which fails part way rejects with an ImportError, whose imported
+// counts the rows which were applied before the failure
+export class ImportError extends Error {
+  readonly cause: unknown;
+  readonly imported: ImportResult;
+
+  constructor(cause: unknown, imported: ImportResult) {
+    super(`import failed after ${imported.rows} rows: ${String(cause)}`);
+    this.name = "ImportError";
+    this.cause = cause;
+    this.imported = imported;
+  }
+}
+
+export const DEFAULT_IMPORT_MUTATION_BYTES = 256 * 1024;
+
+// reads source as batches of rows which are each about maxBytes() large.
+// csv fields are text, except empty unquoted fields which are null; ndjson
+// values are kept, except booleans become 1 or 0 and objects and arrays
+// become their json
+export async function* importBatches(
+  table: string,
+  format: ImportFormat,
+  source: ImportSource,
+  maxBytes: () => number,
+  columns?: string[],
+): AsyncGenerator<ImportBatch> {
+  const text = decode(toStream(source));
+  const rows = format === "csv" ? csvRows(text, columns) : ndjsonRows(text, columns);
+
+  let batch: SqlValue[][] = [];
+  let batchColumns: string[] = [];
+  let batchBytes = 0;
+  for await (const [cols, row] of rows) {
+    const size = row.reduce((sum: number, v) => sum + valueSize(v), 0);
+    if (batch.length > 0 && batchBytes + size > maxBytes()) {
+      yield { table, columns: batchColumns, rows: batch };
+      batch = [];
+      batchBytes = 0;
+    }
+    batch.push(row);
+    batchColumns = cols;
+    batchBytes += size;
+  }
+  if (batch.length > 0) {
+    yield { table, columns: batchColumns, rows: batch };
+  }
+}
+
+function toStream(source: ImportSource): ReadableStream<Uint8Array> {
+  if (typeof source === "string") {
+    return new Blob([source]).stream();
+  }
+  if (source instanceof Blob) {
+    return source.stream();
+  }
+  return source;
+}
+
+async function* decode(stream: ReadableStream<Uint8Array>): AsyncGenerator<string> {
+  const decoder = new TextDecoder();
+  const reader = stream.getReader();
+  try {
+    for (;;) {
+      const { done, value } = await reader.read();
+      if (done) break;
+      yield decoder.decode(value, { stream: true });
+    }
+    yield decoder.decode();
+  } finally {
+    reader.releaseLock();
+  }
+}
+
+// a rough size of the value once serialized in a mutation
+function valueSize(value: SqlValue): number {
+  if (value === null) return 1;
+  if (typeof value === "string") return value.length + 8;
+  if (value instanceof Uint8Array) return value.byteLength + 8;
+  return 9;
+}
+
+// rfc 4180 records, with the header as columns unless they are given
+async function* csvRows(
+  text: AsyncIterable<string>,
+  header?: string[],
+): AsyncGenerator<[string[], SqlValue[]]> {
+  let columns = header;
+  let record: SqlValue[] = [];
+  let field = "";
+  let quoted = false;
+  let inQuotes = false;
+  let quotePending = false;
+
+  const endField = () => {
+    record.push(field === "" && !quoted ? null : field);
+    field = "";
+    quoted = false;
+  };
+  // a blank line is no record
+  const endRecord = (): SqlValue[] | undefined => {
+    const done = record;
+    record = [];
+    return done.length === 1 && done[0] === null ? undefined : done;
+  };
+  const emit = function* (row: SqlValue[] | undefined): Generator<[string[], SqlValue[]]> {
+    if (!row) return;
+    if (!columns) {
+      columns = row.map((name) => name ?? "");
+      return;
+    }
+    if (row.length !== columns.length) {
+      throw new Error(`csv record has ${row.length} fields, expected ${columns.length}`);
+    }
+    yield [columns, row];
+  };
+
+  for await (const chunk of text) {
+    for (const c of chunk) {
+      if (inQuotes) {
+        if (quotePending) {
+          quotePending = false;
+          if (c === '"') {
+            field += c;
+            continue;
+          }
+          inQuotes = false;
+        } else {
+          if (c === '"') {
+            quotePending = true;
+          } else {
+            field += c;
+          }
+          continue;
+        }
+      }
+      if (c === '"' && field === "" && !quoted) {
+        inQuotes = true;
+        quoted = true;
+      } else if (c === ",") {
+        endField();
+      } else if (c === "\n") {
+        endField();
+        yield* emit(endRecord());
+      } else if (c !== "\r") {
+        field += c;
+      }
+    }
+  }
+  if (inQuotes && !quotePending) {
+    throw new Error("csv ends inside a quoted field");
+  }
+  if (field !== "" || quoted || record.length > 0) {
+    endField();
+    yield* emit(endRecord());
+  }
+}
+
+async function* ndjsonRows(
+  text: AsyncIterable<string>,
+  header?: string[],
+): AsyncGenerator<[string[], SqlValue[]]> {
+  let columns = header;
+  // records may only have keys which are columns
+  let known = header && new Set(header);
+  let rest = "";
+  const parse = (line: string): [string[], SqlValue[]] | undefined => {
+    if (line.trim() === "") return;
+    const record: unknown = JSON.parse(line);
+    if (typeof record !== "object" || record === null || Array.isArray(record)) {
+      throw new Error("ndjson records must be objects");
+    }
+    const values = record as Record<string, unknown>;
+    if (!columns || !known) {
+      columns = Object.keys(values);
+      known = new Set(columns);
+    }
+    const [cols, keys] = [columns, known];
+    const unknown = Object.keys(values).find((key) => !keys.has(key));
+    if (unknown !== undefined) {
+      throw new Error(`ndjson record has key "${unknown}", which isn't one of the columns`);
+    }
+    return [cols, cols.map((col) => toSqlValue(values[col]))];
+  };
+
+  for await (const chunk of text) {
+    const lines = (rest + chunk).split("\n");
+    rest = lines.pop() ?? "";
+    for (const line of lines) {
+      const row = parse(line);
+      if (row) yield row;
+    }
+  }
+  const row = parse(rest);
+  if (row) yield row;
+}
+
+function toSqlValue(value: unknown): SqlValue {
+  switch (typeof value) {
+    case "undefined":
+      return null;
+    case "number":
+    case "string":
+    case "bigint":
+      return value;
+    case "boolean":
+      return value ? 1 : 0;
+    default:
+      return value === null ? null : JSON.stringify(value);
+  }
+}
@@ -0,0 +1,53 @@
+import { ColumnarResult } from "./columnar";
+import { SQLSyncError } from "./error";
+import {
+  ImportBatch,
+  ImportError,
+  ImportFormat,
+  ImportOptions,
+  ImportResult,
+  ImportSource,
+} from "./import";
+import { ParameterizedQuery, normalizeQuery, sql } from "./sql";
+import {
+  AbortOptions,
+  DocType,
+  DocumentHandle,
+  QuerySubscription,
+  Row,
+  SQLSync,
+  SQLSyncOptions,
+} from "./sqlsync";
+import { QueryEntry, QueryState, QueryStore, Readable, queryStoreFor } from "./store";
+import { serializeMutationAsJSON } from "./util";
+
+export {
+  ColumnarResult,
+  DocumentHandle,
+  ImportError,
+  QueryEntry,
+  QueryStore,
+  SQLSync,
+  SQLSyncError,
+  normalizeQuery,
+  queryStoreFor,
+  serializeMutationAsJSON,
+  sql,
+};
+export type {
+  AbortOptions,
+  DocType,
+  ImportBatch,
+  ImportFormat,
+  ImportOptions,
+  ImportResult,
+  ImportSource,
+  ParameterizedQuery,
+  QueryState,
+  QuerySubscription,
+  Readable,
+  Row,
+  SQLSyncOptions,
+};
+
+// eof: this file only exports
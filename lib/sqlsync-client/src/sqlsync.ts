@@ -0,0 +1,928 @@
+import {
+  ConnectionStatus,
+  DocEvent,
+  DocId,
+  DocReply,
+  ExportFormat,
+  ExportSource,
+  HandlerId,
+  MutationReceipt,
+  NetworkStats,
+  OpenOptions,
+  QueryKey,
+  ReducerTrapInfo,
+  SqlValue,
+  SyncHealth,
+  SyncPriority,
+  WorkerRequest,
+  WorkerToHostMsg,
+  PROTOCOL_VERSION,
+  journalIdToString,
+} from "@orbitinghail/sqlsync-worker";
+import { ColumnarResult } from "./columnar";
+import { SQLSyncError } from "./error";
+import {
+  DEFAULT_IMPORT_MUTATION_BYTES,
+  ImportBatch,
+  ImportError,
+  ImportFormat,
+  ImportOptions,
+  ImportResult,
+  ImportSource,
+  importBatches,
+} from "./import";
+import { ParameterizedQuery, toQueryKey } from "./sql";
+import { NarrowTaggedEnum, OmitUnion, abortable, assertUnreachable, initWorker } from "./util";
+
+export type Row = Record<string, SqlValue>;
+
+export interface DocType<Mutation> {
+  readonly reducerUrl: string | URL;
+  readonly serializeMutation: (mutation: Mutation) => Uint8Array;
+  // whether queries may read the document before it syncs, and whether
+  // opening it waits for the sync
+  readonly openOptions?: OpenOptions;
+}
+
+type DocReplyTag = DocReply["tag"];
+type SelectDocReply<T> = NarrowTaggedEnum<DocReply, T>;
+
+export interface QuerySubscription {
+  // hasDrafts is set when the rows include the effects of drafts, which may
+  // never sync (see mutateDraft)
+  handleRows: (rows: Row[], hasDrafts: boolean) => void;
+  handleErr: (err: SQLSyncError) => void;
+}
+
+export interface SQLSyncOptions {
+  // once the documents open in the worker hold more than this many bytes,
+  // the least recently used idle ones are evicted from memory and reloaded
+  // the next time they're used
+  memoryBudgetBytes?: number;
+  // replicate every document over a single connection to the coordinator,
+  // rather than one connection per document. the coordinator must serve
+  // multiplexed connections at /mux
+  multiplex?: boolean;
+}
+
+// a long running request (an open waiting for the initial sync, a large
+// query, a wait for a token) can be aborted through its signal. the promise
+// rejects with the signal's reason, and the worker drops the request
+export interface AbortOptions {
+  signal?: AbortSignal;
+}
+
+// the worker doesn't reply to a close, so it needs no handler
+const CLOSE_REQUEST: WorkerRequest = { tag: "Close", handlerId: 0 };
+
+const nextHandlerId = (() => {
+  let handlerId = 0;
+  return () => handlerId++;
+})();
+
+const nextExportId = (() => {
+  let exportId = 0;
+  return () => exportId++;
+})();
+
+export class SQLSync {
+  #port: MessagePort;
+  #openDocs = new Set<DocId>();
+  #pendingOpens = new Map<DocId, Promise<{ tag: "Ack" }>>();
+  #msgHandlers = new Map<HandlerId, (msg: DocReply) => void>();
+  #querySubscriptions = new Map<QueryKey, QuerySubscription[]>();
+  #connectionStatus: ConnectionStatus = "disconnected";
+  #connectionStatusListeners = new Set<(status: ConnectionStatus) => void>();
+  #reducerErrorListeners = new Set<(docId: DocId, err: string, trap?: ReducerTrapInfo) => void>();
+  #syncHealthListeners = new Set<(docId: DocId, health: SyncHealth) => void>();
+  #updateRequiredListeners = new Set<(docId: DocId, reducerDigest: string) => void>();
+  #reducerUpdatedListeners = new Set<(docId: DocId, reducerDigest: string) => void>();
+
+  constructor(
+    workerUrl: string | URL,
+    wasmUrl: string | URL,
+    coordinatorUrl?: string | URL,
+    options?: SQLSyncOptions,
+  ) {
+    this.#msgHandlers = new Map();
+    const port = initWorker(workerUrl);
+    this.#port = port;
+
+    // We use a WeakRef here to avoid a circular reference between this.port and this.
+    // This allows the SQLSync object to be garbage collected when it is no longer needed.
+    const weakThis = new WeakRef(this);
+    this.#port.onmessage = (msg) => {
+      const thisRef = weakThis.deref();
+      if (thisRef) {
+        thisRef.#handleMessage(msg);
+      } else {
+        console.log(
+          "sqlsync: dropping message; sqlsync object has been garbage collected",
+          msg.data,
+        );
+        // clean up the port
+        port.postMessage(CLOSE_REQUEST);
+        port.onmessage = null;
+        return;
+      }
+    };
+
+    this.#boot(wasmUrl.toString(), coordinatorUrl?.toString(), options).catch((err) => {
+      // TODO: expose this error to the app in a nicer way
+      // probably through some event handlers on the SQLSync object
+      console.error("sqlsync boot failed", err);
+      throw err;
+    });
+  }
+
+  close() {
+    this.#port.onmessage = null;
+    this.#port.postMessage(CLOSE_REQUEST);
+  }
+
+  #handleMessage(event: MessageEvent) {
+    const msg = event.data as WorkerToHostMsg;
+
+    if (msg.tag === "Reply") {
+      console.log("sqlsync: received reply", msg.handlerId, msg.reply);
+      const handler = this.#msgHandlers.get(msg.handlerId);
+      if (handler) {
+        handler(msg.reply);
+      } else {
+        console.error("sqlsync: no handler for message", msg);
+        throw new Error("no handler for message");
+      }
+    } else if (msg.tag === "Event") {
+      this.#handleDocEvent(msg.docId, msg.evt);
+    } else {
+      assertUnreachable("unknown message", msg);
+    }
+  }
+
+  #handleDocEvent(docId: DocId, evt: DocEvent) {
+    console.log(`sqlsync: doc ${journalIdToString(docId)} received event`, evt);
+    if (evt.tag === "ConnectionStatus") {
+      this.#connectionStatus = evt.status;
+      for (const listener of this.#connectionStatusListeners) {
+        listener(evt.status);
+      }
+    } else if (evt.tag === "SubscriptionChanged") {
+      const subscriptions = this.#querySubscriptions.get(evt.key);
+      if (subscriptions) {
+        for (const subscription of subscriptions) {
+          subscription.handleRows(evt.rows, evt.hasDrafts);
+        }
+      }
+    } else if (evt.tag === "SubscriptionErr") {
+      const subscriptions = this.#querySubscriptions.get(evt.key);
+      if (subscriptions) {
+        for (const subscription of subscriptions) {
+          subscription.handleErr(new SQLSyncError(evt.err));
+        }
+      }
+    } else if (evt.tag === "SyncHealth") {
+      for (const listener of this.#syncHealthListeners) {
+        listener(docId, evt.health);
+      }
+    } else if (evt.tag === "ReducerErr") {
+      console.error(`sqlsync: doc ${journalIdToString(docId)} reducer error`, evt.err, evt.trap);
+      for (const listener of this.#reducerErrorListeners) {
+        listener(docId, evt.err, evt.trap ?? undefined);
+      }
+    } else if (evt.tag === "UpdateRequired") {
+      console.warn(`sqlsync: doc ${journalIdToString(docId)} requires reducer`, evt.reducerDigest);
+      for (const listener of this.#updateRequiredListeners) {
+        listener(docId, evt.reducerDigest);
+      }
+    } else if (evt.tag === "ReducerUpdated") {
+      console.info(`sqlsync: doc ${journalIdToString(docId)} switched reducer`, evt.reducerDigest);
+      for (const listener of this.#reducerUpdatedListeners) {
+        listener(docId, evt.reducerDigest);
+      }
+    } else {
+      assertUnreachable("unknown event", evt);
+    }
+  }
+
+  #send<T extends Exclude<DocReplyTag, "Err">>(
+    expectedReplyTag: T,
+    msg: OmitUnion<WorkerRequest, "handlerId">,
+    signal?: AbortSignal,
+  ): Promise<SelectDocReply<T>> {
+    return new Promise((resolve, reject) => {
+      if (signal?.aborted) {
+        reject(signal.reason);
+        return;
+      }
+      const handlerId = nextHandlerId();
+      const req: WorkerRequest = { ...msg, handlerId };
+
+      console.log("sqlsync: sending message", req.handlerId, req.tag === "Doc" ? req.req : req);
+
+      const onAbort = () => {
+        // the worker still replies, with ABORTED or with the result if it had
+        // already finished, so keep a handler around to drop the reply
+        this.#msgHandlers.set(handlerId, () => this.#msgHandlers.delete(handlerId));
+        if (req.tag === "Doc") {
+          this.#send("Ack", {
+            tag: "Doc",
+            docId: req.docId,
+            req: { tag: "Cancel", handlerId },
+          }).catch((err) => console.warn("sqlsync: failed to cancel request", err));
+        }
+        reject(signal?.reason);
+      };
+      signal?.addEventListener("abort", onAbort, { once: true });
+
+      this.#msgHandlers.set(handlerId, (msg: DocReply) => {
+        this.#msgHandlers.delete(handlerId);
+        signal?.removeEventListener("abort", onAbort);
+        if (msg.tag === "Err") {
+          reject(new SQLSyncError(msg.err));
+        } else if (msg.tag === expectedReplyTag) {
+          // TODO: is it possible to get Typescript to infer this cast?
+          resolve(msg as SelectDocReply<T>);
+        } else {
+          console.warn("sqlsync: unexpected reply", msg);
+          reject(new Error(`expected ${expectedReplyTag} reply; got ${msg.tag}`));
+        }
+      });
+
+      this.#port.postMessage(req);
+    });
+  }
+
+  async #boot(wasmUrl: string, coordinatorUrl?: string, options?: SQLSyncOptions): Promise<void> {
+    await this.#send("Ack", {
+      tag: "Boot",
+      protocolVersion: PROTOCOL_VERSION,
+      wasmUrl,
+      coordinatorUrl,
+      memoryBudgetBytes: options?.memoryBudgetBytes,
+      multiplex: options?.multiplex,
+    });
+  }
+
+  // document returns a handle for calling the document's methods without
+  // passing its id and type each time
+  document<M>(docId: DocId, docType: DocType<M>): DocumentHandle<M> {
+    return new DocumentHandle(this, docId, docType);
+  }
+
+  // closeDocument drops this tab's subscriptions to the document. the worker
+  // closes it once no tab has it open and its mutations have synced. using
+  // the document again reopens it
+  async closeDocument(docId: DocId): Promise<void> {
+    await this.#pendingOpens.get(docId);
+    if (!this.#openDocs.delete(docId)) {
+      return;
+    }
+    await this.#send("Ack", { tag: "Doc", docId, req: { tag: "Close" } });
+  }
+
+  // open resolves once the document is open, which with blockUntilSynced is
+  // once it has synced. documents are opened on first use, so this is only
+  // needed to wait for the initial sync, or to abort it
+  async open<M>(docId: DocId, docType: DocType<M>, opts?: AbortOptions): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+  }
+
+  async #open<M>(docId: DocId, docType: DocType<M>, signal?: AbortSignal): Promise<void> {
+    let openPromise = this.#pendingOpens.get(docId);
+    if (openPromise) {
+      // joining an open which is already in flight only stops waiting for it
+      // on abort; the signal of the caller which started it cancels it
+      await abortable(openPromise, signal);
+      return;
+    }
+    openPromise = this.#send(
+      "Ack",
+      {
+        tag: "Doc",
+        docId,
+        req: {
+          tag: "Open",
+          reducerUrl: docType.reducerUrl.toString(),
+          options: docType.openOptions,
+        },
+      },
+      signal,
+    );
+    this.#pendingOpens.set(docId, openPromise);
+    try {
+      await openPromise;
+      this.#openDocs.add(docId);
+    } finally {
+      this.#pendingOpens.delete(docId);
+    }
+  }
+
+  async query<M, T extends Row = Row>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+    opts?: AbortOptions,
+  ): Promise<T[]> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+
+    const reply = await this.#send(
+      "RecordSet",
+      {
+        tag: "Doc",
+        docId: docId,
+        req: { tag: "Query", sql, params },
+      },
+      opts?.signal,
+    );
+
+    return reply.rows as T[];
+  }
+
+  // like query, but the results arrive as typed arrays which are moved from
+  // the worker rather than copied, which is much cheaper for large results
+  async queryColumnar<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+    opts?: AbortOptions,
+  ): Promise<ColumnarResult> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+
+    const reply = await this.#send(
+      "Columns",
+      {
+        tag: "Doc",
+        docId: docId,
+        req: { tag: "Query", sql, params, format: "columnar" },
+      },
+      opts?.signal,
+    );
+
+    return new ColumnarResult(reply.columns, reply.rowCount, reply.data, reply.hasDrafts);
+  }
+
+  // like query, but the results arrive as an Arrow IPC stream, for libraries
+  // which read Arrow such as apache-arrow's tableFromIPC or DuckDB-wasm
+  async queryArrow<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+    opts?: AbortOptions,
+  ): Promise<Uint8Array> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+
+    const reply = await this.#send(
+      "Arrow",
+      {
+        tag: "Doc",
+        docId: docId,
+        req: { tag: "Query", sql, params, format: "arrow" },
+      },
+      opts?.signal,
+    );
+
+    return reply.ipc;
+  }
+
+  async subscribe<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    query: ParameterizedQuery,
+    subscription: QuerySubscription,
+  ): Promise<() => void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const queryKey = await toQueryKey(query);
+
+    // get or create subscription
+    let subscriptions = this.#querySubscriptions.get(queryKey);
+    if (!subscriptions) {
+      subscriptions = [];
+      this.#querySubscriptions.set(queryKey, subscriptions);
+    }
+    if (subscriptions.indexOf(subscription) === -1) {
+      subscriptions.push(subscription);
+    } else {
+      throw new Error("sqlsync: duplicate subscription");
+    }
+
+    // send subscribe request
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "QuerySubscribe", key: queryKey, sql: query.sql, params: query.params },
+    });
+
+    // return unsubscribe function
+    return () => {
+      const subscriptions = this.#querySubscriptions.get(queryKey);
+      if (!subscriptions) {
+        // no subscriptions
+        return;
+      }
+      const idx = subscriptions.indexOf(subscription);
+      if (idx === -1) {
+        // no subscription
+        return;
+      }
+      subscriptions.splice(idx, 1);
+
+      globalThis.setTimeout(() => {
+        // we want to wait a tiny bit before sending finalizing the unsubscribe
+        // to handle the case that React resubscribes to the same query right away
+        this.#unsubscribeIfNeeded(docId, queryKey).catch((err) => {
+          console.error("sqlsync: error unsubscribing", err);
+        });
+      }, 10);
+    };
+  }
+
+  async #unsubscribeIfNeeded(docId: DocId, queryKey: QueryKey): Promise<void> {
+    const subscriptions = this.#querySubscriptions.get(queryKey);
+    if (Array.isArray(subscriptions) && subscriptions.length === 0) {
+      // query subscription is still registered but has no subscriptions on our side
+      // inform the worker that we are no longer interested in this query
+      this.#querySubscriptions.delete(queryKey);
+
+      if (this.#openDocs.has(docId)) {
+        await this.#send("Ack", {
+          tag: "Doc",
+          docId,
+          req: { tag: "QueryUnsubscribe", key: queryKey },
+        });
+      }
+    }
+  }
+
+  // execLocal runs sql against the document's local-only tables, which live in
+  // the `local` schema (e.g. `local.ui_state`). local tables are never synced,
+  // so they are a good place for per-device state. queries can read them
+  async execLocal<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "ExecLocal", sql, params },
+    });
+  }
+
+  // pass the same 16 byte idempotencyKey when retrying a mutation whose
+  // outcome is unknown and it will only be applied once. set timestamp to
+  // record the current time with the mutation, and tag to label it for
+  // activity feeds built from the coordinator's change log. resolves to the
+  // mutation's receipt, or undefined if it was skipped as a duplicate
+  async mutate<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    mutation: M,
+    opts?: { idempotencyKey?: Uint8Array; timestamp?: boolean; tag?: number },
+  ): Promise<MutationReceipt | undefined> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("Mutated", {
+      tag: "Doc",
+      docId,
+      req: {
+        tag: "Mutate",
+        mutation: docType.serializeMutation(mutation),
+        idempotencyKey: opts?.idempotencyKey,
+        timestampMs: opts?.timestamp ? Date.now() : undefined,
+        tag: opts?.tag,
+      },
+    });
+    return reply.receipt ?? undefined;
+  }
+
+  // import reads csv or ndjson rows from source into table, sending them as
+  // few mutations as the document's mutation size limit allows. the import
+  // isn't atomic: each mutation is applied on its own, so if the import
+  // fails or is aborted part way, the mutations already sent remain and it
+  // rejects with an ImportError counting their rows
+  async import<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    table: string,
+    format: ImportFormat,
+    source: ImportSource,
+    opts: ImportOptions<M>,
+  ): Promise<ImportResult> {
+    let maxBytes = opts.maxMutationBytes ?? DEFAULT_IMPORT_MUTATION_BYTES;
+    const result: ImportResult = { rows: 0, mutations: 0 };
+
+    const submit = async (batch: ImportBatch): Promise<void> => {
+      opts.signal?.throwIfAborted();
+      try {
+        await this.mutate(docId, docType, opts.mutation(batch));
+      } catch (e) {
+        if (!(e instanceof SQLSyncError) || e.code !== "MUTATION_TOO_LARGE") {
+          throw e;
+        }
+        if (batch.rows.length < 2) {
+          throw e;
+        }
+        // our estimate was over the document's limit, so later batches are
+        // cut smaller and this one is split
+        maxBytes = Math.min(maxBytes, (e.info.limit ?? maxBytes) / 2);
+        const mid = batch.rows.length >> 1;
+        await submit({ ...batch, rows: batch.rows.slice(0, mid) });
+        await submit({ ...batch, rows: batch.rows.slice(mid) });
+        return;
+      }
+      result.rows += batch.rows.length;
+      result.mutations += 1;
+    };
+
+    try {
+      for await (const batch of importBatches(
+        table,
+        format,
+        source,
+        () => maxBytes,
+        opts.columns,
+      )) {
+        await submit(batch);
+      }
+    } catch (e) {
+      throw new ImportError(e, result);
+    }
+    return result;
+  }
+
+  // export writes the rows of a table, or the results of a query, to writer
+  // in format, a chunk at a time as the writer accepts them. resolves to the
+  // number of rows exported. writer is closed once the export is done, and
+  // aborted if it fails or opts.signal aborts. a table is read in rowid
+  // order, exporting each row once even as it changes, while a query's
+  // export fails with EXPORT_CHANGED if the document changes before it's done
+  async export<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    source: string | ParameterizedQuery,
+    format: ExportFormat,
+    writer: WritableStream<Uint8Array>,
+    opts?: AbortOptions,
+  ): Promise<number> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+    const exportSource: ExportSource =
+      typeof source === "string"
+        ? { tag: "Table", table: source }
+        : { tag: "Query", sql: source.sql, params: source.params };
+
+    const exportId = nextExportId();
+    await this.#send(
+      "Ack",
+      { tag: "Doc", docId, req: { tag: "Export", exportId, source: exportSource, format } },
+      opts?.signal,
+    );
+
+    const out = writer.getWriter();
+    let rows = 0;
+    let done = false;
+    try {
+      while (!done) {
+        const chunk = await this.#send(
+          "ExportChunk",
+          { tag: "Doc", docId, req: { tag: "ExportNext", exportId } },
+          opts?.signal,
+        );
+        await out.write(chunk.data);
+        rows += chunk.rows;
+        done = chunk.done;
+      }
+      await out.close();
+    } catch (e) {
+      await out.abort(e).catch(() => {});
+      throw e;
+    } finally {
+      out.releaseLock();
+      if (!done) {
+        this.#send("Ack", { tag: "Doc", docId, req: { tag: "ExportClose", exportId } }).catch(
+          () => {},
+        );
+      }
+    }
+    return rows;
+  }
+
+  // mutateDraft applies a mutation locally without syncing it, e.g. while a
+  // form is being edited. queries see its effects until it's discarded, or
+  // promoted to a regular mutation. resolves to the draft's id
+  async mutateDraft<M>(docId: DocId, docType: DocType<M>, mutation: M): Promise<number> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("Draft", {
+      tag: "Doc",
+      docId,
+      req: { tag: "MutateDraft", mutation: docType.serializeMutation(mutation) },
+    });
+    return reply.draftId;
+  }
+
+  // resolves to the promoted mutation's receipt, like mutate
+  async promoteDraft<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    draftId: number,
+  ): Promise<MutationReceipt | undefined> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("Mutated", {
+      tag: "Doc",
+      docId,
+      req: { tag: "PromoteDraft", draftId },
+    });
+    return reply.receipt ?? undefined;
+  }
+
+  async discardDraft<M>(docId: DocId, docType: DocType<M>, draftId: number): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "DiscardDraft", draftId },
+    });
+  }
+
+  // waitFor resolves once queries observe the mutation a receipt's
+  // consistencyToken was returned for. tokens can be passed to other clients,
+  // which observe the mutation once they've synced past it
+  async waitFor<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    consistencyToken: string,
+    opts?: AbortOptions,
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType, opts?.signal);
+    }
+    await this.#send(
+      "Ack",
+      {
+        tag: "Doc",
+        docId,
+        req: { tag: "WaitFor", consistencyToken },
+      },
+      opts?.signal,
+    );
+  }
+
+  // cancelPending rolls back a mutation which hasn't been sent to the
+  // coordinator yet, e.g. to undo it before it syncs. resolves to false if
+  // it may already have been sent, in which case only another mutation can
+  // undo it
+  async cancelPending<M>(docId: DocId, docType: DocType<M>, mutationId: number): Promise<boolean> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("Cancelled", {
+      tag: "Doc",
+      docId,
+      req: { tag: "CancelPending", mutationId },
+    });
+    return reply.cancelled;
+  }
+
+  get connectionStatus(): ConnectionStatus {
+    return this.#connectionStatus;
+  }
+
+  addConnectionStatusListener(listener: (status: ConnectionStatus) => void): () => void {
+    this.#connectionStatusListeners.add(listener);
+    return () => {
+      this.#connectionStatusListeners.delete(listener);
+    };
+  }
+
+  addReducerErrorListener(
+    listener: (docId: DocId, err: string, trap?: ReducerTrapInfo) => void,
+  ): () => void {
+    this.#reducerErrorListeners.add(listener);
+    return () => {
+      this.#reducerErrorListeners.delete(listener);
+    };
+  }
+
+  // called when the coordinator runs a newer reducer than the document was
+  // opened with, see reducer_version! in sqlsync-reducer. mutations fail
+  // until the worker has downloaded the coordinator's reducer and switched
+  // to it, see addReducerUpdatedListener
+  addUpdateRequiredListener(listener: (docId: DocId, reducerDigest: string) => void): () => void {
+    this.#updateRequiredListeners.add(listener);
+    return () => {
+      this.#updateRequiredListeners.delete(listener);
+    };
+  }
+
+  addReducerUpdatedListener(listener: (docId: DocId, reducerDigest: string) => void): () => void {
+    this.#reducerUpdatedListeners.add(listener);
+    return () => {
+      this.#reducerUpdatedListeners.delete(listener);
+    };
+  }
+
+  // sync health events arrive about once a second while an open document's
+  // health is changing
+  addSyncHealthListener(listener: (docId: DocId, health: SyncHealth) => void): () => void {
+    this.#syncHealthListeners.add(listener);
+    return () => {
+      this.#syncHealthListeners.delete(listener);
+    };
+  }
+
+  async syncHealth<M>(docId: DocId, docType: DocType<M>): Promise<SyncHealth> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("SyncHealth", {
+      tag: "Doc",
+      docId,
+      req: { tag: "SyncHealth" },
+    });
+    return reply.health;
+  }
+
+  async setConnectionEnabled<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    enabled: boolean,
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "SetConnectionEnabled", enabled },
+    });
+  }
+
+  // pauseSync stops replicating the document without closing its connection,
+  // e.g. while the app is in the background. mutations keep applying locally
+  // and are sent once resumeSync is called
+  async pauseSync<M>(docId: DocId, docType: DocType<M>): Promise<void> {
+    await this.#setSyncPaused(docId, docType, true);
+  }
+
+  async resumeSync<M>(docId: DocId, docType: DocType<M>): Promise<void> {
+    await this.#setSyncPaused(docId, docType, false);
+  }
+
+  async #setSyncPaused<M>(docId: DocId, docType: DocType<M>, paused: boolean): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: paused ? "PauseSync" : "ResumeSync" },
+    });
+  }
+
+  async networkStats<M>(docId: DocId, docType: DocType<M>): Promise<NetworkStats> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    const reply = await this.#send("NetworkStats", {
+      tag: "Doc",
+      docId,
+      req: { tag: "NetworkStats" },
+    });
+    return reply.stats;
+  }
+
+  // once budgetBytes more bytes have been sent and received for the document,
+  // stop pulling changes from the coordinator until the budget is raised or
+  // cleared. local mutations are still sent, so they reach the coordinator on
+  // metered connections
+  async setNetworkBudget<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    budgetBytes?: number,
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "SetNetworkBudget", budgetBytes },
+    });
+  }
+
+  // with multiplexing on, foreground documents sync ahead of background
+  // ones; e.g. put the document on screen in the foreground and the rest of
+  // the workspace in the background
+  async setPriority<M>(docId: DocId, docType: DocType<M>, priority: SyncPriority): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "SetPriority", priority },
+    });
+  }
+}
+
+export class DocumentHandle<M> {
+  readonly sqlsync: SQLSync;
+  readonly docId: DocId;
+  readonly docType: DocType<M>;
+
+  constructor(sqlsync: SQLSync, docId: DocId, docType: DocType<M>) {
+    this.sqlsync = sqlsync;
+    this.docId = docId;
+    this.docType = docType;
+  }
+
+  open(opts?: AbortOptions): Promise<void> {
+    return this.sqlsync.open(this.docId, this.docType, opts);
+  }
+
+  query<T extends Row = Row>(sql: string, params: SqlValue[], opts?: AbortOptions): Promise<T[]> {
+    return this.sqlsync.query<M, T>(this.docId, this.docType, sql, params, opts);
+  }
+
+  queryColumnar(sql: string, params: SqlValue[], opts?: AbortOptions): Promise<ColumnarResult> {
+    return this.sqlsync.queryColumnar(this.docId, this.docType, sql, params, opts);
+  }
+
+  queryArrow(sql: string, params: SqlValue[], opts?: AbortOptions): Promise<Uint8Array> {
+    return this.sqlsync.queryArrow(this.docId, this.docType, sql, params, opts);
+  }
+
+  subscribe(query: ParameterizedQuery, subscription: QuerySubscription): Promise<() => void> {
+    return this.sqlsync.subscribe(this.docId, this.docType, query, subscription);
+  }
+
+  mutate(
+    mutation: M,
+    opts?: { idempotencyKey?: Uint8Array; timestamp?: boolean; tag?: number },
+  ): Promise<MutationReceipt | undefined> {
+    return this.sqlsync.mutate(this.docId, this.docType, mutation, opts);
+  }
+
+  import(
+    table: string,
+    format: ImportFormat,
+    source: ImportSource,
+    opts: ImportOptions<M>,
+  ): Promise<ImportResult> {
+    return this.sqlsync.import(this.docId, this.docType, table, format, source, opts);
+  }
+
+  export(
+    source: string | ParameterizedQuery,
+    format: ExportFormat,
+    writer: WritableStream<Uint8Array>,
+    opts?: AbortOptions,
+  ): Promise<number> {
+    return this.sqlsync.export(this.docId, this.docType, source, format, writer, opts);
+  }
+
+  execLocal(sql: string, params: SqlValue[]): Promise<void> {
+    return this.sqlsync.execLocal(this.docId, this.docType, sql, params);
+  }
+
+  waitFor(consistencyToken: string, opts?: AbortOptions): Promise<void> {
+    return this.sqlsync.waitFor(this.docId, this.docType, consistencyToken, opts);
+  }
+
+  syncHealth(): Promise<SyncHealth> {
+    return this.sqlsync.syncHealth(this.docId, this.docType);
+  }
+
+  setPriority(priority: SyncPriority): Promise<void> {
+    return this.sqlsync.setPriority(this.docId, this.docType, priority);
+  }
+
+  close(): Promise<void> {
+    return this.sqlsync.closeDocument(this.docId);
+  }
+}
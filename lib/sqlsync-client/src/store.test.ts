@@ -0,0 +1,131 @@
+import { DocId, ErrorInfo, randomJournalId } from "@orbitinghail/sqlsync-worker";
+import { afterEach, beforeEach, describe, expect, it, vi } from "vitest";
+import { SQLSyncError } from "./error";
+import { ParameterizedQuery } from "./sql";
+import { DocType, QuerySubscription, Row, SQLSync } from "./sqlsync";
+import { QueryState, QueryStore, queryStoreKey } from "./store";
+
+const docType = {} as DocType<unknown>;
+
+// a SQLSync which only records subscriptions, so tests can push rows at them
+class FakeSQLSync {
+  subscriptions: { query: ParameterizedQuery; sub: QuerySubscription; closed: boolean }[] = [];
+
+  subscribe(
+    _docId: DocId,
+    _docType: DocType<unknown>,
+    query: ParameterizedQuery,
+    sub: QuerySubscription,
+  ): Promise<() => void> {
+    const entry = { query, sub, closed: false };
+    this.subscriptions.push(entry);
+    return Promise.resolve(() => {
+      entry.closed = true;
+    });
+  }
+}
+
+const setup = () => {
+  const fake = new FakeSQLSync();
+  const store = new QueryStore(fake as unknown as SQLSync);
+  return { fake, store, docId: randomJournalId() };
+};
+
+describe("queryStoreKey", () => {
+  it("is equal for equal queries", () => {
+    const docId = randomJournalId();
+    const a = queryStoreKey(docId, { sql: "select ?", params: [1, "a"] });
+    const b = queryStoreKey(docId, { sql: "select ?", params: [1, "a"] });
+    expect(a).toEqual(b);
+    expect(queryStoreKey(docId, { sql: "select ?", params: [2, "a"] })).not.toEqual(a);
+    expect(queryStoreKey(randomJournalId(), { sql: "select ?", params: [1, "a"] })).not.toEqual(a);
+  });
+
+  it("tells bigints from numbers", () => {
+    const docId = randomJournalId();
+    const big = queryStoreKey(docId, { sql: "select ?", params: [1n] });
+    expect(big).not.toEqual(queryStoreKey(docId, { sql: "select ?", params: [1] }));
+  });
+});
+
+describe("QueryStore", () => {
+  beforeEach(() => {
+    vi.useFakeTimers();
+  });
+  afterEach(() => {
+    vi.useRealTimers();
+  });
+
+  it("shares an entry between equal queries", async () => {
+    const { fake, store, docId } = setup();
+    const a = store.get(docType, docId, { sql: "select 1", params: [] });
+    const b = store.get(docType, docId, { sql: "select 1", params: [] });
+    expect(a).toBe(b);
+    expect(fake.subscriptions).toHaveLength(1);
+
+    expect(a.getSnapshot()).toEqual({ state: "pending" });
+    const listener = vi.fn();
+    a.subscribe(listener);
+    fake.subscriptions[0].sub.handleRows([{ n: 1 }], false);
+    await a.settled;
+    expect(listener).toHaveBeenCalledTimes(1);
+    expect(a.getSnapshot()).toEqual({ state: "success", rows: [{ n: 1 }], hasDrafts: false });
+
+    // errors keep the last rows around
+    const error = new SQLSyncError({
+      code: "INTERNAL",
+      message: "boom",
+      retryable: false,
+    } as ErrorInfo);
+    fake.subscriptions[0].sub.handleErr(error);
+    expect(a.getSnapshot()).toEqual({ state: "error", error, rows: [{ n: 1 }] });
+
+    // results which include drafts say so
+    fake.subscriptions[0].sub.handleRows([{ n: 2 }], true);
+    expect(a.getSnapshot()).toEqual({ state: "success", rows: [{ n: 2 }], hasDrafts: true });
+  });
+
+  it("unsubscribes a while after the last reader leaves", async () => {
+    const { fake, store, docId } = setup();
+    const query = { sql: "select 1", params: [] };
+    const entry = store.get(docType, docId, query);
+    const unsubscribe = entry.subscribe(() => {});
+
+    // readers keep the entry alive
+    await vi.advanceTimersByTimeAsync(60_000);
+    expect(store.get(docType, docId, query)).toBe(entry);
+
+    // and a quick resubscribe reuses it
+    unsubscribe();
+    await vi.advanceTimersByTimeAsync(1000);
+    const resubscribe = entry.subscribe(() => {});
+    await vi.advanceTimersByTimeAsync(60_000);
+    expect(fake.subscriptions[0].closed).toBe(false);
+
+    resubscribe();
+    await vi.advanceTimersByTimeAsync(60_000);
+    expect(fake.subscriptions[0].closed).toBe(true);
+    expect(store.get(docType, docId, query)).not.toBe(entry);
+    expect(fake.subscriptions).toHaveLength(2);
+  });
+
+  it("evicts entries which are never read", async () => {
+    const { fake, store, docId } = setup();
+    store.get(docType, docId, { sql: "select 1", params: [] });
+    await vi.advanceTimersByTimeAsync(60_000);
+    expect(fake.subscriptions[0].closed).toBe(true);
+  });
+
+  it("follows the svelte store contract", () => {
+    const { fake, store, docId } = setup();
+    const states: QueryState<Row>[] = [];
+    const unsubscribe = store.readable(docType, docId, "select 1").subscribe((s) => states.push(s));
+    fake.subscriptions[0].sub.handleRows([{ n: 1 }], false);
+    unsubscribe();
+    fake.subscriptions[0].sub.handleRows([{ n: 2 }], false);
+    expect(states).toEqual([
+      { state: "pending" },
+      { state: "success", rows: [{ n: 1 }], hasDrafts: false },
+    ]);
+  });
+});
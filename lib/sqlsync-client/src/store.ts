@@ -0,0 +1,154 @@
+// a framework agnostic store of query results. framework bindings read
+// entries with subscribe/getSnapshot semantics (e.g. react's
+// useSyncExternalStore), or through QueryStore.readable, which follows the
+// svelte store contract that solid's `from` also accepts.
+
+import { DocId, journalIdToString } from "@orbitinghail/sqlsync-worker";
+import { ParameterizedQuery, normalizeQuery } from "./sql";
+import { DocType, Row, SQLSync } from "./sqlsync";
+import { pendingPromise } from "./util";
+
+export type QueryState<R> =
+  | { state: "pending"; rows?: R[] }
+  // hasDrafts is set when the rows include the effects of drafts
+  | { state: "success"; rows: R[]; hasDrafts: boolean }
+  | { state: "error"; error: Error; rows?: R[] };
+
+export interface Readable<T> {
+  subscribe(run: (value: T) => void): () => void;
+}
+
+// how long a query stays subscribed after its last reader goes away, so that
+// components which suspended or quickly remount reuse the previous result
+const RETAIN_MS = 5000;
+
+// queryStoreKey is computed synchronously (e.g. during render), so that
+// queries with equal sql and params share an entry
+export function queryStoreKey(docId: DocId, query: ParameterizedQuery): string {
+  const params = JSON.stringify(query.params, (_, v) =>
+    typeof v === "bigint" ? { bigint: v.toString() } : v,
+  );
+  return `${journalIdToString(docId)}:${params}:${query.sql}`;
+}
+
+export class QueryEntry {
+  #state: QueryState<Row> = { state: "pending" };
+  #listeners = new Set<() => void>();
+  #unsubscribe: Promise<() => void>;
+  #evict: () => void;
+  #evictTimer?: ReturnType<typeof setTimeout>;
+  #settle: (v: undefined) => void;
+
+  // resolves once the query has its first result or error
+  readonly settled: Promise<undefined>;
+
+  constructor(
+    sqlsync: SQLSync,
+    docType: DocType<unknown>,
+    docId: DocId,
+    query: ParameterizedQuery,
+    evict: () => void,
+  ) {
+    const [settled, settle] = pendingPromise();
+    this.settled = settled;
+    this.#settle = settle;
+    this.#evict = evict;
+    this.#unsubscribe = sqlsync.subscribe(docId, docType, query, {
+      handleRows: (rows, hasDrafts) => this.#update({ state: "success", rows, hasDrafts }),
+      handleErr: (err) => this.#update({ state: "error", error: err, rows: this.#state.rows }),
+    });
+    this.#unsubscribe.catch((err: Error) => {
+      console.error("sqlsync: error subscribing", err);
+      this.#update({ state: "error", error: err });
+    });
+    // nothing may ever read this entry, e.g. if the render which created it
+    // was discarded
+    this.#scheduleEvict();
+  }
+
+  getSnapshot = (): QueryState<Row> => this.#state;
+
+  subscribe = (listener: () => void): (() => void) => {
+    this.#listeners.add(listener);
+    globalThis.clearTimeout(this.#evictTimer);
+    return () => {
+      this.#listeners.delete(listener);
+      if (this.#listeners.size === 0) {
+        this.#scheduleEvict();
+      }
+    };
+  };
+
+  #update(state: QueryState<Row>) {
+    this.#state = state;
+    this.#settle(undefined);
+    for (const listener of this.#listeners) {
+      listener();
+    }
+  }
+
+  #scheduleEvict() {
+    globalThis.clearTimeout(this.#evictTimer);
+    this.#evictTimer = globalThis.setTimeout(() => {
+      if (this.#listeners.size > 0) {
+        return;
+      }
+      this.#evict();
+      this.#unsubscribe
+        .then((unsub) => unsub())
+        .catch((err) => {
+          console.error("sqlsync: error unsubscribing", err);
+        });
+    }, RETAIN_MS);
+  }
+}
+
+export class QueryStore {
+  #sqlsync: SQLSync;
+  #entries = new Map<string, QueryEntry>();
+
+  constructor(sqlsync: SQLSync) {
+    this.#sqlsync = sqlsync;
+  }
+
+  // get the entry for a query, subscribing to it if needed
+  get<M>(docType: DocType<M>, docId: DocId, query: ParameterizedQuery): QueryEntry {
+    const key = queryStoreKey(docId, query);
+    let entry = this.#entries.get(key);
+    if (!entry) {
+      entry = new QueryEntry(this.#sqlsync, docType as DocType<unknown>, docId, query, () =>
+        this.#entries.delete(key),
+      );
+      this.#entries.set(key, entry);
+    }
+    return entry;
+  }
+
+  // a store for the query which subscribes to it while it has subscribers
+  readable<M, R = Row>(
+    docType: DocType<M>,
+    docId: DocId,
+    query: ParameterizedQuery | string,
+  ): Readable<QueryState<R>> {
+    const normalized = normalizeQuery(query);
+    return {
+      subscribe: (run) => {
+        const entry = this.get(docType, docId, normalized);
+        run(entry.getSnapshot() as QueryState<R>);
+        return entry.subscribe(() => run(entry.getSnapshot() as QueryState<R>));
+      },
+    };
+  }
+}
+
+const stores = new WeakMap<SQLSync, QueryStore>();
+
+// the QueryStore shared by every binding using this SQLSync instance
+export function queryStoreFor(sqlsync: SQLSync): QueryStore {
+  let store = stores.get(sqlsync);
+  if (!store) {
+    store = new QueryStore(sqlsync);
+    stores.set(sqlsync, store);
+  }
+  return store;
+}
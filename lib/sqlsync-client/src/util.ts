@@ -1,6 +1,4 @@
-import { SqlValue } from "@orbitinghail/sqlsync-worker";
 import * as sha256 from "fast-sha256";
-import { Row } from "./sqlsync";
 
 // omits the given keys from each member of the union
 // https://stackoverflow.com/a/57103940/65872
@@ -32,17 +30,23 @@ export const serializeMutationAsJSON = <M>(mutation: M) => {
   return UTF8Encoder.encode(serialized);
 };
 
-export function toRows<R extends Row = Row>(columns: string[], rows: SqlValue[][]): R[] {
-  const out: R[] = [];
-  for (const row of rows) {
-    const obj: Row = {};
-    for (let i = 0; i < columns.length; i++) {
-      obj[columns[i]] = row[i];
-    }
-    out.push(obj as R);
+// rejects with the signal's reason once it aborts, without cancelling promise
+export const abortable = <T>(promise: Promise<T>, signal?: AbortSignal): Promise<T> => {
+  if (!signal) {
+    return promise;
   }
-  return out;
-}
+  return new Promise((resolve, reject) => {
+    const onAbort = () => reject(signal.reason);
+    if (signal.aborted) {
+      onAbort();
+      return;
+    }
+    signal.addEventListener("abort", onAbort, { once: true });
+    promise
+      .then(resolve, reject)
+      .finally(() => signal.removeEventListener("abort", onAbort));
+  });
+};
 
 export const pendingPromise = <T = undefined>(): [Promise<T>, (v: T) => void] => {
   let resolve: (v: T) => void;
@@ -0,0 +1,20 @@
+import { resolve } from "path";
+import { defineConfig } from "vite";
+import dts from "vite-plugin-dts";
+
+export default defineConfig({
+  plugins: [dts()],
+  build: {
+    lib: {
+      entry: resolve(__dirname, "src/index.ts"),
+      name: "SQLSyncClient",
+      formats: ["es", "umd"],
+    },
+    sourcemap: true,
+    rollupOptions: {
+      output: {
+        exports: "named",
+      },
+    },
+  },
+});
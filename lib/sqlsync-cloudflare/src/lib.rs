@@ -0,0 +1,13 @@
+//! Adapters which run a sqlsync::server::CoordinatorServer inside a
+//! Cloudflare Durable Object: DurableObjectStorage persists frames to the
+//! object's transactional storage, and WebSocketSet connects the server to
+//! the object's websockets. relay_multiplexed lets a worker in front of the
+//! objects accept multiplexed client connections.
+
+mod relay;
+mod sockets;
+mod storage;
+
+pub use relay::relay_multiplexed;
+pub use sockets::{SocketEvent, WebSocketSet};
+pub use storage::DurableObjectStorage;
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, future::Future};
+
+use futures::{select, stream::FuturesUnordered, SinkExt, StreamExt};
+use gloo_net::websocket::{futures::WebSocket, Message};
+use sqlsync::{
+    mux::{Multiplexer, MuxEvent, StreamId, DEFAULT_WINDOW_BYTES},
+    server::{ClientId, ServerOutput},
+    JournalId, ModuleDigest,
+};
+use worker::console_error;
+
+use crate::{SocketEvent, WebSocketSet};
+
+/// how many bytes from a coordinator may wait for the client's credit
+/// before we stop reading from its socket
+const MAX_QUEUED_BYTES: usize = 2 * DEFAULT_WINDOW_BYTES as usize;
+
+/// relay a multiplexed client connection (see sqlsync::mux) to the
+/// documents' coordinators, so that the client only holds one websocket.
+/// connect opens a websocket to a document's coordinator, as the client
+/// would without multiplexing. returns once the client disconnects
+///
+/// connects run alongside the relay, so a slow coordinator only holds up
+/// its own stream. the client is granted credit as its data reaches the
+/// coordinators, and a coordinator's socket is paused while the client is
+/// too far behind on its stream, so neither direction buffers without bound
+pub async fn relay_multiplexed<F, Fut>(client: WebSocket, mut connect: F)
+where
+    F: FnMut(JournalId, Option<ModuleDigest>) -> Fut,
+    Fut: Future<Output = worker::Result<WebSocket>>,
+{
+    let (mut writer, reader) = client.split();
+    let mut reader = reader.fuse();
+    let mut mux = Multiplexer::default();
+    // the coordinators' sockets, keyed by stream. we are their client, so
+    // the server outputs are just messages to send them
+    let mut docs = WebSocketSet::default();
+    // each connect is tagged with its attempt, as the stream may close and
+    // open again before it finishes
+    let mut connects = FuturesUnordered::new();
+    let mut connecting: HashMap<StreamId, u64> = HashMap::new();
+    let mut next_attempt: u64 = 0;
+    // data which arrived while its stream was connecting. it isn't consumed
+    // until it's relayed, so it's bounded by the stream's window
+    let mut pending: HashMap<StreamId, Vec<Vec<u8>>> = HashMap::new();
+
+    'relay: loop {
+        select! {
+            msg = reader.next() => {
+                let Some(Ok(Message::Bytes(msg))) = msg else {
+                    break 'relay;
+                };
+                match mux.receive(&msg) {
+                    Ok(Some(MuxEvent::Opened { stream, doc, reducer })) => {
+                        let attempt = next_attempt;
+                        next_attempt += 1;
+                        connecting.insert(stream, attempt);
+                        pending.insert(stream, vec![]);
+                        let socket = connect(doc, reducer);
+                        connects.push(async move {
+                            (stream, attempt, doc, socket.await)
+                        });
+                    }
+                    Ok(Some(MuxEvent::Data { stream, msg })) => {
+                        match pending.get_mut(&stream) {
+                            Some(queued) => queued.push(msg),
+                            None => {
+                                relay(&mut mux, &mut docs, stream, vec![msg])
+                                    .await
+                            }
+                        }
+                    }
+                    Ok(Some(MuxEvent::Closed { stream })) => {
+                        connecting.remove(&stream);
+                        pending.remove(&stream);
+                        let client = stream.into();
+                        docs.apply(vec![ServerOutput::Close { client }]).await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        console_error!("multiplexed connection error: {}", e);
+                        break 'relay;
+                    }
+                }
+            }
+            (stream, attempt, doc, socket) = connects.select_next_some() => {
+                // a connect for a stream which has since closed is dropped
+                if connecting.get(&stream) == Some(&attempt) {
+                    connecting.remove(&stream);
+                    let queued = pending.remove(&stream).unwrap_or_default();
+                    match socket {
+                        Ok(socket) => {
+                            docs.insert(stream.into(), socket);
+                            relay(&mut mux, &mut docs, stream, queued).await;
+                        }
+                        Err(e) => {
+                            console_error!("can't connect {}: {}", doc, e);
+                            mux.close(stream);
+                        }
+                    }
+                }
+            }
+            (client, event) = docs.select_next_some() => match event {
+                SocketEvent::Message(msg) => {
+                    let _ = mux.send(client as StreamId, msg);
+                }
+                SocketEvent::Closed => {
+                    docs.apply(vec![ServerOutput::Close { client }]).await;
+                    mux.close(client as StreamId);
+                }
+            },
+        }
+
+        let msgs = match mux.poll() {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                console_error!("failed to encode multiplexed messages: {}", e);
+                break 'relay;
+            }
+        };
+        for msg in msgs {
+            if writer.send(Message::Bytes(msg)).await.is_err() {
+                break 'relay;
+            }
+        }
+
+        // stop reading from the coordinators the client has fallen behind
+        for stream in mux.streams() {
+            let behind = mux.queued_bytes(stream) >= MAX_QUEUED_BYTES;
+            docs.set_paused(stream.into(), behind);
+        }
+    }
+
+    // hang up on every coordinator
+    let streams: Vec<ClientId> = mux.streams().map(Into::into).collect();
+    let closes = streams
+        .into_iter()
+        .map(|client| ServerOutput::Close { client })
+        .collect();
+    docs.apply(closes).await;
+}
+
+/// send the client's data on to its stream's coordinator, granting the
+/// client credit for each message once it's sent
+async fn relay(
+    mux: &mut Multiplexer,
+    docs: &mut WebSocketSet,
+    stream: StreamId,
+    msgs: Vec<Vec<u8>>,
+) {
+    for msg in msgs {
+        let bytes = msg.len();
+        let client = stream.into();
+        let send = ServerOutput::Send { client, msg };
+        if !docs.apply(vec![send]).await.is_empty() {
+            mux.close(stream);
+            return;
+        }
+        mux.consumed(stream, bytes);
+    }
+}
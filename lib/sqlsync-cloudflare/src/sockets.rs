@@ -0,0 +1,395 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    fmt::Display,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+};
+
+use futures::{
+    future,
+    stream::{self, FusedStream, LocalBoxStream, SelectAll, SplitSink},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use gloo_net::websocket::{futures::WebSocket, Message};
+use sqlsync::server::{ClientId, ServerOutput};
+use worker::console_error;
+
+#[derive(Debug)]
+pub enum SocketEvent {
+    /// a binary message, to pass to CoordinatorServer::receive
+    Message(Vec<u8>),
+    /// the connection closed or failed, the client should be disconnected
+    Closed,
+}
+
+/// lets a socket's reader be paused, waking it once it's resumed
+#[derive(Default)]
+struct Gate {
+    paused: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+/// the websockets of a CoordinatorServer's clients. As a stream, it yields
+/// the events of every socket which isn't paused. S is only ever something
+/// other than a WebSocket in tests
+pub struct WebSocketSet<S = WebSocket> {
+    writers: BTreeMap<ClientId, SplitSink<S, Message>>,
+    readers: SelectAll<LocalBoxStream<'static, (ClientId, SocketEvent)>>,
+    gates: BTreeMap<ClientId, Rc<Gate>>,
+}
+
+impl<S> Default for WebSocketSet<S> {
+    fn default() -> Self {
+        Self {
+            writers: BTreeMap::new(),
+            readers: SelectAll::new(),
+            gates: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S> WebSocketSet<S> {
+    /// stop reading from a client's socket until it's resumed, e.g. while
+    /// what it has sent can't be handled yet. its messages wait in the
+    /// socket, pushing back on the client
+    pub fn set_paused(&mut self, client: ClientId, paused: bool) {
+        let Some(gate) = self.gates.get(&client) else {
+            return;
+        };
+        gate.paused.set(paused);
+        if !paused {
+            if let Some(waker) = gate.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<S, E> WebSocketSet<S>
+where
+    S: Stream<Item = Result<Message, E>> + Sink<Message, Error = E> + 'static,
+    E: Display,
+{
+    pub fn insert(&mut self, client: ClientId, socket: S) {
+        let (writer, reader) = socket.split();
+        let events = reader
+            .map(move |msg| match msg {
+                Ok(Message::Bytes(bytes)) => SocketEvent::Message(bytes),
+                Ok(Message::Text(_)) => {
+                    console_error!("client {} sent a text message", client);
+                    SocketEvent::Closed
+                }
+                Err(e) => {
+                    console_error!("client {} websocket error: {}", client, e);
+                    SocketEvent::Closed
+                }
+            })
+            .chain(stream::once(future::ready(SocketEvent::Closed)))
+            .map(move |event| (client, event));
+        let gate = Rc::new(Gate::default());
+        let reader_gate = gate.clone();
+        let mut events = events.boxed_local();
+        let gated = stream::poll_fn(move |cx| {
+            if reader_gate.paused.get() {
+                *reader_gate.waker.borrow_mut() = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            events.poll_next_unpin(cx)
+        });
+        self.writers.insert(client, writer);
+        self.gates.insert(client, gate);
+        self.readers.push(gated.boxed_local());
+    }
+
+    /// perform a CoordinatorServer's outputs, returning the clients whose
+    /// sockets failed; they should be disconnected from the server
+    pub async fn apply(&mut self, outputs: Vec<ServerOutput>) -> Vec<ClientId> {
+        let mut failed = vec![];
+        for output in outputs {
+            match output {
+                ServerOutput::Send { client, msg } => {
+                    let Some(writer) = self.writers.get_mut(&client) else {
+                        continue;
+                    };
+                    if let Err(e) = writer.send(Message::Bytes(msg)).await {
+                        console_error!("error sending to {}: {}", client, e);
+                        self.writers.remove(&client);
+                        // let the reader report the socket closing
+                        self.set_paused(client, false);
+                        self.gates.remove(&client);
+                        failed.push(client);
+                    }
+                }
+                ServerOutput::Close { client } => {
+                    self.set_paused(client, false);
+                    self.gates.remove(&client);
+                    if let Some(mut writer) = self.writers.remove(&client) {
+                        let _ = writer.close().await;
+                    }
+                }
+            }
+        }
+        failed
+    }
+}
+
+impl<S> Stream for WebSocketSet<S> {
+    type Item = (ClientId, SocketEvent);
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.readers.poll_next_unpin(cx)
+    }
+}
+
+impl<S> FusedStream for WebSocketSet<S> {
+    fn is_terminated(&self) -> bool {
+        self.readers.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::{
+        channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+        executor::block_on,
+        FutureExt, Sink, SinkExt, Stream, StreamExt,
+    };
+    use gloo_net::websocket::Message;
+    use sqlsync::{
+        coordinator::CoordinatorDocument,
+        local::{LocalDocument, NoopSignal},
+        replication::HeartbeatConfig,
+        server::{CoordinatorServer, ServerOutput},
+        session::Session,
+        JournalId, MemoryJournal, MemoryJournalFactory, Reducer,
+    };
+
+    use super::{SocketEvent, WebSocketSet};
+
+    /// the coordinator's end of an in memory connection
+    struct MemorySocket {
+        incoming: UnboundedReceiver<Result<Message, SendError>>,
+        outgoing: UnboundedSender<Message>,
+    }
+
+    /// the client's end
+    struct Remote {
+        outgoing: UnboundedSender<Result<Message, SendError>>,
+        incoming: UnboundedReceiver<Message>,
+    }
+
+    fn pair() -> (MemorySocket, Remote) {
+        let (to_socket, incoming) = mpsc::unbounded();
+        let (outgoing, to_remote) = mpsc::unbounded();
+        (
+            MemorySocket { incoming, outgoing },
+            Remote { outgoing: to_socket, incoming: to_remote },
+        )
+    }
+
+    impl Stream for MemorySocket {
+        type Item = Result<Message, SendError>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            self.incoming.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<Message> for MemorySocket {
+        type Error = SendError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            self.outgoing.poll_ready_unpin(cx)
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            msg: Message,
+        ) -> Result<(), SendError> {
+            self.outgoing.start_send_unpin(msg)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            self.outgoing.poll_flush_unpin(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            self.outgoing.poll_close_unpin(cx)
+        }
+    }
+
+    /// a reducer which accepts every mutation without changing anything
+    fn noop_reducer() -> Vec<u8> {
+        wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                ;; Ok(None) in bincode: no requests
+                (data (i32.const 2048) "\00\00\00\00\00")
+                (func (export "ffi_buf_allocate") (param i32) (result i32)
+                    i32.const 1024)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32)
+                    i32.const 5)
+                (func (export "ffi_init_reducer"))
+                (func (export "ffi_reduce") (param i32) (result i32)
+                    i32.const 2048)
+                (func (export "ffi_reactor_step") (param i32) (result i32)
+                    i32.const 2048))"#,
+        )
+        .unwrap()
+    }
+
+    type Local = LocalDocument<MemoryJournal, NoopSignal>;
+
+    /// deliver messages between the server, through sockets, and the
+    /// client until neither side has anything more to send
+    async fn pump(
+        server: &mut CoordinatorServer<MemoryJournal>,
+        sockets: &mut WebSocketSet<MemorySocket>,
+        remote: &mut Remote,
+        session: &mut Session,
+        local: &mut Local,
+    ) {
+        loop {
+            let mut progressed = false;
+            while let Ok(Some(msg)) = remote.incoming.try_next() {
+                let Message::Bytes(msg) = msg else {
+                    panic!("the server sent text")
+                };
+                let resp = session.receive(local, &msg, 0).unwrap();
+                for msg in
+                    resp.into_iter().chain(session.sync(&*local).unwrap())
+                {
+                    remote
+                        .outgoing
+                        .unbounded_send(Ok(Message::Bytes(msg)))
+                        .unwrap();
+                }
+                progressed = true;
+            }
+            while let Some(Some((client, event))) =
+                sockets.next().now_or_never()
+            {
+                let SocketEvent::Message(msg) = event else {
+                    panic!("client {} closed its socket", client)
+                };
+                let mut out = server.receive(client, &msg, 0);
+                out.extend(server.sync());
+                assert!(sockets.apply(out).await.is_empty());
+                progressed = true;
+            }
+            if !progressed {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn sessions_round_trip_through_the_sockets() {
+        block_on(async {
+            let mut rng = rand::thread_rng();
+            let reducer = noop_reducer();
+            let doc_id = JournalId::new128(&mut rng);
+            let mut server = CoordinatorServer::new(
+                CoordinatorDocument::open(
+                    MemoryJournal::open(doc_id).unwrap(),
+                    MemoryJournalFactory,
+                    &reducer,
+                )
+                .unwrap(),
+            );
+            let mut local = LocalDocument::open(
+                MemoryJournal::open(doc_id).unwrap(),
+                MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+                Reducer::new(&reducer[..]).unwrap(),
+                NoopSignal,
+                NoopSignal,
+                NoopSignal,
+            )
+            .unwrap();
+            local.mutate(b"m").unwrap();
+
+            let mut sockets = WebSocketSet::default();
+            let (socket, mut remote) = pair();
+            let (client, out) = server.accept(0).unwrap();
+            sockets.insert(client, socket);
+            assert!(sockets.apply(out).await.is_empty());
+
+            let mut session = Session::new(HeartbeatConfig::default(), 0);
+            let start = session.start(&local).unwrap();
+            remote
+                .outgoing
+                .unbounded_send(Ok(Message::Bytes(start)))
+                .unwrap();
+            pump(
+                &mut server,
+                &mut sockets,
+                &mut remote,
+                &mut session,
+                &mut local,
+            )
+            .await;
+            assert!(session.initialized());
+            // the coordinator acknowledged the mutation
+            assert_eq!(session.acked_lsn(), Some(0));
+            assert_eq!(session.unacked_frames(), 0);
+
+            // a close output hangs up on the client
+            let close = vec![ServerOutput::Close { client }];
+            assert!(sockets.apply(close).await.is_empty());
+            assert!(remote.incoming.next().await.is_none());
+
+            // and the client hanging up is reported once
+            drop(remote);
+            assert!(matches!(
+                sockets.next().await,
+                Some((c, SocketEvent::Closed)) if c == client
+            ));
+            assert_eq!(sockets.next().await.map(|(c, _)| c), None);
+        })
+    }
+
+    #[test]
+    fn paused_sockets_hold_their_messages() {
+        block_on(async {
+            let mut sockets = WebSocketSet::default();
+            let (socket, remote) = pair();
+            sockets.insert(1, socket);
+            remote
+                .outgoing
+                .unbounded_send(Ok(Message::Bytes(vec![1])))
+                .unwrap();
+
+            sockets.set_paused(1, true);
+            assert!(sockets.next().now_or_never().is_none());
+
+            sockets.set_paused(1, false);
+            assert!(matches!(
+                sockets.next().now_or_never(),
+                Some(Some((1, SocketEvent::Message(msg)))) if msg == [1]
+            ));
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use js_sys::Uint8Array;
+use sqlsync::{server::FrameStorage, Lsn, LsnRange};
+use wasm_bindgen::JsValue;
+use worker::{Error, Result, Storage};
+
+const RANGE_KEY: &str = "RANGE";
+
+fn frame_key(lsn: Lsn) -> String {
+    format!("lsn-{}", lsn)
+}
+
+/// stores each frame under its own key, next to the range of stored frames
+pub struct DurableObjectStorage {
+    /// the range of lsns that have been written to storage
+    range: LsnRange,
+    storage: Storage,
+}
+
+impl DurableObjectStorage {
+    pub async fn open(mut storage: Storage) -> Result<Self> {
+        let range = match storage.get::<LsnRange>(RANGE_KEY).await {
+            Ok(range) => range,
+            Err(_) => {
+                let range = LsnRange::empty();
+                storage.put(RANGE_KEY, &range).await?;
+                range
+            }
+        };
+        Ok(Self { range, storage })
+    }
+}
+
+impl FrameStorage for DurableObjectStorage {
+    type Error = Error;
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    async fn read_frame(&self, lsn: Lsn) -> Result<Vec<u8>> {
+        let frame = self
+            .storage
+            .get::<serde_bytes::ByteBuf>(&frame_key(lsn))
+            .await?;
+        Ok(frame.into_vec())
+    }
+
+    async fn write_frame(&mut self, lsn: Lsn, frame: Vec<u8>) -> Result<()> {
+        // get the new range, assuming the write goes through
+        let new_range = self.range.append(lsn);
+        let range = serde_wasm_bindgen::to_value(&new_range)
+            .map_err(|e| Error::RustError(e.to_string()))?;
+
+        // write the frame and the new range in a single transaction
+        let obj = js_sys::Object::new();
+        js_sys::Reflect::set(&obj, &JsValue::from_str(RANGE_KEY), &range)?;
+        js_sys::Reflect::set(
+            &obj,
+            &JsValue::from_str(&frame_key(lsn)),
+            &Uint8Array::from(frame.as_slice()),
+        )?;
+        self.storage.put_multiple_raw(obj).await?;
+
+        self.range = new_range;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+#ifndef SQLSYNC_H
+#define SQLSYNC_H
+
+/* generated by cbindgen, do not edit; regenerate with `just sqlsync-ffi-header` */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum SqlSyncStatus {
+  SQL_SYNC_STATUS_OK = 0,
+  SQL_SYNC_STATUS_ERROR = 1,
+  /**
+   * the coordinator stopped responding; the host should close the
+   * connection and reconnect
+   */
+  SQL_SYNC_STATUS_TIMED_OUT = 2,
+} SqlSyncStatus;
+
+/**
+ * the kinds of SqlSyncValue. values from the host carry their kind as a
+ * plain integer, since a C enum may hold any value
+ */
+typedef enum SqlSyncValueKind {
+  SQL_SYNC_VALUE_KIND_NULL = 0,
+  SQL_SYNC_VALUE_KIND_INTEGER = 1,
+  SQL_SYNC_VALUE_KIND_REAL = 2,
+  SQL_SYNC_VALUE_KIND_TEXT = 3,
+  SQL_SYNC_VALUE_KIND_BLOB = 4,
+} SqlSyncValueKind;
+
+typedef struct SqlSyncDocument SqlSyncDocument;
+
+/**
+ * sends one binary message to the coordinator
+ */
+typedef struct SqlSyncTransport {
+  void *ctx;
+  void (*send)(void *ctx, const uint8_t *msg, size_t len);
+} SqlSyncTransport;
+
+/**
+ * a sqlite value; integer is set for INTEGER values, real for REAL values,
+ * and data and len for TEXT (utf-8, not nul terminated) and BLOB values
+ */
+typedef struct SqlSyncValue {
+  /**
+   * a SqlSyncValueKind
+   */
+  uint32_t kind;
+  int64_t integer;
+  double real;
+  const uint8_t *data;
+  size_t len;
+} SqlSyncValue;
+
+/**
+ * called once per result row with the column names and values, which are
+ * only valid for the duration of the call; return false to stop early
+ */
+typedef bool (*SqlSyncRowCallback)(void *ctx,
+                                   const char *const *columns,
+                                   const SqlSyncValue *values,
+                                   size_t len);
+
+/**
+ * the message describing the last error on this thread, or null; valid
+ * until the next call on this thread which fails
+ */
+const char *sqlsync_last_error(void);
+
+/**
+ * open a document from its base58 id and the bytes of a compiled reducer;
+ * returns null on error. The document is stored in the storage_path
+ * directory along with any mutations which haven't reached the coordinator
+ * yet, so reopening it from the same directory picks up where it left off.
+ * The document must be freed with sqlsync_document_free.
+ */
+SqlSyncDocument *sqlsync_document_open(const char *doc_id,
+                                       const uint8_t *reducer,
+                                       size_t reducer_len,
+                                       const char *storage_path,
+                                       SqlSyncTransport transport);
+
+void sqlsync_document_free(SqlSyncDocument *doc);
+
+SqlSyncStatus sqlsync_document_mutate(SqlSyncDocument *doc,
+                                      const uint8_t *mutation,
+                                      size_t mutation_len);
+
+/**
+ * run a query, calling callback with each row of the result
+ */
+SqlSyncStatus sqlsync_document_query(const SqlSyncDocument *doc,
+                                     const char *sql,
+                                     const SqlSyncValue *params,
+                                     size_t params_len,
+                                     SqlSyncRowCallback callback,
+                                     void *ctx);
+
+/**
+ * start replicating over a new connection to the coordinator, sending the
+ * initial messages through the transport
+ */
+SqlSyncStatus sqlsync_document_connect(SqlSyncDocument *doc);
+
+/**
+ * handle a binary message from the coordinator
+ */
+SqlSyncStatus sqlsync_document_receive(SqlSyncDocument *doc,
+                                       const uint8_t *msg,
+                                       size_t msg_len);
+
+/**
+ * should be called once sqlsync_document_heartbeat_deadline passes; sends a
+ * ping when the connection has been idle and returns
+ * SQL_SYNC_STATUS_TIMED_OUT once the coordinator stops responding
+ */
+SqlSyncStatus sqlsync_document_heartbeat(SqlSyncDocument *doc);
+
+/**
+ * unix milliseconds at which sqlsync_document_heartbeat should next be
+ * called, or -1 if the document is not connected
+ */
+int64_t sqlsync_document_heartbeat_deadline(const SqlSyncDocument *doc);
+
+/**
+ * forget the current connection; call sqlsync_document_connect to start over
+ */
+void sqlsync_document_disconnect(SqlSyncDocument *doc);
+
+#endif  /* SQLSYNC_H */
@@ -0,0 +1,565 @@
+//! A C ABI for LocalDocument, so iOS and Android apps can embed SQLSync from
+//! Swift or Kotlin. The header lives in include/sqlsync.h and is generated by
+//! cbindgen.
+//!
+//! The host owns the network connection. Outgoing replication messages are
+//! handed to the SqlSyncTransport passed to sqlsync_document_open, and
+//! incoming messages are fed to sqlsync_document_receive. The transport is
+//! only called once the function which produced the messages is done with
+//! the document, so its send callback may call back into the document.
+//!
+//! Unless noted otherwise, pointer arguments must be non-null and valid for
+//! the duration of the call, strings must be nul terminated UTF-8, and a
+//! document must only be used from one thread at a time. Functions which
+//! return SQL_SYNC_STATUS_ERROR (or null) record a message which can be read
+//! with sqlsync_last_error.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use sqlsync::{
+    embed::{EmbedError, EmbeddedDocument},
+    session::SessionError,
+    sqlite::{self, params_from_iter},
+    unixtime::unix_timestamp_milliseconds,
+    FileJournal, FileJournalFactory, JournalError, JournalId,
+    JournalIdParseError, Reducer,
+};
+use thiserror::Error;
+
+mod sql;
+
+use sql::SqlSyncValue;
+
+#[derive(Error, Debug)]
+enum FfiError {
+    #[error("{0} must not be null")]
+    NullPointer(&'static str),
+
+    #[error("{0} is not valid utf-8")]
+    InvalidUtf8(&'static str),
+
+    #[error("{0} is not a SqlSyncValueKind")]
+    InvalidValueKind(u32),
+
+    #[error(transparent)]
+    SqlSync(#[from] sqlsync::error::Error),
+
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+
+    #[error(transparent)]
+    JournalIdParse(#[from] JournalIdParseError),
+
+    #[error(transparent)]
+    Embed(#[from] EmbedError),
+
+    #[error(transparent)]
+    Sqlite(#[from] sqlite::Error),
+}
+
+type Result<T> = std::result::Result<T, FfiError>;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlSyncStatus {
+    Ok = 0,
+    Error = 1,
+    /// the coordinator stopped responding; the host should close the
+    /// connection and reconnect
+    TimedOut = 2,
+}
+
+/// sends one binary message to the coordinator
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SqlSyncTransport {
+    pub ctx: *mut c_void,
+    pub send: Option<
+        unsafe extern "C" fn(ctx: *mut c_void, msg: *const u8, len: usize),
+    >,
+}
+
+impl SqlSyncTransport {
+    fn send(&self, msg: &[u8]) {
+        if let Some(send) = self.send {
+            unsafe { send(self.ctx, msg.as_ptr(), msg.len()) }
+        }
+    }
+}
+
+/// called once per result row with the column names and values, which are
+/// only valid for the duration of the call; return false to stop early
+pub type SqlSyncRowCallback = Option<
+    unsafe extern "C" fn(
+        ctx: *mut c_void,
+        columns: *const *const c_char,
+        values: *const SqlSyncValue,
+        len: usize,
+    ) -> bool,
+>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(msg: String) {
+    // interior nul bytes would truncate the message
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// the message describing the last error on this thread, or null; valid
+/// until the next call on this thread which fails
+#[no_mangle]
+pub extern "C" fn sqlsync_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |msg| msg.as_ptr())
+    })
+}
+
+/// run f, recording errors and panics rather than letting them unwind into
+/// the host
+fn guard<T>(fallback: T, f: impl FnOnce() -> Result<T>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(err)) => {
+            set_last_error(err.to_string());
+            fallback
+        }
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".into());
+            set_last_error(format!("sqlsync panicked: {}", msg));
+            fallback
+        }
+    }
+}
+
+/// a slice from a pointer and length; data may be null when len is zero
+unsafe fn slice<'a, T>(
+    name: &'static str,
+    data: *const T,
+    len: usize,
+) -> Result<&'a [T]> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(FfiError::NullPointer(name))
+    } else {
+        Ok(std::slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn string<'a>(name: &'static str, s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(FfiError::NullPointer(name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| FfiError::InvalidUtf8(name))
+}
+
+pub struct SqlSyncDocument {
+    doc: EmbeddedDocument<FileJournal>,
+    transport: SqlSyncTransport,
+}
+
+/// run f with the document, and then send the messages it returns. the
+/// borrow of the document ends before the first send, so the transport may
+/// call back into it
+unsafe fn send_from(
+    doc: *mut SqlSyncDocument,
+    f: impl FnOnce(&mut EmbeddedDocument<FileJournal>) -> Result<Vec<Vec<u8>>>,
+) -> Result<()> {
+    let (transport, msgs) = {
+        let doc = doc.as_mut().ok_or(FfiError::NullPointer("doc"))?;
+        (doc.transport, f(&mut doc.doc)?)
+    };
+    for msg in msgs {
+        transport.send(&msg);
+    }
+    Ok(())
+}
+
+/// open a document from its base58 id and the bytes of a compiled reducer;
+/// returns null on error. The document is stored in the storage_path
+/// directory along with any mutations which haven't reached the coordinator
+/// yet, so reopening it from the same directory picks up where it left off.
+/// The document must be freed with sqlsync_document_free.
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_open(
+    doc_id: *const c_char,
+    reducer: *const u8,
+    reducer_len: usize,
+    storage_path: *const c_char,
+    transport: SqlSyncTransport,
+) -> *mut SqlSyncDocument {
+    guard(ptr::null_mut(), || {
+        let doc_id = JournalId::from_base58(string("doc_id", doc_id)?)?;
+        let storage_path = string("storage_path", storage_path)?;
+        let (storage, timeline) =
+            FileJournalFactory::new(storage_path).open_document(doc_id)?;
+        let reducer = Reducer::new(slice("reducer", reducer, reducer_len)?)?;
+
+        let doc = EmbeddedDocument::open(storage, timeline, reducer)?;
+        Ok(Box::into_raw(Box::new(SqlSyncDocument { doc, transport })))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_free(doc: *mut SqlSyncDocument) {
+    if !doc.is_null() {
+        drop(Box::from_raw(doc));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_mutate(
+    doc: *mut SqlSyncDocument,
+    mutation: *const u8,
+    mutation_len: usize,
+) -> SqlSyncStatus {
+    guard(SqlSyncStatus::Error, || {
+        let mutation = slice("mutation", mutation, mutation_len)?;
+        send_from(doc, |doc| Ok(doc.mutate(mutation)?))?;
+        Ok(SqlSyncStatus::Ok)
+    })
+}
+
+/// run a query, calling callback with each row of the result
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_query(
+    doc: *const SqlSyncDocument,
+    sql: *const c_char,
+    params: *const SqlSyncValue,
+    params_len: usize,
+    callback: SqlSyncRowCallback,
+    ctx: *mut c_void,
+) -> SqlSyncStatus {
+    guard(SqlSyncStatus::Error, || {
+        let doc = doc.as_ref().ok_or(FfiError::NullPointer("doc"))?;
+        let sql = string("sql", sql)?;
+        let callback = callback.ok_or(FfiError::NullPointer("callback"))?;
+        let params = slice("params", params, params_len)?
+            .iter()
+            .map(|p| p.to_sql_output())
+            .collect::<Result<Vec<_>>>()?;
+
+        let conn = doc.doc.doc().sqlite_readonly();
+        let mut stmt = conn.prepare(sql)?;
+        let columns = stmt
+            .column_names()
+            .iter()
+            .map(|&name| CString::new(name).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let column_ptrs: Vec<_> = columns.iter().map(|c| c.as_ptr()).collect();
+
+        let mut rows = stmt.query(params_from_iter(params))?;
+        let mut values = Vec::with_capacity(columns.len());
+        while let Some(row) = rows.next()? {
+            values.clear();
+            for i in 0..columns.len() {
+                values.push(SqlSyncValue::from(row.get_ref(i)?));
+            }
+            let more = callback(
+                ctx,
+                column_ptrs.as_ptr(),
+                values.as_ptr(),
+                values.len(),
+            );
+            if !more {
+                break;
+            }
+        }
+        Ok(SqlSyncStatus::Ok)
+    })
+}
+
+/// start replicating over a new connection to the coordinator, sending the
+/// initial messages through the transport
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_connect(
+    doc: *mut SqlSyncDocument,
+) -> SqlSyncStatus {
+    guard(SqlSyncStatus::Error, || {
+        let now = unix_timestamp_milliseconds();
+        send_from(doc, |doc| Ok(doc.connect(now)?))?;
+        Ok(SqlSyncStatus::Ok)
+    })
+}
+
+/// handle a binary message from the coordinator
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_receive(
+    doc: *mut SqlSyncDocument,
+    msg: *const u8,
+    msg_len: usize,
+) -> SqlSyncStatus {
+    guard(SqlSyncStatus::Error, || {
+        let msg = slice("msg", msg, msg_len)?;
+        let now = unix_timestamp_milliseconds();
+        send_from(doc, |doc| Ok(doc.receive(msg, now)?))?;
+        Ok(SqlSyncStatus::Ok)
+    })
+}
+
+/// should be called once sqlsync_document_heartbeat_deadline passes; sends a
+/// ping when the connection has been idle and returns
+/// SQL_SYNC_STATUS_TIMED_OUT once the coordinator stops responding
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_heartbeat(
+    doc: *mut SqlSyncDocument,
+) -> SqlSyncStatus {
+    guard(SqlSyncStatus::Error, || {
+        let now = unix_timestamp_milliseconds();
+        match send_from(doc, |doc| Ok(doc.heartbeat(now)?)) {
+            Ok(()) => Ok(SqlSyncStatus::Ok),
+            Err(FfiError::Embed(EmbedError::Session(
+                SessionError::TimedOut,
+            ))) => Ok(SqlSyncStatus::TimedOut),
+            Err(err) => Err(err),
+        }
+    })
+}
+
+/// unix milliseconds at which sqlsync_document_heartbeat should next be
+/// called, or -1 if the document is not connected
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_heartbeat_deadline(
+    doc: *const SqlSyncDocument,
+) -> i64 {
+    doc.as_ref()
+        .and_then(|doc| doc.doc.deadline())
+        .unwrap_or(-1)
+}
+
+/// forget the current connection; call sqlsync_document_connect to start over
+#[no_mangle]
+pub unsafe extern "C" fn sqlsync_document_disconnect(
+    doc: *mut SqlSyncDocument,
+) {
+    if let Some(doc) = doc.as_mut() {
+        doc.doc.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_char, c_void, CStr, CString},
+        path::PathBuf,
+        ptr, slice,
+    };
+
+    use sqlsync::JournalId;
+
+    use super::*;
+
+    /// a reducer which accepts every mutation without changing anything
+    fn noop_reducer() -> Vec<u8> {
+        wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                ;; Ok(None) in bincode: no requests
+                (data (i32.const 2048) "\00\00\00\00\00")
+                (func (export "ffi_buf_allocate") (param i32) (result i32)
+                    i32.const 1024)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32)
+                    i32.const 5)
+                (func (export "ffi_init_reducer"))
+                (func (export "ffi_reduce") (param i32) (result i32)
+                    i32.const 2048)
+                (func (export "ffi_reactor_step") (param i32) (result i32)
+                    i32.const 2048))"#,
+        )
+        .unwrap()
+    }
+
+    /// what the transport has seen; doc is set so that send can call back
+    /// into the document, as a host might
+    struct Sent {
+        doc: *mut SqlSyncDocument,
+        msgs: Vec<Vec<u8>>,
+        deadlines: Vec<i64>,
+    }
+
+    unsafe extern "C" fn record(ctx: *mut c_void, msg: *const u8, len: usize) {
+        let sent = &mut *(ctx as *mut Sent);
+        sent.msgs.push(slice::from_raw_parts(msg, len).to_vec());
+        sent.deadlines
+            .push(sqlsync_document_heartbeat_deadline(sent.doc));
+    }
+
+    struct Fixture {
+        dir: PathBuf,
+        sent: *mut Sent,
+        doc: *mut SqlSyncDocument,
+    }
+
+    impl Fixture {
+        fn open() -> Self {
+            let id = JournalId::new128(&mut rand::thread_rng());
+            let dir = std::env::temp_dir().join(format!("sqlsync-ffi-{}", id));
+            let sent = Box::into_raw(Box::new(Sent {
+                doc: ptr::null_mut(),
+                msgs: vec![],
+                deadlines: vec![],
+            }));
+            let transport = SqlSyncTransport {
+                ctx: sent as *mut c_void,
+                send: Some(record),
+            };
+            let doc_id = CString::new(id.to_base58()).unwrap();
+            let path = CString::new(dir.to_str().unwrap()).unwrap();
+            let reducer = noop_reducer();
+            let doc = unsafe {
+                sqlsync_document_open(
+                    doc_id.as_ptr(),
+                    reducer.as_ptr(),
+                    reducer.len(),
+                    path.as_ptr(),
+                    transport,
+                )
+            };
+            assert!(!doc.is_null(), "{:?}", last_error());
+            unsafe { (*sent).doc = doc };
+            Self { dir, sent, doc }
+        }
+
+        fn sent(&self) -> &Sent {
+            unsafe { &*self.sent }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            unsafe {
+                sqlsync_document_free(self.doc);
+                drop(Box::from_raw(self.sent));
+            }
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn last_error() -> Option<String> {
+        let err = sqlsync_last_error();
+        (!err.is_null()).then(|| {
+            unsafe { CStr::from_ptr(err) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    #[test]
+    fn the_transport_may_call_back_into_the_document() {
+        let f = Fixture::open();
+        unsafe {
+            assert_eq!(
+                sqlsync_document_mutate(f.doc, b"m".as_ptr(), 1),
+                SqlSyncStatus::Ok
+            );
+            // not connected, so there is nothing to send
+            assert!(f.sent().msgs.is_empty());
+            assert_eq!(sqlsync_document_heartbeat_deadline(f.doc), -1);
+
+            assert_eq!(sqlsync_document_connect(f.doc), SqlSyncStatus::Ok);
+            assert_eq!(f.sent().msgs.len(), 2);
+            // the session is in place before any message is sent
+            assert!(f.sent().deadlines.iter().all(|&d| d > 0));
+
+            assert_eq!(sqlsync_document_heartbeat(f.doc), SqlSyncStatus::Ok);
+            sqlsync_document_disconnect(f.doc);
+            assert_eq!(
+                sqlsync_document_receive(f.doc, ptr::null(), 0),
+                SqlSyncStatus::Error
+            );
+            assert_eq!(
+                last_error().as_deref(),
+                Some("document is not connected")
+            );
+        }
+    }
+
+    unsafe extern "C" fn collect(
+        ctx: *mut c_void,
+        columns: *const *const c_char,
+        values: *const SqlSyncValue,
+        len: usize,
+    ) -> bool {
+        let rows = &mut *(ctx as *mut Vec<Vec<(String, SqlSyncValue)>>);
+        let columns = slice::from_raw_parts(columns, len);
+        let values = slice::from_raw_parts(values, len);
+        rows.push(
+            columns
+                .iter()
+                .zip(values)
+                .map(|(&c, &v)| {
+                    (CStr::from_ptr(c).to_string_lossy().into_owned(), v)
+                })
+                .collect(),
+        );
+        true
+    }
+
+    fn query(
+        f: &Fixture,
+        sql: &str,
+        params: &[SqlSyncValue],
+    ) -> (SqlSyncStatus, Vec<Vec<(String, SqlSyncValue)>>) {
+        let sql = CString::new(sql).unwrap();
+        let mut rows = vec![];
+        let status = unsafe {
+            sqlsync_document_query(
+                f.doc,
+                sql.as_ptr(),
+                params.as_ptr(),
+                params.len(),
+                Some(collect),
+                &mut rows as *mut _ as *mut c_void,
+            )
+        };
+        (status, rows)
+    }
+
+    fn value(kind: u32) -> SqlSyncValue {
+        SqlSyncValue { kind, integer: 0, real: 0.0, data: ptr::null(), len: 0 }
+    }
+
+    #[test]
+    fn queries_check_value_kinds() {
+        let f = Fixture::open();
+        let text = b"hi";
+        let params = [
+            SqlSyncValue { integer: 7, ..value(1) },
+            SqlSyncValue { data: text.as_ptr(), len: 2, ..value(3) },
+        ];
+        let (status, rows) = query(&f, "SELECT ? AS i, ? AS t", &params);
+        assert_eq!(status, SqlSyncStatus::Ok);
+        assert_eq!(rows.len(), 1);
+        let [(i_name, i), (t_name, t)] = &rows[0][..] else {
+            panic!("expected two columns, got {}", rows[0].len());
+        };
+        assert_eq!((i_name.as_str(), i.kind, i.integer), ("i", 1, 7));
+        assert_eq!((t_name.as_str(), t.kind, t.len), ("t", 3, 2));
+
+        let (status, rows) = query(&f, "SELECT ?", &[value(9)]);
+        assert_eq!(status, SqlSyncStatus::Error);
+        assert!(rows.is_empty());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("9 is not a SqlSyncValueKind")
+        );
+    }
+}
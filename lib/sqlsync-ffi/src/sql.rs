@@ -0,0 +1,80 @@
+use std::ptr;
+
+use sqlsync::sqlite::types::{ToSqlOutput, ValueRef};
+
+use crate::{slice, FfiError, Result};
+
+/// the kinds of SqlSyncValue. values from the host carry their kind as a
+/// plain integer, since a C enum may hold any value
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlSyncValueKind {
+    Null = 0,
+    Integer = 1,
+    Real = 2,
+    Text = 3,
+    Blob = 4,
+}
+
+/// a sqlite value; integer is set for INTEGER values, real for REAL values,
+/// and data and len for TEXT (utf-8, not nul terminated) and BLOB values
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SqlSyncValue {
+    /// a SqlSyncValueKind
+    pub kind: u32,
+    pub integer: i64,
+    pub real: f64,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+impl SqlSyncValue {
+    fn new(kind: SqlSyncValueKind) -> Self {
+        Self {
+            kind: kind as u32,
+            integer: 0,
+            real: 0.0,
+            data: ptr::null(),
+            len: 0,
+        }
+    }
+
+    fn bytes(kind: SqlSyncValueKind, data: &[u8]) -> Self {
+        Self { data: data.as_ptr(), len: data.len(), ..Self::new(kind) }
+    }
+
+    /// data and len must describe a valid buffer for TEXT and BLOB values
+    pub(crate) unsafe fn to_sql_output(&self) -> Result<ToSqlOutput<'_>> {
+        let data = || slice("param data", self.data, self.len);
+        const NULL: u32 = SqlSyncValueKind::Null as u32;
+        const INTEGER: u32 = SqlSyncValueKind::Integer as u32;
+        const REAL: u32 = SqlSyncValueKind::Real as u32;
+        const TEXT: u32 = SqlSyncValueKind::Text as u32;
+        const BLOB: u32 = SqlSyncValueKind::Blob as u32;
+        Ok(ToSqlOutput::Borrowed(match self.kind {
+            NULL => ValueRef::Null,
+            INTEGER => ValueRef::Integer(self.integer),
+            REAL => ValueRef::Real(self.real),
+            TEXT => ValueRef::Text(data()?),
+            BLOB => ValueRef::Blob(data()?),
+            kind => return Err(FfiError::InvalidValueKind(kind)),
+        }))
+    }
+}
+
+impl From<ValueRef<'_>> for SqlSyncValue {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => Self::new(SqlSyncValueKind::Null),
+            ValueRef::Integer(v) => {
+                Self { integer: v, ..Self::new(SqlSyncValueKind::Integer) }
+            }
+            ValueRef::Real(v) => {
+                Self { real: v, ..Self::new(SqlSyncValueKind::Real) }
+            }
+            ValueRef::Text(v) => Self::bytes(SqlSyncValueKind::Text, v),
+            ValueRef::Blob(v) => Self::bytes(SqlSyncValueKind::Blob, v),
+        }
+    }
+}
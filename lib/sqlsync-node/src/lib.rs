@@ -0,0 +1,235 @@
+//! Node.js bindings for LocalDocument, so backend services and Electron main
+//! processes can participate in sync without a browser worker.
+//!
+//! The binding doesn't open network connections. Instead, connect, receive,
+//! and heartbeat return the replication messages which the host must send to
+//! the coordinator, which keeps the choice of websocket library in JS.
+
+use std::collections::BTreeMap;
+
+use napi::{
+    bindgen_prelude::Buffer,
+    threadsafe_function::{
+        ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction,
+        ThreadsafeFunctionCallMode,
+    },
+    Env, JsFunction,
+};
+use napi_derive::napi;
+use sqlsync::{
+    embed::EmbeddedDocument, sqlite::params_from_iter,
+    unixtime::unix_timestamp_milliseconds, FileJournal, FileJournalFactory,
+    JournalId, ReactiveQuery, Reducer,
+};
+
+mod sql;
+
+use sql::{SqlRow, SqlValue};
+
+type Result<T> = napi::Result<T>;
+
+fn to_napi_err(err: impl ToString) -> napi::Error {
+    napi::Error::from_reason(err.to_string())
+}
+
+fn to_buffers(msgs: Vec<Vec<u8>>) -> Vec<Buffer> {
+    msgs.into_iter().map(Buffer::from).collect()
+}
+
+struct Subscription {
+    query: ReactiveQuery<SqlValue>,
+    callback: ThreadsafeFunction<Vec<SqlRow>, ErrorStrategy::CalleeHandled>,
+}
+
+#[napi]
+pub struct Document {
+    doc: EmbeddedDocument<FileJournal>,
+    subscriptions: BTreeMap<u32, Subscription>,
+    next_subscription_id: u32,
+}
+
+#[napi]
+impl Document {
+    /// open a document from its base58 id and the bytes of a compiled
+    /// reducer. the document is stored in the storage_path directory, along
+    /// with any mutations which haven't reached the coordinator yet, so
+    /// reopening it from the same directory picks up where it left off
+    #[napi(factory)]
+    pub fn open(
+        doc_id: String,
+        reducer: Buffer,
+        storage_path: String,
+    ) -> Result<Self> {
+        let doc_id = JournalId::from_base58(&doc_id).map_err(to_napi_err)?;
+        let (storage, timeline) = FileJournalFactory::new(storage_path)
+            .open_document(doc_id)
+            .map_err(to_napi_err)?;
+        let reducer = Reducer::new(&reducer[..]).map_err(to_napi_err)?;
+
+        let doc = EmbeddedDocument::open(storage, timeline, reducer)
+            .map_err(to_napi_err)?;
+
+        Ok(Self {
+            doc,
+            subscriptions: BTreeMap::new(),
+            next_subscription_id: 0,
+        })
+    }
+
+    #[napi(getter)]
+    pub fn doc_id(&self) -> String {
+        self.doc.doc().doc_id().to_base58()
+    }
+
+    /// apply a mutation, returning any messages to send to the coordinator
+    #[napi]
+    pub fn mutate(&mut self, mutation: Buffer) -> Result<Vec<Buffer>> {
+        let out = self.doc.mutate(&mutation).map_err(to_napi_err)?;
+        self.refresh_changed()?;
+        Ok(to_buffers(out))
+    }
+
+    #[napi(
+        ts_args_type = "sql: string, params?: SqlValue[] | null",
+        ts_return_type = "Array<Record<string, SqlValue>>"
+    )]
+    pub fn query(
+        &self,
+        sql: String,
+        params: Option<Vec<SqlValue>>,
+    ) -> Result<Vec<SqlRow>> {
+        let params = params.unwrap_or_default();
+        self.doc
+            .doc()
+            .query(|conn| {
+                let mut stmt = conn.prepare(&sql)?;
+                let columns: Vec<_> =
+                    stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+
+                let mut rows = Vec::new();
+                let mut cursor = stmt.query(params_from_iter(params.iter()))?;
+                while let Some(row) = cursor.next()? {
+                    rows.push(SqlRow::read(&columns, row)?);
+                }
+                Ok::<_, sqlsync::sqlite::Error>(rows)
+            })
+            .map_err(to_napi_err)
+    }
+
+    /// call `callback(err, rows)` with the query's results now and whenever
+    /// a change to the document may have changed them
+    #[napi(
+        ts_args_type = "sql: string, params: SqlValue[] | null | undefined, \
+        callback: (err: Error | null, rows: Array<Record<string, SqlValue>>) => void"
+    )]
+    pub fn subscribe(
+        &mut self,
+        env: Env,
+        sql: String,
+        params: Option<Vec<SqlValue>>,
+        callback: JsFunction,
+    ) -> Result<u32> {
+        let mut callback = callback.create_threadsafe_function(
+            0,
+            |ctx: ThreadSafeCallContext<Vec<SqlRow>>| Ok(vec![ctx.value]),
+        )?;
+        // subscriptions shouldn't keep the process alive on their own
+        callback.unref(&env)?;
+
+        let mut subscription = Subscription {
+            query: ReactiveQuery::new(sql, params.unwrap_or_default()),
+            callback,
+        };
+        self.refresh(&mut subscription);
+
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.subscriptions.insert(id, subscription);
+        Ok(id)
+    }
+
+    #[napi]
+    pub fn unsubscribe(&mut self, id: u32) {
+        self.subscriptions.remove(&id);
+    }
+
+    /// start replicating over a new connection to the coordinator, returning
+    /// the messages to send once it opens
+    #[napi]
+    pub fn connect(&mut self) -> Result<Vec<Buffer>> {
+        let out = self
+            .doc
+            .connect(unix_timestamp_milliseconds())
+            .map_err(to_napi_err)?;
+        Ok(to_buffers(out))
+    }
+
+    /// handle a binary message from the coordinator
+    #[napi]
+    pub fn receive(&mut self, msg: Buffer) -> Result<Vec<Buffer>> {
+        let out = self
+            .doc
+            .receive(&msg, unix_timestamp_milliseconds())
+            .map_err(to_napi_err)?;
+        self.refresh_changed()?;
+        Ok(to_buffers(out))
+    }
+
+    /// should be called periodically while connected; returns a ping to send
+    /// when the connection has been idle, and fails once the coordinator
+    /// stops responding, in which case the host should reconnect
+    #[napi]
+    pub fn heartbeat(&mut self) -> Result<Vec<Buffer>> {
+        let out = self
+            .doc
+            .heartbeat(unix_timestamp_milliseconds())
+            .map_err(to_napi_err)?;
+        Ok(to_buffers(out))
+    }
+
+    /// unix milliseconds at which heartbeat should next be called
+    #[napi]
+    pub fn heartbeat_deadline(&self) -> Option<i64> {
+        self.doc.deadline()
+    }
+
+    /// forget the current connection; call connect to start over
+    #[napi]
+    pub fn disconnect(&mut self) {
+        self.doc.disconnect();
+    }
+}
+
+impl Document {
+    /// refresh the subscriptions whose results the last operation may have
+    /// changed
+    fn refresh_changed(&mut self) -> Result<()> {
+        if self.doc.take_storage_changed() {
+            let changes =
+                self.doc.doc_mut().storage_changes().map_err(to_napi_err)?;
+            let mut subscriptions = std::mem::take(&mut self.subscriptions);
+            for subscription in subscriptions.values_mut() {
+                subscription.query.handle_storage_change(&changes);
+                if subscription.query.is_dirty() {
+                    self.refresh(subscription);
+                }
+            }
+            self.subscriptions = subscriptions;
+        }
+        Ok(())
+    }
+
+    fn refresh(&self, subscription: &mut Subscription) {
+        let result = subscription
+            .query
+            .refresh(self.doc.doc().sqlite_readonly(), SqlRow::read)
+            .map(|(_, rows)| rows)
+            .map_err(to_napi_err);
+        if result.is_err() {
+            subscription.query.mark_error();
+        }
+        subscription
+            .callback
+            .call(result, ThreadsafeFunctionCallMode::NonBlocking);
+    }
+}
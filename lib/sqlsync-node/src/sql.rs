@@ -0,0 +1,163 @@
+use napi::{
+    bindgen_prelude::{
+        BigInt, Buffer, FromNapiValue, Null, Object, ToNapiValue, TypeName,
+    },
+    sys, Env, JsBigInt, JsBoolean, JsBuffer, JsNumber, JsString, JsUnknown,
+    ValueType,
+};
+use sqlsync::sqlite::{
+    self,
+    types::{ToSqlOutput, ValueRef},
+    Row, ToSql,
+};
+
+/// the largest integer a JS number represents exactly
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+#[derive(Debug, Clone)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<ValueRef<'_>> for SqlValue {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => SqlValue::Null,
+            ValueRef::Integer(v) => SqlValue::Integer(v),
+            ValueRef::Real(v) => SqlValue::Real(v),
+            ValueRef::Text(v) => {
+                SqlValue::Text(String::from_utf8_lossy(v).into_owned())
+            }
+            ValueRef::Blob(v) => SqlValue::Blob(v.to_vec()),
+        }
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> sqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(match self {
+            SqlValue::Null => ValueRef::Null,
+            SqlValue::Integer(v) => ValueRef::Integer(*v),
+            SqlValue::Real(v) => ValueRef::Real(*v),
+            SqlValue::Text(v) => ValueRef::Text(v.as_bytes()),
+            SqlValue::Blob(v) => ValueRef::Blob(v),
+        }))
+    }
+}
+
+impl TypeName for SqlValue {
+    fn type_name() -> &'static str {
+        "SqlValue"
+    }
+
+    fn value_type() -> ValueType {
+        ValueType::Unknown
+    }
+}
+
+/// NULL becomes null, integers which don't fit in a number become a BigInt,
+/// and blobs become a Buffer
+impl ToNapiValue for SqlValue {
+    unsafe fn to_napi_value(
+        env: sys::napi_env,
+        val: Self,
+    ) -> napi::Result<sys::napi_value> {
+        match val {
+            SqlValue::Null => Null::to_napi_value(env, Null),
+            SqlValue::Integer(v) if v.unsigned_abs() <= MAX_SAFE_INTEGER => {
+                f64::to_napi_value(env, v as f64)
+            }
+            SqlValue::Integer(v) => BigInt::to_napi_value(env, BigInt::from(v)),
+            SqlValue::Real(v) => f64::to_napi_value(env, v),
+            SqlValue::Text(v) => String::to_napi_value(env, v),
+            SqlValue::Blob(v) => Buffer::to_napi_value(env, v.into()),
+        }
+    }
+}
+
+/// accepts the same params as the browser client: null or undefined,
+/// booleans (as 0 or 1), numbers, bigints, strings and Buffers
+impl FromNapiValue for SqlValue {
+    unsafe fn from_napi_value(
+        env: sys::napi_env,
+        napi_val: sys::napi_value,
+    ) -> napi::Result<Self> {
+        let value = JsUnknown::from_napi_value(env, napi_val)?;
+        Ok(match value.get_type()? {
+            ValueType::Null | ValueType::Undefined => SqlValue::Null,
+            ValueType::Boolean => {
+                let v = value.cast::<JsBoolean>().get_value()?;
+                SqlValue::Integer(v as i64)
+            }
+            ValueType::Number => {
+                let v = value.cast::<JsNumber>().get_double()?;
+                if v.fract() == 0.0 && v.abs() <= MAX_SAFE_INTEGER as f64 {
+                    SqlValue::Integer(v as i64)
+                } else {
+                    SqlValue::Real(v)
+                }
+            }
+            ValueType::BigInt => {
+                let (v, lossless) = value.cast::<JsBigInt>().get_i64()?;
+                if !lossless {
+                    return Err(napi::Error::from_reason(
+                        "bigint param does not fit in a 64 bit integer",
+                    ));
+                }
+                SqlValue::Integer(v)
+            }
+            ValueType::String => SqlValue::Text(
+                value.cast::<JsString>().into_utf8()?.into_owned()?,
+            ),
+            ValueType::Object if value.is_buffer()? => {
+                SqlValue::Blob(value.cast::<JsBuffer>().into_value()?.to_vec())
+            }
+            other => {
+                return Err(napi::Error::from_reason(format!(
+                    "unsupported sql param of type {:?}",
+                    other
+                )))
+            }
+        })
+    }
+}
+
+/// a query result row, as an object keyed by column name
+pub struct SqlRow(Vec<(String, SqlValue)>);
+
+impl SqlRow {
+    pub fn read(columns: &[String], row: &Row<'_>) -> sqlite::Result<Self> {
+        let mut values = Vec::with_capacity(columns.len());
+        for (i, column) in columns.iter().enumerate() {
+            values.push((column.clone(), row.get_ref(i)?.into()));
+        }
+        Ok(Self(values))
+    }
+}
+
+impl TypeName for SqlRow {
+    fn type_name() -> &'static str {
+        "SqlRow"
+    }
+
+    fn value_type() -> ValueType {
+        ValueType::Object
+    }
+}
+
+impl ToNapiValue for SqlRow {
+    unsafe fn to_napi_value(
+        env: sys::napi_env,
+        val: Self,
+    ) -> napi::Result<sys::napi_value> {
+        let mut obj = Env::from_raw(env).create_object()?;
+        for (column, value) in val.0 {
+            obj.set(column, value)?;
+        }
+        Object::to_napi_value(env, obj)
+    }
+}
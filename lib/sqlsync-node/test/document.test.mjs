@@ -0,0 +1,98 @@
+// run with `pnpm test`, after building the binding with `pnpm build-debug`
+// and the counter reducer with `just wasm-counter-reducer`
+import assert from "node:assert/strict";
+import { mkdtempSync, readFileSync, rmSync } from "node:fs";
+import { createRequire } from "node:module";
+import { tmpdir } from "node:os";
+import { join } from "node:path";
+import { afterEach, beforeEach, test } from "node:test";
+
+const require = createRequire(import.meta.url);
+const { Document } = require("../index.js");
+
+const REDUCER = readFileSync(
+  new URL(
+    "../../../target/wasm32-unknown-unknown/debug/examples/counter_reducer.wasm",
+    import.meta.url,
+  ),
+);
+
+// counter mutations are bincode enums: a little endian u32 variant index
+const InitSchema = Buffer.from([0, 0, 0, 0]);
+const Incr = Buffer.from([1, 0, 0, 0]);
+
+const DOC_ID = "VM7fC4gKxa52pbdtrgd9G9";
+
+// subscription callbacks are queued onto the event loop
+const waitFor = async (condition) => {
+  for (let i = 0; i < 100 && !condition(); i++) {
+    await new Promise((resolve) => setTimeout(resolve, 10));
+  }
+  assert.ok(condition());
+};
+
+const counter = (doc) => doc.query("select value from counter where id = ?", [0])[0]?.value;
+
+let dir;
+beforeEach(() => {
+  dir = mkdtempSync(join(tmpdir(), "sqlsync-node-"));
+});
+afterEach(() => {
+  rmSync(dir, { recursive: true, force: true });
+});
+
+test("mutations are applied and queryable", () => {
+  const doc = Document.open(DOC_ID, REDUCER, dir);
+  assert.equal(doc.docId, DOC_ID);
+
+  // not connected, so there is nothing to send
+  assert.deepEqual(doc.mutate(InitSchema), []);
+  doc.mutate(Incr);
+  doc.mutate(Incr);
+  assert.equal(counter(doc), 2);
+
+  const rows = doc.query("select ? as n, ? as big, ? as s, ? as b, ? as z", [
+    1,
+    2n ** 60n,
+    "hi",
+    Buffer.from([0, 255]),
+    null,
+  ]);
+  assert.deepEqual(rows, [{ n: 1, big: 2n ** 60n, s: "hi", b: Buffer.from([0, 255]), z: null }]);
+});
+
+test("subscriptions see changes", async () => {
+  const doc = Document.open(DOC_ID, REDUCER, dir);
+  doc.mutate(InitSchema);
+
+  const seen = [];
+  const id = doc.subscribe("select value from counter", null, (err, rows) => {
+    assert.equal(err, null);
+    seen.push(rows[0]?.value);
+  });
+  doc.mutate(Incr);
+  await waitFor(() => seen.length === 2);
+  assert.deepEqual(seen, [0, 1]);
+
+  doc.unsubscribe(id);
+  doc.mutate(Incr);
+  await new Promise((resolve) => setTimeout(resolve, 50));
+  assert.deepEqual(seen, [0, 1]);
+});
+
+test("pending mutations survive reopening the storage path", () => {
+  let doc = Document.open(DOC_ID, REDUCER, dir);
+  doc.mutate(InitSchema);
+  doc.mutate(Incr);
+  doc = null;
+
+  const reopened = Document.open(DOC_ID, REDUCER, dir);
+  assert.equal(counter(reopened), 1);
+
+  // a new connection replays the timeline to the coordinator
+  const msgs = reopened.connect();
+  assert.equal(msgs.length, 2);
+  assert.ok(reopened.heartbeatDeadline() > 0);
+  reopened.disconnect();
+  assert.equal(reopened.heartbeatDeadline(), null);
+});
@@ -0,0 +1,3 @@
+from ._sqlsync import Document, SqlSyncError
+
+__all__ = ["Document", "SqlSyncError"]
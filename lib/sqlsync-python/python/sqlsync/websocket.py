@@ -0,0 +1,56 @@
+"""Replicate a Document with a coordinator over a websocket.
+
+Requires the `websockets` package (`pip install sqlsync[websockets]`).
+"""
+
+import asyncio
+import time
+
+import websockets
+
+from . import Document
+
+
+class Replicator:
+    """Drives replication for one Document over one websocket connection.
+
+    Mutations should go through Replicator.mutate while connected, so that
+    new frames are sent to the coordinator right away.
+    """
+
+    def __init__(self, doc: Document, ws):
+        self.doc = doc
+        self.ws = ws
+
+    async def mutate(self, mutation: bytes) -> None:
+        await self._send(self.doc.mutate(mutation))
+
+    async def run(self) -> None:
+        """Replicate until the connection closes or times out."""
+        await self._send(self.doc.connect())
+        try:
+            while True:
+                await self._send(self.doc.heartbeat())
+                deadline = self.doc.heartbeat_deadline()
+                timeout = max(deadline / 1000 - time.time(), 0)
+                try:
+                    msg = await asyncio.wait_for(self.ws.recv(), timeout)
+                except asyncio.TimeoutError:
+                    continue
+                await self._send(self.doc.receive(msg))
+        finally:
+            self.doc.disconnect()
+
+    async def _send(self, msgs) -> None:
+        for msg in msgs:
+            await self.ws.send(msg)
+
+
+async def replicate(doc: Document, url: str, **connect_kwargs) -> None:
+    """Replicate doc with the coordinator at url until the connection fails.
+
+    Callers which want to stay in sync should catch the error and call
+    replicate again after a backoff.
+    """
+    async with websockets.connect(url, **connect_kwargs) as ws:
+        await Replicator(doc, ws).run()
@@ -0,0 +1,179 @@
+//! Python bindings for LocalDocument, so notebooks and ETL jobs can read and
+//! write documents.
+//!
+//! Like the Node.js binding, Document is sans-io: connect, receive, mutate,
+//! and heartbeat return the replication messages to send to the
+//! coordinator. The sqlsync.websocket module drives a Document over a
+//! websocket using asyncio.
+
+use pyo3::{
+    create_exception,
+    exceptions::PyException,
+    prelude::*,
+    types::{PyBytes, PyList},
+};
+use sqlsync::{
+    embed::EmbeddedDocument, sqlite::params_from_iter,
+    unixtime::unix_timestamp_milliseconds, FileJournal, FileJournalFactory,
+    JournalId, Reducer,
+};
+
+mod sql;
+
+use sql::{row_to_dict, SqlValue};
+
+create_exception!(sqlsync, SqlSyncError, PyException);
+
+fn to_py_err(err: impl ToString) -> PyErr {
+    SqlSyncError::new_err(err.to_string())
+}
+
+/// messages to send to the coordinator
+type Outbox = Vec<Py<PyBytes>>;
+
+fn outbox(py: Python<'_>, msgs: Vec<Vec<u8>>) -> Outbox {
+    msgs.iter()
+        .map(|msg| PyBytes::new(py, msg).into())
+        .collect()
+}
+
+#[pyclass(unsendable, module = "sqlsync")]
+pub struct Document {
+    doc: EmbeddedDocument<FileJournal>,
+}
+
+#[pymethods]
+impl Document {
+    /// open a document from its base58 id and the bytes of a compiled
+    /// reducer. the document is stored in the storage_path directory, along
+    /// with any mutations which haven't reached the coordinator yet, so
+    /// reopening it from the same directory picks up where it left off
+    #[staticmethod]
+    pub fn open(
+        doc_id: &str,
+        reducer: &[u8],
+        storage_path: &str,
+    ) -> PyResult<Self> {
+        let doc_id = JournalId::from_base58(doc_id).map_err(to_py_err)?;
+        let (storage, timeline) = FileJournalFactory::new(storage_path)
+            .open_document(doc_id)
+            .map_err(to_py_err)?;
+        let reducer = Reducer::new(reducer).map_err(to_py_err)?;
+
+        let doc = EmbeddedDocument::open(storage, timeline, reducer)
+            .map_err(to_py_err)?;
+        Ok(Self { doc })
+    }
+
+    #[getter]
+    pub fn doc_id(&self) -> String {
+        self.doc.doc().doc_id().to_base58()
+    }
+
+    /// apply a mutation, returning any messages to send to the coordinator
+    pub fn mutate(
+        &mut self,
+        py: Python<'_>,
+        mutation: &[u8],
+    ) -> PyResult<Outbox> {
+        let out = self.doc.mutate(mutation).map_err(to_py_err)?;
+        Ok(outbox(py, out))
+    }
+
+    /// run a query, returning a list of dicts keyed by column name
+    #[pyo3(signature = (sql, params = None))]
+    pub fn query<'py>(
+        &self,
+        py: Python<'py>,
+        sql: &str,
+        params: Option<Vec<SqlValue>>,
+    ) -> PyResult<&'py PyList> {
+        let (_, rows) = self.query_rows(py, sql, params)?;
+        Ok(PyList::new(py, rows))
+    }
+
+    /// run a query, returning a pandas DataFrame; requires pandas
+    #[pyo3(signature = (sql, params = None))]
+    pub fn query_df<'py>(
+        &self,
+        py: Python<'py>,
+        sql: &str,
+        params: Option<Vec<SqlValue>>,
+    ) -> PyResult<&'py PyAny> {
+        let (columns, rows) = self.query_rows(py, sql, params)?;
+        py.import("pandas")?
+            .getattr("DataFrame")?
+            .call_method1("from_records", (rows, columns))
+    }
+
+    /// start replicating over a new connection to the coordinator, returning
+    /// the messages to send once it opens
+    pub fn connect(&mut self, py: Python<'_>) -> PyResult<Outbox> {
+        let out = self
+            .doc
+            .connect(unix_timestamp_milliseconds())
+            .map_err(to_py_err)?;
+        Ok(outbox(py, out))
+    }
+
+    /// handle a binary message from the coordinator
+    pub fn receive(&mut self, py: Python<'_>, msg: &[u8]) -> PyResult<Outbox> {
+        let out = self
+            .doc
+            .receive(msg, unix_timestamp_milliseconds())
+            .map_err(to_py_err)?;
+        Ok(outbox(py, out))
+    }
+
+    /// should be called periodically while connected; returns a ping to send
+    /// when the connection has been idle, and raises once the coordinator
+    /// stops responding, in which case the caller should reconnect
+    pub fn heartbeat(&mut self, py: Python<'_>) -> PyResult<Outbox> {
+        let out = self
+            .doc
+            .heartbeat(unix_timestamp_milliseconds())
+            .map_err(to_py_err)?;
+        Ok(outbox(py, out))
+    }
+
+    /// unix milliseconds at which heartbeat should next be called
+    pub fn heartbeat_deadline(&self) -> Option<i64> {
+        self.doc.deadline()
+    }
+
+    /// forget the current connection; call connect to start over
+    pub fn disconnect(&mut self) {
+        self.doc.disconnect();
+    }
+}
+
+impl Document {
+    fn query_rows<'py>(
+        &self,
+        py: Python<'py>,
+        sql: &str,
+        params: Option<Vec<SqlValue>>,
+    ) -> PyResult<(Vec<String>, Vec<&'py PyAny>)> {
+        let params = params.unwrap_or_default();
+        let conn = self.doc.doc().sqlite_readonly();
+        let mut stmt = conn.prepare(sql).map_err(to_py_err)?;
+        let columns: Vec<_> =
+            stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+
+        let mut rows = Vec::new();
+        let mut cursor = stmt
+            .query(params_from_iter(params.iter()))
+            .map_err(to_py_err)?;
+        while let Some(row) = cursor.next().map_err(to_py_err)? {
+            rows.push(row_to_dict(py, &columns, row)?.into());
+        }
+        Ok((columns, rows))
+    }
+}
+
+#[pymodule]
+fn _sqlsync(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Document>()?;
+    m.add("SqlSyncError", py.get_type::<SqlSyncError>())?;
+    Ok(())
+}
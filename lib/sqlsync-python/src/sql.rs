@@ -0,0 +1,80 @@
+use pyo3::{
+    exceptions::PyTypeError,
+    prelude::*,
+    types::{PyBool, PyBytes, PyDict, PyFloat, PyLong, PyString},
+};
+use sqlsync::sqlite::{
+    self,
+    types::{ToSqlOutput, ValueRef},
+    Row, ToSql,
+};
+
+#[derive(Debug, Clone)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> sqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(match self {
+            SqlValue::Null => ValueRef::Null,
+            SqlValue::Integer(v) => ValueRef::Integer(*v),
+            SqlValue::Real(v) => ValueRef::Real(*v),
+            SqlValue::Text(v) => ValueRef::Text(v.as_bytes()),
+            SqlValue::Blob(v) => ValueRef::Blob(v),
+        }))
+    }
+}
+
+/// accepts None, bool (as 0 or 1), int, float, str and bytes
+impl<'source> FromPyObject<'source> for SqlValue {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        if ob.is_none() {
+            Ok(SqlValue::Null)
+        } else if let Ok(v) = ob.downcast::<PyBool>() {
+            // bool is a subclass of int, so it must be checked first
+            Ok(SqlValue::Integer(v.is_true() as i64))
+        } else if ob.is_instance_of::<PyLong>() {
+            Ok(SqlValue::Integer(ob.extract()?))
+        } else if ob.is_instance_of::<PyFloat>() {
+            Ok(SqlValue::Real(ob.extract()?))
+        } else if let Ok(v) = ob.downcast::<PyString>() {
+            Ok(SqlValue::Text(v.to_str()?.to_owned()))
+        } else if let Ok(v) = ob.downcast::<PyBytes>() {
+            Ok(SqlValue::Blob(v.as_bytes().to_vec()))
+        } else {
+            Err(PyTypeError::new_err(format!(
+                "unsupported sql param of type {}",
+                ob.get_type().name()?
+            )))
+        }
+    }
+}
+
+fn value_to_py(py: Python<'_>, value: ValueRef<'_>) -> PyObject {
+    match value {
+        ValueRef::Null => py.None(),
+        ValueRef::Integer(v) => v.into_py(py),
+        ValueRef::Real(v) => v.into_py(py),
+        ValueRef::Text(v) => String::from_utf8_lossy(v).into_py(py),
+        ValueRef::Blob(v) => PyBytes::new(py, v).into(),
+    }
+}
+
+/// read a query result row into a dict keyed by column name
+pub fn row_to_dict<'py>(
+    py: Python<'py>,
+    columns: &[String],
+    row: &Row<'_>,
+) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    for (i, column) in columns.iter().enumerate() {
+        let value = row.get_ref(i).map_err(crate::to_py_err)?;
+        dict.set_item(column, value_to_py(py, value))?;
+    }
+    Ok(dict)
+}
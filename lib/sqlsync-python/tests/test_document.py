@@ -0,0 +1,79 @@
+# run with `python -m unittest discover tests`, after building the binding
+# with `maturin develop` and the counter reducer with
+# `just wasm-counter-reducer`
+import shutil
+import tempfile
+import unittest
+from pathlib import Path
+
+from sqlsync import Document, SqlSyncError
+
+REDUCER = (
+    Path(__file__).parents[3]
+    / "target/wasm32-unknown-unknown/debug/examples/counter_reducer.wasm"
+).read_bytes()
+
+# counter mutations are bincode enums: a little endian u32 variant index
+INIT_SCHEMA = (0).to_bytes(4, "little")
+INCR = (1).to_bytes(4, "little")
+
+DOC_ID = "VM7fC4gKxa52pbdtrgd9G9"
+
+
+def counter(doc):
+    rows = doc.query("select value from counter where id = ?", [0])
+    return rows[0]["value"] if rows else None
+
+
+class DocumentTest(unittest.TestCase):
+    def setUp(self):
+        self.dir = tempfile.mkdtemp(prefix="sqlsync-python-")
+
+    def tearDown(self):
+        shutil.rmtree(self.dir, ignore_errors=True)
+
+    def test_mutations_are_applied_and_queryable(self):
+        doc = Document.open(DOC_ID, REDUCER, self.dir)
+        self.assertEqual(doc.doc_id, DOC_ID)
+
+        # not connected, so there is nothing to send
+        self.assertEqual(doc.mutate(INIT_SCHEMA), [])
+        doc.mutate(INCR)
+        doc.mutate(INCR)
+        self.assertEqual(counter(doc), 2)
+
+        rows = doc.query(
+            "select ? as n, ? as big, ? as s, ? as b, ? as z",
+            [1, 2**60, "hi", b"\x00\xff", None],
+        )
+        self.assertEqual(
+            rows, [{"n": 1, "big": 2**60, "s": "hi", "b": b"\x00\xff", "z": None}]
+        )
+
+        with self.assertRaises(SqlSyncError):
+            doc.query("select * from missing")
+
+    def test_pending_mutations_survive_reopening_the_storage_path(self):
+        doc = Document.open(DOC_ID, REDUCER, self.dir)
+        doc.mutate(INIT_SCHEMA)
+        doc.mutate(INCR)
+        del doc
+
+        reopened = Document.open(DOC_ID, REDUCER, self.dir)
+        self.assertEqual(counter(reopened), 1)
+
+        # a new connection replays the timeline to the coordinator
+        self.assertEqual(len(reopened.connect()), 2)
+        self.assertGreater(reopened.heartbeat_deadline(), 0)
+        self.assertEqual(reopened.heartbeat(), [])
+        reopened.disconnect()
+        self.assertIsNone(reopened.heartbeat_deadline())
+
+    def test_receive_requires_a_connection(self):
+        doc = Document.open(DOC_ID, REDUCER, self.dir)
+        with self.assertRaises(SqlSyncError):
+            doc.receive(b"")
+
+
+if __name__ == "__main__":
+    unittest.main()
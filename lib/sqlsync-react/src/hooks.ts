@@ -1,10 +1,15 @@
-import { ConnectionStatus, DocId } from "@orbitinghail/sqlsync-worker";
-import { deepEqual } from "fast-equals";
-import { useCallback, useContext, useEffect, useRef, useState } from "react";
+import {
+  DocType,
+  ParameterizedQuery,
+  QueryState,
+  Row,
+  SQLSync,
+  normalizeQuery,
+  queryStoreFor,
+} from "@orbitinghail/sqlsync-client";
+import { ConnectionStatus, DocId, MutationReceipt } from "@orbitinghail/sqlsync-worker";
+import { useCallback, useContext, useEffect, useState, useSyncExternalStore } from "react";
 import { SQLSyncContext } from "./context";
-import { ParameterizedQuery, normalizeQuery } from "./sql";
-import { DocType, QuerySubscription, Row, SQLSync } from "./sqlsync";
-import { pendingPromise } from "./util";
 
 export function useSQLSync(): SQLSync {
   const value = useContext(SQLSyncContext);
@@ -17,10 +22,15 @@ export function useSQLSync(): SQLSync {
   return value!;
 }
 
-type MutateFn<M> = (mutation: M) => Promise<void>;
+// resolves to the mutation's receipt, see SQLSync.mutate
+type MutateFn<M> = (mutation: M) => Promise<MutationReceipt | undefined>;
 type UseMutateFn<M> = (docId: DocId) => MutateFn<M>;
 
-type UseQueryFn = <R = Row>(docId: DocId, query: ParameterizedQuery | string) => QueryState<R>;
+type UseQueryFn = <R = Row>(
+  docId: DocId,
+  query: ParameterizedQuery | string,
+  opts?: QueryOptions,
+) => QueryState<R>;
 
 type SetConnectionEnabledFn = (enabled: boolean) => Promise<void>;
 type UseSetConnectionEnabledFn = (docId: DocId) => SetConnectionEnabledFn;
@@ -40,8 +50,12 @@ export function createDocHooks<M>(docType: DocType<M>): DocHooks<M> {
     );
   };
 
-  const useQueryWrapper = <R = Row>(docId: DocId, query: ParameterizedQuery | string) => {
-    return useQuery<M, R>(docType, docId, query);
+  const useQueryWrapper = <R = Row>(
+    docId: DocId,
+    query: ParameterizedQuery | string,
+    opts?: QueryOptions,
+  ) => {
+    return useQuery<M, R>(docType, docId, query, opts);
   };
 
   const useSetConnectionEnabledWrapper = (docId: DocId) => {
@@ -59,58 +73,37 @@ export function createDocHooks<M>(docType: DocType<M>): DocHooks<M> {
   };
 }
 
-export type QueryState<R> =
-  | { state: "pending"; rows?: R[] }
-  | { state: "success"; rows: R[] }
-  | { state: "error"; error: Error; rows?: R[] };
+export interface QueryOptions {
+  // suspend while the first result is pending, and throw errors to the
+  // nearest error boundary rather than returning them
+  suspense?: boolean;
+}
 
+// queries are kept in the SQLSync instance's QueryStore by doc and query, so
+// components reading the same query share one subscription, and params are
+// compared by value rather than identity
 export function useQuery<M, R = Row>(
   docType: DocType<M>,
   docId: DocId,
   rawQuery: ParameterizedQuery | string,
+  opts?: QueryOptions,
 ): QueryState<R> {
   const sqlsync = useSQLSync();
-  const [state, setState] = useState<QueryState<R>>({ state: "pending" });
-
-  // memoize query based on deep equality
-  let query = normalizeQuery(rawQuery);
-  const queryRef = useRef<ParameterizedQuery>(query);
-  if (!deepEqual(queryRef.current, query)) {
-    queryRef.current = query;
+  const entry = queryStoreFor(sqlsync).get(docType, docId, normalizeQuery(rawQuery));
+
+  // switching to a different entry, or reading one which was evicted,
+  // subscribes to it automatically
+  const state = useSyncExternalStore(entry.subscribe, entry.getSnapshot);
+
+  if (opts?.suspense) {
+    if (state.state === "pending") {
+      throw entry.settled;
+    }
+    if (state.state === "error") {
+      throw state.error;
+    }
   }
-  query = queryRef.current;
-
-  useEffect(() => {
-    const [unsubPromise, unsubResolve] = pendingPromise<() => void>();
-
-    const subscription: QuerySubscription = {
-      handleRows: (rows: Row[]) => setState({ state: "success", rows: rows as R[] }),
-      handleErr: (err: string) =>
-        setState((s) => ({
-          state: "error",
-          error: new Error(err),
-          rows: s.rows,
-        })),
-    };
-
-    sqlsync
-      .subscribe(docId, docType, query, subscription)
-      .then(unsubResolve)
-      .catch((err: Error) => {
-        console.error("sqlsync: error subscribing", err);
-        setState({ state: "error", error: err });
-      });
-
-    return () => {
-      unsubPromise
-        .then((unsub) => unsub())
-        .catch((err) => {
-          console.error("sqlsync: error unsubscribing", err);
-        });
-    };
-  }, [sqlsync, docId, docType, query]);
-
-  return state;
+  return state as QueryState<R>;
 }
 
 export const useConnectionStatus = (): ConnectionStatus => {
@@ -1,10 +1,8 @@
+import { DocType, QueryState, Row, serializeMutationAsJSON, sql } from "@orbitinghail/sqlsync-client";
 import { SQLSyncProvider } from "./context";
-import { createDocHooks, useConnectionStatus } from "./hooks";
-import { sql } from "./sql";
-import { DocType, Row } from "./sqlsync";
-import { serializeMutationAsJSON } from "./util";
+import { QueryOptions, createDocHooks, useConnectionStatus } from "./hooks";
 
 export { SQLSyncProvider, createDocHooks, serializeMutationAsJSON, sql, useConnectionStatus };
-export type { DocType, Row };
+export type { DocType, QueryOptions, QueryState, Row };
 
 // eof: this file only exports
@@ -22,9 +22,15 @@ fn main() -> anyhow::Result<()> {
         .init()?;
 
     // build guest.wasm using: `cargo build --target wasm32-unknown-unknown --example guest`
-    let wasm_bytes = include_bytes!(
-        "../../../target/wasm32-unknown-unknown/debug/examples/guest.wasm"
-    );
+    // or pass the path of a guest built some other way, such as with the
+    // small-alloc feature and the reducer profile
+    let wasm_bytes = match std::env::args().nth(1) {
+        Some(path) => std::fs::read(path)?,
+        None => include_bytes!(
+            "../../../target/wasm32-unknown-unknown/debug/examples/guest.wasm"
+        )
+        .to_vec(),
+    };
 
     let engine = Engine::default();
     let module = Module::new(&engine, &wasm_bytes[..])?;
@@ -43,6 +49,10 @@ fn main() -> anyhow::Result<()> {
     // initialize the reducer
     ffi.init_reducer(&mut store)?;
 
+    // let the guest reuse its request and response buffers
+    let pool_bytes = ffi.negotiate_buf_pool(&mut store, 64 * 1024)?;
+    assert_eq!(pool_bytes, 64 * 1024);
+
     let mutation = Mutation::Set("hello".to_string(), "world".to_string());
     let mutation = &bincode::serialize(&mutation)?;
 
@@ -0,0 +1,110 @@
+//! Bulk inserts, for mutations which carry many rows at once, such as the
+//! batches built by the JS client's import. A multi-row INSERT per batch is
+//! much cheaper than a statement per row, both for the client and for the
+//! coordinator replaying the mutation. Reducers run the statements with
+//! guest_reactor::insert_rows.
+
+use crate::types::{ReducerError, SqliteValue};
+
+/// the most parameters sqlite binds in one statement, see
+/// SQLITE_MAX_VARIABLE_NUMBER
+const MAX_PARAMS: usize = 32766;
+
+#[derive(Debug, Clone)]
+pub struct InsertStatement {
+    pub sql: String,
+    pub params: Vec<SqliteValue>,
+}
+
+/// the statements which insert rows into the columns of table, as few as
+/// sqlite's parameter limit allows. identifiers are quoted, but reducers
+/// should still check that table is one the mutation may write to
+pub fn insert_statements<R, V>(
+    table: &str,
+    columns: &[String],
+    rows: impl IntoIterator<Item = R>,
+) -> Result<Vec<InsertStatement>, ReducerError>
+where
+    R: IntoIterator<Item = V>,
+    V: Into<SqliteValue>,
+{
+    if columns.is_empty() {
+        return Err(ReducerError::Unknown(
+            "bulk insert needs at least one column".into(),
+        ));
+    }
+    let names: Vec<_> = columns.iter().map(|c| quote(c)).collect();
+    let prefix = format!(
+        "INSERT INTO {} ({}) VALUES ",
+        quote(table),
+        names.join(", ")
+    );
+    let placeholder = format!("({})", vec!["?"; columns.len()].join(", "));
+    let rows_per_statement = MAX_PARAMS / columns.len();
+
+    let mut statements = Vec::new();
+    let mut params = Vec::new();
+    let mut num_rows = 0;
+    for (i, row) in rows.into_iter().enumerate() {
+        let before = params.len();
+        params.extend(row.into_iter().map(Into::into));
+        if params.len() - before != columns.len() {
+            return Err(ReducerError::Unknown(format!(
+                "row {} has {} values, expected {}",
+                i,
+                params.len() - before,
+                columns.len()
+            )));
+        }
+        num_rows += 1;
+        if num_rows == rows_per_statement {
+            statements.push(statement(&prefix, &placeholder, num_rows, params));
+            params = Vec::new();
+            num_rows = 0;
+        }
+    }
+    if num_rows > 0 {
+        statements.push(statement(&prefix, &placeholder, num_rows, params));
+    }
+    Ok(statements)
+}
+
+fn statement(
+    prefix: &str,
+    placeholder: &str,
+    num_rows: usize,
+    params: Vec<SqliteValue>,
+) -> InsertStatement {
+    let sql = prefix.to_owned() + &vec![placeholder; num_rows].join(", ");
+    InsertStatement { sql, params }
+}
+
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::insert_statements;
+
+    #[test]
+    fn splits_rows_at_the_parameter_limit() {
+        let columns: Vec<String> =
+            (0..1000).map(|i| format!("c{}", i)).collect();
+        let rows = (0..70).map(|_| vec![1i64; 1000]);
+        let statements = insert_statements("t\"x", &columns, rows).unwrap();
+
+        // 32 rows of 1000 params fit in each statement
+        let lens: Vec<_> = statements.iter().map(|s| s.params.len()).collect();
+        assert_eq!(lens, vec![32000, 32000, 6000]);
+        assert!(statements[0]
+            .sql
+            .starts_with("INSERT INTO \"t\"\"x\" (\"c0\""));
+        assert!(statements[2].sql.ends_with("?)"));
+        assert_eq!(statements[2].sql.matches("),").count(), 5);
+
+        let short = vec![vec![Some("a".to_owned())], vec![]];
+        let columns = vec!["a".to_owned()];
+        assert!(insert_statements("t", &columns, short).is_err());
+    }
+}
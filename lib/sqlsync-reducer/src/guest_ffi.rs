@@ -20,51 +20,106 @@ pub fn fbm() -> &'static mut FFIBufManager {
     }
 }
 
+// the most released buffers the pool holds, whatever their size
+const MAX_POOLED_BUFS: usize = 16;
+
 pub struct FFIBufManager {
-    // map from pointer to buffer to length of buffer
-    bufs: BTreeMap<FFIBufPtr, FFIBufLen>,
+    // map from pointer to buffer to length and capacity of buffer
+    bufs: BTreeMap<FFIBufPtr, (FFIBufLen, usize)>,
+
+    // buffers which have been released, reused by later allocations rather
+    // than going back to the allocator. the pool stays empty unless the host
+    // sets a limit through ffi_buf_pool
+    pool: Vec<FFIBuf>,
+    pool_bytes: usize,
+    pool_limit: usize,
 }
 
 impl FFIBufManager {
     pub fn new() -> Self {
         Self {
             bufs: BTreeMap::new(),
+            pool: Vec::new(),
+            pool_bytes: 0,
+            pool_limit: 0,
         }
     }
 
     pub fn alloc(&mut self, len: FFIBufLen) -> FFIBufPtr {
-        let mut buf = Vec::with_capacity(len as usize);
-        let ptr = buf.as_mut_ptr();
-        self.bufs.insert(ptr, len);
-        std::mem::forget(buf);
-        ptr
+        let mut buf = self.take(len as usize);
+        // the host overwrites the whole buffer
+        buf.resize(len as usize, 0);
+        self.track(buf)
     }
 
     pub fn dealloc(&mut self, ptr: FFIBufPtr) {
-        self.consume(ptr);
-        // immediately drops the vec, freeing the memory
+        let buf = self.consume(ptr);
+        self.recycle(buf);
     }
 
     pub fn length(&self, ptr: FFIBufPtr) -> FFIBufLen {
-        *self.bufs.get(&ptr).unwrap()
+        self.bufs.get(&ptr).unwrap().0
     }
 
     pub fn consume(&mut self, ptr: FFIBufPtr) -> FFIBuf {
-        let len = self.bufs.remove(&ptr).unwrap();
-        unsafe { Vec::from_raw_parts(ptr, len as usize, len as usize) }
+        let (len, capacity) = self.bufs.remove(&ptr).unwrap();
+        unsafe { Vec::from_raw_parts(ptr, len as usize, capacity) }
     }
 
     pub fn encode<T: Serialize>(&mut self, data: &T) -> Result<FFIBufPtr, bincode::Error> {
-        let mut buf = bincode::serialize(data)?;
-        let ptr = buf.as_mut_ptr();
-        self.bufs.insert(ptr, buf.len() as FFIBufLen);
-        std::mem::forget(buf);
-        Ok(ptr)
+        let mut buf = self.take(bincode::serialized_size(data)? as usize);
+        bincode::serialize_into(&mut buf, data)?;
+        Ok(self.track(buf))
     }
 
     pub fn decode<T: DeserializeOwned>(&mut self, ptr: FFIBufPtr) -> Result<T, bincode::Error> {
         let buf = self.consume(ptr);
-        bincode::deserialize(&buf)
+        let out = bincode::deserialize(&buf);
+        self.recycle(buf);
+        out
+    }
+
+    /// keep up to limit bytes of released buffers around for reuse
+    pub fn set_pool_limit(&mut self, limit: usize) {
+        self.pool_limit = limit;
+        while self.pool_bytes > limit {
+            let buf = self.pool.pop().unwrap();
+            self.pool_bytes -= buf.capacity();
+        }
+    }
+
+    fn track(&mut self, mut buf: FFIBuf) -> FFIBufPtr {
+        let ptr = buf.as_mut_ptr();
+        self.bufs
+            .insert(ptr, (buf.len() as FFIBufLen, buf.capacity()));
+        std::mem::forget(buf);
+        ptr
+    }
+
+    /// an empty buffer with room for len bytes, the smallest pooled one
+    /// which fits if there is one
+    fn take(&mut self, len: usize) -> FFIBuf {
+        let fits = (0..self.pool.len())
+            .filter(|&i| self.pool[i].capacity() >= len)
+            .min_by_key(|&i| self.pool[i].capacity());
+        match fits {
+            Some(i) => {
+                let buf = self.pool.swap_remove(i);
+                self.pool_bytes -= buf.capacity();
+                buf
+            }
+            None => Vec::with_capacity(len),
+        }
+    }
+
+    fn recycle(&mut self, mut buf: FFIBuf) {
+        let fits = self.pool_bytes + buf.capacity() <= self.pool_limit;
+        if fits && self.pool.len() < MAX_POOLED_BUFS {
+            buf.clear();
+            self.pool_bytes += buf.capacity();
+            self.pool.push(buf);
+        }
+        // otherwise the buffer is dropped, freeing the memory
     }
 }
 
@@ -83,6 +138,16 @@ pub fn ffi_buf_len(ptr: FFIBufPtr) -> FFIBufLen {
     fbm().length(ptr)
 }
 
+/// called by hosts which support buffer reuse once the reducer has been
+/// initialized, with the most memory the guest may hold on to between calls
+/// for reusing request and response buffers. returns the limit the guest
+/// agreed to
+#[no_mangle]
+pub fn ffi_buf_pool(max_bytes: FFIBufLen) -> FFIBufLen {
+    fbm().set_pool_limit(max_bytes as usize);
+    max_bytes
+}
+
 extern "C" {
     fn host_log(log_req: FFIBufPtr);
 }
@@ -123,3 +188,80 @@ fn panic_hook(info: &panic::PanicInfo) {
     let record_ptr = fbm().encode(&record).unwrap();
     unsafe { host_log(record_ptr) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FFIBufManager, MAX_POOLED_BUFS};
+
+    #[test]
+    fn buffers_are_only_pooled_once_the_host_sets_a_limit() {
+        let mut fbm = FFIBufManager::new();
+        let ptr = fbm.alloc(100);
+        assert_eq!(fbm.length(ptr), 100);
+        fbm.dealloc(ptr);
+        assert!(fbm.pool.is_empty());
+
+        fbm.set_pool_limit(1024);
+        let ptr = fbm.alloc(100);
+        fbm.dealloc(ptr);
+        assert_eq!(fbm.pool.len(), 1);
+        assert_eq!(fbm.pool_bytes, 100);
+
+        // the pooled buffer is reused by anything it fits
+        let reused = fbm.alloc(50);
+        assert_eq!(reused, ptr);
+        assert_eq!(fbm.length(reused), 50);
+        assert_eq!(fbm.pool_bytes, 0);
+        fbm.dealloc(reused);
+
+        // lowering the limit frees buffers past it
+        fbm.set_pool_limit(10);
+        assert!(fbm.pool.is_empty());
+        assert_eq!(fbm.pool_bytes, 0);
+    }
+
+    #[test]
+    fn the_smallest_pooled_buffer_which_fits_is_taken() {
+        let mut fbm = FFIBufManager::new();
+        fbm.set_pool_limit(usize::MAX);
+        let ptrs: Vec<_> = [300, 100, 200].map(|len| fbm.alloc(len)).into();
+        for &ptr in &ptrs {
+            fbm.dealloc(ptr);
+        }
+        assert_eq!(fbm.alloc(150), ptrs[2]);
+        assert_eq!(fbm.alloc(150), ptrs[0]);
+        assert_eq!(fbm.alloc(50), ptrs[1]);
+        assert!(fbm.pool.is_empty());
+    }
+
+    #[test]
+    fn the_pool_holds_a_bounded_number_of_buffers() {
+        let mut fbm = FFIBufManager::new();
+        fbm.set_pool_limit(usize::MAX);
+        let ptrs: Vec<_> =
+            (0..MAX_POOLED_BUFS + 4).map(|_| fbm.alloc(8)).collect();
+        for ptr in ptrs {
+            fbm.dealloc(ptr);
+        }
+        assert_eq!(fbm.pool.len(), MAX_POOLED_BUFS);
+        assert_eq!(fbm.pool_bytes, MAX_POOLED_BUFS * 8);
+    }
+
+    #[test]
+    fn encodes_and_decodes_through_the_pool() {
+        let mut fbm = FFIBufManager::new();
+        fbm.set_pool_limit(1024);
+        let value = (1u64, "hello".to_string());
+        let ptr = fbm.encode(&value).unwrap();
+        assert_eq!(
+            fbm.length(ptr) as u64,
+            bincode::serialized_size(&value).unwrap()
+        );
+        let decoded: (u64, String) = fbm.decode(ptr).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(fbm.pool.len(), 1);
+
+        // the decoded buffer is reused for the next encode
+        assert_eq!(fbm.encode(&value).unwrap(), ptr);
+    }
+}
@@ -10,6 +10,7 @@ use std::{
 use serde::de::DeserializeOwned;
 
 use crate::{
+    bulk::insert_statements,
     guest_ffi::{fbm, FFIBufPtr},
     types::{
         ErrorResponse, ExecResponse, QueryResponse, ReducerError, Request,
@@ -148,6 +149,24 @@ pub fn raw_execute(
     ResponseFuture::new(id)
 }
 
+/// insert rows into the columns of table, returning how many were inserted;
+/// see crate::bulk
+pub async fn insert_rows<R, V>(
+    table: &str,
+    columns: &[String],
+    rows: impl IntoIterator<Item = R>,
+) -> Result<usize, ReducerError>
+where
+    R: IntoIterator<Item = V>,
+    V: Into<SqliteValue>,
+{
+    let mut inserted = 0;
+    for stmt in insert_statements(table, columns, rows)? {
+        inserted += raw_execute(stmt.sql, stmt.params).await?.changes;
+    }
+    Ok(inserted)
+}
+
 #[macro_export]
 macro_rules! query {
     ($sql:expr $(, $arg:expr)*) => {
@@ -189,6 +208,39 @@ macro_rules! init_reducer {
             sqlsync_reducer::guest_ffi::install_panic_hook();
         }
     };
+
+    // task should be (String) -> Future<Output = Result<(), ReducerError>>
+    // and is called with the name of each scheduled task the coordinator runs
+    ($fn:ident, task = $task:ident) => {
+        sqlsync_reducer::init_reducer!($fn);
+
+        #[no_mangle]
+        pub fn ffi_task(
+            name_ptr: sqlsync_reducer::guest_ffi::FFIBufPtr,
+        ) -> sqlsync_reducer::guest_ffi::FFIBufPtr {
+            let reactor = sqlsync_reducer::guest_reactor::reactor();
+            let fbm = sqlsync_reducer::guest_ffi::fbm();
+            let name = String::from_utf8(fbm.consume(name_ptr)).unwrap();
+
+            reactor.spawn(Box::pin(async move { $task(name).await }));
+
+            let requests = reactor.step(None);
+            fbm.encode(&requests).unwrap()
+        }
+    };
+}
+
+/// declare the reducer's version. clients only refuse to mutate a document
+/// whose coordinator runs a different reducer once the coordinator's version
+/// is newer than theirs, so bump it whenever mutations change meaning
+#[macro_export]
+macro_rules! reducer_version {
+    ($version:expr) => {
+        #[no_mangle]
+        pub extern "C" fn ffi_reducer_version() -> u32 {
+            $version
+        }
+    };
 }
 
 #[no_mangle]
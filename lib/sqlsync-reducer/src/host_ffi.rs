@@ -20,9 +20,15 @@ pub enum WasmFFI {
         ffi_buf_allocate: TypedFunc<FFIBufLen, FFIBufPtr>,
         ffi_buf_deallocate: TypedFunc<FFIBufPtr, ()>,
         ffi_buf_len: TypedFunc<FFIBufPtr, FFIBufLen>,
+        /// only exported by reducers which can reuse their ffi buffers
+        ffi_buf_pool: Option<TypedFunc<FFIBufLen, FFIBufLen>>,
         ffi_init_reducer: TypedFunc<(), ()>,
         ffi_reduce: TypedFunc<FFIBufPtr, FFIBufPtr>,
         ffi_reactor_step: TypedFunc<FFIBufPtr, FFIBufPtr>,
+        /// only exported by reducers which define scheduled tasks
+        ffi_task: Option<TypedFunc<FFIBufPtr, FFIBufPtr>>,
+        /// only exported by reducers which declare a version
+        ffi_reducer_version: Option<TypedFunc<(), u32>>,
     },
 }
 
@@ -47,6 +53,9 @@ impl WasmFFI {
             .get_typed_func::<FFIBufPtr, ()>(store, "ffi_buf_deallocate")?;
         let ffi_buf_len = instance
             .get_typed_func::<FFIBufPtr, FFIBufLen>(store, "ffi_buf_len")?;
+        let ffi_buf_pool = instance
+            .get_typed_func::<FFIBufLen, FFIBufLen>(store, "ffi_buf_pool")
+            .ok();
         let ffi_init_reducer =
             instance.get_typed_func::<(), ()>(store, "ffi_init_reducer")?;
         let ffi_reduce = instance
@@ -56,23 +65,33 @@ impl WasmFFI {
                 store,
                 "ffi_reactor_step",
             )?;
+        let ffi_task = instance
+            .get_typed_func::<FFIBufPtr, FFIBufPtr>(store, "ffi_task")
+            .ok();
+        let ffi_reducer_version = instance
+            .get_typed_func::<(), u32>(store, "ffi_reducer_version")
+            .ok();
 
         Ok(Self::Initialized {
             memory,
             ffi_buf_allocate,
             ffi_buf_deallocate,
             ffi_buf_len,
+            ffi_buf_pool,
             ffi_init_reducer,
             ffi_reduce,
             ffi_reactor_step,
+            ffi_task,
+            ffi_reducer_version,
         })
     }
 
-    fn consume(
+    /// decode the buffer in place, then release it back to the guest
+    fn decode<T: DeserializeOwned>(
         &self,
         mut store: impl AsContextMut,
         ptr: FFIBufPtr,
-    ) -> Result<FFIBuf, WasmFFIError> {
+    ) -> Result<T, WasmFFIError> {
         match self {
             Self::Uninitialized => Err(WasmFFIError::Uninitialized),
             Self::Initialized {
@@ -82,10 +101,10 @@ impl WasmFFI {
                 ..
             } => {
                 let len = ffi_buf_len.call(&mut store, ptr)?;
-                let mem = memory.data(&store);
-                let buf = mem[ptr as usize..(ptr + len) as usize].to_vec();
+                let buf = guest_buf(memory.data(&store), ptr, len)?;
+                let out = bincode::deserialize(buf);
                 ffi_buf_deallocate.call(&mut store, ptr)?;
-                Ok(buf)
+                Ok(out?)
             }
         }
     }
@@ -100,29 +119,61 @@ impl WasmFFI {
             Self::Initialized { memory, ffi_buf_allocate, .. } => {
                 let len = buf.len() as FFIBufLen;
                 let ptr = ffi_buf_allocate.call(&mut store, len)?;
-                let mem = memory.data_mut(&mut store);
-                mem[ptr as usize..(ptr + len) as usize].copy_from_slice(buf);
+                guest_buf_mut(memory.data_mut(&mut store), ptr, len)?
+                    .copy_from_slice(buf);
                 Ok(ptr)
             }
         }
     }
 
-    fn decode<T: DeserializeOwned>(
-        &self,
-        mut store: impl AsContextMut,
-        ptr: FFIBufPtr,
-    ) -> Result<T, WasmFFIError> {
-        let buf = self.consume(&mut store, ptr)?;
-        Ok(bincode::deserialize(&buf)?)
-    }
-
+    /// encode data straight into a buffer allocated by the guest
     pub fn encode<T: Serialize>(
         &self,
         mut store: impl AsContextMut,
         data: T,
     ) -> Result<FFIBufPtr, WasmFFIError> {
-        let bytes = bincode::serialize(&data)?;
-        self.persist(&mut store, &bytes)
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { memory, ffi_buf_allocate, .. } => {
+                let len = bincode::serialized_size(&data)? as FFIBufLen;
+                let ptr = ffi_buf_allocate.call(&mut store, len)?;
+                let buf = guest_buf_mut(memory.data_mut(&mut store), ptr, len)?;
+                bincode::serialize_into(buf, &data)?;
+                Ok(ptr)
+            }
+        }
+    }
+
+    /// let the guest keep up to max_bytes of released buffers to reuse for
+    /// later requests and responses. returns the limit the guest agreed to,
+    /// which is 0 for reducers built before buffers could be reused
+    pub fn negotiate_buf_pool(
+        &self,
+        mut ctx: impl AsContextMut,
+        max_bytes: FFIBufLen,
+    ) -> Result<FFIBufLen, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_buf_pool: None, .. } => Ok(0),
+            Self::Initialized { ffi_buf_pool: Some(ffi_buf_pool), .. } => {
+                Ok(ffi_buf_pool.call(&mut ctx, max_bytes)?)
+            }
+        }
+    }
+
+    /// the version the reducer declared with reducer_version!, if any
+    pub fn reducer_version(
+        &self,
+        mut ctx: impl AsContextMut,
+    ) -> Result<Option<u32>, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_reducer_version: None, .. } => Ok(None),
+            Self::Initialized {
+                ffi_reducer_version: Some(ffi_reducer_version),
+                ..
+            } => Ok(Some(ffi_reducer_version.call(&mut ctx, ())?)),
+        }
     }
 
     pub fn init_reducer(
@@ -154,6 +205,26 @@ impl WasmFFI {
         }
     }
 
+    pub fn task(
+        &self,
+        mut ctx: impl AsContextMut,
+        name: &str,
+    ) -> Result<Requests, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_task: None, .. } => {
+                Err(WasmFFIError::TaskNotExported)
+            }
+            Self::Initialized { ffi_task: Some(ffi_task), .. } => {
+                let name_ptr = self.persist(&mut ctx, name.as_bytes())?;
+                let requests_ptr = ffi_task.call(&mut ctx, name_ptr)?;
+                let requests: Result<Requests, ReducerError> =
+                    self.decode(&mut ctx, requests_ptr)?;
+                Ok(requests?)
+            }
+        }
+    }
+
     pub fn reactor_step(
         &self,
         mut ctx: impl AsContextMut,
@@ -189,6 +260,12 @@ pub enum WasmFFIError {
 
     #[error("Wasm FFI must be initialized before use")]
     Uninitialized,
+
+    #[error("reducer doesn't export any tasks")]
+    TaskNotExported,
+
+    #[error("guest buffer of {len} bytes at {ptr} is outside its memory")]
+    OutOfBounds { ptr: FFIBufPtr, len: FFIBufLen },
 }
 
 impl HostError for WasmFFIError {}
@@ -205,16 +282,51 @@ impl From<Trap> for WasmFFIError {
     }
 }
 
+/// the len bytes of guest memory at ptr. the guest picks both, so a buggy
+/// or malicious reducer can hand the host a buffer outside its memory
+pub fn guest_buf(
+    mem: &[u8],
+    ptr: FFIBufPtr,
+    len: FFIBufLen,
+) -> Result<&[u8], WasmFFIError> {
+    ptr.checked_add(len)
+        .and_then(|end| mem.get(ptr as usize..end as usize))
+        .ok_or(WasmFFIError::OutOfBounds { ptr, len })
+}
+
+/// like guest_buf, for the host to write into
+pub fn guest_buf_mut(
+    mem: &mut [u8],
+    ptr: FFIBufPtr,
+    len: FFIBufLen,
+) -> Result<&mut [u8], WasmFFIError> {
+    ptr.checked_add(len)
+        .and_then(|end| mem.get_mut(ptr as usize..end as usize))
+        .ok_or(WasmFFIError::OutOfBounds { ptr, len })
+}
+
+/// register a log handler which forwards guest logs to the `log` crate
 pub fn register_log_handler(
     linker: &mut Linker<WasmFFI>,
 ) -> Result<(), LinkerError> {
+    register_log_handler_with(linker, |record| record.log())
+}
+
+/// register a log handler which passes every guest log record to `handler`
+pub fn register_log_handler_with<F>(
+    linker: &mut Linker<WasmFFI>,
+    handler: F,
+) -> Result<(), LinkerError>
+where
+    F: Fn(&LogRecord) + Send + Sync + 'static,
+{
     linker.func_wrap(
         "env",
         "host_log",
-        |mut ctx: Caller<'_, WasmFFI>, record_ptr: FFIBufPtr| {
+        move |mut ctx: Caller<'_, WasmFFI>, record_ptr: FFIBufPtr| {
             let exports = *ctx.data();
             let record: LogRecord = exports.decode(&mut ctx, record_ptr)?;
-            record.log();
+            handler(&record);
             Ok(())
         },
     )?;
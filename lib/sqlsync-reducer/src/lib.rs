@@ -1,4 +1,7 @@
+pub mod bulk;
+pub mod mutation;
 pub mod types;
+pub mod typescript;
 
 #[cfg(feature = "guest")]
 pub mod guest_reactor;
@@ -8,3 +11,12 @@ pub mod guest_ffi;
 
 #[cfg(feature = "host")]
 pub mod host_ffi;
+
+// reducers run single threaded, so the allocator needs no locking
+#[cfg(all(feature = "small-alloc", target_arch = "wasm32"))]
+#[global_allocator]
+static ALLOCATOR: lol_alloc::AssumeSingleThreaded<
+    lol_alloc::FreeListAllocator,
+> = unsafe {
+    lol_alloc::AssumeSingleThreaded::new(lol_alloc::FreeListAllocator::new())
+};
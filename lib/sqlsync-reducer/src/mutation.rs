@@ -0,0 +1,129 @@
+//! Typed mutations. A reducer and its hosts have to agree on how mutations
+//! are encoded, which is easy to get wrong when each side declares its own
+//! copy of the mutation enum. Instead declare it once with [`mutations!`] in
+//! a module or crate which both the reducer and the host depend on:
+//!
+//! ```ignore
+//! sqlsync_reducer::mutations! {
+//!     #[derive(Debug)]
+//!     pub enum Mutation {
+//!         InitSchema,
+//!         AddTodo { id: u64, title: String },
+//!     }
+//! }
+//! ```
+//!
+//! Hosts then submit `Mutation::AddTodo { .. }` directly (see
+//! LocalDocument::mutate_typed in sqlsync) and the reducer decodes it with
+//! [`TypedMutation::decode`]. Any change to the enum changes both sides at
+//! once, so they can't drift apart. The enum derives serde's traits, so the
+//! crate declaring it must depend on serde. JS clients can stay in sync too,
+//! using definitions generated by [`crate::typescript`].
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{types::ReducerError, typescript::Variant};
+
+/// TypedMutation is implemented by enums declared with [`mutations!`]
+pub trait TypedMutation: Serialize + DeserializeOwned {
+    /// the enum's declaration, for hosts which want to check at runtime that
+    /// they were built against the same mutations as a reducer
+    const SCHEMA: &'static str;
+    /// the enum's name
+    const NAME: &'static str;
+
+    /// the enum's variants, in declaration order; see [`crate::typescript`]
+    fn variants() -> Vec<Variant>;
+
+    fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("mutations are always serializable")
+    }
+
+    fn decode(mutation: &[u8]) -> Result<Self, ReducerError> {
+        Ok(bincode::deserialize(mutation)?)
+    }
+}
+
+#[macro_export]
+macro_rules! mutations {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident { $($body:tt)* }
+    ) => {
+        $(#[$meta])*
+        #[derive(::serde::Serialize, ::serde::Deserialize)]
+        $vis enum $name { $($body)* }
+
+        impl $crate::mutation::TypedMutation for $name {
+            const SCHEMA: &'static str =
+                stringify!(enum $name { $($body)* });
+            const NAME: &'static str = stringify!($name);
+
+            fn variants() -> Vec<$crate::typescript::Variant> {
+                $crate::__mutation_variants!($($body)*)
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __mutation_variants {
+    ($(
+        $(#[$vmeta:meta])*
+        $variant:ident
+        $(( $($tty:ty),* $(,)? ))?
+        $({ $($(#[$fmeta:meta])* $field:ident : $fty:ty),* $(,)? })?
+    ),* $(,)?) => {
+        vec![$($crate::typescript::Variant {
+            name: stringify!($variant),
+            fields: $crate::__mutation_fields!(
+                $(( $($tty),* ))? $({ $($field : $fty),* })?
+            ),
+        }),*]
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __mutation_fields {
+    () => {
+        $crate::typescript::Fields::Unit
+    };
+    (( $($ty:ty),* )) => {
+        $crate::typescript::Fields::Tuple(vec![
+            $(<$ty as $crate::typescript::Describe>::field_type()),*
+        ])
+    };
+    ({ $($field:ident : $ty:ty),* }) => {
+        $crate::typescript::Fields::Named(vec![$((
+            stringify!($field),
+            <$ty as $crate::typescript::Describe>::field_type(),
+        )),*])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypedMutation;
+
+    crate::mutations! {
+        #[derive(Debug, PartialEq)]
+        enum Mutation {
+            Set(String, i64),
+            Delete { key: String },
+        }
+    }
+
+    #[test]
+    fn roundtrips_mutations() {
+        for mutation in [
+            Mutation::Set("a".into(), 1),
+            Mutation::Delete { key: "b".into() },
+        ] {
+            assert_eq!(Mutation::decode(&mutation.encode()).unwrap(), mutation);
+        }
+        assert!(Mutation::decode(&[0xff; 4]).is_err());
+        assert!(Mutation::SCHEMA.contains("Delete"));
+    }
+}
@@ -40,7 +40,7 @@ impl FromIterator<SqliteValue> for Row {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Query {
         sql: String,
@@ -103,11 +103,27 @@ impl From<&log::Record<'_>> for LogRecord {
 }
 
 impl LogRecord {
+    pub fn level(&self) -> Level {
+        Level::from_str(&self.level).unwrap_or(Level::Error)
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
     pub fn log(&self) {
         log::logger().log(
             &log::Record::builder()
-                .level(Level::from_str(&self.level).unwrap_or(Level::Error))
-                .file(self.file.as_ref().map(|s| s.as_str()))
+                .level(self.level())
+                .file(self.file())
                 .line(self.line)
                 .module_path(Some("wasm guest"))
                 .args(format_args!("{}", self.message))
@@ -0,0 +1,472 @@
+//! TypeScript definitions. Enums declared with [`mutations!`] and structs
+//! declared with [`row!`] describe their fields at runtime, which is enough
+//! to generate the matching TypeScript types. For each mutation enum this
+//! also generates an encoder which produces the same bytes as
+//! [`TypedMutation::encode`], so a JS client can construct mutations type
+//! safely and pass the encoder to its DocType as serializeMutation:
+//!
+//! ```ignore
+//! let ts = Definitions::new()
+//!     .mutation::<Mutation>()
+//!     .row::<Task>()
+//!     .render();
+//! std::fs::write("src/reducer.ts", ts)?;
+//! ```
+//!
+//! Field attributes which change the encoding (such as serde renames or
+//! skips) are not reflected in the generated code, so avoid them.
+//!
+//! [`mutations!`]: crate::mutations
+//! [`row!`]: crate::row
+
+use std::fmt::Write;
+
+use crate::mutation::TypedMutation;
+
+/// the shape of a mutation field or row column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    String,
+    Option(Box<FieldType>),
+    Array(Box<FieldType>),
+}
+
+/// Describe is implemented by every type which may be used as a mutation
+/// field or row column
+pub trait Describe {
+    fn field_type() -> FieldType;
+}
+
+macro_rules! describe_scalars {
+    ($($ty:ty => $field_type:ident),*) => {
+        $(impl Describe for $ty {
+            fn field_type() -> FieldType {
+                FieldType::$field_type
+            }
+        })*
+    };
+}
+
+describe_scalars!(
+    bool => Bool, u8 => U8, u16 => U16, u32 => U32, u64 => U64, i8 => I8,
+    i16 => I16, i32 => I32, i64 => I64, f32 => F32, f64 => F64,
+    String => String
+);
+
+impl<T: Describe> Describe for Option<T> {
+    fn field_type() -> FieldType {
+        FieldType::Option(Box::new(T::field_type()))
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    fn field_type() -> FieldType {
+        FieldType::Array(Box::new(T::field_type()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fields {
+    Unit,
+    Tuple(Vec<FieldType>),
+    Named(Vec<(&'static str, FieldType)>),
+}
+
+/// a variant of a mutation enum, in declaration order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub name: &'static str,
+    pub fields: Fields,
+}
+
+/// QueryRow is implemented by structs declared with [`row!`](crate::row)
+pub trait QueryRow {
+    const NAME: &'static str;
+    fn columns() -> Vec<(&'static str, FieldType)>;
+}
+
+/// declare the shape of the rows returned by a query, so it can be included
+/// in generated TypeScript definitions
+#[macro_export]
+macro_rules! row {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$fmeta:meta])* $fvis:vis $field:ident : $fty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$fmeta])* $fvis $field: $fty),*
+        }
+
+        impl $crate::typescript::QueryRow for $name {
+            const NAME: &'static str = stringify!($name);
+            fn columns(
+            ) -> Vec<(&'static str, $crate::typescript::FieldType)> {
+                vec![$((
+                    stringify!($field),
+                    <$fty as $crate::typescript::Describe>::field_type(),
+                )),*]
+            }
+        }
+    };
+}
+
+/// Definitions accumulates mutation enums and row types and renders them as
+/// a single TypeScript module
+#[derive(Debug, Default)]
+pub struct Definitions {
+    decls: Vec<String>,
+    needs_writer: bool,
+}
+
+impl Definitions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a union type for M, tagged like the rest of the sqlsync JS api, and
+    /// an `encode<M>` function producing M::encode's bytes
+    pub fn mutation<M: TypedMutation>(mut self) -> Self {
+        let variants = M::variants();
+        let mut out = String::new();
+
+        writeln!(out, "export type {} =", M::NAME).unwrap();
+        for variant in variants.iter() {
+            let mut members = vec![format!("tag: \"{}\"", variant.name)];
+            match &variant.fields {
+                Fields::Unit => {}
+                Fields::Tuple(types) => {
+                    let types: Vec<_> =
+                        types.iter().map(mutation_type).collect();
+                    members.push(format!("args: [{}]", types.join(", ")));
+                }
+                Fields::Named(fields) => {
+                    members.extend(fields.iter().map(|(name, ty)| {
+                        format!("{}: {}", name, mutation_type(ty))
+                    }));
+                }
+            }
+            writeln!(out, "  | {{ {} }}", members.join("; ")).unwrap();
+        }
+        if variants.is_empty() {
+            writeln!(out, "  never").unwrap();
+        }
+        out.push_str(";\n\n");
+
+        writeln!(
+            out,
+            "export function encode{}(mutation: {}): Uint8Array {{",
+            M::NAME,
+            M::NAME
+        )
+        .unwrap();
+        out.push_str("  const w = new BincodeWriter();\n");
+        out.push_str("  switch (mutation.tag) {\n");
+        for (index, variant) in variants.iter().enumerate() {
+            writeln!(out, "    case \"{}\":", variant.name).unwrap();
+            writeln!(out, "      w.u32({});", index).unwrap();
+            match &variant.fields {
+                Fields::Unit => {}
+                Fields::Tuple(types) => {
+                    for (i, ty) in types.iter().enumerate() {
+                        let expr = format!("mutation.args[{}]", i);
+                        encode_field(&mut out, ty, &expr, 3);
+                    }
+                }
+                Fields::Named(fields) => {
+                    for (name, ty) in fields {
+                        let expr = format!("mutation.{}", name);
+                        encode_field(&mut out, ty, &expr, 3);
+                    }
+                }
+            }
+            out.push_str("      break;\n");
+        }
+        out.push_str("  }\n  return w.finish();\n}\n");
+
+        self.decls.push(out);
+        self.needs_writer = true;
+        self
+    }
+
+    /// an object type for R's columns, as the JS client returns them from
+    /// queries
+    pub fn row<R: QueryRow>(mut self) -> Self {
+        let mut out = String::new();
+        writeln!(out, "export type {} = {{", R::NAME).unwrap();
+        for (name, ty) in R::columns() {
+            writeln!(out, "  {}: {};", name, column_type(&ty)).unwrap();
+        }
+        out.push_str("};\n");
+        self.decls.push(out);
+        self
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from(
+            "// generated by sqlsync_reducer::typescript, do not edit\n",
+        );
+        if self.needs_writer {
+            out.push('\n');
+            out.push_str(BINCODE_WRITER_TS);
+        }
+        for decl in self.decls.iter() {
+            out.push('\n');
+            out.push_str(decl);
+        }
+        out
+    }
+}
+
+fn mutation_type(ty: &FieldType) -> String {
+    match ty {
+        FieldType::Bool => "boolean".into(),
+        FieldType::U64 | FieldType::I64 => "number | bigint".into(),
+        FieldType::String => "string".into(),
+        FieldType::Option(inner) => format!("{} | null", mutation_type(inner)),
+        FieldType::Array(inner) if **inner == FieldType::U8 => {
+            "Uint8Array | number[]".into()
+        }
+        FieldType::Array(inner) => match **inner {
+            FieldType::Option(_) | FieldType::U64 | FieldType::I64 => {
+                format!("({})[]", mutation_type(inner))
+            }
+            _ => format!("{}[]", mutation_type(inner)),
+        },
+        _ => "number".into(),
+    }
+}
+
+/// sqlite has no booleans or arrays, so columns are typed by how the JS
+/// client decodes sqlite's values: integers, reals, text, blobs and nulls
+fn column_type(ty: &FieldType) -> String {
+    match ty {
+        FieldType::U64 | FieldType::I64 => "number | bigint".into(),
+        FieldType::String => "string".into(),
+        FieldType::Option(inner) => format!("{} | null", column_type(inner)),
+        FieldType::Array(inner) if **inner == FieldType::U8 => {
+            "Uint8Array".into()
+        }
+        FieldType::Array(_) => "unknown".into(),
+        _ => "number".into(),
+    }
+}
+
+/// append statements encoding expr, matching bincode's default encoding
+fn encode_field(out: &mut String, ty: &FieldType, expr: &str, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match ty {
+        FieldType::Bool => {
+            writeln!(out, "{}w.u8({} ? 1 : 0);", indent, expr).unwrap()
+        }
+        FieldType::Option(inner) => {
+            writeln!(out, "{}if ({} == null) {{", indent, expr).unwrap();
+            writeln!(out, "{}  w.u8(0);", indent).unwrap();
+            writeln!(out, "{}}} else {{", indent).unwrap();
+            writeln!(out, "{}  w.u8(1);", indent).unwrap();
+            encode_field(out, inner, expr, depth + 1);
+            writeln!(out, "{}}}", indent).unwrap();
+        }
+        FieldType::Array(inner) if **inner == FieldType::U8 => {
+            writeln!(out, "{}w.bytes({});", indent, expr).unwrap()
+        }
+        FieldType::Array(inner) => {
+            let item = format!("v{}", depth);
+            writeln!(out, "{}w.u64({}.length);", indent, expr).unwrap();
+            writeln!(out, "{}for (const {} of {}) {{", indent, item, expr)
+                .unwrap();
+            encode_field(out, inner, &item, depth + 1);
+            writeln!(out, "{}}}", indent).unwrap();
+        }
+        scalar => {
+            let method = match scalar {
+                FieldType::U8 => "u8",
+                FieldType::U16 => "u16",
+                FieldType::U32 => "u32",
+                FieldType::U64 => "u64",
+                FieldType::I8 => "i8",
+                FieldType::I16 => "i16",
+                FieldType::I32 => "i32",
+                FieldType::I64 => "i64",
+                FieldType::F32 => "f32",
+                FieldType::F64 => "f64",
+                _ => "string",
+            };
+            writeln!(out, "{}w.{}({});", indent, method, expr).unwrap()
+        }
+    }
+}
+
+/// bincode's default encoding: little endian fixed width integers, with u64
+/// lengths for strings and sequences and a u32 index for enum variants
+const BINCODE_WRITER_TS: &str = r#"const UTF8Encoder = new TextEncoder();
+
+class BincodeWriter {
+  private buf = new Uint8Array(64);
+  private view = new DataView(this.buf.buffer);
+  private len = 0;
+
+  private reserve(n: number): number {
+    if (this.len + n > this.buf.length) {
+      const next = new Uint8Array(Math.max(this.buf.length * 2, this.len + n));
+      next.set(this.buf);
+      this.buf = next;
+      this.view = new DataView(next.buffer);
+    }
+    const offset = this.len;
+    this.len += n;
+    return offset;
+  }
+
+  // each writer reserves before it reads buf or view, which growing replaces
+  u8(v: number) {
+    const off = this.reserve(1);
+    this.view.setUint8(off, v);
+  }
+  u16(v: number) {
+    const off = this.reserve(2);
+    this.view.setUint16(off, v, true);
+  }
+  u32(v: number) {
+    const off = this.reserve(4);
+    this.view.setUint32(off, v, true);
+  }
+  u64(v: number | bigint) {
+    const off = this.reserve(8);
+    this.view.setBigUint64(off, BigInt(v), true);
+  }
+  i8(v: number) {
+    const off = this.reserve(1);
+    this.view.setInt8(off, v);
+  }
+  i16(v: number) {
+    const off = this.reserve(2);
+    this.view.setInt16(off, v, true);
+  }
+  i32(v: number) {
+    const off = this.reserve(4);
+    this.view.setInt32(off, v, true);
+  }
+  i64(v: number | bigint) {
+    const off = this.reserve(8);
+    this.view.setBigInt64(off, BigInt(v), true);
+  }
+  f32(v: number) {
+    const off = this.reserve(4);
+    this.view.setFloat32(off, v, true);
+  }
+  f64(v: number) {
+    const off = this.reserve(8);
+    this.view.setFloat64(off, v, true);
+  }
+
+  bytes(v: Uint8Array | number[]) {
+    this.u64(v.length);
+    const off = this.reserve(v.length);
+    this.buf.set(v, off);
+  }
+
+  string(v: string) { this.bytes(UTF8Encoder.encode(v)); }
+
+  finish(): Uint8Array { return this.buf.slice(0, this.len); }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::{Definitions, Describe, FieldType, BINCODE_WRITER_TS};
+
+    crate::mutations! {
+        #[allow(dead_code)]
+        enum Mutation {
+            InitSchema,
+            Set(String, Option<i64>),
+            Tag { ids: Vec<u32>, body: Vec<u8> },
+        }
+    }
+
+    crate::row! {
+        #[allow(dead_code)]
+        struct Entry {
+            key: String,
+            value: Option<i64>,
+            pinned: bool,
+        }
+    }
+
+    #[test]
+    fn renders_definitions() {
+        assert_eq!(
+            <Vec<Option<u8>>>::field_type(),
+            FieldType::Array(Box::new(FieldType::Option(Box::new(
+                FieldType::U8
+            ))))
+        );
+
+        let ts = Definitions::new()
+            .mutation::<Mutation>()
+            .row::<Entry>()
+            .render();
+        assert!(ts.contains("class BincodeWriter"));
+        assert!(ts.contains(concat!(
+            "  | { tag: \"Set\"; args: [string, number | bigint | null] }\n",
+            "  | { tag: \"Tag\"; ids: number[]; body: Uint8Array | number[] }\n",
+        )));
+        assert!(ts.contains(concat!(
+            "    case \"Tag\":\n",
+            "      w.u32(2);\n",
+            "      w.u64(mutation.ids.length);\n",
+            "      for (const v3 of mutation.ids) {\n",
+            "        w.u32(v3);\n",
+            "      }\n",
+            "      w.bytes(mutation.body);\n",
+        )));
+        assert!(ts.contains("  value: number | bigint | null;\n"));
+        assert!(ts.contains("  pinned: number;\n"));
+    }
+
+    #[test]
+    fn writers_reserve_before_writing() {
+        // reserve may replace buf and view, so a writer which reads either
+        // before reserving writes to the old buffer once it grows
+        let lines: Vec<&str> = BINCODE_WRITER_TS.lines().collect();
+        let mut writes = 0;
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.trim();
+            if line.contains("this.reserve(") {
+                assert!(
+                    line.starts_with("const off = this.reserve("),
+                    "{}",
+                    line
+                );
+            }
+            if line.starts_with("this.view.") || line.starts_with("this.buf.") {
+                assert!(
+                    line.contains("(off, ") || line.contains(", off)"),
+                    "{}",
+                    line
+                );
+                assert!(
+                    lines[i - 1].trim().starts_with("const off = "),
+                    "{}",
+                    line
+                );
+                writes += 1;
+            }
+        }
+        assert_eq!(writes, 11);
+    }
+}
@@ -0,0 +1,64 @@
+import {
+  DocType,
+  ParameterizedQuery,
+  QueryState,
+  Row,
+  SQLSync,
+  queryStoreFor,
+  serializeMutationAsJSON,
+  sql,
+} from "@orbitinghail/sqlsync-client";
+import { ConnectionStatus, DocId } from "@orbitinghail/sqlsync-worker";
+import {
+  Accessor,
+  createContext,
+  createEffect,
+  createSignal,
+  onCleanup,
+  useContext,
+} from "solid-js";
+
+// provide the app's SQLSync instance with <SQLSyncContext.Provider value={sqlsync}>
+export const SQLSyncContext = createContext<SQLSync>();
+
+export function useSQLSync(): SQLSync {
+  const value = useContext(SQLSyncContext);
+  if (!value) {
+    throw new Error(
+      "could not find sqlsync context value; please ensure the component is wrapped in a <SQLSyncContext.Provider>",
+    );
+  }
+  return value;
+}
+
+type MaybeAccessor<T> = T | Accessor<T>;
+
+const access = <T>(value: MaybeAccessor<T>): T =>
+  typeof value === "function" ? (value as Accessor<T>)() : value;
+
+// a signal with the query's latest state. docId and query may be accessors,
+// in which case the query is resubscribed whenever they change. queries are
+// shared with every other reader of the same doc, sql and params
+export function createQuery<M, R = Row>(
+  docType: DocType<M>,
+  docId: MaybeAccessor<DocId>,
+  query: MaybeAccessor<ParameterizedQuery | string>,
+): Accessor<QueryState<R>> {
+  const store = queryStoreFor(useSQLSync());
+  const [state, setState] = createSignal<QueryState<R>>({ state: "pending" });
+  createEffect(() => {
+    const readable = store.readable<M, R>(docType, access(docId), access(query));
+    onCleanup(readable.subscribe((next) => setState(() => next)));
+  });
+  return state;
+}
+
+export function createConnectionStatus(): Accessor<ConnectionStatus> {
+  const sqlsync = useSQLSync();
+  const [status, setStatus] = createSignal<ConnectionStatus>(sqlsync.connectionStatus);
+  onCleanup(sqlsync.addConnectionStatusListener(setStatus));
+  return status;
+}
+
+export { serializeMutationAsJSON, sql };
+export type { DocType, QueryState, Row };
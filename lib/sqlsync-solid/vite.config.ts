@@ -0,0 +1,24 @@
+import { resolve } from "path";
+import { defineConfig } from "vite";
+import dts from "vite-plugin-dts";
+
+export default defineConfig({
+  plugins: [dts()],
+  build: {
+    lib: {
+      entry: resolve(__dirname, "src/index.ts"),
+      name: "SQLSyncSolid",
+      formats: ["es", "umd"],
+    },
+    sourcemap: true,
+    rollupOptions: {
+      external: ["solid-js"],
+      output: {
+        exports: "named",
+        globals: {
+          "solid-js": "Solid",
+        },
+      },
+    },
+  },
+});
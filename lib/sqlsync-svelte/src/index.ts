@@ -0,0 +1,50 @@
+import {
+  DocType,
+  ParameterizedQuery,
+  QueryState,
+  Row,
+  SQLSync,
+  queryStoreFor,
+  serializeMutationAsJSON,
+  sql,
+} from "@orbitinghail/sqlsync-client";
+import { ConnectionStatus, DocId } from "@orbitinghail/sqlsync-worker";
+import { getContext, setContext } from "svelte";
+import { Readable, readable } from "svelte/store";
+
+const SQLSyncKey = Symbol("sqlsync");
+
+// make sqlsync available to query and connectionStatus in this component's
+// descendants. like setContext, it must be called during initialization
+export function setSQLSync(sqlsync: SQLSync) {
+  setContext(SQLSyncKey, sqlsync);
+}
+
+export function getSQLSync(): SQLSync {
+  const value = getContext<SQLSync | undefined>(SQLSyncKey);
+  if (!value) {
+    throw new Error(
+      "could not find sqlsync; please ensure setSQLSync was called by an ancestor component",
+    );
+  }
+  return value;
+}
+
+// a store with the query's latest state, e.g. {#each $tasks.rows ?? [] as task}.
+// queries are shared with every other reader of the same doc, sql and params.
+// outside of component initialization, pass sqlsync explicitly
+export function query<M, R = Row>(
+  docType: DocType<M>,
+  docId: DocId,
+  rawQuery: ParameterizedQuery | string,
+  sqlsync: SQLSync = getSQLSync(),
+): Readable<QueryState<R>> {
+  return queryStoreFor(sqlsync).readable<M, R>(docType, docId, rawQuery);
+}
+
+export function connectionStatus(sqlsync: SQLSync = getSQLSync()): Readable<ConnectionStatus> {
+  return readable(sqlsync.connectionStatus, (set) => sqlsync.addConnectionStatusListener(set));
+}
+
+export { serializeMutationAsJSON, sql };
+export type { DocType, QueryState, Row };
@@ -0,0 +1,25 @@
+import { resolve } from "path";
+import { defineConfig } from "vite";
+import dts from "vite-plugin-dts";
+
+export default defineConfig({
+  plugins: [dts()],
+  build: {
+    lib: {
+      entry: resolve(__dirname, "src/index.ts"),
+      name: "SQLSyncSvelte",
+      formats: ["es", "umd"],
+    },
+    sourcemap: true,
+    rollupOptions: {
+      external: ["svelte", "svelte/store"],
+      output: {
+        exports: "named",
+        globals: {
+          svelte: "Svelte",
+          "svelte/store": "SvelteStore",
+        },
+      },
+    },
+  },
+});
@@ -0,0 +1,76 @@
+import {
+  DocType,
+  ParameterizedQuery,
+  QueryState,
+  Row,
+  SQLSync,
+  queryStoreFor,
+  serializeMutationAsJSON,
+  sql,
+} from "@orbitinghail/sqlsync-client";
+import { ConnectionStatus, DocId } from "@orbitinghail/sqlsync-worker";
+import {
+  InjectionKey,
+  MaybeRefOrGetter,
+  Ref,
+  inject,
+  onScopeDispose,
+  provide,
+  readonly,
+  shallowReadonly,
+  shallowRef,
+  toValue,
+  watchEffect,
+} from "vue";
+
+const SQLSyncKey: InjectionKey<SQLSync> = Symbol("sqlsync");
+
+// make sqlsync available to useQuery in this component's descendants
+export function provideSQLSync(sqlsync: SQLSync) {
+  provide(SQLSyncKey, sqlsync);
+}
+
+export function useSQLSync(): SQLSync {
+  const value = inject(SQLSyncKey);
+  if (!value) {
+    throw new Error(
+      "could not find sqlsync; please ensure provideSQLSync was called by an ancestor component",
+    );
+  }
+  return value;
+}
+
+// a ref with the query's latest state. docId and query may be refs or
+// getters, in which case the query is resubscribed whenever they change.
+// queries are shared with every other reader of the same doc, sql and params
+export function useQuery<M, R = Row>(
+  docType: DocType<M>,
+  docId: MaybeRefOrGetter<DocId>,
+  query: MaybeRefOrGetter<ParameterizedQuery | string>,
+): Readonly<Ref<QueryState<R>>> {
+  const store = queryStoreFor(useSQLSync());
+  const state = shallowRef<QueryState<R>>({ state: "pending" });
+  watchEffect((onCleanup) => {
+    const readable = store.readable<M, R>(docType, toValue(docId), toValue(query));
+    onCleanup(
+      readable.subscribe((next) => {
+        state.value = next;
+      }),
+    );
+  });
+  return shallowReadonly(state);
+}
+
+export function useConnectionStatus(): Readonly<Ref<ConnectionStatus>> {
+  const sqlsync = useSQLSync();
+  const status = shallowRef<ConnectionStatus>(sqlsync.connectionStatus);
+  onScopeDispose(
+    sqlsync.addConnectionStatusListener((next) => {
+      status.value = next;
+    }),
+  );
+  return readonly(status);
+}
+
+export { serializeMutationAsJSON, sql };
+export type { DocType, QueryState, Row };
@@ -1,21 +1,15 @@
-use std::{collections::HashMap, fmt::Debug};
-
-use anyhow::anyhow;
-use futures::{
-    channel::mpsc::{self, UnboundedSender},
-    SinkExt,
-};
 use serde::{Deserialize, Serialize};
-use sqlsync::JournalId;
+use sqlsync::{mux::Priority, JournalId, ReducerTrap};
 use tsify::{declare, Tsify};
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
 use crate::{
-    doc_task::DocTask,
-    net::ConnectionStatus,
+    error::{ErrorInfo, WorkerError},
+    manager::DocumentManager,
+    net::{ConnectionStatus, NetworkStats, SyncHealth},
     reactive::QueryKey,
     sql::SqlValue,
-    utils::{fetch_reducer, WasmError, WasmResult},
+    utils::{WasmError, WasmResult},
 };
 
 #[wasm_bindgen(typescript_custom_section)]
@@ -28,10 +22,23 @@ export type PageIdx = number;
 export type QueryKey = string;
 
 interface WorkerApi {
-    handle(msg: HostToWorkerMsg): Promise<void>;
+    handle(portId: PortId, req: WorkerRequest): Promise<void>;
 }
 "#;
 
+/// the version of the messages below. a shared worker outlives the tabs
+/// which started it, so after a deploy a tab may find a worker running an
+/// older build; the worker refuses to boot for a tab speaking another
+/// version. bump Current whenever a message changes incompatibly. it's
+/// exported to js, so the tab sends the version of the build it loaded
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    Current = 1,
+}
+
+pub const PROTOCOL_VERSION: u32 = ProtocolVersion::Current as u32;
+
 pub type PortId = u32;
 pub type HandlerId = u32;
 
@@ -68,9 +75,40 @@ extern "C" {
     pub fn send_all(this: &PortRouter, msg: WorkerToHostMsg);
 }
 
+/// every message a tab sends the worker
 #[derive(Debug, Deserialize, Tsify)]
-#[serde(rename_all = "camelCase")]
+#[serde(tag = "tag", rename_all_fields = "camelCase")]
 #[tsify(from_wasm_abi)]
+pub enum WorkerRequest {
+    /// start the worker. the first boot loads the wasm at wasm_url and
+    /// configures the worker; later boots only check the protocol version
+    Boot {
+        handler_id: HandlerId,
+        protocol_version: u32,
+        wasm_url: String,
+        #[serde(default)]
+        #[tsify(optional)]
+        coordinator_url: Option<String>,
+        /// see the manager module
+        #[serde(default)]
+        #[tsify(optional)]
+        memory_budget_bytes: Option<u64>,
+        /// replicate every document over one connection to the coordinator
+        #[serde(default)]
+        #[tsify(optional)]
+        multiplex: bool,
+    },
+    Doc {
+        handler_id: HandlerId,
+        doc_id: DocId,
+        req: DocRequest,
+    },
+    /// the tab is going away; it gets no reply
+    Close { handler_id: HandlerId },
+}
+
+/// a document request from a port, as the manager and doc tasks see it
+#[derive(Debug)]
 pub struct HostToWorkerMsg {
     pub port_id: PortId,
     pub handler_id: HandlerId,
@@ -83,24 +121,157 @@ impl HostToWorkerMsg {
         WorkerToHostMsg::Reply { handler_id: self.handler_id, reply }
     }
 
-    pub fn reply_err<E: Debug>(&self, err: E) -> WorkerToHostMsg {
+    pub fn reply_err(&self, err: &WasmError) -> WorkerToHostMsg {
         WorkerToHostMsg::Reply {
             handler_id: self.handler_id,
-            reply: DocReply::Err { err: format!("{:?}", err) },
+            reply: DocReply::Err { err: err.into() },
+        }
+    }
+}
+
+/// OpenOptions control how a document behaves before it has synced with
+/// the coordinator. a document is synced once it has received everything
+/// the coordinator had when the connection was opened; documents without a
+/// coordinator are always synced
+#[derive(Debug, Deserialize, Tsify, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpenOptions {
+    /// let queries read the local state before the first sync
+    #[tsify(optional)]
+    pub allow_stale_reads: bool,
+    /// refuse queries once the coordinator hasn't been heard from while
+    /// synced for this long, e.g. while offline
+    #[tsify(optional)]
+    pub max_staleness_ms: Option<i64>,
+    /// only reply to the open once the document has synced. the reply
+    /// waits for as long as the connection is disabled
+    #[tsify(optional)]
+    pub block_until_synced: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            allow_stale_reads: true,
+            max_staleness_ms: None,
+            block_until_synced: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// refuse to read a document which caught up with its coordinator at
+    /// caught_up_ms (None if it never has) if that's staler than these
+    /// options allow
+    pub fn check_staleness(
+        &self,
+        caught_up_ms: Option<i64>,
+        now_ms: i64,
+    ) -> Result<(), WorkerError> {
+        let Some(caught_up_ms) = caught_up_ms else {
+            if self.allow_stale_reads {
+                return Ok(());
+            }
+            return Err(WorkerError::NotSynced);
+        };
+        let staleness_ms = now_ms - caught_up_ms;
+        match self.max_staleness_ms {
+            Some(max) if staleness_ms > max => {
+                Err(WorkerError::Stale { staleness_ms, max_staleness_ms: max })
+            }
+            _ => Ok(()),
         }
     }
 }
 
+/// which documents sync first when they share a multiplexed connection
+#[derive(Debug, Deserialize, Tsify, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncPriority {
+    #[default]
+    Foreground,
+    Background,
+}
+
+impl From<SyncPriority> for Priority {
+    fn from(priority: SyncPriority) -> Self {
+        match priority {
+            SyncPriority::Foreground => Priority::Foreground,
+            SyncPriority::Background => Priority::Background,
+        }
+    }
+}
+
+/// how a query's results are sent to the tab
+#[derive(Debug, Deserialize, Tsify, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ResultFormat {
+    /// an object per row, see DocReply::RecordSet
+    #[default]
+    Rows,
+    /// typed arrays per column, see DocReply::Columns
+    Columnar,
+    /// an Arrow IPC stream, see DocReply::Arrow
+    Arrow,
+}
+
+/// see sqlsync::export
+#[derive(Debug, Deserialize, Tsify, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl From<ExportFormat> for sqlsync::export::ExportFormat {
+    fn from(format: ExportFormat) -> Self {
+        match format {
+            ExportFormat::Csv => Self::Csv,
+            ExportFormat::Ndjson => Self::Ndjson,
+            ExportFormat::Parquet => Self::Parquet,
+        }
+    }
+}
+
+/// chosen by the tab, unique among its exports of a document
+pub type ExportId = u32;
+
+/// what an Export reads
+#[derive(Debug, Deserialize, Tsify, Clone)]
+#[serde(tag = "tag", rename_all_fields = "camelCase")]
+pub enum ExportSource {
+    /// every row of a table, in rowid order. each row is exported once,
+    /// even if the table changes during the export: rows changed ahead of
+    /// the export are read as changed. tables without a rowid need a Query
+    Table { table: String },
+    /// the results of sql. the export fails with EXPORT_CHANGED if the
+    /// document changes before it's done
+    Query { sql: String, params: Vec<SqlValue> },
+}
+
 #[derive(Debug, Deserialize, Tsify)]
 #[serde(tag = "tag", rename_all_fields = "camelCase")]
 #[tsify(from_wasm_abi)]
 pub enum DocRequest {
+    /// open the document, or apply new options to it if it's already open.
+    /// the read options of the latest open apply to every port
     Open {
         reducer_url: String,
+        #[serde(default)]
+        #[tsify(optional)]
+        options: OpenOptions,
     },
+    /// close the document on this port, dropping the port's subscriptions.
+    /// the worker closes the document once no port has it open and its
+    /// mutations have synced
+    Close,
     Query {
         sql: String,
         params: Vec<SqlValue>,
+        #[serde(default)]
+        #[tsify(optional)]
+        format: ResultFormat,
     },
     QuerySubscribe {
         key: QueryKey,
@@ -110,15 +281,95 @@ pub enum DocRequest {
     QueryUnsubscribe {
         key: QueryKey,
     },
+    /// execute sql against the document's local-only tables
+    ExecLocal {
+        sql: String,
+        params: Vec<SqlValue>,
+    },
     Mutate {
         #[serde(with = "serde_bytes")]
         #[tsify(type = "Uint8Array")]
         mutation: Vec<u8>,
+        /// 16 random bytes identifying this mutation, reused across retries
+        #[serde(default, with = "serde_bytes")]
+        #[tsify(optional, type = "Uint8Array")]
+        idempotency_key: Option<Vec<u8>>,
+        /// the client's wall-clock time in unix milliseconds
+        #[serde(default)]
+        #[tsify(optional)]
+        timestamp_ms: Option<i64>,
+        /// an app-defined tag which activity feeds can read without
+        /// decoding the mutation
+        #[serde(default)]
+        #[tsify(optional)]
+        tag: Option<u32>,
+    },
+    /// apply a mutation locally without syncing it until it's promoted
+    MutateDraft {
+        #[serde(with = "serde_bytes")]
+        #[tsify(type = "Uint8Array")]
+        mutation: Vec<u8>,
+    },
+    PromoteDraft {
+        draft_id: u64,
+    },
+    DiscardDraft {
+        draft_id: u64,
+    },
+    /// reply once queries observe the mutation a consistency token was
+    /// returned for, which may have been made by another client
+    WaitFor {
+        consistency_token: String,
+    },
+    /// cancel a mutation which hasn't been sent to the coordinator yet,
+    /// rolling back its local effects
+    CancelPending {
+        mutation_id: u64,
     },
     RefreshConnectionStatus,
     SetConnectionEnabled {
         enabled: bool,
     },
+    /// stop replicating the document until ResumeSync, without closing the
+    /// connection
+    PauseSync,
+    ResumeSync,
+    NetworkStats,
+    SyncHealth,
+    /// pause pulling changes from the coordinator once this many more bytes
+    /// have been sent and received, or never if unset
+    SetNetworkBudget {
+        #[serde(default)]
+        #[tsify(optional)]
+        budget_bytes: Option<u64>,
+    },
+    /// foreground documents sync ahead of background ones; the priority is
+    /// kept while the document is evicted
+    SetPriority {
+        priority: SyncPriority,
+    },
+    /// cancel this port's request with handler_id, which then fails with
+    /// ABORTED. requests queued behind others don't run, opens and waits
+    /// stop waiting, and queries and export chunks stop part way; a request
+    /// which has already finished is unaffected. a cancelled open releases
+    /// the document as a Close would, see the cancel module
+    Cancel {
+        handler_id: HandlerId,
+    },
+    /// start exporting source, which the tab then reads with ExportNext
+    /// until a chunk is done. the export is dropped once it's done, closed,
+    /// or an ExportNext fails or is cancelled
+    Export {
+        export_id: ExportId,
+        source: ExportSource,
+        format: ExportFormat,
+    },
+    ExportNext {
+        export_id: ExportId,
+    },
+    ExportClose {
+        export_id: ExportId,
+    },
 }
 
 #[derive(Debug, Serialize, Tsify)]
@@ -140,15 +391,78 @@ pub enum WorkerToHostMsg {
 #[tsify(into_wasm_abi)]
 pub enum DocReply {
     Ack,
+    /// unset when the mutation was skipped as a duplicate
+    Mutated {
+        receipt: Option<MutationReceipt>,
+    },
+    Draft {
+        draft_id: u64,
+    },
+    /// false if the mutation may already have been sent to the coordinator
+    Cancelled {
+        cancelled: bool,
+    },
     RecordSet {
         columns: Vec<String>,
-        rows: Vec<Vec<SqlValue>>,
+        /// built by sql::RowSet
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "Record<string, SqlValue>[]")]
+        rows: JsValue,
+        /// whether the rows include the effects of drafts, which may never
+        /// sync. see DocRequest::MutateDraft
+        has_drafts: bool,
+    },
+    /// a query's results in ResultFormat::Columnar. the tab should transfer
+    /// the buffers when posting this rather than copying them
+    Columns {
+        columns: Vec<String>,
+        row_count: usize,
+        /// built by sql::ColumnSet, one per column
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "ColumnData[]")]
+        data: JsValue,
+        /// as for RecordSet
+        has_drafts: bool,
+    },
+    /// a query's results in ResultFormat::Arrow, transferred like Columns
+    Arrow {
+        /// see sqlsync::arrow
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "Uint8Array")]
+        ipc: JsValue,
+        /// as for RecordSet
+        has_drafts: bool,
+    },
+    /// the next piece of an export's file, transferred like Columns
+    ExportChunk {
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "Uint8Array")]
+        data: JsValue,
+        /// the rows encoded in data
+        rows: usize,
+        /// whether this is the export's last chunk
+        done: bool,
     },
     Err {
-        err: String,
+        err: ErrorInfo,
+    },
+    NetworkStats {
+        stats: NetworkStats,
+    },
+    SyncHealth {
+        health: SyncHealth,
     },
 }
 
+#[derive(Debug, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationReceipt {
+    /// for CancelPending
+    pub mutation_id: u64,
+    /// for WaitFor, on this or any other client
+    pub consistency_token: String,
+}
+
 #[derive(Debug, Serialize, Tsify, Clone)]
 #[serde(tag = "tag", rename_all_fields = "camelCase")]
 #[tsify(into_wasm_abi)]
@@ -156,97 +470,166 @@ pub enum DocEvent {
     ConnectionStatus {
         status: ConnectionStatus,
     },
+    /// sent periodically while the document's sync health changes
+    SyncHealth {
+        health: SyncHealth,
+    },
     SubscriptionChanged {
         key: QueryKey,
         columns: Vec<String>,
-        rows: Vec<Vec<SqlValue>>,
+        /// built by sql::RowSet
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "Record<string, SqlValue>[]")]
+        rows: JsValue,
+        /// as for DocReply::RecordSet
+        has_drafts: bool,
     },
     SubscriptionErr {
         key: QueryKey,
+        err: ErrorInfo,
+    },
+    ReducerErr {
         err: String,
+        trap: Option<ReducerTrapInfo>,
+    },
+    /// the coordinator runs a newer reducer, identified by the base58
+    /// sha256 digest of its wasm. mutations fail until the document switches
+    /// to that reducer, which the worker downloads from the coordinator
+    UpdateRequired {
+        reducer_digest: String,
     },
+    /// the document switched to the coordinator's reducer
+    ReducerUpdated {
+        reducer_digest: String,
+    },
+}
+
+#[derive(Debug, Serialize, Tsify, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReducerTrapInfo {
+    message: String,
+    panic: Option<String>,
+    backtrace: Vec<String>,
+    in_flight: String,
+}
+
+impl From<&ReducerTrap> for ReducerTrapInfo {
+    fn from(trap: &ReducerTrap) -> Self {
+        Self {
+            message: trap.message.clone(),
+            panic: trap.panic.clone(),
+            backtrace: trap.backtrace.clone(),
+            in_flight: trap.in_flight.to_string(),
+        }
+    }
 }
 
 #[wasm_bindgen]
 pub struct WorkerApi {
-    coordinator_url: Option<String>,
     ports: PortRouter,
-    inboxes: HashMap<DocId, UnboundedSender<HostToWorkerMsg>>,
+    docs: DocumentManager,
 }
 
 #[wasm_bindgen]
 impl WorkerApi {
+    /// start the worker from the first Boot request, which handle then
+    /// replies to
     #[wasm_bindgen(constructor)]
     pub fn new(
         ports: PortRouter,
-        coordinator_url: Option<String>,
-    ) -> WorkerApi {
-        WorkerApi { coordinator_url, ports, inboxes: HashMap::new() }
+        boot: WorkerRequest,
+    ) -> WasmResult<WorkerApi> {
+        let WorkerRequest::Boot {
+            coordinator_url,
+            memory_budget_bytes,
+            multiplex,
+            ..
+        } = boot
+        else {
+            return Err(WorkerError::NotBooted.into());
+        };
+        let docs = DocumentManager::new(
+            ports.clone(),
+            coordinator_url,
+            memory_budget_bytes,
+            multiplex,
+        );
+        Ok(WorkerApi { ports, docs })
     }
 
     #[wasm_bindgen(skip_typescript)]
-    pub async fn handle(&mut self, msg: JsValue) -> WasmResult<()> {
-        let mut msg: HostToWorkerMsg = serde_wasm_bindgen::from_value(msg)?;
-        log::info!("handle: {:?}", msg);
-
-        match &msg.req {
-            DocRequest::Open { reducer_url } => {
-                if let Some(inbox) = self.inboxes.get_mut(&msg.doc_id) {
-                    // doc is already open
-                    // request a connection status update from the doc
-                    msg.req = DocRequest::RefreshConnectionStatus;
-                    inbox.send(msg).await?;
+    pub async fn handle(
+        &mut self,
+        port_id: PortId,
+        req: JsValue,
+    ) -> WasmResult<()> {
+        let req: WorkerRequest = serde_wasm_bindgen::from_value(req)?;
+        log::info!("handle: {:?}", req);
+        match req {
+            WorkerRequest::Boot { handler_id, protocol_version, .. } => {
+                let reply = if protocol_version == PROTOCOL_VERSION {
+                    DocReply::Ack
                 } else {
-                    // open the doc
-                    self.spawn_doc_task(msg.doc_id, &reducer_url).await?;
-                    let _ = self
-                        .ports
-                        .send_one(msg.port_id, msg.reply(DocReply::Ack));
-                }
+                    let err = WasmError::from(WorkerError::ProtocolVersion {
+                        worker: PROTOCOL_VERSION,
+                        tab: protocol_version,
+                    });
+                    DocReply::Err { err: (&err).into() }
+                };
+                let _ = self.ports.send_one(
+                    port_id,
+                    WorkerToHostMsg::Reply { handler_id, reply },
+                );
+                Ok(())
+            }
+            WorkerRequest::Doc { handler_id, doc_id, req } => {
+                let msg = HostToWorkerMsg { port_id, handler_id, doc_id, req };
+                self.docs.handle(msg).await
+            }
+            WorkerRequest::Close { .. } => {
+                self.docs.close_port(port_id);
+                Ok(())
             }
-
-            _ => match self.inboxes.get_mut(&msg.doc_id) {
-                Some(inbox) => inbox.send(msg).await?,
-                None => {
-                    let _ = self.ports.send_one(
-                        msg.port_id,
-                        msg.reply_err(WasmError(anyhow!(
-                            "no document with id {}",
-                            msg.doc_id
-                        ))),
-                    );
-                }
-            },
         }
-
-        Ok(())
     }
+}
 
-    async fn spawn_doc_task(
-        &mut self,
-        doc_id: JournalId,
-        reducer_url: &str,
-    ) -> Result<(), WasmError> {
-        let (reducer, digest) = fetch_reducer(reducer_url).await?;
-
-        let doc_url = self.coordinator_url.as_ref().map(|url| {
-            format!(
-                "{}/doc/{}?reducer={}",
-                url,
-                doc_id.to_base58(),
-                bs58::encode(&digest).into_string()
-            )
-        });
-
-        let (tx, rx) = mpsc::unbounded();
-
-        let task =
-            DocTask::new(doc_id, doc_url, reducer, rx, self.ports.clone())?;
-
-        wasm_bindgen_futures::spawn_local(task.into_task());
-
-        self.inboxes.insert(doc_id, tx);
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use js_sys::Object;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::OpenOptions;
+    use crate::error::WorkerError;
+
+    #[wasm_bindgen_test]
+    fn open_options_default_to_stale_reads() {
+        let options: OpenOptions =
+            serde_wasm_bindgen::from_value(Object::new().into()).unwrap();
+        assert!(options.allow_stale_reads);
+        assert!(!options.block_until_synced);
+        assert_eq!(options.max_staleness_ms, None);
+        assert!(options.check_staleness(None, 0).is_ok());
+    }
 
-        Ok(())
+    #[wasm_bindgen_test]
+    fn stale_reads_are_refused_past_the_options() {
+        let strict = OpenOptions {
+            allow_stale_reads: false,
+            max_staleness_ms: Some(100),
+            ..OpenOptions::default()
+        };
+        assert!(matches!(
+            strict.check_staleness(None, 0),
+            Err(WorkerError::NotSynced)
+        ));
+        assert!(strict.check_staleness(Some(1000), 1100).is_ok());
+        assert!(matches!(
+            strict.check_staleness(Some(1000), 1101),
+            Err(WorkerError::Stale {
+                staleness_ms: 101,
+                max_staleness_ms: 100
+            })
+        ));
     }
 }
@@ -0,0 +1,165 @@
+//! A port cancels one of its requests by sending a Cancel with the request's
+//! handler id. The manager records it in the document's Cancellations as
+//! soon as it arrives, and the doc task picks it up: queued requests don't
+//! run, opens and waits stop waiting, and reads stop part way through.
+//!
+//! Reads run synchronously, and nothing else in the worker runs meanwhile,
+//! not even the manager. So long reads check in with a CancelCheck as they
+//! step through their rows, which every so often yields to the event loop to
+//! let a Cancel arrive, failing the read with ABORTED once it has.
+
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use gloo::timers::future::TimeoutFuture;
+use sqlsync::sqlite::{Row, Rows};
+
+use crate::{
+    api::{HandlerId, PortId},
+    error::WorkerError,
+    utils::WasmResult,
+};
+
+/// the requests a doc task should abort, by port and handler id
+pub type Cancellations = Rc<RefCell<HashSet<(PortId, HandlerId)>>>;
+
+/// how many rows a read steps through between checks
+const CHECK_ROWS: usize = 256;
+
+/// how long a read runs before it yields to the event loop. yielding costs
+/// a timer, which browsers may delay by a few ms
+const YIELD_MS: f64 = 50.0;
+
+/// the cancellation of a single request
+pub struct CancelCheck {
+    cancelled: Cancellations,
+    request: (PortId, HandlerId),
+    // when the read last yielded
+    yielded_at: f64,
+}
+
+impl CancelCheck {
+    pub fn new(
+        cancelled: &Cancellations,
+        port_id: PortId,
+        handler_id: HandlerId,
+    ) -> Self {
+        Self {
+            cancelled: cancelled.clone(),
+            request: (port_id, handler_id),
+            yielded_at: js_sys::Date::now(),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.borrow().contains(&self.request)
+    }
+
+    /// yield to the event loop if the read has run for a while, then fail
+    /// with Aborted if the request has been cancelled. the cancellation is
+    /// left for the doc task to take, which replies to the request
+    pub async fn checkpoint(&mut self) -> WasmResult<()> {
+        if js_sys::Date::now() - self.yielded_at >= YIELD_MS {
+            TimeoutFuture::new(0).await;
+            self.yielded_at = js_sys::Date::now();
+        }
+        if self.is_cancelled() {
+            return Err(WorkerError::Aborted.into());
+        }
+        Ok(())
+    }
+
+    /// call f with up to limit rows, checking in between. returns how many
+    /// rows were read
+    pub async fn for_each_row<F>(
+        &mut self,
+        rows: &mut Rows<'_>,
+        limit: usize,
+        mut f: F,
+    ) -> WasmResult<usize>
+    where
+        F: FnMut(&Row<'_>) -> WasmResult<()>,
+    {
+        let mut read = 0;
+        while read < limit {
+            let Some(row) = rows.next()? else { break };
+            f(row)?;
+            read += 1;
+            if read % CHECK_ROWS == 0 {
+                self.checkpoint().await?;
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use gloo::timers::future::TimeoutFuture;
+    use sqlsync::sqlite::Connection;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{CancelCheck, Cancellations};
+    use crate::error::{ErrorCode, ErrorInfo};
+
+    // more rows than are read before the first yield
+    const ROWS: &str = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL \
+        SELECT i + 1 FROM n WHERE i < 10000000) SELECT i FROM n";
+
+    #[wasm_bindgen_test]
+    async fn reads_every_row_unless_cancelled() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT column1 FROM (VALUES (1), (2), (3), (4), (5))")
+            .unwrap();
+        let mut rows = stmt.query([]).unwrap();
+
+        let cancelled = Cancellations::default();
+        let mut check = CancelCheck::new(&cancelled, 1, 2);
+        let mut sum = 0;
+        let read = check
+            .for_each_row(&mut rows, 3, |row| {
+                sum += row.get::<_, i64>(0)?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!((read, sum), (3, 6));
+        let read = check.for_each_row(&mut rows, 10, |_| Ok(())).await;
+        assert_eq!(read.unwrap(), 2);
+
+        // another request's cancellation doesn't stop it
+        cancelled.borrow_mut().insert((1, 3));
+        cancelled.borrow_mut().insert((2, 2));
+        assert!(!check.is_cancelled());
+        assert!(check.checkpoint().await.is_ok());
+    }
+
+    #[wasm_bindgen_test]
+    async fn a_cancel_stops_a_read_part_way() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn.prepare(ROWS).unwrap();
+        let mut rows = stmt.query([]).unwrap();
+
+        // the cancel arrives once the read yields to the event loop
+        let cancelled = Cancellations::default();
+        let cancel = cancelled.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            TimeoutFuture::new(0).await;
+            cancel.borrow_mut().insert((1, 2));
+        });
+
+        let mut check = CancelCheck::new(&cancelled, 1, 2);
+        let mut read = 0;
+        let err = check
+            .for_each_row(&mut rows, usize::MAX, |_| {
+                read += 1;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorInfo::from(&err).code, ErrorCode::Aborted);
+        assert!(read < 10_000_000);
+        // the doc task takes the cancellation once it replies
+        assert!(check.is_cancelled());
+    }
+}
@@ -1,23 +1,44 @@
-use anyhow::anyhow;
-use futures::{channel::mpsc, select, FutureExt, StreamExt};
+use std::{cell::Cell, collections::HashMap, rc::Rc};
+
+use futures::{channel::mpsc, select, stream::Fuse, FutureExt, StreamExt};
+use gloo::timers::future::{IntervalStream, TimeoutFuture};
+use js_sys::Uint8Array;
 use rand::thread_rng;
 use sqlsync::{
-    local::LocalDocument, sqlite::params_from_iter, JournalId, MemoryJournal,
-    Reducer,
+    export::Exporter,
+    local::LocalDocument,
+    snapshot::Checkpoint,
+    sqlite::{params, params_from_iter, types::Value, OptionalExtension, Rows},
+    timeline::{ConsistencyToken, FrameMeta, IdempotencyKey},
+    unixtime::unix_timestamp_milliseconds,
+    JournalId, MemoryJournal, ModuleDigest, Reducer,
 };
 
 use crate::{
     api::{
-        DocEvent, DocReply, DocRequest, HostToWorkerMsg, PortRouter,
-        WorkerToHostMsg,
+        DocEvent, DocReply, DocRequest, ExportId, ExportSource, HandlerId,
+        HostToWorkerMsg, MutationReceipt, OpenOptions, PortId, PortRouter,
+        ResultFormat, SyncPriority, WorkerToHostMsg,
     },
-    net::{ConnectionTask, CoordinatorClient},
+    cancel::{CancelCheck, Cancellations},
+    error::WorkerError,
+    manager::DocUsage,
+    net::{ConnectionTask, CoordinatorClient, Endpoint, SyncHealth},
     reactive::ReactiveQueries,
     signal::{SignalEmitter, SignalRouter},
-    sql::SqlValue,
-    utils::{WasmError, WasmResult},
+    sql::{ColumnSet, RowSet, SqlValue},
+    utils::{cache_reducer, cached_reducer, WasmError, WasmResult},
 };
 
+// how often changes to the sync health are reported to the host
+const SYNC_HEALTH_INTERVAL_MS: u32 = 1000;
+
+// rows encoded per ExportNext
+const EXPORT_CHUNK_ROWS: usize = 10_000;
+
+// rows encoded between checks for the ExportNext's cancellation
+const EXPORT_PIECE_ROWS: usize = 1_000;
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 enum Signal {
     StorageChanged,
@@ -34,15 +55,55 @@ pub struct DocTask {
     ports: PortRouter,
     queries: ReactiveQueries<SignalEmitter<Signal>>,
     coordinator_client: CoordinatorClient<SignalEmitter<Signal>>,
+    health_ticks: Fuse<IntervalStream>,
+    last_health: Option<SyncHealth>,
+    open_options: OpenOptions,
+    // opens which are waiting for the document to sync before replying
+    pending_opens: Vec<(PortId, HandlerId)>,
+    // WaitFor requests which are waiting for queries to observe a token
+    pending_waits: Vec<(PortId, HandlerId, ConsistencyToken)>,
+    // the coordinator's reducer digest the host was last told about
+    update_required: Option<ModuleDigest>,
+    // read by the DocumentManager to decide which documents to evict
+    usage: Rc<Cell<DocUsage>>,
+    // added to by the DocumentManager as Cancels arrive
+    cancelled: Cancellations,
+    exports: HashMap<(PortId, ExportId), Export>,
+}
+
+struct Export {
+    exporter: Exporter,
+    cursor: ExportCursor,
+}
+
+// a statement can't be held across requests, as its read would hold up
+// the document's writes, so each chunk of an export runs a query of its own
+enum ExportCursor {
+    // a table is read in rowid order from the first rowid it hasn't
+    // exported, so each row is read once however the table changes
+    Table {
+        table: String,
+        next_rowid: i64,
+    },
+    // a query runs again and skips the rows already exported, which only
+    // continues the same results while the document is unchanged
+    Query {
+        sql: String,
+        params: Vec<SqlValue>,
+        offset: usize,
+        data_version: u64,
+    },
 }
 
 impl DocTask {
     pub fn new(
         doc_id: JournalId,
-        doc_url: Option<String>,
+        endpoint: Option<Endpoint>,
         reducer: Reducer,
+        reducer_digest: ModuleDigest,
         inbox: mpsc::UnboundedReceiver<HostToWorkerMsg>,
         ports: PortRouter,
+        usage: Rc<Cell<DocUsage>>,
     ) -> WasmResult<Self> {
         // TODO: use persisted timeline id when we start persisting the journal to OPFS
         let timeline_id = JournalId::new128(&mut thread_rng());
@@ -51,7 +112,7 @@ impl DocTask {
 
         let storage = MemoryJournal::open(doc_id)?;
         let timeline = MemoryJournal::open(timeline_id)?;
-        let doc = LocalDocument::open(
+        let mut doc = LocalDocument::open(
             storage,
             timeline,
             reducer,
@@ -59,15 +120,55 @@ impl DocTask {
             signals.emitter(Signal::TimelineChanged),
             signals.emitter(Signal::CanRebase),
         )?;
+        doc.set_reducer_digest(reducer_digest);
 
         let queries =
             ReactiveQueries::new(signals.emitter(Signal::HasDirtyQueries));
         let coordinator_client = CoordinatorClient::new(
-            doc_url,
+            endpoint,
             signals.emitter(Signal::ConnectionStateChanged),
         );
 
-        Ok(Self { doc, inbox, signals, ports, queries, coordinator_client })
+        Ok(Self {
+            doc,
+            inbox,
+            signals,
+            ports,
+            queries,
+            coordinator_client,
+            health_ticks: IntervalStream::new(SYNC_HEALTH_INTERVAL_MS).fuse(),
+            last_health: None,
+            open_options: OpenOptions::default(),
+            pending_opens: vec![],
+            pending_waits: vec![],
+            update_required: None,
+            usage,
+            cancelled: Cancellations::default(),
+            exports: HashMap::new(),
+        })
+    }
+
+    pub fn cancellations(&self) -> Cancellations {
+        self.cancelled.clone()
+    }
+
+    /// apply the options of an earlier open, when the document is reopened
+    /// after being evicted
+    pub fn set_open_options(&mut self, options: OpenOptions) {
+        self.open_options = options;
+    }
+
+    pub fn set_priority(&mut self, priority: SyncPriority) {
+        self.coordinator_client.set_priority(priority.into());
+    }
+
+    /// seed the document from a checkpoint before it starts replicating
+    pub fn bootstrap(&mut self, checkpoint: Checkpoint) -> WasmResult<()> {
+        let lsn = checkpoint.lsn;
+        if self.doc.bootstrap(checkpoint)? {
+            log::info!("bootstrapped document from checkpoint at lsn {}", lsn);
+        }
+        Ok(())
     }
 
     pub async fn into_task(mut self) {
@@ -91,12 +192,39 @@ impl DocTask {
                 },
                 task = self.coordinator_client.poll().fuse() => {
                     self.coordinator_client.handle(&mut self.doc, task).await;
+                    self.reply_to_pending_opens();
+                    self.check_update_required().await;
                 },
-                msg = self.inbox.select_next_some() => {
-                    self.handle_message(msg).await;
+                msg = self.inbox.next().fuse() => match msg {
+                    Some(msg) => {
+                        self.handle_message(msg).await;
+                        let mut usage = self.usage.get();
+                        usage.handled += 1;
+                        self.usage.set(usage);
+                    }
+                    // the DocumentManager closed the document
+                    None => break,
+                },
+                _ = self.health_ticks.select_next_some() => {
+                    self.handle_health_tick();
                 },
             }
+            self.report_usage();
         }
+        log::info!("closed document {}", self.doc.doc_id());
+    }
+
+    fn report_usage(&self) {
+        let idle = self.doc.sync_lag().pending_mutations == 0
+            && self.doc.drafts().is_empty()
+            && self.queries.is_empty()
+            && self.pending_opens.is_empty()
+            && self.pending_waits.is_empty();
+        self.usage.set(DocUsage {
+            memory_bytes: self.doc.journal_size().unwrap_or(0),
+            idle,
+            ..self.usage.get()
+        });
     }
 
     async fn handle_signals(&mut self, signals: Vec<Signal>) {
@@ -106,7 +234,7 @@ impl DocTask {
                     self.handle_connection_state_changed()
                 }
                 Signal::TimelineChanged => self.handle_timeline_changed().await,
-                Signal::HasDirtyQueries => self.handle_dirty_queries(),
+                Signal::HasDirtyQueries => self.handle_dirty_queries().await,
 
                 Signal::StorageChanged => {
                     if let Err(e) = self.handle_storage_changed() {
@@ -116,13 +244,24 @@ impl DocTask {
 
                 Signal::CanRebase => {
                     if let Err(e) = self.doc.rebase() {
-                        panic!("failed to rebase the document; this may mean that a mutation is failing to apply: {:?}", e);
+                        log::error!("failed to rebase the document; this may mean that a mutation is failing to apply: {:?}", e);
+                        self.emit_reducer_err(&e);
                     }
                 }
             }
         }
     }
 
+    fn emit_reducer_err(&mut self, err: &sqlsync::error::Error) {
+        let _ = self.ports.send_all(WorkerToHostMsg::Event {
+            doc_id: self.doc.doc_id(),
+            evt: DocEvent::ReducerErr {
+                err: err.to_string(),
+                trap: err.reducer_trap().map(Into::into),
+            },
+        });
+    }
+
     fn handle_connection_state_changed(&mut self) {
         let _ = self.ports.send_all(WorkerToHostMsg::Event {
             doc_id: self.doc.doc_id(),
@@ -132,38 +271,180 @@ impl DocTask {
         });
     }
 
+    /// switch to the coordinator's reducer when it runs a different one,
+    /// from the cache or by downloading it from the coordinator
+    async fn check_update_required(&mut self) {
+        if let Some((digest, wasm)) = self.doc.take_received_reducer() {
+            if let Err(e) = cache_reducer(&digest, &wasm).await {
+                log::warn!("failed to cache reducer: {:?}", e);
+            }
+            self.load_reducer(digest, &wasm);
+        }
+
+        let digest = self.doc.update_required();
+        if digest == self.update_required {
+            return;
+        }
+        self.update_required = digest;
+        let Some(digest) = digest else {
+            return;
+        };
+        match cached_reducer(&digest).await {
+            Ok(Some(wasm)) => return self.load_reducer(digest, &wasm),
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to read reducer cache: {:?}", e),
+        }
+
+        let reducer_digest = bs58::encode(digest).into_string();
+        log::warn!("coordinator runs a newer reducer: {}", reducer_digest);
+        let _ = self.ports.send_all(WorkerToHostMsg::Event {
+            doc_id: self.doc.doc_id(),
+            evt: DocEvent::UpdateRequired { reducer_digest },
+        });
+        self.coordinator_client.request_reducer(digest);
+        self.coordinator_client
+            .handle(&mut self.doc, ConnectionTask::Sync)
+            .await;
+    }
+
+    fn load_reducer(&mut self, digest: ModuleDigest, wasm: &[u8]) {
+        let result = Reducer::new(wasm)
+            .map_err(sqlsync::error::Error::from)
+            .and_then(|reducer| self.doc.replace_reducer(reducer, digest));
+        if let Err(e) = result {
+            log::error!("failed to switch reducers: {:?}", e);
+            return self.emit_reducer_err(&e);
+        }
+        let reducer_digest = bs58::encode(digest).into_string();
+        log::info!("switched to the coordinator's reducer: {}", reducer_digest);
+        let _ = self.ports.send_all(WorkerToHostMsg::Event {
+            doc_id: self.doc.doc_id(),
+            evt: DocEvent::ReducerUpdated { reducer_digest },
+        });
+    }
+
+    fn synced(&self) -> bool {
+        !self.coordinator_client.can_enable()
+            || self.coordinator_client.caught_up_ms().is_some()
+    }
+
+    fn reply_to_pending_opens(&mut self) {
+        if self.pending_opens.is_empty() || !self.synced() {
+            return;
+        }
+        for (port_id, handler_id) in std::mem::take(&mut self.pending_opens) {
+            let reply =
+                WorkerToHostMsg::Reply { handler_id, reply: DocReply::Ack };
+            let _ = self.ports.send_one(port_id, reply);
+        }
+    }
+
+    /// refuse to read the document if it's staler than the open options
+    /// allow
+    fn check_staleness(&self) -> WasmResult<()> {
+        if !self.coordinator_client.can_enable() {
+            return Ok(());
+        }
+        Ok(self.open_options.check_staleness(
+            self.coordinator_client.caught_up_ms(),
+            unix_timestamp_milliseconds(),
+        )?)
+    }
+
+    fn sync_health(&self) -> SyncHealth {
+        self.coordinator_client.sync_health(self.doc.sync_lag())
+    }
+
+    fn handle_health_tick(&mut self) {
+        let health = self.sync_health();
+        if self.last_health.as_ref() != Some(&health) {
+            let _ = self.ports.send_all(WorkerToHostMsg::Event {
+                doc_id: self.doc.doc_id(),
+                evt: DocEvent::SyncHealth { health: health.clone() },
+            });
+            self.last_health = Some(health);
+        }
+    }
+
     fn handle_storage_changed(&mut self) -> anyhow::Result<()> {
         let changes = self.doc.storage_changes()?;
         log::debug!("storage changed: {:?}", changes);
         self.queries.handle_storage_change(&changes);
+        self.reply_to_pending_waits()
+    }
+
+    fn reply_to_pending_waits(&mut self) -> anyhow::Result<()> {
+        let mut waiting = vec![];
+        let waits = std::mem::take(&mut self.pending_waits);
+        for (port_id, handler_id, token) in waits {
+            // e.g. the mutation was cancelled, which only fails its waiters
+            let reply = match self.doc.observes(token) {
+                Ok(false) => {
+                    waiting.push((port_id, handler_id, token));
+                    continue;
+                }
+                Ok(true) => DocReply::Ack,
+                Err(err) => {
+                    let err = WasmError::from(err);
+                    DocReply::Err { err: (&err).into() }
+                }
+            };
+            let reply = WorkerToHostMsg::Reply { handler_id, reply };
+            let _ = self.ports.send_one(port_id, reply);
+        }
+        self.pending_waits = waiting;
         Ok(())
     }
 
+    /// the receipt of the latest mutation, if applied is set
+    fn receipt(&self, applied: bool) -> Option<MutationReceipt> {
+        let mutation_id = self.doc.last_mutation_id().filter(|_| applied)?;
+        Some(MutationReceipt {
+            mutation_id,
+            consistency_token: self
+                .doc
+                .consistency_token(mutation_id)
+                .to_string(),
+        })
+    }
+
     async fn handle_timeline_changed(&mut self) {
         self.coordinator_client
             .handle(&mut self.doc, ConnectionTask::Sync)
             .await;
     }
 
-    fn handle_dirty_queries(&mut self) {
+    async fn handle_dirty_queries(&mut self) {
+        let mut delays = self.doc.busy_backoff().delays();
         if let Some(query) = self.queries.next_dirty_query() {
-            let result =
-                query.refresh(self.doc.sqlite_readonly(), |columns, row| {
-                    let mut out = Vec::with_capacity(columns.len());
-                    for i in 0..columns.len() {
-                        let val: SqlValue = row.get_ref(i)?.into();
-                        out.push(val);
+            let (result, rows) = loop {
+                let mut rows = None;
+                let result = query.refresh(
+                    self.doc.sqlite_readonly(),
+                    |columns, row| {
+                        rows.get_or_insert_with(|| RowSet::new(columns))
+                            .push(row)
+                    },
+                );
+                if matches!(&result, Err(err) if err.is_busy()) {
+                    if let Some(delay) = delays.next() {
+                        TimeoutFuture::new(delay.as_millis() as u32).await;
+                        continue;
                     }
-                    Ok::<_, WasmError>(out)
-                });
+                }
+                break (result, rows);
+            };
 
             let msg = match result {
-                Ok((columns, rows)) => WorkerToHostMsg::Event {
+                Ok((columns, _)) => WorkerToHostMsg::Event {
                     doc_id: self.doc.doc_id(),
                     evt: DocEvent::SubscriptionChanged {
                         key: query.query_key().clone(),
+                        rows: rows
+                            .unwrap_or_else(|| RowSet::new(&columns))
+                            .into_js(),
                         columns,
-                        rows,
+                        has_drafts: self.doc.has_drafts(),
                     },
                 },
                 Err(err) => {
@@ -172,7 +453,7 @@ impl DocTask {
                         doc_id: self.doc.doc_id(),
                         evt: DocEvent::SubscriptionErr {
                             key: query.query_key().clone(),
-                            err: err.to_string(),
+                            err: (&err).into(),
                         },
                     }
                 }
@@ -185,14 +466,209 @@ impl DocTask {
     }
 
     async fn handle_message(&mut self, msg: HostToWorkerMsg) {
-        match self.process_request(&msg).await {
+        // a request cancelled while it was queued doesn't run, but an open
+        // still applies its options; the manager has released the port
+        let cancelled = self.take_cancelled(msg.port_id, msg.handler_id);
+        if let DocRequest::Open { options, .. } = msg.req {
+            self.open_options = options;
+            self.handle_connection_state_changed();
+            if options.block_until_synced && !self.synced() && !cancelled {
+                self.pending_opens.push((msg.port_id, msg.handler_id));
+                return;
+            }
+        }
+        if cancelled {
+            self.abandon_export(&msg);
+            self.reply_aborted(msg.port_id, msg.handler_id);
+            return;
+        }
+        // tokens which fail to parse or check are answered with the error
+        if let DocRequest::WaitFor { consistency_token } = &msg.req {
+            if let Ok(token) = consistency_token.parse() {
+                if !self.doc.observes(token).unwrap_or(true) {
+                    self.pending_waits.push((
+                        msg.port_id,
+                        msg.handler_id,
+                        token,
+                    ));
+                    return;
+                }
+            }
+        }
+        let reply = match self.process_request(&msg).await {
             Ok(reply) => {
                 log::info!("doc task reply: {:?}", reply);
-                let _ = self.ports.send_one(msg.port_id, msg.reply(reply));
+                msg.reply(reply)
             }
             Err(err) => {
                 log::info!("doc task error: {:?}", err);
-                let _ = self.ports.send_one(msg.port_id, msg.reply_err(err));
+                msg.reply_err(&err)
+            }
+        };
+        // long reads stop part way once they're cancelled (see CancelCheck),
+        // and the reply to any other request cancelled meanwhile isn't sent
+        if self.take_cancelled(msg.port_id, msg.handler_id) {
+            self.abandon_export(&msg);
+            self.reply_aborted(msg.port_id, msg.handler_id);
+            return;
+        }
+        let _ = self.ports.send_one(msg.port_id, reply);
+    }
+
+    fn take_cancelled(&self, port_id: PortId, handler_id: HandlerId) -> bool {
+        self.cancelled.borrow_mut().remove(&(port_id, handler_id))
+    }
+
+    fn reply_aborted(&self, port_id: PortId, handler_id: HandlerId) {
+        let err = WasmError::from(WorkerError::Aborted);
+        let reply = DocReply::Err { err: (&err).into() };
+        let _ = self
+            .ports
+            .send_one(port_id, WorkerToHostMsg::Reply { handler_id, reply });
+    }
+
+    /// abort a request which is waiting for the document, e.g. for its
+    /// initial sync
+    fn cancel(&mut self, port_id: PortId, handler_id: HandlerId) {
+        // the request was handled before this, so it's no longer queued
+        self.take_cancelled(port_id, handler_id);
+        let before = self.pending_opens.len() + self.pending_waits.len();
+        self.pending_opens
+            .retain(|&pending| pending != (port_id, handler_id));
+        self.pending_waits.retain(|&(port, handler, _)| {
+            (port, handler) != (port_id, handler_id)
+        });
+        if self.pending_opens.len() + self.pending_waits.len() < before {
+            self.reply_aborted(port_id, handler_id);
+        }
+    }
+
+    // an export whose chunk is cancelled can't resume where it was
+    fn abandon_export(&mut self, msg: &HostToWorkerMsg) {
+        if let DocRequest::ExportNext { export_id } = msg.req {
+            self.exports.remove(&(msg.port_id, export_id));
+        }
+    }
+
+    /// write the export's next chunk, returning its rows and whether it's
+    /// the last
+    async fn export_next(
+        &self,
+        check: &mut CancelCheck,
+        export_id: ExportId,
+        export: &mut Export,
+    ) -> WasmResult<(usize, bool)> {
+        let conn = self.doc.sqlite_readonly();
+        match &mut export.cursor {
+            ExportCursor::Table { table, next_rowid } => {
+                let table = quote(table);
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT * FROM {} WHERE rowid >= ? ORDER BY rowid",
+                    table
+                ))?;
+                let mut rows = stmt.query([*next_rowid])?;
+                let written =
+                    write_chunk(check, &mut export.exporter, &mut rows).await?;
+                if written < EXPORT_CHUNK_ROWS {
+                    return Ok((written, true));
+                }
+                // the first row after the chunk, which the next one starts at
+                let next: Option<i64> = conn
+                    .prepare_cached(&format!(
+                        "SELECT rowid FROM {} WHERE rowid >= ? \
+                         ORDER BY rowid LIMIT 1 OFFSET ?",
+                        table
+                    ))?
+                    .query_row(params![*next_rowid, written], |row| row.get(0))
+                    .optional()?;
+                match next {
+                    Some(next) => {
+                        *next_rowid = next;
+                        Ok((written, false))
+                    }
+                    None => Ok((written, true)),
+                }
+            }
+            ExportCursor::Query { sql, params, offset, data_version } => {
+                if self.doc.data_version() != *data_version {
+                    return Err(WorkerError::ExportChanged(export_id).into());
+                }
+                let mut stmt = conn.prepare_cached(sql)?;
+                let mut rows = stmt.query(params_from_iter(params.iter()))?;
+                for _ in 0..*offset {
+                    if rows.next()?.is_none() {
+                        break;
+                    }
+                }
+                let written =
+                    write_chunk(check, &mut export.exporter, &mut rows).await?;
+                *offset += written;
+                Ok((written, written < EXPORT_CHUNK_ROWS))
+            }
+        }
+    }
+
+    /// read the results of sql, stopping early if the request is
+    /// cancelled. the statement is held while the read yields, which is
+    /// safe as nothing else runs on the document meanwhile
+    async fn query(
+        &self,
+        check: &mut CancelCheck,
+        sql: &str,
+        params: &[SqlValue],
+        format: ResultFormat,
+    ) -> WasmResult<DocReply> {
+        let has_drafts = self.doc.has_drafts();
+        let conn = self.doc.sqlite_readonly();
+        let mut stmt = conn.prepare(sql)?;
+
+        let columns: Vec<_> =
+            stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+
+        let mut cursor = stmt.query(params_from_iter(params.iter()))?;
+        match format {
+            ResultFormat::Rows => {
+                let mut rows = RowSet::new(&columns);
+                check
+                    .for_each_row(&mut cursor, usize::MAX, |row| {
+                        Ok(rows.push(row)?)
+                    })
+                    .await?;
+                Ok(DocReply::RecordSet {
+                    columns,
+                    rows: rows.into_js(),
+                    has_drafts,
+                })
+            }
+            ResultFormat::Columnar => {
+                let mut data = ColumnSet::new(columns.len());
+                check
+                    .for_each_row(&mut cursor, usize::MAX, |row| {
+                        Ok(data.push(row)?)
+                    })
+                    .await?;
+                Ok(DocReply::Columns {
+                    columns,
+                    row_count: data.len(),
+                    data: data.into_js(),
+                    has_drafts,
+                })
+            }
+            ResultFormat::Arrow => {
+                let mut values: Vec<Vec<Value>> = vec![vec![]; columns.len()];
+                check
+                    .for_each_row(&mut cursor, usize::MAX, |row| {
+                        for (i, column) in values.iter_mut().enumerate() {
+                            column.push(row.get(i)?);
+                        }
+                        Ok(())
+                    })
+                    .await?;
+                let ipc = sqlsync::arrow::encode_values(&columns, &values)?;
+                Ok(DocReply::Arrow {
+                    ipc: Uint8Array::from(&ipc[..]).into(),
+                    has_drafts,
+                })
             }
         }
     }
@@ -203,32 +679,115 @@ impl DocTask {
     ) -> WasmResult<DocReply> {
         log::info!("DocTask::process_request: {:?}", msg.req);
         match &msg.req {
-            DocRequest::Open { .. } => {
-                Err(WasmError(anyhow!("doc is already open")))
+            DocRequest::Open { .. } => Ok(DocReply::Ack),
+
+            DocRequest::Close => {
+                self.queries.unsubscribe_all(&vec![msg.port_id]);
+                self.exports
+                    .retain(|&(port_id, _), _| port_id != msg.port_id);
+                Ok(DocReply::Ack)
             }
 
-            DocRequest::Query { sql, params } => self.doc.query(|conn| {
-                let params = params_from_iter(params.iter());
-                let mut stmt = conn.prepare(sql)?;
+            DocRequest::Export { export_id, source, format } => {
+                self.check_staleness()?;
+                let (sql, cursor) = match source.clone() {
+                    ExportSource::Table { table } => (
+                        format!("SELECT *, rowid FROM {}", quote(&table)),
+                        ExportCursor::Table { table, next_rowid: i64::MIN },
+                    ),
+                    ExportSource::Query { sql, params } => (
+                        sql.clone(),
+                        ExportCursor::Query {
+                            sql,
+                            params,
+                            offset: 0,
+                            data_version: self.doc.data_version(),
+                        },
+                    ),
+                };
+                // prepare now, so a bad query (or a table without a rowid)
+                // fails here rather than on the first chunk
+                let mut columns: Vec<String> = self.doc.query(|conn| {
+                    let stmt = conn.prepare(&sql)?;
+                    Ok::<_, WasmError>(
+                        stmt.column_names()
+                            .iter()
+                            .map(|&s| s.to_owned())
+                            .collect(),
+                    )
+                })?;
+                if let ExportCursor::Table { .. } = cursor {
+                    // the rowid was only selected to check there is one
+                    columns.pop();
+                }
+                let export = Export {
+                    exporter: Exporter::new((*format).into(), columns),
+                    cursor,
+                };
+                self.exports.insert((msg.port_id, *export_id), export);
+                Ok(DocReply::Ack)
+            }
 
-                let columns: Vec<_> =
-                    stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+            DocRequest::ExportNext { export_id } => {
+                let key = (msg.port_id, *export_id);
+                let Some(mut export) = self.exports.remove(&key) else {
+                    return Err(WorkerError::UnknownExport(*export_id).into());
+                };
+                let mut check = CancelCheck::new(
+                    &self.cancelled,
+                    msg.port_id,
+                    msg.handler_id,
+                );
+                let (rows, done) = self
+                    .export_next(&mut check, *export_id, &mut export)
+                    .await?;
+                let mut data = export.exporter.take_output();
+                if done {
+                    data.append(&mut export.exporter.finish()?);
+                } else {
+                    self.exports.insert(key, export);
+                }
+                Ok(DocReply::ExportChunk {
+                    data: Uint8Array::from(&data[..]).into(),
+                    rows,
+                    done,
+                })
+            }
 
-                let rows = stmt
-                    .query_and_then(params, |row| {
-                        let mut out = Vec::with_capacity(columns.len());
-                        for i in 0..columns.len() {
-                            let val: SqlValue = row.get_ref(i)?.into();
-                            out.push(val);
-                        }
-                        Ok::<_, WasmError>(out)
-                    })?
-                    .collect::<Result<Vec<_>, _>>()?;
+            DocRequest::ExportClose { export_id } => {
+                self.exports.remove(&(msg.port_id, *export_id));
+                Ok(DocReply::Ack)
+            }
 
-                Ok::<_, WasmError>(DocReply::RecordSet { columns, rows })
-            }),
+            DocRequest::Query { sql, params, format } => {
+                self.check_staleness()?;
+                // retry while the database is busy, waiting on the event loop
+                // rather than blocking the worker
+                let mut check = CancelCheck::new(
+                    &self.cancelled,
+                    msg.port_id,
+                    msg.handler_id,
+                );
+                let mut delays = self.doc.busy_backoff().delays();
+                loop {
+                    match self.query(&mut check, sql, params, *format).await {
+                        Err(err) if err.is_busy() => match delays.next() {
+                            Some(delay) => {
+                                TimeoutFuture::new(delay.as_millis() as u32)
+                                    .await;
+                                if check.is_cancelled() {
+                                    return Err(WorkerError::Aborted.into());
+                                }
+                            }
+                            None => return Err(err),
+                        },
+                        out => return out,
+                    }
+                }
+            }
 
             DocRequest::QuerySubscribe { key, sql, params } => {
+                self.check_staleness()?;
                 self.queries
                     .subscribe(msg.port_id, key, sql, params.to_vec());
                 Ok(DocReply::Ack)
@@ -239,11 +798,79 @@ impl DocTask {
                 Ok(DocReply::Ack)
             }
 
-            DocRequest::Mutate { mutation } => {
-                self.doc.mutate(&mutation.to_vec())?;
+            DocRequest::ExecLocal { sql, params } => {
+                self.doc.mutate_local(|txn| {
+                    txn.execute(sql, params_from_iter(params.iter()))
+                })?;
                 Ok(DocReply::Ack)
             }
 
+            DocRequest::Mutate {
+                mutation,
+                idempotency_key,
+                timestamp_ms,
+                tag,
+            } => {
+                let key = idempotency_key
+                    .as_ref()
+                    .map(|key| IdempotencyKey::try_from(&key[..]))
+                    .transpose()
+                    .map_err(sqlsync::error::Error::from)?;
+                let meta = FrameMeta { timestamp_ms: *timestamp_ms, tag: *tag };
+                match self.doc.mutate_with_meta(&mutation, key, meta) {
+                    Ok(applied) => {
+                        Ok(DocReply::Mutated { receipt: self.receipt(applied) })
+                    }
+                    Err(err) => {
+                        if err.reducer_trap().is_some() {
+                            self.emit_reducer_err(&err);
+                        }
+                        Err(err.into())
+                    }
+                }
+            }
+
+            DocRequest::MutateDraft { mutation } => {
+                match self.doc.mutate_draft(mutation) {
+                    Ok(draft_id) => Ok(DocReply::Draft { draft_id }),
+                    Err(err) => {
+                        if err.reducer_trap().is_some() {
+                            self.emit_reducer_err(&err);
+                        }
+                        Err(err.into())
+                    }
+                }
+            }
+
+            DocRequest::PromoteDraft { draft_id } => {
+                let applied = self.doc.promote_draft(
+                    *draft_id,
+                    None,
+                    FrameMeta::default(),
+                )?;
+                Ok(DocReply::Mutated { receipt: self.receipt(applied) })
+            }
+
+            DocRequest::DiscardDraft { draft_id } => {
+                self.doc.discard_draft(*draft_id)?;
+                Ok(DocReply::Ack)
+            }
+
+            DocRequest::WaitFor { consistency_token } => {
+                let token = consistency_token
+                    .parse()
+                    .map_err(sqlsync::error::Error::from)?;
+                if !self.doc.observes(token)? {
+                    return Err(WorkerError::NotObserved.into());
+                }
+                Ok(DocReply::Ack)
+            }
+
+            DocRequest::CancelPending { mutation_id } => {
+                let cancelled = self.doc.cancel_pending(*mutation_id)?;
+                Ok(DocReply::Cancelled { cancelled })
+            }
+
             DocRequest::RefreshConnectionStatus => {
                 let _ = self.ports.send_one(
                     msg.port_id,
@@ -265,9 +892,7 @@ impl DocTask {
                         if self.coordinator_client.can_enable() {
                             ConnectionTask::Connect
                         } else {
-                            return Err(WasmError(anyhow!(
-                                "cannot enable connection without coordinator url"
-                            )));
+                            return Err(WorkerError::NoCoordinator.into());
                         }
                     }
                 };
@@ -275,6 +900,71 @@ impl DocTask {
 
                 Ok(DocReply::Ack)
             }
+
+            DocRequest::PauseSync | DocRequest::ResumeSync => {
+                if matches!(msg.req, DocRequest::PauseSync) {
+                    self.coordinator_client.pause_sync();
+                } else {
+                    self.coordinator_client.resume_sync();
+                }
+                // close or reopen the receive window, and on resume send
+                // what was mutated while paused
+                self.coordinator_client
+                    .handle(&mut self.doc, ConnectionTask::Sync)
+                    .await;
+                Ok(DocReply::Ack)
+            }
+
+            DocRequest::SyncHealth => {
+                Ok(DocReply::SyncHealth { health: self.sync_health() })
+            }
+
+            DocRequest::NetworkStats => Ok(DocReply::NetworkStats {
+                stats: self.coordinator_client.network_stats(),
+            }),
+
+            DocRequest::SetNetworkBudget { budget_bytes } => {
+                self.coordinator_client.set_network_budget(*budget_bytes);
+                // apply the budget to the receive window right away
+                self.coordinator_client
+                    .handle(&mut self.doc, ConnectionTask::Sync)
+                    .await;
+                Ok(DocReply::Ack)
+            }
+
+            DocRequest::SetPriority { priority } => {
+                self.set_priority(*priority);
+                Ok(DocReply::Ack)
+            }
+
+            DocRequest::Cancel { handler_id } => {
+                self.cancel(msg.port_id, *handler_id);
+                Ok(DocReply::Ack)
+            }
         }
     }
 }
+
+/// write up to a chunk of rows, in pieces with a check in between. returns
+/// how many rows were written
+async fn write_chunk(
+    check: &mut CancelCheck,
+    exporter: &mut Exporter,
+    rows: &mut Rows<'_>,
+) -> WasmResult<usize> {
+    let mut written = 0;
+    while written < EXPORT_CHUNK_ROWS {
+        let limit = EXPORT_PIECE_ROWS.min(EXPORT_CHUNK_ROWS - written);
+        let piece = exporter.write_rows(rows, limit)?;
+        written += piece;
+        if piece < limit {
+            break;
+        }
+        check.checkpoint().await?;
+    }
+    Ok(written)
+}
+
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
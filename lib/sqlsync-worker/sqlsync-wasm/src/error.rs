@@ -0,0 +1,504 @@
+//! Errors cross into JavaScript as ErrorInfo: a stable code which frontends
+//! can branch on, the error's message, whether retrying could help, and
+//! context fields for the codes which have them. The code is found by
+//! walking the error's chain for the error types of sqlsync and this crate,
+//! so it survives the context added to an error on its way up.
+
+use std::{error::Error as StdError, io};
+
+use serde::Serialize;
+use sqlsync::{
+    arrow::EncodeError,
+    error::Error as SqlSyncError,
+    export::ExportError,
+    replication::{ProtocolError, ReplicationError},
+    session::SessionError,
+    timeline::TimelineError,
+    JournalError, JournalId, ReducerError,
+};
+use thiserror::Error;
+use tsify::Tsify;
+
+use crate::{api::ReducerTrapInfo, utils::WasmError};
+
+/// errors raised by the worker itself
+#[derive(Error, Debug)]
+pub enum WorkerError {
+    #[error("the worker hasn't booted")]
+    NotBooted,
+
+    #[error("worker speaks protocol version {worker}, tab speaks {tab}")]
+    ProtocolVersion { worker: u32, tab: u32 },
+
+    #[error("no document with id {0}")]
+    UnknownDocument(JournalId),
+
+    #[error("document has not synced with the coordinator yet")]
+    NotSynced,
+
+    #[error(
+        "document is {staleness_ms}ms stale, more than the {max_staleness_ms}ms allowed"
+    )]
+    Stale {
+        staleness_ms: i64,
+        max_staleness_ms: i64,
+    },
+
+    #[error("token isn't observed yet")]
+    NotObserved,
+
+    #[error("cannot enable connection without coordinator url")]
+    NoCoordinator,
+
+    #[error("the request was cancelled")]
+    Aborted,
+
+    #[error("no export with id {0}")]
+    UnknownExport(u32),
+
+    #[error("the document changed during export {0}")]
+    ExportChanged(u32),
+}
+
+/// codes are part of the worker's protocol, so existing codes must keep
+/// their meaning
+#[derive(Debug, Serialize, Tsify, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// no more specific code applies
+    Internal,
+    NotBooted,
+    /// see api::PROTOCOL_VERSION
+    ProtocolVersion,
+    UnknownDocument,
+    /// see OpenOptions
+    NotSynced,
+    Stale,
+    NotObserved,
+    /// the mutation a token was waited on for was cancelled, see
+    /// DocRequest::CancelPending
+    MutationCancelled,
+    NoCoordinator,
+    /// the tab cancelled the request, see DocRequest::Cancel
+    Aborted,
+    /// the export finished, was closed, or its document was evicted
+    UnknownExport,
+    /// the document changed between the chunks of a query's export, see
+    /// ExportSource::Query
+    ExportChanged,
+    /// the reducer trapped, see ErrorInfo::trap
+    ReducerTrap,
+    /// the reducer failed to load or broke the reducer interface
+    Reducer,
+    /// the coordinator runs another reducer, see ErrorInfo::reducer_digest
+    UpdateRequired,
+    MutationTooLarge,
+    UnknownDraft,
+    InvalidToken,
+    /// the document has reached its maximum size
+    StorageFull,
+    /// a lock held by the document's other connection
+    Busy,
+    /// sqlite, the vfs or the journal failed
+    Storage,
+    /// the coordinator rejected our credentials
+    AuthFailed,
+    DocNotFound,
+    ReducerMismatch,
+    /// the document is over a storage quota on the coordinator
+    QuotaExceeded,
+    /// replicating with the coordinator failed
+    Replication,
+    Network,
+}
+
+#[derive(Debug, Serialize, Tsify, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    /// the error and its causes, for logging rather than matching on
+    pub message: String,
+    /// whether the request may succeed if it's retried
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub doc_id: Option<JournalId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub trap: Option<ReducerTrapInfo>,
+    /// in base58, for UpdateRequired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub reducer_digest: Option<String>,
+    /// for MutationTooLarge the mutation's size in bytes, for Stale how
+    /// stale the document is in ms, and for ProtocolVersion the tab's
+    /// version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub value: Option<i64>,
+    /// what value may not exceed, or the worker's protocol version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub limit: Option<i64>,
+    /// how long the coordinator asked us to wait before retrying
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub retry_after_ms: Option<u32>,
+}
+
+impl From<&WasmError> for ErrorInfo {
+    fn from(err: &WasmError) -> Self {
+        let mut info = ErrorInfo {
+            code: ErrorCode::Internal,
+            message: format!("{:#}", err.0),
+            retryable: false,
+            doc_id: None,
+            trap: None,
+            reducer_digest: None,
+            value: None,
+            limit: None,
+            retry_after_ms: None,
+        };
+        for cause in err.0.chain() {
+            if info.classify(cause) {
+                break;
+            }
+        }
+        info
+    }
+}
+
+impl From<WorkerError> for WasmError {
+    fn from(err: WorkerError) -> Self {
+        WasmError(err.into())
+    }
+}
+
+impl ErrorInfo {
+    /// fill in the code and context from cause, returning false if it isn't
+    /// one of the errors we know. transparent variants hide the wrapped
+    /// error from the chain, so they're matched here
+    fn classify(&mut self, cause: &(dyn StdError + 'static)) -> bool {
+        if let Some(err) = cause.downcast_ref::<WorkerError>() {
+            self.worker(err);
+        } else if let Some(err) = cause.downcast_ref::<SqlSyncError>() {
+            self.sqlsync(err);
+        } else if let Some(err) = cause.downcast_ref::<TimelineError>() {
+            self.timeline(err);
+        } else if let Some(err) = cause.downcast_ref::<ReducerError>() {
+            self.reducer(err);
+        } else if let Some(err) = cause.downcast_ref::<SessionError>() {
+            match err {
+                SessionError::Replication(err) => self.replication(err),
+                SessionError::Bincode(_) | SessionError::ResyncTooLarge(_) => {
+                    self.set(ErrorCode::Replication)
+                }
+                SessionError::TimedOut => self.retry(ErrorCode::Network),
+            }
+        } else if let Some(err) = cause.downcast_ref::<ReplicationError>() {
+            self.replication(err);
+        } else if let Some(err) = cause.downcast_ref::<ProtocolError>() {
+            self.protocol(err);
+        } else if let Some(err) = cause.downcast_ref::<sqlsync::sqlite::Error>()
+        {
+            self.sqlite(err);
+        } else if let Some(err) = cause.downcast_ref::<EncodeError>() {
+            self.encode(err);
+        } else if let Some(err) = cause.downcast_ref::<ExportError>() {
+            match err {
+                ExportError::Sqlite(err) => self.sqlite(err),
+                ExportError::Io(_) => self.set(ErrorCode::Storage),
+                ExportError::Encode(err) => self.encode(err),
+                ExportError::Parquet(_) => self.set(ErrorCode::Internal),
+            }
+        } else if cause.is::<JournalError>() || cause.is::<io::Error>() {
+            self.set(ErrorCode::Storage);
+        } else if cause.is::<gloo::net::Error>()
+            || cause.is::<gloo::net::websocket::WebSocketError>()
+        {
+            self.retry(ErrorCode::Network);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn set(&mut self, code: ErrorCode) {
+        self.code = code;
+        self.retryable = false;
+    }
+
+    fn retry(&mut self, code: ErrorCode) {
+        self.code = code;
+        self.retryable = true;
+    }
+
+    fn worker(&mut self, err: &WorkerError) {
+        match err {
+            WorkerError::NotBooted => self.set(ErrorCode::NotBooted),
+            WorkerError::ProtocolVersion { worker, tab } => {
+                self.set(ErrorCode::ProtocolVersion);
+                self.value = Some(*tab as i64);
+                self.limit = Some(*worker as i64);
+            }
+            WorkerError::UnknownDocument(doc_id) => {
+                self.set(ErrorCode::UnknownDocument);
+                self.doc_id = Some(*doc_id);
+            }
+            WorkerError::NotSynced => self.retry(ErrorCode::NotSynced),
+            WorkerError::Stale { staleness_ms, max_staleness_ms } => {
+                self.retry(ErrorCode::Stale);
+                self.value = Some(*staleness_ms);
+                self.limit = Some(*max_staleness_ms);
+            }
+            WorkerError::NotObserved => self.retry(ErrorCode::NotObserved),
+            WorkerError::NoCoordinator => self.set(ErrorCode::NoCoordinator),
+            WorkerError::Aborted => self.set(ErrorCode::Aborted),
+            WorkerError::UnknownExport(_) => self.set(ErrorCode::UnknownExport),
+            WorkerError::ExportChanged(_) => self.set(ErrorCode::ExportChanged),
+        }
+    }
+
+    fn sqlsync(&mut self, err: &SqlSyncError) {
+        if let Some(trap) = err.reducer_trap() {
+            self.set(ErrorCode::ReducerTrap);
+            self.trap = Some(trap.into());
+            return;
+        }
+        match err {
+            SqlSyncError::ReplicationError(err) => self.replication(err),
+            SqlSyncError::JournalError(_) => self.set(ErrorCode::Storage),
+            SqlSyncError::JournalIdParseError(_) => {
+                self.set(ErrorCode::InvalidToken)
+            }
+            SqlSyncError::TimelineError(err) => self.timeline(err),
+            SqlSyncError::ReducerError(err) => self.reducer(err),
+            SqlSyncError::SqliteError(err) => self.sqlite(err),
+            SqlSyncError::DocumentExists(doc_id) => {
+                self.set(ErrorCode::Internal);
+                self.doc_id = Some(*doc_id);
+            }
+            SqlSyncError::MutationTooLarge { len, max } => {
+                self.set(ErrorCode::MutationTooLarge);
+                self.value = Some(*len as i64);
+                self.limit = Some(*max as i64);
+            }
+            SqlSyncError::UpdateRequired { digest } => {
+                self.set(ErrorCode::UpdateRequired);
+                self.reducer_digest = Some(bs58::encode(digest).into_string());
+            }
+            SqlSyncError::UnknownDraft(_) => self.set(ErrorCode::UnknownDraft),
+            SqlSyncError::NotObserved(_) => self.set(ErrorCode::NotObserved),
+            SqlSyncError::LsnNotRetained { .. } => {
+                self.retry(ErrorCode::Replication)
+            }
+        }
+    }
+
+    fn timeline(&mut self, err: &TimelineError) {
+        match err {
+            TimelineError::IoError(_) | TimelineError::JournalError(_) => {
+                self.set(ErrorCode::Storage)
+            }
+            // a frame in the timeline journal which we can't read back
+            TimelineError::UnsupportedFrameVersion(_)
+            | TimelineError::MalformedFrame(_)
+            | TimelineError::ImplausibleMutationSize { .. } => {
+                self.set(ErrorCode::Storage)
+            }
+            TimelineError::Sqlite(err) => self.sqlite(err),
+            TimelineError::ReducerError(err) => self.reducer(err),
+            TimelineError::InvalidIdempotencyKey(_) => {
+                self.set(ErrorCode::Internal)
+            }
+            TimelineError::InvalidConsistencyToken(_) => {
+                self.set(ErrorCode::InvalidToken)
+            }
+            TimelineError::MutationCancelled(_) => {
+                self.set(ErrorCode::MutationCancelled)
+            }
+        }
+    }
+
+    fn reducer(&mut self, err: &ReducerError) {
+        match err {
+            ReducerError::Trap(trap) => {
+                self.set(ErrorCode::ReducerTrap);
+                self.trap = Some(trap.as_ref().into());
+            }
+            ReducerError::Sqlite(err) => self.sqlite(err),
+            _ => self.set(ErrorCode::Reducer),
+        }
+    }
+
+    fn sqlite(&mut self, err: &sqlsync::sqlite::Error) {
+        if sqlsync::is_busy(err) {
+            self.retry(ErrorCode::Busy);
+        } else if err.sqlite_error_code()
+            == Some(sqlsync::sqlite::ErrorCode::DiskFull)
+        {
+            self.set(ErrorCode::StorageFull);
+        } else {
+            self.set(ErrorCode::Storage);
+        }
+    }
+
+    fn encode(&mut self, err: &EncodeError) {
+        match err {
+            EncodeError::Sqlite(err) => self.sqlite(err),
+            EncodeError::Arrow(_) | EncodeError::ColumnType { .. } => {
+                self.set(ErrorCode::Internal)
+            }
+        }
+    }
+
+    fn replication(&mut self, err: &ReplicationError) {
+        match err {
+            ReplicationError::Remote(err) => self.protocol(err),
+            ReplicationError::ReconnectLater { retry_after_ms } => {
+                self.retry(ErrorCode::Replication);
+                self.retry_after_ms = Some(*retry_after_ms);
+            }
+            ReplicationError::Io(_) | ReplicationError::JournalError(_) => {
+                self.set(ErrorCode::Storage)
+            }
+            ReplicationError::FrameTooLarge { .. } => {
+                self.set(ErrorCode::Replication)
+            }
+            _ => self.retry(ErrorCode::Replication),
+        }
+    }
+
+    fn protocol(&mut self, err: &ProtocolError) {
+        match err {
+            ProtocolError::AuthFailed => self.set(ErrorCode::AuthFailed),
+            ProtocolError::DocNotFound(doc_id) => {
+                self.set(ErrorCode::DocNotFound);
+                self.doc_id = Some(*doc_id);
+            }
+            ProtocolError::ReducerMismatch(_) => {
+                self.set(ErrorCode::ReducerMismatch)
+            }
+            ProtocolError::QuotaExceeded(_) => {
+                self.set(ErrorCode::QuotaExceeded)
+            }
+            ProtocolError::Retryable { retry_after_ms, .. } => {
+                self.retry(ErrorCode::Replication);
+                self.retry_after_ms = *retry_after_ms;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use std::io;
+
+    use anyhow::Context;
+    use sqlsync::{
+        error::Error as SqlSyncError,
+        replication::{ProtocolError, ReplicationError},
+        session::SessionError,
+        timeline::TimelineError,
+        JournalId,
+    };
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{ErrorCode, ErrorInfo, WorkerError};
+    use crate::utils::WasmError;
+
+    fn info(err: impl Into<anyhow::Error>) -> ErrorInfo {
+        ErrorInfo::from(&WasmError(err.into()))
+    }
+
+    #[wasm_bindgen_test]
+    fn worker_errors_carry_their_context() {
+        let stale = info(WorkerError::Stale {
+            staleness_ms: 1500,
+            max_staleness_ms: 1000,
+        });
+        assert_eq!(stale.code, ErrorCode::Stale);
+        assert!(stale.retryable);
+        assert_eq!(stale.value, Some(1500));
+        assert_eq!(stale.limit, Some(1000));
+
+        let version = info(WorkerError::ProtocolVersion { worker: 2, tab: 1 });
+        assert_eq!(version.code, ErrorCode::ProtocolVersion);
+        assert!(!version.retryable);
+        assert_eq!(version.value, Some(1));
+        assert_eq!(version.limit, Some(2));
+    }
+
+    #[wasm_bindgen_test]
+    fn classifies_through_added_context() {
+        let doc_id = JournalId::Size128([7; 16]);
+        let err = anyhow::Error::from(WorkerError::UnknownDocument(doc_id))
+            .context("failed to open document");
+        let info = info(err);
+        assert_eq!(info.code, ErrorCode::UnknownDocument);
+        assert_eq!(info.doc_id, Some(doc_id));
+        // the message keeps the whole chain
+        assert!(info.message.starts_with("failed to open document: "));
+        assert!(info.message.contains(&doc_id.to_string()));
+
+        let err: anyhow::Result<()> =
+            Err(io::Error::other("disk on fire")).context("flushing");
+        assert_eq!(self::info(err.unwrap_err()).code, ErrorCode::Storage);
+    }
+
+    #[wasm_bindgen_test]
+    fn classifies_wrapped_errors() {
+        // transparent variants are matched on rather than walked
+        let err = SqlSyncError::ReplicationError(ReplicationError::Remote(
+            ProtocolError::Retryable {
+                message: "coordinator restarting".into(),
+                retry_after_ms: Some(250),
+            },
+        ));
+        let info = info(err);
+        assert_eq!(info.code, ErrorCode::Replication);
+        assert!(info.retryable);
+        assert_eq!(info.retry_after_ms, Some(250));
+
+        let err = SqlSyncError::TimelineError(TimelineError::MalformedFrame(
+            "truncated",
+        ));
+        assert_eq!(self::info(err).code, ErrorCode::Storage);
+
+        let err = SessionError::Replication(ReplicationError::Remote(
+            ProtocolError::AuthFailed,
+        ));
+        let info = self::info(err);
+        assert_eq!(info.code, ErrorCode::AuthFailed);
+        assert!(!info.retryable);
+
+        let info = self::info(SessionError::TimedOut);
+        assert_eq!(info.code, ErrorCode::Network);
+        assert!(info.retryable);
+    }
+
+    #[wasm_bindgen_test]
+    fn sqlsync_errors_carry_their_context() {
+        let info = info(SqlSyncError::MutationTooLarge { len: 200, max: 100 });
+        assert_eq!(info.code, ErrorCode::MutationTooLarge);
+        assert_eq!(info.value, Some(200));
+        assert_eq!(info.limit, Some(100));
+
+        let digest = [3; 32];
+        let info = self::info(SqlSyncError::UpdateRequired { digest });
+        assert_eq!(info.code, ErrorCode::UpdateRequired);
+        assert_eq!(
+            info.reducer_digest,
+            Some(bs58::encode(digest).into_string())
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn unknown_errors_are_internal() {
+        let info = info(anyhow::anyhow!("something else"));
+        assert_eq!(info.code, ErrorCode::Internal);
+        assert!(!info.retryable);
+        assert_eq!(info.message, "something else");
+        assert_eq!(info.doc_id, None);
+    }
+}
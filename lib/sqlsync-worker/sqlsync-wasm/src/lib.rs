@@ -1,8 +1,13 @@
 mod api;
+mod cancel;
 mod doc_task;
+mod error;
+mod manager;
+mod mux;
 mod net;
 mod reactive;
 mod signal;
+mod spill;
 mod sql;
 mod utils;
 
@@ -16,4 +21,6 @@ pub fn main() {
     utils::set_panic_hook();
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Info);
+    sqlsync::logging::set_log_sink(ConsoleLogger);
+    wasm_bindgen_futures::spawn_local(spill::install());
 }
@@ -0,0 +1,585 @@
+//! DocumentManager keeps track of the documents open in a worker. Documents
+//! are opened on demand, the first time a port sends them an Open, and are
+//! closed once every port which opened them has sent a Close (or gone away)
+//! and they are idle.
+//!
+//! Workspaces may open far more documents than fit in memory, so the manager
+//! can be given a memory budget. While the materialized documents use more
+//! than it, the least recently used idle documents are evicted: their doc
+//! task is dropped, and the document is materialized again from a checkpoint
+//! and the coordinator the next time a port uses it. A document is idle
+//! while it has no subscriptions, drafts, waiting requests or mutations the
+//! coordinator hasn't confirmed, so evicting it loses nothing.
+//!
+//! Every materialized document connects to the coordinator at the manager's
+//! url, so evicting documents also bounds how many connections are open.
+//! With multiplexing on, the documents instead share a single connection to
+//! the coordinator's /mux endpoint, each replicating over its own stream.
+//! Streams sync in priority order, so the app can keep the document on
+//! screen in the foreground and let the rest of the workspace trickle in.
+
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    SinkExt,
+};
+use sqlsync::JournalId;
+
+use crate::{
+    api::{
+        DocReply, DocRequest, HandlerId, HostToWorkerMsg, OpenOptions, PortId,
+        PortRouter, SyncPriority,
+    },
+    cancel::Cancellations,
+    doc_task::DocTask,
+    error::WorkerError,
+    mux::MuxConnection,
+    net::Endpoint,
+    utils::{fetch_checkpoint, fetch_reducer, WasmError},
+};
+
+/// what a doc task reports to the manager after each step
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DocUsage {
+    /// see LocalDocument::journal_size
+    pub memory_bytes: u64,
+    /// whether the document can be closed without losing anything
+    pub idle: bool,
+    /// messages the task has handled, so that a task isn't closed while
+    /// requests sent to it are still queued
+    pub handled: u64,
+}
+
+/// the manager's handle to an open document
+struct DocHandle {
+    // ports which have opened the document and not closed it
+    ports: HashSet<PortId>,
+    // the open which added each port, so that cancelling it releases the
+    // document again
+    opens: HashMap<PortId, HandlerId>,
+    // the latest open's reducer and options, to materialize the document
+    // with again after it's evicted
+    reducer_url: String,
+    options: OpenOptions,
+    priority: SyncPriority,
+    // the manager's clock when the document was last used
+    last_used: u64,
+    // unset while the document is evicted
+    task: Option<TaskHandle>,
+}
+
+impl DocHandle {
+    fn memory_bytes(&self) -> u64 {
+        self.task
+            .as_ref()
+            .map_or(0, |task| task.usage.get().memory_bytes)
+    }
+
+    fn idle(&self) -> bool {
+        let Some(task) = &self.task else {
+            return true;
+        };
+        let usage = task.usage.get();
+        usage.idle && usage.handled == task.sent
+    }
+}
+
+struct TaskHandle {
+    inbox: UnboundedSender<HostToWorkerMsg>,
+    usage: Rc<Cell<DocUsage>>,
+    cancelled: Cancellations,
+    // messages sent to the task, see DocUsage::handled
+    sent: u64,
+}
+
+pub struct DocumentManager {
+    coordinator_url: Option<String>,
+    // shared by every document when multiplexing
+    mux: Option<MuxConnection>,
+    ports: PortRouter,
+    docs: HashMap<JournalId, DocHandle>,
+    memory_budget: Option<u64>,
+    // ticks once per request, to find the least recently used documents
+    clock: u64,
+}
+
+impl DocumentManager {
+    pub fn new(
+        ports: PortRouter,
+        coordinator_url: Option<String>,
+        memory_budget: Option<u64>,
+        multiplex: bool,
+    ) -> Self {
+        let mux = coordinator_url
+            .as_ref()
+            .filter(|_| multiplex)
+            .map(|url| MuxConnection::new(format!("{}/mux", url)));
+        Self {
+            coordinator_url,
+            mux,
+            ports,
+            docs: HashMap::new(),
+            memory_budget,
+            clock: 0,
+        }
+    }
+
+    pub async fn handle(
+        &mut self,
+        msg: HostToWorkerMsg,
+    ) -> Result<(), WasmError> {
+        self.clock += 1;
+
+        if let DocRequest::Open { reducer_url, options } = &msg.req {
+            if !self.docs.contains_key(&msg.doc_id) {
+                let task = Self::spawn(
+                    &self.coordinator_url,
+                    &self.mux,
+                    &self.ports,
+                    msg.doc_id,
+                    reducer_url,
+                    *options,
+                    SyncPriority::default(),
+                )
+                .await?;
+                self.docs.insert(
+                    msg.doc_id,
+                    DocHandle {
+                        ports: HashSet::new(),
+                        opens: HashMap::new(),
+                        reducer_url: reducer_url.clone(),
+                        options: *options,
+                        priority: SyncPriority::default(),
+                        last_used: 0,
+                        task: Some(task),
+                    },
+                );
+            }
+            let handle = self.docs.get_mut(&msg.doc_id).expect("doc is open");
+            if handle.ports.insert(msg.port_id) {
+                handle.opens.insert(msg.port_id, msg.handler_id);
+            }
+            handle.reducer_url.clone_from(reducer_url);
+            handle.options = *options;
+        }
+
+        let Some(handle) = self.docs.get_mut(&msg.doc_id) else {
+            let _ = self.ports.send_one(
+                msg.port_id,
+                msg.reply_err(&WorkerError::UnknownDocument(msg.doc_id).into()),
+            );
+            return Ok(());
+        };
+        handle.last_used = self.clock;
+
+        if let DocRequest::SetPriority { priority } = msg.req {
+            handle.priority = priority;
+            // an evicted document picks its priority up when it's
+            // materialized again
+            if handle.task.is_none() {
+                let _ =
+                    self.ports.send_one(msg.port_id, msg.reply(DocReply::Ack));
+                return Ok(());
+            }
+        }
+
+        if let DocRequest::Cancel { handler_id } = msg.req {
+            // the tab gave up on opening the document, so the document is
+            // closed once it's idle, which stops its initial sync. the doc
+            // task answers the open itself
+            if handle.opens.get(&msg.port_id) == Some(&handler_id) {
+                handle.opens.remove(&msg.port_id);
+                handle.ports.remove(&msg.port_id);
+            }
+            match &handle.task {
+                Some(task) => {
+                    task.cancelled
+                        .borrow_mut()
+                        .insert((msg.port_id, handler_id));
+                }
+                // nothing is running for an evicted document
+                None => {
+                    let _ = self
+                        .ports
+                        .send_one(msg.port_id, msg.reply(DocReply::Ack));
+                    return Ok(());
+                }
+            }
+        }
+
+        if let DocRequest::Close = msg.req {
+            handle.ports.remove(&msg.port_id);
+            handle.opens.remove(&msg.port_id);
+            // an evicted document has no subscriptions to drop
+            if handle.task.is_none() {
+                let _ =
+                    self.ports.send_one(msg.port_id, msg.reply(DocReply::Ack));
+                self.evict();
+                return Ok(());
+            }
+        }
+
+        if handle.task.is_none() {
+            log::info!("rematerializing document {}", msg.doc_id);
+            handle.task = Some(
+                Self::spawn(
+                    &self.coordinator_url,
+                    &self.mux,
+                    &self.ports,
+                    msg.doc_id,
+                    &handle.reducer_url,
+                    handle.options,
+                    handle.priority,
+                )
+                .await?,
+            );
+        }
+
+        // the doc task replies to Open itself, as it may wait to sync first
+        let task = handle.task.as_mut().expect("doc is materialized");
+        task.sent += 1;
+        task.inbox.send(msg).await?;
+
+        self.evict();
+        Ok(())
+    }
+
+    /// forget a port which went away, as if it had closed every document
+    pub fn close_port(&mut self, port_id: PortId) {
+        for handle in self.docs.values_mut() {
+            handle.ports.remove(&port_id);
+            handle.opens.remove(&port_id);
+        }
+        self.evict();
+    }
+
+    /// close idle documents no port has open, then evict the least
+    /// recently used idle documents until the rest fit in the memory budget.
+    /// the document used by the current request is never evicted
+    fn evict(&mut self) {
+        self.docs.retain(|doc_id, handle| {
+            let open = !handle.ports.is_empty() || !handle.idle();
+            if !open {
+                log::info!("closing document {}", doc_id);
+            }
+            open
+        });
+
+        let Some(budget) = self.memory_budget else {
+            return;
+        };
+        let mut used: u64 =
+            self.docs.values().map(DocHandle::memory_bytes).sum();
+        if used <= budget {
+            return;
+        }
+
+        let clock = self.clock;
+        let mut candidates: Vec<_> = self
+            .docs
+            .iter_mut()
+            .filter(|(_, handle)| {
+                handle.task.is_some()
+                    && handle.idle()
+                    && handle.last_used != clock
+            })
+            .collect();
+        candidates.sort_by_key(|(_, handle)| handle.last_used);
+        for (doc_id, handle) in candidates {
+            if used <= budget {
+                break;
+            }
+            log::info!(
+                "evicting document {} to stay within {} bytes",
+                doc_id,
+                budget
+            );
+            used -= handle.memory_bytes();
+            handle.task = None;
+        }
+    }
+
+    async fn spawn(
+        coordinator_url: &Option<String>,
+        mux: &Option<MuxConnection>,
+        ports: &PortRouter,
+        doc_id: JournalId,
+        reducer_url: &str,
+        options: OpenOptions,
+        priority: SyncPriority,
+    ) -> Result<TaskHandle, WasmError> {
+        let (reducer, digest) = fetch_reducer(reducer_url).await?;
+
+        let endpoint = match mux {
+            Some(conn) => Some(Endpoint::Mux {
+                conn: conn.clone(),
+                doc: doc_id,
+                reducer: digest,
+            }),
+            None => coordinator_url.as_ref().map(|url| {
+                Endpoint::Url(format!(
+                    "{}/doc/{}?reducer={}",
+                    url,
+                    doc_id.to_base58(),
+                    bs58::encode(&digest).into_string()
+                ))
+            }),
+        };
+
+        // checkpoints are served over http(s) from the same host
+        let checkpoint_url = coordinator_url.as_ref().map(|url| {
+            format!(
+                "{}/doc/{}/snapshot",
+                url.replacen("ws", "http", 1),
+                doc_id.to_base58()
+            )
+        });
+
+        let (tx, rx) = mpsc::unbounded();
+        let usage = Rc::new(Cell::new(DocUsage::default()));
+
+        let mut task = DocTask::new(
+            doc_id,
+            endpoint,
+            reducer,
+            digest,
+            rx,
+            ports.clone(),
+            usage.clone(),
+        )?;
+        task.set_open_options(options);
+        task.set_priority(priority);
+        let cancelled = task.cancellations();
+
+        if let Some(url) = checkpoint_url {
+            // without a checkpoint we fall back to replicating every frame
+            match fetch_checkpoint(&url).await {
+                Ok(Some(checkpoint)) => task.bootstrap(checkpoint)?,
+                Ok(None) => {}
+                Err(e) => log::warn!("failed to fetch checkpoint: {:?}", e),
+            }
+        }
+
+        wasm_bindgen_futures::spawn_local(task.into_task());
+
+        Ok(TaskHandle { inbox: tx, usage, cancelled, sent: 0 })
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+        rc::Rc,
+    };
+
+    use futures::channel::mpsc;
+    use js_sys::Object;
+    use sqlsync::JournalId;
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{DocHandle, DocUsage, DocumentManager, TaskHandle};
+    use crate::api::{
+        DocRequest, HostToWorkerMsg, OpenOptions, PortId, SyncPriority,
+    };
+
+    const PORT: PortId = 1;
+
+    fn manager(memory_budget: Option<u64>) -> DocumentManager {
+        // replies can't be sent to a plain object, and the manager ignores
+        // replies which fail to send
+        DocumentManager::new(
+            Object::new().unchecked_into(),
+            None,
+            memory_budget,
+            false,
+        )
+    }
+
+    fn doc(id: u8) -> JournalId {
+        JournalId::Size128([id; 16])
+    }
+
+    /// a materialized document using memory_bytes, as its task last
+    /// reported
+    fn task(memory_bytes: u64, idle: bool) -> TaskHandle {
+        let (inbox, _) = mpsc::unbounded();
+        TaskHandle {
+            inbox,
+            usage: Rc::new(Cell::new(DocUsage {
+                memory_bytes,
+                idle,
+                handled: 0,
+            })),
+            cancelled: Default::default(),
+            sent: 0,
+        }
+    }
+
+    fn open(
+        manager: &mut DocumentManager,
+        id: u8,
+        last_used: u64,
+        task: Option<TaskHandle>,
+    ) {
+        manager.docs.insert(
+            doc(id),
+            DocHandle {
+                ports: HashSet::from([PORT]),
+                opens: HashMap::new(),
+                reducer_url: "reducer.wasm".into(),
+                options: OpenOptions::default(),
+                priority: SyncPriority::default(),
+                last_used,
+                task,
+            },
+        );
+        manager.clock = manager.clock.max(last_used);
+    }
+
+    fn materialized(manager: &DocumentManager) -> Vec<JournalId> {
+        let mut docs: Vec<_> = manager
+            .docs
+            .iter()
+            .filter(|(_, handle)| handle.task.is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        docs.sort_by_key(|id| id.bytes().to_vec());
+        docs
+    }
+
+    fn request(id: u8, req: DocRequest) -> HostToWorkerMsg {
+        HostToWorkerMsg { port_id: PORT, handler_id: 7, doc_id: doc(id), req }
+    }
+
+    #[wasm_bindgen_test]
+    fn evicts_least_recently_used_idle_documents() {
+        let mut manager = manager(Some(250));
+        open(&mut manager, 1, 1, Some(task(100, true)));
+        open(&mut manager, 2, 2, Some(task(100, true)));
+        open(&mut manager, 3, 3, Some(task(100, false)));
+        manager.clock += 1;
+
+        manager.evict();
+        assert_eq!(materialized(&manager), [doc(2), doc(3)]);
+        // evicted documents stay open for their ports
+        assert_eq!(manager.docs.len(), 3);
+
+        // busy documents and the one in use are never evicted
+        manager.memory_budget = Some(0);
+        manager.docs.get_mut(&doc(2)).unwrap().last_used = manager.clock;
+        manager.evict();
+        assert_eq!(materialized(&manager), [doc(2), doc(3)]);
+    }
+
+    #[wasm_bindgen_test]
+    fn closes_idle_documents_no_port_has_open() {
+        let mut manager = manager(None);
+        open(&mut manager, 1, 1, Some(task(100, true)));
+        open(&mut manager, 2, 1, Some(task(100, false)));
+        // a request is still queued for it
+        let mut queued = task(100, true);
+        queued.sent = 1;
+        open(&mut manager, 3, 1, Some(queued));
+        open(&mut manager, 4, 1, None);
+
+        manager.close_port(PORT);
+        let mut remaining: Vec<_> = manager.docs.keys().copied().collect();
+        remaining.sort_by_key(|id| id.bytes().to_vec());
+        assert_eq!(remaining, [doc(2), doc(3)]);
+
+        // once the task catches up it can be closed
+        let handle = manager.docs.get_mut(&doc(3)).unwrap();
+        let task = handle.task.as_ref().unwrap();
+        task.usage.set(DocUsage { handled: 1, ..task.usage.get() });
+        manager.evict();
+        assert_eq!(manager.docs.keys().collect::<Vec<_>>(), [&doc(2)]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn evicted_documents_answer_without_materializing() {
+        let mut manager = manager(Some(0));
+        open(&mut manager, 1, 1, None);
+
+        let priority = SyncPriority::Background;
+        manager
+            .handle(request(1, DocRequest::SetPriority { priority }))
+            .await
+            .unwrap();
+        manager
+            .handle(request(1, DocRequest::Cancel { handler_id: 3 }))
+            .await
+            .unwrap();
+        let handle = &manager.docs[&doc(1)];
+        assert_eq!(handle.priority, priority);
+        assert!(handle.task.is_none());
+
+        // closing it forgets the document
+        manager.handle(request(1, DocRequest::Close)).await.unwrap();
+        assert!(manager.docs.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn cancelling_an_open_releases_the_document() {
+        let mut manager = manager(None);
+        let (inbox, mut received) = mpsc::unbounded();
+        let task = TaskHandle { inbox, ..task(100, true) };
+        let cancelled = task.cancelled.clone();
+        open(&mut manager, 1, 1, Some(task));
+
+        let from_port = |port_id, handler_id, req| HostToWorkerMsg {
+            port_id,
+            handler_id,
+            doc_id: doc(1),
+            req,
+        };
+        let opening = DocRequest::Open {
+            reducer_url: "reducer.wasm".into(),
+            options: OpenOptions::default(),
+        };
+        manager.handle(from_port(2, 5, opening)).await.unwrap();
+        let ports = |manager: &DocumentManager| {
+            let mut ports: Vec<_> =
+                manager.docs[&doc(1)].ports.iter().copied().collect();
+            ports.sort();
+            ports
+        };
+        assert_eq!(ports(&manager), [PORT, 2]);
+
+        // cancelling another of the port's requests keeps it open
+        let cancel = |handler_id| DocRequest::Cancel { handler_id };
+        manager.handle(from_port(2, 4, cancel(4))).await.unwrap();
+        assert_eq!(ports(&manager), [PORT, 2]);
+        manager.handle(from_port(2, 6, cancel(5))).await.unwrap();
+        assert_eq!(ports(&manager), [PORT]);
+        assert!(cancelled.borrow().contains(&(2, 5)));
+
+        // and the document closes once the task answers them, as the other
+        // port closes it
+        manager.handle(request(1, DocRequest::Close)).await.unwrap();
+        assert_eq!(manager.docs.len(), 1);
+        let mut handled = 0;
+        while let Ok(Some(_)) = received.try_next() {
+            handled += 1;
+        }
+        assert_eq!(handled, 4);
+        let task = manager.docs[&doc(1)].task.as_ref().unwrap();
+        task.usage.set(DocUsage { handled, ..task.usage.get() });
+        manager.evict();
+        assert!(manager.docs.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn requests_for_unknown_documents_are_refused() {
+        let mut manager = manager(None);
+        manager.handle(request(9, DocRequest::Close)).await.unwrap();
+        assert!(manager.docs.is_empty());
+    }
+}
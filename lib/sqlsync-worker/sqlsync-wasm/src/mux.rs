@@ -0,0 +1,308 @@
+//! MuxConnection is the worker's end of a multiplexed coordinator
+//! connection (see sqlsync::mux). It owns one websocket, opened when the
+//! first document opens a stream and closed once the last stream closes,
+//! and hands each document a MuxStream to replicate over. When the socket
+//! fails every stream ends, and each document reconnects with its usual
+//! backoff by opening a new stream.
+
+use std::{cell::Cell, collections::HashMap, rc::Rc};
+
+use anyhow::anyhow;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, Either},
+    stream::{Fuse, SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use gloo::net::websocket::{futures::WebSocket, Message};
+use sqlsync::{
+    mux::{Multiplexer, MuxEvent, Priority, StreamId},
+    JournalId, ModuleDigest,
+};
+
+enum Command {
+    Open {
+        stream: StreamId,
+        doc: JournalId,
+        reducer: Option<ModuleDigest>,
+        priority: Priority,
+        events: UnboundedSender<Vec<u8>>,
+    },
+    Send {
+        stream: StreamId,
+        msg: Vec<u8>,
+    },
+    /// the document has taken bytes of the stream's data, so the
+    /// coordinator may send more
+    Consumed {
+        stream: StreamId,
+        bytes: usize,
+    },
+    Close {
+        stream: StreamId,
+    },
+    SetPriority {
+        stream: StreamId,
+        priority: Priority,
+    },
+}
+
+#[derive(Clone)]
+pub struct MuxConnection {
+    commands: UnboundedSender<Command>,
+    // stream ids aren't reused, so a late message for a closed stream can't
+    // reach a newer one
+    next_stream: Rc<Cell<StreamId>>,
+}
+
+impl MuxConnection {
+    pub fn new(url: String) -> Self {
+        let (commands, rx) = mpsc::unbounded();
+        wasm_bindgen_futures::spawn_local(run(url, rx));
+        Self { commands, next_stream: Rc::new(Cell::new(0)) }
+    }
+
+    /// open a stream which replicates doc
+    pub fn open(
+        &self,
+        doc: JournalId,
+        reducer: Option<ModuleDigest>,
+        priority: Priority,
+    ) -> MuxStream {
+        let stream = self.next_stream.get();
+        self.next_stream.set(stream.wrapping_add(1));
+        let (events, rx) = mpsc::unbounded();
+        let _ = self.commands.unbounded_send(Command::Open {
+            stream,
+            doc,
+            reducer,
+            priority,
+            events,
+        });
+        MuxStream { stream, commands: self.commands.clone(), events: rx }
+    }
+}
+
+/// one document's stream on a MuxConnection, closed when dropped
+pub struct MuxStream {
+    stream: StreamId,
+    commands: UnboundedSender<Command>,
+    events: UnboundedReceiver<Vec<u8>>,
+}
+
+impl MuxStream {
+    pub fn send(&mut self, msg: Vec<u8>) -> anyhow::Result<()> {
+        let stream = self.stream;
+        self.commands
+            .unbounded_send(Command::Send { stream, msg })
+            .map_err(|_| anyhow!("multiplexed connection is gone"))
+    }
+
+    /// the next message from the coordinator. the coordinator only gets
+    /// credit for more once the document has taken this one
+    pub async fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+        let msg = self.events.next().await.ok_or_else(|| {
+            anyhow!("multiplexed stream {} closed", self.stream)
+        })?;
+        let stream = self.stream;
+        let bytes = msg.len();
+        let _ = self
+            .commands
+            .unbounded_send(Command::Consumed { stream, bytes });
+        Ok(msg)
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        let stream = self.stream;
+        let _ = self
+            .commands
+            .unbounded_send(Command::SetPriority { stream, priority });
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let stream = self.stream;
+        let _ = self.commands.unbounded_send(Command::Close { stream });
+    }
+}
+
+struct Socket {
+    reader: Fuse<SplitStream<WebSocket>>,
+    writer: SplitSink<WebSocket, Message>,
+}
+
+async fn run(url: String, mut commands: UnboundedReceiver<Command>) {
+    let mut mux = Multiplexer::default();
+    let mut streams: HashMap<StreamId, UnboundedSender<Vec<u8>>> =
+        HashMap::new();
+    let mut socket: Option<Socket> = None;
+
+    loop {
+        let event = match socket.as_mut() {
+            Some(Socket { reader, .. }) => {
+                match future::select(commands.next(), reader.next()).await {
+                    Either::Left((cmd, _)) => Either::Left(cmd),
+                    Either::Right((msg, _)) => Either::Right(msg),
+                }
+            }
+            None => Either::Left(commands.next().await),
+        };
+
+        let ok = match event {
+            // every handle to the connection has been dropped
+            Either::Left(None) => return,
+            Either::Left(Some(Command::Open {
+                stream,
+                doc,
+                reducer,
+                priority,
+                events,
+            })) => {
+                if socket.is_none() {
+                    log::info!("connecting to {}", url);
+                    match WebSocket::open(&url) {
+                        Ok(ws) => {
+                            let (writer, reader) = ws.split();
+                            socket =
+                                Some(Socket { reader: reader.fuse(), writer });
+                        }
+                        Err(e) => {
+                            // dropping events ends the stream, the document
+                            // retries with its backoff
+                            log::error!("connection error: {:?}", e);
+                            continue;
+                        }
+                    }
+                }
+                match mux.open(stream, doc, reducer) {
+                    Ok(()) => {
+                        let _ = mux.set_priority(stream, priority);
+                        streams.insert(stream, events);
+                    }
+                    Err(e) => log::error!("failed to open stream: {:?}", e),
+                }
+                true
+            }
+            // the stream may have ended with a failed socket
+            Either::Left(Some(Command::Send { stream, msg })) => {
+                let _ = mux.send(stream, msg);
+                true
+            }
+            Either::Left(Some(Command::Consumed { stream, bytes })) => {
+                mux.consumed(stream, bytes);
+                true
+            }
+            Either::Left(Some(Command::Close { stream })) => {
+                mux.close(stream);
+                streams.remove(&stream);
+                true
+            }
+            Either::Left(Some(Command::SetPriority { stream, priority })) => {
+                let _ = mux.set_priority(stream, priority);
+                true
+            }
+            Either::Right(Some(Ok(Message::Bytes(bytes)))) => {
+                match mux.receive(&bytes) {
+                    Ok(Some(MuxEvent::Data { stream, msg })) => {
+                        if let Some(events) = streams.get(&stream) {
+                            let _ = events.unbounded_send(msg);
+                        }
+                        true
+                    }
+                    Ok(Some(MuxEvent::Closed { stream })) => {
+                        streams.remove(&stream);
+                        true
+                    }
+                    Ok(Some(MuxEvent::Opened { stream, .. })) => {
+                        log::warn!("coordinator opened stream {}", stream);
+                        mux.close(stream);
+                        true
+                    }
+                    Ok(None) => true,
+                    Err(e) => {
+                        log::error!("multiplexed connection error: {:?}", e);
+                        false
+                    }
+                }
+            }
+            Either::Right(msg) => {
+                log::error!("multiplexed connection closed: {:?}", msg);
+                false
+            }
+        };
+
+        let ok = match socket.as_mut() {
+            Some(Socket { writer, .. }) if ok => {
+                write(&mut mux, writer).await && !streams.is_empty()
+            }
+            _ => ok,
+        };
+        if !ok && socket.take().is_some() {
+            // ending every stream makes its document reconnect
+            mux = Multiplexer::default();
+            streams.clear();
+        }
+    }
+}
+
+/// send what the multiplexer has for the socket, returning false if the
+/// socket failed
+async fn write(
+    mux: &mut Multiplexer,
+    writer: &mut SplitSink<WebSocket, Message>,
+) -> bool {
+    let msgs = match mux.poll() {
+        Ok(msgs) => msgs,
+        Err(e) => {
+            log::error!("failed to encode multiplexed messages: {:?}", e);
+            return false;
+        }
+    };
+    for msg in msgs {
+        if let Err(e) = writer.send(Message::Bytes(msg)).await {
+            log::error!("multiplexed connection error: {:?}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// a MuxStream without a connection behind it, so tests can play the
+/// coordinator's end of a document's stream
+#[cfg(all(test, target_arch = "wasm32"))]
+pub(crate) mod testing {
+    use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+    use super::{Command, MuxStream};
+
+    pub struct Peer {
+        commands: UnboundedReceiver<Command>,
+        events: UnboundedSender<Vec<u8>>,
+    }
+
+    impl Peer {
+        /// the messages the document has sent since the last call
+        pub fn sent(&mut self) -> Vec<Vec<u8>> {
+            let mut sent = vec![];
+            while let Ok(Some(cmd)) = self.commands.try_next() {
+                if let Command::Send { msg, .. } = cmd {
+                    sent.push(msg);
+                }
+            }
+            sent
+        }
+
+        /// send msg to the document
+        pub fn deliver(&self, msg: Vec<u8>) {
+            self.events.unbounded_send(msg).expect("stream is open");
+        }
+    }
+
+    pub fn stream() -> (MuxStream, Peer) {
+        let (commands, commands_rx) = mpsc::unbounded();
+        let (events, events_rx) = mpsc::unbounded();
+        let stream = MuxStream { stream: 0, commands, events: events_rx };
+        (stream, Peer { commands: commands_rx, events })
+    }
+}
@@ -1,33 +1,133 @@
-use std::{
-    fmt::Debug,
-    io::{self, Cursor},
-};
+use std::{cell::RefCell, fmt::Debug, io, rc::Rc};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use futures::{
+    future::{self, Either},
+    pin_mut,
     stream::{Fuse, SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use gloo::net::websocket::{futures::WebSocket, Message};
+use gloo::{
+    net::websocket::{futures::WebSocket, Message},
+    timers::future::TimeoutFuture,
+};
 use serde::Serialize;
 use sqlsync::{
-    local::Signal,
+    local::{Signal, SyncLag},
+    mux::Priority,
     replication::{
-        ReplicationDestination, ReplicationMsg, ReplicationProtocol,
-        ReplicationSource,
+        HeartbeatConfig, ProtocolError, ReplicationDestination,
+        ReplicationError, ReplicationMsg, ReplicationSource,
     },
+    session::{self, Session, SessionError},
+    unixtime::unix_timestamp_milliseconds,
+    JournalId, ModuleDigest,
 };
 use tsify::Tsify;
 
-use crate::utils::Backoff;
+use crate::{
+    mux::{MuxConnection, MuxStream},
+    utils::Backoff,
+};
 
 // reconnect backoff starts at 10ms and doubles each time, up to 5s
 const MIN_BACKOFF_MS: u32 = 10;
 const MAX_BACKOFF_MS: u32 = 5000;
 
+// how many frames the coordinator may send us before waiting for an ack;
+// large enough to keep a high latency link busy during a cold start
+const RECEIVE_WINDOW_FRAMES: u32 = 500;
+
+/// bytes sent to and received from the coordinator by a document, across
+/// reconnects
+#[derive(Debug, Serialize, Tsify, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// see CoordinatorClient::set_network_budget
+    pub budget_bytes: Option<u64>,
+    /// bytes sent and received since the budget was set
+    pub budget_used: u64,
+}
+
+impl NetworkStats {
+    pub fn over_budget(&self) -> bool {
+        self.budget_bytes
+            .is_some_and(|budget| self.budget_used >= budget)
+    }
+
+    fn sent(&mut self, len: usize) {
+        self.bytes_sent += len as u64;
+        self.budget_used += len as u64;
+    }
+
+    fn received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+        self.budget_used += len as u64;
+    }
+}
+
+/// SyncHealth is a snapshot of how well a document is keeping up with its
+/// coordinator, for UIs to display
+#[derive(Debug, Serialize, Tsify, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncHealth {
+    pub status: ConnectionStatus,
+    /// the round trip time to the coordinator, measured by heartbeats on
+    /// the current connection
+    pub rtt_ms: Option<i64>,
+    /// mutations sent on the current connection which the coordinator
+    /// hasn't acknowledged
+    pub unacked_mutations: usize,
+    /// see SyncLag
+    pub pending_mutations: usize,
+    pub received_lsn: Option<u64>,
+    pub applied_lsn: Option<u64>,
+    /// when the coordinator last acknowledged our mutations or sent us a
+    /// storage frame, in unix milliseconds
+    pub last_sync_ms: Option<i64>,
+}
+
+/// what the client shares with its current connection
+#[derive(Debug, Default)]
+struct Shared {
+    stats: NetworkStats,
+    sync_paused: bool,
+    last_sync_ms: Option<i64>,
+    caught_up_ms: Option<i64>,
+    reducer_request: Option<ModuleDigest>,
+    priority: Priority,
+}
+
+impl Shared {
+    /// whether the coordinator should hold off sending us storage frames
+    fn window_closed(&self) -> bool {
+        self.sync_paused || self.stats.over_budget()
+    }
+}
+
+type SharedState = Rc<RefCell<Shared>>;
+
+/// where a document connects to its coordinator
+pub enum Endpoint {
+    /// a websocket of its own, at the document's url
+    Url(String),
+    /// a stream on a connection shared with other documents
+    Mux {
+        conn: MuxConnection,
+        doc: JournalId,
+        reducer: ModuleDigest,
+    },
+}
+
 pub struct CoordinatorClient<S: Signal> {
-    // while url is none, the state will always be disabled
-    url: Option<String>,
+    // while endpoint is none, the state will always be disabled
+    endpoint: Option<Endpoint>,
+
+    // the connection counts network usage here, and reads whether
+    // replication is paused
+    shared: SharedState,
 
     // we use an option here to work around rust ownership rules when we are
     // transitioning the state
@@ -37,19 +137,102 @@ pub struct CoordinatorClient<S: Signal> {
 }
 
 impl<S: Signal> CoordinatorClient<S> {
-    pub fn new(doc_url: Option<String>, state_changed: S) -> Self {
-        let state = Some(doc_url.as_ref().map_or_else(
+    pub fn new(endpoint: Option<Endpoint>, state_changed: S) -> Self {
+        let state = Some(endpoint.as_ref().map_or_else(
             || ConnectionState::Disabled,
             |_| ConnectionState::Disconnected {
                 backoff: Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
             },
         ));
 
-        Self { url: doc_url, state, state_changed }
+        Self { endpoint, shared: SharedState::default(), state, state_changed }
     }
 
     pub fn can_enable(&self) -> bool {
-        self.url.is_some()
+        self.endpoint.is_some()
+    }
+
+    pub fn network_stats(&self) -> NetworkStats {
+        self.shared.borrow().stats
+    }
+
+    /// once budget bytes have been sent and received from now on, stop
+    /// pulling storage frames from the coordinator until the budget is
+    /// raised or cleared. the document keeps sending its mutations, so
+    /// they aren't lost on a metered connection. the change takes effect
+    /// on the next sync
+    pub fn set_network_budget(&mut self, budget: Option<u64>) {
+        let stats = &mut self.shared.borrow_mut().stats;
+        stats.budget_bytes = budget;
+        stats.budget_used = 0;
+    }
+
+    /// stop replicating in both directions while keeping the connection,
+    /// and the document, open. mutations made while paused are sent once
+    /// sync resumes. like set_network_budget this takes effect on the next
+    /// sync. to go quiet for a long time, disable the connection instead,
+    /// since a paused connection still exchanges heartbeats
+    pub fn pause_sync(&mut self) {
+        self.shared.borrow_mut().sync_paused = true;
+    }
+
+    pub fn resume_sync(&mut self) {
+        self.shared.borrow_mut().sync_paused = false;
+    }
+
+    pub fn sync_paused(&self) -> bool {
+        self.shared.borrow().sync_paused
+    }
+
+    /// the last time we heard from the coordinator on a connection which had
+    /// caught up with everything the coordinator had when it was opened, in
+    /// unix milliseconds. None until the document first syncs
+    pub fn caught_up_ms(&self) -> Option<i64> {
+        self.shared.borrow().caught_up_ms
+    }
+
+    /// ask the coordinator for the wasm of its reducer on the next sync; it
+    /// arrives at the document, see LocalDocument::take_received_reducer
+    pub fn request_reducer(&mut self, digest: ModuleDigest) {
+        self.shared.borrow_mut().reducer_request = Some(digest);
+    }
+
+    /// how the document's stream competes with other documents sharing a
+    /// multiplexed connection; a document with its own connection ignores
+    /// this. takes effect immediately, and carries over reconnects
+    // SAFETY: like status, can not be called concurrently with handle
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.shared.borrow_mut().priority = priority;
+        if let Some(
+            ConnectionState::Connecting { ref mut conn, .. }
+            | ConnectionState::Connected { ref mut conn },
+        ) = self.state
+        {
+            conn.transport.set_priority(priority);
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.shared.borrow().priority
+    }
+
+    // SAFETY: like status, can not be called concurrently with handle
+    pub fn sync_health(&self, lag: SyncLag) -> SyncHealth {
+        let conn = match self.state {
+            Some(ConnectionState::Connecting { ref conn, .. })
+            | Some(ConnectionState::Connected { ref conn }) => Some(conn),
+            _ => None,
+        };
+        SyncHealth {
+            status: self.status(),
+            rtt_ms: conn.and_then(|conn| conn.session.rtt_ms()),
+            unacked_mutations: conn
+                .map_or(0, |conn| conn.session.unacked_frames()),
+            pending_mutations: lag.pending_mutations,
+            received_lsn: lag.received_lsn,
+            applied_lsn: lag.applied_lsn,
+            last_sync_ms: self.shared.borrow().last_sync_ms,
+        }
     }
 
     // SAFETY: poll, status, and handle can not be called concurrently on the same CoordinatorClient
@@ -95,7 +278,7 @@ impl<S: Signal> CoordinatorClient<S> {
         );
 
         // handle the task
-        let state = state.handle(&self.url, doc, task).await;
+        let state = state.handle(&self.endpoint, &self.shared, doc, task).await;
 
         // get the new status and save the new state
         let new_status = state.status();
@@ -111,7 +294,8 @@ impl<S: Signal> CoordinatorClient<S> {
 pub enum ConnectionTask {
     Disable,
     Connect,
-    Recv(ReplicationMsg, Cursor<Vec<u8>>),
+    Recv(Vec<u8>),
+    Ping(Vec<u8>),
     Sync,
     Error(anyhow::Error),
 }
@@ -121,7 +305,8 @@ impl Debug for ConnectionTask {
         match self {
             ConnectionTask::Disable => write!(f, "Disable"),
             ConnectionTask::Connect => write!(f, "Connect"),
-            ConnectionTask::Recv(_, _) => write!(f, "Recv"),
+            ConnectionTask::Recv(_) => write!(f, "Recv"),
+            ConnectionTask::Ping(_) => write!(f, "Ping"),
             ConnectionTask::Sync => write!(f, "Sync"),
             ConnectionTask::Error(e) => write!(f, "Error({:?})", e),
         }
@@ -140,6 +325,11 @@ enum ConnectionState {
     Connected {
         conn: CoordinatorConnection,
     },
+    /// the coordinator rejected us in a way reconnecting won't fix; like
+    /// Disabled, only an explicit Connect leaves this state
+    Failed {
+        error: ProtocolError,
+    },
 }
 
 #[derive(Debug, Serialize, Tsify, Clone, PartialEq, Eq)]
@@ -150,6 +340,7 @@ pub enum ConnectionStatus {
     Disconnected,
     Connecting,
     Connected,
+    Failed,
 }
 
 impl ConnectionState {
@@ -159,6 +350,7 @@ impl ConnectionState {
             Self::Disconnected { .. } => ConnectionStatus::Disconnected,
             Self::Connecting { .. } => ConnectionStatus::Connecting,
             Self::Connected { .. } => ConnectionStatus::Connected,
+            Self::Failed { .. } => ConnectionStatus::Failed,
         }
     }
 }
@@ -166,7 +358,7 @@ impl ConnectionState {
 impl ConnectionState {
     async fn poll(&mut self) -> ConnectionTask {
         match self {
-            ConnectionState::Disabled => {
+            ConnectionState::Disabled | ConnectionState::Failed { .. } => {
                 // block forever, someone else will need to transition us to a different state
                 futures::future::pending::<()>().await;
                 unreachable!("ConnectionState should never be disabled")
@@ -175,24 +367,15 @@ impl ConnectionState {
                 backoff.wait().await;
                 ConnectionTask::Connect
             }
-            ConnectionState::Connecting { conn, .. } => {
-                conn.recv().await.map_or_else(
-                    |e| ConnectionTask::Error(e),
-                    |(msg, buf)| ConnectionTask::Recv(msg, buf),
-                )
-            }
-            ConnectionState::Connected { conn } => {
-                conn.recv().await.map_or_else(
-                    |e| ConnectionTask::Error(e),
-                    |(msg, buf)| ConnectionTask::Recv(msg, buf),
-                )
-            }
+            ConnectionState::Connecting { conn, .. } => conn.poll().await,
+            ConnectionState::Connected { conn } => conn.poll().await,
         }
     }
 
     async fn handle<'a, R, D>(
         self,
-        url: &Option<String>,
+        endpoint: &Option<Endpoint>,
+        shared: &SharedState,
         doc: &'a mut D,
         task: ConnectionTask,
     ) -> ConnectionState
@@ -203,30 +386,40 @@ impl ConnectionState {
         use ConnectionState::*;
         use ConnectionTask::*;
 
-        let url = if url.is_some() {
-            url.as_ref().unwrap()
-        } else {
+        let Some(endpoint) = endpoint else {
             return Disabled;
         };
 
         macro_rules! handle_err {
             ($backoff:ident, $err:ident) => {{
                 log::error!("connection error: {:?}", $err);
-                $backoff.step();
-                ConnectionState::Disconnected { $backoff }
+                if let Some(error) = fatal_error(&$err) {
+                    ConnectionState::Failed { error }
+                } else {
+                    $backoff.step();
+                    ConnectionState::Disconnected {
+                        backoff: reconnect_backoff(&$err).unwrap_or($backoff),
+                    }
+                }
             }};
             ($err:ident) => {{
                 log::error!("connection error: {:?}", $err);
-                ConnectionState::Disconnected {
-                    backoff: Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
+                if let Some(error) = fatal_error(&$err) {
+                    ConnectionState::Failed { error }
+                } else {
+                    ConnectionState::Disconnected {
+                        backoff: reconnect_backoff(&$err).unwrap_or_else(
+                            || Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
+                        ),
+                    }
                 }
             }};
         }
 
         match (self, task) {
-            // disabled ignores all tasks except for Connect
-            (Disabled, Connect) => {
-                match CoordinatorConnection::open(url, doc).await {
+            // disabled and failed ignore all tasks except for Connect
+            (Disabled | Failed { .. }, Connect) => {
+                match CoordinatorConnection::open(endpoint, doc, shared).await {
                     Ok(conn) => ConnectionState::Connecting {
                         conn,
                         backoff: Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
@@ -235,12 +428,14 @@ impl ConnectionState {
                 }
             }
             (s @ Disabled, _) => s,
+            (Failed { .. }, Disable) => Disabled,
+            (s @ Failed { .. }, _) => s,
 
             // the disable task universally disables
             (_, Disable) => Disabled,
 
             (Disconnected { mut backoff }, Connect) => {
-                match CoordinatorConnection::open(url, doc).await {
+                match CoordinatorConnection::open(endpoint, doc, shared).await {
                     Ok(conn) => ConnectionState::Connecting { conn, backoff },
                     Err(e) => handle_err!(backoff, e),
                 }
@@ -248,14 +443,15 @@ impl ConnectionState {
 
             (Disconnected { mut backoff }, Error(e)) => handle_err!(backoff, e),
 
-            // ignore sync/recv
+            // ignore sync/recv/ping
             (s @ Disconnected { .. }, Sync) => s,
-            (s @ Disconnected { .. }, Recv(_, _)) => s,
+            (s @ Disconnected { .. }, Recv(_)) => s,
+            (s @ Disconnected { .. }, Ping(_)) => s,
 
             (s @ Connecting { .. }, Connect) => s,
 
-            (Connecting { mut conn, mut backoff }, Recv(msg, buf)) => {
-                if let Err(e) = conn.handle(doc, msg, buf).await {
+            (Connecting { mut conn, mut backoff }, Recv(msg)) => {
+                if let Err(e) = conn.handle(doc, &msg).await {
                     return handle_err!(backoff, e);
                 }
 
@@ -270,6 +466,13 @@ impl ConnectionState {
                 }
             }
 
+            (Connecting { mut conn, mut backoff }, Ping(msg)) => {
+                match conn.send(msg).await {
+                    Ok(()) => Connecting { conn, backoff },
+                    Err(e) => handle_err!(backoff, e),
+                }
+            }
+
             // can't sync until we have completed the connection
             (s @ Connecting { .. }, Sync) => s,
 
@@ -279,13 +482,18 @@ impl ConnectionState {
 
             (s @ Connected { .. }, Connect) => s,
 
-            (Connected { mut conn }, Recv(msg, buf)) => {
-                match conn.handle(doc, msg, buf).await {
+            (Connected { mut conn }, Recv(msg)) => {
+                match conn.handle(doc, &msg).await {
                     Ok(()) => Connected { conn },
                     Err(e) => handle_err!(e),
                 }
             }
 
+            (Connected { mut conn }, Ping(msg)) => match conn.send(msg).await {
+                Ok(()) => Connected { conn },
+                Err(e) => handle_err!(e),
+            },
+
             (Connected { mut conn }, Sync) => match conn.sync(doc).await {
                 Ok(()) => Connected { conn },
                 Err(e) => handle_err!(e),
@@ -296,72 +504,232 @@ impl ConnectionState {
     }
 }
 
+/// if the coordinator asked us to reconnect later (usually because it's
+/// shutting down) then honor its delay rather than our usual backoff
+fn reconnect_backoff(err: &anyhow::Error) -> Option<Backoff> {
+    match err.downcast_ref::<ReplicationError>() {
+        Some(ReplicationError::ReconnectLater { retry_after_ms })
+        | Some(ReplicationError::Remote(ProtocolError::Retryable {
+            retry_after_ms: Some(retry_after_ms),
+            ..
+        })) => Some(Backoff::new(*retry_after_ms, MAX_BACKOFF_MS)),
+        _ => None,
+    }
+}
+
+/// the error the coordinator closed the connection with, if reconnecting
+/// won't help (e.g. the document doesn't exist)
+fn fatal_error(err: &anyhow::Error) -> Option<ProtocolError> {
+    match err.downcast_ref::<ReplicationError>() {
+        Some(ReplicationError::Remote(error)) if !error.is_retryable() => {
+            Some(error.clone())
+        }
+        _ => None,
+    }
+}
+
+enum Transport {
+    Socket {
+        reader: Fuse<SplitStream<WebSocket>>,
+        writer: SplitSink<WebSocket, Message>,
+    },
+    Stream(MuxStream),
+}
+
+impl Transport {
+    fn open(
+        endpoint: &Endpoint,
+        priority: Priority,
+    ) -> anyhow::Result<Transport> {
+        match endpoint {
+            Endpoint::Url(url) => {
+                log::info!("connecting to {}", url);
+                let (writer, reader) = WebSocket::open(url)?.split();
+                Ok(Transport::Socket { reader: reader.fuse(), writer })
+            }
+            Endpoint::Mux { conn, doc, reducer } => {
+                log::info!("opening a multiplexed stream for {}", doc);
+                let stream = conn.open(*doc, Some(*reducer), priority);
+                Ok(Transport::Stream(stream))
+            }
+        }
+    }
+
+    fn set_priority(&mut self, priority: Priority) {
+        if let Transport::Stream(stream) = self {
+            stream.set_priority(priority);
+        }
+    }
+
+    async fn send(&mut self, msg: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Transport::Socket { writer, .. } => {
+                Ok(writer.send(Message::Bytes(msg)).await?)
+            }
+            Transport::Stream(stream) => stream.send(msg),
+        }
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Transport::Socket { reader, .. } => {
+                match reader.select_next_some().await? {
+                    Message::Bytes(bytes) => Ok(bytes),
+                    Message::Text(text) => {
+                        bail!("received unexpected text message: {:?}", text)
+                    }
+                }
+            }
+            Transport::Stream(stream) => stream.recv().await,
+        }
+    }
+}
+
 struct CoordinatorConnection {
-    reader: Fuse<SplitStream<WebSocket>>,
-    writer: SplitSink<WebSocket, Message>,
-    protocol: ReplicationProtocol,
+    transport: Transport,
+    session: Session,
+    shared: SharedState,
+    // whether we've closed our receive window, see Shared::window_closed
+    window_closed: bool,
 }
 
 impl CoordinatorConnection {
     async fn open<D>(
-        url: &str,
+        endpoint: &Endpoint,
+        doc: &D,
+        shared: &SharedState,
+    ) -> anyhow::Result<CoordinatorConnection>
+    where
+        D: ReplicationSource,
+    {
+        let transport = Transport::open(endpoint, shared.borrow().priority)?;
+        Self::start(transport, doc, shared).await
+    }
+
+    /// start replicating doc over transport
+    async fn start<D>(
+        transport: Transport,
         doc: &D,
+        shared: &SharedState,
     ) -> anyhow::Result<CoordinatorConnection>
     where
         D: ReplicationSource,
     {
-        log::info!("connecting to {}", url);
-        let (mut writer, reader) = WebSocket::open(url)?.split();
-        let reader = reader.fuse();
-        let protocol = ReplicationProtocol::new();
+        let session = Session::new(
+            HeartbeatConfig::default(),
+            unix_timestamp_milliseconds(),
+        );
 
-        let start_msg = protocol.start(doc);
-        log::info!("sending start message: {:?}", start_msg);
-        let start_msg = bincode::serialize(&start_msg)?;
-        writer.send(Message::Bytes(start_msg)).await?;
+        let start_msg = session.start(doc)?;
+        let window_closed = shared.borrow().window_closed();
+        let mut conn = CoordinatorConnection {
+            transport,
+            session,
+            shared: shared.clone(),
+            window_closed,
+        };
+        conn.send(start_msg).await?;
+        conn.send_window().await?;
 
-        Ok(CoordinatorConnection { reader, writer, protocol })
+        Ok(conn)
     }
 
     fn initialized(&self) -> bool {
-        self.protocol.initialized()
+        self.session.initialized()
     }
 
-    async fn send(&mut self, msg: ReplicationMsg) -> anyhow::Result<()> {
-        let msg = bincode::serialize(&msg)?;
-        Ok(self.writer.send(Message::Bytes(msg)).await?)
+    async fn send(&mut self, msg: Vec<u8>) -> anyhow::Result<()> {
+        self.shared.borrow_mut().stats.sent(msg.len());
+        self.transport.send(msg).await
     }
 
-    async fn recv(
-        &mut self,
-    ) -> anyhow::Result<(ReplicationMsg, Cursor<Vec<u8>>)> {
-        let msg = self.reader.select_next_some().await?;
-        match msg {
-            Message::Bytes(bytes) => {
-                let mut buf = io::Cursor::new(bytes);
-                Ok((bincode::deserialize_from(&mut buf)?, buf))
+    /// a closed window stops the coordinator from sending us storage frames
+    async fn send_window(&mut self) -> anyhow::Result<()> {
+        let frames = if self.window_closed {
+            0
+        } else {
+            RECEIVE_WINDOW_FRAMES
+        };
+        let msg = session::encode(&ReplicationMsg::Window { frames })?;
+        self.send(msg).await
+    }
+
+    /// close or reopen the receive window if sync was paused or resumed, or
+    /// we've crossed the budget
+    async fn update_window(&mut self) -> anyhow::Result<()> {
+        let window_closed = self.shared.borrow().window_closed();
+        if window_closed != self.window_closed {
+            log::info!(
+                "{} sync from the coordinator: {:?}",
+                if window_closed { "pausing" } else { "resuming" },
+                self.shared.borrow()
+            );
+            self.window_closed = window_closed;
+            self.send_window().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_reducer_request(&mut self) -> anyhow::Result<()> {
+        let request = self.shared.borrow_mut().reducer_request.take();
+        if let Some(digest) = request {
+            let msg = ReplicationMsg::ReducerRequest { digest };
+            self.send(session::encode(&msg)?).await?;
+        }
+        Ok(())
+    }
+
+    /// wait for the next message, or for the heartbeat to need attention.
+    /// browsers don't reliably notice when a proxy silently drops an idle
+    /// websocket, so a missing heartbeat is treated as a connection error
+    async fn poll(&mut self) -> ConnectionTask {
+        loop {
+            let now = unix_timestamp_milliseconds();
+            match self.session.heartbeat(now) {
+                Ok(None) => {}
+                Ok(Some(ping)) => return ConnectionTask::Ping(ping),
+                Err(SessionError::TimedOut) => {
+                    return ConnectionTask::Error(anyhow!(
+                        "coordinator connection timed out"
+                    ))
+                }
+                Err(e) => return ConnectionTask::Error(e.into()),
             }
-            Message::Text(text) => {
-                bail!("received unexpected text message: {:?}", text)
+
+            let wait_ms = (self.session.deadline() - now).max(0) as u32;
+            let recv = self.recv();
+            pin_mut!(recv);
+            match future::select(recv, TimeoutFuture::new(wait_ms)).await {
+                Either::Left((Ok(msg), _)) => return ConnectionTask::Recv(msg),
+                Either::Left((Err(e), _)) => return ConnectionTask::Error(e),
+                Either::Right(_) => continue,
             }
         }
     }
 
-    async fn handle<D>(
-        &mut self,
-        doc: &mut D,
-        msg: ReplicationMsg,
-        mut buf: Cursor<Vec<u8>>,
-    ) -> anyhow::Result<()>
+    async fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+        let bytes = self.transport.recv().await?;
+        self.shared.borrow_mut().stats.received(bytes.len());
+        Ok(bytes)
+    }
+
+    async fn handle<D>(&mut self, doc: &mut D, msg: &[u8]) -> anyhow::Result<()>
     where
         D: ReplicationDestination,
     {
-        log::info!("received message: {:?}", msg);
-        if let Some(resp) = self.protocol.handle(doc, msg, &mut buf)? {
-            log::info!("sending response: {:?}", resp);
+        let now = unix_timestamp_milliseconds();
+        let resp = self.session.receive(doc, msg, now).map_err(unwrap_err)?;
+        if let Some(progress_ms) = self.session.last_progress_ms() {
+            self.shared.borrow_mut().last_sync_ms = Some(progress_ms);
+        }
+        if self.session.caught_up(doc).map_err(unwrap_err)? {
+            self.shared.borrow_mut().caught_up_ms = Some(now);
+        }
+        if let Some(resp) = resp {
             self.send(resp).await?;
         }
-        Ok(())
+        self.update_window().await?;
+        self.send_reducer_request().await
     }
 
     async fn sync<'a, R, D>(&mut self, doc: &'a mut D) -> anyhow::Result<()>
@@ -369,15 +737,160 @@ impl CoordinatorConnection {
         R: io::Read,
         D: ReplicationSource<Reader<'a> = R>,
     {
-        while let Some((msg, mut reader)) = self.protocol.sync(doc)? {
-            log::info!("sending message: {:?}", msg);
+        if !self.shared.borrow().sync_paused {
+            for frame in self.session.sync(doc).map_err(unwrap_err)? {
+                self.send(frame).await?;
+            }
+        }
+        self.update_window().await?;
+        self.send_reducer_request().await
+    }
+}
+
+/// surface protocol errors directly, so that fatal_error and
+/// reconnect_backoff can find them
+fn unwrap_err(err: SessionError) -> anyhow::Error {
+    match err {
+        SessionError::Replication(err) => err.into(),
+        err => err.into(),
+    }
+}
 
-            let mut buf = io::Cursor::new(vec![]);
-            bincode::serialize_into(&mut buf, &msg)?;
-            io::copy(&mut reader, &mut buf)?;
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use sqlsync::{
+        replication::{HeartbeatConfig, ReplicationMsg},
+        session::Session,
+        Journal, JournalId, LsnRange, MemoryJournal,
+    };
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{
+        CoordinatorConnection, SharedState, Transport, RECEIVE_WINDOW_FRAMES,
+    };
+    use crate::mux::testing::{self, Peer};
+
+    fn journal() -> MemoryJournal {
+        MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap()
+    }
 
-            self.writer.send(Message::Bytes(buf.into_inner())).await?;
+    /// stands in for the coordinator, replicating the document's timeline
+    /// into journal
+    struct Remote {
+        journal: MemoryJournal,
+        session: Session,
+        peer: Peer,
+    }
+
+    impl Remote {
+        async fn connect(
+            doc: &MemoryJournal,
+            shared: &SharedState,
+        ) -> (CoordinatorConnection, Remote) {
+            let (stream, peer) = testing::stream();
+            let conn = CoordinatorConnection::start(
+                Transport::Stream(stream),
+                doc,
+                shared,
+            )
+            .await
+            .unwrap();
+            let remote = Remote {
+                journal: MemoryJournal::open(doc.id()).unwrap(),
+                session: Session::new(HeartbeatConfig::default(), 0),
+                peer,
+            };
+            (conn, remote)
         }
-        Ok(())
+
+        /// answer conn until it has nothing more to send, returning what it
+        /// sent
+        async fn exchange(
+            &mut self,
+            conn: &mut CoordinatorConnection,
+            doc: &mut MemoryJournal,
+        ) -> Vec<ReplicationMsg> {
+            let mut sent = vec![];
+            loop {
+                let msgs = self.peer.sent();
+                if msgs.is_empty() {
+                    return sent;
+                }
+                for msg in msgs {
+                    sent.push(bincode::deserialize(&msg).unwrap());
+                    let reply = self
+                        .session
+                        .receive(&mut self.journal, &msg, 0)
+                        .unwrap();
+                    if let Some(reply) = reply {
+                        self.peer.deliver(reply);
+                        let reply = conn.recv().await.unwrap();
+                        conn.handle(doc, &reply).await.unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    fn windows(sent: &[ReplicationMsg]) -> Vec<u32> {
+        sent.iter()
+            .filter_map(|msg| match msg {
+                ReplicationMsg::Window { frames } => Some(*frames),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn frames(sent: &[ReplicationMsg]) -> usize {
+        sent.iter()
+            .filter(|msg| matches!(msg, ReplicationMsg::Frame { .. }))
+            .count()
+    }
+
+    #[wasm_bindgen_test]
+    async fn paused_sync_holds_back_mutations_and_storage() {
+        let shared = SharedState::default();
+        let mut doc = journal();
+        doc.append(&b"a"[..]).unwrap();
+        let (mut conn, mut remote) = Remote::connect(&doc, &shared).await;
+        let sent = remote.exchange(&mut conn, &mut doc).await;
+        assert_eq!(windows(&sent), [RECEIVE_WINDOW_FRAMES]);
+
+        shared.borrow_mut().sync_paused = true;
+        conn.sync(&mut doc).await.unwrap();
+        let sent = remote.exchange(&mut conn, &mut doc).await;
+        assert_eq!(windows(&sent), [0]);
+        assert_eq!(frames(&sent), 0);
+
+        // the connection stays open, but mutations aren't sent
+        doc.append(&b"b"[..]).unwrap();
+        conn.sync(&mut doc).await.unwrap();
+        assert!(remote.exchange(&mut conn, &mut doc).await.is_empty());
+        assert_eq!(remote.journal.range(), LsnRange::empty());
+
+        // resuming reopens the window and sends what was mutated meanwhile
+        shared.borrow_mut().sync_paused = false;
+        conn.sync(&mut doc).await.unwrap();
+        let sent = remote.exchange(&mut conn, &mut doc).await;
+        assert_eq!(windows(&sent), [RECEIVE_WINDOW_FRAMES]);
+        assert_eq!(frames(&sent), 2);
+        assert_eq!(remote.journal.range(), LsnRange::new(0, 1));
+    }
+
+    #[wasm_bindgen_test]
+    async fn acks_clear_unacked_mutations() {
+        let shared = SharedState::default();
+        let mut doc = journal();
+        let (mut conn, mut remote) = Remote::connect(&doc, &shared).await;
+        remote.exchange(&mut conn, &mut doc).await;
+        // the coordinator's range counts as hearing from it
+        let connected_ms = shared.borrow().last_sync_ms.unwrap();
+
+        doc.append(&b"a"[..]).unwrap();
+        conn.sync(&mut doc).await.unwrap();
+        assert_eq!(conn.session.unacked_frames(), 1);
+        remote.exchange(&mut conn, &mut doc).await;
+        assert_eq!(conn.session.unacked_frames(), 0);
+        assert!(shared.borrow().last_sync_ms.unwrap() >= connected_ms);
     }
 }
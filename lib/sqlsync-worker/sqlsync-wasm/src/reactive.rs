@@ -104,6 +104,10 @@ impl<S: Signal> ReactiveQueries<S> {
         self.queries.retain(|_, tracker| !tracker.ports.is_empty());
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+
     /// next_dirty_query returns the first dirty query, and sets
     /// self.has_dirty_queries if there are more
     pub fn next_dirty_query(&mut self) -> Option<&mut QueryTracker> {
@@ -0,0 +1,251 @@
+//! SpillPool hands out OPFS files for sqlsync to move temp files into once
+//! they outgrow memory, see sqlsync::set_spill_files. Temp files are written
+//! synchronously, which OPFS only allows through sync access handles, and
+//! those can only be opened asynchronously: so the pool opens a few ahead of
+//! time and replaces each one as it's taken. A file goes back into the pool,
+//! emptied, once sqlsync drops its temp file.
+//!
+//! Sync access handles only exist in dedicated workers. In a shared worker
+//! (or before the pool has opened) there are no spill files, and writes
+//! which would grow a temp file past its memory limit fail with SQLITE_FULL.
+
+use std::{cell::RefCell, io, rc::Rc};
+
+use js_sys::{Object, Promise, Reflect};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+/// the OPFS directory spill files are created in
+const SPILL_DIR: &str = "sqlsync-spill";
+
+/// how many spill files the pool keeps open ahead of time
+const SPARE_FILES: usize = 4;
+
+#[wasm_bindgen]
+extern "C" {
+    type StorageManager;
+
+    #[wasm_bindgen(method, catch, js_name = "getDirectory")]
+    fn get_directory(this: &StorageManager) -> Result<Promise, JsValue>;
+
+    type DirectoryHandle;
+
+    #[wasm_bindgen(method, catch, js_name = "getDirectoryHandle")]
+    fn get_directory_handle(
+        this: &DirectoryHandle,
+        name: &str,
+        options: &Object,
+    ) -> Result<Promise, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = "getFileHandle")]
+    fn get_file_handle(
+        this: &DirectoryHandle,
+        name: &str,
+        options: &Object,
+    ) -> Result<Promise, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = "removeEntry")]
+    fn remove_entry(
+        this: &DirectoryHandle,
+        name: &str,
+    ) -> Result<Promise, JsValue>;
+
+    type FileHandle;
+
+    #[wasm_bindgen(method, catch, js_name = "createSyncAccessHandle")]
+    fn create_sync_access_handle(this: &FileHandle)
+        -> Result<Promise, JsValue>;
+
+    type SyncAccessHandle;
+
+    #[wasm_bindgen(method, catch, js_name = "getSize")]
+    fn get_size(this: &SyncAccessHandle) -> Result<f64, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn truncate(this: &SyncAccessHandle, size: f64) -> Result<(), JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn read(
+        this: &SyncAccessHandle,
+        buf: &mut [u8],
+        options: &Object,
+    ) -> Result<f64, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn write(
+        this: &SyncAccessHandle,
+        buf: &[u8],
+        options: &Object,
+    ) -> Result<f64, JsValue>;
+
+    #[wasm_bindgen(method)]
+    fn close(this: &SyncAccessHandle);
+}
+
+fn options(key: &str, value: JsValue) -> Result<Object, JsValue> {
+    let options = Object::new();
+    Reflect::set(&options, &key.into(), &value)?;
+    Ok(options)
+}
+
+fn js_err(err: JsValue) -> io::Error {
+    io::Error::other(format!("spill file failed: {:?}", err))
+}
+
+/// open the pool and register it with sqlsync, if this worker has OPFS
+pub async fn install() {
+    match SpillPool::open().await {
+        Ok(pool) => sqlsync::set_spill_files(move || {
+            pool.take()
+                .map(|file| Box::new(file) as sqlsync::BoxedSpillFile)
+        }),
+        Err(err) => log::info!(
+            "spill files are unavailable, temp files are limited to memory: {:?}",
+            err
+        ),
+    }
+}
+
+struct Spilled {
+    name: String,
+    handle: SyncAccessHandle,
+}
+
+struct Pool {
+    dir: DirectoryHandle,
+    spare: RefCell<Vec<Spilled>>,
+}
+
+#[derive(Clone)]
+pub struct SpillPool(Rc<Pool>);
+
+impl SpillPool {
+    pub async fn open() -> Result<Self, JsValue> {
+        let navigator = Reflect::get(&js_sys::global(), &"navigator".into())?;
+        let storage: StorageManager =
+            Reflect::get(&navigator, &"storage".into())?.unchecked_into();
+        let root: DirectoryHandle = JsFuture::from(storage.get_directory()?)
+            .await?
+            .unchecked_into();
+        let create = options("create", true.into())?;
+        let dir =
+            JsFuture::from(root.get_directory_handle(SPILL_DIR, &create)?)
+                .await?
+                .unchecked_into();
+
+        let pool = SpillPool(Rc::new(Pool { dir, spare: RefCell::default() }));
+        // in a shared worker this is where opening fails
+        for _ in 0..SPARE_FILES {
+            let file = pool.open_file().await?;
+            pool.0.spare.borrow_mut().push(file);
+        }
+        Ok(pool)
+    }
+
+    async fn open_file(&self) -> Result<Spilled, JsValue> {
+        let name = format!("{:016x}.tmp", rand::random::<u64>());
+        let create = options("create", true.into())?;
+        let file: FileHandle =
+            JsFuture::from(self.0.dir.get_file_handle(&name, &create)?)
+                .await?
+                .unchecked_into();
+        let handle = JsFuture::from(file.create_sync_access_handle()?)
+            .await?
+            .unchecked_into();
+        Ok(Spilled { name, handle })
+    }
+
+    /// take a spare file, and start opening its replacement. returns None
+    /// if every spare file is taken and none of their replacements have
+    /// opened yet
+    pub fn take(&self) -> Option<SpillFile> {
+        let file = self.0.spare.borrow_mut().pop()?;
+        let pool = self.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            match pool.open_file().await {
+                Ok(file) => pool.put(file),
+                Err(err) => {
+                    log::warn!("failed to open a spill file: {:?}", err)
+                }
+            }
+        });
+        Some(SpillFile { file: Some(file), pool: self.clone() })
+    }
+
+    /// return a file to the pool, or close and remove it if the pool is full
+    fn put(&self, file: Spilled) {
+        let mut spare = self.0.spare.borrow_mut();
+        if spare.len() < SPARE_FILES && file.handle.truncate(0.0).is_ok() {
+            spare.push(file);
+            return;
+        }
+        file.handle.close();
+        if let Ok(removed) = self.0.dir.remove_entry(&file.name) {
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Err(err) = JsFuture::from(removed).await {
+                    log::warn!("failed to remove a spill file: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+pub struct SpillFile {
+    // only None once dropped
+    file: Option<Spilled>,
+    pool: SpillPool,
+}
+
+impl SpillFile {
+    fn handle(&self) -> &SyncAccessHandle {
+        &self.file.as_ref().expect("spill file is open").handle
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            self.pool.put(file);
+        }
+    }
+}
+
+impl sqlsync::SpillFile for SpillFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.handle().get_size().map_err(js_err)? as u64)
+    }
+
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        self.handle().truncate(size as f64).map_err(js_err)
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<()> {
+        let at = options("at", (pos as f64).into()).map_err(js_err)?;
+        let written = self.handle().write(buf, &at).map_err(js_err)?;
+        if (written as usize) < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("spill file wrote {} of {} bytes", written, buf.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let at = options("at", (pos as f64).into()).map_err(js_err)?;
+        Ok(self.handle().read(buf, &at).map_err(js_err)? as usize)
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::SpillPool;
+
+    #[wasm_bindgen_test]
+    async fn spill_files_need_opfs() {
+        // the tests run in node, which has no OPFS
+        assert!(SpillPool::open().await.is_err());
+    }
+}
@@ -1,10 +1,13 @@
+use js_sys::{
+    Array, BigInt, Float64Array, Object, Reflect, Uint32Array, Uint8Array,
+};
 use serde::{de::Visitor, Deserialize, Serialize};
 use sqlsync::sqlite::{
     self,
     types::{ToSqlOutput, ValueRef},
-    ToSql,
+    Row, ToSql,
 };
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
 #[derive(Debug, Clone)]
 pub enum SqlValue {
@@ -30,6 +33,13 @@ export type SqlValue =
     | string
     | bigint
     | Uint8Array;
+
+export interface ColumnData {
+    kinds: Uint8Array;
+    numbers: Float64Array;
+    offsets: Uint32Array;
+    bytes: Uint8Array;
+}
 "#;
 
 impl ToSql for SqlValue {
@@ -56,6 +66,173 @@ impl From<ValueRef<'_>> for SqlValue {
     }
 }
 
+/// the largest integer a JS number represents exactly
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// convert a sqlite value to JS: NULL becomes null (rather than undefined),
+/// and integers which don't fit in a number become a BigInt
+pub fn value_to_js(value: ValueRef<'_>) -> JsValue {
+    match value {
+        ValueRef::Null => JsValue::NULL,
+        ValueRef::Integer(v) if v.unsigned_abs() <= MAX_SAFE_INTEGER => {
+            JsValue::from_f64(v as f64)
+        }
+        ValueRef::Integer(v) => BigInt::from(v).into(),
+        ValueRef::Real(v) => JsValue::from_f64(v),
+        ValueRef::Text(v) => JsValue::from_str(&String::from_utf8_lossy(v)),
+        ValueRef::Blob(v) => Uint8Array::from(v).into(),
+    }
+}
+
+/// RowSet builds query results directly as an array of JS objects keyed by
+/// column name, skipping the intermediate SqlValues and a second pass in JS
+pub struct RowSet {
+    keys: Vec<JsValue>,
+    rows: Array,
+}
+
+impl RowSet {
+    pub fn new(columns: &[String]) -> Self {
+        Self {
+            keys: columns.iter().map(|c| JsValue::from_str(c)).collect(),
+            rows: Array::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: &Row<'_>) -> sqlite::Result<()> {
+        let obj = Object::new();
+        for (i, key) in self.keys.iter().enumerate() {
+            let value = value_to_js(row.get_ref(i)?);
+            Reflect::set(&obj, key, &value)
+                .expect("setting a property on a plain object can't fail");
+        }
+        self.rows.push(&obj);
+        Ok(())
+    }
+
+    pub fn into_js(self) -> JsValue {
+        self.rows.into()
+    }
+}
+
+/// the kind of each value in ColumnData.kinds, exported to js for the
+/// client to decode them with
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Null = 0,
+    /// in numbers
+    Number = 1,
+    /// an integer too large for a number, as decimal text in bytes
+    BigInt = 2,
+    /// utf8 in bytes
+    Text = 3,
+    Blob = 4,
+}
+
+/// ColumnSet builds query results column by column into typed arrays, which
+/// are transferred to the tab rather than structured cloned, so a large
+/// result costs the tab's main thread next to nothing to receive. sqlite
+/// columns aren't typed, so each column records every value's kind, a
+/// number for each row (NaN unless the value is a number), and the bytes of
+/// its text, blobs and big integers; row i's bytes are
+/// bytes[offsets[i]..offsets[i + 1]]
+pub struct ColumnSet {
+    columns: Vec<ColumnData>,
+    rows: usize,
+}
+
+#[derive(Default)]
+struct ColumnData {
+    kinds: Vec<u8>,
+    numbers: Vec<f64>,
+    offsets: Vec<u32>,
+    bytes: Vec<u8>,
+}
+
+impl ColumnData {
+    fn push(&mut self, value: ValueRef<'_>) {
+        if self.offsets.is_empty() {
+            self.offsets.push(0);
+        }
+        let (kind, number) = match value {
+            ValueRef::Null => (ValueKind::Null, f64::NAN),
+            ValueRef::Integer(v) if v.unsigned_abs() <= MAX_SAFE_INTEGER => {
+                (ValueKind::Number, v as f64)
+            }
+            ValueRef::Integer(v) => {
+                self.bytes.extend_from_slice(v.to_string().as_bytes());
+                (ValueKind::BigInt, f64::NAN)
+            }
+            ValueRef::Real(v) => (ValueKind::Number, v),
+            ValueRef::Text(v) => {
+                self.bytes.extend_from_slice(v);
+                (ValueKind::Text, f64::NAN)
+            }
+            ValueRef::Blob(v) => {
+                self.bytes.extend_from_slice(v);
+                (ValueKind::Blob, f64::NAN)
+            }
+        };
+        self.kinds.push(kind as u8);
+        self.numbers.push(number);
+        self.offsets.push(self.bytes.len() as u32);
+    }
+
+    fn into_js(mut self) -> JsValue {
+        if self.offsets.is_empty() {
+            self.offsets.push(0);
+        }
+        let obj = Object::new();
+        let fields: [(&str, JsValue); 4] = [
+            ("kinds", Uint8Array::from(&self.kinds[..]).into()),
+            ("numbers", Float64Array::from(&self.numbers[..]).into()),
+            ("offsets", Uint32Array::from(&self.offsets[..]).into()),
+            ("bytes", Uint8Array::from(&self.bytes[..]).into()),
+        ];
+        for (key, value) in fields {
+            Reflect::set(&obj, &JsValue::from_str(key), &value)
+                .expect("setting a property on a plain object can't fail");
+        }
+        obj.into()
+    }
+}
+
+impl ColumnSet {
+    pub fn new(columns: usize) -> Self {
+        Self {
+            columns: (0..columns).map(|_| ColumnData::default()).collect(),
+            rows: 0,
+        }
+    }
+
+    pub fn push(&mut self, row: &Row<'_>) -> sqlite::Result<()> {
+        for (i, column) in self.columns.iter_mut().enumerate() {
+            column.push(row.get_ref(i)?);
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// an array with each column's ColumnData. the typed arrays are copied
+    /// out of wasm memory, so they can be transferred
+    pub fn into_js(self) -> JsValue {
+        let out = Array::new();
+        for column in self.columns {
+            out.push(&column.into_js());
+        }
+        out.into()
+    }
+}
+
 impl Serialize for SqlValue {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -145,3 +322,166 @@ impl<'de> Deserialize<'de> for SqlValue {
         deserializer.deserialize_any(SqlValueVisitor)
     }
 }
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use js_sys::{
+        Array, BigInt, Float64Array, Reflect, Uint32Array, Uint8Array,
+    };
+    use sqlsync::sqlite::{types::ValueRef, Connection};
+    use wasm_bindgen::JsValue;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{
+        value_to_js, ColumnData, ColumnSet, RowSet, ValueKind, MAX_SAFE_INTEGER,
+    };
+
+    fn get(obj: &JsValue, key: &str) -> JsValue {
+        Reflect::get(obj, &JsValue::from_str(key)).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn integers_outside_the_safe_range_are_bigints() {
+        let safe = MAX_SAFE_INTEGER as i64;
+        for v in [safe, -safe] {
+            assert_eq!(
+                value_to_js(ValueRef::Integer(v)).as_f64(),
+                Some(v as f64)
+            );
+        }
+        for v in [safe + 1, -safe - 1, i64::MAX, i64::MIN] {
+            let js = value_to_js(ValueRef::Integer(v));
+            assert_eq!(js, JsValue::from(BigInt::from(v)));
+        }
+        assert!(value_to_js(ValueRef::Null).is_null());
+    }
+
+    #[wasm_bindgen_test]
+    fn rows_are_objects_keyed_by_column() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "select 1 as n, 2.5 as r, null as z, 9007199254740993 as big,
+                    'hi' as s, x'00ff' as b
+                union all select 2, null, null, null, null, null",
+            )
+            .unwrap();
+        let columns: Vec<String> =
+            stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+        let mut rows = stmt.query([]).unwrap();
+        let mut set = RowSet::new(&columns);
+        while let Some(row) = rows.next().unwrap() {
+            set.push(row).unwrap();
+        }
+
+        let rows = Array::from(&set.into_js());
+        assert_eq!(rows.length(), 2);
+        let row = rows.get(0);
+        assert_eq!(get(&row, "n").as_f64(), Some(1.0));
+        assert_eq!(get(&row, "r").as_f64(), Some(2.5));
+        // nulls are null rather than undefined, so the key is present
+        assert!(get(&row, "z").is_null());
+        assert!(Reflect::has(&row, &JsValue::from_str("z")).unwrap());
+        assert_eq!(
+            get(&row, "big"),
+            JsValue::from(BigInt::from(9007199254740993i64))
+        );
+        assert_eq!(get(&row, "s").as_string().as_deref(), Some("hi"));
+        assert_eq!(Uint8Array::from(get(&row, "b")).to_vec(), vec![0x00, 0xff]);
+
+        let row = rows.get(1);
+        assert_eq!(get(&row, "n").as_f64(), Some(2.0));
+        assert!(get(&row, "b").is_null());
+    }
+
+    #[wasm_bindgen_test]
+    fn column_data_records_each_value() {
+        let big = MAX_SAFE_INTEGER as i64 + 1;
+        let mut column = ColumnData::default();
+        for value in [
+            ValueRef::Integer(7),
+            ValueRef::Null,
+            ValueRef::Integer(-big),
+            ValueRef::Real(0.5),
+            ValueRef::Text(b"hi"),
+            ValueRef::Blob(&[0, 255]),
+        ] {
+            column.push(value);
+        }
+
+        let kinds = [
+            ValueKind::Number,
+            ValueKind::Null,
+            ValueKind::BigInt,
+            ValueKind::Number,
+            ValueKind::Text,
+            ValueKind::Blob,
+        ];
+        assert_eq!(column.kinds, kinds.map(|kind| kind as u8));
+        // only numbers have a number
+        assert_eq!(column.numbers[0], 7.0);
+        assert_eq!(column.numbers[3], 0.5);
+        for i in [1, 2, 4, 5] {
+            assert!(column.numbers[i].is_nan());
+        }
+        // big integers are their decimal text
+        let big = (-big).to_string();
+        let after_big = big.len() as u32;
+        assert_eq!(
+            column.offsets,
+            [0, 0, 0, after_big, after_big, after_big + 2, after_big + 4]
+        );
+        let mut bytes = big.into_bytes();
+        bytes.extend_from_slice(b"hi\x00\xff");
+        assert_eq!(column.bytes, bytes);
+    }
+
+    #[wasm_bindgen_test]
+    fn columns_are_typed_arrays() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "select 1 as n, 'a' as s union all select null, 'bc'
+                union all select 2.5, null",
+            )
+            .unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        let mut set = ColumnSet::new(2);
+        assert!(set.is_empty());
+        while let Some(row) = rows.next().unwrap() {
+            set.push(row).unwrap();
+        }
+        assert_eq!(set.len(), 3);
+
+        let columns = Array::from(&set.into_js());
+        assert_eq!(columns.length(), 2);
+        let n = columns.get(0);
+        assert_eq!(
+            Uint8Array::from(get(&n, "kinds")).to_vec(),
+            [ValueKind::Number, ValueKind::Null, ValueKind::Number]
+                .map(|kind| kind as u8)
+        );
+        let numbers = Float64Array::from(get(&n, "numbers")).to_vec();
+        assert_eq!((numbers[0], numbers[2]), (1.0, 2.5));
+        assert!(numbers[1].is_nan());
+        assert_eq!(
+            Uint32Array::from(get(&n, "offsets")).to_vec(),
+            [0, 0, 0, 0]
+        );
+
+        let s = columns.get(1);
+        assert_eq!(
+            Uint32Array::from(get(&s, "offsets")).to_vec(),
+            [0, 1, 3, 3]
+        );
+        assert_eq!(Uint8Array::from(get(&s, "bytes")).to_vec(), b"abc");
+    }
+
+    #[wasm_bindgen_test]
+    fn empty_columns_still_have_an_offset() {
+        let columns = Array::from(&ColumnSet::new(1).into_js());
+        let column = columns.get(0);
+        assert_eq!(Uint8Array::from(get(&column, "kinds")).length(), 0);
+        assert_eq!(Uint32Array::from(get(&column, "offsets")).to_vec(), [0]);
+    }
+}
@@ -7,11 +7,17 @@ use gloo::{
 use js_sys::{Reflect, Uint8Array};
 use log::Level;
 use sha2::{Digest, Sha256};
-use sqlsync::Reducer;
+use sqlsync::{
+    logging::{LogRecord, LogSink, LogSource},
+    snapshot::Checkpoint,
+    Deserializable, ModuleDigest, Reducer,
+};
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::console;
 
+use crate::error::ErrorInfo;
+
 pub fn set_panic_hook() {
     // For more details see
     // https://github.com/rustwasm/console_error_panic_hook#readme
@@ -20,31 +26,71 @@ pub fn set_panic_hook() {
 
 pub struct ConsoleLogger;
 
+fn console_log_for_level(level: Level) -> fn(&JsValue) {
+    match level {
+        Level::Error => console::error_1,
+        Level::Warn => console::warn_1,
+        Level::Info => console::info_1,
+        Level::Debug => console::log_1,
+        Level::Trace => console::debug_1,
+    }
+}
+
 impl log::Log for ConsoleLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         metadata.level() <= log::Level::Info
     }
 
     fn log(&self, record: &log::Record) {
-        let console_log = match record.level() {
-            Level::Error => console::error_1,
-            Level::Warn => console::warn_1,
-            Level::Info => console::info_1,
-            Level::Debug => console::log_1,
-            Level::Trace => console::debug_1,
-        };
-
+        let console_log = console_log_for_level(record.level());
         console_log(&format!("sqlsync: {}", record.args()).into());
     }
 
     fn flush(&self) {}
 }
 
+impl LogSink for ConsoleLogger {
+    fn enabled(&self, level: Level, _source: LogSource) -> bool {
+        level <= log::Level::Info
+    }
+
+    fn log(&self, record: &LogRecord<'_>) {
+        let prefix = match record.source {
+            LogSource::Host => "sqlsync",
+            LogSource::Reducer => "sqlsync reducer",
+        };
+        let mut msg = prefix.to_owned();
+        if let Some(doc_id) = record.doc_id {
+            msg.push_str(&format!(" [{}]", doc_id));
+        }
+        if let Some(mutation) = record.mutation {
+            msg.push_str(&format!(" [{}]", mutation));
+        }
+        msg.push_str(&format!(": {}", record.args));
+
+        let console_log = console_log_for_level(record.level);
+        console_log(&msg.into());
+    }
+}
+
 pub type WasmResult<T> = Result<T, WasmError>;
 
 #[derive(Debug)]
 pub struct WasmError(pub anyhow::Error);
 
+impl WasmError {
+    /// true if a lock held by the document's other connection caused this
+    /// error, so the request may succeed if retried
+    pub fn is_busy(&self) -> bool {
+        if let Some(err) = self.0.downcast_ref::<sqlsync::error::Error>() {
+            return err.is_busy();
+        }
+        self.0
+            .downcast_ref::<sqlsync::sqlite::Error>()
+            .is_some_and(sqlsync::is_busy)
+    }
+}
+
 impl Display for WasmError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.0, f)
@@ -60,9 +106,15 @@ impl From<JsValue> for WasmError {
     }
 }
 
+/// errors thrown into js are Errors with the ErrorInfo as their info
 impl From<WasmError> for JsValue {
     fn from(value: WasmError) -> Self {
-        JsValue::from_str(&format!("{}", value))
+        let info = ErrorInfo::from(&value);
+        let err = js_sys::Error::new(&info.message);
+        if let Ok(info) = serde_wasm_bindgen::to_value(&info) {
+            let _ = Reflect::set(&err, &"info".into(), &info);
+        }
+        err.into()
     }
 }
 
@@ -95,6 +147,8 @@ impl_from_error!(
     io::Error,
     sqlsync::error::Error,
     sqlsync::sqlite::Error,
+    sqlsync::arrow::EncodeError,
+    sqlsync::export::ExportError,
     sqlsync::JournalError,
     sqlsync::replication::ReplicationError,
     sqlsync::JournalIdParseError,
@@ -105,9 +159,29 @@ impl_from_error!(
     futures::channel::mpsc::SendError,
 );
 
+/// fetch the checkpoint the coordinator has published for a document; None
+/// if it hasn't published one yet
+pub async fn fetch_checkpoint(
+    checkpoint_url: &str,
+) -> Result<Option<Checkpoint>, WasmError> {
+    let resp = Request::get(checkpoint_url).send().await?;
+    if resp.status() == 404 {
+        return Ok(None);
+    }
+    if !resp.ok() {
+        return Err(WasmError(anyhow!(
+            "failed to load checkpoint; response has status: {} {}",
+            resp.status(),
+            resp.status_text()
+        )));
+    }
+    let data = resp.binary().await?;
+    Ok(Some(Checkpoint::deserialize_from(data)?))
+}
+
 pub async fn fetch_reducer(
     reducer_url: &str,
-) -> Result<(Reducer, Vec<u8>), WasmError> {
+) -> Result<(Reducer, ModuleDigest), WasmError> {
     let resp = Request::get(reducer_url).send().await?;
     if !resp.ok() {
         return Err(WasmError(anyhow!(
@@ -127,10 +201,10 @@ pub async fn fetch_reducer(
         .expect("crypto not found")
         .subtle();
 
-    let digest: Vec<u8> = if subtle.is_undefined() {
+    let digest: ModuleDigest = if subtle.is_undefined() {
         let mut hasher = Sha256::new();
         hasher.update(&reducer_wasm_bytes);
-        hasher.finalize().to_vec()
+        hasher.finalize().into()
     } else {
         // sha256 sum the data
         // TODO: it would be much better to stream the data through the hash function
@@ -140,7 +214,10 @@ pub async fn fetch_reducer(
             &mut reducer_wasm_bytes,
         )?)
         .await?;
-        Uint8Array::new(&digest).to_vec()
+        Uint8Array::new(&digest)
+            .to_vec()
+            .try_into()
+            .expect("sha256 digests are 32 bytes")
     };
 
     let reducer = Reducer::new(reducer_wasm_bytes.as_slice())?;
@@ -148,6 +225,57 @@ pub async fn fetch_reducer(
     Ok((reducer, digest))
 }
 
+// reducers downloaded from a coordinator are kept in the browser's cache
+// storage, keyed by their digest, so each is only downloaded once
+const REDUCER_CACHE: &str = "sqlsync-reducers";
+
+fn reducer_cache_key(digest: &ModuleDigest) -> String {
+    format!(
+        "/sqlsync/reducers/{}.wasm",
+        bs58::encode(digest).into_string()
+    )
+}
+
+async fn open_reducer_cache() -> Result<Option<web_sys::Cache>, WasmError> {
+    let caches = Reflect::get(&js_sys::global(), &"caches".into())?;
+    if caches.is_undefined() {
+        return Ok(None);
+    }
+    let caches = caches.dyn_into::<web_sys::CacheStorage>()?;
+    let cache = JsFuture::from(caches.open(REDUCER_CACHE)).await?;
+    Ok(Some(cache.dyn_into()?))
+}
+
+pub async fn cached_reducer(
+    digest: &ModuleDigest,
+) -> Result<Option<Vec<u8>>, WasmError> {
+    let Some(cache) = open_reducer_cache().await? else {
+        return Ok(None);
+    };
+    let resp = JsFuture::from(cache.match_with_str(&reducer_cache_key(digest)))
+        .await?;
+    if resp.is_undefined() {
+        return Ok(None);
+    }
+    let resp = resp.dyn_into::<web_sys::Response>()?;
+    let data = JsFuture::from(resp.array_buffer()?).await?;
+    Ok(Some(Uint8Array::new(&data).to_vec()))
+}
+
+pub async fn cache_reducer(
+    digest: &ModuleDigest,
+    wasm: &[u8],
+) -> Result<(), WasmError> {
+    let Some(cache) = open_reducer_cache().await? else {
+        return Ok(());
+    };
+    let body = Uint8Array::from(wasm);
+    let resp = web_sys::Response::new_with_opt_buffer_source(Some(&body))?;
+    JsFuture::from(cache.put_with_str(&reducer_cache_key(digest), &resp))
+        .await?;
+    Ok(())
+}
+
 pub struct Backoff {
     current_ms: u32,
     max_ms: u32,
@@ -1,48 +1,57 @@
 import type {
+  ColumnData,
   ConnectionStatus,
   DocEvent,
   DocId,
   DocReply,
   DocRequest,
+  ErrorCode,
+  ErrorInfo,
+  ExportFormat,
+  ExportSource,
   HandlerId,
-  HostToWorkerMsg,
+  MutationReceipt,
+  NetworkStats,
+  OpenOptions,
   QueryKey,
+  ReducerTrapInfo,
+  ResultFormat,
   SqlValue,
+  SyncHealth,
+  SyncPriority,
+  WorkerRequest,
   WorkerToHostMsg,
 } from "../sqlsync-wasm/pkg/sqlsync_wasm";
+import { ProtocolVersion, ValueKind } from "../sqlsync-wasm/pkg/sqlsync_wasm.js";
 
 export * from "./journal-id";
 export type {
-  HostToWorkerMsg,
+  WorkerRequest,
   DocRequest,
   WorkerToHostMsg,
   DocReply,
   DocEvent,
   DocId,
+  ErrorCode,
+  ErrorInfo,
   SqlValue,
+  ColumnData,
+  ResultFormat,
+  ExportFormat,
+  ExportSource,
   HandlerId,
   QueryKey,
   ConnectionStatus,
+  MutationReceipt,
+  NetworkStats,
+  OpenOptions,
+  ReducerTrapInfo,
+  SyncHealth,
+  SyncPriority,
 };
 
-export interface BootRequest {
-  tag: "Boot";
-  handlerId: HandlerId;
-  coordinatorUrl?: string;
-  wasmUrl: string;
-}
+// generated from sqlsync-wasm, so they always match this build's worker
+export { ProtocolVersion, ValueKind };
 
-export interface CloseRequest {
-  tag: "Close";
-  handlerId: HandlerId;
-}
-
-export type WorkerRequest =
-  | {
-      tag: "Doc";
-      handlerId: HandlerId;
-      docId: DocId;
-      req: DocRequest;
-    }
-  | BootRequest
-  | CloseRequest;
+// a worker only boots for tabs speaking its version
+export const PROTOCOL_VERSION: number = ProtocolVersion.Current;
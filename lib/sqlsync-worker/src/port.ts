@@ -14,8 +14,8 @@ export class Port {
     this.port = new WeakRef(port);
   }
 
-  postMessage(msg: WorkerToHostMsg) {
-    this.port.deref()?.postMessage(msg);
+  postMessage(msg: WorkerToHostMsg, transfer: Transferable[] = []) {
+    this.port.deref()?.postMessage(msg, transfer);
   }
 
   close() {
@@ -50,7 +50,7 @@ export class PortRouter {
     console.log("sqlsync: sending message", msg, "to", portId);
     const port = this.#ports.get(portId);
     if (port) {
-      port.postMessage(msg);
+      port.postMessage(msg, transferables(msg));
     } else {
       throw new SendError([portId]);
     }
@@ -80,6 +80,29 @@ export class PortRouter {
   }
 }
 
+// columnar results are only sent to the port which asked for them, so their
+// buffers can be moved to the tab rather than copied
+function transferables(msg: WorkerToHostMsg): Transferable[] {
+  if (msg.tag !== "Reply") {
+    return [];
+  }
+  switch (msg.reply.tag) {
+    case "Columns":
+      return msg.reply.data.flatMap((column) => [
+        column.kinds.buffer,
+        column.numbers.buffer,
+        column.offsets.buffer,
+        column.bytes.buffer,
+      ]);
+    case "Arrow":
+      return [msg.reply.ipc.buffer];
+    case "ExportChunk":
+      return [msg.reply.data.buffer];
+    default:
+      return [];
+  }
+}
+
 export class SendError extends Error {
   constructor(readonly _missingPorts: PortId[]) {
     super(`missing ports: ${_missingPorts.join(", ")}`);
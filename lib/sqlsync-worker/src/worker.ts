@@ -1,7 +1,11 @@
-import init, { DocReply, HandlerId, WorkerApi } from "../sqlsync-wasm/pkg/sqlsync_wasm.js";
-import { WorkerRequest } from "./index";
+import init, {
+  DocReply,
+  ErrorInfo,
+  HandlerId,
+  WorkerApi,
+  WorkerRequest,
+} from "../sqlsync-wasm/pkg/sqlsync_wasm.js";
 import { PortId, PortRouter } from "./port";
-import { assertUnreachable } from "./util";
 
 const ports = new PortRouter();
 let workerApi: WorkerApi | null = null;
@@ -22,7 +26,12 @@ const MessageQueue = (() => {
       queue = queue.then(
         () => handleMessage(m),
         (e) => {
-          const err = e instanceof Error ? e.message : `error: ${JSON.stringify(e)}`;
+          // errors thrown by the worker api carry their ErrorInfo
+          const err: ErrorInfo = (e as { info?: ErrorInfo } | undefined)?.info ?? {
+            code: "INTERNAL",
+            message: e instanceof Error ? e.message : `error: ${JSON.stringify(e)}`,
+            retryable: false,
+          };
           reply(m.portId, m.req.handlerId, { tag: "Err", err });
         },
       );
@@ -58,27 +67,28 @@ addEventListener("connect", (e: Event) => {
 async function handleMessage({ portId, req }: Message) {
   console.log("sqlsync: received message", req);
 
-  if (req.tag === "Boot") {
+  if (req.tag === "Close") {
+    console.log("sqlsync: Received close request from port", portId);
+    ports.unregister(portId);
     if (!workerApi) {
-      console.log("sqlsync: initializing wasm");
-      await init(req.wasmUrl);
-      workerApi = new WorkerApi(ports, req.coordinatorUrl);
-      console.log("sqlsync: wasm initialized");
-    } else {
-      // TODO(UPGRADE): if a new boot request comes in with different params we
-      // should trigger a worker upgrade
-      console.warn("sqlsync: ignoring duplicate boot request");
+      return;
     }
-    reply(portId, req.handlerId, { tag: "Ack" });
-  } else if (req.tag === "Doc") {
-    if (!workerApi) {
+  }
+
+  if (!workerApi) {
+    if (req.tag !== "Boot") {
       throw new Error("not booted");
     }
-    await workerApi.handle({ portId, ...req });
-  } else if (req.tag === "Close") {
-    console.log("sqlsync: Received close request from port", portId);
-    ports.unregister(portId);
-  } else {
-    assertUnreachable("unknown message", req);
+    console.log("sqlsync: initializing wasm");
+    await init(req.wasmUrl);
+    workerApi = new WorkerApi(ports, req);
+    console.log("sqlsync: wasm initialized");
+  } else if (req.tag === "Boot") {
+    // TODO(UPGRADE): if a new boot request comes in with different params we
+    // should trigger a worker upgrade
+    console.warn("sqlsync: ignoring duplicate boot request");
   }
+
+  // the worker api handles every request, including the boot it started from
+  await workerApi.handle(portId, req);
 }
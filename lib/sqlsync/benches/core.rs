@@ -0,0 +1,332 @@
+//! Benchmarks for sqlsync's core paths: reading and writing pages through
+//! storage, the read path under different page cache sizes, serializing
+//! frames, checkpointing the WAL, applying mutations
+//! and replicating them between a client and a coordinator over an
+//! in-memory transport, and bootstrapping clients of a large synthetic
+//! document. They use the task and stress reducers, so run them with `just
+//! bench` which builds both first.
+use std::io;
+
+use criterion::{
+    criterion_group, criterion_main, BatchSize, Criterion, Throughput,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sqlsync::{
+    coordinator::CoordinatorDocument,
+    local::{LocalDocument, NoopSignal},
+    replication::ReplicationProtocol,
+    CacheSize, Journal, JournalId, JournalMode, MemoryJournal,
+    MemoryJournalFactory, Reducer, Serializable, PAGESIZE,
+};
+
+#[path = "../examples/shared/stress.rs"]
+mod stress;
+// the scripted client steps are only used by the stress example
+#[allow(dead_code)]
+#[path = "../examples/shared/workload.rs"]
+mod workload;
+use workload::{ChurnProfile, DocumentSpec, Workload};
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Mutation {
+    InitSchema,
+
+    AppendTask { id: i64, description: String },
+}
+
+type Local = LocalDocument<MemoryJournal, NoopSignal>;
+type Coordinator = CoordinatorDocument<MemoryJournal>;
+
+/// the number of tasks documents are seeded with
+const TASKS: i64 = 2000;
+
+// build task_reducer.wasm using: `cargo build --target wasm32-unknown-unknown --example task-reducer`
+const WASM_BYTES: &[u8] = include_bytes!(
+    "../../../target/wasm32-unknown-unknown/debug/examples/task_reducer.wasm"
+);
+// build stress_reducer.wasm using: `cargo build --target wasm32-unknown-unknown --example stress-reducer`
+const STRESS_WASM_BYTES: &[u8] = include_bytes!(
+    "../../../target/wasm32-unknown-unknown/debug/examples/stress_reducer.wasm"
+);
+
+fn append_task(id: i64) -> Vec<u8> {
+    bincode::serialize(&Mutation::AppendTask {
+        id,
+        description: format!("task {} with a reasonably long description", id),
+    })
+    .unwrap()
+}
+
+fn open_local(rng: &mut StdRng, doc_id: JournalId) -> Local {
+    LocalDocument::open(
+        MemoryJournal::open(doc_id).unwrap(),
+        MemoryJournal::open(JournalId::new128(rng)).unwrap(),
+        Reducer::new(WASM_BYTES).unwrap(),
+        NoopSignal,
+        NoopSignal,
+        NoopSignal,
+    )
+    .unwrap()
+}
+
+fn open_coordinator(doc_id: JournalId, mode: JournalMode) -> Coordinator {
+    let mut doc = CoordinatorDocument::open(
+        MemoryJournal::open(doc_id).unwrap(),
+        MemoryJournalFactory,
+        WASM_BYTES,
+    )
+    .unwrap();
+    doc.set_journal_mode(mode).unwrap();
+    doc.mutate(&bincode::serialize(&Mutation::InitSchema).unwrap())
+        .unwrap();
+    for id in 0..TASKS {
+        doc.mutate(&append_task(id)).unwrap();
+    }
+    doc
+}
+
+fn scan(doc: &Local) -> i64 {
+    doc.query(|conn| {
+        conn.query_row(
+            "select sum(length(description)) from tasks",
+            [],
+            |row| row.get(0),
+        )
+    })
+    .unwrap()
+}
+
+fn storage(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut doc = open_local(&mut rng, JournalId::new128(&mut rng));
+    doc.mutate(&bincode::serialize(&Mutation::InitSchema).unwrap())
+        .unwrap();
+    for id in 0..TASKS {
+        doc.mutate(&append_task(id)).unwrap();
+    }
+
+    let mut group = c.benchmark_group("storage");
+    group.throughput(Throughput::Elements(1));
+    let mut next_id = TASKS;
+    group.bench_function("write", |b| {
+        b.iter(|| {
+            next_id += 1;
+            doc.mutate(&append_task(next_id)).unwrap()
+        })
+    });
+
+    // a small cache makes every scan read most of its pages from storage
+    doc.set_cache_size(CacheSize::Pages(16)).unwrap();
+    group.bench_function("read", |b| b.iter(|| scan(&doc)));
+    group.finish();
+}
+
+fn read_path(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut doc = open_local(&mut rng, JournalId::new128(&mut rng));
+    doc.mutate(&bincode::serialize(&Mutation::InitSchema).unwrap())
+        .unwrap();
+    for id in 0..TASKS {
+        doc.mutate(&append_task(id)).unwrap();
+    }
+
+    let mut group = c.benchmark_group("read_path");
+    let mut next_id = TASKS;
+    for cache_size in [
+        CacheSize::Pages(16),
+        CacheSize::Pages(256),
+        CacheSize::Adaptive,
+    ] {
+        doc.set_cache_size(cache_size).unwrap();
+        group.bench_function(format!("{:?}", cache_size), |b| {
+            b.iter(|| scan(&doc))
+        });
+        // the cache has to survive a write between every scan
+        group.bench_function(format!("{:?}/with_writes", cache_size), |b| {
+            b.iter(|| {
+                next_id += 1;
+                doc.mutate(&append_task(next_id)).unwrap();
+                scan(&doc)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let doc =
+        open_coordinator(JournalId::new128(&mut rng), JournalMode::Memory);
+    let snapshot = doc.snapshot().unwrap();
+    let snapshot_len = snapshot.to_vec().unwrap().len();
+
+    let mut group = c.benchmark_group("serialization");
+    group.throughput(Throughput::Bytes(snapshot_len as u64));
+    group.bench_function("snapshot", |b| b.iter(|| snapshot.to_vec().unwrap()));
+
+    let frame: Vec<u8> = (0..256 * PAGESIZE).map(|_| rng.gen()).collect();
+    group.throughput(Throughput::Bytes(frame.len() as u64));
+    group.bench_function("append", |b| {
+        b.iter_batched_ref(
+            || MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            |journal| journal.append(&frame[..]).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn mutations(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut group = c.benchmark_group("mutate");
+    group.throughput(Throughput::Elements(1));
+    // in WAL mode every mutation is also checkpointed into storage
+    for mode in [JournalMode::Memory, JournalMode::Wal] {
+        let mut doc = open_coordinator(JournalId::new128(&mut rng), mode);
+        let mut next_id = TASKS;
+        group.bench_function(format!("{:?}", mode), |b| {
+            b.iter(|| {
+                next_id += 1;
+                doc.mutate(&append_task(next_id)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// send msg from one side of a connection to the other, handling the reply.
+/// each side has one protocol for the connection
+macro_rules! send {
+    ($from:ident -> $to:ident, $protocols:expr, $msg:expr, $reader:expr) => {{
+        let (from, to) = $protocols;
+        if let Some(resp) = to.handle(&mut $to, $msg, $reader).unwrap() {
+            from.handle(&mut $from, resp, &mut io::empty()).unwrap();
+        }
+    }};
+}
+
+macro_rules! sync {
+    ($from:ident -> $to:ident, $protocols:expr) => {{
+        let (from, to) = $protocols;
+        while let Some((msg, reader)) = from.sync(&$from).unwrap() {
+            // copy the frame out to release the borrow on the document, as a
+            // network transport would
+            let mut reader = &reader.to_owned()[..];
+            send!($from -> $to, (&mut *from, &mut *to), msg, &mut reader);
+        }
+    }};
+}
+
+fn replication(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let doc_id = JournalId::new128(&mut rng);
+    let mut remote = open_coordinator(doc_id, JournalMode::Memory);
+    let mut local = open_local(&mut rng, doc_id);
+
+    let mut local_protocol = ReplicationProtocol::new();
+    let mut remote_protocol = ReplicationProtocol::new();
+    let msg = local_protocol.start(&local);
+    send!(
+        local -> remote,
+        (&mut local_protocol, &mut remote_protocol),
+        msg,
+        &mut io::empty()
+    );
+    let msg = remote_protocol.start(&remote);
+    send!(
+        remote -> local,
+        (&mut remote_protocol, &mut local_protocol),
+        msg,
+        &mut io::empty()
+    );
+
+    // bring the client up to date with the seeded document
+    sync!(remote -> local, (&mut remote_protocol, &mut local_protocol));
+    local.rebase().unwrap();
+
+    let mut group = c.benchmark_group("replication");
+    group.throughput(Throughput::Elements(1));
+    let mut next_id = TASKS;
+    group.bench_function("round_trip", |b| {
+        b.iter(|| {
+            next_id += 1;
+            local.mutate(&append_task(next_id)).unwrap();
+            sync!(
+                local -> remote,
+                (&mut local_protocol, &mut remote_protocol)
+            );
+            remote.step().unwrap();
+            sync!(
+                remote -> local,
+                (&mut remote_protocol, &mut local_protocol)
+            );
+            local.rebase().unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn large_document(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let doc_id = JournalId::new128(&mut rng);
+    let spec = DocumentSpec::megabytes(8, 8);
+    let mut doc = CoordinatorDocument::open(
+        MemoryJournal::open(doc_id).unwrap(),
+        MemoryJournalFactory,
+        STRESS_WASM_BYTES,
+    )
+    .unwrap();
+    let mut workload = Workload::new(0, 0, spec, ChurnProfile::UPDATE_HEAVY);
+    for mutation in workload.seed() {
+        doc.mutate_typed(&mutation).unwrap();
+    }
+    while doc.has_pending_work() {
+        doc.step().unwrap();
+    }
+
+    let mut group = c.benchmark_group("large_document");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("churn", |b| {
+        b.iter(|| {
+            doc.mutate_typed(&workload.next_mutation()).unwrap();
+            doc.step().unwrap();
+        })
+    });
+
+    let checkpoint = doc.checkpoint().unwrap().unwrap();
+    group.throughput(Throughput::Bytes(spec.bytes as u64));
+    group
+        .bench_function("checkpoint", |b| b.iter(|| doc.checkpoint().unwrap()));
+    group.bench_function("bootstrap", |b| {
+        b.iter_batched(
+            || {
+                let local = LocalDocument::open(
+                    MemoryJournal::open(doc_id).unwrap(),
+                    MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+                    Reducer::new(STRESS_WASM_BYTES).unwrap(),
+                    NoopSignal,
+                    NoopSignal,
+                    NoopSignal,
+                )
+                .unwrap();
+                (local, checkpoint.clone())
+            },
+            |(mut local, checkpoint)| local.bootstrap(checkpoint).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    storage,
+    read_path,
+    serialization,
+    mutations,
+    replication,
+    large_document
+);
+criterion_main!(benches);
@@ -1,18 +1,16 @@
 // build: "cargo build --target wasm32-unknown-unknown --example counter-reducer"
 
-use serde::{Deserialize, Serialize};
-use sqlsync_reducer::{execute, init_reducer, types::ReducerError};
+use sqlsync_reducer::{
+    execute, init_reducer, mutation::TypedMutation, types::ReducerError,
+};
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Mutation {
-    InitSchema,
-    Incr,
-    Decr,
-}
+#[path = "shared/counter.rs"]
+mod counter;
+use counter::Mutation;
 
-init_reducer!(reducer);
+init_reducer!(reducer, task = task);
 async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
-    let mutation: Mutation = bincode::deserialize(&mutation)?;
+    let mutation = Mutation::decode(&mutation)?;
     match mutation {
         Mutation::InitSchema => {
             let create_table = execute!(
@@ -46,3 +44,13 @@ async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
 
     Ok(())
 }
+
+async fn task(name: String) -> Result<(), ReducerError> {
+    match name.as_str() {
+        "double" => {
+            execute!("UPDATE counter SET value = value * 2").await?;
+        }
+        other => log::warn!("unknown task: {}", other),
+    }
+    Ok(())
+}
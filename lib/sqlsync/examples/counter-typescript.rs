@@ -0,0 +1,32 @@
+///! This example generates TypeScript definitions for counter-reducer's
+///! mutations and rows, so a JS client can construct mutations type safely.
+///! Frontends run it as part of their build:
+///!
+///!     cargo run --example counter-typescript > src/counter.ts
+///
+use sqlsync_reducer::{row, typescript::Definitions};
+
+#[path = "shared/counter.rs"]
+mod counter;
+use counter::Mutation;
+
+row! {
+    #[allow(dead_code)]
+    pub struct Counter {
+        pub id: i64,
+        pub value: i64,
+    }
+}
+
+fn main() {
+    let ts = Definitions::new()
+        .mutation::<Mutation>()
+        .row::<Counter>()
+        .render();
+
+    // the encoder must number variants in the same order as bincode
+    assert!(ts.contains("case \"Incr\":\n      w.u32(1);"));
+    assert!(ts.contains("export type Counter = {"));
+
+    print!("{}", ts);
+}
@@ -9,8 +9,10 @@ use serde::{Deserialize, Serialize};
 use sqlsync::{
     coordinator::CoordinatorDocument,
     local::{LocalDocument, NoopSignal},
-    replication::ReplicationProtocol,
+    quota::{QuotaKind, StorageQuota},
+    replication::{ReplicationProtocol, ReplicationSource},
     sqlite::Connection,
+    timeline::FrameMeta,
     JournalId, MemoryJournal, MemoryJournalFactory, Reducer,
 };
 
@@ -317,6 +319,37 @@ fn main() -> anyhow::Result<()> {
     print_tasks!(local)?;
     print_tasks!(local2)?;
 
+    // profile replaying local's unsynced mutations
+    let before = local.query(|conn| query_tasks(conn))?;
+    let profile = local.replay_profile(local.source_range())?;
+    log::info!("replay profile: {:?}", profile.totals());
+    assert_eq!(profile.mutations.len(), 2);
+    for mutation in profile.mutations.iter() {
+        assert!(mutation.statements > 0 && mutation.pages_dirtied > 0);
+    }
+    // profiling leaves the document as it was
+    assert_eq!(local.query(|conn| query_tasks(conn))?, before);
+
+    // drafts are visible locally, but only sync once they're promoted
+    let draft = |id: i64, description: &str| {
+        bincode::serialize(&Mutation::AppendTask {
+            id,
+            description: description.into(),
+        })
+    };
+    let kept = local.mutate_draft(&draft(5, "write the draft")?)?;
+    let discarded = local.mutate_draft(&draft(6, "never mind")?)?;
+    assert_eq!(
+        local.query(|conn| query_tasks(conn))?.len(),
+        before.len() + 2
+    );
+    local.discard_draft(discarded)?;
+    local.promote_draft(kept, None, FrameMeta::default())?;
+    assert!(local.drafts().is_empty());
+    let tasks = local.query(|conn| query_tasks(conn))?;
+    assert_eq!(tasks.len(), before.len() + 1);
+    assert!(tasks.iter().any(|task| task.id == 5));
+
     sync!(local -> remote);
     sync!(local2 -> remote);
 
@@ -334,6 +367,39 @@ fn main() -> anyhow::Result<()> {
     print_tasks!(local)?;
     print_tasks!(local2)?;
 
+    // the coordinator can be queried as of any storage lsn it still retains
+    let count_tasks = |conn: &Connection| {
+        conn.query_row("select count(*) from tasks", [], |row| row.get(0))
+    };
+    let history = remote.history_range();
+    let latest: usize =
+        remote.query_at(history.last().unwrap(), count_tasks)?;
+    assert_eq!(latest, remote.query(|conn| query_tasks(conn))?.len());
+    assert!(remote.query_at(history.next(), count_tasks).is_err());
+
+    // once the journal is at its quota mutations are rejected, and wait
+    // until there is room for them
+    remote.set_quota(StorageQuota {
+        max_journal_bytes: Some(remote.journal_size()?),
+        ..StorageQuota::default()
+    })?;
+    mutate!(local, AppendTask 7, "free up some space");
+    sync!(local -> remote);
+    step_remote!();
+    let rejections = remote.take_rejections();
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].kind, QuotaKind::Journal);
+    assert!(!remote.has_pending_work());
+
+    remote.set_quota(StorageQuota::default())?;
+    step_remote!();
+    assert!(remote.take_rejections().is_empty());
+    assert_eq!(remote.rejected_timelines().count(), 0);
+    sync!(remote -> local);
+    rebase!(local);
+    sync!(remote -> local2);
+    rebase!(local2);
+
     // get both sets of tasks and make sure they are the same
     let tasks1 = local.query(|conn| query_tasks(conn))?;
     let tasks2 = local2.query(|conn| query_tasks(conn))?;
@@ -0,0 +1,295 @@
+///! This example exercises the exactly-once guarantee between a client's
+///! timeline and the coordinator. It drops acknowledgements, reconnects
+///! clients mid-sync, restarts the coordinator, and replays stale frames, and
+///! then checks that every mutation was applied exactly once. Finally a cold
+///! client bootstraps from a checkpoint rather than replicating every frame,
+///! and the coordinator redacts its history, forcing a stale client to resync.
+///
+use std::io;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sqlsync::{
+    coordinator::CoordinatorDocument,
+    local::{LocalDocument, NoopSignal},
+    positioned_io::PositionedReader,
+    replication::{
+        ReplicationDestination, ReplicationMsg, ReplicationProtocol,
+        ReplicationSource,
+    },
+    JournalId, MemoryJournal, MemoryJournalFactory, Reducer,
+};
+
+#[path = "shared/counter.rs"]
+mod counter;
+use counter::Mutation;
+
+type Local = LocalDocument<MemoryJournal, NoopSignal>;
+type Remote = CoordinatorDocument<MemoryJournal>;
+
+/// a replication session from one side of a connection to the other
+struct Session {
+    outgoing: ReplicationProtocol,
+    incoming: ReplicationProtocol,
+}
+
+impl Session {
+    /// perform the initial range handshake from `from` to `to`
+    fn connect<F, T>(from: &mut F, to: &mut T) -> anyhow::Result<Self>
+    where
+        F: ReplicationSource + ReplicationDestination,
+        T: ReplicationDestination,
+    {
+        let mut session = Session {
+            outgoing: ReplicationProtocol::new(),
+            incoming: ReplicationProtocol::new(),
+        };
+        let msg = session.outgoing.start(&*from);
+        let resp = session
+            .incoming
+            .handle(to, msg, &mut io::empty())?
+            .expect("range request must be answered");
+        session.outgoing.handle(from, resp, &mut io::empty())?;
+        Ok(session)
+    }
+
+    /// send every frame `from` has for `to`, returning the number of frames
+    /// sent. if drop_acks is set the acknowledgements are lost on the way
+    /// back, as if the connection died right after the frames were sent
+    fn sync<F, T>(
+        &mut self,
+        from: &mut F,
+        to: &mut T,
+        drop_acks: bool,
+    ) -> anyhow::Result<usize>
+    where
+        F: ReplicationSource + ReplicationDestination,
+        T: ReplicationDestination,
+    {
+        let mut sent = 0;
+        loop {
+            let Some((msg, reader)) = self.outgoing.sync(&*from)? else {
+                break;
+            };
+            let frame = read_frame(reader)?;
+            let ack = self.incoming.handle(to, msg, &mut &frame[..])?;
+            sent += 1;
+            if let Some(ack) = ack {
+                if !drop_acks {
+                    self.outgoing.handle(from, ack, &mut io::empty())?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
+
+fn read_frame(reader: impl PositionedReader) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; reader.size()?];
+    reader.read_exact_at(0, &mut buf)?;
+    Ok(buf)
+}
+
+fn counter(doc: &Local) -> anyhow::Result<i64> {
+    Ok(doc.query(|conn| {
+        conn.query_row("select value from counter", [], |row| row.get(0))
+    })?)
+}
+
+fn mutate(doc: &mut Local, mutation: Mutation) -> anyhow::Result<()> {
+    doc.mutate_typed(&mutation)?;
+    Ok(())
+}
+
+fn step_all(remote: &mut Remote) -> anyhow::Result<()> {
+    while remote.has_pending_work() {
+        remote.step()?;
+    }
+    Ok(())
+}
+
+/// bring the client up to date with the coordinator
+fn refresh(local: &mut Local, remote: &mut Remote) -> anyhow::Result<()> {
+    let mut session = Session::connect(remote, local)?;
+    session.sync(remote, local, false)?;
+    local.rebase()?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .without_timestamps()
+        .env()
+        .init()?;
+
+    // seed a random number generater from the command line
+    // or use a random seed
+    let rng_seed: u64 = std::env::args()
+        .nth(1)
+        .map(|seed| seed.parse().unwrap())
+        .unwrap_or_else(|| rand::thread_rng().gen());
+
+    log::info!("using rng seed: {}", rng_seed);
+
+    let mut rng = StdRng::seed_from_u64(rng_seed);
+
+    let doc_id = JournalId::new128(&mut rng);
+    let timeline_id = JournalId::new128(&mut rng);
+    // build counter_reducer.wasm using: `cargo build --target wasm32-unknown-unknown --example counter-reducer`
+    let wasm_bytes = include_bytes!(
+        "../../../target/wasm32-unknown-unknown/debug/examples/counter_reducer.wasm"
+    );
+
+    let mut local = LocalDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournal::open(timeline_id)?,
+        Reducer::new(wasm_bytes.as_slice())?,
+        NoopSignal,
+        NoopSignal,
+        NoopSignal,
+    )?;
+    let mut remote = CoordinatorDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournalFactory,
+        &wasm_bytes[..],
+    )?;
+
+    let mut expected = 0;
+
+    // lsns 0..=5
+    mutate(&mut local, Mutation::InitSchema)?;
+    for _ in 0..5 {
+        mutate(&mut local, Mutation::Incr)?;
+        expected += 1;
+    }
+
+    log::info!("race 1: every ack is lost, then the client reconnects");
+    let mut session = Session::connect(&mut local, &mut remote)?;
+    assert_eq!(session.sync(&mut local, &mut remote, true)?, 6);
+    step_all(&mut remote)?;
+    // the client never heard back, so a fresh session must pick up after the
+    // coordinator's watermark rather than resending everything
+    let mut session = Session::connect(&mut local, &mut remote)?;
+    assert_eq!(session.sync(&mut local, &mut remote, false)?, 0);
+
+    log::info!("race 2: the client reconnects while frames are still queued");
+    for _ in 0..3 {
+        mutate(&mut local, Mutation::Incr)?;
+        expected += 1;
+    }
+    assert_eq!(session.sync(&mut local, &mut remote, true)?, 3);
+    assert!(remote.has_pending_work());
+    let mut session = Session::connect(&mut local, &mut remote)?;
+    assert_eq!(session.sync(&mut local, &mut remote, false)?, 0);
+    step_all(&mut remote)?;
+
+    log::info!("race 3: a stale frame arrives after it has been applied");
+    let stale =
+        read_frame(local.read_lsn(2)?.expect("lsn 2 is in the timeline"))?;
+    remote.write_lsn(timeline_id, 2, &mut &stale[..])?;
+    assert!(
+        !remote.has_pending_work(),
+        "applied lsns must not be queued again"
+    );
+
+    log::info!("race 4: the coordinator restarts and loses its timelines");
+    for _ in 0..2 {
+        mutate(&mut local, Mutation::Decr)?;
+        expected -= 1;
+    }
+    // acks for these are lost, and the coordinator restarts before applying
+    let mut session = Session::connect(&mut local, &mut remote)?;
+    assert_eq!(session.sync(&mut local, &mut remote, true)?, 2);
+
+    // restart the coordinator with a copy of its storage but no timelines
+    let mut storage = MemoryJournal::open(doc_id)?;
+    let mut copy = Session::connect(&mut remote, &mut storage)?;
+    copy.sync(&mut remote, &mut storage, false)?;
+    let mut remote = CoordinatorDocument::open(
+        storage,
+        MemoryJournalFactory,
+        &wasm_bytes[..],
+    )?;
+
+    // the client should only resend the two frames which never got applied
+    let mut session = Session::connect(&mut local, &mut remote)?;
+    assert_eq!(session.sync(&mut local, &mut remote, false)?, 2);
+    step_all(&mut remote)?;
+
+    log::info!("the coordinator submits a mutation of its own");
+    remote.mutate_typed(&Mutation::Incr)?;
+    expected += 1;
+    remote.run_task("double")?;
+    expected *= 2;
+    step_all(&mut remote)?;
+
+    // the coordinator can be read directly, without syncing a client
+    let remote_value: i64 = remote.query(|conn| {
+        conn.query_row("select value from counter", [], |row| row.get(0))
+    })?;
+    assert_eq!(remote_value, expected);
+
+    let metrics = remote.metrics();
+    log::info!("coordinator metrics: {:?}", metrics);
+    assert!(metrics.reducer.memory_pages > 0);
+    // fuel is only metered for reducers with a fuel limit
+    assert_eq!(metrics.reducer.fuel_consumed, None);
+    // the resent frames and the coordinator's own mutations, since restarting
+    assert_eq!(metrics.pending_mutations, 0);
+    assert_eq!(metrics.apply_wait.mutations, 4);
+
+    refresh(&mut local, &mut remote)?;
+    let value = counter(&local)?;
+    log::info!("final counter value: {} (expected {})", value, expected);
+    assert_eq!(value, expected, "mutations were not applied exactly once");
+
+    // and the client has dropped everything the coordinator applied
+    assert!(local.source_range().is_empty());
+
+    log::info!("a cold client bootstraps from a checkpoint");
+    // query planner statistics are shipped along with the checkpoint
+    assert!(remote.optimize()?);
+    let checkpoint = remote.checkpoint()?.expect("the coordinator has storage");
+    remote.mutate_typed(&Mutation::Incr)?;
+    expected += 1;
+    step_all(&mut remote)?;
+    let mut cold = LocalDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournal::open(JournalId::new128(&mut rng))?,
+        Reducer::new(wasm_bytes.as_slice())?,
+        NoopSignal,
+        NoopSignal,
+        NoopSignal,
+    )?;
+    assert!(cold.bootstrap(checkpoint)?);
+    // only the frame committed after the checkpoint needs to be replicated
+    let mut session = Session::connect(&mut remote, &mut cold)?;
+    assert_eq!(session.sync(&mut remote, &mut cold, false)?, 1);
+    cold.rebase()?;
+    assert_eq!(counter(&cold)?, expected);
+    let analyzed_tables: i64 = cold.query(|conn| {
+        conn.query_row("select count(*) from sqlite_stat1", [], |row| {
+            row.get(0)
+        })
+    })?;
+    assert!(analyzed_tables > 0);
+
+    log::info!("the coordinator redacts the counter's history");
+    let epoch = remote.redact(|conn| {
+        conn.execute("update counter set value = 0", []).map(|_| ())
+    })?;
+    expected = 0;
+    assert_eq!(remote.history_range().len(), 1);
+    // the stale client is told the new epoch during its next handshake, and
+    // drops its storage before replicating the folded frame
+    let mut protocol = ReplicationProtocol::new();
+    let msg = ReplicationMsg::Epoch { epoch };
+    protocol.handle(&mut local, msg, &mut io::empty())?;
+    assert!(local.storage_lsn().is_none());
+    refresh(&mut local, &mut remote)?;
+    assert_eq!(counter(&local)?, expected);
+
+    log::info!("DONE");
+
+    Ok(())
+}
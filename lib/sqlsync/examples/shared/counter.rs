@@ -0,0 +1,11 @@
+//! The counter's mutations, shared by counter-reducer and the examples which
+//! drive it.
+
+sqlsync_reducer::mutations! {
+    #[derive(Debug)]
+    pub enum Mutation {
+        InitSchema,
+        Incr,
+        Decr,
+    }
+}
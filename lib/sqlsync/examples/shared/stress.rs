@@ -0,0 +1,13 @@
+//! The stress reducer's mutations, shared by stress-reducer and the workload
+//! generators in shared/workload.rs. Payloads are generated on the client and
+//! carried in the mutation so that the reducer stays deterministic.
+
+sqlsync_reducer::mutations! {
+    #[derive(Debug, Clone)]
+    pub enum Mutation {
+        CreateTables { tables: u32 },
+        Insert { table: u32, id: i64, payload: Vec<u8> },
+        Update { table: u32, id: i64, payload: Vec<u8> },
+        Delete { table: u32, id: i64 },
+    }
+}
@@ -0,0 +1,147 @@
+//! Generators for synthetic documents and scripted client workloads over the
+//! stress reducer (see shared/stress.rs). Everything is derived from a seeded
+//! rng, so a document or workload can be reproduced from its seed.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::stress::Mutation;
+
+/// the shape of a synthetic document
+#[derive(Debug, Clone, Copy)]
+pub struct DocumentSpec {
+    pub tables: u32,
+    /// the number of payload bytes to seed the document with
+    pub bytes: usize,
+    /// the average payload size, payloads vary by up to half of this
+    pub row_size: usize,
+}
+
+impl DocumentSpec {
+    /// a document of roughly mb megabytes spread over tables tables
+    pub fn megabytes(mb: usize, tables: u32) -> Self {
+        Self { tables, bytes: mb << 20, row_size: 512 }
+    }
+}
+
+/// the relative weights of the mutations a workload makes once the document
+/// has been seeded
+#[derive(Debug, Clone, Copy)]
+pub struct ChurnProfile {
+    pub inserts: u32,
+    pub updates: u32,
+    pub deletes: u32,
+}
+
+impl ChurnProfile {
+    /// the document only grows
+    pub const APPEND: Self = Self { inserts: 1, updates: 0, deletes: 0 };
+    /// rows are mostly rewritten in place
+    pub const UPDATE_HEAVY: Self = Self { inserts: 1, updates: 8, deletes: 1 };
+    /// the document stays around the same size while its rows turn over
+    pub const TURNOVER: Self = Self { inserts: 2, updates: 1, deletes: 2 };
+}
+
+/// one step of a scripted client
+#[derive(Debug, Clone)]
+pub enum Step {
+    Mutate(Mutation),
+    /// push pending mutations to the coordinator and pull its changes
+    Sync,
+}
+
+/// generates the mutations of one client. each client allocates ids from its
+/// own range and only changes rows it inserted, so clients never conflict and
+/// the final document is independent of how their mutations interleave
+pub struct Workload {
+    rng: StdRng,
+    spec: DocumentSpec,
+    churn: ChurnProfile,
+    /// the live row ids in each table
+    rows: Vec<Vec<i64>>,
+    next_id: i64,
+}
+
+impl Workload {
+    pub fn new(
+        seed: u64,
+        client: u32,
+        spec: DocumentSpec,
+        churn: ChurnProfile,
+    ) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed ^ u64::from(client)),
+            spec,
+            churn,
+            rows: vec![Vec::new(); spec.tables as usize],
+            next_id: i64::from(client) << 40,
+        }
+    }
+
+    /// the mutations which create and fill the document described by the
+    /// spec
+    pub fn seed(&mut self) -> Vec<Mutation> {
+        let mut mutations =
+            vec![Mutation::CreateTables { tables: self.spec.tables }];
+        let mut bytes = 0;
+        while bytes < self.spec.bytes {
+            let mutation = self.insert();
+            if let Mutation::Insert { ref payload, .. } = mutation {
+                bytes += payload.len();
+            }
+            mutations.push(mutation);
+        }
+        mutations
+    }
+
+    /// the next mutation according to the churn profile. updates and deletes
+    /// fall back to inserts while the client has no rows
+    pub fn next_mutation(&mut self) -> Mutation {
+        let ChurnProfile { inserts, updates, deletes } = self.churn;
+        let roll = self.rng.gen_range(0..inserts + updates + deletes);
+        let table = self.rng.gen_range(0..self.spec.tables);
+        let rows = &self.rows[table as usize];
+        if roll < inserts || rows.is_empty() {
+            return self.insert();
+        }
+        let idx = self.rng.gen_range(0..rows.len());
+        if roll < inserts + updates {
+            let id = rows[idx];
+            Mutation::Update { table, id, payload: self.payload() }
+        } else {
+            let id = self.rows[table as usize].swap_remove(idx);
+            Mutation::Delete { table, id }
+        }
+    }
+
+    /// a script of steps mutations, syncing after every sync_every
+    /// mutations and once at the end
+    pub fn script(&mut self, steps: usize, sync_every: usize) -> Vec<Step> {
+        let mut script = Vec::with_capacity(steps + steps / sync_every + 1);
+        for i in 1..=steps {
+            script.push(Step::Mutate(self.next_mutation()));
+            if i % sync_every == 0 {
+                script.push(Step::Sync);
+            }
+        }
+        if !matches!(script.last(), Some(Step::Sync)) {
+            script.push(Step::Sync);
+        }
+        script
+    }
+
+    fn insert(&mut self) -> Mutation {
+        let table = self.rng.gen_range(0..self.spec.tables);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.rows[table as usize].push(id);
+        Mutation::Insert { table, id, payload: self.payload() }
+    }
+
+    fn payload(&mut self) -> Vec<u8> {
+        let size = self.spec.row_size;
+        let len = self.rng.gen_range(size / 2..=size + size / 2).max(1);
+        let mut payload = vec![0; len];
+        self.rng.fill(&mut payload[..]);
+        payload
+    }
+}
@@ -0,0 +1,53 @@
+// build: "cargo build --target wasm32-unknown-unknown --example stress-reducer"
+
+use sqlsync_reducer::{
+    execute, init_reducer, mutation::TypedMutation, types::ReducerError,
+};
+
+#[path = "shared/stress.rs"]
+mod stress;
+use stress::Mutation;
+
+init_reducer!(reducer);
+async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
+    let mutation = Mutation::decode(&mutation)?;
+    match mutation {
+        Mutation::CreateTables { tables } => {
+            for table in 0..tables {
+                execute!(format!(
+                    "CREATE TABLE IF NOT EXISTS t{} (
+                        id INTEGER PRIMARY KEY,
+                        payload BLOB NOT NULL
+                    )",
+                    table
+                ))
+                .await?;
+            }
+        }
+        Mutation::Insert { table, id, payload } => {
+            execute!(
+                format!(
+                    "INSERT OR REPLACE INTO t{} (id, payload) VALUES (?, ?)",
+                    table
+                ),
+                id,
+                payload
+            )
+            .await?;
+        }
+        Mutation::Update { table, id, payload } => {
+            execute!(
+                format!("UPDATE t{} SET payload = ? WHERE id = ?", table),
+                payload,
+                id
+            )
+            .await?;
+        }
+        Mutation::Delete { table, id } => {
+            execute!(format!("DELETE FROM t{} WHERE id = ?", table), id)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+///! This example builds a large synthetic document on the coordinator and
+///! then runs scripted workloads from several clients against it, compacting
+///! the coordinator's storage as it goes. Finally a cold client bootstraps
+///! from a checkpoint, and every document is checked to have converged.
+///!
+///! usage: stress [rng_seed] [megabytes] [clients]
+///
+use std::{io, time::Instant};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sqlsync::{
+    coordinator::CoordinatorDocument,
+    local::{LocalDocument, NoopSignal},
+    order::ApplyOrder,
+    positioned_io::PositionedReader,
+    replication::{
+        ReplicationDestination, ReplicationProtocol, ReplicationSource,
+    },
+    JournalId, MemoryJournal, MemoryJournalFactory, Reducer,
+};
+
+#[path = "shared/stress.rs"]
+mod stress;
+#[path = "shared/workload.rs"]
+mod workload;
+use workload::{ChurnProfile, DocumentSpec, Step, Workload};
+
+type Local = LocalDocument<MemoryJournal, NoopSignal>;
+type Remote = CoordinatorDocument<MemoryJournal>;
+
+const TABLES: u32 = 8;
+const STEPS: usize = 500;
+const SYNC_EVERY: usize = 25;
+
+/// send every frame from has for to
+fn sync<F, T>(from: &mut F, to: &mut T) -> anyhow::Result<usize>
+where
+    F: ReplicationSource + ReplicationDestination,
+    T: ReplicationDestination,
+{
+    let mut outgoing = ReplicationProtocol::new();
+    let mut incoming = ReplicationProtocol::new();
+    let msg = outgoing.start(&*from);
+    let resp = incoming
+        .handle(to, msg, &mut io::empty())?
+        .expect("range request must be answered");
+    outgoing.handle(from, resp, &mut io::empty())?;
+
+    let mut sent = 0;
+    while let Some((msg, reader)) = outgoing.sync(&*from)? {
+        let frame = read_frame(reader)?;
+        if let Some(ack) = incoming.handle(to, msg, &mut &frame[..])? {
+            outgoing.handle(from, ack, &mut io::empty())?;
+        }
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+fn read_frame(reader: impl PositionedReader) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; reader.size()?];
+    reader.read_exact_at(0, &mut buf)?;
+    Ok(buf)
+}
+
+fn step_all(remote: &mut Remote) -> anyhow::Result<()> {
+    while remote.has_pending_work() {
+        remote.step()?;
+    }
+    Ok(())
+}
+
+/// push the client's mutations and bring it up to date with the coordinator
+fn round_trip(local: &mut Local, remote: &mut Remote) -> anyhow::Result<()> {
+    sync(local, remote)?;
+    step_all(remote)?;
+    sync(remote, local)?;
+    local.rebase()?;
+    Ok(())
+}
+
+/// the row count and total payload size of every table, which must match
+/// between converged documents
+fn summary(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(i64, i64)>> {
+    (0..TABLES)
+        .map(|table| {
+            conn.query_row(
+                &format!(
+                    "select count(*), ifnull(sum(length(payload)), 0) from t{}",
+                    table
+                ),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .without_timestamps()
+        .env()
+        .init()?;
+
+    let mut args = std::env::args().skip(1);
+    // seed a random number generater from the command line
+    // or use a random seed
+    let rng_seed: u64 = args
+        .next()
+        .filter(|seed| !seed.is_empty())
+        .map(|seed| seed.parse().unwrap())
+        .unwrap_or_else(|| rand::thread_rng().gen());
+    let megabytes: usize =
+        args.next().map(|mb| mb.parse().unwrap()).unwrap_or(16);
+    let clients: u32 = args.next().map(|n| n.parse().unwrap()).unwrap_or(4);
+
+    log::info!("using rng seed: {}", rng_seed);
+
+    let mut rng = StdRng::seed_from_u64(rng_seed);
+    let doc_id = JournalId::new128(&mut rng);
+    // build stress_reducer.wasm using: `cargo build --target wasm32-unknown-unknown --example stress-reducer`
+    let wasm_bytes = include_bytes!(
+        "../../../target/wasm32-unknown-unknown/debug/examples/stress_reducer.wasm"
+    );
+
+    let spec = DocumentSpec::megabytes(megabytes, TABLES);
+    let mut remote = CoordinatorDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournalFactory,
+        &wasm_bytes[..],
+    )?;
+
+    log::info!("seeding a {}MB document over {} tables", megabytes, TABLES);
+    let start = Instant::now();
+    let mut seeder = Workload::new(rng_seed, 0, spec, ChurnProfile::APPEND);
+    for mutation in seeder.seed() {
+        remote.mutate_typed(&mutation)?;
+    }
+    step_all(&mut remote)?;
+    log::info!("seeded in {:?}", start.elapsed());
+    // keep clients with update heavy workloads from starving the others
+    remote.set_apply_order(ApplyOrder::RoundRobin { quantum: 8 })?;
+
+    let mut locals = (1..=clients)
+        .map(|_| {
+            Ok(LocalDocument::open(
+                MemoryJournal::open(doc_id)?,
+                MemoryJournal::open(JournalId::new128(&mut rng))?,
+                Reducer::new(wasm_bytes.as_slice())?,
+                NoopSignal,
+                NoopSignal,
+                NoopSignal,
+            )?)
+        })
+        .collect::<anyhow::Result<Vec<Local>>>()?;
+
+    let start = Instant::now();
+    for local in locals.iter_mut() {
+        round_trip(local, &mut remote)?;
+    }
+    log::info!("{} clients caught up in {:?}", clients, start.elapsed());
+
+    let profiles = [
+        ChurnProfile::APPEND,
+        ChurnProfile::UPDATE_HEAVY,
+        ChurnProfile::TURNOVER,
+    ];
+    let mut scripts = (1..=clients)
+        .map(|client| {
+            let churn = profiles[client as usize % profiles.len()];
+            Workload::new(rng_seed, client, spec, churn)
+                .script(STEPS, SYNC_EVERY)
+                .into_iter()
+        })
+        .collect::<Vec<_>>();
+
+    log::info!("running {} steps on each client", STEPS);
+    let start = Instant::now();
+    let mut running = clients as usize;
+    while running > 0 {
+        running = 0;
+        // interleave the scripts, starting from a random client each round
+        let offset = rng.gen_range(0..locals.len());
+        for i in 0..locals.len() {
+            let client = (offset + i) % locals.len();
+            let local = &mut locals[client];
+            match scripts[client].next() {
+                Some(Step::Mutate(mutation)) => {
+                    local.mutate_typed(&mutation)?;
+                }
+                Some(Step::Sync) => round_trip(local, &mut remote)?,
+                None => continue,
+            }
+            running += 1;
+        }
+        // compact everything each client has seen
+        if rng.gen_bool(0.01) {
+            if let Some(up_to) =
+                locals.iter_mut().filter_map(|l| l.storage_lsn()).min()
+            {
+                remote.compact(up_to)?;
+            }
+        }
+    }
+    for local in locals.iter_mut() {
+        round_trip(local, &mut remote)?;
+    }
+    log::info!("workloads finished in {:?}", start.elapsed());
+    log::info!("coordinator metrics: {:?}", remote.metrics());
+
+    log::info!("a cold client bootstraps from a checkpoint");
+    let start = Instant::now();
+    let checkpoint = remote.checkpoint()?.expect("the coordinator has storage");
+    let mut cold = LocalDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournal::open(JournalId::new128(&mut rng))?,
+        Reducer::new(wasm_bytes.as_slice())?,
+        NoopSignal,
+        NoopSignal,
+        NoopSignal,
+    )?;
+    assert!(cold.bootstrap(checkpoint)?);
+    round_trip(&mut cold, &mut remote)?;
+    log::info!("bootstrapped in {:?}", start.elapsed());
+
+    let expected = remote.query(summary)?;
+    for local in locals.iter().chain([&cold]) {
+        assert_eq!(local.query(summary)?, expected);
+    }
+
+    log::info!("DONE");
+
+    Ok(())
+}
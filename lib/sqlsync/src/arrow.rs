@@ -0,0 +1,286 @@
+//! Query results encoded as an Arrow IPC stream, which analytics frontends
+//! (DuckDB, Perspective, apache-arrow in js, ...) read without converting
+//! row by row.
+//!
+//! sqlite columns aren't typed, so each column's Arrow type is the narrowest
+//! type holding all of its values: Null, then Int64, Float64, Utf8 and
+//! Binary. integers in a Float64 column become floats, numbers in a Utf8
+//! column become their text, and everything in a Binary column becomes its
+//! bytes. the types are only known once every row is read, so the results
+//! are buffered and written as a single batch.
+
+use std::sync::Arc;
+
+use arrow_array::{
+    builder::{BinaryBuilder, Float64Builder, Int64Builder, StringBuilder},
+    ArrayRef, NullArray, RecordBatch,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use rusqlite::{types::Value, Connection, Params, Rows};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[error("column {column} holds a {found:?}, but its type is {expected:?}")]
+    ColumnType {
+        column: String,
+        expected: ColumnType,
+        found: ColumnType,
+    },
+}
+
+/// run sql on conn, returning its results as an Arrow IPC stream
+pub fn query_arrow<P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Vec<u8>, EncodeError> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<_> =
+        stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+    let rows = stmt.query(params)?;
+    encode_rows(&columns, rows)
+}
+
+/// encode rows, whose columns are named by columns, as an Arrow IPC stream
+pub fn encode_rows(
+    columns: &[String],
+    mut rows: Rows<'_>,
+) -> Result<Vec<u8>, EncodeError> {
+    let values = read_columns(&mut rows, columns.len(), usize::MAX)?;
+    encode_values(columns, &values)
+}
+
+/// encode each column's values, as read by the caller, as an Arrow IPC
+/// stream
+pub fn encode_values(
+    columns: &[String],
+    values: &[Vec<Value>],
+) -> Result<Vec<u8>, EncodeError> {
+    let types: Vec<_> = values.iter().map(|v| ColumnType::infer(v)).collect();
+    let schema = schema(columns, &types);
+    let batch = record_batch(&schema, &types, values)?;
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+/// read up to limit rows, returning each column's values
+pub(crate) fn read_columns(
+    rows: &mut Rows<'_>,
+    num_columns: usize,
+    limit: usize,
+) -> rusqlite::Result<Vec<Vec<Value>>> {
+    let mut values: Vec<Vec<Value>> = vec![Vec::new(); num_columns];
+    let mut read = 0;
+    while read < limit {
+        let Some(row) = rows.next()? else { break };
+        for (i, column) in values.iter_mut().enumerate() {
+            column.push(row.get(i)?);
+        }
+        read += 1;
+    }
+    Ok(values)
+}
+
+pub(crate) fn schema(columns: &[String], types: &[ColumnType]) -> SchemaRef {
+    let fields: Vec<_> = columns
+        .iter()
+        .zip(types)
+        .map(|(name, ty)| Field::new(name, ty.data_type(), true))
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// a batch of values, which fails if a value is wider than its column's type
+pub(crate) fn record_batch(
+    schema: &SchemaRef,
+    types: &[ColumnType],
+    values: &[Vec<Value>],
+) -> Result<RecordBatch, EncodeError> {
+    let mut arrays = Vec::with_capacity(types.len());
+    for ((field, ty), column) in schema.fields().iter().zip(types).zip(values) {
+        let found = ColumnType::infer(column);
+        if found > *ty {
+            return Err(EncodeError::ColumnType {
+                column: field.name().clone(),
+                expected: *ty,
+                found,
+            });
+        }
+        arrays.push(ty.build(column));
+    }
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+/// ordered from narrowest to widest, so a column's type is the max of its
+/// values' types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColumnType {
+    Null,
+    Int64,
+    Float64,
+    Utf8,
+    Binary,
+}
+
+impl ColumnType {
+    /// the narrowest type holding all of values
+    pub(crate) fn infer(values: &[Value]) -> Self {
+        values
+            .iter()
+            .map(Self::of)
+            .max()
+            .unwrap_or(ColumnType::Null)
+    }
+
+    pub(crate) fn data_type(self) -> DataType {
+        match self {
+            ColumnType::Null => DataType::Null,
+            ColumnType::Int64 => DataType::Int64,
+            ColumnType::Float64 => DataType::Float64,
+            ColumnType::Utf8 => DataType::Utf8,
+            ColumnType::Binary => DataType::Binary,
+        }
+    }
+
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => ColumnType::Null,
+            Value::Integer(_) => ColumnType::Int64,
+            Value::Real(_) => ColumnType::Float64,
+            Value::Text(_) => ColumnType::Utf8,
+            Value::Blob(_) => ColumnType::Binary,
+        }
+    }
+
+    /// values must fit in self
+    fn build(self, values: &[Value]) -> ArrayRef {
+        match self {
+            ColumnType::Null => Arc::new(NullArray::new(values.len())),
+            ColumnType::Int64 => {
+                let mut builder = Int64Builder::with_capacity(values.len());
+                for value in values {
+                    match value {
+                        Value::Integer(v) => builder.append_value(*v),
+                        _ => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Float64 => {
+                let mut builder = Float64Builder::with_capacity(values.len());
+                for value in values {
+                    match value {
+                        Value::Integer(v) => builder.append_value(*v as f64),
+                        Value::Real(v) => builder.append_value(*v),
+                        _ => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Utf8 => {
+                let mut builder = StringBuilder::new();
+                for value in values {
+                    match value {
+                        Value::Null => builder.append_null(),
+                        Value::Integer(v) => {
+                            builder.append_value(v.to_string())
+                        }
+                        Value::Real(v) => builder.append_value(v.to_string()),
+                        Value::Text(v) => builder.append_value(v),
+                        Value::Blob(_) => unreachable!("blobs widen to binary"),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Binary => {
+                let mut builder = BinaryBuilder::new();
+                for value in values {
+                    match value {
+                        Value::Null => builder.append_null(),
+                        Value::Integer(v) => {
+                            builder.append_value(v.to_string())
+                        }
+                        Value::Real(v) => builder.append_value(v.to_string()),
+                        Value::Text(v) => builder.append_value(v),
+                        Value::Blob(v) => builder.append_value(v),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{
+        cast::AsArray,
+        types::{Float64Type, Int64Type},
+    };
+    use arrow_ipc::reader::StreamReader;
+    use arrow_schema::DataType;
+    use rusqlite::Connection;
+
+    use super::query_arrow;
+
+    #[test]
+    fn columns_widen_to_hold_every_value() {
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = "
+            select 1 as i, 1 as f, 1 as s, 'a' as b, null as n
+            union all select 2, 2.5, 'two', x'00ff', null
+            union all select null, null, null, null, null
+        ";
+        let ipc = query_arrow(&conn, sql, []).unwrap();
+
+        let mut reader = StreamReader::try_new(&ipc[..], None).unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+        assert_eq!(batch.num_rows(), 3);
+
+        let types: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.data_type().clone())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                DataType::Int64,
+                DataType::Float64,
+                DataType::Utf8,
+                DataType::Binary,
+                DataType::Null
+            ]
+        );
+
+        let i = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(i.iter().collect::<Vec<_>>(), vec![Some(1), Some(2), None]);
+        let f = batch.column(1).as_primitive::<Float64Type>();
+        assert_eq!(
+            f.iter().collect::<Vec<_>>(),
+            vec![Some(1.0), Some(2.5), None]
+        );
+        let s = batch.column(2).as_string::<i32>();
+        assert_eq!(
+            s.iter().collect::<Vec<_>>(),
+            vec![Some("1"), Some("two"), None]
+        );
+        let b = batch.column(3).as_binary::<i32>();
+        assert_eq!(
+            b.iter().collect::<Vec<_>>(),
+            vec![Some(&b"a"[..]), Some(&[0x00, 0xff][..]), None]
+        );
+    }
+}
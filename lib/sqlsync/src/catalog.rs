@@ -0,0 +1,435 @@
+//! The Catalog indexes a host's documents for admin tools and dashboards:
+//! each document's metadata, size, reducer and when it last changed, kept
+//! in a sqlite database of the host's choosing. Hosts record a document
+//! after stepping it, which reads everything but the metadata from the
+//! CoordinatorDocument, and list the catalog a page at a time with a
+//! CatalogQuery. Queries and pages are serde types so that an admin api can
+//! accept and return them as they are.
+//!
+//! A host whose documents run in separate processes (like the demo's
+//! durable objects) describes each one with CatalogEntry::of where it runs,
+//! and sends the entry to a single catalog to upsert. The catalog's
+//! database can be in memory, in which case the host persists entries as
+//! they change and restores them on startup.
+
+use std::collections::BTreeMap;
+
+use rusqlite::{
+    named_params, params_from_iter, types::Value, Connection,
+    OptionalExtension, Row,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coordinator::CoordinatorDocument, error::Result,
+    unixtime::unix_timestamp_milliseconds, Journal, JournalId, Lsn,
+    ModuleDigest,
+};
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+const CATALOG_TABLES_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS catalog_documents (
+        doc_id BLOB PRIMARY KEY,
+        database_bytes INTEGER NOT NULL,
+        journal_bytes INTEGER NOT NULL,
+        last_lsn INTEGER,
+        modified_ms INTEGER NOT NULL,
+        reducer_digest BLOB
+    ) STRICT;
+
+    CREATE TABLE IF NOT EXISTS catalog_metadata (
+        doc_id BLOB NOT NULL
+            REFERENCES catalog_documents (doc_id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (doc_id, key)
+    ) STRICT;
+
+    CREATE INDEX IF NOT EXISTS catalog_metadata_by_value
+        ON catalog_metadata (key, value);
+";
+
+/// modified_ms only moves when the document does
+const CATALOG_UPSERT_SQL: &str = "
+    INSERT INTO catalog_documents (
+        doc_id, database_bytes, journal_bytes, last_lsn, modified_ms,
+        reducer_digest
+    ) VALUES (
+        :doc_id, :database_bytes, :journal_bytes, :last_lsn, :modified_ms,
+        :reducer_digest
+    )
+    ON CONFLICT (doc_id) DO UPDATE SET
+        database_bytes = excluded.database_bytes,
+        journal_bytes = excluded.journal_bytes,
+        modified_ms = CASE
+            WHEN last_lsn IS excluded.last_lsn THEN modified_ms
+            ELSE excluded.modified_ms
+        END,
+        last_lsn = excluded.last_lsn,
+        reducer_digest = excluded.reducer_digest
+";
+
+const CATALOG_COLUMNS: &str = "
+    doc_id, database_bytes, journal_bytes, last_lsn, modified_ms,
+    reducer_digest
+";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub doc_id: JournalId,
+    /// set by the host, see Catalog::set_metadata
+    pub metadata: BTreeMap<String, String>,
+    pub database_bytes: u64,
+    pub journal_bytes: u64,
+    /// the last lsn in the document's storage journal, None if it's empty
+    pub last_lsn: Option<Lsn>,
+    /// when the catalog first saw last_lsn, in unix milliseconds
+    pub modified_ms: i64,
+    pub reducer_digest: Option<ModuleDigest>,
+}
+
+impl CatalogEntry {
+    /// describe doc, without metadata
+    pub fn of<J: Journal>(doc: &CoordinatorDocument<J>) -> Result<Self> {
+        Ok(Self {
+            doc_id: doc.doc_id(),
+            metadata: BTreeMap::new(),
+            database_bytes: doc.database_size()?,
+            journal_bytes: doc.journal_size()?,
+            last_lsn: doc.history_range().last(),
+            modified_ms: unix_timestamp_milliseconds(),
+            reducer_digest: doc.reducer_digest(),
+        })
+    }
+}
+
+/// filters and pagination for Catalog::list. an entry must match every
+/// filter which is set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogQuery {
+    /// metadata the document must have, key by key
+    pub metadata: BTreeMap<String, String>,
+    pub reducer_digest: Option<ModuleDigest>,
+    pub modified_after_ms: Option<i64>,
+    pub min_database_bytes: Option<u64>,
+    /// the next_cursor of the previous page
+    pub cursor: Option<JournalId>,
+    /// defaults to DEFAULT_PAGE_SIZE, and is capped at MAX_PAGE_SIZE
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogPage {
+    /// ordered by doc id
+    pub entries: Vec<CatalogEntry>,
+    /// None once there are no more entries
+    pub next_cursor: Option<JournalId>,
+}
+
+pub struct Catalog {
+    sqlite: Connection,
+}
+
+impl Catalog {
+    /// use sqlite to store the catalog, creating its tables if they don't
+    /// exist yet
+    pub fn open(sqlite: Connection) -> Result<Self> {
+        sqlite.pragma_update(None, "foreign_keys", true)?;
+        sqlite.execute_batch(CATALOG_TABLES_SQL)?;
+        Ok(Self { sqlite })
+    }
+
+    /// add doc to the catalog, or update its entry
+    pub fn record<J: Journal>(
+        &mut self,
+        doc: &CoordinatorDocument<J>,
+    ) -> Result<()> {
+        self.upsert(&CatalogEntry::of(doc)?)
+    }
+
+    /// record everything but the entry's metadata. modified_ms is kept if
+    /// the entry's last_lsn hasn't changed
+    pub fn upsert(&mut self, entry: &CatalogEntry) -> Result<()> {
+        let digest = entry.reducer_digest.as_ref().map(|d| &d[..]);
+        self.sqlite.execute(
+            CATALOG_UPSERT_SQL,
+            named_params! {
+                ":doc_id": entry.doc_id,
+                ":database_bytes": entry.database_bytes,
+                ":journal_bytes": entry.journal_bytes,
+                ":last_lsn": entry.last_lsn,
+                ":modified_ms": entry.modified_ms,
+                ":reducer_digest": digest,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// replace a document's metadata, returning false if the document isn't
+    /// in the catalog
+    pub fn set_metadata(
+        &mut self,
+        doc_id: JournalId,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<bool> {
+        let tx = self.sqlite.transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM catalog_documents WHERE doc_id = ?)",
+            [doc_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM catalog_metadata WHERE doc_id = ?", [doc_id])?;
+        for (key, value) in metadata {
+            tx.execute(
+                "INSERT INTO catalog_metadata (doc_id, key, value)
+                VALUES (?, ?, ?)",
+                rusqlite::params![doc_id, key, value],
+            )?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// record a persisted entry, metadata and all
+    pub fn restore(&mut self, entry: &CatalogEntry) -> Result<()> {
+        self.upsert(entry)?;
+        self.set_metadata(entry.doc_id, &entry.metadata)?;
+        Ok(())
+    }
+
+    /// remove a document which the host has deleted, returning false if it
+    /// wasn't in the catalog
+    pub fn remove(&mut self, doc_id: JournalId) -> Result<bool> {
+        let removed = self.sqlite.execute(
+            "DELETE FROM catalog_documents WHERE doc_id = ?",
+            [doc_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn get(&self, doc_id: JournalId) -> Result<Option<CatalogEntry>> {
+        let entry = self
+            .sqlite
+            .query_row(
+                &format!(
+                    "SELECT {} FROM catalog_documents WHERE doc_id = ?",
+                    CATALOG_COLUMNS
+                ),
+                [doc_id],
+                read_entry,
+            )
+            .optional()?;
+        entry.map(|entry| self.with_metadata(entry)).transpose()
+    }
+
+    /// a page of the entries which match query
+    pub fn list(&self, query: &CatalogQuery) -> Result<CatalogPage> {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+        let mut filters = vec![];
+        let mut params: Vec<Value> = vec![];
+        if let Some(cursor) = query.cursor {
+            filters.push("doc_id > ?");
+            params.push(Value::Blob(cursor.bytes().to_vec()));
+        }
+        if let Some(digest) = query.reducer_digest {
+            filters.push("reducer_digest = ?");
+            params.push(Value::Blob(digest.to_vec()));
+        }
+        if let Some(after) = query.modified_after_ms {
+            filters.push("modified_ms > ?");
+            params.push(Value::Integer(after));
+        }
+        if let Some(min) = query.min_database_bytes {
+            filters.push("database_bytes >= ?");
+            params.push(Value::Integer(min as i64));
+        }
+        for (key, value) in query.metadata.iter() {
+            filters.push(
+                "EXISTS (
+                    SELECT 1 FROM catalog_metadata AS m
+                    WHERE m.doc_id = catalog_documents.doc_id
+                        AND m.key = ? AND m.value = ?
+                )",
+            );
+            params.push(Value::Text(key.clone()));
+            params.push(Value::Text(value.clone()));
+        }
+        let filters = if filters.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", filters.join(" AND "))
+        };
+        // fetch one extra entry to learn whether there is another page
+        params.push(Value::Integer(limit as i64 + 1));
+
+        let mut stmt = self.sqlite.prepare(&format!(
+            "SELECT {} FROM catalog_documents {} ORDER BY doc_id LIMIT ?",
+            CATALOG_COLUMNS, filters
+        ))?;
+        let mut entries = stmt
+            .query_map(params_from_iter(params), read_entry)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|entry| entry.doc_id)
+        } else {
+            None
+        };
+        let entries = entries
+            .into_iter()
+            .map(|entry| self.with_metadata(entry))
+            .collect::<Result<_>>()?;
+        Ok(CatalogPage { entries, next_cursor })
+    }
+
+    fn with_metadata(&self, mut entry: CatalogEntry) -> Result<CatalogEntry> {
+        let mut stmt = self.sqlite.prepare_cached(
+            "SELECT key, value FROM catalog_metadata WHERE doc_id = ?",
+        )?;
+        entry.metadata = stmt
+            .query_map([entry.doc_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entry)
+    }
+}
+
+fn read_entry(row: &Row) -> rusqlite::Result<CatalogEntry> {
+    let digest: Option<Vec<u8>> = row.get(5)?;
+    Ok(CatalogEntry {
+        doc_id: row.get(0)?,
+        metadata: BTreeMap::new(),
+        database_bytes: row.get(1)?,
+        journal_bytes: row.get(2)?,
+        last_lsn: row.get(3)?,
+        modified_ms: row.get(4)?,
+        reducer_digest: digest.and_then(|d| d.try_into().ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rusqlite::Connection;
+
+    use super::{Catalog, CatalogEntry, CatalogQuery};
+    use crate::JournalId;
+
+    fn entry(id: u8, last_lsn: Option<u64>, modified_ms: i64) -> CatalogEntry {
+        CatalogEntry {
+            doc_id: JournalId::Size128([id; 16]),
+            metadata: BTreeMap::new(),
+            database_bytes: id as u64 * 4096,
+            journal_bytes: id as u64 * 8192,
+            last_lsn,
+            modified_ms,
+            reducer_digest: Some([id % 2; 32]),
+        }
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lists_entries_by_page() {
+        let mut catalog =
+            Catalog::open(Connection::open_in_memory().unwrap()).unwrap();
+        for id in 1..=5 {
+            catalog.upsert(&entry(id, Some(0), 100)).unwrap();
+        }
+        let mut seen = vec![];
+        let mut query = CatalogQuery { limit: Some(2), ..Default::default() };
+        loop {
+            let page = catalog.list(&query).unwrap();
+            seen.extend(page.entries.iter().map(|e| e.doc_id));
+            match page.next_cursor {
+                Some(_) => query.cursor = page.next_cursor,
+                None => break,
+            }
+        }
+        let all: Vec<JournalId> =
+            (1..=5).map(|id| entry(id, None, 0).doc_id).collect();
+        assert_eq!(seen, all);
+    }
+
+    #[test]
+    fn filters_entries() {
+        let mut catalog =
+            Catalog::open(Connection::open_in_memory().unwrap()).unwrap();
+        for id in 1..=4 {
+            catalog.upsert(&entry(id, Some(0), 100)).unwrap();
+        }
+        let doc = |id| entry(id, None, 0).doc_id;
+        assert!(catalog
+            .set_metadata(doc(2), &metadata(&[("team", "a")]))
+            .unwrap());
+        assert!(catalog
+            .set_metadata(doc(3), &metadata(&[("team", "a"), ("tier", "pro")]))
+            .unwrap());
+        assert!(!catalog
+            .set_metadata(doc(9), &metadata(&[("team", "a")]))
+            .unwrap());
+
+        let ids = |query: CatalogQuery| -> Vec<JournalId> {
+            let page = catalog.list(&query).unwrap();
+            page.entries.iter().map(|e| e.doc_id).collect()
+        };
+        assert_eq!(
+            ids(CatalogQuery {
+                metadata: metadata(&[("team", "a")]),
+                ..Default::default()
+            }),
+            vec![doc(2), doc(3)]
+        );
+        assert_eq!(
+            ids(CatalogQuery {
+                metadata: metadata(&[("team", "a"), ("tier", "pro")]),
+                ..Default::default()
+            }),
+            vec![doc(3)]
+        );
+        assert_eq!(
+            ids(CatalogQuery {
+                reducer_digest: Some([0; 32]),
+                min_database_bytes: Some(3 * 4096),
+                ..Default::default()
+            }),
+            vec![doc(4)]
+        );
+
+        // only a new lsn moves modified_ms
+        catalog.upsert(&entry(1, Some(0), 200)).unwrap();
+        catalog.upsert(&entry(2, Some(1), 200)).unwrap();
+        assert_eq!(
+            ids(CatalogQuery {
+                modified_after_ms: Some(100),
+                ..Default::default()
+            }),
+            vec![doc(2)]
+        );
+
+        let entry = catalog.get(doc(3)).unwrap().unwrap();
+        assert_eq!(entry.metadata.get("tier").map(|s| s.as_str()), Some("pro"));
+
+        // a restored entry keeps its metadata and modified_ms
+        let mut restored =
+            Catalog::open(Connection::open_in_memory().unwrap()).unwrap();
+        restored.restore(&entry).unwrap();
+        assert_eq!(restored.get(doc(3)).unwrap(), Some(entry));
+
+        assert!(catalog.remove(doc(3)).unwrap());
+        assert_eq!(catalog.get(doc(3)).unwrap(), None);
+        assert!(!catalog.remove(doc(3)).unwrap());
+    }
+}
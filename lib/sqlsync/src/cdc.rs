@@ -0,0 +1,226 @@
+//! Change data capture for coordinators. Once enabled, every step records
+//! the mutations it applied as a ChangeBatch in a change log journal which
+//! external systems (Kafka, NATS, webhooks, ...) consume.
+//!
+//! Delivery is at-least-once: a batch is appended to the change log before
+//! the storage frame containing its changes is committed, and stays in the
+//! log until the consumer acks it. The log's first lsn is therefore the
+//! consumer's cursor, and persists along with the log journal. A crash may
+//! redeliver batches, or rarely deliver a batch whose changes never reached
+//! storage; consumers which care can compare ChangeBatch::storage_lsn with
+//! the document's storage range.
+
+use std::io::{self, Read, Seek, Write};
+
+use crate::{
+    journal::{Journal, JournalId},
+    positioned_io::{PositionedCursor, PositionedReader},
+    timeline::FrameMeta,
+    Deserializable, JournalResult, Lsn, Serializable,
+};
+
+/// a mutation applied by the coordinator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub timeline_id: JournalId,
+    pub timeline_lsn: Lsn,
+    pub mutation: Vec<u8>,
+    /// the timestamp and tag the client attached to the mutation
+    pub meta: FrameMeta,
+}
+
+/// the changes applied by a single coordinator step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeBatch {
+    /// the storage lsn these changes are committed in. applying a mutation
+    /// always advances its timeline's watermark, so every batch has a frame
+    pub storage_lsn: Lsn,
+    pub changes: Vec<Change>,
+}
+
+/// Binary layout of a ChangeBatch is:
+/// storage_lsn: u64
+/// num_changes: u32
+/// for each change [
+///   timeline_id_len: u8
+///   timeline_id: [u8; timeline_id_len]
+///   timeline_lsn: u64
+///   mutation_len: u32
+///   mutation: [u8; mutation_len]
+/// ]
+/// for each change [
+///   meta: FrameMeta
+/// ]
+///
+/// the metadata follows the changes so that batches recorded before it was
+/// added can still be read, as changes with empty metadata
+impl Serializable for ChangeBatch {
+    fn serialize_into<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.storage_lsn.to_le_bytes())?;
+        writer.write_all(&(self.changes.len() as u32).to_le_bytes())?;
+        for change in &self.changes {
+            let id = change.timeline_id.bytes();
+            writer.write_all(&[id.len() as u8])?;
+            writer.write_all(id)?;
+            writer.write_all(&change.timeline_lsn.to_le_bytes())?;
+            writer.write_all(&(change.mutation.len() as u32).to_le_bytes())?;
+            writer.write_all(&change.mutation)?;
+        }
+        for change in &self.changes {
+            change.meta.serialize_into(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserializable for ChangeBatch {
+    fn deserialize_from<R: PositionedReader>(reader: R) -> io::Result<Self> {
+        let mut reader = PositionedCursor::new(reader);
+
+        let storage_lsn = read_u64(&mut reader)?;
+        let num_changes = read_u32(&mut reader)?;
+        let mut changes = Vec::with_capacity(num_changes as usize);
+        for _ in 0..num_changes {
+            let mut id_len = [0; 1];
+            reader.read_exact(&mut id_len)?;
+            let mut id = vec![0; id_len[0] as usize];
+            reader.read_exact(&mut id)?;
+            let timeline_id = JournalId::try_from(id)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let timeline_lsn = read_u64(&mut reader)?;
+            let mut mutation = vec![0; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut mutation)?;
+            changes.push(Change {
+                timeline_id,
+                timeline_lsn,
+                mutation,
+                meta: FrameMeta::default(),
+            });
+        }
+        if reader.stream_position()? < reader.size()? as u64 {
+            for change in changes.iter_mut() {
+                change.meta = FrameMeta::deserialize_from(&mut reader)?;
+            }
+        }
+
+        Ok(Self { storage_lsn, changes })
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// ChangeLog is the journal of ChangeBatches which have not been acked yet
+pub struct ChangeLog<J: Journal> {
+    journal: J,
+}
+
+impl<J: Journal> ChangeLog<J> {
+    pub fn new(journal: J) -> Self {
+        Self { journal }
+    }
+
+    pub(crate) fn record(&mut self, batch: ChangeBatch) -> JournalResult<()> {
+        self.journal.append(batch)?;
+        // the batch must be durable before the changes are
+        self.journal.sync()
+    }
+
+    /// read up to limit unacked batches, oldest first
+    pub fn pending(&self, limit: usize) -> io::Result<Vec<(Lsn, ChangeBatch)>> {
+        let mut out = Vec::new();
+        let mut cursor = self.journal.scan();
+        while out.len() < limit && cursor.advance()? {
+            let lsn = cursor.lsn().expect("cursor must have an lsn");
+            out.push((lsn, ChangeBatch::deserialize_from(&cursor)?));
+        }
+        Ok(out)
+    }
+
+    /// ack every batch up to and including lsn; acked batches are dropped
+    /// from the log and won't be delivered again
+    pub fn ack(&mut self, up_to: Lsn) -> JournalResult<()> {
+        self.journal.drop_prefix(up_to)?;
+        self.journal.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, ChangeBatch, ChangeLog};
+    use crate::{
+        timeline::FrameMeta, Deserializable, JournalId, MemoryJournal,
+        Serializable,
+    };
+
+    fn batch(storage_lsn: u64) -> ChangeBatch {
+        let timeline_id = JournalId::new128(&mut rand::thread_rng());
+        ChangeBatch {
+            storage_lsn,
+            changes: vec![
+                Change {
+                    timeline_id,
+                    timeline_lsn: 3,
+                    mutation: vec![1, 2],
+                    meta: FrameMeta { timestamp_ms: Some(1700), tag: Some(7) },
+                },
+                Change {
+                    timeline_id,
+                    timeline_lsn: 4,
+                    mutation: vec![],
+                    meta: FrameMeta::default(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn reads_batches_without_metadata() {
+        let mut batch = batch(0);
+        let mut bytes = batch.to_vec().unwrap();
+        // drop the metadata section, as batches recorded before it have none
+        bytes.truncate(bytes.len() - 1 - (1 + 8 + 4));
+        batch.changes[0].meta = FrameMeta::default();
+        assert_eq!(ChangeBatch::deserialize_from(&bytes[..]).unwrap(), batch);
+    }
+
+    #[test]
+    fn truncated_batches_never_decode() {
+        let bytes = batch(0).to_vec().unwrap();
+        let without_meta = bytes.len() - 1 - (1 + 8 + 4);
+        for len in 0..bytes.len() {
+            // except where the metadata section starts, see above
+            let decoded = ChangeBatch::deserialize_from(&bytes[..len]);
+            assert_eq!(decoded.is_ok(), len == without_meta, "at {}", len);
+        }
+    }
+
+    #[test]
+    fn redelivers_until_acked() {
+        let journal =
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap();
+        let mut log = ChangeLog::new(journal);
+        let (first, second) = (batch(0), batch(1));
+        log.record(first.clone()).unwrap();
+        log.record(second.clone()).unwrap();
+
+        assert_eq!(log.pending(1).unwrap(), vec![(0, first.clone())]);
+        // nothing was acked, so the same batch is delivered again
+        assert_eq!(log.pending(1).unwrap(), vec![(0, first)]);
+
+        log.ack(0).unwrap();
+        assert_eq!(log.pending(10).unwrap(), vec![(1, second)]);
+        log.ack(1).unwrap();
+        assert!(log.pending(10).unwrap().is_empty());
+    }
+}
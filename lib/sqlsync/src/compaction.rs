@@ -0,0 +1,441 @@
+//! Compaction keeps a coordinator's storage journal from growing forever by
+//! folding its oldest frames into a single frame which holds every page as of
+//! the last folded lsn (see CoordinatorDocument::compact). A Compactor decides
+//! when to compact by consulting its policies, and never folds past a limit
+//! chosen by the host; CoordinatorServer::compaction_limit is the lowest lsn
+//! every connected client has acked.
+//!
+//! Compactors have no io and never read the clock, like the rest of the
+//! server side. A CoordinatorServer polls its Compactor when the host calls
+//! CoordinatorServer::compact, and only once the document's receive queue
+//! is empty, so compaction runs between mutations and never holds up
+//! replication. Clients which were disconnected while the frames they needed
+//! were folded can't catch up frame by frame, so they are reset (see
+//! ReplicationMsg::Reset) and start over from the folded frame.
+//!
+//! A RetentionPolicy keeps history addressable for audit and time travel
+//! (see CoordinatorDocument::query_at) by holding back the fold limit, and
+//! keeps checkpoints of the oldest retained lsn as frames are folded away.
+
+use std::{collections::BTreeMap, fmt::Debug, time::Duration};
+
+use crate::{
+    coordinator::CoordinatorDocument,
+    error::Result,
+    replication::{ReplicationDestination, ReplicationSource},
+    snapshot::Checkpoint,
+    Journal, JournalError, Lsn, LsnRange,
+};
+
+/// what a CompactionPolicy knows about a document's storage journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionState {
+    /// frames in the storage journal
+    pub frames: usize,
+    /// the size of every frame in the storage journal
+    pub bytes: u64,
+    /// when the oldest frame which hasn't been folded was first observed
+    pub oldest_ms: Option<i64>,
+    /// when the storage journal last grew
+    pub last_write_ms: Option<i64>,
+    pub now_ms: i64,
+}
+
+pub trait CompactionPolicy: Debug + Send {
+    fn should_compact(&self, state: &CompactionState) -> bool;
+
+    /// when should_compact will next return true if nothing is written in
+    /// the meantime, for policies which depend on time
+    fn deadline(&self, _state: &CompactionState) -> Option<i64> {
+        None
+    }
+}
+
+/// compact once storage holds more than this many frames
+#[derive(Debug, Clone, Copy)]
+pub struct FrameThreshold(pub usize);
+
+impl CompactionPolicy for FrameThreshold {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        state.frames > self.0
+    }
+}
+
+/// compact once storage holds more than this many bytes
+#[derive(Debug, Clone, Copy)]
+pub struct ByteThreshold(pub u64);
+
+impl CompactionPolicy for ByteThreshold {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        state.bytes > self.0
+    }
+}
+
+/// compact once a frame has gone this long without being folded
+#[derive(Debug, Clone, Copy)]
+pub struct MaxAge(pub Duration);
+
+impl CompactionPolicy for MaxAge {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        self.deadline(state).is_some_and(|at| state.now_ms >= at)
+    }
+
+    fn deadline(&self, state: &CompactionState) -> Option<i64> {
+        Some(state.oldest_ms? + self.0.as_millis() as i64)
+    }
+}
+
+/// compact once nothing has been written for this long, so that busy
+/// documents are compacted during quiet periods
+#[derive(Debug, Clone, Copy)]
+pub struct IdleTime(pub Duration);
+
+impl CompactionPolicy for IdleTime {
+    fn should_compact(&self, state: &CompactionState) -> bool {
+        self.deadline(state).is_some_and(|at| state.now_ms >= at)
+    }
+
+    fn deadline(&self, state: &CompactionState) -> Option<i64> {
+        state.oldest_ms?;
+        Some(state.last_write_ms? + self.0.as_millis() as i64)
+    }
+}
+
+/// how much of a document's history a Compactor keeps addressable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// never fold frames which were first observed less than this long ago
+    pub keep_history: Option<Duration>,
+    /// how many checkpoints to keep of the oldest lsn each compaction
+    /// folds away
+    pub keep_checkpoints: usize,
+}
+
+/// the history of a document which can still be read, see
+/// Compactor::addressable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressableHistory {
+    /// every storage lsn in this range can be queried, see
+    /// CoordinatorDocument::query_at
+    pub range: LsnRange,
+    /// the lsns of the retained checkpoints before range, oldest first
+    pub checkpoints: Vec<Lsn>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionMetrics {
+    /// compactions which folded at least one frame
+    pub runs: u64,
+    /// times a policy asked for compaction but the limit didn't allow any
+    /// frames to be folded
+    pub blocked: u64,
+    pub frames_removed: u64,
+    /// how much smaller storage got, summed over every run
+    pub bytes_reclaimed: u64,
+    pub last_run_ms: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct Compactor {
+    policies: Vec<Box<dyn CompactionPolicy>>,
+    min_interval: Duration,
+    /// the size of each storage frame and when it was first observed
+    frames: BTreeMap<Lsn, (u64, i64)>,
+    /// the frame holding the result of the last compaction
+    folded: Option<Lsn>,
+    last_write_ms: Option<i64>,
+    last_attempt_ms: Option<i64>,
+    retention: RetentionPolicy,
+    /// oldest first, at most retention.keep_checkpoints
+    checkpoints: Vec<Checkpoint>,
+    metrics: CompactionMetrics,
+}
+
+impl Compactor {
+    /// compact whenever any of the policies asks to, but no more than once
+    /// every ten seconds
+    pub fn new(policies: Vec<Box<dyn CompactionPolicy>>) -> Self {
+        Self {
+            policies,
+            min_interval: Duration::from_secs(10),
+            frames: BTreeMap::new(),
+            folded: None,
+            last_write_ms: None,
+            last_attempt_ms: None,
+            retention: RetentionPolicy::default(),
+            checkpoints: Vec::new(),
+            metrics: CompactionMetrics::default(),
+        }
+    }
+
+    pub fn set_min_interval(&mut self, min_interval: Duration) {
+        self.min_interval = min_interval;
+    }
+
+    pub fn retention(&self) -> RetentionPolicy {
+        self.retention
+    }
+
+    /// checkpoints beyond the new keep_checkpoints are dropped, oldest first
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+        self.trim_checkpoints();
+    }
+
+    /// the retained checkpoints, oldest first
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// forget the storage frames measured so far and the retained
+    /// checkpoints, which still hold redacted rows once the document has
+    /// been redacted (see CoordinatorDocument::redact)
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.folded = None;
+        self.checkpoints.clear();
+    }
+
+    /// the history of doc which can still be read, either directly or from a
+    /// retained checkpoint
+    pub fn addressable<J: Journal>(
+        &self,
+        doc: &CoordinatorDocument<J>,
+    ) -> AddressableHistory {
+        AddressableHistory {
+            range: doc.history_range(),
+            checkpoints: self.checkpoints.iter().map(|c| c.lsn).collect(),
+        }
+    }
+
+    pub fn metrics(&self) -> CompactionMetrics {
+        self.metrics
+    }
+
+    pub fn state(&self, now_ms: i64) -> CompactionState {
+        CompactionState {
+            frames: self.frames.len(),
+            bytes: self.frames.values().map(|(bytes, _)| bytes).sum(),
+            oldest_ms: self
+                .frames
+                .iter()
+                .find(|(lsn, _)| Some(**lsn) != self.folded)
+                .map(|(_, (_, seen_ms))| *seen_ms),
+            last_write_ms: self.last_write_ms,
+            now_ms,
+        }
+    }
+
+    /// unix milliseconds at which poll should next be called, if no frames
+    /// are written in the meantime
+    pub fn deadline(&self, now_ms: i64) -> Option<i64> {
+        let state = self.state(now_ms);
+        let due = if self.policies.iter().any(|p| p.should_compact(&state)) {
+            Some(now_ms)
+        } else {
+            self.policies
+                .iter()
+                .filter_map(|p| p.deadline(&state))
+                .min()
+        }?;
+        let earliest = self
+            .last_attempt_ms
+            .map_or(due, |at| at + self.min_interval.as_millis() as i64);
+        Some(due.max(earliest))
+    }
+
+    /// compact doc if a policy asks to, folding no further than limit.
+    /// returns the number of frames removed
+    pub fn poll<J>(
+        &mut self,
+        doc: &mut CoordinatorDocument<J>,
+        limit: Option<Lsn>,
+        now_ms: i64,
+    ) -> Result<usize>
+    where
+        J: Journal + ReplicationSource + ReplicationDestination,
+    {
+        if doc.has_pending_work() {
+            return Ok(0);
+        }
+        self.observe(doc, now_ms)?;
+
+        if let Some(at) = self.last_attempt_ms {
+            if now_ms < at + self.min_interval.as_millis() as i64 {
+                return Ok(0);
+            }
+        }
+        let state = self.state(now_ms);
+        if !self.policies.iter().any(|p| p.should_compact(&state)) {
+            return Ok(0);
+        }
+        self.last_attempt_ms = Some(now_ms);
+
+        let limit = match (limit, self.retention_limit(now_ms)) {
+            (Some(limit), Some(retained)) => Some(limit.min(retained)),
+            (limit, _) => limit,
+        };
+        // the oldest lsn is about to be folded away
+        let oldest = match (doc.history_range().first(), limit) {
+            (Some(first), Some(up_to))
+                if self.retention.keep_checkpoints > 0 && up_to > first =>
+            {
+                doc.checkpoint_at(first)?
+            }
+            _ => None,
+        };
+        let removed = match limit {
+            Some(up_to) => doc.compact(up_to)?,
+            None => 0,
+        };
+        if removed == 0 {
+            self.metrics.blocked += 1;
+            return Ok(0);
+        }
+        if let Some(checkpoint) = oldest {
+            self.checkpoints.push(checkpoint);
+            self.trim_checkpoints();
+        }
+
+        // the frames before the folded one are gone, and it has to be
+        // measured again
+        if let LsnRange::NonEmpty { first, .. } = doc.source_range() {
+            self.frames = self.frames.split_off(&first);
+            self.frames.remove(&first);
+            if let Some(frame) = doc.storage_frames(first).next() {
+                let len = frame.map_err(JournalError::from)?.len();
+                self.frames.insert(first, (len as u64, now_ms));
+            }
+            self.folded = Some(first);
+        }
+        let bytes = self.state(now_ms).bytes;
+
+        self.metrics.runs += 1;
+        self.metrics.frames_removed += removed as u64;
+        self.metrics.bytes_reclaimed += state.bytes.saturating_sub(bytes);
+        self.metrics.last_run_ms = Some(now_ms);
+        Ok(removed)
+    }
+
+    /// the highest lsn which may be folded without dropping history that
+    /// retention keeps: frames observed within keep_history stay queryable
+    /// as long as the fold stops at the first of them
+    fn retention_limit(&self, now_ms: i64) -> Option<Lsn> {
+        let keep = self.retention.keep_history?;
+        let cutoff = now_ms - keep.as_millis() as i64;
+        self.frames
+            .iter()
+            .filter(|(lsn, _)| Some(**lsn) != self.folded)
+            .find(|(_, (_, seen_ms))| *seen_ms > cutoff)
+            .map(|(lsn, _)| *lsn)
+    }
+
+    fn trim_checkpoints(&mut self) {
+        let excess = self
+            .checkpoints
+            .len()
+            .saturating_sub(self.retention.keep_checkpoints);
+        self.checkpoints.drain(..excess);
+    }
+
+    /// measure any frames which have been written since the last call
+    fn observe<J>(
+        &mut self,
+        doc: &CoordinatorDocument<J>,
+        now_ms: i64,
+    ) -> Result<()>
+    where
+        J: Journal + ReplicationSource,
+    {
+        let from = self.frames.last_key_value().map_or(0, |(lsn, _)| lsn + 1);
+        for frame in doc.storage_frames(from) {
+            let frame = frame.map_err(JournalError::from)?;
+            self.frames
+                .insert(frame.lsn(), (frame.len() as u64, now_ms));
+            self.last_write_ms = Some(now_ms);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        CompactionPolicy, CompactionState, Compactor, FrameThreshold, IdleTime,
+        MaxAge, RetentionPolicy,
+    };
+    use crate::{
+        page::{SparsePages, PAGESIZE},
+        storage::Storage,
+        Journal, JournalId, LsnRange, MemoryJournal,
+    };
+
+    #[test]
+    fn policies() {
+        let state = CompactionState {
+            frames: 10,
+            bytes: 1000,
+            oldest_ms: Some(100),
+            last_write_ms: Some(500),
+            now_ms: 1000,
+        };
+        assert!(FrameThreshold(9).should_compact(&state));
+        assert!(!FrameThreshold(10).should_compact(&state));
+
+        let age = MaxAge(Duration::from_millis(1000));
+        assert!(!age.should_compact(&state));
+        assert_eq!(age.deadline(&state), Some(1100));
+
+        let idle = IdleTime(Duration::from_millis(400));
+        assert!(idle.should_compact(&state));
+        // nothing to fold once every frame has been folded
+        let folded = CompactionState { oldest_ms: None, ..state };
+        assert!(!idle.should_compact(&folded));
+    }
+
+    #[test]
+    fn retention_holds_back_recent_frames() {
+        let mut compactor = Compactor::new(vec![]);
+        compactor.set_retention(RetentionPolicy {
+            keep_history: Some(Duration::from_millis(100)),
+            keep_checkpoints: 0,
+        });
+        for (lsn, seen_ms) in [(0, 0), (1, 50), (2, 200)] {
+            compactor.frames.insert(lsn, (1, seen_ms));
+        }
+        assert_eq!(compactor.retention_limit(120), Some(1));
+        assert_eq!(compactor.retention_limit(400), None);
+
+        // the folded frame was rewritten recently, but holds older history
+        compactor.folded = Some(1);
+        compactor.frames.insert(1, (1, 300));
+        assert_eq!(compactor.retention_limit(250), Some(2));
+    }
+
+    #[test]
+    fn folds_storage_frames() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = MemoryJournal::open(id).unwrap();
+        for page_idxs in [&[1, 2][..], &[2], &[3]] {
+            let mut pages = SparsePages::new();
+            for &page_idx in page_idxs {
+                pages.write(page_idx, [journal.range().len() as u8; PAGESIZE]);
+            }
+            journal.append(pages).unwrap();
+        }
+        let mut storage = Storage::new(journal);
+        let before = storage.snapshot().unwrap();
+
+        assert_eq!(storage.compact(1).unwrap(), 1);
+        assert_eq!(storage.visible_range(), LsnRange::new(1, 2));
+        let after = storage.snapshot().unwrap();
+        for page_idx in 1..=3 {
+            assert_eq!(before.get(page_idx), after.get(page_idx));
+        }
+
+        // there is nothing before the folded frame left to fold
+        assert_eq!(storage.compact(1).unwrap(), 0);
+    }
+}
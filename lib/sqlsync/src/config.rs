@@ -0,0 +1,27 @@
+//! DocumentConfig is policy which the coordinator pushes to its clients
+//! during the replication handshake, so that changing it doesn't require
+//! redeploying clients.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentConfig {
+    /// a client only replaces its config with one of a higher version, so
+    /// versions should start at 1 and increase with every change
+    pub version: u64,
+    /// clients refuse to apply mutations larger than this, in bytes
+    pub max_mutation_size: Option<u64>,
+    /// clients compress mutations larger than this, in bytes, before they
+    /// enter the timeline and get replicated
+    pub compress_mutations_over: Option<u64>,
+    /// clients should compact their storage once it holds this many frames
+    pub compact_after_frames: Option<u64>,
+    /// optional protocol features the coordinator supports
+    pub capabilities: Vec<String>,
+}
+
+impl DocumentConfig {
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|c| c == name)
+    }
+}
@@ -1,32 +1,125 @@
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::db::{open_with_vfs, ConnectionPair};
-use crate::error::Result;
-use crate::reducer::Reducer;
+use rusqlite::Connection;
+
+use crate::cdc::{Change, ChangeBatch, ChangeLog};
+use crate::config::DocumentConfig;
+use crate::db::{
+    checkpoint_wal, open_with_storage, register_clock, set_cache_size,
+    set_journal_mode, set_max_size, CacheSize, ConnectionPair, JournalMode,
+};
+use crate::effects::{ack_mutation, existing_effects, pending_effects, Effect};
+use crate::error::{Error, Result};
+use crate::hints::MutationHints;
+use crate::logging::{self, GuestLogConfig};
+use crate::order::{
+    read_apply_order, write_apply_order, ApplyOrder, QueueWait, ReceiveQueue,
+    ReceiveQueueEntry,
+};
+use crate::page::SparsePages;
+use crate::positioned_io::PositionedReader;
+use crate::quota::{QuotaExceeded, QuotaHook, QuotaKind, StorageQuota};
+use crate::redaction::{self, read_epoch};
+use crate::reducer::{MemoryStats, ModuleDigest, Reducer, ReducerPool};
 use crate::replication::{ReplicationDestination, ReplicationError, ReplicationSource};
-use crate::timeline::{apply_timeline_range, run_timeline_migration};
+use crate::resync::{PageTree, TreeNode};
+use crate::schedule::{read_schedule, schedule_mutation, Schedule, ScheduledTask};
+use crate::snapshot::{Checkpoint, Snapshot};
+use crate::stats::{page_stats, PageStats};
+use crate::timeline::{
+    applied_lsn, apply_timeline_range_until, cancelled_frame, observes_token,
+    run_timeline_migration, task_mutation, verify_commuting, Codec, ConsistencyToken, FrameKind, FrameMeta, TimelineError, TimelineFrame,
+};
+use crate::ttl::{expiry_mutation, has_expired_rows};
+use crate::unixtime::{unix_timestamp_milliseconds, HlcTimestamp, HybridClock};
 use crate::{
-    journal::{Journal, JournalFactory, JournalId},
+    journal::{Frames, Journal, JournalFactory, JournalId},
     lsn::LsnRange,
-    storage::Storage,
+    storage::{Durability, Storage},
 };
-use crate::{JournalError, Lsn};
+use crate::{JournalError, Lsn, Serializable, TypedMutation, PAGESIZE};
+
+/// the timeline mutations submitted by the coordinator itself are authored
+/// by; clients generate random ids so they never collide with it
+pub const SERVER_TIMELINE_ID: JournalId = JournalId::Size128([0; 16]);
 
-struct ReceiveQueueEntry {
-    id: JournalId,
-    range: LsnRange,
+/// CreateDocumentOptions seed a new document so that clients never race to
+/// initialize it
+#[derive(Debug, Default)]
+pub struct CreateDocumentOptions {
+    /// start from a copy of another document, see CoordinatorDocument::snapshot
+    pub template: Option<Snapshot>,
+    /// a mutation applied (as a server mutation) before the document is
+    /// returned, e.g. to create its schema
+    pub init_mutation: Option<Vec<u8>>,
+}
+
+/// CoordinatorMetrics summarizes how much load a document puts on its host,
+/// see CoordinatorDocument::metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoordinatorMetrics {
+    /// frames in the storage journal
+    pub storage_frames: usize,
+    /// timelines with mutations waiting to be applied
+    pub pending_timelines: usize,
+    /// mutations waiting to be applied
+    pub pending_mutations: usize,
+    /// the most mutations waiting to be applied from any one timeline
+    pub max_timeline_depth: usize,
+    /// how long the oldest pending mutation has been waiting
+    pub oldest_wait: Duration,
+    /// how long applied mutations waited to be applied, since the document
+    /// was opened
+    pub apply_wait: QueueWait,
+    pub reducer: MemoryStats,
 }
 
 pub struct CoordinatorDocument<J: Journal> {
     reducer: Reducer,
-    storage: Box<Storage<J>>,
+    reducer_digest: Option<ModuleDigest>,
+    // the connections read through storage, so they're dropped first
     sqlite: ConnectionPair,
+    storage: Box<Storage<J>>,
     timeline_factory: J::Factory,
     timelines: HashMap<JournalId, J>,
-    timeline_receive_queue: VecDeque<ReceiveQueueEntry>,
+    timeline_receive_queue: ReceiveQueue,
+    clock: Arc<Mutex<HybridClock>>,
+    changes: Option<ChangeLog<J>>,
+    schedule: Schedule,
+    /// effects which have been acked by a mutation that hasn't been applied
+    acked_effects: HashSet<i64>,
+    config: DocumentConfig,
+    cache_size: CacheSize,
+    // the cache size currently applied to sqlite, in pages
+    cache_pages: u32,
+    // bumped by every redaction, see the redaction module
+    epoch: u64,
+    quota: StorageQuota,
+    quota_hook: Option<Box<dyn QuotaHook>>,
+    // the ranges of timelines whose mutations were rejected for exceeding
+    // the quota, waiting to be retried. see the quota module
+    over_quota: HashMap<JournalId, LsnRange>,
+    // rejections which haven't been taken by the host yet
+    rejections: Vec<QuotaExceeded>,
+    // a range whose reducer was preempted part way through a mutation, see
+    // step_with_budget
+    preempted: Option<Preempted>,
+}
+
+/// a range being applied in a transaction which is still open, because the
+/// reducer was preempted part way through a mutation
+#[derive(Debug)]
+struct Preempted {
+    entry: ReceiveQueueEntry,
+    // the range the transaction started with, which is what gets rejected
+    // if the transaction fails for lack of space
+    started: LsnRange,
+    changes: Vec<Change>,
 }
 
 impl<J: Journal> Debug for CoordinatorDocument<J> {
@@ -44,19 +137,171 @@ impl<J: Journal> CoordinatorDocument<J> {
         timeline_factory: J::Factory,
         reducer_wasm_bytes: &[u8],
     ) -> Result<Self> {
-        let (mut sqlite, storage) = open_with_vfs(storage)?;
+        let reducer = Reducer::new(reducer_wasm_bytes)?;
+        let mut doc =
+            Self::open_with_reducer(storage, timeline_factory, reducer)?;
+        doc.reducer_digest = Some(ReducerPool::digest(reducer_wasm_bytes));
+        Ok(doc)
+    }
+
+    /// create a new document in an empty storage journal, seeded according
+    /// to options. the seed is written as the journal's first frame, and
+    /// synced before this returns, so a create which fails part way leaves
+    /// the journal empty and can be retried
+    pub fn create(
+        storage: J,
+        timeline_factory: J::Factory,
+        reducer: Reducer,
+        options: CreateDocumentOptions,
+    ) -> Result<Self> {
+        if storage.range().is_non_empty() {
+            return Err(Error::DocumentExists(storage.id()));
+        }
+        // the template, the cleanup and the init mutation stay pending in
+        // storage until they are committed together
+        let mut storage = Box::new(Storage::new(storage));
+        let from_template = match options.template {
+            Some(template) if !template.is_empty() => {
+                storage.stage(&template.into_pages())?;
+                true
+            }
+            _ => false,
+        };
+
+        let mut doc = Self::open_storage(storage, timeline_factory, reducer)?;
+        if from_template {
+            doc.clear_bookkeeping()?;
+        }
+        if let Some(mutation) = options.init_mutation {
+            doc.mutate(&mutation)?;
+            // applying the mutation commits storage
+            while doc.has_pending_work() {
+                doc.step()?;
+            }
+        }
+        doc.commit_storage()?;
+        doc.storage.sync()?;
+        Ok(doc)
+    }
+
+    /// forget the template's timelines, effects, epoch, schedule and apply
+    /// order, none of which apply to the new document. what its reducer
+    /// declared along with the schema, the mutation hints and ttls, stays
+    fn clear_bookkeeping(&mut self) -> Result<()> {
+        let tables: Vec<String> = self
+            .sqlite
+            .readwrite
+            .prepare(
+                "SELECT name FROM sqlite_schema
+                WHERE type = 'table' AND name IN (
+                    '__sqlsync_timelines', '__sqlsync_idempotency_keys',
+                    '__sqlsync_cancelled', '__sqlsync_effects',
+                    '__sqlsync_epoch', '__sqlsync_schedule',
+                    '__sqlsync_apply_order'
+                )",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let txn = self.sqlite.readwrite.transaction()?;
+        for table in tables {
+            txn.execute(&format!("DELETE FROM {}", table), [])?;
+        }
+        txn.commit()?;
+
+        // and read back what the document starts with instead
+        let sqlite = &self.sqlite.readwrite;
+        self.epoch = read_epoch(sqlite)?;
+        self.schedule = read_schedule(sqlite)?;
+        self.timeline_receive_queue =
+            ReceiveQueue::new(read_apply_order(sqlite)?);
+        Ok(())
+    }
+
+    /// open a document with an existing reducer instance, typically
+    /// acquired from a ReducerPool
+    pub fn open_with_reducer(
+        storage: J,
+        timeline_factory: J::Factory,
+        reducer: Reducer,
+    ) -> Result<Self> {
+        let storage = Box::new(Storage::new(storage));
+        Self::open_storage(storage, timeline_factory, reducer)
+    }
+
+    fn open_storage(
+        storage: Box<Storage<J>>,
+        timeline_factory: J::Factory,
+        mut reducer: Reducer,
+    ) -> Result<Self> {
+        reducer.set_doc_id(storage.id());
+
+        let (mut sqlite, storage) = open_with_storage(storage)?;
 
         // TODO: this feels awkward here
         run_timeline_migration(&mut sqlite.readwrite)?;
+        let apply_order = read_apply_order(&sqlite.readwrite)?;
+        let epoch = read_epoch(&sqlite.readwrite)?;
+        let schedule = read_schedule(&sqlite.readwrite)?;
+
+        let clock = Arc::new(Mutex::new(HybridClock::default()));
+        register_clock(&sqlite.readwrite, clock.clone())?;
 
-        Ok(Self {
-            reducer: Reducer::new(reducer_wasm_bytes)?,
+        let mut doc = Self {
+            reducer_digest: reducer.module_digest(),
+            reducer,
             storage,
             sqlite,
             timeline_factory,
             timelines: HashMap::new(),
-            timeline_receive_queue: VecDeque::new(),
-        })
+            timeline_receive_queue: ReceiveQueue::new(apply_order),
+            clock,
+            changes: None,
+            schedule,
+            acked_effects: HashSet::new(),
+            config: DocumentConfig::default(),
+            cache_size: CacheSize::default(),
+            cache_pages: 0,
+            epoch,
+            quota: StorageQuota::default(),
+            quota_hook: None,
+            over_quota: HashMap::new(),
+            rejections: vec![],
+            preempted: None,
+        };
+        // storage holds uncommitted pages in memory either way, and a
+        // transaction left open by a preempted reducer mustn't expose them
+        // to queries
+        doc.sqlite
+            .readwrite
+            .pragma_update(None, "cache_spill", false)?;
+        doc.resize_cache()?;
+        Ok(doc)
+    }
+
+    /// the digest of the reducer's wasm, which clients are told about so
+    /// that they can tell when they run a different reducer
+    pub fn reducer_digest(&self) -> Option<ModuleDigest> {
+        self.reducer_digest
+    }
+
+    /// the version the reducer declared, see Reducer::version
+    pub fn reducer_version(&self) -> Option<u32> {
+        self.reducer.version()
+    }
+
+    /// set the reducer digest when the document was opened with a reducer
+    /// which didn't come from a ReducerPool
+    pub fn set_reducer_digest(&mut self, digest: ModuleDigest) {
+        self.reducer_digest = Some(digest);
+    }
+
+    /// close the document, returning its reducer so that it can be released
+    /// back into a ReducerPool. a mutation the reducer was preempted in is
+    /// rolled back, and applied again from the start when the document is
+    /// next opened
+    pub fn into_reducer(mut self) -> Reducer {
+        self.reducer.abandon();
+        self.reducer
     }
 
     fn get_or_create_timeline_mut(
@@ -69,55 +314,808 @@ impl<J: Journal> CoordinatorDocument<J> {
         }
     }
 
+    pub fn doc_id(&self) -> JournalId {
+        self.storage.id()
+    }
+
+    /// with Durability::Sync, each step syncs the storage journal before
+    /// the new frame is made visible to clients
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.storage.set_durability(durability);
+    }
+
+    /// record every applied mutation in log for change data capture. see
+    /// the cdc module for delivery guarantees
+    pub fn enable_change_log(&mut self, log: J) {
+        self.changes = Some(ChangeLog::new(log));
+    }
+
+    /// the change log, if enabled. consumers read pending batches from it
+    /// and ack them once delivered
+    pub fn change_log(&mut self) -> Option<&mut ChangeLog<J>> {
+        self.changes.as_mut()
+    }
+
+    /// a copy of the document's current state, for use as a template
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::new(self.storage.snapshot().map_err(JournalError::from)?))
+    }
+
+    /// a snapshot of the document along with the storage lsn it was taken
+    /// at, which cold clients can bootstrap from. None if the document has no
+    /// storage yet
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>> {
+        let Some(lsn) = self.storage.last_committed_lsn() else {
+            return Ok(None);
+        };
+        Ok(Some(Checkpoint { doc_id: self.doc_id(), lsn, snapshot: self.snapshot()? }))
+    }
+
+    /// a checkpoint as of a storage lsn within history_range, or None if
+    /// the lsn isn't retained
+    pub fn checkpoint_at(&self, lsn: Lsn) -> Result<Option<Checkpoint>> {
+        let pages =
+            self.storage.snapshot_at(lsn).map_err(JournalError::from)?;
+        Ok(pages.map(|pages| Checkpoint {
+            doc_id: self.doc_id(),
+            lsn,
+            snapshot: Snapshot::new(pages),
+        }))
+    }
+
+    /// refresh sqlite's query planner statistics (the sqlite_stat tables) and
+    /// commit them to storage. they replicate like any other page and are
+    /// included in checkpoints, so clients get good query plans without each
+    /// running ANALYZE. hosts typically call this before publishing a
+    /// checkpoint. returns true if the statistics changed
+    pub fn optimize(&mut self) -> Result<bool> {
+        self.finish_preempted()?;
+        let analyzed: bool = self.sqlite.readwrite.query_row(
+            "SELECT EXISTS (
+                SELECT 1 FROM sqlite_schema WHERE name = 'sqlite_stat1'
+            )",
+            [],
+            |row| row.get(0),
+        )?;
+        // once every table has been analyzed, optimize only re-analyzes the
+        // tables which have changed size significantly since
+        if analyzed {
+            self.sqlite.readwrite.execute_batch("PRAGMA optimize")?;
+        } else {
+            self.sqlite.readwrite.execute_batch("ANALYZE")?;
+        }
+
+        let before = self.storage.source_range().last();
+        self.commit_storage()?;
+        Ok(self.storage.source_range().last() != before)
+    }
+
+    /// a merkle tree over the document's pages, for clients to compare their
+    /// own storage against, see resync
+    pub fn page_tree(&self) -> Result<PageTree> {
+        let pages = self.storage.snapshot().map_err(JournalError::from)?;
+        Ok(PageTree::build(&pages))
+    }
+
+    /// a checkpoint holding only the pages in the given leaves of the page
+    /// tree, for a client to repair its storage with
+    pub fn repair_pages(&self, leaves: &[TreeNode]) -> Result<Checkpoint> {
+        let all = self.storage.snapshot().map_err(JournalError::from)?;
+        let mut pages = SparsePages::new();
+        for leaf in leaves {
+            for page_idx in leaf.pages() {
+                if let Some(page) = all.get(page_idx) {
+                    pages.write(page_idx, *page);
+                }
+            }
+        }
+        Ok(Checkpoint {
+            doc_id: self.doc_id(),
+            lsn: self.storage.last_committed_lsn().unwrap_or(0),
+            snapshot: Snapshot::new(pages),
+        })
+    }
+
+    /// pages, bytes and page writes in window per table and index
+    pub fn page_stats(&self, window: LsnRange) -> Result<PageStats> {
+        page_stats(&self.sqlite.readonly, &self.storage, window)
+    }
+
+    /// filter and rate limit the logs emitted by this document's reducer
+    pub fn set_guest_log_config(&mut self, config: GuestLogConfig) {
+        self.reducer.set_log_config(config);
+    }
+
+    /// iterate over the document's storage journal starting at from, for
+    /// tools which tail a coordinator without replicating from it
+    pub fn storage_frames(&self, from: Lsn) -> Frames<'_, J> {
+        self.storage.frames(from)
+    }
+
+    /// cap the size of the document in bytes. a mutation which would grow the
+    /// document beyond the cap fails to apply
+    pub fn set_max_size(&mut self, max_bytes: u64) -> Result<()> {
+        self.finish_preempted()?;
+        Ok(set_max_size(&self.sqlite.readwrite, &mut self.storage, max_bytes)?)
+    }
+
+    pub fn quota(&self) -> StorageQuota {
+        self.quota
+    }
+
+    /// the size of the document's database in bytes
+    pub fn database_size(&self) -> Result<u64> {
+        Ok(self.storage.num_pages()? as u64 * PAGESIZE as u64)
+    }
+
+    /// the size of the storage journal's frames in bytes, which
+    /// StorageQuota::max_journal_bytes limits
+    pub fn journal_size(&self) -> Result<u64> {
+        Ok(self.storage.journal_size().map_err(JournalError::from)?)
+    }
+
+    /// replace the document's storage quota, see the quota module. the
+    /// database limit replaces any cap set by set_max_size. rejected
+    /// mutations are retried under the new quota
+    pub fn set_quota(&mut self, quota: StorageQuota) -> Result<()> {
+        self.set_max_size(quota.max_database_bytes.unwrap_or(u64::MAX))?;
+        self.quota = quota;
+        self.retry_rejected();
+        Ok(())
+    }
+
+    /// call hook whenever a mutation is rejected for exceeding the quota
+    pub fn set_quota_hook(&mut self, hook: impl QuotaHook + 'static) {
+        self.quota_hook = Some(Box::new(hook));
+    }
+
+    /// the rejections since the last call, for the host to tell the
+    /// offending clients about
+    pub fn take_rejections(&mut self) -> Vec<QuotaExceeded> {
+        std::mem::take(&mut self.rejections)
+    }
+
+    /// the timelines whose mutations are waiting to be retried
+    pub fn rejected_timelines(&self) -> impl Iterator<Item = JournalId> + '_ {
+        self.over_quota.keys().copied()
+    }
+
+    /// queue rejected mutations to be applied again, once space has been
+    /// freed. they are rejected again if there still isn't room
+    pub fn retry_rejected(&mut self) {
+        for (id, range) in self.over_quota.drain() {
+            self.timeline_receive_queue
+                .requeue(ReceiveQueueEntry { id, range });
+        }
+    }
+
+    /// set a rejected range aside and report it
+    fn reject(
+        &mut self,
+        entry: ReceiveQueueEntry,
+        kind: QuotaKind,
+        limit: u64,
+        used: u64,
+    ) {
+        let event = QuotaExceeded {
+            doc: self.storage.id(),
+            timeline: entry.id,
+            kind,
+            limit,
+            used,
+        };
+        logging::warn!(doc = self.storage.id(); "rejected mutations from timeline {}: {}", entry.id, event);
+        if let Some(hook) = self.quota_hook.as_mut() {
+            hook.quota_exceeded(&event);
+        }
+        self.rejections.push(event);
+        self.park(entry);
+    }
+
+    /// add a range to its timeline's rejected range. later ranges of a
+    /// rejected timeline wait behind it, so that they apply in order
+    fn park(&mut self, entry: ReceiveQueueEntry) {
+        let parked = self.over_quota.entry(entry.id).or_insert(entry.range);
+        let last = parked.last().max(entry.range.last());
+        if let (Some(first), Some(last)) = (parked.first(), last) {
+            *parked = LsnRange::new(first, last);
+        }
+    }
+
+    /// cap the memory used by a WAL mode document's uncommitted and
+    /// uncheckpointed frames, which grow with the largest transaction. see
+    /// Storage::set_wal_memory_limit
+    pub fn set_wal_memory_limit(&mut self, max_bytes: usize) {
+        self.storage.set_wal_memory_limit(max_bytes);
+    }
+
+    /// size the page cache of each of the document's sqlite connections.
+    /// CacheSize::Adaptive follows the document as it grows
+    pub fn set_cache_size(&mut self, cache_size: CacheSize) -> Result<()> {
+        self.cache_size = cache_size;
+        self.resize_cache()
+    }
+
+    fn resize_cache(&mut self) -> Result<()> {
+        let pages = self.cache_size.pages(self.storage.num_pages()?);
+        if pages != self.cache_pages {
+            set_cache_size(&self.sqlite, pages)?;
+            self.cache_pages = pages;
+        }
+        Ok(())
+    }
+
+    /// checkpoint what sqlite has committed and commit it to storage. in WAL
+    /// mode frames behind a query are left for the next commit
+    fn commit_storage(&mut self) -> Result<()> {
+        self.finish_preempted()?;
+        checkpoint_wal(&self.sqlite.readwrite)?;
+        self.storage.commit()?;
+        Ok(())
+    }
+
+    /// switch sqlite's journal mode. the mode is recorded in the document's
+    /// first page, so clients switch too once they receive it
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> Result<()> {
+        self.finish_preempted()?;
+        set_journal_mode(&self.sqlite.readwrite, mode)?;
+        self.commit_storage()?;
+        Ok(())
+    }
+
+    /// how many times the document has been redacted. sent to clients as a
+    /// ReplicationMsg::Epoch during the handshake
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// the config sent to clients as a ReplicationMsg::Config during the
+    /// handshake
+    pub fn config(&self) -> &DocumentConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: DocumentConfig) {
+        self.config = config;
+    }
+
+    /// the next timestamp from this document's hybrid clock. coordinators
+    /// send this to clients as a ReplicationMsg::Clock during the handshake
+    pub fn now(&self) -> HlcTimestamp {
+        self.clock.lock().unwrap_or_else(|e| e.into_inner()).now()
+    }
+
+    /// run read-only queries against the document's current state, for
+    /// server rendering and background jobs which aren't sync clients.
+    /// mutations which haven't been stepped yet aren't visible
+    pub fn query<F, O, E>(&self, f: F) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Connection) -> std::result::Result<O, E>,
+        E: std::convert::From<rusqlite::Error>,
+    {
+        f(&self.sqlite.readonly)
+    }
+
+    /// the storage lsns which query_at can read the document as of. compact
+    /// drops the start of this range, see Storage::retained_range
+    pub fn history_range(&self) -> LsnRange {
+        self.storage.retained_range()
+    }
+
+    /// run read-only queries against the document as it was at a storage
+    /// lsn within history_range, for reports which must be consistent with
+    /// a point in time. the document is rebuilt from storage for each call,
+    /// in a vfs which is unregistered once f returns
+    pub fn query_at<F, O>(&self, lsn: Lsn, f: F) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<O>,
+    {
+        self.checkpoint_at(lsn)?
+            .ok_or(Error::LsnNotRetained {
+                lsn,
+                retained: self.history_range(),
+            })?
+            .query(f)
+    }
+
+    /// whether queries observe the mutation the token was returned for, see
+    /// LocalDocument::mutate
+    pub fn observes(&self, token: ConsistencyToken) -> Result<bool> {
+        Ok(observes_token(&self.sqlite.readonly, token)?)
+    }
+
+    /// step until queries observe the mutation the token was returned for.
+    /// returns false if the mutation hasn't been received yet, so there was
+    /// nothing to step
+    pub fn wait_for(&mut self, token: ConsistencyToken) -> Result<bool> {
+        while !self.observes(token)? {
+            let pending = self
+                .timeline_receive_queue
+                .timelines()
+                .any(|id| id == token.timeline_id);
+            if !pending {
+                return Ok(false);
+            }
+            self.step()?;
+        }
+        Ok(true)
+    }
+
+    /// run read-only queries once they observe the mutation the token was
+    /// returned for, stepping until they do (see wait_for). fails with
+    /// Error::NotObserved if the mutation hasn't been received yet
+    pub fn query_observing<F, O>(
+        &mut self,
+        token: ConsistencyToken,
+        f: F,
+    ) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<O>,
+    {
+        if !self.wait_for(token)? {
+            return Err(Error::NotObserved(token));
+        }
+        Ok(f(&self.sqlite.readonly)?)
+    }
+
+    /// submit a mutation on behalf of the server, e.g. from a scheduled job
+    /// or an external data feed. it is applied by the reducer during the next
+    /// step like any client mutation, and reaches clients as a normal storage
+    /// frame. returns the mutation's lsn in the server timeline
+    pub fn mutate(&mut self, mutation: &[u8]) -> Result<Lsn> {
+        let applied = self.applied_lsn(SERVER_TIMELINE_ID)?;
+        let timeline = self.get_or_create_timeline_mut(SERVER_TIMELINE_ID)?;
+
+        // the server timeline doesn't survive a restart; skip past the lsns
+        // which were already applied so new mutations aren't mistaken for
+        // retransmits
+        if let Some(applied) = applied {
+            if timeline.range().next() <= applied {
+                timeline.drop_prefix(applied)?;
+            }
+        }
+
+        let lsn = timeline.range().next();
+        timeline.append(TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta::default(),
+            codec: Codec::Raw,
+            mutation,
+        })?;
+        self.mark_received(SERVER_TIMELINE_ID, lsn);
+        Ok(lsn)
+    }
+
+    /// submit a server mutation declared with sqlsync::mutations!
+    pub fn mutate_typed<M: TypedMutation>(
+        &mut self,
+        mutation: &M,
+    ) -> Result<Lsn> {
+        self.mutate(&mutation.encode())
+    }
+
+    /// run the reducer task with the given name (see init_reducer!) as a
+    /// server mutation
+    pub fn run_task(&mut self, name: &str) -> Result<Lsn> {
+        self.mutate(&task_mutation(name))
+    }
+
+    /// queue a mutation which deletes every row whose ttl column (see the
+    /// ttl module) is at or before the current time. does nothing if no
+    /// rows have expired, so hosts can call it as often as they like
+    pub fn expire_rows(&mut self) -> Result<Option<Lsn>> {
+        let now = unix_timestamp_milliseconds();
+        if !has_expired_rows(&self.sqlite.readwrite, now)? {
+            return Ok(None);
+        }
+        let lsn = self.mutate(&expiry_mutation(now))?;
+        Ok(Some(lsn))
+    }
+
+    /// read up to limit effects which reducers have enqueued and no one has
+    /// acked yet, oldest first. see the effects module
+    pub fn pending_effects(&mut self, limit: usize) -> Result<Vec<Effect>> {
+        // once the receive queue is empty every ack has been applied, and
+        // the acked effects no longer exist
+        if !self.has_pending_work() {
+            self.acked_effects.clear();
+        }
+        let acked = &self.acked_effects;
+        Ok(pending_effects(&self.sqlite.readonly, limit, |id| {
+            acked.contains(&id)
+        })?)
+    }
+
+    /// mark effects as performed so that they are never delivered again.
+    /// acks are deduplicated by effect id: ids which were already acked or
+    /// aren't pending are ignored, so a consumer retrying an ack never
+    /// queues another mutation for it. returns the lsn of the ack mutation,
+    /// or None if there was nothing left to ack
+    pub fn ack_effects(&mut self, ids: &[i64]) -> Result<Option<Lsn>> {
+        let acked = &self.acked_effects;
+        let ids: Vec<_> =
+            ids.iter().copied().filter(|id| !acked.contains(id)).collect();
+        let ids = existing_effects(&self.sqlite.readonly, &ids)?;
+        if ids.is_empty() {
+            return Ok(None);
+        }
+        let lsn = self.mutate(&ack_mutation(&ids))?;
+        self.acked_effects.extend(ids);
+        Ok(Some(lsn))
+    }
+
+    /// replace the document's task schedule. the schedule is recorded by a
+    /// server mutation, so it survives the coordinator restarting once
+    /// that's applied
+    pub fn set_schedule(&mut self, tasks: Vec<ScheduledTask>) -> Result<Lsn> {
+        self.schedule = Schedule::new(tasks, unix_timestamp_milliseconds());
+        self.mutate(&schedule_mutation(&self.schedule))
+    }
+
+    /// the document's task schedule
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// when run_scheduled_tasks next has work to do
+    pub fn schedule_deadline(&self) -> Option<i64> {
+        self.schedule.deadline()
+    }
+
+    /// queue a run of every scheduled task which is due, returning how many
+    /// were queued. hosts call this periodically, then step as usual
+    pub fn run_scheduled_tasks(&mut self) -> Result<usize> {
+        let due = self.schedule.due(unix_timestamp_milliseconds());
+        for name in due.iter() {
+            logging::info!(doc = self.storage.id(); "running scheduled task {}", name);
+            self.run_task(name)?;
+        }
+        // record when the tasks are next due along with their runs
+        if !due.is_empty() {
+            self.mutate(&schedule_mutation(&self.schedule))?;
+        }
+        Ok(due.len())
+    }
+
+    fn applied_lsn(&self, id: JournalId) -> std::result::Result<Option<Lsn>, ReplicationError> {
+        applied_lsn(&self.sqlite.readwrite, id).map_err(|e| {
+            ReplicationError::Io(io::Error::new(io::ErrorKind::Other, e))
+        })
+    }
+
+    pub fn metrics(&self) -> CoordinatorMetrics {
+        let pending: HashSet<JournalId> =
+            self.timeline_receive_queue.timelines().collect();
+        CoordinatorMetrics {
+            storage_frames: self.storage.source_range().len(),
+            pending_timelines: pending.len(),
+            pending_mutations: self.timeline_receive_queue.depth(),
+            max_timeline_depth: self.timeline_receive_queue.max_timeline_depth(),
+            oldest_wait: self
+                .timeline_receive_queue
+                .oldest_wait(unix_timestamp_milliseconds()),
+            apply_wait: self.timeline_receive_queue.wait(),
+            reducer: self.reducer.memory_stats(),
+        }
+    }
+
     pub fn has_pending_work(&self) -> bool {
-        !self.timeline_receive_queue.is_empty()
+        self.preempted.is_some() || !self.timeline_receive_queue.is_empty()
+    }
+
+    /// finish the mutation a reducer was preempted in, so that the sqlite
+    /// connection can be used for something else
+    fn finish_preempted(&mut self) -> Result<()> {
+        while self.preempted.is_some() {
+            self.step()?;
+        }
+        Ok(())
     }
 
     fn mark_received(&mut self, id: JournalId, lsn: Lsn) {
-        match self.timeline_receive_queue.back_mut() {
-            // coalesce this update if the queue already ends with an entry for this journal
-            Some(entry) if entry.id == id => {
-                if !entry.range.contains(lsn) {
-                    entry.range = entry.range.append(lsn)
+        self.timeline_receive_queue
+            .push(id, lsn, unix_timestamp_milliseconds());
+    }
+
+    /// give the timeline weight turns for every turn of other timelines
+    /// under ApplyOrder::RoundRobin, e.g. for clients on a higher plan.
+    /// weights default to 1 and aren't persisted
+    pub fn set_timeline_weight(&mut self, id: JournalId, weight: u32) {
+        self.timeline_receive_queue.set_weight(id, weight);
+    }
+
+    /// how mutations from different timelines are interleaved, see the
+    /// order module
+    pub fn apply_order(&self) -> ApplyOrder {
+        self.timeline_receive_queue.order()
+    }
+
+    /// change how mutations from different timelines are interleaved,
+    /// including those already received. the order is committed to storage
+    /// so that it survives restarts
+    pub fn set_apply_order(&mut self, order: ApplyOrder) -> Result<()> {
+        self.finish_preempted()?;
+        write_apply_order(&self.sqlite.readwrite, order)?;
+        self.commit_storage()?;
+        self.timeline_receive_queue.set_order(order);
+        Ok(())
+    }
+
+    /// apply later ranges of the entry's timeline along with it when every
+    /// mutation between them commutes, see the hints module
+    fn batch_commuting(&mut self, entry: &mut ReceiveQueueEntry) -> Result<()> {
+        let hints = MutationHints::read(&self.sqlite.readwrite)?;
+        if hints.is_empty() {
+            return Ok(());
+        }
+        let timelines = &self.timelines;
+        let commutes = |entry: &ReceiveQueueEntry| -> io::Result<bool> {
+            let Some(timeline) = timelines.get(&entry.id) else {
+                return Ok(false);
+            };
+            let mut cursor = timeline.scan_range(entry.range);
+            while cursor.advance()? {
+                // malformed frames fail when they're applied
+                let frame = cursor.read_all()?;
+                match TimelineFrame::decode(&frame) {
+                    Ok(frame) if hints.commutes(&frame) => {}
+                    _ => return Ok(false),
                 }
             }
-            // otherwise, just push a new entry
-            _ => self.timeline_receive_queue.push_back(ReceiveQueueEntry {
-                id,
-                range: LsnRange::new(lsn, lsn),
-            }),
+            Ok(true)
+        };
+        // the popped range itself must commute before anything moves past
+        // the ranges which arrived after it
+        if !commutes(entry).map_err(JournalError::from)? {
+            return Ok(());
+        }
+        let popped = *entry;
+        let queued: Vec<_> = match cfg!(debug_assertions) {
+            true => self.timeline_receive_queue.entries().copied().collect(),
+            false => vec![],
+        };
+        self.timeline_receive_queue
+            .pull_forward(entry, commutes)
+            .map_err(JournalError::from)?;
+        if cfg!(debug_assertions) && entry.range != popped.range {
+            self.verify_batched(popped, *entry, &queued)?;
         }
+        Ok(())
+    }
+
+    /// check in debug builds that applying batched (popped, with later ranges
+    /// of its timeline pulled forward from queued) before the ranges it moved
+    /// past leaves the document as applying them in arrival order would
+    fn verify_batched(
+        &mut self,
+        popped: ReceiveQueueEntry,
+        batched: ReceiveQueueEntry,
+        queued: &[ReceiveQueueEntry],
+    ) -> Result<()> {
+        let mut arrival = vec![popped];
+        for &next in queued {
+            arrival.push(next);
+            if next.id == batched.id && next.range.last() == batched.range.last()
+            {
+                break;
+            }
+        }
+        let moved_past = arrival.iter().filter(|e| e.id != batched.id);
+        let reordered: Vec<_> =
+            std::iter::once(&batched).chain(moved_past).collect();
+
+        let timelines = &self.timelines;
+        let ranges = |entries: &[&ReceiveQueueEntry]| -> Vec<_> {
+            entries
+                .iter()
+                .map(|e| (&timelines[&e.id], e.range))
+                .collect()
+        };
+        verify_commuting(
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            &ranges(&arrival.iter().collect::<Vec<_>>()),
+            &ranges(&reordered),
+        )?;
+        Ok(())
     }
 
     pub fn step(&mut self) -> Result<()> {
-        // check to see if we have anything in the receive queue
-        let entry = self.timeline_receive_queue.pop_front();
+        self.step_until(|| false)
+    }
+
+    /// step_with_budget is like step, but stops applying mutations once
+    /// budget has been spent so that the caller can yield to its event loop.
+    /// Reducers are preempted between mutations and whenever they await a
+    /// batch of queries or statements, so a step overruns the budget by at
+    /// most one batch. A mutation which is preempted keeps its transaction
+    /// open until a later step finishes it, and has_pending_work stays true
+    /// until then. Whatever is left of the range stays at the front of the
+    /// receive queue and is resumed by the next step.
+    pub fn step_with_budget(&mut self, budget: Duration) -> Result<()> {
+        let deadline =
+            unix_timestamp_milliseconds() + budget.as_millis() as i64;
+        self.step_until(|| unix_timestamp_milliseconds() >= deadline)
+    }
+
+    /// drain applies everything in the receive queue and then syncs the
+    /// storage journal, leaving the document safe to close
+    pub fn drain(&mut self) -> Result<()> {
+        while self.has_pending_work() {
+            self.step()?;
+        }
+        self.storage.sync()?;
+        Ok(())
+    }
 
-        if let Some(entry) = entry {
-            log::debug!("applying range {} to timeline {}", entry.range, entry.id);
+    fn step_until(&mut self, should_yield: impl FnMut() -> bool) -> Result<()> {
+        // finish the mutation the reducer was preempted in before anything
+        // else, its transaction is still open
+        let resuming = self.preempted.is_some();
+        let (entry, started, mut changes) = match self.preempted.take() {
+            Some(Preempted { entry, started, changes }) => {
+                (entry, started, changes)
+            }
+            None => {
+                // check to see if we have anything in the receive queue
+                let Some(mut entry) = self.timeline_receive_queue.pop() else {
+                    return Ok(());
+                };
+                if self.over_quota.contains_key(&entry.id) {
+                    self.park(entry);
+                    return Ok(());
+                }
+                if let Some(limit) = self.quota.max_journal_bytes {
+                    let used = self
+                        .storage
+                        .journal_size()
+                        .map_err(JournalError::from)?;
+                    if used >= limit {
+                        self.reject(entry, QuotaKind::Journal, limit, used);
+                        return Ok(());
+                    }
+                }
+                if self.timeline_receive_queue.order() == ApplyOrder::Arrival {
+                    self.batch_commuting(&mut entry)?;
+                }
+                (entry, entry.range, vec![])
+            }
+        };
+
+        logging::debug!(
+            doc = self.storage.id();
+            "applying range {} to timeline {}", entry.range, entry.id
+        );
+
+        // get the timeline
+        let timeline = self.timelines.get(&entry.id).expect(
+            "timeline missing in timelines but present in the receive queue",
+        );
+
+        // apply part of the timeline (per the receive queue entry) to the db
+        let record_changes = self.changes.is_some();
+        if !resuming {
+            self.reducer.take_storage_full();
+        }
+        let applied = apply_timeline_range_until(
+            timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            entry.range,
+            should_yield,
+            |timeline_lsn, frame| {
+                if record_changes {
+                    changes.push(Change {
+                        timeline_id: entry.id,
+                        timeline_lsn,
+                        mutation: frame.mutation.to_vec(),
+                        meta: frame.meta,
+                    });
+                }
+            },
+        );
+        let applied = match applied.map_err(Error::from) {
+            Ok(applied) => applied,
+            // the transaction rolled back, none of it was applied
+            Err(err)
+                if err.is_storage_full()
+                    || self.reducer.take_storage_full() =>
+            {
+                let used = self.database_size()?;
+                let limit = self.storage.max_size();
+                let entry = ReceiveQueueEntry { id: entry.id, range: started };
+                self.reject(entry, QuotaKind::Database, limit, used);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let remaining = applied.remaining;
 
-            // get the timeline
-            let timeline = self
-                .timelines
-                .get(&entry.id)
-                .expect("timeline missing in timelines but present in the receive queue");
+        // the reducer was preempted part way through a mutation, finish the
+        // transaction next step
+        if applied.preempted {
+            self.preempted = Some(Preempted {
+                entry: ReceiveQueueEntry { id: entry.id, range: remaining },
+                started,
+                changes,
+            });
+            return Ok(());
+        }
+
+        let applied_up_to = match remaining.first() {
+            Some(first) => first.checked_sub(1),
+            None => entry.range.last(),
+        };
+        if let Some(up_to) = applied_up_to {
+            self.timeline_receive_queue.applied(
+                entry.id,
+                up_to,
+                unix_timestamp_milliseconds(),
+            );
+        }
 
-            // apply part of the timeline (per the receive queue entry) to the db
-            apply_timeline_range(
-                timeline,
-                &mut self.sqlite.readwrite,
-                &mut self.reducer,
-                entry.range,
-            )?;
+        // we yielded between mutations, resume from here next step
+        if remaining.is_non_empty() {
+            self.timeline_receive_queue
+                .requeue(ReceiveQueueEntry { id: entry.id, range: remaining });
+        }
 
-            // commit changes
-            self.storage.commit()?;
+        // record changes before committing them so that they are
+        // delivered at least once
+        if let Some(log) = self.changes.as_mut() {
+            if !changes.is_empty() {
+                log.record(ChangeBatch {
+                    storage_lsn: self.storage.source_range().next(),
+                    changes,
+                })?;
+            }
         }
 
+        // commit changes
+        self.commit_storage()?;
+        self.resize_cache()?;
+
         Ok(())
     }
 }
 
+impl<J: Journal + ReplicationDestination> CoordinatorDocument<J> {
+    /// fold the storage frames up to and including up_to into a single frame,
+    /// returning the number of frames removed. clients which haven't received
+    /// up_to yet can no longer catch up frame by frame and are reset, see the
+    /// compaction module
+    pub fn compact(&mut self, up_to: Lsn) -> Result<usize> {
+        let removed = self.storage.compact(up_to)?;
+        if removed > 0 {
+            logging::info!(doc = self.storage.id(); "compacted {} storage frames", removed);
+            // the journal shrank, which may make room for rejected mutations
+            self.retry_rejected();
+        }
+        Ok(removed)
+    }
+
+    /// permanently remove whatever purge deletes or overwrites, from the
+    /// document and from its storage history, and bump the epoch. received
+    /// mutations are applied first so that they can't bring purged rows
+    /// back. returns the new epoch, see the redaction module
+    pub fn redact<F>(&mut self, purge: F) -> Result<u64>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<()>,
+    {
+        self.drain()?;
+        let epoch = redaction::purge(&mut self.sqlite.readwrite, purge)?;
+        // the purge and the fold of every older frame land in one atomic
+        // journal swap, so a crash can't leave the purged pages behind
+        self.finish_preempted()?;
+        checkpoint_wal(&self.sqlite.readwrite)?;
+        self.storage.commit_folded()?;
+        self.epoch = epoch;
+        logging::info!(doc = self.storage.id(); "redacted document, now at epoch {}", epoch);
+        Ok(epoch)
+    }
+}
+
 /// CoordinatorDocument knows how to replicate it's storage journal
 impl<J: Journal + ReplicationSource> ReplicationSource for CoordinatorDocument<J> {
     type Reader<'a> = <J as ReplicationSource>::Reader<'a>
@@ -138,9 +1136,37 @@ impl<J: Journal + ReplicationSource> ReplicationSource for CoordinatorDocument<J
 }
 
 /// CoordinatorDocument knows how to receive timeline journals from elsewhere
+///
+/// Every mutation is applied exactly once, even across reconnects and
+/// coordinator restarts. The applied lsn of each timeline is committed to
+/// storage along with the effects of its mutations, and serves as a
+/// watermark: range never asks a client for anything at or below it, and
+/// write_lsn acknowledges frames at or below it without applying them again.
+///
+/// The server timeline is only written by the coordinator itself, as its
+/// mutations may run tasks, expire rows and ack effects; clients can't
+/// replicate into it.
 impl<J: Journal + ReplicationDestination> ReplicationDestination for CoordinatorDocument<J> {
     fn range(&mut self, id: JournalId) -> std::result::Result<LsnRange, ReplicationError> {
+        if id == SERVER_TIMELINE_ID {
+            return Err(ReplicationError::ReservedJournal(id));
+        }
+        let applied = self.applied_lsn(id)?;
         let timeline = self.get_or_create_timeline_mut(id)?;
+
+        // frames at or below the watermark are no longer needed; this also
+        // fast forwards a timeline which was lost when the coordinator
+        // restarted
+        if let Some(applied) = applied {
+            let start = match Journal::range(timeline) {
+                LsnRange::Empty { nextlsn } => nextlsn,
+                LsnRange::NonEmpty { first, .. } => first,
+            };
+            if applied >= start {
+                timeline.drop_prefix(applied)?;
+            }
+        }
+
         ReplicationDestination::range(timeline, id)
     }
 
@@ -153,9 +1179,600 @@ impl<J: Journal + ReplicationDestination> ReplicationDestination for Coordinator
     where
         R: io::Read,
     {
+        if id == SERVER_TIMELINE_ID {
+            return Err(ReplicationError::ReservedJournal(id));
+        }
+        if matches!(self.applied_lsn(id)?, Some(applied) if lsn <= applied) {
+            // a retransmit of a frame we've already applied, most likely sent
+            // before the client saw our ack
+            logging::debug!(
+                doc = self.storage.id();
+                "ignoring already applied lsn {} from timeline {}", lsn, id
+            );
+            io::copy(reader, &mut io::sink())?;
+            return Ok(());
+        }
+
+        // clients refuse to apply mutations over max_mutation_size, but a
+        // client is free to ignore its config, so the limit is checked again
+        // here, from the lz4 size prefix before anything is decompressed.
+        // the frame is cancelled rather than refused so that the timeline's
+        // later mutations still apply
+        let mut frame = Vec::new();
+        reader.read_to_end(&mut frame)?;
+        let size = TimelineFrame::decode(&frame)
+            .and_then(|frame| frame.mutation_size());
+        let oversized = match (size, self.config.max_mutation_size) {
+            (Ok(size), Some(max)) if size > max => Some(format!(
+                "its mutation is {} bytes, over the limit of {}",
+                size, max
+            )),
+            (Err(err @ TimelineError::ImplausibleMutationSize { .. }), _) => {
+                Some(err.to_string())
+            }
+            _ => None,
+        };
+        if let Some(reason) = oversized {
+            logging::warn!(
+                doc = self.storage.id();
+                "cancelling lsn {} from timeline {}: {}", lsn, id, reason
+            );
+            frame = cancelled_frame().to_vec()?;
+        }
+
         let timeline = self.get_or_create_timeline_mut(id)?;
-        timeline.write_lsn(id, lsn, reader)?;
+        timeline.write_lsn(id, lsn, &mut &frame[..])?;
         self.mark_received(id, lsn);
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use super::{
+        CoordinatorDocument, CreateDocumentOptions, SERVER_TIMELINE_ID,
+    };
+    use crate::{
+        config::DocumentConfig,
+        error::Error,
+        order::ApplyOrder,
+        reducer::{tests::exec_guest, Reducer},
+        replication::{
+            ReplicationDestination, ReplicationError, ReplicationSource,
+        },
+        schedule::ScheduledTask,
+        timeline::{
+            cancelled_frame, Codec, ConsistencyToken, FrameKind, FrameMeta,
+            TimelineError, TimelineFrame,
+        },
+        JournalId, LsnRange, MemoryJournal, MemoryJournalFactory, Serializable,
+    };
+
+    pub(crate) fn counting_guest() -> Vec<u8> {
+        exec_guest("", "INSERT INTO t VALUES (1)", 1)
+    }
+
+    /// a document whose reducer inserts a row into t for every mutation
+    pub(crate) fn counting_doc() -> CoordinatorDocument<MemoryJournal> {
+        doc_with(&counting_guest())
+    }
+
+    /// a document with a table t, whose reducer is wasm
+    fn doc_with(wasm: &[u8]) -> CoordinatorDocument<MemoryJournal> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut doc = CoordinatorDocument::open(
+            MemoryJournal::open(id).unwrap(),
+            MemoryJournalFactory,
+            wasm,
+        )
+        .unwrap();
+        doc.sqlite
+            .readwrite
+            .execute_batch("CREATE TABLE t (x)")
+            .unwrap();
+        doc.commit_storage().unwrap();
+        doc
+    }
+
+    /// open a copy of doc's storage, as a restarted coordinator would
+    fn reopen(
+        doc: &mut CoordinatorDocument<MemoryJournal>,
+    ) -> CoordinatorDocument<MemoryJournal> {
+        let id = doc.storage.id();
+        let mut copy = MemoryJournal::open(id).unwrap();
+        for lsn in doc.storage.source_range().iter() {
+            let mut frame = doc.storage.read_lsn(lsn).unwrap().unwrap();
+            copy.write_lsn(id, lsn, &mut frame).unwrap();
+        }
+        CoordinatorDocument::open(copy, MemoryJournalFactory, &counting_guest())
+            .unwrap()
+    }
+
+    pub(crate) fn count(doc: &CoordinatorDocument<MemoryJournal>) -> i64 {
+        doc.query(|conn| {
+            conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        })
+        .unwrap()
+    }
+
+    pub(crate) fn frame(mutation: &[u8]) -> Vec<u8> {
+        TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta::default(),
+            codec: Codec::Raw,
+            mutation,
+        }
+        .to_vec()
+        .unwrap()
+    }
+
+    /// a sends two mutations with b's in between, all hinted to commute
+    fn interleave_commuting(doc: &mut CoordinatorDocument<MemoryJournal>) {
+        doc.sqlite
+            .readwrite
+            .execute_batch(
+                "INSERT INTO __sqlsync_mutation_hints (tag, commutes)
+                VALUES (1, true)",
+            )
+            .unwrap();
+        doc.commit_storage().unwrap();
+
+        let tagged = TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta { timestamp_ms: None, tag: Some(1) },
+            codec: Codec::Raw,
+            mutation: b"m",
+        }
+        .to_vec()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let (a, b) = (JournalId::new128(&mut rng), JournalId::new128(&mut rng));
+        doc.write_lsn(a, 0, &mut &tagged[..]).unwrap();
+        doc.write_lsn(b, 0, &mut &tagged[..]).unwrap();
+        doc.write_lsn(a, 1, &mut &tagged[..]).unwrap();
+        doc.drain().unwrap();
+    }
+
+    #[test]
+    fn queries_wait_to_observe_tokens() {
+        let mut doc = counting_doc();
+        let timeline_id = JournalId::new128(&mut rand::thread_rng());
+        let token = |lsn| ConsistencyToken { timeline_id, lsn };
+        assert!(!doc.observes(token(0)).unwrap());
+        // nothing has been received, so there's nothing to step
+        assert!(!doc.wait_for(token(0)).unwrap());
+        assert!(matches!(
+            doc.query_observing(token(0), |_| Ok(())),
+            Err(Error::NotObserved(t)) if t == token(0)
+        ));
+
+        let (mutation, cancelled) =
+            (frame(b"m"), cancelled_frame().to_vec().unwrap());
+        doc.write_lsn(timeline_id, 0, &mut &mutation[..]).unwrap();
+        doc.write_lsn(timeline_id, 1, &mut &cancelled[..]).unwrap();
+        let rows: i64 = doc
+            .query_observing(token(0), |conn| {
+                conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            })
+            .unwrap();
+        assert_eq!(rows, 1);
+        assert!(doc.observes(token(0)).unwrap());
+        assert!(!doc.observes(token(2)).unwrap());
+        assert!(matches!(
+            doc.observes(token(1)),
+            Err(Error::TimelineError(TimelineError::MutationCancelled(t)))
+                if t == token(1)
+        ));
+
+        // tokens are per timeline
+        let other = JournalId::new128(&mut rand::thread_rng());
+        let token = ConsistencyToken { timeline_id: other, lsn: 0 };
+        assert!(!doc.observes(token).unwrap());
+    }
+
+    #[test]
+    fn commuting_mutations_are_batched() {
+        let mut doc = counting_doc();
+        let frames = doc.storage.source_range().len();
+        interleave_commuting(&mut doc);
+        assert_eq!(count(&doc), 3);
+        // the hints, then a's two mutations, then b's
+        assert_eq!(doc.storage.source_range().len(), frames + 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "hinted to commute")]
+    fn wrong_commute_hints_fail_debug_builds() {
+        // each mutation records how many timelines were applied before it,
+        // which depends on the order
+        let mut doc = doc_with(&exec_guest(
+            "",
+            "INSERT INTO t SELECT count(*) FROM __sqlsync_timelines",
+            1,
+        ));
+        interleave_commuting(&mut doc);
+    }
+
+    #[test]
+    fn applied_frames_are_never_applied_again() {
+        let mut doc = counting_doc();
+        let client = JournalId::new128(&mut rand::thread_rng());
+        for lsn in 0..2 {
+            doc.write_lsn(client, lsn, &mut &frame(b"m")[..]).unwrap();
+        }
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 2);
+
+        // a retransmit sent before the client saw the ack is dropped
+        doc.write_lsn(client, 1, &mut &frame(b"m")[..]).unwrap();
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 2);
+        assert_eq!(
+            ReplicationDestination::range(&mut doc, client).unwrap(),
+            LsnRange::Empty { nextlsn: 2 }
+        );
+
+        // a restarted coordinator has lost the timeline, but still asks
+        // for what follows the watermark
+        doc.timelines.clear();
+        assert_eq!(
+            ReplicationDestination::range(&mut doc, client).unwrap(),
+            LsnRange::Empty { nextlsn: 2 }
+        );
+        doc.write_lsn(client, 2, &mut &frame(b"m")[..]).unwrap();
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 3);
+    }
+
+    #[test]
+    fn oversized_mutations_are_cancelled() {
+        let mut doc = counting_doc();
+        doc.set_config(DocumentConfig {
+            version: 1,
+            max_mutation_size: Some(16),
+            ..DocumentConfig::default()
+        });
+        let client = JournalId::new128(&mut rand::thread_rng());
+        let big = vec![b'm'; 64];
+        let compressed = TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta::default(),
+            codec: Codec::Lz4,
+            mutation: &lz4_flex::compress_prepend_size(&big),
+        }
+        .to_vec()
+        .unwrap();
+
+        doc.write_lsn(client, 0, &mut &frame(&big)[..]).unwrap();
+        doc.write_lsn(client, 1, &mut &compressed[..]).unwrap();
+        doc.write_lsn(client, 2, &mut &frame(b"m")[..]).unwrap();
+        doc.drain().unwrap();
+
+        // both large mutations were replaced, and the one after them applied
+        assert_eq!(count(&doc), 1);
+        assert_eq!(
+            ReplicationDestination::range(&mut doc, client).unwrap(),
+            LsnRange::Empty { nextlsn: 3 }
+        );
+    }
+
+    #[test]
+    fn implausible_compressed_sizes_are_cancelled() {
+        // without a max_mutation_size, a prefix claiming 4GiB would still be
+        // allocated when the frame is decompressed
+        let mut doc = counting_doc();
+        let client = JournalId::new128(&mut rand::thread_rng());
+        let mut bomb = u32::MAX.to_le_bytes().to_vec();
+        bomb.extend_from_slice(&[0x10, b'm']);
+        let bomb = TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta::default(),
+            codec: Codec::Lz4,
+            mutation: &bomb,
+        }
+        .to_vec()
+        .unwrap();
+
+        doc.write_lsn(client, 0, &mut &bomb[..]).unwrap();
+        doc.write_lsn(client, 1, &mut &frame(b"m")[..]).unwrap();
+        doc.drain().unwrap();
+
+        assert_eq!(count(&doc), 1);
+        assert_eq!(
+            ReplicationDestination::range(&mut doc, client).unwrap(),
+            LsnRange::Empty { nextlsn: 2 }
+        );
+    }
+
+    #[test]
+    fn queries_see_stepped_mutations_and_cant_write() {
+        let mut doc = counting_doc();
+        let client = JournalId::new128(&mut rand::thread_rng());
+        doc.write_lsn(client, 0, &mut &frame(b"m")[..]).unwrap();
+
+        // received mutations are only visible once they're stepped
+        assert_eq!(count(&doc), 0);
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 1);
+
+        let write =
+            doc.query(|conn| conn.execute("INSERT INTO t VALUES (2)", []));
+        assert!(write.is_err());
+        let drop = doc.query(|conn| conn.execute_batch("DROP TABLE t"));
+        assert!(drop.is_err());
+        assert_eq!(count(&doc), 1);
+    }
+
+    #[test]
+    fn clients_cant_write_the_server_timeline() {
+        let mut doc = counting_doc();
+        assert!(matches!(
+            ReplicationDestination::range(&mut doc, SERVER_TIMELINE_ID),
+            Err(ReplicationError::ReservedJournal(_))
+        ));
+        let forged =
+            doc.write_lsn(SERVER_TIMELINE_ID, 0, &mut &frame(b"m")[..]);
+        assert!(matches!(forged, Err(ReplicationError::ReservedJournal(_))));
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 0);
+
+        // while the coordinator's own mutations still go through
+        doc.mutate(b"m").unwrap();
+        doc.drain().unwrap();
+        assert_eq!(count(&doc), 1);
+    }
+
+    #[test]
+    fn optimize_ships_statistics_in_checkpoints() {
+        let mut doc = counting_doc();
+        let client = JournalId::new128(&mut rand::thread_rng());
+        for lsn in 0..3 {
+            doc.write_lsn(client, lsn, &mut &frame(b"m")[..]).unwrap();
+        }
+        doc.drain().unwrap();
+
+        assert!(doc.optimize().unwrap());
+        let stat = |conn: &rusqlite::Connection| {
+            conn.query_row(
+                "SELECT stat FROM sqlite_stat1 WHERE tbl = 't'",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+        };
+        assert_eq!(doc.query(stat).unwrap(), "3");
+        let checkpoint = doc.checkpoint().unwrap().unwrap();
+        assert_eq!(checkpoint.query(stat).unwrap(), "3");
+    }
+
+    #[test]
+    fn schedules_survive_a_restart() {
+        let mut doc = counting_doc();
+        let every = Duration::from_secs(60);
+        let task = ScheduledTask { name: "cleanup".into(), every };
+        doc.set_schedule(vec![task]).unwrap();
+        let deadline = doc.schedule_deadline();
+        assert!(deadline.is_some());
+
+        // the schedule is only recorded once its mutation is applied
+        assert!(reopen(&mut doc).schedule().is_empty());
+        doc.drain().unwrap();
+        let restarted = reopen(&mut doc);
+        assert_eq!(restarted.schedule(), doc.schedule());
+        assert_eq!(restarted.schedule_deadline(), deadline);
+
+        doc.set_schedule(vec![]).unwrap();
+        doc.drain().unwrap();
+        assert!(reopen(&mut doc).schedule().is_empty());
+    }
+
+    #[test]
+    fn effects_are_acked_once() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let wasm = exec_guest(
+            "",
+            "INSERT INTO __sqlsync_effects (effect) VALUES (x'01')",
+            1,
+        );
+        let mut doc = CoordinatorDocument::open(
+            MemoryJournal::open(id).unwrap(),
+            MemoryJournalFactory,
+            &wasm,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            doc.mutate(b"m").unwrap();
+        }
+        doc.drain().unwrap();
+        let ids: Vec<_> = doc
+            .pending_effects(10)
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+
+        // retried and overlapping acks only ack what's still pending
+        assert!(doc.ack_effects(&[ids[0], ids[0]]).unwrap().is_some());
+        assert!(doc.ack_effects(&[ids[0]]).unwrap().is_none());
+        assert_eq!(doc.pending_effects(10).unwrap().len(), 1);
+        doc.drain().unwrap();
+        assert!(doc.ack_effects(&[ids[0]]).unwrap().is_none());
+        assert!(doc.ack_effects(&ids).unwrap().is_some());
+        doc.drain().unwrap();
+        assert!(doc.pending_effects(10).unwrap().is_empty());
+        assert!(doc.ack_effects(&ids).unwrap().is_none());
+    }
+
+    #[test]
+    fn redaction_removes_purged_bytes_from_storage() {
+        const MARKER: &[u8] = b"redact-me-";
+        let frames_contain = |doc: &CoordinatorDocument<MemoryJournal>| {
+            doc.storage.source_range().iter().any(|lsn| {
+                let mut frame = vec![];
+                let mut reader = doc.storage.read_lsn(lsn).unwrap().unwrap();
+                std::io::Read::read_to_end(&mut reader, &mut frame).unwrap();
+                frame.windows(MARKER.len()).any(|w| w == MARKER)
+            })
+        };
+
+        let mut doc = counting_doc();
+        // enough rows to span many pages, so that the purge frees some
+        doc.sqlite
+            .readwrite
+            .execute_batch(
+                "WITH RECURSIVE n(i) AS (
+                    SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200
+                )
+                INSERT INTO t SELECT 'redact-me-' || i || printf('%.500c', 'x')
+                FROM n",
+            )
+            .unwrap();
+        doc.commit_storage().unwrap();
+        doc.mutate(b"keep").unwrap();
+        doc.drain().unwrap();
+        assert!(frames_contain(&doc));
+        let num_pages = doc.storage.num_pages().unwrap();
+
+        let delete = |conn: &rusqlite::Connection| {
+            conn.execute("DELETE FROM t WHERE x LIKE 'redact-me-%'", [])
+                .map(|_| ())
+        };
+        assert_eq!(doc.redact(delete).unwrap(), 1);
+        assert_eq!(count(&doc), 1);
+        assert!(!frames_contain(&doc));
+        // the vacuum handed the freed pages back
+        assert!(doc.storage.num_pages().unwrap() < num_pages);
+
+        let reopened = reopen(&mut doc);
+        assert_eq!(reopened.epoch(), 1);
+        assert_eq!(count(&reopened), 1);
+    }
+
+    fn create(
+        wasm: &[u8],
+        options: CreateDocumentOptions,
+    ) -> Result<CoordinatorDocument<MemoryJournal>, Error> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        CoordinatorDocument::create(
+            MemoryJournal::open(id).unwrap(),
+            MemoryJournalFactory,
+            Reducer::new(wasm).unwrap(),
+            options,
+        )
+    }
+
+    fn timelines(doc: &CoordinatorDocument<MemoryJournal>) -> i64 {
+        doc.query(|conn| {
+            let sql = "SELECT count(*) FROM __sqlsync_timelines";
+            conn.query_row(sql, [], |row| row.get(0))
+        })
+        .unwrap()
+    }
+
+    /// a document with rows, timelines, a schedule, an apply order and an
+    /// epoch, all of which a template carries
+    fn busy_template() -> CoordinatorDocument<MemoryJournal> {
+        let mut doc = counting_doc();
+        let timeline_id = JournalId::new128(&mut rand::thread_rng());
+        let mutation = frame(b"m");
+        doc.write_lsn(timeline_id, 0, &mut &mutation[..]).unwrap();
+        doc.mutate(b"m").unwrap();
+        doc.drain().unwrap();
+        doc.set_apply_order(ApplyOrder::RoundRobin { quantum: 2 })
+            .unwrap();
+        let every = Duration::from_secs(60);
+        let task = ScheduledTask { name: "cleanup".into(), every };
+        doc.set_schedule(vec![task]).unwrap();
+        doc.drain().unwrap();
+        let delete = |conn: &rusqlite::Connection| {
+            conn.execute_batch("DELETE FROM t WHERE rowid = 1")
+        };
+        assert_eq!(doc.redact(delete).unwrap(), 1);
+
+        assert!(count(&doc) > 0);
+        assert!(timelines(&doc) > 0);
+        assert!(!doc.schedule().is_empty());
+        doc
+    }
+
+    #[test]
+    fn create_from_template_keeps_rows_but_not_bookkeeping() {
+        let template = busy_template();
+        let rows = count(&template);
+        let options = CreateDocumentOptions {
+            template: Some(template.snapshot().unwrap()),
+            init_mutation: None,
+        };
+        let mut doc = create(&counting_guest(), options).unwrap();
+
+        assert_eq!(doc.storage.source_range().len(), 1);
+        for doc in [reopen(&mut doc), doc] {
+            assert_eq!(count(&doc), rows);
+            assert_eq!(timelines(&doc), 0);
+            assert_eq!(doc.epoch(), 0);
+            assert!(doc.schedule().is_empty());
+            assert_eq!(doc.apply_order(), ApplyOrder::Arrival);
+        }
+    }
+
+    #[test]
+    fn create_applies_the_init_mutation() {
+        let wasm = exec_guest("", "CREATE TABLE IF NOT EXISTS t (x)", 1);
+        let options = CreateDocumentOptions {
+            template: None,
+            init_mutation: Some(b"m".to_vec()),
+        };
+        let mut doc = create(&wasm, options).unwrap();
+
+        assert_eq!(doc.storage.source_range().len(), 1);
+        assert!(!doc.has_pending_work());
+        assert_eq!(count(&reopen(&mut doc)), 0);
+    }
+
+    #[test]
+    fn create_from_template_with_init_mutation_is_one_frame() {
+        let template = busy_template();
+        let rows = count(&template);
+        let options = CreateDocumentOptions {
+            template: Some(template.snapshot().unwrap()),
+            init_mutation: Some(b"m".to_vec()),
+        };
+        let mut doc = create(&counting_guest(), options).unwrap();
+
+        assert_eq!(doc.storage.source_range().len(), 1);
+        let reopened = reopen(&mut doc);
+        assert_eq!(count(&reopened), rows + 1);
+        // only the init mutation's server timeline is left
+        assert_eq!(timelines(&reopened), 1);
+        assert_eq!(reopened.epoch(), 0);
+    }
+
+    #[test]
+    fn create_refuses_existing_documents() {
+        let mut doc = counting_doc();
+        let id = doc.storage.id();
+        let mut storage = MemoryJournal::open(id).unwrap();
+        for lsn in doc.storage.source_range().iter() {
+            let mut frame = doc.storage.read_lsn(lsn).unwrap().unwrap();
+            storage.write_lsn(id, lsn, &mut frame).unwrap();
+        }
+
+        let result = CoordinatorDocument::create(
+            storage,
+            MemoryJournalFactory,
+            Reducer::new(&counting_guest()[..]).unwrap(),
+            CreateDocumentOptions::default(),
+        );
+        match result {
+            Err(Error::DocumentExists(existing)) => assert_eq!(existing, id),
+            other => panic!("expected DocumentExists, got {:?}", other.err()),
+        }
+    }
+}
@@ -1,43 +1,67 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use rusqlite::{
+    functions::FunctionFlags,
     hooks::{AuthAction, AuthContext, Authorization},
     Connection, OpenFlags,
 };
-use sqlite_vfs::FilePtr;
+use sqlite_vfs::{FilePtr, Registration};
 
 use crate::{
-    journal::Journal, page::PAGESIZE, storage::Storage, vfs::StorageVfs,
+    journal::Journal,
+    page::{PageIdx, PAGESIZE},
+    storage::Storage,
+    unixtime::HybridClock,
+    vfs::StorageVfs,
 };
 
 pub struct ConnectionPair {
     pub readwrite: Connection,
     pub readonly: Connection,
+    // fields drop in order, so the connections close before the vfs they
+    // use is unregistered
+    vfs: Registration,
+    attached: Vec<(String, Registration)>,
 }
 
 type Result<T> = std::result::Result<T, rusqlite::Error>;
 
+/// the schema which holds a LocalDocument's local-only tables
+pub const LOCAL_SCHEMA: &str = "local";
+
 pub fn open_with_vfs<J: Journal>(
     journal: J,
 ) -> Result<(ConnectionPair, Box<Storage<J>>)> {
-    let mut storage = Box::new(Storage::new(journal));
-    let storage_ptr = FilePtr::new(&mut storage);
-
-    // generate random vfs name
-    let vfs_name = format!("local-vfs-{}", rand::random::<u64>());
+    open_with_storage(Box::new(Storage::new(journal)))
+}
 
-    // register the vfs globally
-    let vfs = StorageVfs::new(storage_ptr);
-    sqlite_vfs::register(&vfs_name, vfs)
-        .expect("failed to register local-vfs with sqlite");
+/// like open_with_vfs, for storage which may already hold pages staged for
+/// sqlite, see Storage::stage
+pub(crate) fn open_with_storage<J: Journal>(
+    mut storage: Box<Storage<J>>,
+) -> Result<(ConnectionPair, Box<Storage<J>>)> {
+    let vfs = register_vfs(&mut storage);
 
     let sqlite = Connection::open_with_flags_and_vfs(
         "main.db",
-        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
-        &vfs_name,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI,
+        vfs.name(),
     )?;
 
     sqlite.pragma_update(None, "page_size", PAGESIZE)?;
     sqlite.pragma_update(None, "synchronous", "off")?;
     sqlite.pragma_update(None, "journal_mode", "memory")?;
+    // in WAL mode storage only sees pages once they're checkpointed, so
+    // checkpoint after every commit, see checkpoint_wal
+    sqlite.pragma_update(None, "wal_autocheckpoint", 1)?;
+    // and truncate the WAL whenever sqlite starts it over, so that it only
+    // holds the frames which haven't been checkpointed yet
+    sqlite.pragma_update(None, "journal_size_limit", 0)?;
 
     // Enable incremental auto_vacuum support for query subscriptions
     // When SQLite is in incremental auto_vacuum mode, it will maintain
@@ -45,26 +69,676 @@ pub fn open_with_vfs<J: Journal>(
     // efficiently map changed pages back to their corresponding root.
     sqlite.pragma_update(None, "auto_vacuum", "incremental")?;
 
-    // TODO: benchmark with/without cache
-    // sqlite.pragma_update(None, "default_cache_size", 0).unwrap();
-    // sqlite.pragma_update(None, "cache_size", 0).unwrap();
+    sqlite.authorizer(Some(readwrite_authorizer));
 
     let sqlite_readonly = Connection::open_with_flags_and_vfs(
         "main.db",
-        OpenFlags::SQLITE_OPEN_READ_ONLY,
-        &vfs_name,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        vfs.name(),
+    )?;
+
+    sqlite_readonly.authorizer(Some(readonly_authorizer));
+
+    Ok((
+        ConnectionPair {
+            readwrite: sqlite,
+            readonly: sqlite_readonly,
+            vfs,
+            attached: Vec::new(),
+        },
+        storage,
+    ))
+}
+
+/// open a readonly connection to the document stored in journal, which can't
+/// be written to by anything else while the connection is open. the
+/// connection must be closed before the registration of the vfs it reads
+/// through is dropped, and the storage must outlive both
+pub fn open_readonly<J: Journal>(
+    journal: J,
+) -> Result<(Connection, Registration, Box<Storage<J>>)> {
+    let mut storage = Box::new(Storage::new(journal));
+    let vfs = register_vfs(&mut storage);
+    let sqlite = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        vfs.name(),
     )?;
+    sqlite.authorizer(Some(readonly_authorizer));
+    Ok((sqlite, vfs, storage))
+}
+
+/// register a vfs backed by storage, which is unregistered when the
+/// returned registration is dropped. the storage must outlive every
+/// connection which uses the vfs
+fn register_vfs<J: Journal>(storage: &mut Box<Storage<J>>) -> Registration {
+    let storage_ptr = FilePtr::new(storage);
+
+    // generate random vfs name
+    let vfs_name = format!("local-vfs-{}", rand::random::<u64>());
 
-    sqlite_readonly.authorizer(Some(|ctx: AuthContext| match ctx.action {
+    // register the vfs globally
+    let vfs = StorageVfs::new(storage_ptr);
+    sqlite_vfs::register(&vfs_name, vfs)
+        .expect("failed to register local-vfs with sqlite")
+}
+
+/// attach the document stored in journal to the readonly connection as
+/// schema, allowing queries to join across documents. the attached document
+/// is read-only and reflects the journal as of the time it was attached.
+///
+/// the returned storage must be kept alive until the schema is detached, or
+/// the connections are dropped
+pub fn attach_storage<J: Journal>(
+    sqlite: &mut ConnectionPair,
+    schema: &str,
+    journal: J,
+) -> Result<Box<Storage<J>>> {
+    let mut storage = Box::new(Storage::new(journal));
+    let vfs = register_vfs(&mut storage);
+    let path = format!("file:main.db?vfs={}&mode=ro", vfs.name());
+    attach_readonly(&sqlite.readonly, &path, schema)?;
+    sqlite.attached.push((schema.to_owned(), vfs));
+    Ok(storage)
+}
+
+/// detach a schema attached by attach_storage, unregistering its vfs
+pub fn detach(sqlite: &mut ConnectionPair, schema: &str) -> Result<()> {
+    let conn = &sqlite.readonly;
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    let out = conn.execute("DETACH DATABASE ?1", [schema]);
+    conn.authorizer(Some(readonly_authorizer));
+    out?;
+    sqlite.attached.retain(|(name, _)| name != schema);
+    Ok(())
+}
+
+/// the readonly connection's authorizer denies ATTACH, so lift it while
+/// attaching
+fn attach_readonly(conn: &Connection, path: &str, schema: &str) -> Result<()> {
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    let out = conn.execute("ATTACH DATABASE ?1 AS ?2", [path, schema]);
+    conn.authorizer(Some(readonly_authorizer));
+    out.map(|_| ())
+}
+
+fn readonly_authorizer(ctx: AuthContext<'_>) -> Authorization {
+    match ctx.action {
         AuthAction::Select => Authorization::Allow,
         AuthAction::Read { .. } => Authorization::Allow,
         AuthAction::Recursive => Authorization::Allow,
         AuthAction::Function { .. } => Authorization::Allow,
         _ => Authorization::Deny,
-    }));
+    }
+}
 
-    Ok((
-        ConnectionPair { readwrite: sqlite, readonly: sqlite_readonly },
-        storage,
-    ))
+/// attach an in-memory database as LOCAL_SCHEMA to both connections. tables
+/// in it are never journaled, so they are only visible on this device.
+///
+/// the database is always a memdb, even when the document's storage is a
+/// file: local tables only last as long as the document is open, and are
+/// empty again when it's reopened. apps which need them to survive a
+/// restart should persist them elsewhere and restore them after opening.
+///
+/// once attached, the readwrite connection denies access to LOCAL_SCHEMA
+/// (reducers must stay deterministic) until with_local_writes is used.
+pub fn attach_local(sqlite: &mut ConnectionPair) -> Result<()> {
+    // a memdb whose name starts with a slash is shared by every connection
+    // in the process which opens it
+    let path =
+        format!("file:/sqlsync-local-{}?vfs=memdb", rand::random::<u64>());
+
+    // attach to the readwrite connection first, which creates the database
+    sqlite
+        .readwrite
+        .execute("ATTACH DATABASE ?1 AS ?2", [path.as_str(), LOCAL_SCHEMA])?;
+    sqlite
+        .readwrite
+        .authorizer(Some(replicated_only_authorizer));
+
+    attach_readonly(&sqlite.readonly, &path, LOCAL_SCHEMA)
+}
+
+/// run f with writes to LOCAL_SCHEMA allowed and writes to every other
+/// schema denied
+pub fn with_local_writes<F, O>(sqlite: &mut Connection, f: F) -> O
+where
+    F: FnOnce(&mut Connection) -> O,
+{
+    sqlite.authorizer(Some(local_only_authorizer));
+    let out = f(sqlite);
+    sqlite.authorizer(Some(replicated_only_authorizer));
+    out
+}
+
+/// how sqlite journals a document's transactions. the journal mode is
+/// recorded in the document's first page, so once one replica switches to
+/// WAL mode every other replica follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JournalMode {
+    /// a rollback journal held in memory
+    #[default]
+    Memory,
+    /// a WAL with an in-process wal-index (see wal_index), which lets
+    /// queries keep reading a consistent snapshot while a mutation is
+    /// applied. commits are checkpointed into storage as soon as no query
+    /// is reading from before them
+    Wal,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+        }
+    }
+}
+
+/// switch the journal mode, which sqlite only allows outside of a
+/// transaction
+pub fn set_journal_mode(sqlite: &Connection, mode: JournalMode) -> Result<()> {
+    sqlite.pragma_update(None, "journal_mode", mode.as_str())
+}
+
+/// how far the WAL has been checkpointed into storage, in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WalProgress {
+    pub frames: u32,
+    pub checkpointed: u32,
+}
+
+impl WalProgress {
+    /// frames which are committed but not in storage yet
+    pub fn waiting(&self) -> u32 {
+        self.frames - self.checkpointed
+    }
+}
+
+fn wal_checkpoint(
+    sqlite: &Connection,
+    mode: &str,
+) -> Result<(bool, WalProgress)> {
+    sqlite.query_row(&format!("PRAGMA wal_checkpoint({})", mode), [], |row| {
+        // outside of WAL mode sqlite reports -1 frames
+        let frames = |idx| row.get::<_, i64>(idx).map(|n| n.max(0) as u32);
+        Ok((
+            row.get(0)?,
+            WalProgress { frames: frames(1)?, checkpointed: frames(2)? },
+        ))
+    })
+}
+
+/// checkpoint committed WAL frames into storage, without waiting on
+/// readers. sqlite stops short of the oldest snapshot a query is still
+/// reading, and snapshots always end on a commit, so storage is left
+/// holding a whole transaction at a time. whatever is left waiting is
+/// checkpointed by a later call, once the query is done
+pub fn checkpoint_wal(sqlite: &Connection) -> Result<WalProgress> {
+    wal_checkpoint(sqlite, "PASSIVE").map(|(_, progress)| progress)
+}
+
+/// checkpoint every frame and empty the WAL. storage must be drained before
+/// it is reset, as sqlite would otherwise keep reading the waiting frames
+/// over the reset pages. returns false, leaving the WAL to a later call,
+/// while a query is still reading from it
+pub fn try_drain_wal(sqlite: &Connection) -> Result<bool> {
+    let (busy, _) = wal_checkpoint(sqlite, "TRUNCATE")?;
+    Ok(!busy)
+}
+
+/// like try_drain_wal, but fails with SQLITE_BUSY while a query is still
+/// reading from the WAL, for callers which can't put the drain off
+pub fn drain_wal(sqlite: &Connection) -> Result<()> {
+    if !try_drain_wal(sqlite)? {
+        let waiting = checkpoint_wal(sqlite)?.waiting();
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            Some(format!("{} WAL frames are still being read", waiting)),
+        ));
+    }
+    Ok(())
+}
+
+/// storage captures every page sqlite writes to the main database, and the
+/// vfs can share a WAL and wal-index between connections, but there is
+/// nowhere to keep a rollback journal on disk. so journal_mode may only be
+/// set to one of the JournalModes; a rollback journal is also required for
+/// reducers to roll back their transactions
+fn journal_mode_allowed(ctx: &AuthContext<'_>) -> bool {
+    match ctx.action {
+        AuthAction::Pragma { pragma_name, pragma_value: Some(mode) }
+            if pragma_name.eq_ignore_ascii_case("journal_mode") =>
+        {
+            [JournalMode::Memory, JournalMode::Wal]
+                .iter()
+                .any(|allowed| mode.eq_ignore_ascii_case(allowed.as_str()))
+        }
+        _ => true,
+    }
+}
+
+fn readwrite_authorizer(ctx: AuthContext<'_>) -> Authorization {
+    if journal_mode_allowed(&ctx) {
+        Authorization::Allow
+    } else {
+        Authorization::Deny
+    }
+}
+
+fn replicated_only_authorizer(ctx: AuthContext<'_>) -> Authorization {
+    match ctx.database_name {
+        Some(LOCAL_SCHEMA) => Authorization::Deny,
+        _ => readwrite_authorizer(ctx),
+    }
+}
+
+fn local_only_authorizer(ctx: AuthContext<'_>) -> Authorization {
+    match ctx.action {
+        _ if !journal_mode_allowed(&ctx) => Authorization::Deny,
+        AuthAction::Select
+        | AuthAction::Read { .. }
+        | AuthAction::Recursive
+        | AuthAction::Function { .. }
+        | AuthAction::Transaction { .. }
+        | AuthAction::Savepoint { .. } => Authorization::Allow,
+        _ if ctx.database_name == Some(LOCAL_SCHEMA) => Authorization::Allow,
+        _ => Authorization::Deny,
+    }
+}
+
+/// cap the size of a document at max_bytes. SQLite is told about the cap so
+/// that writes beyond it fail cleanly with SQLITE_FULL rather than partway
+/// through a transaction
+pub fn set_max_size<J: Journal>(
+    sqlite: &Connection,
+    storage: &mut Storage<J>,
+    max_bytes: u64,
+) -> Result<()> {
+    storage.set_max_size(max_bytes);
+    let max_pages = storage.max_size() / PAGESIZE as u64;
+    sqlite.pragma_update(None, "max_page_count", max_pages)
+}
+
+/// CacheSize is how many pages each of a document's sqlite connections may
+/// keep in its page cache. Caches are only dropped when storage changes
+/// underneath sqlite, so a larger cache mostly speeds up reads between
+/// syncs.
+///
+/// Connections don't use sqlite's shared cache: its table locks would fail
+/// queries on the readonly connection while a mutation is being applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheSize {
+    /// room for the whole document, but no less than 256KiB and no more
+    /// than 8MiB
+    #[default]
+    Adaptive,
+    Pages(u32),
+}
+
+impl CacheSize {
+    const ADAPTIVE_MIN_PAGES: u32 = (256 * 1024 / PAGESIZE) as u32;
+    const ADAPTIVE_MAX_PAGES: u32 = (8 * 1024 * 1024 / PAGESIZE) as u32;
+
+    /// the cache size in pages for a document of doc_pages pages
+    pub fn pages(self, doc_pages: PageIdx) -> u32 {
+        match self {
+            CacheSize::Adaptive => doc_pages
+                .clamp(Self::ADAPTIVE_MIN_PAGES, Self::ADAPTIVE_MAX_PAGES),
+            CacheSize::Pages(pages) => pages,
+        }
+    }
+}
+
+pub fn set_cache_size(sqlite: &ConnectionPair, pages: u32) -> Result<()> {
+    for conn in [&sqlite.readwrite, &sqlite.readonly] {
+        conn.pragma_update(None, "cache_size", pages)?;
+    }
+    Ok(())
+}
+
+/// BusyBackoff is how long a query keeps retrying when it fails because the
+/// other connection holds a lock it needs (SQLITE_BUSY), which in WAL mode
+/// can happen while a commit is being checkpointed. sqlite's busy handler
+/// would sleep on the current thread while holding the document, and
+/// browsers can't sleep at all, so hosts wait out each delay on their own
+/// scheduler instead (see LocalDocument::query_retrying)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusyBackoff {
+    /// the delay before the first retry, doubled for each one after it
+    pub initial: Duration,
+    pub max_delay: Duration,
+    /// give up once the delays add up to this much
+    pub timeout: Duration,
+}
+
+impl Default for BusyBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+            timeout: Duration::from_millis(250),
+        }
+    }
+}
+
+impl BusyBackoff {
+    /// never retry
+    pub const NONE: BusyBackoff = BusyBackoff {
+        initial: Duration::ZERO,
+        max_delay: Duration::ZERO,
+        timeout: Duration::ZERO,
+    };
+
+    /// the delay before each retry
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        let Self { initial, max_delay, timeout } = *self;
+        // zero delays would never use up the timeout
+        let floor = Duration::from_millis(1);
+        let max_delay = max_delay.max(floor);
+        let mut next = initial.clamp(floor, max_delay);
+        let mut waited = Duration::ZERO;
+        std::iter::from_fn(move || {
+            if timeout.is_zero() || waited >= timeout {
+                return None;
+            }
+            let delay = next.min(timeout - waited);
+            waited += delay;
+            next = (next * 2).min(max_delay);
+            Some(delay)
+        })
+    }
+}
+
+/// true if err means a lock was held by the other connection, and the
+/// statement may succeed if retried
+pub fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy)
+            | Some(rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+/// expose the document's hybrid clock to reducers as sqlsync_now(), which
+/// returns the next HlcTimestamp as an integer
+pub fn register_clock(
+    sqlite: &Connection,
+    clock: Arc<Mutex<HybridClock>>,
+) -> Result<()> {
+    sqlite.create_scalar_function(
+        "sqlsync_now",
+        0,
+        FunctionFlags::SQLITE_UTF8,
+        move |_| {
+            let mut clock = clock.lock().unwrap_or_else(|e| e.into_inner());
+            Ok(clock.now().as_u64() as i64)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, time::Duration};
+
+    use rusqlite::{Connection, ErrorCode};
+
+    use super::{
+        attach_local, attach_storage, checkpoint_wal, detach, drain_wal,
+        is_busy, open_with_vfs, set_journal_mode, set_max_size, try_drain_wal,
+        with_local_writes, BusyBackoff, CacheSize, JournalMode,
+    };
+    use crate::page::PAGESIZE;
+    use crate::{
+        replication::{ReplicationDestination, ReplicationSource},
+        JournalId, MemoryJournal,
+    };
+
+    fn new_journal() -> MemoryJournal {
+        MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap()
+    }
+
+    #[test]
+    fn adaptive_cache_sizes_are_clamped() {
+        let min = CacheSize::ADAPTIVE_MIN_PAGES;
+        let max = CacheSize::ADAPTIVE_MAX_PAGES;
+        assert_eq!(min, 64);
+        assert_eq!(max, 2048);
+        assert_eq!(CacheSize::Adaptive.pages(0), min);
+        assert_eq!(CacheSize::Adaptive.pages(min - 1), min);
+        assert_eq!(CacheSize::Adaptive.pages(500), 500);
+        assert_eq!(CacheSize::Adaptive.pages(max + 1), max);
+        assert_eq!(CacheSize::Adaptive.pages(u32::MAX), max);
+
+        // a fixed size ignores the document's size
+        assert_eq!(CacheSize::Pages(16).pages(0), 16);
+        assert_eq!(CacheSize::Pages(16).pages(1_000_000), 16);
+        assert_eq!(CacheSize::default(), CacheSize::Adaptive);
+    }
+
+    #[test]
+    fn max_size_is_enforced() {
+        let (sqlite, mut storage) = open_with_vfs(new_journal()).unwrap();
+        set_max_size(&sqlite.readwrite, &mut storage, 8 * PAGESIZE as u64)
+            .unwrap();
+        sqlite.readwrite.execute("create table t (x)", []).unwrap();
+
+        let err = sqlite
+            .readwrite
+            .execute(
+                "with recursive n(x) as (select 1 union all select x + 1 from n where x < 100)
+                insert into t select randomblob(1024) from n",
+                [],
+            )
+            .unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(ErrorCode::DiskFull));
+        assert!(storage.num_pages().unwrap() <= 8);
+    }
+
+    #[test]
+    fn attach_document() {
+        let (sqlite, mut storage) = open_with_vfs(new_journal()).unwrap();
+        sqlite.readwrite.execute("create table t (x)", []).unwrap();
+        sqlite
+            .readwrite
+            .execute("insert into t values (42)", [])
+            .unwrap();
+        storage.commit().unwrap();
+
+        // copy the committed document into a fresh journal
+        let mut copy = MemoryJournal::open(storage.id()).unwrap();
+        for lsn in storage.source_range().iter() {
+            let mut frame = storage.read_lsn(lsn).unwrap().unwrap();
+            copy.write_lsn(storage.id(), lsn, &mut frame).unwrap();
+        }
+
+        let (mut other, _storage) = open_with_vfs(new_journal()).unwrap();
+        let attached = attach_storage(&mut other, "doc", copy).unwrap();
+        let x: i64 = other
+            .readonly
+            .query_row("select x from doc.t", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(x, 42);
+        let vfs = CString::new(other.attached[0].1.name()).unwrap();
+        let registered = || unsafe {
+            !rusqlite::ffi::sqlite3_vfs_find(vfs.as_ptr()).is_null()
+        };
+        assert!(registered());
+
+        // attached documents are read-only
+        assert!(other
+            .readonly
+            .execute("insert into doc.t values (1)", [])
+            .is_err());
+
+        detach(&mut other, "doc").unwrap();
+        drop(attached);
+        assert!(other
+            .readonly
+            .query_row("select x from doc.t", [], |r| r.get::<_, i64>(0))
+            .is_err());
+        // detaching unregisters the attachment's vfs
+        assert!(!registered());
+    }
+
+    #[test]
+    fn journal_mode_is_restricted() {
+        let (mut sqlite, _storage) = open_with_vfs(new_journal()).unwrap();
+        let mode = |conn: &Connection, sql: &str| {
+            conn.query_row(sql, [], |r| r.get::<_, String>(0))
+        };
+
+        for pragma in ["journal_mode = persist", "main.journal_mode = DELETE"] {
+            let sql = format!("pragma {}", pragma);
+            assert!(mode(&sqlite.readwrite, &sql).is_err());
+        }
+        assert_eq!(
+            mode(&sqlite.readwrite, "pragma journal_mode = MEMORY").unwrap(),
+            "memory"
+        );
+
+        // including once local tables are attached
+        attach_local(&mut sqlite).unwrap();
+        assert!(mode(&sqlite.readwrite, "pragma journal_mode = off").is_err());
+        assert_eq!(
+            mode(&sqlite.readwrite, "pragma journal_mode").unwrap(),
+            "memory"
+        );
+    }
+
+    #[test]
+    fn busy_backoff_delays() {
+        let ms = Duration::from_millis;
+        let backoff =
+            BusyBackoff { initial: ms(1), max_delay: ms(4), timeout: ms(12) };
+        let delays: Vec<_> = backoff.delays().collect();
+        assert_eq!(delays, [ms(1), ms(2), ms(4), ms(4), ms(1)]);
+        assert_eq!(BusyBackoff::NONE.delays().count(), 0);
+    }
+
+    #[test]
+    fn wal_readers_see_a_snapshot() {
+        let (sqlite, mut storage) = open_with_vfs(new_journal()).unwrap();
+        set_journal_mode(&sqlite.readwrite, JournalMode::Wal).unwrap();
+        let count = |conn: &Connection| {
+            conn.query_row("select count(*) from t", [], |r| r.get::<_, i64>(0))
+                .unwrap()
+        };
+        sqlite.readwrite.execute("create table t (x)", []).unwrap();
+        sqlite
+            .readwrite
+            .execute("insert into t values (1), (2)", [])
+            .unwrap();
+        assert_eq!(count(&sqlite.readonly), 2);
+
+        // an open statement holds the readonly connection's read
+        // transaction, which keeps seeing what it started with
+        let mut stmt = sqlite.readonly.prepare("select x from t").unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        rows.next().unwrap();
+        sqlite
+            .readwrite
+            .execute("insert into t values (3)", [])
+            .unwrap();
+        assert_eq!(count(&sqlite.readonly), 2);
+        drop(rows);
+        assert_eq!(count(&sqlite.readonly), 3);
+
+        // every commit ends up in storage once the readonly connection lets
+        // the checkpoint through
+        sqlite
+            .readwrite
+            .query_row("pragma wal_checkpoint", [], |_| Ok(()))
+            .unwrap();
+        storage.commit().unwrap();
+        let mut copy = MemoryJournal::open(storage.id()).unwrap();
+        for lsn in storage.source_range().iter() {
+            let mut frame = storage.read_lsn(lsn).unwrap().unwrap();
+            copy.write_lsn(storage.id(), lsn, &mut frame).unwrap();
+        }
+        let (mut other, _storage) = open_with_vfs(new_journal()).unwrap();
+        let _attached = attach_storage(&mut other, "doc", copy).unwrap();
+        let x: i64 = other
+            .readonly
+            .query_row("select sum(x) from doc.t", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(x, 6);
+    }
+
+    #[test]
+    fn wal_is_checkpointed_behind_readers() {
+        let (sqlite, _storage) = open_with_vfs(new_journal()).unwrap();
+        set_journal_mode(&sqlite.readwrite, JournalMode::Wal).unwrap();
+        sqlite.readwrite.execute("create table t (x)", []).unwrap();
+        sqlite
+            .readwrite
+            .execute("insert into t values (1)", [])
+            .unwrap();
+
+        let mut stmt = sqlite.readonly.prepare("select x from t").unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        rows.next().unwrap();
+        sqlite
+            .readwrite
+            .execute("insert into t values (2)", [])
+            .unwrap();
+        assert!(checkpoint_wal(&sqlite.readwrite).unwrap().waiting() > 0);
+        assert!(is_busy(&drain_wal(&sqlite.readwrite).unwrap_err()));
+        assert!(!try_drain_wal(&sqlite.readwrite).unwrap());
+
+        drop(rows);
+        assert_eq!(checkpoint_wal(&sqlite.readwrite).unwrap().waiting(), 0);
+        assert!(try_drain_wal(&sqlite.readwrite).unwrap());
+        drain_wal(&sqlite.readwrite).unwrap();
+        assert_eq!(checkpoint_wal(&sqlite.readwrite).unwrap().frames, 0);
+    }
+
+    #[test]
+    fn local_tables_stay_local() {
+        let (mut sqlite, _storage) = open_with_vfs(new_journal()).unwrap();
+        attach_local(&mut sqlite).unwrap();
+        sqlite
+            .readwrite
+            .execute("create table shared (x)", [])
+            .unwrap();
+
+        with_local_writes(&mut sqlite.readwrite, |conn| {
+            conn.execute("create table local.ui (x)", [])?;
+            conn.execute("insert into local.ui values (1)", [])?;
+            // replicated tables are readable but not writable
+            assert!(conn.execute("insert into shared values (1)", []).is_err());
+            conn.query_row("select count(*) from shared", [], |r| {
+                r.get::<_, i64>(0)
+            })
+        })
+        .unwrap();
+
+        // the replicated connection can't touch local tables
+        assert!(sqlite
+            .readwrite
+            .execute("insert into local.ui values (2)", [])
+            .is_err());
+        assert!(sqlite
+            .readwrite
+            .query_row("select x from local.ui", [], |r| r.get::<_, i64>(0))
+            .is_err());
+
+        // but queries can read them
+        let x: i64 = sqlite
+            .readonly
+            .query_row("select x from local.ui", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(x, 1);
+
+        // and local tables aren't part of the document
+        let tables: i64 = sqlite
+            .readwrite
+            .query_row(
+                "select count(*) from main.sqlite_schema where name = 'ui'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
 }
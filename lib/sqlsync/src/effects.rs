@@ -0,0 +1,150 @@
+//! Outbound effects. Reducers must not call out to the world, since they are
+//! replayed on every client and again on every rebase. Instead a reducer
+//! records the side effect it wants (send an email, call an api) as a row:
+//!
+//! ```sql
+//! INSERT INTO __sqlsync_effects (effect) VALUES (?)
+//! ```
+//!
+//! The coordinator's copy of the document is the canonical one, so only
+//! effects in it are ever performed. A consumer on the server reads them with
+//! CoordinatorDocument::pending_effects, performs them, and acks them; the
+//! ack is a server mutation which deletes the rows on every replica.
+//!
+//! An effect whose consumer dies before acking it is delivered again, so
+//! effects should be made idempotent, e.g. by passing Effect::id as an
+//! idempotency key to the api being called.
+
+use rusqlite::Connection;
+
+const EFFECTS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_effects (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        effect BLOB NOT NULL
+    ) STRICT
+";
+
+/// server timeline mutations which start with this prefix are followed by
+/// the ids of the acked effects, each a little endian i64
+const ACK_MUTATION_MAGIC: &[u8; 8] = b"\0sqseff1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effect {
+    /// unique within the document, and never reused
+    pub id: i64,
+    pub effect: Vec<u8>,
+}
+
+pub(crate) fn run_effects_migration(
+    sqlite: &Connection,
+) -> rusqlite::Result<()> {
+    sqlite.execute(EFFECTS_TABLE_SQL, [])?;
+    Ok(())
+}
+
+/// read up to limit effects, oldest first, skipping those in exclude
+pub(crate) fn pending_effects(
+    sqlite: &Connection,
+    limit: usize,
+    exclude: impl Fn(i64) -> bool,
+) -> rusqlite::Result<Vec<Effect>> {
+    let mut stmt = sqlite
+        .prepare("SELECT id, effect FROM __sqlsync_effects ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    let mut out = vec![];
+    while out.len() < limit {
+        let Some(row) = rows.next()? else {
+            break;
+        };
+        let id = row.get(0)?;
+        if !exclude(id) {
+            out.push(Effect { id, effect: row.get(1)? });
+        }
+    }
+    Ok(out)
+}
+
+/// the ids in ids which are still pending, deduplicated and in order
+pub(crate) fn existing_effects(
+    sqlite: &Connection,
+    ids: &[i64],
+) -> rusqlite::Result<Vec<i64>> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut stmt = sqlite.prepare(
+        "SELECT EXISTS (SELECT 1 FROM __sqlsync_effects WHERE id = ?)",
+    )?;
+    let mut out = vec![];
+    for id in ids {
+        if stmt.query_row([id], |row| row.get(0))? {
+            out.push(id);
+        }
+    }
+    Ok(out)
+}
+
+pub(crate) fn ack_mutation(ids: &[i64]) -> Vec<u8> {
+    let mut out = ACK_MUTATION_MAGIC.to_vec();
+    for id in ids {
+        out.extend_from_slice(&id.to_le_bytes());
+    }
+    out
+}
+
+pub(crate) fn parse_ack_mutation(mutation: &[u8]) -> Option<Vec<i64>> {
+    let ids = mutation.strip_prefix(ACK_MUTATION_MAGIC)?;
+    if ids.len() % 8 != 0 {
+        return None;
+    }
+    Some(
+        ids.chunks_exact(8)
+            .map(|id| i64::from_le_bytes(id.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+pub(crate) fn delete_effects(
+    tx: &Connection,
+    ids: &[i64],
+) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare("DELETE FROM __sqlsync_effects WHERE id = ?")?;
+    for id in ids {
+        stmt.execute([id])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    #[test]
+    fn ack_deletes_effects() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_effects_migration(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO __sqlsync_effects (effect) VALUES (x'01'), (x'02'), (x'03')",
+        )
+        .unwrap();
+
+        let pending = pending_effects(&conn, 2, |id| id == 1).unwrap();
+        assert_eq!(
+            pending,
+            vec![
+                Effect { id: 2, effect: vec![2] },
+                Effect { id: 3, effect: vec![3] }
+            ]
+        );
+
+        let ids = parse_ack_mutation(&ack_mutation(&[1, 2])).unwrap();
+        let tx = conn.transaction().unwrap();
+        delete_effects(&tx, &ids).unwrap();
+        tx.commit().unwrap();
+        let pending = pending_effects(&conn, 10, |_| false).unwrap();
+        assert_eq!(pending, vec![Effect { id: 3, effect: vec![3] }]);
+        assert_eq!(existing_effects(&conn, &[3, 1, 3, 9]).unwrap(), vec![3]);
+    }
+}
@@ -0,0 +1,260 @@
+//! EmbeddedDocument is the state the native bindings (Node.js, Python and
+//! the C ABI) share: a LocalDocument, the signals it raises, and the Session
+//! for the current connection to the coordinator, if any.
+//!
+//! Like Session it does no io. Every call returns the replication messages
+//! the host must send to the coordinator, so a binding only converts types
+//! and leaves the choice of websocket library to its host.
+
+use std::{cell::Cell, rc::Rc};
+
+use thiserror::Error;
+
+use crate::{
+    error,
+    local::{LocalDocument, Signal},
+    replication::{
+        HeartbeatConfig, ReplicationDestination, ReplicationMsg,
+        ReplicationSource,
+    },
+    session::{self, Session, SessionError},
+    Journal, Reducer,
+};
+
+/// matches the receive window used by the browser worker
+pub const RECEIVE_WINDOW_FRAMES: u32 = 500;
+
+#[derive(Error, Debug)]
+pub enum EmbedError {
+    #[error("document is not connected")]
+    NotConnected,
+
+    #[error(transparent)]
+    SqlSync(#[from] error::Error),
+
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
+
+pub type Result<T> = std::result::Result<T, EmbedError>;
+
+/// a signal which records that it fired until it's taken
+#[derive(Clone, Default)]
+pub struct Flag(Rc<Cell<bool>>);
+
+impl Flag {
+    pub fn take(&self) -> bool {
+        self.0.replace(false)
+    }
+}
+
+impl Signal for Flag {
+    fn emit(&mut self) {
+        self.0.set(true)
+    }
+}
+
+pub struct EmbeddedDocument<J> {
+    doc: LocalDocument<J, Flag>,
+    storage_changed: Flag,
+    timeline_changed: Flag,
+    can_rebase: Flag,
+    session: Option<Session>,
+}
+
+impl<J> EmbeddedDocument<J>
+where
+    J: Journal + ReplicationSource + ReplicationDestination,
+{
+    pub fn open(storage: J, timeline: J, reducer: Reducer) -> Result<Self> {
+        let storage_changed = Flag::default();
+        let timeline_changed = Flag::default();
+        let can_rebase = Flag::default();
+
+        let doc = LocalDocument::open(
+            storage,
+            timeline,
+            reducer,
+            storage_changed.clone(),
+            timeline_changed.clone(),
+            can_rebase.clone(),
+        )?;
+
+        Ok(Self {
+            doc,
+            storage_changed,
+            timeline_changed,
+            can_rebase,
+            session: None,
+        })
+    }
+
+    pub fn doc(&self) -> &LocalDocument<J, Flag> {
+        &self.doc
+    }
+
+    pub fn doc_mut(&mut self) -> &mut LocalDocument<J, Flag> {
+        &mut self.doc
+    }
+
+    /// true if storage changed since the last call, in which case queries
+    /// may have different results
+    pub fn take_storage_changed(&mut self) -> bool {
+        self.storage_changed.take()
+    }
+
+    /// apply a mutation, returning any messages to send to the coordinator
+    pub fn mutate(&mut self, mutation: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.doc.mutate(mutation)?;
+        self.settle()
+    }
+
+    /// start replicating over a new connection to the coordinator, returning
+    /// the messages to send once it opens
+    pub fn connect(&mut self, now_ms: i64) -> Result<Vec<Vec<u8>>> {
+        let session = Session::new(HeartbeatConfig::default(), now_ms);
+        let window_msg =
+            ReplicationMsg::Window { frames: RECEIVE_WINDOW_FRAMES };
+        let out =
+            vec![session.start(&self.doc)?, session::encode(&window_msg)?];
+
+        self.session = Some(session);
+        Ok(out)
+    }
+
+    /// handle a binary message from the coordinator
+    pub fn receive(&mut self, msg: &[u8], now_ms: i64) -> Result<Vec<Vec<u8>>> {
+        let session = self.session.as_mut().ok_or(EmbedError::NotConnected)?;
+
+        let mut out = Vec::new();
+        out.extend(session.receive(&mut self.doc, msg, now_ms)?);
+
+        // acknowledgements may open the window for more frames
+        self.timeline_changed.emit();
+        out.extend(self.settle()?);
+        Ok(out)
+    }
+
+    /// should be called once deadline passes; returns a ping to send when
+    /// the connection has been idle, and SessionError::TimedOut once the
+    /// coordinator stops responding, in which case the session is dropped
+    /// and the host should reconnect
+    pub fn heartbeat(&mut self, now_ms: i64) -> Result<Vec<Vec<u8>>> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(vec![]);
+        };
+        match session.heartbeat(now_ms) {
+            Ok(ping) => Ok(ping.into_iter().collect()),
+            Err(err) => {
+                if matches!(err, SessionError::TimedOut) {
+                    self.session = None;
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    /// unix milliseconds at which heartbeat should next be called, or None
+    /// if the document is not connected
+    pub fn deadline(&self) -> Option<i64> {
+        self.session.as_ref().map(Session::deadline)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// forget the current connection; call connect to start over
+    pub fn disconnect(&mut self) {
+        self.session = None;
+    }
+
+    /// rebase if possible and collect any frames to sync to the coordinator
+    fn settle(&mut self) -> Result<Vec<Vec<u8>>> {
+        if self.can_rebase.take() {
+            self.doc.rebase()?;
+        }
+
+        if self.timeline_changed.take() {
+            if let Some(session) = self.session.as_mut() {
+                return Ok(session.sync(&self.doc)?);
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{EmbedError, EmbeddedDocument};
+    use crate::{
+        reducer::tests::exec_guest,
+        replication::{HeartbeatConfig, ReplicationSource},
+        session::{Session, SessionError},
+        Journal, JournalId, LsnRange, MemoryJournal, Reducer,
+    };
+
+    fn open() -> EmbeddedDocument<MemoryJournal> {
+        let mut rng = rand::thread_rng();
+        let wasm = exec_guest("", "CREATE TABLE IF NOT EXISTS t (x)", 1);
+        EmbeddedDocument::open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            Reducer::new(&wasm[..]).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn replicates_mutations_once_connected() {
+        let mut doc = open();
+        assert!(matches!(doc.receive(b"", 0), Err(EmbedError::NotConnected)));
+        // nothing is sent until there's a connection
+        assert!(doc.mutate(b"a").unwrap().is_empty());
+        assert!(doc.take_storage_changed());
+
+        // stands in for the coordinator's copy of the timeline
+        let mut remote = MemoryJournal::open(doc.doc().source_id()).unwrap();
+        let mut remote_session = Session::new(HeartbeatConfig::default(), 0);
+
+        let mut to_remote = doc.connect(0).unwrap();
+        while !to_remote.is_empty() {
+            let mut to_doc = vec![];
+            for msg in to_remote.drain(..) {
+                to_doc.extend(
+                    remote_session.receive(&mut remote, &msg, 0).unwrap(),
+                );
+            }
+            for msg in to_doc {
+                to_remote.extend(doc.receive(&msg, 0).unwrap());
+            }
+        }
+        assert_eq!(remote.range(), LsnRange::new(0, 0));
+
+        // new mutations are synced straight away
+        let out = doc.mutate(b"b").unwrap();
+        assert_eq!(out.len(), 1);
+        remote_session.receive(&mut remote, &out[0], 0).unwrap();
+        assert_eq!(remote.range(), LsnRange::new(0, 1));
+    }
+
+    #[test]
+    fn heartbeat_timeouts_drop_the_session() {
+        let mut doc = open();
+        assert!(doc.heartbeat(0).unwrap().is_empty());
+        assert_eq!(doc.deadline(), None);
+
+        doc.connect(0).unwrap();
+        assert!(doc.is_connected());
+        let timeout =
+            HeartbeatConfig::default().timeout + Duration::from_secs(1);
+        assert!(matches!(
+            doc.heartbeat(timeout.as_millis() as i64),
+            Err(EmbedError::Session(SessionError::TimedOut))
+        ));
+        assert!(!doc.is_connected());
+        assert_eq!(doc.deadline(), None);
+    }
+}
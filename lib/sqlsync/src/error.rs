@@ -1,8 +1,11 @@
 use thiserror::Error;
 
 use crate::{
-    reducer::ReducerError, replication::ReplicationError, timeline::TimelineError, JournalError,
-    JournalIdParseError,
+    lsn::LsnRange,
+    reducer::{ModuleDigest, ReducerError, ReducerTrap},
+    replication::ReplicationError,
+    timeline::{ConsistencyToken, TimelineError},
+    JournalError, JournalId, JournalIdParseError, Lsn,
 };
 
 #[derive(Error, Debug)]
@@ -24,6 +27,66 @@ pub enum Error {
 
     #[error(transparent)]
     SqliteError(#[from] rusqlite::Error),
+
+    #[error("document {0} already exists")]
+    DocumentExists(JournalId),
+
+    #[error("mutation is {len} bytes, larger than the maximum of {max}")]
+    MutationTooLarge { len: u64, max: u64 },
+
+    /// the coordinator runs a different reducer, so this client's document
+    /// is read only until it loads that reducer
+    #[error(
+        "the coordinator runs reducer {}, update to it to mutate the document",
+        bs58::encode(digest).into_string()
+    )]
+    UpdateRequired { digest: ModuleDigest },
+
+    #[error("unknown draft {0}")]
+    UnknownDraft(u64),
+
+    /// the mutation hasn't reached the document, so there's nothing to wait
+    /// for yet
+    #[error("mutation {0} hasn't been received")]
+    NotObserved(ConsistencyToken),
+
+    #[error("storage lsn {lsn} isn't retained, history covers {retained}")]
+    LsnNotRetained { lsn: Lsn, retained: LsnRange },
+}
+
+impl Error {
+    /// true if this error was caused by the document reaching its max size
+    pub fn is_storage_full(&self) -> bool {
+        let sqlite_err = match self {
+            Error::SqliteError(err)
+            | Error::TimelineError(TimelineError::Sqlite(err)) => err,
+            _ => return false,
+        };
+        sqlite_err.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull)
+    }
+
+    /// true if a lock held by another connection caused this error, see
+    /// BusyBackoff
+    pub fn is_busy(&self) -> bool {
+        match self {
+            Error::SqliteError(err)
+            | Error::TimelineError(TimelineError::Sqlite(err)) => {
+                crate::db::is_busy(err)
+            }
+            _ => false,
+        }
+    }
+
+    /// returns the reducer trap which caused this error, if any
+    pub fn reducer_trap(&self) -> Option<&ReducerTrap> {
+        match self {
+            Error::ReducerError(ReducerError::Trap(trap))
+            | Error::TimelineError(TimelineError::ReducerError(
+                ReducerError::Trap(trap),
+            )) => Some(trap),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
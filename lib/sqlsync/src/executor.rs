@@ -0,0 +1,272 @@
+//! DocumentExecutor hosts many CoordinatorDocuments on a bounded pool of
+//! native threads. Each document behaves like an actor: work is posted to its
+//! mailbox and executed in order by whichever worker picks the document up, so
+//! a document is never touched by two threads at once while a slow document
+//! only ever occupies a single worker.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{coordinator::CoordinatorDocument, logging, Journal, JournalId};
+
+/// the maximum number of jobs a worker will run for a document before giving
+/// other documents a turn
+const MAX_JOBS_PER_TURN: usize = 32;
+
+/// how long a worker will spend applying mutations for a document before
+/// giving other documents a turn
+const STEP_BUDGET: Duration = Duration::from_millis(50);
+
+type Job<J> = Box<dyn FnOnce(&mut CoordinatorDocument<J>) + Send>;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct Mailbox<J: Journal> {
+    jobs: VecDeque<Job<J>>,
+    /// true while the document is in the run queue or held by a worker
+    scheduled: bool,
+}
+
+struct Actor<J: Journal> {
+    id: JournalId,
+    mailbox: Mutex<Mailbox<J>>,
+    doc: Mutex<CoordinatorDocument<J>>,
+}
+
+struct Shared<J: Journal> {
+    run_queue: Mutex<VecDeque<Arc<Actor<J>>>>,
+    ready: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl<J: Journal> Shared<J> {
+    fn schedule(&self, actor: Arc<Actor<J>>) {
+        lock(&self.run_queue).push_back(actor);
+        self.ready.notify_one();
+    }
+
+    /// block until there is an actor to run, or the executor shuts down
+    fn next(&self) -> Option<Arc<Actor<J>>> {
+        let mut queue = lock(&self.run_queue);
+        loop {
+            if let Some(actor) = queue.pop_front() {
+                return Some(actor);
+            }
+            if self.shutdown.load(Ordering::Acquire) {
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+pub struct DocumentExecutor<J: Journal> {
+    shared: Arc<Shared<J>>,
+    actors: Mutex<HashMap<JournalId, Arc<Actor<J>>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<J> DocumentExecutor<J>
+where
+    J: Journal + Send + 'static,
+    J::Factory: Send,
+{
+    pub fn new(num_workers: usize) -> Self {
+        assert!(num_workers > 0, "executor needs at least one worker");
+
+        let shared = Arc::new(Shared {
+            run_queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let workers = (0..num_workers)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("sqlsync-executor-{}", i))
+                    .spawn(move || {
+                        while let Some(actor) = shared.next() {
+                            run_turn(&shared, actor);
+                        }
+                    })
+                    .expect("failed to spawn executor worker")
+            })
+            .collect();
+
+        Self { shared, actors: Mutex::new(HashMap::new()), workers }
+    }
+
+    /// start hosting a document, returning its id
+    pub fn insert(&self, doc: CoordinatorDocument<J>) -> JournalId {
+        let id = doc.doc_id();
+        let actor = Arc::new(Actor {
+            id,
+            mailbox: Mutex::new(Mailbox {
+                jobs: VecDeque::new(),
+                scheduled: false,
+            }),
+            doc: Mutex::new(doc),
+        });
+        lock(&self.actors).insert(id, actor);
+        id
+    }
+
+    /// stop hosting a document. jobs already in its mailbox still run, and
+    /// the document is dropped once the last of them completes
+    pub fn remove(&self, id: JournalId) -> bool {
+        lock(&self.actors).remove(&id).is_some()
+    }
+
+    /// queue f to run against the document. jobs for a document run in the
+    /// order they are sent; jobs for different documents run in parallel.
+    /// returns false if the document isn't hosted by this executor
+    pub fn send<F>(&self, id: JournalId, f: F) -> bool
+    where
+        F: FnOnce(&mut CoordinatorDocument<J>) + Send + 'static,
+    {
+        let Some(actor) = lock(&self.actors).get(&id).cloned() else {
+            return false;
+        };
+
+        let should_schedule = {
+            let mut mailbox = lock(&actor.mailbox);
+            mailbox.jobs.push_back(Box::new(f));
+            !std::mem::replace(&mut mailbox.scheduled, true)
+        };
+        if should_schedule {
+            self.shared.schedule(actor);
+        }
+        true
+    }
+
+    /// like send, but returns a receiver for the job's result
+    pub fn call<F, R>(&self, id: JournalId, f: F) -> Option<mpsc::Receiver<R>>
+    where
+        F: FnOnce(&mut CoordinatorDocument<J>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let sent = self.send(id, move |doc| {
+            let _ = tx.send(f(doc));
+        });
+        sent.then_some(rx)
+    }
+}
+
+impl<J: Journal> Drop for DocumentExecutor<J> {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        // notify while holding the lock so no worker can miss the shutdown
+        // between checking the flag and waiting
+        let queue = lock(&self.shared.run_queue);
+        self.shared.ready.notify_all();
+        drop(queue);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_turn<J: Journal>(shared: &Shared<J>, actor: Arc<Actor<J>>) {
+    let has_pending_work = {
+        let mut doc = lock(&actor.doc);
+
+        for _ in 0..MAX_JOBS_PER_TURN {
+            let Some(job) = lock(&actor.mailbox).jobs.pop_front() else {
+                break;
+            };
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| job(&mut doc)));
+            if result.is_err() {
+                logging::error!(doc = actor.id; "executor job panicked");
+            }
+        }
+
+        if doc.has_pending_work() {
+            if let Err(e) = doc.step_with_budget(STEP_BUDGET) {
+                logging::error!(doc = actor.id; "failed to step document: {}", e);
+            }
+        }
+        doc.has_pending_work()
+    };
+
+    let reschedule = {
+        let mut mailbox = lock(&actor.mailbox);
+        mailbox.scheduled = has_pending_work || !mailbox.jobs.is_empty();
+        mailbox.scheduled
+    };
+    if reschedule {
+        shared.schedule(actor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::DocumentExecutor;
+    use crate::{
+        coordinator::CoordinatorDocument, reducer::tests::guest, JournalId,
+        MemoryJournal, MemoryJournalFactory,
+    };
+
+    fn new_doc() -> CoordinatorDocument<MemoryJournal> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        CoordinatorDocument::open(
+            MemoryJournal::open(id).unwrap(),
+            MemoryJournalFactory,
+            &guest(""),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn runs_jobs_in_order_and_survives_panics() {
+        let executor = DocumentExecutor::new(2);
+        let id = executor.insert(new_doc());
+
+        let order = Arc::new(Mutex::new(vec![]));
+        for i in 0..100 {
+            let order = order.clone();
+            assert!(executor.send(id, move |_| order.lock().unwrap().push(i)));
+        }
+        assert!(executor.send(id, |_| panic!("job panicked")));
+        let doc_id = executor.call(id, |doc| doc.doc_id()).unwrap();
+        assert_eq!(doc_id.recv().unwrap(), id);
+        assert_eq!(*order.lock().unwrap(), (0..100).collect::<Vec<_>>());
+
+        // documents which aren't hosted don't take jobs
+        let other = JournalId::new128(&mut rand::thread_rng());
+        assert!(!executor.send(other, |_| {}));
+        assert!(executor.remove(id));
+        assert!(executor.call(id, |_| ()).is_none());
+    }
+
+    #[test]
+    fn steps_documents_until_they_are_idle() {
+        let executor = DocumentExecutor::new(1);
+        let id = executor.insert(new_doc());
+
+        let lsn = executor.call(id, |doc| doc.mutate(b"mutation").unwrap());
+        assert_eq!(lsn.unwrap().recv().unwrap(), 0);
+
+        // the mutation is applied by the worker without any more jobs
+        let applied = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let pending = executor.call(id, |doc| doc.has_pending_work());
+            !pending.unwrap().recv().unwrap()
+        });
+        assert!(applied);
+    }
+}
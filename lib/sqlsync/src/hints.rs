@@ -0,0 +1,225 @@
+//! Mutation hints. A reducer declares properties of its mutations, keyed by
+//! the app-defined tag clients attach to them (see timeline::FrameMeta), by
+//! inserting into __sqlsync_mutation_hints:
+//!
+//! ```sql
+//! INSERT OR REPLACE INTO __sqlsync_mutation_hints (tag, idempotent, commutes)
+//! VALUES (3, true, false)
+//! ```
+//!
+//! - an idempotent mutation has no further effect when it's applied twice in
+//!   a row, so a timeline's back to back repeats of it (same bytes) are only
+//!   applied once, both by the coordinator and when clients rebase. In debug
+//!   builds every skipped repeat is still applied in a savepoint and the
+//!   document is checked to be unchanged.
+//! - mutations which commute may be reordered among each other. Under
+//!   ApplyOrder::Arrival the coordinator uses this to apply a timeline's
+//!   later commuting mutations early, in the same storage frame as its
+//!   earlier ones, when only commuting mutations from other timelines
+//!   arrived in between. In debug builds every such batch is also applied
+//!   in arrival order, in a transaction which is rolled back, and the
+//!   document is checked to end up the same either way.
+//!
+//! Mutations without a tag never match a hint. Hints are read from the
+//! document as each range of mutations is applied, so a reducer can declare
+//! them in its schema migration.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use rusqlite::{types::ValueRef, Connection};
+
+use crate::timeline::TimelineFrame;
+
+const HINTS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_mutation_hints (
+        tag INTEGER PRIMARY KEY NOT NULL,
+        idempotent INTEGER NOT NULL DEFAULT false,
+        commutes INTEGER NOT NULL DEFAULT false
+    ) STRICT
+";
+
+pub(crate) fn run_hints_migration(sqlite: &Connection) -> rusqlite::Result<()> {
+    sqlite.execute(HINTS_TABLE_SQL, [])?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MutationHints {
+    idempotent: HashSet<u32>,
+    commutes: HashSet<u32>,
+}
+
+impl MutationHints {
+    /// the hints declared in the document
+    pub fn read(sqlite: &Connection) -> rusqlite::Result<Self> {
+        let mut hints = Self::default();
+        let mut stmt = sqlite.prepare_cached(
+            "SELECT tag, idempotent, commutes FROM __sqlsync_mutation_hints",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let tag: u32 = row.get(0)?;
+            if row.get::<_, bool>(1)? {
+                hints.idempotent.insert(tag);
+            }
+            if row.get::<_, bool>(2)? {
+                hints.commutes.insert(tag);
+            }
+        }
+        Ok(hints)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.idempotent.is_empty() && self.commutes.is_empty()
+    }
+
+    pub fn idempotent(&self, frame: &TimelineFrame) -> bool {
+        frame
+            .meta
+            .tag
+            .is_some_and(|tag| self.idempotent.contains(&tag))
+    }
+
+    pub fn commutes(&self, frame: &TimelineFrame) -> bool {
+        frame
+            .meta
+            .tag
+            .is_some_and(|tag| self.commutes.contains(&tag))
+    }
+}
+
+/// RepeatFilter spots back to back repeats of an idempotent mutation while
+/// a timeline is replayed in order
+pub(crate) struct RepeatFilter {
+    hints: MutationHints,
+    last: Option<Vec<u8>>,
+}
+
+impl RepeatFilter {
+    pub fn new(hints: MutationHints) -> Self {
+        Self { hints, last: None }
+    }
+
+    /// whether frame repeats the previous frame passed to this filter and
+    /// may be skipped
+    pub fn is_repeat(&mut self, frame: &TimelineFrame) -> bool {
+        if !self.hints.idempotent(frame) {
+            self.last = None;
+            return false;
+        }
+        if self.last.as_deref() == Some(frame.mutation) {
+            return true;
+        }
+        self.last = Some(frame.mutation.to_vec());
+        false
+    }
+}
+
+/// a digest of every row in the document's own tables, for checking in debug
+/// builds that a mutation declared idempotent didn't change anything
+pub(crate) fn content_digest(sqlite: &Connection) -> rusqlite::Result<u64> {
+    let tables = sqlite
+        .prepare_cached(
+            "SELECT name FROM sqlite_schema WHERE type = 'table'
+            AND name NOT GLOB 'sqlite_*' AND name NOT GLOB '__sqlsync_*'
+            ORDER BY name",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut hasher = DefaultHasher::new();
+    for table in tables {
+        table.hash(&mut hasher);
+        let mut stmt = sqlite.prepare(&format!(
+            "SELECT * FROM \"{}\"",
+            table.replace('"', "\"\"")
+        ))?;
+        let columns = stmt.column_count();
+        let mut rows = stmt.query([])?;
+        // rows are combined in any order, since tables may lack a rowid
+        let mut digest = 0u64;
+        while let Some(row) = rows.next()? {
+            let mut row_hasher = DefaultHasher::new();
+            for i in 0..columns {
+                match row.get_ref(i)? {
+                    ValueRef::Null => 0u8.hash(&mut row_hasher),
+                    ValueRef::Integer(v) => v.hash(&mut row_hasher),
+                    ValueRef::Real(v) => v.to_bits().hash(&mut row_hasher),
+                    ValueRef::Text(v) | ValueRef::Blob(v) => {
+                        v.hash(&mut row_hasher)
+                    }
+                }
+            }
+            digest = digest.wrapping_add(row_hasher.finish());
+        }
+        digest.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{
+        content_digest, run_hints_migration, MutationHints, RepeatFilter,
+    };
+    use crate::timeline::{Codec, FrameKind, FrameMeta, TimelineFrame};
+
+    fn frame(tag: Option<u32>, mutation: &[u8]) -> TimelineFrame {
+        TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta { timestamp_ms: None, tag },
+            codec: Codec::Raw,
+            mutation,
+        }
+    }
+
+    #[test]
+    fn skips_repeats_of_idempotent_mutations() {
+        let sqlite = Connection::open_in_memory().unwrap();
+        run_hints_migration(&sqlite).unwrap();
+        sqlite
+            .execute_batch(
+                "INSERT INTO __sqlsync_mutation_hints (tag, idempotent)
+                VALUES (1, true), (2, false)",
+            )
+            .unwrap();
+        let mut filter =
+            RepeatFilter::new(MutationHints::read(&sqlite).unwrap());
+        let repeats: Vec<bool> = [
+            frame(Some(1), b"a"),
+            frame(Some(1), b"a"),
+            frame(Some(1), b"b"),
+            frame(Some(2), b"c"),
+            frame(Some(2), b"c"),
+            frame(Some(1), b"b"),
+            frame(None, b"b"),
+        ]
+        .iter()
+        .map(|f| filter.is_repeat(f))
+        .collect();
+        assert_eq!(repeats, [false, true, false, false, false, false, false]);
+    }
+
+    #[test]
+    fn digest_ignores_row_order() {
+        let sqlite = Connection::open_in_memory().unwrap();
+        sqlite
+            .execute_batch("CREATE TABLE t (v); INSERT INTO t VALUES (1), (2);")
+            .unwrap();
+        let digest = content_digest(&sqlite).unwrap();
+        sqlite
+            .execute_batch("DELETE FROM t; INSERT INTO t VALUES (2), (1);")
+            .unwrap();
+        assert_eq!(content_digest(&sqlite).unwrap(), digest);
+        sqlite
+            .execute_batch("UPDATE t SET v = 3 WHERE v = 1")
+            .unwrap();
+        assert_ne!(content_digest(&sqlite).unwrap(), digest);
+    }
+}
@@ -0,0 +1,1200 @@
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use crate::logging;
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::PositionedReader;
+use crate::replication::{
+    ReplicationDestination, ReplicationError, ReplicationSource,
+};
+use crate::{JournalError, JournalFactory, Serializable};
+
+use super::{Cursor, Journal, JournalId, JournalResult, Scannable};
+
+/// FileJournal file layout:
+///
+/// header (HEADER_SIZE bytes):
+///   magic: [u8; 8]
+///   version: u32
+///   journal id length: u8
+///   journal id: [u8; 32] (zero padded)
+///   first lsn: u64
+///
+/// followed by frames:
+///   payload length: u32
+///   payload checksum: u32
+///   payload: [u8; length]
+///
+/// All integers are little endian. Frames are only ever appended, so a crash
+/// can at worst leave a partially written frame at the end of the file. On
+/// open any frame which is truncated or fails its checksum is discarded along
+/// with everything after it.
+const MAGIC: &[u8; 8] = b"SQLSYNCJ";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 64;
+const FRAME_HEADER_SIZE: u64 = 8;
+
+/// 32 bit FNV-1a, used to detect torn frames
+fn frame_checksum(data: &[u8]) -> u32 {
+    extend_checksum(0x811c9dc5, data)
+}
+
+fn extend_checksum(mut hash: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// the most a FrameWriter buffers before writing to the file
+const FRAME_WRITE_BUFFER: usize = 64 * 1024;
+
+/// FrameWriter streams a payload into the file as it's serialized,
+/// checksumming it along the way, so appending a frame never holds a copy
+/// of the whole payload. the frame header is written by the caller once the
+/// payload is on disk
+struct FrameWriter<'a> {
+    file: &'a File,
+    /// where the next flushed byte goes
+    offset: u64,
+    len: u64,
+    checksum: u32,
+    buf: Vec<u8>,
+}
+
+impl<'a> FrameWriter<'a> {
+    fn new(file: &'a File, offset: u64) -> Self {
+        Self {
+            file,
+            offset,
+            len: 0,
+            checksum: frame_checksum(&[]),
+            buf: Vec::with_capacity(FRAME_WRITE_BUFFER),
+        }
+    }
+
+    /// flush the rest of the payload, returning its length and checksum
+    fn finish(mut self) -> io::Result<(u64, u32)> {
+        self.flush()?;
+        Ok((self.len, self.checksum))
+    }
+}
+
+impl Write for FrameWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > FRAME_WRITE_BUFFER {
+            self.flush()?;
+        }
+        if data.len() >= FRAME_WRITE_BUFFER {
+            write_all_at(self.file, data, self.offset)?;
+            self.offset += data.len() as u64;
+        } else {
+            self.buf.extend_from_slice(data);
+        }
+        self.checksum = extend_checksum(self.checksum, data);
+        self.len += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_all_at(self.file, &self.buf, self.offset)?;
+        self.offset += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(
+    file: &File,
+    mut buf: &[u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn read_exact_at(
+    file: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Clone, Copy)]
+struct FrameLoc {
+    /// offset of the payload in the file
+    offset: u64,
+    len: u32,
+}
+
+/// FramePipeline checksums and writes appended frames on a background
+/// thread, so that appending a large frame doesn't hold up the commit which
+/// produced it. frames are written one at a time in the order they were
+/// appended, and until a frame is on disk it's read from its queued payload
+struct FramePipeline {
+    worker: Option<(
+        mpsc::Sender<PipelineMsg>,
+        thread::JoinHandle<io::Result<()>>,
+    )>,
+    /// the offset up to which frames are on disk
+    written: Arc<AtomicU64>,
+    /// frames which may not be on disk yet by offset, oldest first
+    queued: VecDeque<(u64, Arc<[u8]>)>,
+    /// frames appended after a failed write are never written, so once the
+    /// worker fails every later write or sync fails too
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+impl FramePipeline {
+    fn new(end: u64) -> Self {
+        Self {
+            worker: None,
+            written: Arc::new(AtomicU64::new(end)),
+            queued: VecDeque::new(),
+            failed: None,
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match &self.failed {
+            Some((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// queue the frame which starts at offset
+    fn push(
+        &mut self,
+        file: &File,
+        offset: u64,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        self.check()?;
+        let written = self.written.load(Ordering::Acquire);
+        while self.queued.front().is_some_and(|(at, _)| *at < written) {
+            self.queued.pop_front();
+        }
+
+        if self.worker.is_none() {
+            let file = file.try_clone()?;
+            let written = self.written.clone();
+            let (tx, rx) = mpsc::channel();
+            let handle = thread::Builder::new()
+                .name("sqlsync-journal".into())
+                .spawn(move || write_frames(file, rx, written))?;
+            self.worker = Some((tx, handle));
+        }
+
+        let payload: Arc<[u8]> = payload.into();
+        self.queued.push_back((offset, payload.clone()));
+        self.send(PipelineMsg::Frame(offset, payload))
+    }
+
+    fn send(&mut self, msg: PipelineMsg) -> io::Result<()> {
+        let (tx, _) = self.worker.as_ref().expect("pipeline has a worker");
+        if tx.send(msg).is_err() {
+            // the worker has stopped, find out why
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    /// wait for every queued frame to be written, keeping the worker
+    fn flush(&mut self) -> io::Result<()> {
+        self.check()?;
+        if self.worker.is_none() {
+            return Ok(());
+        }
+        let (done, flushed) = mpsc::sync_channel(1);
+        self.send(PipelineMsg::Flush(done))?;
+        if flushed.recv().is_err() {
+            // the worker stopped before it got to the flush
+            self.finish()?;
+        }
+        self.queued.clear();
+        Ok(())
+    }
+
+    /// wait for every queued frame to be written and stop the worker, which
+    /// the next push starts again
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some((tx, handle)) = self.worker.take() {
+            drop(tx);
+            let result = handle.join().unwrap_or_else(|_| {
+                Err(io::Error::other("journal writer panicked"))
+            });
+            if let Err(err) = result {
+                self.failed = Some((err.kind(), err.to_string()));
+            }
+        }
+        self.check()?;
+        self.queued.clear();
+        Ok(())
+    }
+
+    /// the payload of the frame starting at offset, if it may not be on disk
+    fn get(&self, offset: u64) -> Option<Arc<[u8]>> {
+        if offset < self.written.load(Ordering::Acquire) {
+            return None;
+        }
+        self.queued
+            .iter()
+            .find(|(at, _)| *at == offset)
+            .map(|(_, payload)| payload.clone())
+    }
+}
+
+enum PipelineMsg {
+    /// the payload of the frame which starts at offset
+    Frame(u64, Arc<[u8]>),
+    /// replied to once every frame sent before it is on disk
+    Flush(mpsc::SyncSender<()>),
+}
+
+fn write_frames(
+    file: File,
+    msgs: mpsc::Receiver<PipelineMsg>,
+    written: Arc<AtomicU64>,
+) -> io::Result<()> {
+    for msg in msgs {
+        let (offset, payload) = match msg {
+            PipelineMsg::Frame(offset, payload) => (offset, payload),
+            PipelineMsg::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let mut header = [0u8; FRAME_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&frame_checksum(&payload).to_le_bytes());
+
+        let wrote = write_all_at(&file, &header, offset).and_then(|_| {
+            write_all_at(&file, &payload, offset + FRAME_HEADER_SIZE)
+        });
+        if let Err(err) = wrote {
+            let _ = file.set_len(offset);
+            return Err(err);
+        }
+        written.store(
+            offset + FRAME_HEADER_SIZE + payload.len() as u64,
+            Ordering::Release,
+        );
+    }
+    Ok(())
+}
+
+pub struct FileJournal {
+    id: JournalId,
+    path: PathBuf,
+    file: File,
+    range: LsnRange,
+    frames: Vec<FrameLoc>,
+    /// the offset at which the next frame will be written
+    end: u64,
+    pipeline: Option<FramePipeline>,
+}
+
+impl Debug for FileJournal {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("FileJournal")
+            .field(&self.id)
+            .field(&self.path)
+            .field(&self.range)
+            .finish()
+    }
+}
+
+impl FileJournal {
+    /// open the journal stored at path, creating it if it doesn't exist
+    pub fn open(path: impl AsRef<Path>, id: JournalId) -> JournalResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        if file.metadata()?.len() < HEADER_SIZE {
+            // either brand new, or we crashed while creating the file
+            Self::write_header(&mut file, id, 0)?;
+            file.set_len(HEADER_SIZE)?;
+            file.sync_all()?;
+        }
+
+        let first = Self::read_header(&file, id)?;
+        let mut journal = FileJournal {
+            id,
+            path,
+            file,
+            range: LsnRange::Empty { nextlsn: first },
+            frames: Vec::new(),
+            end: HEADER_SIZE,
+            pipeline: None,
+        };
+        journal.recover()?;
+        Ok(journal)
+    }
+
+    fn write_header(
+        file: &mut File,
+        id: JournalId,
+        first: Lsn,
+    ) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        let id_bytes = id.bytes();
+        header[12] = id_bytes.len() as u8;
+        header[13..13 + id_bytes.len()].copy_from_slice(id_bytes);
+        header[45..53].copy_from_slice(&first.to_le_bytes());
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)
+    }
+
+    fn read_header(file: &File, id: JournalId) -> JournalResult<Lsn> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        let n = read_at(file, &mut header, 0)?;
+        if n != header.len() || &header[0..8] != MAGIC {
+            return Err(
+                invalid_data("not a sqlsync journal file".into()).into()
+            );
+        }
+
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported journal file version {}",
+                version
+            ))
+            .into());
+        }
+
+        let id_len = header[12] as usize;
+        if id_len > 32 || &header[13..13 + id_len] != id.bytes() {
+            return Err(invalid_data(format!(
+                "journal file does not belong to journal {}",
+                id
+            ))
+            .into());
+        }
+
+        Ok(u64::from_le_bytes(header[45..53].try_into().unwrap()))
+    }
+
+    /// scan the file, indexing every intact frame and discarding any torn
+    /// frame (and everything after it) at the end of the file
+    fn recover(&mut self) -> JournalResult<()> {
+        let file_len = self.file.metadata()?.len();
+        let mut offset = HEADER_SIZE;
+
+        while offset < file_len {
+            let mut frame_header = [0u8; FRAME_HEADER_SIZE as usize];
+            if offset + FRAME_HEADER_SIZE > file_len
+                || read_at(&self.file, &mut frame_header, offset)?
+                    != frame_header.len()
+            {
+                break;
+            }
+            let len =
+                u32::from_le_bytes(frame_header[0..4].try_into().unwrap());
+            let checksum =
+                u32::from_le_bytes(frame_header[4..8].try_into().unwrap());
+
+            let payload_offset = offset + FRAME_HEADER_SIZE;
+            if payload_offset + len as u64 > file_len {
+                break;
+            }
+            let mut payload = vec![0u8; len as usize];
+            read_exact_at(&self.file, &mut payload, payload_offset)?;
+            if frame_checksum(&payload) != checksum {
+                break;
+            }
+
+            self.frames.push(FrameLoc { offset: payload_offset, len });
+            self.range = self.range.extend_by(1);
+            offset = payload_offset + len as u64;
+        }
+
+        if offset < file_len {
+            logging::warn!(
+                doc = self.id;
+                "discarding {} bytes of torn frames at the end of {:?}",
+                file_len - offset,
+                self.path
+            );
+            self.file.set_len(offset)?;
+            self.file.sync_all()?;
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    /// write appended frames on a background thread, see FramePipeline.
+    /// frames may not be on disk until the next sync, which is already the
+    /// case for the OS's own buffering with Durability::Relaxed
+    pub fn set_pipelined(&mut self, pipelined: bool) -> JournalResult<()> {
+        match (pipelined, self.pipeline.take()) {
+            (true, pipeline) => {
+                self.pipeline = Some(
+                    pipeline.unwrap_or_else(|| FramePipeline::new(self.end)),
+                )
+            }
+            (false, Some(mut pipeline)) => pipeline.finish()?,
+            (false, None) => {}
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        let len = payload.len() as u32;
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.push(&self.file, self.end, payload)?;
+        } else {
+            let mut frame =
+                Vec::with_capacity(FRAME_HEADER_SIZE as usize + payload.len());
+            frame.extend_from_slice(&len.to_le_bytes());
+            frame.extend_from_slice(&frame_checksum(&payload).to_le_bytes());
+            frame.extend_from_slice(&payload);
+
+            self.file.seek(SeekFrom::Start(self.end))?;
+            if let Err(err) = self.file.write_all(&frame) {
+                // don't leave a partial frame behind for the next append to
+                // land after
+                let _ = self.file.set_len(self.end);
+                return Err(err);
+            }
+        }
+
+        self.appended(len);
+        Ok(())
+    }
+
+    /// serialize obj straight into a new frame at the end of the file, see
+    /// FrameWriter
+    fn stream_frame(&mut self, obj: &impl Serializable) -> io::Result<()> {
+        let mut writer =
+            FrameWriter::new(&self.file, self.end + FRAME_HEADER_SIZE);
+        let written = obj
+            .serialize_into(&mut writer)
+            .and_then(|_| writer.finish())
+            .and_then(|(len, checksum)| {
+                let len = u32::try_from(len).map_err(|_| {
+                    invalid_data(format!("frame of {} bytes is too large", len))
+                })?;
+                let mut header = [0u8; FRAME_HEADER_SIZE as usize];
+                header[0..4].copy_from_slice(&len.to_le_bytes());
+                header[4..8].copy_from_slice(&checksum.to_le_bytes());
+                write_all_at(&self.file, &header, self.end)?;
+                Ok(len)
+            });
+        match written {
+            Ok(len) => {
+                self.appended(len);
+                Ok(())
+            }
+            Err(err) => {
+                // don't leave a partial frame behind for the next append to
+                // land after
+                let _ = self.file.set_len(self.end);
+                Err(err)
+            }
+        }
+    }
+
+    /// record the frame of len bytes just written at the end of the file
+    fn appended(&mut self, len: u32) {
+        self.frames
+            .push(FrameLoc { offset: self.end + FRAME_HEADER_SIZE, len });
+        self.range = self.range.extend_by(1);
+        self.end += FRAME_HEADER_SIZE + len as u64;
+    }
+
+    fn read_frame(&self, loc: FrameLoc) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; loc.len as usize];
+        read_exact_at(&self.file, &mut buf, loc.offset)?;
+        Ok(buf)
+    }
+
+    /// atomically replace the journal file with one containing only the
+    /// frames in range, which must be a prefix or suffix of the current
+    /// range
+    fn rewrite(&mut self, range: LsnRange) -> JournalResult<()> {
+        let pipelined = self.finish_pipeline()?;
+        let offsets = self.range.intersection_offsets(&range);
+        let first = match range {
+            LsnRange::Empty { nextlsn } => nextlsn,
+            LsnRange::NonEmpty { first, .. } => first,
+        };
+        let payloads =
+            self.frames[offsets].iter().map(|loc| self.read_frame(*loc));
+        let tmp_path = self.write_tmp(first, payloads)?;
+        self.swap_in(&tmp_path, pipelined)
+    }
+
+    /// wait for queued frames, returning whether the journal was pipelined
+    fn finish_pipeline(&mut self) -> io::Result<bool> {
+        match &mut self.pipeline {
+            Some(pipeline) => {
+                pipeline.finish()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// write a journal file holding payloads, the first of which is at
+    /// first, next to this one, returning its path
+    fn write_tmp(
+        &self,
+        first: Lsn,
+        payloads: impl Iterator<Item = io::Result<Vec<u8>>>,
+    ) -> io::Result<PathBuf> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        Self::write_header(&mut tmp, self.id, first)?;
+        for payload in payloads {
+            let payload = payload?;
+            tmp.write_all(&(payload.len() as u32).to_le_bytes())?;
+            tmp.write_all(&frame_checksum(&payload).to_le_bytes())?;
+            tmp.write_all(&payload)?;
+        }
+        tmp.sync_all()?;
+        Ok(tmp_path)
+    }
+
+    /// rename a file written by write_tmp over the journal file and reopen it
+    fn swap_in(
+        &mut self,
+        tmp_path: &Path,
+        pipelined: bool,
+    ) -> JournalResult<()> {
+        fs::rename(tmp_path, &self.path)?;
+        *self = Self::open(&self.path, self.id)?;
+        self.set_pipelined(pipelined)
+    }
+}
+
+impl Drop for FileJournal {
+    fn drop(&mut self) {
+        if let Some(pipeline) = &mut self.pipeline {
+            if let Err(err) = pipeline.finish() {
+                logging::warn!(
+                    doc = self.id;
+                    "failed to write frames to {:?}: {}",
+                    self.path,
+                    err
+                );
+            }
+        }
+    }
+}
+
+pub struct FileJournalFactory {
+    dir: PathBuf,
+    pipelined: bool,
+}
+
+impl FileJournalFactory {
+    /// journals will be stored in dir, one file per journal
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into(), pipelined: false }
+    }
+
+    /// open journals with FileJournal::set_pipelined
+    pub fn pipelined(mut self) -> Self {
+        self.pipelined = true;
+        self
+    }
+
+    fn path(&self, id: JournalId) -> PathBuf {
+        self.dir.join(format!("{}.journal", id.to_base58()))
+    }
+
+    /// open the storage and timeline journals of a LocalDocument, creating
+    /// dir if needed. the document's timeline id is kept next to its storage,
+    /// so mutations which hadn't reached the coordinator survive a restart
+    pub fn open_document(
+        &self,
+        doc_id: JournalId,
+    ) -> JournalResult<(FileJournal, FileJournal)> {
+        fs::create_dir_all(&self.dir)?;
+        let id_path = self.dir.join(format!("{}.timeline", doc_id.to_base58()));
+        let timeline_id = match fs::read_to_string(&id_path) {
+            Ok(id) => JournalId::from_base58(id.trim()).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let id = JournalId::new128(&mut rand::thread_rng());
+                // written aside and renamed, so a crash can't leave a
+                // partial id behind
+                let tmp = id_path.with_extension("timeline.tmp");
+                fs::write(&tmp, id.to_base58())?;
+                File::open(&tmp)?.sync_all()?;
+                fs::rename(&tmp, &id_path)?;
+                id
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok((self.open(doc_id)?, self.open(timeline_id)?))
+    }
+}
+
+impl JournalFactory<FileJournal> for FileJournalFactory {
+    fn open(&self, id: JournalId) -> JournalResult<FileJournal> {
+        let mut journal = FileJournal::open(self.path(id), id)?;
+        journal.set_pipelined(self.pipelined)?;
+        Ok(journal)
+    }
+}
+
+impl Journal for FileJournal {
+    type Factory = FileJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> JournalResult<()> {
+        if self.pipeline.is_none() {
+            self.stream_frame(&obj)?;
+            return Ok(());
+        }
+        // the pipeline's worker needs a payload of its own
+        let entry = obj
+            .to_vec()
+            .map_err(|err| JournalError::SerializationError(err))?;
+        self.write_frame(entry)?;
+        Ok(())
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_prefix(up_to);
+        if remaining_range != self.range {
+            self.rewrite(remaining_range)?;
+        }
+        Ok(())
+    }
+
+    fn drop_suffix(&mut self, from: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_suffix(from);
+        if remaining_range != self.range {
+            self.rewrite(remaining_range)?;
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        first: Lsn,
+        frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        let pipelined = self.finish_pipeline()?;
+        let tmp_path = self.write_tmp(first, frames.into_iter().map(Ok))?;
+        self.swap_in(&tmp_path, pipelined)
+    }
+
+    fn sync(&mut self) -> JournalResult<()> {
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.flush()?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+pub struct FileFrameReader<'a> {
+    file: &'a File,
+    loc: FrameLoc,
+    // the frame's payload while it's queued to be written
+    queued: Option<Arc<[u8]>>,
+}
+
+impl<'a> PositionedReader for FileFrameReader<'a> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.loc.len as usize;
+        if pos >= len {
+            return Ok(0);
+        }
+        let n = buf.len().min(len - pos);
+        if let Some(payload) = &self.queued {
+            buf[..n].copy_from_slice(&payload[pos..pos + n]);
+            return Ok(n);
+        }
+        read_at(self.file, &mut buf[..n], self.loc.offset + pos as u64)
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        Ok(self.loc.len as usize)
+    }
+}
+
+impl Scannable for FileJournal {
+    type Reader<'a>
+        = FileFrameReader<'a>
+    where
+        Self: 'a;
+
+    fn scan<'a>(&'a self) -> Cursor<'a, Self, LsnIter> {
+        Cursor::new(self, self.range.iter())
+    }
+
+    fn scan_range<'a>(&'a self, range: LsnRange) -> Cursor<'a, Self, LsnIter> {
+        let intersection = self.range.intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get<'a>(&'a self, lsn: Lsn) -> io::Result<Option<Self::Reader<'a>>> {
+        Ok(self.range.offset(lsn).map(|offset| {
+            let loc = self.frames[offset];
+            FileFrameReader {
+                file: &self.file,
+                loc,
+                queued: self.pipeline.as_ref().and_then(|pipeline| {
+                    pipeline.get(loc.offset - FRAME_HEADER_SIZE)
+                }),
+            }
+        }))
+    }
+}
+
+impl ReplicationSource for FileJournal {
+    type Reader<'a>
+        = FileFrameReader<'a>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn<'a>(
+        &'a self,
+        lsn: Lsn,
+    ) -> io::Result<Option<Self::Reader<'a>>> {
+        self.get(lsn)
+    }
+}
+
+impl ReplicationDestination for FileJournal {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(self.range)
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+
+        let accepted_range = if self.range.is_empty() {
+            // if we have no range, then we reset to the incoming lsn
+            LsnRange::new(lsn, lsn)
+        } else {
+            // accept any lsn in our current range or immediately following
+            self.range.extend_by(1)
+        };
+
+        if !accepted_range.contains(lsn) {
+            return Err(ReplicationError::NonContiguousLsn {
+                received: lsn,
+                range: accepted_range,
+            });
+        }
+
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+
+        // frames are immutable, so if we already have this lsn there is
+        // nothing to do
+        if self.range.contains(lsn) {
+            return Ok(());
+        }
+
+        if self.range.is_empty() && self.range.next() != lsn {
+            // move the start of the (empty) journal to the incoming lsn
+            self.rewrite(LsnRange::Empty { nextlsn: lsn })?;
+        }
+        self.write_frame(frame_data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::journal::tests::ScratchDir;
+
+    fn frame_data(i: usize) -> Vec<u8> {
+        vec![i as u8; 1 + (i * 37) % 200]
+    }
+
+    /// write n frames to a fresh journal, returning the frames along with
+    /// the file offset at which each frame ends
+    fn write_journal(
+        path: &Path,
+        id: JournalId,
+        n: usize,
+    ) -> (Vec<Vec<u8>>, Vec<u64>) {
+        let mut journal = FileJournal::open(path, id).unwrap();
+        let mut frames = Vec::new();
+        let mut ends = Vec::new();
+        let mut end = HEADER_SIZE;
+        for i in 0..n {
+            let data = frame_data(i);
+            journal.append(&data[..]).unwrap();
+            end += FRAME_HEADER_SIZE + data.len() as u64;
+            ends.push(end);
+            frames.push(data);
+        }
+        journal.sync().unwrap();
+        (frames, ends)
+    }
+
+    fn read_all(journal: &FileJournal) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut cursor = journal.scan();
+        while cursor.advance().unwrap() {
+            let mut buf = vec![0; cursor.size().unwrap()];
+            cursor.read_exact_at(0, &mut buf).unwrap();
+            out.push(buf);
+        }
+        out
+    }
+
+    /// open the (damaged) journal at path and check that exactly the first
+    /// `intact` frames survived and that the journal is writable again
+    fn assert_recovered(
+        path: &Path,
+        id: JournalId,
+        frames: &[Vec<u8>],
+        intact: usize,
+    ) {
+        let mut journal = FileJournal::open(path, id).unwrap();
+        assert_eq!(journal.range().len(), intact);
+        assert_eq!(read_all(&journal), frames[..intact]);
+
+        // appending after recovery must not be shadowed by the torn tail
+        journal.append(&b"after recovery"[..]).unwrap();
+        drop(journal);
+
+        let journal = FileJournal::open(path, id).unwrap();
+        let mut expected = frames[..intact].to_vec();
+        expected.push(b"after recovery".to_vec());
+        assert_eq!(read_all(&journal), expected);
+    }
+
+    #[test]
+    fn reopen_preserves_frames() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let (frames, ends) = write_journal(&path, id, 10);
+        assert_eq!(fs::metadata(&path).unwrap().len(), *ends.last().unwrap());
+
+        let journal = FileJournal::open(&path, id).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(0, 9));
+        assert_eq!(read_all(&journal), frames);
+    }
+
+    #[test]
+    fn large_frames_stream_to_disk() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        // pages which span many of FrameWriter's buffers
+        let mut pages = crate::page::SparsePages::new();
+        for page_idx in 1..=40 {
+            pages.write(page_idx, [page_idx as u8; crate::PAGESIZE]);
+        }
+        let frame = pages.to_vec().unwrap();
+        assert!(frame.len() > 2 * FRAME_WRITE_BUFFER);
+
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        journal.append(&b"small"[..]).unwrap();
+        journal.append(pages).unwrap();
+        journal.append(&b"after"[..]).unwrap();
+        assert_eq!(read_all(&journal)[1], frame);
+        drop(journal);
+
+        let journal = FileJournal::open(&path, id).unwrap();
+        assert_eq!(
+            read_all(&journal),
+            vec![b"small".to_vec(), frame, b"after".to_vec()]
+        );
+    }
+
+    #[test]
+    fn synced_commits_are_on_disk() {
+        use sqlite_vfs::File;
+
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        journal.set_pipelined(true).unwrap();
+        let mut storage = crate::storage::Storage::new(journal);
+        storage.set_durability(crate::Durability::Sync);
+        for i in 0..10 {
+            storage.write(0, &[i; crate::PAGESIZE]).unwrap();
+            storage.commit().unwrap();
+
+            // the pipeline has written the commit by the time it returns
+            let reopened = FileJournal::open(&path, id).unwrap();
+            assert_eq!(reopened.range(), LsnRange::new(0, i as u64));
+        }
+    }
+
+    #[test]
+    fn syncing_keeps_the_pipeline_worker() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        journal.set_pipelined(true).unwrap();
+        journal.append(&b"first"[..]).unwrap();
+        journal.sync().unwrap();
+
+        let worker_id = |journal: &FileJournal| {
+            let pipeline = journal.pipeline.as_ref().unwrap();
+            let (_, handle) = pipeline.worker.as_ref().unwrap();
+            handle.thread().id()
+        };
+        let worker = worker_id(&journal);
+        for i in 0..10 {
+            journal.append(&frame_data(i)[..]).unwrap();
+            journal.sync().unwrap();
+            assert_eq!(worker_id(&journal), worker);
+            assert!(journal.pipeline.as_ref().unwrap().queued.is_empty());
+        }
+
+        let reopened = FileJournal::open(&path, id).unwrap();
+        assert_eq!(reopened.range(), LsnRange::new(0, 10));
+    }
+
+    #[test]
+    fn pipelined_frames_are_readable_before_they_are_written() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        journal.set_pipelined(true).unwrap();
+        let frames: Vec<_> = (0..50).map(frame_data).collect();
+        for frame in &frames {
+            journal.append(&frame[..]).unwrap();
+        }
+        assert_eq!(read_all(&journal), frames);
+
+        // rewriting waits for the queue, and the journal stays pipelined
+        journal.drop_prefix(9).unwrap();
+        journal.append(&b"after rewrite"[..]).unwrap();
+        journal.sync().unwrap();
+        assert!(journal.pipeline.is_some());
+        drop(journal);
+
+        let journal = FileJournal::open(&path, id).unwrap();
+        let mut expected = frames[10..].to_vec();
+        expected.push(b"after rewrite".to_vec());
+        assert_eq!(read_all(&journal), expected);
+    }
+
+    #[test]
+    fn torn_write_at_every_offset() {
+        let dir = ScratchDir::new();
+        let original = dir.0.join("original");
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let (frames, ends) = write_journal(&original, id, 10);
+        let bytes = fs::read(&original).unwrap();
+
+        for cut in HEADER_SIZE..=bytes.len() as u64 {
+            fs::write(&path, &bytes[..cut as usize]).unwrap();
+            let intact = ends.iter().filter(|end| **end <= cut).count();
+            assert_recovered(&path, id, &frames, intact);
+        }
+    }
+
+    #[test]
+    fn bit_flip_in_every_frame_byte() {
+        let dir = ScratchDir::new();
+        let original = dir.0.join("original");
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let (frames, ends) = write_journal(&original, id, 10);
+        let bytes = fs::read(&original).unwrap();
+
+        for pos in HEADER_SIZE..bytes.len() as u64 {
+            let mut damaged = bytes.clone();
+            damaged[pos as usize] ^= 1 << (pos % 8);
+            fs::write(&path, &damaged).unwrap();
+
+            // every frame before the damaged one survives
+            let intact = ends.iter().filter(|end| **end <= pos).count();
+            assert_recovered(&path, id, &frames, intact);
+        }
+    }
+
+    #[test]
+    fn zeroed_tail_is_discarded() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        // a crash after the file grew but before the data hit disk can leave
+        // a run of zeros at the end of the file
+        let (frames, _) = write_journal(&path, id, 5);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[0; 4096]);
+        fs::write(&path, &bytes).unwrap();
+
+        assert_recovered(&path, id, &frames, frames.len());
+    }
+
+    #[test]
+    fn torn_header_resets_journal() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        // the header is synced before any frame is written, so a short
+        // header can only come from a crash while creating the journal
+        write_journal(&path, id, 0);
+        let bytes = fs::read(&path).unwrap();
+        for cut in 0..HEADER_SIZE {
+            fs::write(&path, &bytes[..cut as usize]).unwrap();
+            assert_recovered(&path, id, &[], 0);
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_journal() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let other = JournalId::new256(&mut rand::thread_rng());
+
+        write_journal(&path, id, 3);
+        assert!(FileJournal::open(&path, other).is_err());
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[0] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert!(FileJournal::open(&path, id).is_err());
+    }
+
+    #[test]
+    fn documents_reopen_their_timeline() {
+        let dir = ScratchDir::new();
+        let factory = FileJournalFactory::new(dir.0.join("docs"));
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+
+        let (storage, mut timeline) = factory.open_document(doc_id).unwrap();
+        assert_eq!(storage.id(), doc_id);
+        timeline.append(&frame_data(1)[..]).unwrap();
+        timeline.sync().unwrap();
+        let timeline_id = timeline.id();
+        drop((storage, timeline));
+
+        let (_, timeline) = factory.open_document(doc_id).unwrap();
+        assert_eq!(timeline.id(), timeline_id);
+        assert_eq!(read_all(&timeline), vec![frame_data(1)]);
+
+        // other documents get their own timeline
+        let other = JournalId::new128(&mut rand::thread_rng());
+        let (_, timeline) = factory.open_document(other).unwrap();
+        assert_ne!(timeline.id(), timeline_id);
+    }
+
+    #[test]
+    fn drop_prefix_survives_reopen() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journal");
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let (frames, _) = write_journal(&path, id, 10);
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        journal.drop_prefix(4).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(5, 9));
+        drop(journal);
+
+        let journal = FileJournal::open(&path, id).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(5, 9));
+        assert_eq!(read_all(&journal), frames[5..]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Frames iterates over a storage journal for tools which want to follow a
+//! document (backup daemons, analytics pipelines) without speaking the
+//! replication protocol.
+//!
+//! To tail a journal, iterate until the iterator is exhausted, remember
+//! Frames::next_lsn, and start a new iterator from it once the journal has
+//! grown. Frames don't record when they were written, so consumers which
+//! need timestamps should note when they first observe each lsn.
+
+use std::io;
+
+use crate::{
+    page::{Page, SerializedPagesReader, PAGESIZE},
+    positioned_io::PositionedReader,
+    Lsn, LsnRange, PageIdx,
+};
+
+use super::Journal;
+
+pub struct Frames<'a, J: Journal> {
+    journal: &'a J,
+    next_lsn: Lsn,
+    end: Lsn,
+}
+
+impl<'a, J: Journal> Frames<'a, J> {
+    /// iterate over every frame in journal starting at from. if the journal
+    /// no longer has from (due to a dropped prefix) iteration starts at the
+    /// first frame it does have; compare Frame::lsn to detect the gap
+    pub fn new(journal: &'a J, from: Lsn) -> Self {
+        let range = journal.range();
+        let next_lsn = match range {
+            LsnRange::NonEmpty { first, .. } => from.max(first),
+            LsnRange::Empty { .. } => from,
+        };
+        Self { journal, next_lsn, end: range.next() }
+    }
+
+    /// the lsn to resume from once this iterator is exhausted
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+}
+
+impl<'a, J: Journal> Iterator for Frames<'a, J> {
+    type Item = io::Result<Frame<J::Reader<'a>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_lsn >= self.end {
+            return None;
+        }
+        let lsn = self.next_lsn;
+        self.next_lsn += 1;
+        match self.journal.get(lsn) {
+            Ok(Some(reader)) => Some(Frame::new(lsn, reader)),
+            Ok(None) => Some(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("lsn {} is missing from the journal", lsn),
+            ))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Frame is one frame of a storage journal: the set of pages written by a
+/// single commit
+pub struct Frame<R: PositionedReader> {
+    lsn: Lsn,
+    len: usize,
+    pages: SerializedPagesReader<R>,
+}
+
+impl<R: PositionedReader> Frame<R> {
+    fn new(lsn: Lsn, reader: R) -> io::Result<Self> {
+        let len = reader.size()?;
+        Ok(Self { lsn, len, pages: SerializedPagesReader(reader) })
+    }
+
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+
+    /// the size of the frame in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn num_pages(&self) -> io::Result<usize> {
+        self.pages.num_pages()
+    }
+
+    /// the pages written by this frame, sorted desc
+    pub fn page_idxs(&self) -> io::Result<Vec<PageIdx>> {
+        self.pages.page_idxs()
+    }
+
+    /// read a page from this frame, returning false if the frame doesn't
+    /// contain it
+    pub fn read_page(
+        &self,
+        page_idx: PageIdx,
+        page: &mut Page,
+    ) -> io::Result<bool> {
+        let n = self.pages.read(page_idx, 0, page)?;
+        Ok(n == PAGESIZE)
+    }
+
+    /// the raw frame, in the format used by the replication protocol
+    pub fn into_reader(self) -> R {
+        self.pages.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Frames;
+    use crate::{
+        page::{SparsePages, PAGESIZE},
+        Journal, JournalId, MemoryJournal,
+    };
+
+    fn commit(journal: &mut MemoryJournal, page_idxs: &[u32]) {
+        let mut pages = SparsePages::new();
+        for &page_idx in page_idxs {
+            pages.write(page_idx, [page_idx as u8; PAGESIZE]);
+        }
+        journal.append(pages).unwrap();
+    }
+
+    #[test]
+    fn tail_journal() {
+        let mut journal =
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap();
+        commit(&mut journal, &[1, 2]);
+        commit(&mut journal, &[3]);
+
+        let mut frames = Frames::new(&journal, 0);
+        let frame = frames.next().unwrap().unwrap();
+        assert_eq!(frame.lsn(), 0);
+        assert_eq!(frame.num_pages().unwrap(), 2);
+        assert_eq!(frame.page_idxs().unwrap(), vec![2, 1]);
+        let mut page = [0; PAGESIZE];
+        assert!(frame.read_page(2, &mut page).unwrap());
+        assert_eq!(page[0], 2);
+        assert!(!frame.read_page(3, &mut page).unwrap());
+
+        assert_eq!(frames.next().unwrap().unwrap().lsn(), 1);
+        assert!(frames.next().is_none());
+        let resume = frames.next_lsn();
+        assert_eq!(resume, 2);
+
+        // pick up where we left off, even across a dropped prefix
+        commit(&mut journal, &[4]);
+        journal.drop_prefix(1).unwrap();
+        let lsns: Vec<_> = Frames::new(&journal, resume)
+            .map(|f| f.unwrap().lsn())
+            .collect();
+        assert_eq!(lsns, vec![2]);
+        let lsns: Vec<_> =
+            Frames::new(&journal, 0).map(|f| f.unwrap().lsn()).collect();
+        assert_eq!(lsns, vec![2]);
+    }
+}
@@ -37,6 +37,31 @@ pub trait Journal: Scannable + Debug + Sized {
 
     /// drop the journal's prefix
     fn drop_prefix(&mut self, up_to: Lsn) -> JournalResult<()>;
+
+    /// drop every entry from lsn from onwards, so that the next entry
+    /// appended is at from. journals which can't rewrite their tail don't
+    /// support this
+    fn drop_suffix(&mut self, _from: Lsn) -> JournalResult<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    /// replace every entry with frames, the first of which is at first, so
+    /// that a crash leaves either the old entries or the new ones and never
+    /// a mix. journals which can't swap their entries atomically don't
+    /// support this
+    fn replace(
+        &mut self,
+        _first: Lsn,
+        _frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    /// flush every appended entry to durable storage. journals which aren't
+    /// backed by durable storage have nothing to do here
+    fn sync(&mut self) -> JournalResult<()> {
+        Ok(())
+    }
 }
 
 pub trait JournalFactory<J> {
@@ -53,8 +53,8 @@ impl Journal for MemoryJournal {
 
     fn append(&mut self, obj: impl Serializable) -> JournalResult<()> {
         // serialize the entry
-        let mut entry: Vec<u8> = Vec::new();
-        obj.serialize_into(&mut entry)
+        let entry = obj
+            .to_vec()
             .map_err(|err| JournalError::SerializationError(err))?;
 
         // update the journal
@@ -71,6 +71,19 @@ impl Journal for MemoryJournal {
         self.range = remaining_range;
         Ok(())
     }
+
+    fn drop_suffix(&mut self, from: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_suffix(from);
+        self.data.truncate(remaining_range.len());
+        self.range = remaining_range;
+        Ok(())
+    }
+
+    fn replace(&mut self, first: Lsn, frames: Vec<Vec<u8>>) -> JournalResult<()> {
+        self.range = LsnRange::starting_at(first, frames.len());
+        self.data = frames;
+        Ok(())
+    }
 }
 
 impl Scannable for MemoryJournal {
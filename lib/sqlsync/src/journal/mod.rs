@@ -1,10 +1,57 @@
 mod cursor;
+#[cfg(not(target_arch = "wasm32"))]
+mod file;
+mod frames;
 mod journal;
 mod journalid;
 mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(not(target_arch = "wasm32"))]
+mod sqlite;
+mod tiered;
 
 pub use cursor::{Cursor, Scannable};
+pub use frames::{Frame, Frames};
 pub use journal::*;
 pub use journalid::{JournalId, JournalIdParseError};
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use file::{FileFrameReader, FileJournal, FileJournalFactory};
 pub use memory::{MemoryJournal, MemoryJournalFactory};
+#[cfg(not(target_arch = "wasm32"))]
+pub use sqlite::{SqliteJournal, SqliteJournalFactory};
+pub use tiered::{
+    ObjectStore, TieredFrameReader, TieredJournal, TieredJournalFactory,
+    TieringPolicy,
+};
+#[cfg(feature = "postgres")]
+pub use self::postgres::{
+    PostgresFrameReader, PostgresJournal, PostgresJournalFactory,
+};
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+pub(crate) mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::JournalId;
+
+    /// a scratch directory which is removed on drop
+    pub(crate) struct ScratchDir(pub(crate) PathBuf);
+
+    impl ScratchDir {
+        pub(crate) fn new() -> Self {
+            let id = JournalId::new128(&mut rand::thread_rng());
+            let dir = std::env::temp_dir().join(format!("sqlsync-{}", id));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
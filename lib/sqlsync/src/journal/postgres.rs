@@ -0,0 +1,591 @@
+//! PostgresJournal stores frames in Postgres, so a coordinator can reuse an
+//! existing database for durability. Frames live in a single table indexed
+//! by (journal id, lsn), and every journal has a row recording the lsn it
+//! starts at once its prefix has been dropped.
+//!
+//! Only one process may write to a journal at a time: opening a journal
+//! takes a session level advisory lock on the journal's own connection,
+//! which Postgres releases when that connection closes. Frames are read on
+//! demand rather than when the journal is opened, READAHEAD_FRAMES at a
+//! time so that scanning a journal costs a query per batch of frames.
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use postgres::{Client, NoTls};
+use sha2::{Digest, Sha256};
+
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::PositionedReader;
+use crate::replication::{
+    ReplicationDestination, ReplicationError, ReplicationSource,
+};
+use crate::{JournalError, JournalFactory, Serializable};
+
+use super::{Cursor, Journal, JournalId, JournalResult, Scannable};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sqlsync_journals (
+    id bytea PRIMARY KEY,
+    first_lsn bigint NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS sqlsync_frames (
+    journal_id bytea NOT NULL
+        REFERENCES sqlsync_journals (id) ON DELETE CASCADE,
+    lsn bigint NOT NULL,
+    data bytea NOT NULL,
+    PRIMARY KEY (journal_id, lsn)
+);
+";
+
+/// frames fetched by each read which misses the readahead buffer
+const READAHEAD_FRAMES: u64 = 64;
+
+fn pg_err(err: postgres::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn locked_err(id: JournalId) -> JournalError {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("journal {} is locked by another writer", id),
+    )
+    .into()
+}
+
+/// advisory locks are keyed by a bigint; a collision between two journals
+/// only means that they can't be written by different processes at once
+fn lock_key(id: JournalId) -> i64 {
+    let digest = Sha256::digest(id.bytes());
+    i64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+type Connect = dyn Fn() -> Result<Client, postgres::Error> + Send + Sync;
+
+pub struct PostgresJournalFactory {
+    connect: Box<Connect>,
+}
+
+impl PostgresJournalFactory {
+    /// connect is called for every journal opened, as each journal holds its
+    /// lock on its own connection
+    pub fn new(
+        connect: impl Fn() -> Result<Client, postgres::Error>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self { connect: Box::new(connect) }
+    }
+
+    /// connect without tls, e.g. to a database on localhost
+    pub fn no_tls(config: postgres::Config) -> Self {
+        Self::new(move || config.connect(NoTls))
+    }
+
+    fn connect(&self) -> JournalResult<Client> {
+        Ok((self.connect)().map_err(pg_err)?)
+    }
+
+    /// create the tables used by PostgresJournal if they don't exist
+    pub fn migrate(&self) -> JournalResult<()> {
+        self.connect()?.batch_execute(SCHEMA).map_err(pg_err)?;
+        Ok(())
+    }
+
+    /// create a new journal, failing if it already exists
+    pub fn create(&self, id: JournalId) -> JournalResult<PostgresJournal> {
+        let mut client = self.connect()?;
+        lock(&mut client, id)?;
+        let created = client
+            .execute(
+                "INSERT INTO sqlsync_journals (id) VALUES ($1)
+                ON CONFLICT DO NOTHING",
+                &[&id.bytes()],
+            )
+            .map_err(pg_err)?;
+        if created == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("journal {} already exists", id),
+            )
+            .into());
+        }
+        PostgresJournal::load(client, id)
+    }
+
+    /// delete a journal along with all of its frames in one transaction,
+    /// returning false if it didn't exist. Fails if the journal is open
+    pub fn delete(&self, id: JournalId) -> JournalResult<bool> {
+        let mut client = self.connect()?;
+        let mut tx = client.transaction().map_err(pg_err)?;
+        let locked: bool = tx
+            .query_one("SELECT pg_try_advisory_xact_lock($1)", &[&lock_key(id)])
+            .map_err(pg_err)?
+            .get(0);
+        if !locked {
+            return Err(locked_err(id));
+        }
+        let deleted = tx
+            .execute(
+                "DELETE FROM sqlsync_journals WHERE id = $1",
+                &[&id.bytes()],
+            )
+            .map_err(pg_err)?;
+        tx.commit().map_err(pg_err)?;
+        Ok(deleted > 0)
+    }
+}
+
+impl JournalFactory<PostgresJournal> for PostgresJournalFactory {
+    /// open a journal, creating it if it doesn't exist
+    fn open(&self, id: JournalId) -> JournalResult<PostgresJournal> {
+        let mut client = self.connect()?;
+        lock(&mut client, id)?;
+        client
+            .execute(
+                "INSERT INTO sqlsync_journals (id) VALUES ($1)
+                ON CONFLICT DO NOTHING",
+                &[&id.bytes()],
+            )
+            .map_err(pg_err)?;
+        PostgresJournal::load(client, id)
+    }
+}
+
+/// take the journal's writer lock for the life of the connection
+fn lock(client: &mut Client, id: JournalId) -> JournalResult<()> {
+    let locked: bool = client
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&lock_key(id)])
+        .map_err(pg_err)?
+        .get(0);
+    if locked {
+        Ok(())
+    } else {
+        Err(locked_err(id))
+    }
+}
+
+pub struct PostgresJournal {
+    id: JournalId,
+    client: Mutex<Client>,
+    range: LsnRange,
+    /// the frames fetched by the last read which missed it
+    readahead: Mutex<BTreeMap<Lsn, Arc<Vec<u8>>>>,
+}
+
+impl Debug for PostgresJournal {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("PostgresJournal")
+            .field(&self.id)
+            .field(&self.range)
+            .finish()
+    }
+}
+
+impl PostgresJournal {
+    fn load(mut client: Client, id: JournalId) -> JournalResult<Self> {
+        let first: i64 = client
+            .query_one(
+                "SELECT first_lsn FROM sqlsync_journals WHERE id = $1",
+                &[&id.bytes()],
+            )
+            .map_err(pg_err)?
+            .get(0);
+
+        let row = client
+            .query_one(
+                "SELECT min(lsn), max(lsn), count(*) FROM sqlsync_frames
+                WHERE journal_id = $1",
+                &[&id.bytes()],
+            )
+            .map_err(pg_err)?;
+        let (min, max, count): (Option<i64>, Option<i64>, i64) =
+            (row.get(0), row.get(1), row.get(2));
+
+        let range = match (min, max) {
+            (Some(min), Some(max))
+                if min == first && max - min + 1 == count =>
+            {
+                LsnRange::new(min as Lsn, max as Lsn)
+            }
+            (None, None) => LsnRange::Empty { nextlsn: first as Lsn },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("journal {} is missing frames", id),
+                )
+                .into())
+            }
+        };
+
+        Ok(Self {
+            id,
+            client: Mutex::new(client),
+            range,
+            readahead: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn client(&mut self) -> &mut Client {
+        self.client.get_mut().unwrap()
+    }
+
+    fn write_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        let lsn = self.range.next() as i64;
+        let id = self.id;
+        self.client()
+            .execute(
+                "INSERT INTO sqlsync_frames (journal_id, lsn, data)
+                VALUES ($1, $2, $3)",
+                &[&id.bytes(), &lsn, &frame],
+            )
+            .map_err(pg_err)?;
+        self.range = self.range.extend_by(1);
+        Ok(())
+    }
+
+    /// read the frame at lsn, which must be in range, along with the frames
+    /// after it up to READAHEAD_FRAMES
+    fn fetch(&self, lsn: Lsn) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(frame) = self.readahead.lock().unwrap().get(&lsn) {
+            return Ok(frame.clone());
+        }
+
+        let end = self.range.last().map_or(lsn, |last| last + 1);
+        let end = end.min(lsn + READAHEAD_FRAMES);
+        let rows = self
+            .client
+            .lock()
+            .unwrap()
+            .query(
+                "SELECT lsn, data FROM sqlsync_frames
+                WHERE journal_id = $1 AND lsn >= $2 AND lsn < $3",
+                &[&self.id.bytes(), &(lsn as i64), &(end as i64)],
+            )
+            .map_err(pg_err)?;
+        let frames: BTreeMap<Lsn, Arc<Vec<u8>>> = rows
+            .into_iter()
+            .map(|row| (row.get::<_, i64>(0) as Lsn, Arc::new(row.get(1))))
+            .collect();
+
+        let frame = frames.get(&lsn).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("journal {} is missing lsn {}", self.id, lsn),
+            )
+        })?;
+        *self.readahead.lock().unwrap() = frames;
+        Ok(frame)
+    }
+
+    /// drop every frame outside of range, which must be a suffix of the
+    /// current range
+    fn truncate(&mut self, range: LsnRange) -> JournalResult<()> {
+        let first = match range {
+            LsnRange::Empty { nextlsn } => nextlsn,
+            LsnRange::NonEmpty { first, .. } => first,
+        } as i64;
+
+        let id = self.id;
+        let mut tx = self.client().transaction().map_err(pg_err)?;
+        tx.execute(
+            "DELETE FROM sqlsync_frames WHERE journal_id = $1 AND lsn < $2",
+            &[&id.bytes(), &first],
+        )
+        .map_err(pg_err)?;
+        tx.execute(
+            "UPDATE sqlsync_journals SET first_lsn = $2 WHERE id = $1",
+            &[&id.bytes(), &first],
+        )
+        .map_err(pg_err)?;
+        tx.commit().map_err(pg_err)?;
+
+        self.readahead
+            .get_mut()
+            .unwrap()
+            .retain(|&lsn, _| range.contains(lsn));
+        self.range = range;
+        Ok(())
+    }
+}
+
+pub struct PostgresFrameReader(Arc<Vec<u8>>);
+
+impl PositionedReader for PostgresFrameReader {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.as_slice().read_at(pos, buf)
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        Ok(self.0.len())
+    }
+}
+
+impl Drop for PostgresJournal {
+    fn drop(&mut self) {
+        // closing the connection would release the lock too, but not
+        // before drop returns
+        let key = lock_key(self.id);
+        let _ = self
+            .client()
+            .execute("SELECT pg_advisory_unlock($1)", &[&key]);
+    }
+}
+
+impl Journal for PostgresJournal {
+    type Factory = PostgresJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> JournalResult<()> {
+        let entry = obj.to_vec().map_err(JournalError::SerializationError)?;
+        self.write_frame(entry)?;
+        Ok(())
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_prefix(up_to);
+        if remaining_range != self.range {
+            self.truncate(remaining_range)?;
+        }
+        Ok(())
+    }
+
+    fn drop_suffix(&mut self, from: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_suffix(from);
+        if remaining_range != self.range {
+            let id = self.id;
+            self.client()
+                .execute(
+                    "DELETE FROM sqlsync_frames
+                    WHERE journal_id = $1 AND lsn >= $2",
+                    &[&id.bytes(), &(from as i64)],
+                )
+                .map_err(pg_err)?;
+            self.readahead
+                .get_mut()
+                .unwrap()
+                .retain(|&lsn, _| lsn < from);
+            self.range = remaining_range;
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        first: Lsn,
+        frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        let id = self.id;
+        let mut tx = self.client().transaction().map_err(pg_err)?;
+        tx.execute(
+            "DELETE FROM sqlsync_frames WHERE journal_id = $1",
+            &[&id.bytes()],
+        )
+        .map_err(pg_err)?;
+        for (lsn, frame) in (first..).zip(&frames) {
+            tx.execute(
+                "INSERT INTO sqlsync_frames (journal_id, lsn, data)
+                VALUES ($1, $2, $3)",
+                &[&id.bytes(), &(lsn as i64), frame],
+            )
+            .map_err(pg_err)?;
+        }
+        tx.execute(
+            "UPDATE sqlsync_journals SET first_lsn = $2 WHERE id = $1",
+            &[&id.bytes(), &(first as i64)],
+        )
+        .map_err(pg_err)?;
+        tx.commit().map_err(pg_err)?;
+
+        self.readahead.get_mut().unwrap().clear();
+        self.range = LsnRange::starting_at(first, frames.len());
+        Ok(())
+    }
+}
+
+impl Scannable for PostgresJournal {
+    type Reader<'a>
+        = PostgresFrameReader
+    where
+        Self: 'a;
+
+    fn scan<'a>(&'a self) -> Cursor<'a, Self, LsnIter> {
+        Cursor::new(self, self.range.iter())
+    }
+
+    fn scan_range<'a>(&'a self, range: LsnRange) -> Cursor<'a, Self, LsnIter> {
+        let intersection = self.range.intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get<'a>(&'a self, lsn: Lsn) -> io::Result<Option<Self::Reader<'a>>> {
+        if !self.range.contains(lsn) {
+            return Ok(None);
+        }
+        Ok(Some(PostgresFrameReader(self.fetch(lsn)?)))
+    }
+}
+
+impl ReplicationSource for PostgresJournal {
+    type Reader<'a>
+        = PostgresFrameReader
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn<'a>(
+        &'a self,
+        lsn: Lsn,
+    ) -> io::Result<Option<Self::Reader<'a>>> {
+        self.get(lsn)
+    }
+}
+
+impl ReplicationDestination for PostgresJournal {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(self.range)
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+
+        let accepted_range = if self.range.is_empty() {
+            // if we have no range, then we reset to the incoming lsn
+            LsnRange::new(lsn, lsn)
+        } else {
+            // accept any lsn in our current range or immediately following
+            self.range.extend_by(1)
+        };
+
+        if !accepted_range.contains(lsn) {
+            return Err(ReplicationError::NonContiguousLsn {
+                received: lsn,
+                range: accepted_range,
+            });
+        }
+
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+
+        // frames are immutable, so if we already have this lsn there is
+        // nothing to do
+        if self.range.contains(lsn) {
+            return Ok(());
+        }
+
+        if self.range.is_empty() && self.range.next() != lsn {
+            // move the start of the (empty) journal to the incoming lsn
+            self.truncate(LsnRange::Empty { nextlsn: lsn })?;
+        }
+        self.write_frame(frame_data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// these tests need a scratch database, e.g.
+    /// SQLSYNC_TEST_POSTGRES="host=localhost user=postgres"
+    fn factory() -> PostgresJournalFactory {
+        let config = std::env::var("SQLSYNC_TEST_POSTGRES")
+            .expect("SQLSYNC_TEST_POSTGRES is not set");
+        let factory = PostgresJournalFactory::no_tls(config.parse().unwrap());
+        factory.migrate().unwrap();
+        factory
+    }
+
+    fn read(journal: &PostgresJournal, lsn: Lsn) -> Option<Vec<u8>> {
+        journal.get(lsn).unwrap().map(|frame| frame.0.to_vec())
+    }
+
+    #[test]
+    #[ignore = "needs SQLSYNC_TEST_POSTGRES"]
+    fn frames_survive_reopen() {
+        let factory = factory();
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = factory.create(id).unwrap();
+        for i in 0..10u8 {
+            journal.append(&[i][..]).unwrap();
+        }
+        journal.drop_prefix(4).unwrap();
+
+        // only one writer at a time
+        assert!(factory.open(id).is_err());
+        assert!(factory.delete(id).is_err());
+        drop(journal);
+
+        let journal = factory.open(id).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(5, 9));
+        assert_eq!(read(&journal, 5), Some(vec![5]));
+        drop(journal);
+
+        assert!(factory.create(id).is_err());
+        assert!(factory.delete(id).unwrap());
+        assert!(!factory.delete(id).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs SQLSYNC_TEST_POSTGRES"]
+    fn reads_span_readahead_batches_and_suffixes_drop() {
+        let factory = factory();
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = factory.create(id).unwrap();
+        let frames = READAHEAD_FRAMES as u8 * 2 + 8;
+        for i in 0..frames {
+            journal.append(&[i][..]).unwrap();
+        }
+        for i in (0..frames).rev() {
+            assert_eq!(read(&journal, i as Lsn), Some(vec![i]));
+        }
+        for i in 0..frames {
+            assert_eq!(read(&journal, i as Lsn), Some(vec![i]));
+        }
+
+        journal.drop_suffix(100).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(0, 99));
+        assert_eq!(read(&journal, 100), None);
+        journal.append(&[0xff][..]).unwrap();
+        assert_eq!(read(&journal, 100), Some(vec![0xff]));
+        drop(journal);
+
+        let journal = factory.open(id).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(0, 100));
+        assert_eq!(read(&journal, 100), Some(vec![0xff]));
+        drop(journal);
+        assert!(factory.delete(id).unwrap());
+    }
+}
@@ -0,0 +1,503 @@
+//! SqliteJournal stores the frames of every journal in a single SQLite
+//! database, for deployments which don't want a file per journal. Each
+//! journal has a row recording the lsn it starts at, and its frames are rows
+//! in a shared table keyed by (journal id, lsn).
+//!
+//! The database runs in WAL mode, so snapshot exports can read it from
+//! another process (see SqliteJournalFactory::open_readonly) while the
+//! coordinator writes. Frames are cached in memory once loaded, so reads
+//! never touch the database.
+
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::replication::{
+    ReplicationDestination, ReplicationError, ReplicationSource,
+};
+use crate::{JournalError, JournalFactory, Serializable};
+
+use super::{Cursor, Journal, JournalId, JournalResult, Scannable};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS journals (
+    id BLOB PRIMARY KEY,
+    first_lsn INTEGER NOT NULL DEFAULT 0
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS frames (
+    journal_id BLOB NOT NULL REFERENCES journals (id) ON DELETE CASCADE,
+    lsn INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (journal_id, lsn)
+) WITHOUT ROWID;
+";
+
+fn sql_err(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn readonly_err() -> JournalError {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "journal database was opened read only",
+    )
+    .into()
+}
+
+#[derive(Clone)]
+pub struct SqliteJournalFactory {
+    conn: Arc<Mutex<Connection>>,
+    readonly: bool,
+}
+
+impl SqliteJournalFactory {
+    /// open the database at path, creating it if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> JournalResult<Self> {
+        let conn = Connection::open(path).map_err(sql_err)?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .map_err(sql_err)?;
+        // every commit is durable once it returns
+        conn.execute_batch(
+            "PRAGMA synchronous = FULL; PRAGMA foreign_keys = ON;",
+        )
+        .map_err(sql_err)?;
+        conn.execute_batch(SCHEMA).map_err(sql_err)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)), readonly: false })
+    }
+
+    /// open the database without taking part in writes. Each journal is
+    /// loaded as of a single read transaction, so it's consistent even
+    /// while a coordinator writes to the database
+    pub fn open_readonly(path: impl AsRef<Path>) -> JournalResult<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(sql_err)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)), readonly: true })
+    }
+
+    /// create a new journal, failing if it already exists
+    pub fn create(&self, id: JournalId) -> JournalResult<SqliteJournal> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        let created = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO journals (id) VALUES (?) ON CONFLICT DO NOTHING",
+                params![id],
+            )
+            .map_err(sql_err)?;
+        if created == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("journal {} already exists", id),
+            )
+            .into());
+        }
+        self.load(id)
+    }
+
+    /// delete a journal along with all of its frames in one transaction,
+    /// returning false if it didn't exist
+    pub fn delete(&self, id: JournalId) -> JournalResult<bool> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM journals WHERE id = ?", params![id])
+            .map_err(sql_err)?;
+        Ok(deleted > 0)
+    }
+
+    /// the ids of every journal in the database
+    pub fn list(&self) -> JournalResult<Vec<JournalId>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT id FROM journals").map_err(sql_err)?;
+        let ids = stmt.query_map([], |row| row.get(0)).map_err(sql_err)?;
+        Ok(ids.collect::<Result<Vec<_>, _>>().map_err(sql_err)?)
+    }
+
+    fn load(&self, id: JournalId) -> JournalResult<SqliteJournal> {
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction().map_err(sql_err)?;
+
+        let first: Option<i64> = txn
+            .query_row(
+                "SELECT first_lsn FROM journals WHERE id = ?",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sql_err)?;
+        let Some(first) = first else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("journal {} does not exist", id),
+            )
+            .into());
+        };
+
+        let mut range = LsnRange::Empty { nextlsn: first as Lsn };
+        let mut data = Vec::new();
+        {
+            let mut stmt = txn
+                .prepare(
+                    "SELECT lsn, data FROM frames
+                    WHERE journal_id = ? ORDER BY lsn",
+                )
+                .map_err(sql_err)?;
+            let mut rows = stmt.query(params![id]).map_err(sql_err)?;
+            while let Some(row) = rows.next().map_err(sql_err)? {
+                let lsn: i64 = row.get(0).map_err(sql_err)?;
+                if lsn as Lsn != range.next() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "journal {} is missing lsn {}",
+                            id,
+                            range.next()
+                        ),
+                    )
+                    .into());
+                }
+                data.push(row.get::<_, Vec<u8>>(1).map_err(sql_err)?);
+                range = range.extend_by(1);
+            }
+        }
+        txn.commit().map_err(sql_err)?;
+
+        Ok(SqliteJournal {
+            id,
+            conn: self.conn.clone(),
+            readonly: self.readonly,
+            range,
+            data,
+        })
+    }
+}
+
+impl JournalFactory<SqliteJournal> for SqliteJournalFactory {
+    /// open a journal, creating it if it doesn't exist and the database is
+    /// writable
+    fn open(&self, id: JournalId) -> JournalResult<SqliteJournal> {
+        if !self.readonly {
+            self.conn
+                .lock()
+                .unwrap()
+                .execute(
+                    "INSERT INTO journals (id) VALUES (?)
+                    ON CONFLICT DO NOTHING",
+                    params![id],
+                )
+                .map_err(sql_err)?;
+        }
+        self.load(id)
+    }
+}
+
+pub struct SqliteJournal {
+    id: JournalId,
+    conn: Arc<Mutex<Connection>>,
+    readonly: bool,
+    range: LsnRange,
+    data: Vec<Vec<u8>>,
+}
+
+impl Debug for SqliteJournal {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("SqliteJournal")
+            .field(&self.id)
+            .field(&self.range)
+            .finish()
+    }
+}
+
+impl SqliteJournal {
+    fn write_frame(&mut self, frame: Vec<u8>) -> JournalResult<()> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO frames (journal_id, lsn, data) VALUES (?, ?, ?)",
+                params![self.id, self.range.next() as i64, frame],
+            )
+            .map_err(sql_err)?;
+        self.data.push(frame);
+        self.range = self.range.extend_by(1);
+        Ok(())
+    }
+
+    /// drop every frame outside of range, which must be a suffix of the
+    /// current range
+    fn truncate(&mut self, range: LsnRange) -> JournalResult<()> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        let first = match range {
+            LsnRange::Empty { nextlsn } => nextlsn,
+            LsnRange::NonEmpty { first, .. } => first,
+        } as i64;
+
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let txn = conn.transaction().map_err(sql_err)?;
+            txn.execute(
+                "DELETE FROM frames WHERE journal_id = ? AND lsn < ?",
+                params![self.id, first],
+            )
+            .map_err(sql_err)?;
+            txn.execute(
+                "UPDATE journals SET first_lsn = ? WHERE id = ?",
+                params![first, self.id],
+            )
+            .map_err(sql_err)?;
+            txn.commit().map_err(sql_err)?;
+        }
+
+        let offsets = self.range.intersection_offsets(&range);
+        self.data = self.data[offsets].to_vec();
+        self.range = range;
+        Ok(())
+    }
+}
+
+impl Journal for SqliteJournal {
+    type Factory = SqliteJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> JournalResult<()> {
+        let entry = obj.to_vec().map_err(JournalError::SerializationError)?;
+        self.write_frame(entry)
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> JournalResult<()> {
+        let remaining_range = self.range.trim_prefix(up_to);
+        if remaining_range != self.range {
+            self.truncate(remaining_range)?;
+        }
+        Ok(())
+    }
+
+    fn drop_suffix(&mut self, from: Lsn) -> JournalResult<()> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        let remaining_range = self.range.trim_suffix(from);
+        if remaining_range != self.range {
+            self.conn
+                .lock()
+                .unwrap()
+                .execute(
+                    "DELETE FROM frames WHERE journal_id = ? AND lsn >= ?",
+                    params![self.id, from as i64],
+                )
+                .map_err(sql_err)?;
+            self.data.truncate(remaining_range.len());
+            self.range = remaining_range;
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        first: Lsn,
+        frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        if self.readonly {
+            return Err(readonly_err());
+        }
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let txn = conn.transaction().map_err(sql_err)?;
+            txn.execute(
+                "DELETE FROM frames WHERE journal_id = ?",
+                params![self.id],
+            )
+            .map_err(sql_err)?;
+            {
+                let mut stmt = txn
+                    .prepare(
+                        "INSERT INTO frames (journal_id, lsn, data)
+                        VALUES (?, ?, ?)",
+                    )
+                    .map_err(sql_err)?;
+                for (lsn, frame) in (first..).zip(&frames) {
+                    stmt.execute(params![self.id, lsn as i64, frame])
+                        .map_err(sql_err)?;
+                }
+            }
+            txn.execute(
+                "UPDATE journals SET first_lsn = ? WHERE id = ?",
+                params![first as i64, self.id],
+            )
+            .map_err(sql_err)?;
+            txn.commit().map_err(sql_err)?;
+        }
+
+        self.range = LsnRange::starting_at(first, frames.len());
+        self.data = frames;
+        Ok(())
+    }
+}
+
+impl Scannable for SqliteJournal {
+    type Reader<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn scan<'a>(&'a self) -> Cursor<'a, Self, LsnIter> {
+        Cursor::new(self, self.range.iter())
+    }
+
+    fn scan_range<'a>(&'a self, range: LsnRange) -> Cursor<'a, Self, LsnIter> {
+        let intersection = self.range.intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get<'a>(&'a self, lsn: Lsn) -> io::Result<Option<Self::Reader<'a>>> {
+        Ok(self
+            .range
+            .offset(lsn)
+            .map(|offset| self.data[offset].as_slice()))
+    }
+}
+
+impl ReplicationSource for SqliteJournal {
+    type Reader<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn<'a>(
+        &'a self,
+        lsn: Lsn,
+    ) -> io::Result<Option<Self::Reader<'a>>> {
+        self.get(lsn)
+    }
+}
+
+impl ReplicationDestination for SqliteJournal {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(self.range)
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+
+        let accepted_range = if self.range.is_empty() {
+            // if we have no range, then we reset to the incoming lsn
+            LsnRange::new(lsn, lsn)
+        } else {
+            // accept any lsn in our current range or immediately following
+            self.range.extend_by(1)
+        };
+
+        if !accepted_range.contains(lsn) {
+            return Err(ReplicationError::NonContiguousLsn {
+                received: lsn,
+                range: accepted_range,
+            });
+        }
+
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+
+        // frames are immutable, so if we already have this lsn there is
+        // nothing to do
+        if self.range.contains(lsn) {
+            return Ok(());
+        }
+
+        if self.range.is_empty() && self.range.next() != lsn {
+            // move the start of the (empty) journal to the incoming lsn
+            self.truncate(LsnRange::Empty { nextlsn: lsn })?;
+        }
+        self.write_frame(frame_data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::tests::ScratchDir;
+
+    #[test]
+    fn journals_share_one_database() {
+        let dir = ScratchDir::new();
+        let path = dir.0.join("journals.db");
+        let factory = SqliteJournalFactory::open(&path).unwrap();
+
+        let a = JournalId::new128(&mut rand::thread_rng());
+        let b = JournalId::new128(&mut rand::thread_rng());
+        let mut journal_a = factory.create(a).unwrap();
+        let mut journal_b = factory.open(b).unwrap();
+        for i in 0..10u8 {
+            journal_a.append(&[i][..]).unwrap();
+            journal_b.append(&[i, i][..]).unwrap();
+        }
+        journal_a.drop_prefix(4).unwrap();
+        assert!(factory.create(a).is_err());
+
+        // a reader sees committed frames while the writer is still open
+        let reader = SqliteJournalFactory::open_readonly(&path).unwrap();
+        let journal = reader.open(a).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(5, 9));
+        assert_eq!(journal.get(5).unwrap(), Some(&[5][..]));
+        assert_eq!(reader.open(b).unwrap().range(), LsnRange::new(0, 9));
+        assert!(reader.delete(a).is_err());
+
+        assert!(factory.delete(b).unwrap());
+        assert!(!factory.delete(b).unwrap());
+        assert_eq!(factory.list().unwrap(), vec![a]);
+        drop(factory);
+
+        let factory = SqliteJournalFactory::open(&path).unwrap();
+        let journal = factory.open(a).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(5, 9));
+    }
+}
@@ -0,0 +1,607 @@
+//! TieredJournal keeps a journal's recent frames in a hot journal (usually a
+//! FileJournal on local disk) and migrates older frames to an ObjectStore
+//! such as S3, so long document histories don't need big local disks.
+//!
+//! Cold frames are fetched on demand and a bounded number of them are
+//! cached in memory. Which frames are cold is recorded in a manifest object
+//! next to the frames; frames are uploaded before the manifest which names
+//! them, and only dropped from the hot journal afterwards, so a crash at any
+//! point leaves every frame readable from one of the tiers.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::logging;
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::PositionedReader;
+use crate::replication::{
+    ReplicationDestination, ReplicationError, ReplicationSource,
+};
+use crate::{JournalFactory, Serializable};
+
+use super::{Cursor, Journal, JournalId, JournalResult, Scannable};
+
+/// a flat key value store for cold frames, e.g. an S3 bucket
+pub trait ObjectStore {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TieringPolicy {
+    /// how many of the most recent frames stay in the hot journal
+    pub hot_frames: usize,
+    /// frames are migrated once the hot journal holds this many more than
+    /// hot_frames, so that uploads happen in batches
+    pub migrate_batch: usize,
+    /// how many cold frames are cached in memory once fetched
+    pub cache_frames: usize,
+}
+
+impl Default for TieringPolicy {
+    fn default() -> Self {
+        Self { hot_frames: 10_000, migrate_batch: 1_000, cache_frames: 256 }
+    }
+}
+
+fn frame_key(id: JournalId, lsn: Lsn) -> String {
+    // zero padded so that a listing returns frames in order
+    format!("{}/{:020}", id.to_base58(), lsn)
+}
+
+fn manifest_key(id: JournalId) -> String {
+    format!("{}/cold", id.to_base58())
+}
+
+fn encode_range(range: LsnRange) -> Vec<u8> {
+    match range {
+        LsnRange::Empty { nextlsn } => nextlsn.to_le_bytes().to_vec(),
+        LsnRange::NonEmpty { first, last } => {
+            [first.to_le_bytes(), last.to_le_bytes()].concat()
+        }
+    }
+}
+
+fn decode_range(data: &[u8]) -> io::Result<LsnRange> {
+    let lsn_at = |i: usize| {
+        Lsn::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().unwrap())
+    };
+    match data.len() {
+        8 => Ok(LsnRange::Empty { nextlsn: lsn_at(0) }),
+        16 if lsn_at(0) <= lsn_at(1) => Ok(LsnRange::new(lsn_at(0), lsn_at(1))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid tier manifest",
+        )),
+    }
+}
+
+/// the cold frames fetched most recently
+struct FrameCache {
+    capacity: usize,
+    frames: BTreeMap<Lsn, Arc<Vec<u8>>>,
+    order: VecDeque<Lsn>,
+}
+
+impl FrameCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, frames: BTreeMap::new(), order: VecDeque::new() }
+    }
+
+    fn get(&self, lsn: Lsn) -> Option<Arc<Vec<u8>>> {
+        self.frames.get(&lsn).cloned()
+    }
+
+    fn insert(&mut self, lsn: Lsn, frame: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.frames.remove(&evicted);
+            }
+        }
+        self.order.push_back(lsn);
+        self.frames.insert(lsn, frame);
+    }
+
+    fn evict_up_to(&mut self, up_to: Lsn) {
+        self.frames.retain(|&lsn, _| lsn > up_to);
+        self.order.retain(|&lsn| lsn > up_to);
+    }
+}
+
+pub struct TieredJournal<J: Journal, O> {
+    hot: J,
+    store: Arc<O>,
+    policy: TieringPolicy,
+    /// frames which have been migrated out of the hot journal; always ends
+    /// right before the hot journal's range when non empty
+    cold: LsnRange,
+    cache: Mutex<FrameCache>,
+}
+
+impl<J: Journal, O> Debug for TieredJournal<J, O> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("TieredJournal")
+            .field(&self.hot)
+            .field(&("cold", &self.cold))
+            .finish()
+    }
+}
+
+impl<J: Journal, O: ObjectStore> TieredJournal<J, O> {
+    pub fn open(
+        hot: J,
+        store: Arc<O>,
+        policy: TieringPolicy,
+    ) -> JournalResult<Self> {
+        let id = hot.id();
+        let cold = match store.get(&manifest_key(id))? {
+            Some(data) => decode_range(&data)?,
+            None => LsnRange::empty(),
+        };
+        let mut journal = Self {
+            hot,
+            store,
+            policy,
+            cold,
+            cache: Mutex::new(FrameCache::new(policy.cache_frames)),
+        };
+        journal.reconcile()?;
+        Ok(journal)
+    }
+
+    /// make sure the hot journal starts where the cold tier ends
+    fn reconcile(&mut self) -> JournalResult<()> {
+        let LsnRange::NonEmpty { last, .. } = self.cold else {
+            return Ok(());
+        };
+        let hot = self.hot.range();
+        let hot_first = LsnRange::empty_preceeding(&hot).next();
+        if hot.is_non_empty()
+            && hot_first <= last
+            && self.replaced(hot_first)?
+        {
+            // we crashed after replacing the frames but before forgetting
+            // the cold tier
+            self.forget_cold(hot_first)?;
+        } else if hot.is_non_empty() && hot_first <= last && last < hot.next() {
+            // we crashed after migrating frames but before dropping them
+            self.hot.drop_prefix(last)?;
+        } else if hot_first != last + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "hot tier of journal {} starts at lsn {} but the cold \
+                    tier ends at lsn {}",
+                    self.hot.id(),
+                    hot_first,
+                    last
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// whether the hot frame at lsn, which the cold tier may also hold, was
+    /// written by replace rather than left behind by tier. tier only drops
+    /// frames from the hot journal once they are in the object store, so a
+    /// hot frame which is missing there or differs was replaced. a replaced
+    /// frame which happens to match is treated as migrated, which leaves
+    /// the frames from before the replace in place
+    fn replaced(&self, lsn: Lsn) -> JournalResult<bool> {
+        if !self.cold.contains(lsn) {
+            return Ok(true);
+        }
+        let hot = match self.hot.get(lsn)? {
+            Some(frame) => Some(frame.read_all()?),
+            None => None,
+        };
+        Ok(self.store.get(&frame_key(self.hot.id(), lsn))? != hot)
+    }
+
+    /// empty the cold tier, once every frame is in the hot journal
+    fn forget_cold(&mut self, nextlsn: Lsn) -> JournalResult<()> {
+        if self.cold.is_empty() {
+            return Ok(());
+        }
+        let cold = LsnRange::Empty { nextlsn };
+        self.write_manifest(cold)?;
+        let forgotten = std::mem::replace(&mut self.cold, cold);
+        self.delete_cold(forgotten.iter());
+        let mut cache = self.cache.lock().unwrap();
+        *cache = FrameCache::new(cache.capacity);
+        Ok(())
+    }
+
+    /// delete the objects of frames which the manifest no longer names
+    fn delete_cold(&self, lsns: impl Iterator<Item = Lsn>) {
+        for lsn in lsns {
+            let key = frame_key(self.hot.id(), lsn);
+            if let Err(err) = self.store.delete(&key) {
+                logging::warn!(
+                    doc = self.hot.id();
+                    "failed to delete cold frame {}: {}",
+                    key,
+                    err
+                );
+            }
+        }
+    }
+
+    pub fn hot(&self) -> &J {
+        &self.hot
+    }
+
+    /// the range of frames which only exist in the object store
+    pub fn cold_range(&self) -> LsnRange {
+        self.cold
+    }
+
+    fn write_manifest(&self, cold: LsnRange) -> io::Result<()> {
+        self.store
+            .put(&manifest_key(self.hot.id()), &encode_range(cold))
+    }
+
+    /// migrate every frame beyond the newest policy.hot_frames to the
+    /// object store, returning the number of frames migrated
+    pub fn tier(&mut self) -> JournalResult<usize> {
+        let hot = self.hot.range();
+        let excess = hot.len().saturating_sub(self.policy.hot_frames);
+        let LsnRange::NonEmpty { first, .. } = hot else {
+            return Ok(0);
+        };
+        if excess == 0 {
+            return Ok(0);
+        }
+
+        let up_to = first + excess as Lsn - 1;
+        for lsn in first..=up_to {
+            let frame = self
+                .hot
+                .get(lsn)?
+                .expect("lsn is in the hot range")
+                .read_all()?;
+            self.store.put(&frame_key(self.hot.id(), lsn), &frame)?;
+        }
+
+        let cold = match self.cold {
+            LsnRange::NonEmpty { first: cold_first, .. } => {
+                LsnRange::new(cold_first, up_to)
+            }
+            LsnRange::Empty { .. } => LsnRange::new(first, up_to),
+        };
+        self.write_manifest(cold)?;
+        self.cold = cold;
+        self.hot.drop_prefix(up_to)?;
+        Ok(excess)
+    }
+
+    fn fetch(&self, lsn: Lsn) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(frame) = self.cache.lock().unwrap().get(lsn) {
+            return Ok(frame);
+        }
+        let Some(frame) = self.store.get(&frame_key(self.hot.id(), lsn))?
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cold frame {} is missing from the object store", lsn),
+            ));
+        };
+        let frame = Arc::new(frame);
+        self.cache.lock().unwrap().insert(lsn, frame.clone());
+        Ok(frame)
+    }
+}
+
+pub struct TieredJournalFactory<J: Journal, O> {
+    hot: J::Factory,
+    store: Arc<O>,
+    policy: TieringPolicy,
+}
+
+impl<J: Journal, O> TieredJournalFactory<J, O> {
+    pub fn new(hot: J::Factory, store: Arc<O>, policy: TieringPolicy) -> Self {
+        Self { hot, store, policy }
+    }
+}
+
+impl<J: Journal, O: ObjectStore> JournalFactory<TieredJournal<J, O>>
+    for TieredJournalFactory<J, O>
+{
+    fn open(&self, id: JournalId) -> JournalResult<TieredJournal<J, O>> {
+        TieredJournal::open(self.hot.open(id)?, self.store.clone(), self.policy)
+    }
+}
+
+impl<J: Journal, O: ObjectStore> Journal for TieredJournal<J, O> {
+    type Factory = TieredJournalFactory<J, O>;
+
+    fn id(&self) -> JournalId {
+        self.hot.id()
+    }
+
+    fn range(&self) -> LsnRange {
+        let hot = self.hot.range();
+        match self.cold {
+            LsnRange::Empty { .. } => hot,
+            LsnRange::NonEmpty { first, last } => {
+                LsnRange::new(first, hot.last().unwrap_or(last))
+            }
+        }
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> JournalResult<()> {
+        self.hot.append(obj)
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> JournalResult<()> {
+        let cold = self.cold.trim_prefix(up_to);
+        if cold != self.cold {
+            // forget the frames before deleting them, so that a crash
+            // leaves objects behind rather than a manifest naming missing
+            // frames
+            self.write_manifest(cold)?;
+            self.delete_cold(
+                self.cold.iter().filter(|&lsn| !cold.contains(lsn)),
+            );
+            self.cache.lock().unwrap().evict_up_to(up_to);
+            self.cold = cold;
+        }
+        self.hot.drop_prefix(up_to)
+    }
+
+    /// only frames which are still in the hot journal can be dropped
+    fn drop_suffix(&mut self, from: Lsn) -> JournalResult<()> {
+        if self.cold.last().is_some_and(|last| from <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't drop frames which have been tiered",
+            )
+            .into());
+        }
+        self.hot.drop_suffix(from)
+    }
+
+    /// the hot journal replaces every frame, and then the cold tier is
+    /// forgotten. see reconcile for a crash in between
+    fn replace(
+        &mut self,
+        first: Lsn,
+        frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        self.hot.replace(first, frames)?;
+        self.forget_cold(first)
+    }
+
+    /// also migrates frames to the object store once the hot journal has
+    /// outgrown the policy
+    fn sync(&mut self) -> JournalResult<()> {
+        self.hot.sync()?;
+        let limit = self.policy.hot_frames + self.policy.migrate_batch;
+        if self.hot.range().len() >= limit {
+            self.tier()?;
+        }
+        Ok(())
+    }
+}
+
+pub enum TieredFrameReader<'a, J: Scannable + 'a> {
+    Hot(J::Reader<'a>),
+    Cold(Arc<Vec<u8>>),
+}
+
+impl<'a, J: Scannable + 'a> PositionedReader for TieredFrameReader<'a, J> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Hot(reader) => reader.read_at(pos, buf),
+            Self::Cold(frame) => frame.as_slice().read_at(pos, buf),
+        }
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        match self {
+            Self::Hot(reader) => reader.size(),
+            Self::Cold(frame) => Ok(frame.len()),
+        }
+    }
+}
+
+impl<J: Journal, O: ObjectStore> Scannable for TieredJournal<J, O> {
+    type Reader<'a>
+        = TieredFrameReader<'a, J>
+    where
+        Self: 'a;
+
+    fn scan<'a>(&'a self) -> Cursor<'a, Self, LsnIter> {
+        Cursor::new(self, self.range().iter())
+    }
+
+    fn scan_range<'a>(&'a self, range: LsnRange) -> Cursor<'a, Self, LsnIter> {
+        let intersection = self.range().intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get<'a>(&'a self, lsn: Lsn) -> io::Result<Option<Self::Reader<'a>>> {
+        if self.cold.contains(lsn) {
+            return Ok(Some(TieredFrameReader::Cold(self.fetch(lsn)?)));
+        }
+        Ok(self.hot.get(lsn)?.map(TieredFrameReader::Hot))
+    }
+}
+
+impl<J: Journal, O: ObjectStore> ReplicationSource for TieredJournal<J, O> {
+    type Reader<'a>
+        = TieredFrameReader<'a, J>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn<'a>(
+        &'a self,
+        lsn: Lsn,
+    ) -> io::Result<Option<Self::Reader<'a>>> {
+        self.get(lsn)
+    }
+}
+
+impl<J, O> ReplicationDestination for TieredJournal<J, O>
+where
+    J: Journal + ReplicationDestination,
+    O: ObjectStore,
+{
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(Journal::range(self))
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if self.cold.contains(lsn) {
+            // frames are immutable, so there is nothing to do
+            io::copy(reader, &mut io::sink())?;
+            return Ok(());
+        }
+        // the hot journal may only reset its range while there is nothing
+        // in the cold tier for it to follow
+        let range = Journal::range(self);
+        if self.cold.is_non_empty() && lsn > range.next() {
+            return Err(ReplicationError::NonContiguousLsn {
+                received: lsn,
+                range: LsnRange::empty_following(&range),
+            });
+        }
+        self.hot.write_lsn(id, lsn, reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryJournal;
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    impl ObjectStore for MemoryStore {
+        fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().insert(key.into(), data.into());
+            Ok(())
+        }
+
+        fn delete(&self, key: &str) -> io::Result<()> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn read_all<J: Journal>(journal: &J) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut cursor = journal.scan();
+        while cursor.advance().unwrap() {
+            out.push(cursor.read_all().unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn migrates_and_fetches_cold_frames() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let store = Arc::new(MemoryStore::default());
+        let policy =
+            TieringPolicy { hot_frames: 3, migrate_batch: 2, cache_frames: 2 };
+        let mut journal = TieredJournal::open(
+            MemoryJournal::open(id).unwrap(),
+            store.clone(),
+            policy,
+        )
+        .unwrap();
+
+        let frames: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 4]).collect();
+        for frame in &frames {
+            journal.append(&frame[..]).unwrap();
+            journal.sync().unwrap();
+        }
+
+        // migration happens in batches
+        assert_eq!(journal.cold_range(), LsnRange::new(0, 5));
+        assert_eq!(Journal::range(journal.hot()), LsnRange::new(6, 9));
+        assert_eq!(Journal::range(&journal), LsnRange::new(0, 9));
+        assert_eq!(read_all(&journal), frames);
+
+        // the manifest lets a new journal find the cold frames
+        let mut hot = MemoryJournal::open(id).unwrap();
+        for (lsn, frame) in frames.iter().enumerate().skip(6) {
+            hot.write_lsn(id, lsn as Lsn, &mut &frame[..]).unwrap();
+        }
+        let reopened = TieredJournal::open(hot, store.clone(), policy).unwrap();
+        assert_eq!(read_all(&reopened), frames);
+
+        journal.drop_prefix(3).unwrap();
+        assert_eq!(journal.cold_range(), LsnRange::new(4, 5));
+        assert!(store.get(&frame_key(id, 3)).unwrap().is_none());
+        assert_eq!(read_all(&journal), frames[4..]);
+    }
+
+    #[test]
+    fn replaced_frames_survive_a_crash_before_the_manifest() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let store = Arc::new(MemoryStore::default());
+        let policy =
+            TieringPolicy { hot_frames: 2, migrate_batch: 0, cache_frames: 2 };
+        let mut journal = TieredJournal::open(
+            MemoryJournal::open(id).unwrap(),
+            store.clone(),
+            policy,
+        )
+        .unwrap();
+        for i in 0..5u8 {
+            journal.append(&[i][..]).unwrap();
+            journal.sync().unwrap();
+        }
+        assert_eq!(journal.cold_range(), LsnRange::new(0, 2));
+
+        // the hot journal is replaced but the manifest still names the old
+        // cold frames
+        let replaced = vec![vec![0xff], vec![3], vec![4]];
+        let mut hot = MemoryJournal::open(id).unwrap();
+        hot.replace(2, replaced.clone()).unwrap();
+        let mut reopened =
+            TieredJournal::open(hot, store.clone(), policy).unwrap();
+        assert!(reopened.cold_range().is_empty());
+        assert!(store.get(&frame_key(id, 0)).unwrap().is_none());
+        assert_eq!(read_all(&reopened), replaced);
+
+        // without a crash the cold tier is forgotten straight away
+        journal.replace(2, replaced.clone()).unwrap();
+        assert!(journal.cold_range().is_empty());
+        assert_eq!(read_all(&journal), replaced);
+        reopened.append(&[5][..]).unwrap();
+        assert_eq!(Journal::range(&reopened), LsnRange::new(2, 5));
+    }
+}
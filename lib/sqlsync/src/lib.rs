@@ -3,28 +3,67 @@ mod iter;
 mod journal;
 mod lsn;
 mod page;
+mod pending;
 mod reactive_query;
 mod reducer;
 mod serialization;
 mod storage;
 mod vfs;
+mod wal_index;
 
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod catalog;
+pub mod cdc;
+pub mod compaction;
+pub mod config;
 pub mod coordinator;
+pub mod effects;
+#[cfg(feature = "session")]
+pub mod embed;
 pub mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod executor;
+pub mod export;
+pub mod hints;
 pub mod local;
+pub mod logging;
+#[cfg(feature = "session")]
+pub mod mux;
+pub mod order;
 pub mod positioned_io;
+pub mod profile;
+pub mod quota;
+pub mod redaction;
 pub mod replication;
+pub mod resync;
+pub mod schedule;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "session")]
+pub mod session;
+pub mod snapshot;
+pub mod stats;
+pub mod tenant;
 pub mod timeline;
+pub mod ttl;
 pub mod unixtime;
+pub mod webhook;
 
+pub use db::{is_busy, BusyBackoff, CacheSize, JournalMode};
 pub use journal::*;
 pub use reactive_query::ReactiveQuery;
-pub use reducer::{Reducer, ReducerError};
+pub use reducer::{
+    InFlight, MemoryStats, ModuleDigest, Reducer, ReducerError, ReducerPool,
+    ReducerTrap, Reduction,
+};
 pub use serialization::{Deserializable, Serializable};
-pub use storage::StorageChange;
+pub use sqlsync_reducer::{mutation::TypedMutation, mutations};
+pub use storage::{Durability, GcStats, StorageChange};
+pub use vfs::{set_spill_files, BoxedSpillFile, SpillFile};
 
 pub use lsn::{Lsn, LsnRange};
-pub use page::PageIdx;
+pub use page::{Page, PageIdx, PAGESIZE};
 
 pub mod sqlite {
     pub use rusqlite::*;
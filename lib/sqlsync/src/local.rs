@@ -1,19 +1,48 @@
-use std::{fmt::Debug, io};
+use std::{
+    fmt::Debug,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
 
-use rusqlite::Connection;
+use rusqlite::{Connection, Transaction};
 
 use crate::{
-    db::{open_with_vfs, ConnectionPair},
-    error::Result,
+    config::DocumentConfig,
+    db::{
+        attach_local, attach_storage, checkpoint_wal, detach, drain_wal,
+        is_busy, open_with_vfs, register_clock, set_cache_size, set_max_size,
+        try_drain_wal, with_local_writes, BusyBackoff, CacheSize,
+        ConnectionPair,
+    },
+    error::{Error, Result},
     journal::{Journal, JournalId},
+    logging::{self, GuestLogConfig},
     lsn::LsnRange,
-    reducer::Reducer,
+    page::PageIdx,
+    positioned_io::PositionedReader,
+    profile::ReplayProfile,
+    redaction::read_epoch,
+    reducer::{ModuleDigest, Reducer, ReducerPool},
     replication::{
-        ReplicationDestination, ReplicationError, ReplicationSource,
+        ReplicationDestination, ReplicationError, ReplicationMsg,
+        ReplicationSource,
     },
-    storage::{Storage, StorageChange},
-    timeline::{apply_mutation, rebase_timeline, run_timeline_migration},
-    Lsn,
+    resync::{PageTree, Resync, TreeHash, TreeNode},
+    snapshot::{Checkpoint, Snapshot},
+    stats::{page_stats, PageStats},
+    storage::{Durability, GcStats, Storage, StorageChange},
+    timeline::{
+        apply_draft, apply_keyed_mutation, cancel_mutation, is_cancelled,
+        observes_token, profile_timeline, rebase_timeline,
+        run_timeline_migration, ConsistencyToken, FrameMeta, IdempotencyKey,
+        TimelineError,
+    },
+    unixtime::{HlcTimestamp, HybridClock},
+    JournalError, Lsn, Serializable, TypedMutation,
 };
 
 pub trait Signal {
@@ -25,11 +54,94 @@ impl Signal for NoopSignal {
     fn emit(&mut self) {}
 }
 
+/// StorageRetention bounds a client's storage journal for devices with
+/// little room to spare. Once storage holds more than max_frames frames, all
+/// but the most recent keep_frames are folded into a single frame holding
+/// every page as of then. Frames which have been folded are gone locally; a
+/// page_stats window reaching back past them only sees the folded frame,
+/// query_at an older lsn needs request_history to fetch the document as of
+/// that lsn from the coordinator, and diverged pages are refetched with
+/// resync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageRetention {
+    pub max_frames: usize,
+    pub keep_frames: usize,
+}
+
+/// SyncLag is how far a document trails the frames it has received from the
+/// coordinator, and how much of its own work the coordinator has yet to
+/// reflect back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncLag {
+    /// the last storage frame received from the coordinator
+    pub received_lsn: Option<Lsn>,
+    /// the last storage frame queries can see, which catches up on rebase
+    pub applied_lsn: Option<Lsn>,
+    /// local mutations which no rebase has confirmed the coordinator applied
+    pub pending_mutations: usize,
+}
+
+impl SyncLag {
+    /// received storage frames which haven't been applied yet
+    pub fn frames_behind(&self) -> u64 {
+        match (self.received_lsn, self.applied_lsn) {
+            (Some(received), Some(applied)) => received.saturating_sub(applied),
+            (Some(received), None) => received + 1,
+            (None, _) => 0,
+        }
+    }
+}
+
+/// a mutation which has been applied locally without being added to the
+/// timeline, see LocalDocument::mutate_draft
+struct Draft {
+    id: u64,
+    mutation: Vec<u8>,
+}
+
 pub struct LocalDocument<J, S> {
     reducer: Reducer,
+    reducer_digest: Option<ModuleDigest>,
+    // the coordinator's reducer digest, when it differs from ours
+    update_required: Option<ModuleDigest>,
+    // a reducer's wasm sent by the coordinator, see take_received_reducer
+    received_reducer: Option<(ModuleDigest, Vec<u8>)>,
     timeline: J,
-    storage: Box<Storage<J>>,
+    // timeline frames before this lsn may have been sent to the coordinator,
+    // which includes every frame from before the document was opened
+    sent_until: AtomicU64,
+    // applied on top of the timeline, oldest first
+    drafts: Vec<Draft>,
+    next_draft_id: u64,
+    // where drafts are persisted, see persist_drafts
+    drafts_log: Option<J>,
+    // the connections read through storage and the attached storages, so
+    // they're dropped first
     sqlite: ConnectionPair,
+    storage: Box<Storage<J>>,
+    attached: Vec<(String, Box<Storage<J>>)>,
+    durability: Durability,
+    clock: Arc<Mutex<HybridClock>>,
+    config: DocumentConfig,
+    retention: Option<StorageRetention>,
+    gc_stats: GcStats,
+    cache_size: CacheSize,
+    // the cache size currently applied to sqlite, in pages
+    cache_pages: u32,
+    busy_backoff: BusyBackoff,
+
+    // set when local-only or attached tables change, as storage doesn't
+    // track them
+    untracked_changes: bool,
+    // see data_version
+    data_version: u64,
+
+    // a resync with the coordinator, along with the storage lsn our page
+    // tree was built at
+    resync: Option<(Option<Lsn>, Resync)>,
+    // the document as of an lsn outside our storage, fetched from the
+    // coordinator by request_history
+    history: Option<Checkpoint>,
 
     // signals
     storage_changed: S,
@@ -54,37 +166,283 @@ where
     pub fn open(
         storage: J,
         timeline: J,
-        reducer: Reducer,
+        mut reducer: Reducer,
         storage_changed: S,
         timeline_changed: S,
         rebase_available: S,
     ) -> Result<Self> {
+        reducer.set_doc_id(storage.id());
         let (mut sqlite, storage) = open_with_vfs(storage)?;
 
         // TODO: this feels awkward here
         run_timeline_migration(&mut sqlite.readwrite)?;
+        attach_local(&mut sqlite)?;
 
-        Ok(Self {
+        let clock = Arc::new(Mutex::new(HybridClock::default()));
+        register_clock(&sqlite.readwrite, clock.clone())?;
+
+        let mut doc = Self {
+            reducer_digest: reducer.module_digest(),
+            update_required: None,
+            received_reducer: None,
             reducer,
+            sent_until: AtomicU64::new(timeline.range().next()),
             timeline,
+            drafts: Vec::new(),
+            next_draft_id: 0,
+            drafts_log: None,
             storage,
             sqlite,
+            attached: Vec::new(),
+            durability: Durability::default(),
+            clock,
+            config: DocumentConfig::default(),
+            retention: None,
+            gc_stats: GcStats::default(),
+            cache_size: CacheSize::default(),
+            cache_pages: 0,
+            busy_backoff: BusyBackoff::default(),
+            untracked_changes: false,
+            data_version: 0,
+            resync: None,
+            history: None,
             storage_changed,
             timeline_changed,
             rebase_available,
-        })
+        };
+        doc.resize_cache()?;
+
+        // a timeline which outlives the process (e.g. a FileJournal) may
+        // hold mutations that storage doesn't include yet
+        if !doc.timeline.range().is_empty() {
+            doc.rebase_local()?;
+            doc.signal_storage_change();
+        }
+        Ok(doc)
     }
 
     fn signal_storage_change(&mut self) {
+        self.data_version += 1;
         if self.storage.has_changes() {
             self.storage_changed.emit()
         }
     }
 
+    /// revert storage to the last committed lsn. in WAL mode every waiting
+    /// frame is checkpointed first, otherwise sqlite would keep reading
+    /// them over the reverted pages
+    fn reset_storage(&mut self) -> Result<()> {
+        drain_wal(&self.sqlite.readwrite)?;
+        self.storage.reset()?;
+        Ok(())
+    }
+
+    /// reapply the timeline's pending mutations and then the drafts. storage
+    /// must have been reset first
+    fn rebase_local(&mut self) -> Result<()> {
+        rebase_timeline(
+            &mut self.timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+        )?;
+        self.apply_drafts()
+    }
+
+    /// apply every draft on top of the timeline, discarding the ones which
+    /// the reducer now rejects
+    fn apply_drafts(&mut self) -> Result<()> {
+        let timeline_id = self.timeline.id();
+        let mut rejected = vec![];
+        for draft in &self.drafts {
+            match apply_draft(
+                &mut self.sqlite.readwrite,
+                &mut self.reducer,
+                timeline_id,
+                &draft.mutation,
+            ) {
+                Ok(()) => {}
+                Err(TimelineError::ReducerError(err)) => {
+                    logging::warn!(
+                        doc = self.storage.id();
+                        "discarding draft {}: {}", draft.id, err
+                    );
+                    rejected.push(draft.id);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if rejected.is_empty() {
+            return Ok(());
+        }
+        self.drafts.retain(|draft| !rejected.contains(&draft.id));
+        self.write_drafts()
+    }
+
+    /// rewrite the drafts log, if there is one, to hold the current drafts
+    fn write_drafts(&mut self) -> Result<()> {
+        let Some(log) = self.drafts_log.as_mut() else {
+            return Ok(());
+        };
+        let first = log.range().next();
+        let drafts = self.drafts.iter().map(|draft| draft.mutation.clone());
+        log.replace(first, drafts.collect())?;
+        log.sync()?;
+        Ok(())
+    }
+
+    /// roll back the drafts, run f and then reapply the drafts on top of
+    /// whatever it added to the timeline
+    fn without_drafts<O>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<O>,
+    ) -> Result<O> {
+        let drafts = std::mem::take(&mut self.drafts);
+        let out = self.reset_storage().and_then(|_| self.rebase_local());
+        let out = out.and_then(|_| f(self));
+        self.drafts = drafts;
+        self.apply_drafts()?;
+        checkpoint_wal(&self.sqlite.readwrite)?;
+        self.signal_storage_change();
+        out
+    }
+
     pub fn doc_id(&self) -> JournalId {
         self.storage.source_id()
     }
 
+    /// with Durability::Sync, mutate only returns once the mutation has been
+    /// synced to the timeline journal
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        self.storage.set_durability(durability);
+    }
+
+    /// cap the size of the document in bytes. mutations which would grow the
+    /// document beyond the cap fail; see Error::is_storage_full
+    pub fn set_max_size(&mut self, max_bytes: u64) -> Result<()> {
+        Ok(set_max_size(
+            &self.sqlite.readwrite,
+            &mut self.storage,
+            max_bytes,
+        )?)
+    }
+
+    pub fn busy_backoff(&self) -> BusyBackoff {
+        self.busy_backoff
+    }
+
+    /// how query_retrying retries queries which find the database busy
+    pub fn set_busy_backoff(&mut self, busy_backoff: BusyBackoff) {
+        self.busy_backoff = busy_backoff;
+    }
+
+    /// cap the memory used by a WAL mode document's uncommitted and
+    /// uncheckpointed frames, which grow with the largest transaction. see
+    /// Storage::set_wal_memory_limit
+    pub fn set_wal_memory_limit(&mut self, max_bytes: usize) {
+        self.storage.set_wal_memory_limit(max_bytes);
+    }
+
+    /// size the page cache of each of the document's sqlite connections.
+    /// CacheSize::Adaptive follows the document as it grows
+    pub fn set_cache_size(&mut self, cache_size: CacheSize) -> Result<()> {
+        self.cache_size = cache_size;
+        self.resize_cache()
+    }
+
+    fn resize_cache(&mut self) -> Result<()> {
+        let pages = self.cache_size.pages(self.storage.num_pages()?);
+        if pages != self.cache_pages {
+            set_cache_size(&self.sqlite, pages)?;
+            self.cache_pages = pages;
+        }
+        Ok(())
+    }
+
+    /// the config most recently sent by the coordinator
+    pub fn config(&self) -> &DocumentConfig {
+        &self.config
+    }
+
+    /// set the digest of this document's reducer, needed to notice that the
+    /// coordinator runs a different one unless the reducer came from a
+    /// ReducerPool
+    pub fn set_reducer_digest(&mut self, digest: ModuleDigest) {
+        self.reducer_digest = Some(digest);
+    }
+
+    /// the coordinator's reducer digest when the coordinator runs a newer
+    /// reducer than this document, going by the versions the reducers
+    /// declare (see Reducer::version). mutations fail with
+    /// Error::UpdateRequired until the client reopens the document with that
+    /// reducer, while changes from the coordinator are still received. a
+    /// client which is ahead of its coordinator, e.g. mid deploy, keeps
+    /// mutating
+    pub fn update_required(&self) -> Option<ModuleDigest> {
+        self.update_required
+    }
+
+    /// the wasm of the coordinator's reducer, once it has arrived in reply
+    /// to a ReplicationMsg::ReducerRequest
+    pub fn take_received_reducer(&mut self) -> Option<(ModuleDigest, Vec<u8>)> {
+        self.received_reducer.take()
+    }
+
+    /// switch to a different reducer, such as the coordinator's, and rebase
+    /// pending mutations with it as the old reducer applied them
+    pub fn replace_reducer(
+        &mut self,
+        mut reducer: Reducer,
+        digest: ModuleDigest,
+    ) -> Result<()> {
+        reducer.set_doc_id(self.storage.id());
+        self.reducer = reducer;
+        self.reducer_digest = Some(digest);
+        if self.update_required == Some(digest) {
+            self.update_required = None;
+        }
+        self.reset_storage()?;
+        self.rebase_local()?;
+        self.signal_storage_change();
+        Ok(())
+    }
+
+    /// bound how many storage frames are kept locally, overriding the
+    /// coordinator's DocumentConfig::compact_after_frames. None defers to the
+    /// coordinator
+    pub fn set_storage_retention(
+        &mut self,
+        retention: Option<StorageRetention>,
+    ) {
+        self.retention = retention;
+    }
+
+    /// the retention applied whenever the document is rebased, if any
+    pub fn storage_retention(&self) -> Option<StorageRetention> {
+        self.retention.or_else(|| {
+            let max_frames = self.config.compact_after_frames?;
+            Some(StorageRetention {
+                max_frames: max_frames as usize,
+                keep_frames: 0,
+            })
+        })
+    }
+
+    /// filter and rate limit the logs emitted by this document's reducer
+    pub fn set_guest_log_config(&mut self, config: GuestLogConfig) {
+        self.reducer.set_log_config(config);
+    }
+
+    fn clock(&self) -> MutexGuard<'_, HybridClock> {
+        self.clock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// the next timestamp from this document's hybrid clock; the same clock
+    /// backs sqlsync_now() in reducers
+    pub fn now(&self) -> HlcTimestamp {
+        self.clock().now()
+    }
+
     pub fn query<F, O, E>(&self, f: F) -> std::result::Result<O, E>
     where
         F: FnOnce(&Connection) -> std::result::Result<O, E>,
@@ -93,44 +451,556 @@ where
         f(&self.sqlite.readonly)
     }
 
+    /// like query, but f is run again while it fails with SQLITE_BUSY,
+    /// calling sleep with each of busy_backoff's delays in between. native
+    /// hosts can pass std::thread::sleep, while async hosts should run their
+    /// own loop over busy_backoff().delays() and wait on their scheduler
+    pub fn query_retrying<F, O, S>(
+        &self,
+        mut f: F,
+        mut sleep: S,
+    ) -> rusqlite::Result<O>
+    where
+        F: FnMut(&Connection) -> rusqlite::Result<O>,
+        S: FnMut(Duration),
+    {
+        let mut delays = self.busy_backoff.delays();
+        loop {
+            match f(&self.sqlite.readonly) {
+                Err(err) if is_busy(&err) => match delays.next() {
+                    Some(delay) => sleep(delay),
+                    None => return Err(err),
+                },
+                out => return out,
+            }
+        }
+    }
+
     #[inline]
     pub fn sqlite_readonly(&self) -> &Connection {
         &self.sqlite.readonly
     }
 
-    pub fn mutate(&mut self, m: &[u8]) -> Result<()> {
-        apply_mutation(
+    /// apply a mutation, returning a token which any replica of the document
+    /// can check it observes the mutation with
+    pub fn mutate(&mut self, m: &[u8]) -> Result<ConsistencyToken> {
+        self.mutate_with_key(m, None)?;
+        let lsn = self.last_mutation_id().expect("mutate appends a mutation");
+        Ok(self.consistency_token(lsn))
+    }
+
+    /// encode and apply a mutation declared with sqlsync::mutations!
+    pub fn mutate_typed<M: TypedMutation>(
+        &mut self,
+        mutation: &M,
+    ) -> Result<ConsistencyToken> {
+        self.mutate(&mutation.encode())
+    }
+
+    /// the consistency token of a mutation in this document's timeline
+    pub fn consistency_token(&self, mutation_id: Lsn) -> ConsistencyToken {
+        ConsistencyToken { timeline_id: self.timeline.id(), lsn: mutation_id }
+    }
+
+    /// whether queries observe the mutation the token was returned for,
+    /// either because it's one of this document's pending mutations or
+    /// because the document has rebased onto storage which includes it.
+    /// tokens from other replicas (or an earlier session's timeline) are only
+    /// observed once they've been through the coordinator. fails with
+    /// TimelineError::MutationCancelled if the mutation was cancelled, see
+    /// cancel_pending
+    pub fn observes(&self, token: ConsistencyToken) -> Result<bool> {
+        if token.timeline_id == self.timeline.id()
+            && self.timeline.range().contains(token.lsn)
+        {
+            if is_cancelled(&self.timeline, token.lsn)? {
+                return Err(TimelineError::MutationCancelled(token).into());
+            }
+            return Ok(true);
+        }
+        Ok(observes_token(&self.sqlite.readonly, token)?)
+    }
+
+    /// mutate with an idempotency key. if a mutation with the same key has
+    /// already been applied from this document's timeline the mutation is
+    /// skipped and false is returned.
+    pub fn mutate_with_key(
+        &mut self,
+        m: &[u8],
+        key: Option<IdempotencyKey>,
+    ) -> Result<bool> {
+        self.mutate_with_meta(m, key, FrameMeta::default())
+    }
+
+    /// like mutate_with_key, but attaches a timestamp and tag to the
+    /// mutation which the coordinator's change log reports along with it
+    pub fn mutate_with_meta(
+        &mut self,
+        m: &[u8],
+        key: Option<IdempotencyKey>,
+        meta: FrameMeta,
+    ) -> Result<bool> {
+        if let Some(digest) = self.update_required {
+            return Err(Error::UpdateRequired { digest });
+        }
+        if let Some(max) = self.config.max_mutation_size {
+            if m.len() as u64 > max {
+                return Err(Error::MutationTooLarge {
+                    len: m.len() as u64,
+                    max,
+                });
+            }
+        }
+        // with drafts, the mutation is reduced on top of them just like a
+        // pending mutation is. it's ordered before them in the timeline
+        // though, which the next rebase (or promote or discard) catches up on
+        let applied = apply_keyed_mutation(
             &mut self.timeline,
             &mut self.sqlite.readwrite,
             &mut self.reducer,
             m,
+            key,
+            meta,
+            self.config.compress_mutations_over,
+        )?;
+        if !applied {
+            return Ok(false);
+        }
+        if self.durability == Durability::Sync {
+            self.timeline.sync()?;
+        }
+        self.timeline_changed.emit();
+        checkpoint_wal(&self.sqlite.readwrite)?;
+        self.signal_storage_change();
+        Ok(true)
+    }
+
+    /// apply a mutation as a draft: local queries see its effects, but it
+    /// isn't added to the timeline (and so never syncs) until it's promoted.
+    /// drafts stay on top of the timeline as it's rebased, and a draft the
+    /// reducer rejects on a rebase is discarded. returns the draft's id
+    pub fn mutate_draft(&mut self, m: &[u8]) -> Result<u64> {
+        if let Some(digest) = self.update_required {
+            return Err(Error::UpdateRequired { digest });
+        }
+        apply_draft(
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            self.timeline.id(),
+            m,
         )?;
+        if let Some(log) = self.drafts_log.as_mut() {
+            log.append(m)?;
+            log.sync()?;
+        }
+        let id = self.next_draft_id;
+        self.next_draft_id += 1;
+        self.drafts.push(Draft { id, mutation: m.to_vec() });
+        checkpoint_wal(&self.sqlite.readwrite)?;
+        self.signal_storage_change();
+        Ok(id)
+    }
+
+    /// the ids of the document's drafts, oldest first. while there are any,
+    /// queries see state which may never sync
+    pub fn drafts(&self) -> Vec<u64> {
+        self.drafts.iter().map(|draft| draft.id).collect()
+    }
+
+    /// whether queries see the effects of any drafts
+    pub fn has_drafts(&self) -> bool {
+        !self.drafts.is_empty()
+    }
+
+    /// keep the document's drafts in log, so they outlive the document. the
+    /// drafts already in log (e.g. from the last time the document was open)
+    /// are restored underneath the document's current drafts. draft ids are
+    /// only stable while the document is open
+    pub fn persist_drafts(&mut self, log: J) -> Result<()> {
+        let mut drafts = vec![];
+        let mut cursor = log.scan();
+        while cursor.advance().map_err(JournalError::from)? {
+            let mutation = cursor.read_all().map_err(JournalError::from)?;
+            drafts.push(Draft { id: self.next_draft_id, mutation });
+            self.next_draft_id += 1;
+        }
+        drop(cursor);
+        self.drafts_log = Some(log);
+
+        let restored = !drafts.is_empty();
+        drafts.append(&mut self.drafts);
+        self.drafts = drafts;
+        if restored {
+            // the restored drafts go underneath ours
+            self.without_drafts(|_| Ok(()))?;
+        }
+        self.write_drafts()
+    }
+
+    fn draft_index(&self, id: u64) -> Result<usize> {
+        self.drafts
+            .iter()
+            .position(|draft| draft.id == id)
+            .ok_or(Error::UnknownDraft(id))
+    }
+
+    /// add a draft to the timeline as mutate_with_meta would, after every
+    /// mutation already in it. a draft which fails to promote is kept
+    pub fn promote_draft(
+        &mut self,
+        id: u64,
+        key: Option<IdempotencyKey>,
+        meta: FrameMeta,
+    ) -> Result<bool> {
+        let idx = self.draft_index(id)?;
+        let draft = self.drafts.remove(idx);
+        let out = self.without_drafts(|doc| {
+            doc.mutate_with_meta(&draft.mutation, key, meta)
+        });
+        if out.is_err() {
+            self.drafts.insert(idx, draft);
+            self.without_drafts(|_| Ok(()))?;
+            return out;
+        }
+        // a crash before this restores the draft, which is then both
+        // promoted and a draft until it's discarded
+        self.write_drafts()?;
+        out
+    }
+
+    /// discard a draft, rolling back its effects
+    pub fn discard_draft(&mut self, id: u64) -> Result<()> {
+        let idx = self.draft_index(id)?;
+        self.drafts.remove(idx);
+        self.write_drafts()?;
+        self.without_drafts(|_| Ok(()))
+    }
+
+    /// the id of the latest mutation in this document's timeline, which is
+    /// its lsn there
+    pub fn last_mutation_id(&self) -> Option<Lsn> {
+        self.timeline.range().last()
+    }
+
+    /// cancel a local mutation which hasn't been sent to the coordinator
+    /// yet, rolling back its effects by rebasing the mutations after it.
+    /// returns false if the mutation may already have been sent (or is
+    /// unknown), in which case only another mutation can undo it
+    pub fn cancel_pending(&mut self, mutation_id: Lsn) -> Result<bool> {
+        if mutation_id < *self.sent_until.get_mut()
+            || !cancel_mutation(&mut self.timeline, mutation_id)?
+        {
+            return Ok(false);
+        }
+        if self.durability == Durability::Sync {
+            self.timeline.sync()?;
+        }
+        self.reset_storage()?;
+        self.rebase_local()?;
         self.timeline_changed.emit();
         self.signal_storage_change();
+        Ok(true)
+    }
+
+    /// run f in a transaction which may only write to this device's
+    /// local-only tables (the `local` schema). local tables are never
+    /// journaled or replicated, which makes them a good home for UI state
+    /// and caches. they are readable from queries but not from reducers.
+    /// they are held in memory whatever the document's journal is, so they
+    /// are lost when the document is closed (see db::attach_local).
+    pub fn mutate_local<F, O, E>(&mut self, f: F) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Transaction) -> std::result::Result<O, E>,
+        E: std::convert::From<rusqlite::Error>,
+    {
+        let out = with_local_writes(&mut self.sqlite.readwrite, |conn| {
+            let txn = conn.transaction()?;
+            let out = f(&txn)?;
+            txn.commit()?;
+            Ok::<_, E>(out)
+        })?;
+        self.untracked_changes = true;
+        self.data_version += 1;
+        self.storage_changed.emit();
+        Ok(out)
+    }
+
+    /// attach another document's storage journal as schema so that queries
+    /// can join across documents. the attachment is read-only and doesn't
+    /// follow changes to the other document; detach and attach it again to
+    /// pick them up.
+    pub fn attach(&mut self, schema: &str, journal: J) -> Result<()> {
+        let storage = attach_storage(&mut self.sqlite, schema, journal)?;
+        self.attached.push((schema.to_owned(), storage));
+        self.untracked_changes = true;
+        self.data_version += 1;
+        self.storage_changed.emit();
         Ok(())
     }
 
+    pub fn detach(&mut self, schema: &str) -> Result<()> {
+        detach(&mut self.sqlite, schema)?;
+        // only drop the storage once sqlite is done with it
+        self.attached.retain(|(name, _)| name != schema);
+        self.untracked_changes = true;
+        self.data_version += 1;
+        self.storage_changed.emit();
+        Ok(())
+    }
+
+    pub fn storage_changes(&mut self) -> Result<StorageChange> {
+        let changes = self.storage.changes()?;
+        // we don't know which untracked tables changed, so refresh everything
+        if std::mem::take(&mut self.untracked_changes) {
+            return Ok(StorageChange::Full);
+        }
+        Ok(changes)
+    }
+
+    /// moves whenever the document may have changed, so that a reader
+    /// spanning several queries can tell whether they all saw the same
+    /// document. like sqlite's PRAGMA data_version, only changes between
+    /// two reads matter, not the value itself
+    pub fn data_version(&self) -> u64 {
+        self.data_version
+    }
+
+    pub fn storage_lsn(&mut self) -> Option<Lsn> {
+        self.storage.last_committed_lsn()
+    }
+
+    /// replay the mutations in range of the timeline on top of the latest
+    /// storage, as a rebase would, measuring each one. the document is
+    /// rebased afterwards
+    pub fn replay_profile(&mut self, range: LsnRange) -> Result<ReplayProfile> {
+        self.reset_storage()?;
+        let storage = &self.storage;
+        let profiled = profile_timeline(
+            &self.timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            range,
+            || storage.pages_written(),
+        );
+
+        // discard the replayed mutations, even if profiling failed
+        self.reset_storage()?;
+        self.rebase_local()?;
+        self.signal_storage_change();
+
+        Ok(ReplayProfile {
+            range: range.intersect(&self.timeline.range()),
+            mutations: profiled?,
+        })
+    }
+
+    /// pages, bytes and page writes in window per table and index of the
+    /// replicated database; local-only tables aren't included
+    pub fn page_stats(&self, window: LsnRange) -> Result<PageStats> {
+        page_stats(&self.sqlite.readonly, &self.storage, window)
+    }
+
+    /// the storage lsns query_at can read without asking the coordinator.
+    /// collect_garbage drops the lsns before its last pass
+    pub fn history_range(&self) -> LsnRange {
+        self.storage.retained_range()
+    }
+
+    /// run read-only queries against the replicated document as it was at
+    /// a storage lsn, see CoordinatorDocument::query_at. lsns outside
+    /// history_range fail with Error::LsnNotRetained, unless the document
+    /// as of lsn has been fetched from the coordinator with request_history
+    pub fn query_at<F, O>(&self, lsn: Lsn, f: F) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<O>,
+    {
+        let pages =
+            self.storage.snapshot_at(lsn).map_err(JournalError::from)?;
+        if let Some(pages) = pages {
+            let checkpoint = Checkpoint {
+                doc_id: self.doc_id(),
+                lsn,
+                snapshot: Snapshot::new(pages),
+            };
+            return checkpoint.query(f);
+        }
+        match &self.history {
+            Some(checkpoint) if checkpoint.lsn == lsn => checkpoint.query(f),
+            _ => Err(Error::LsnNotRetained {
+                lsn,
+                retained: self.history_range(),
+            }),
+        }
+    }
+
+    /// the message asking the coordinator for the document as of lsn, for
+    /// query_at an lsn which storage_retention has folded away. only the
+    /// most recently fetched lsn is kept
+    pub fn request_history(&self, lsn: Lsn) -> ReplicationMsg {
+        ReplicationMsg::HistoryRequest { lsn }
+    }
+}
+
+impl<J, S> LocalDocument<J, S>
+where
+    J: Journal + ReplicationSource + ReplicationDestination,
+    S: Signal,
+{
+    /// rebase local mutations on top of storage frames received from the
+    /// coordinator. while a query is still reading the WAL the rebase is put
+    /// off, raising rebase_available again so the host retries
     pub fn rebase(&mut self) -> Result<()> {
         if self.storage.has_committed_pages()
             && self.storage.has_invisible_pages()
         {
+            if !try_drain_wal(&self.sqlite.readwrite)? {
+                // a query is still reading the WAL, so the rebase waits for
+                // the host's next call
+                logging::debug!(
+                    doc = self.doc_id();
+                    "deferring rebase until queries finish reading the WAL"
+                );
+                self.rebase_available.emit();
+                return Ok(());
+            }
             self.storage.reset()?;
-            rebase_timeline(
-                &mut self.timeline,
-                &mut self.sqlite.readwrite,
-                &mut self.reducer,
-            )?;
+            self.retain_storage()?;
+            self.resize_cache()?;
+            self.rebase_local()?;
             self.signal_storage_change();
         }
         Ok(())
     }
 
-    pub fn storage_changes(&mut self) -> Result<StorageChange> {
-        Ok(self.storage.changes()?)
+    /// fold old storage frames away according to storage_retention. sqlite
+    /// sees the same pages either way, so this is invisible to queries
+    fn retain_storage(&mut self) -> Result<()> {
+        let Some(retention) = self.storage_retention() else {
+            return Ok(());
+        };
+        let range = self.storage.visible_range();
+        if let Some(last) = range.last() {
+            if range.len() > retention.max_frames {
+                let up_to = last.saturating_sub(retention.keep_frames as Lsn);
+                self.storage.compact(up_to)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn storage_lsn(&mut self) -> Option<Lsn> {
-        self.storage.last_committed_lsn()
+    /// reclaim the page versions rebases have left behind in storage frames,
+    /// see Storage::collect_garbage. the most recent young_frames frames are
+    /// left alone, as their pages are the most likely to be overwritten
+    /// again soon. returns what this pass reclaimed; gc_stats sums every pass
+    pub fn collect_garbage(&mut self, young_frames: usize) -> Result<GcStats> {
+        let up_to = self
+            .storage
+            .visible_range()
+            .last()
+            .and_then(|last| last.checked_sub(young_frames as Lsn));
+        let Some(up_to) = up_to else {
+            return Ok(GcStats::default());
+        };
+        let pass = self.storage.collect_garbage(up_to)?;
+        self.gc_stats.runs += pass.runs;
+        self.gc_stats.frames_rewritten += pass.frames_rewritten;
+        self.gc_stats.pages_reclaimed += pass.pages_reclaimed;
+        self.gc_stats.bytes_reclaimed += pass.bytes_reclaimed;
+        Ok(pass)
+    }
+
+    /// every garbage collection pass since the document was opened
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats
+    }
+
+    /// the size of the storage journal's frames in bytes, which is most of
+    /// what a document in a MemoryJournal holds in memory
+    pub fn journal_size(&self) -> Result<u64> {
+        Ok(self.storage.journal_size().map_err(JournalError::from)?)
+    }
+
+    pub fn sync_lag(&self) -> SyncLag {
+        SyncLag {
+            received_lsn: self.storage.last_committed_lsn(),
+            applied_lsn: self.storage.visible_range().last(),
+            pending_mutations: self.timeline.range().len(),
+        }
+    }
+
+    /// seed a document which has no storage yet from a checkpoint published by
+    /// its coordinator. replication then picks up from the lsn following the
+    /// checkpoint. returns false (ignoring the checkpoint) if the document
+    /// already has storage
+    pub fn bootstrap(&mut self, checkpoint: Checkpoint) -> Result<bool> {
+        if checkpoint.doc_id != self.doc_id() {
+            return Err(
+                ReplicationError::UnknownJournal(checkpoint.doc_id).into()
+            );
+        }
+        if self.storage.has_committed_pages() || checkpoint.snapshot.is_empty()
+        {
+            return Ok(false);
+        }
+        let mut frame = Vec::new();
+        checkpoint
+            .snapshot
+            .serialize_into(&mut frame)
+            .map_err(JournalError::SerializationError)?;
+        self.write_lsn(checkpoint.doc_id, checkpoint.lsn, &mut &frame[..])?;
+        Ok(true)
+    }
+
+    /// a merkle tree over the document's visible pages, see resync
+    pub fn page_tree(&self) -> Result<PageTree> {
+        let pages = self.storage.snapshot().map_err(JournalError::from)?;
+        Ok(PageTree::build(&pages))
+    }
+
+    /// start comparing our storage with the coordinator's, returning the
+    /// message to send it. the replies are handled by the replication
+    /// protocol, which repairs storage once it knows which pages differ
+    pub fn start_resync(&mut self) -> Result<ReplicationMsg> {
+        let resync = Resync::new(self.page_tree()?);
+        let msg =
+            ReplicationMsg::TreeRequest { nodes: resync.request().to_vec() };
+        self.resync = Some((self.storage.visible_range().last(), resync));
+        Ok(msg)
+    }
+
+    /// repair diverged storage with the pages in leaves returned by
+    /// CoordinatorDocument::repair_pages. the leaves' pages are replaced by
+    /// the coordinator's, dropping any we have which it doesn't. storage is
+    /// replaced by a single frame at the repair's lsn, and then local
+    /// mutations are rebased on top
+    pub fn repair(
+        &mut self,
+        leaves: &[TreeNode],
+        repair: Checkpoint,
+    ) -> Result<()> {
+        if repair.doc_id != self.doc_id() {
+            return Err(ReplicationError::UnknownJournal(repair.doc_id).into());
+        }
+        let mut pages = self.storage.snapshot().map_err(JournalError::from)?;
+        let repaired = repair.snapshot.into_pages();
+        let mut changed: Vec<PageIdx> = vec![];
+        for leaf in leaves {
+            for page_idx in leaf.pages() {
+                let ours = pages.remove(page_idx);
+                let theirs = repaired.get(page_idx);
+                if let Some(page) = theirs {
+                    pages.write(page_idx, *page);
+                }
+                if ours.as_ref() != theirs {
+                    changed.push(page_idx);
+                }
+            }
+        }
+        self.storage.replace(repair.lsn, pages, changed)?;
+
+        self.rebase_local()?;
+        self.signal_storage_change();
+        Ok(())
     }
 }
 
@@ -152,13 +1022,16 @@ impl<J: ReplicationSource, S> ReplicationSource for LocalDocument<J, S> {
         &'a self,
         lsn: crate::Lsn,
     ) -> io::Result<Option<Self::Reader<'a>>> {
+        self.sent_until.fetch_max(lsn + 1, Ordering::Relaxed);
         self.timeline.read_lsn(lsn)
     }
 }
 
 /// LocalDocument knows how to receive a storage journal from elsewhere
-impl<J: ReplicationDestination, S: Signal> ReplicationDestination
-    for LocalDocument<J, S>
+impl<J, S> ReplicationDestination for LocalDocument<J, S>
+where
+    J: Journal + ReplicationSource + ReplicationDestination,
+    S: Signal,
 {
     fn range(
         &mut self,
@@ -180,4 +1053,427 @@ impl<J: ReplicationDestination, S: Signal> ReplicationDestination
         self.rebase_available.emit();
         out
     }
+
+    fn observe_timestamp(&mut self, timestamp: HlcTimestamp) {
+        let mut clock = self.clock.lock().unwrap_or_else(|e| e.into_inner());
+        clock.observe(timestamp);
+    }
+
+    fn observe_config(&mut self, config: DocumentConfig) {
+        if config.version > self.config.version {
+            self.config = config;
+        }
+    }
+
+    fn observe_reducer(&mut self, digest: ModuleDigest, version: Option<u32>) {
+        self.update_required = match self.reducer_digest {
+            Some(ours) if ours != digest => {
+                // unless both reducers declare a version there's no telling
+                // which is newer, so the coordinator's is assumed to be
+                match (self.reducer.version(), version) {
+                    (Some(ours), Some(theirs)) if ours >= theirs => None,
+                    _ => Some(digest),
+                }
+            }
+            _ => None,
+        };
+    }
+
+    fn receive_reducer(&mut self, digest: ModuleDigest, wasm: Vec<u8>) {
+        if ReducerPool::digest(&wasm) != digest {
+            logging::warn!(
+                doc = self.storage.id();
+                "discarding reducer wasm which fails its digest check"
+            );
+            return;
+        }
+        self.received_reducer = Some((digest, wasm));
+    }
+
+    /// the coordinator has compacted away the storage frames we're missing,
+    /// so storage starts over from its folded frame. local mutations are
+    /// kept and rebased once storage arrives
+    fn reset(
+        &mut self,
+        id: JournalId,
+    ) -> std::result::Result<(), ReplicationError> {
+        if id != self.storage.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        logging::info!(
+            doc = id;
+            "clearing storage to resync from a compacted coordinator"
+        );
+        self.storage.clear()
+    }
+
+    fn receive_tree_hashes(
+        &mut self,
+        lsn: Option<Lsn>,
+        hashes: Vec<TreeHash>,
+    ) -> std::result::Result<Option<ReplicationMsg>, ReplicationError> {
+        let Some((ours, resync)) = &mut self.resync else {
+            return Ok(None);
+        };
+        let moved = self.storage.visible_range().last() != *ours;
+        if moved || *ours != lsn || hashes.len() != resync.request().len() {
+            logging::info!(
+                "abandoning resync at lsn {:?}, the coordinator is at {:?}",
+                ours,
+                lsn
+            );
+            self.resync = None;
+            return Ok(None);
+        }
+        resync.receive(&hashes);
+        if !resync.is_done() {
+            let nodes = resync.request().to_vec();
+            return Ok(Some(ReplicationMsg::TreeRequest { nodes }));
+        }
+        let leaves = resync.differing().to_vec();
+        if leaves.is_empty() {
+            self.resync = None;
+            return Ok(None);
+        }
+        Ok(Some(ReplicationMsg::RepairRequest { leaves }))
+    }
+
+    fn receive_repair(
+        &mut self,
+        leaves: Vec<TreeNode>,
+        repair: Checkpoint,
+    ) -> std::result::Result<(), ReplicationError> {
+        let Some((lsn, _)) = self.resync.take() else {
+            return Ok(());
+        };
+        // the repair is only as of our tree if neither side has moved on
+        if lsn != Some(repair.lsn) || self.storage.last_committed_lsn() != lsn {
+            logging::info!(
+                "abandoning resync at lsn {:?}, the repair is at {}",
+                lsn,
+                repair.lsn
+            );
+            return Ok(());
+        }
+        self.repair(&leaves, repair).map_err(|e| {
+            ReplicationError::Io(io::Error::new(io::ErrorKind::Other, e))
+        })
+    }
+
+    fn receive_history(
+        &mut self,
+        lsn: Lsn,
+        checkpoint: Option<Checkpoint>,
+    ) -> std::result::Result<(), ReplicationError> {
+        match checkpoint {
+            Some(checkpoint) if checkpoint.doc_id != self.doc_id() => {
+                Err(ReplicationError::UnknownJournal(checkpoint.doc_id))
+            }
+            Some(checkpoint) => {
+                self.history = Some(checkpoint);
+                Ok(())
+            }
+            None => {
+                logging::info!(
+                    doc = self.doc_id();
+                    "the coordinator doesn't retain storage lsn {}",
+                    lsn
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// a document from an older epoch still holds redacted history, so its
+    /// storage is cleared and then replicated again from scratch. local
+    /// mutations are kept and rebased once storage arrives
+    fn observe_epoch(
+        &mut self,
+        epoch: u64,
+    ) -> std::result::Result<(), ReplicationError> {
+        let ours = read_epoch(&self.sqlite.readonly).map_err(|e| {
+            ReplicationError::Io(io::Error::new(io::ErrorKind::Other, e))
+        })?;
+        if ours < epoch && self.storage.has_committed_pages() {
+            logging::info!(
+                doc = self.storage.id();
+                "clearing storage from epoch {} to resync at epoch {}",
+                ours,
+                epoch
+            );
+            self.storage.clear()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalDocument, NoopSignal, SyncLag};
+    use crate::{
+        error::Error,
+        reducer::{
+            tests::{exec_guest, versioned_guest},
+            Reducer, ReducerPool,
+        },
+        replication::{
+            HeartbeatConfig, ReplicationDestination, ReplicationSource,
+            REDUCER_CHUNK_BYTES,
+        },
+        session::{encode_reducer, Session},
+        timeline::TimelineError,
+        Journal, JournalId, MemoryJournal,
+    };
+
+    fn open(
+        storage: MemoryJournal,
+        timeline: MemoryJournal,
+    ) -> LocalDocument<MemoryJournal, NoopSignal> {
+        let wasm = exec_guest("", "CREATE TABLE IF NOT EXISTS t (x)", 1);
+        LocalDocument::open(
+            storage,
+            timeline,
+            Reducer::new(&wasm[..]).unwrap(),
+            NoopSignal,
+            NoopSignal,
+            NoopSignal,
+        )
+        .unwrap()
+    }
+
+    fn tables(doc: &LocalDocument<MemoryJournal, NoopSignal>) -> i64 {
+        doc.query(|conn| {
+            conn.query_row(
+                "SELECT count(*) FROM sqlite_master WHERE name = 't'",
+                [],
+                |row| row.get(0),
+            )
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn pending_mutations_are_replayed_on_open() {
+        let mut rng = rand::thread_rng();
+        let doc_id = JournalId::new128(&mut rng);
+        let timeline_id = JournalId::new128(&mut rng);
+        let mut doc = open(
+            MemoryJournal::open(doc_id).unwrap(),
+            MemoryJournal::open(timeline_id).unwrap(),
+        );
+        doc.mutate(b"m").unwrap();
+        assert_eq!(tables(&doc), 1);
+
+        // copy the timeline, as a FileJournal would store it across restarts
+        let mut timeline = MemoryJournal::open(timeline_id).unwrap();
+        for lsn in doc.timeline.source_range().iter() {
+            let mut frame = doc.timeline.read_lsn(lsn).unwrap().unwrap();
+            timeline.write_lsn(timeline_id, lsn, &mut frame).unwrap();
+        }
+        assert_eq!(timeline.range(), doc.timeline.range());
+
+        let doc = open(MemoryJournal::open(doc_id).unwrap(), timeline);
+        assert_eq!(tables(&doc), 1);
+    }
+
+    #[test]
+    fn drafts_are_persisted_and_restored() {
+        let mut rng = rand::thread_rng();
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        let log_id = JournalId::new128(&mut rng);
+        doc.persist_drafts(MemoryJournal::open(log_id).unwrap())
+            .unwrap();
+        let discarded = doc.mutate_draft(b"d").unwrap();
+        assert!(doc.has_drafts());
+        assert_eq!(tables(&doc), 1);
+
+        // mutations apply on top of the drafts, which stay
+        doc.mutate(b"m").unwrap();
+        let kept = doc.mutate_draft(b"k").unwrap();
+        assert_eq!(doc.drafts(), vec![discarded, kept]);
+        doc.discard_draft(discarded).unwrap();
+        assert!(matches!(
+            doc.discard_draft(discarded),
+            Err(Error::UnknownDraft(id)) if id == discarded
+        ));
+
+        // copy the log, as a FileJournal would store it across restarts
+        let log = doc.drafts_log.as_ref().unwrap();
+        assert_eq!(log.range().len(), 1);
+        let mut copy = MemoryJournal::open(log_id).unwrap();
+        for lsn in log.range().iter() {
+            let mut frame = log.read_lsn(lsn).unwrap().unwrap();
+            copy.write_lsn(log_id, lsn, &mut frame).unwrap();
+        }
+
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        assert_eq!(tables(&doc), 0);
+        doc.persist_drafts(copy).unwrap();
+        assert_eq!(doc.drafts().len(), 1);
+        assert_eq!(tables(&doc), 1);
+
+        doc.discard_draft(doc.drafts()[0]).unwrap();
+        assert!(!doc.has_drafts());
+        assert_eq!(tables(&doc), 0);
+        assert!(doc.drafts_log.as_ref().unwrap().range().is_empty());
+    }
+
+    #[test]
+    fn data_version_moves_with_the_document() {
+        let mut rng = rand::thread_rng();
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        let mut version = doc.data_version();
+        let mut moved = |doc: &LocalDocument<_, _>| {
+            let moved = doc.data_version() != version;
+            version = doc.data_version();
+            moved
+        };
+
+        // reads leave it be
+        assert_eq!(tables(&doc), 0);
+        assert!(!moved(&doc));
+
+        doc.mutate(b"m").unwrap();
+        assert!(moved(&doc));
+        let draft = doc.mutate_draft(b"d").unwrap();
+        assert!(moved(&doc));
+        doc.discard_draft(draft).unwrap();
+        assert!(moved(&doc));
+        doc.mutate_local(|txn| {
+            txn.execute("CREATE TABLE local.ui (x)", [])?;
+            Ok::<_, rusqlite::Error>(())
+        })
+        .unwrap();
+        assert!(moved(&doc));
+        assert_eq!(tables(&doc), 1);
+        assert!(!moved(&doc));
+    }
+
+    #[test]
+    fn cancelled_mutations_roll_back_and_fail_their_tokens() {
+        let mut rng = rand::thread_rng();
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        let cancelled = doc.mutate(b"m").unwrap();
+        assert_eq!(tables(&doc), 1);
+        assert!(doc.cancel_pending(cancelled.lsn).unwrap());
+        assert_eq!(tables(&doc), 0);
+        assert!(!doc.cancel_pending(cancelled.lsn).unwrap());
+        assert!(matches!(
+            doc.observes(cancelled),
+            Err(Error::TimelineError(TimelineError::MutationCancelled(token)))
+                if token == cancelled
+        ));
+
+        // the cancelled frame keeps its lsn, so later tokens are unaffected
+        let token = doc.mutate(b"m").unwrap();
+        assert_eq!(token.lsn, cancelled.lsn + 1);
+        assert_eq!(tables(&doc), 1);
+        assert!(doc.observes(token).unwrap());
+    }
+
+    #[test]
+    fn sync_lag_counts_pending_mutations_and_unapplied_frames() {
+        let mut rng = rand::thread_rng();
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        assert_eq!(doc.sync_lag(), SyncLag::default());
+        doc.mutate(b"m").unwrap();
+        doc.mutate(b"m").unwrap();
+        assert_eq!(doc.sync_lag().pending_mutations, 2);
+        assert_eq!(doc.sync_lag().frames_behind(), 0);
+
+        let lag = SyncLag {
+            received_lsn: Some(4),
+            applied_lsn: Some(1),
+            pending_mutations: 0,
+        };
+        assert_eq!(lag.frames_behind(), 3);
+        assert_eq!(SyncLag { applied_lsn: None, ..lag }.frames_behind(), 5);
+    }
+
+    #[test]
+    fn only_newer_coordinator_reducers_make_documents_read_only() {
+        let mut rng = rand::thread_rng();
+        let wasm = versioned_guest("CREATE TABLE IF NOT EXISTS t (x)", 2);
+        let mut doc = LocalDocument::open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            Reducer::new(&wasm[..]).unwrap(),
+            NoopSignal,
+            NoopSignal,
+            NoopSignal,
+        )
+        .unwrap();
+        let (ours, theirs) = ([1; 32], [2; 32]);
+        doc.set_reducer_digest(ours);
+
+        doc.observe_reducer(ours, Some(2));
+        assert_eq!(doc.update_required(), None);
+
+        // a coordinator which is behind, e.g. mid deploy, is ignored
+        doc.observe_reducer(theirs, Some(1));
+        assert_eq!(doc.update_required(), None);
+        doc.observe_reducer(theirs, Some(2));
+        assert_eq!(doc.update_required(), None);
+        doc.mutate(b"m").unwrap();
+
+        doc.observe_reducer(theirs, Some(3));
+        assert_eq!(doc.update_required(), Some(theirs));
+        assert!(matches!(
+            doc.mutate(b"m"),
+            Err(Error::UpdateRequired { digest }) if digest == theirs
+        ));
+
+        // without a version there's no telling which is newer
+        doc.observe_reducer(ours, Some(2));
+        doc.observe_reducer(theirs, None);
+        assert_eq!(doc.update_required(), Some(theirs));
+    }
+
+    #[test]
+    fn received_reducers_are_reassembled_and_digest_checked() {
+        let mut rng = rand::thread_rng();
+        let mut doc = open(
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rng)).unwrap(),
+        );
+        let mut session = Session::new(HeartbeatConfig::default(), 0);
+        let wasm: Vec<u8> =
+            (0..REDUCER_CHUNK_BYTES * 5 / 2).map(|i| i as u8).collect();
+        let digest = ReducerPool::digest(&wasm);
+        let msgs = encode_reducer(digest, &wasm).unwrap();
+        assert_eq!(msgs.len(), 3);
+
+        // a missing chunk drops the reducer
+        for msg in [&msgs[0], &msgs[2]] {
+            session.receive(&mut doc, msg, 0).unwrap();
+        }
+        assert_eq!(doc.take_received_reducer(), None);
+
+        for msg in &msgs {
+            assert_eq!(doc.take_received_reducer(), None);
+            session.receive(&mut doc, msg, 0).unwrap();
+        }
+        assert_eq!(doc.take_received_reducer(), Some((digest, wasm.clone())));
+
+        // as does wasm which doesn't match the digest it was sent with
+        for msg in encode_reducer([0; 32], &wasm).unwrap() {
+            session.receive(&mut doc, &msg, 0).unwrap();
+        }
+        assert_eq!(doc.take_received_reducer(), None);
+    }
 }
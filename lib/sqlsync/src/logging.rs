@@ -0,0 +1,326 @@
+//! SQLSync routes its own logs, along with logs emitted by reducer guests,
+//! through a pluggable [`LogSink`]. Embedders such as the browser worker can
+//! install a sink to decide where logs end up, while the default sink simply
+//! forwards everything to the `log` crate.
+//!
+//! Reducer logs can be filtered and rate limited per document with a
+//! [`GuestLogConfig`], so that one hot reducer can't flood a coordinator
+//! hosting thousands of documents.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+};
+
+pub use log::{Level, LevelFilter};
+use sqlsync_reducer::types::LogRecord as GuestLogRecord;
+
+use crate::{unixtime::unix_timestamp_milliseconds, JournalId, Lsn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSource {
+    /// emitted by sqlsync itself
+    Host,
+    /// emitted by a reducer guest via the host_log ffi
+    Reducer,
+}
+
+/// MutationId identifies a mutation by the timeline it was submitted to and
+/// its lsn within that timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MutationId {
+    pub timeline_id: JournalId,
+    pub lsn: Lsn,
+}
+
+impl fmt::Display for MutationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.timeline_id, self.lsn)
+    }
+}
+
+pub struct LogRecord<'a> {
+    pub level: Level,
+    pub source: LogSource,
+    /// the document this record is associated with, if known
+    pub doc_id: Option<JournalId>,
+    /// the mutation being applied when this record was emitted, if any
+    pub mutation: Option<MutationId>,
+    pub target: &'a str,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub args: fmt::Arguments<'a>,
+}
+
+pub trait LogSink: Send + Sync {
+    fn enabled(&self, level: Level, source: LogSource) -> bool;
+    fn log(&self, record: &LogRecord<'_>);
+}
+
+/// LogCrateSink is the default sink, it forwards records to the `log` crate
+pub struct LogCrateSink;
+
+impl LogSink for LogCrateSink {
+    fn enabled(&self, level: Level, _source: LogSource) -> bool {
+        level <= log::max_level()
+    }
+
+    fn log(&self, record: &LogRecord<'_>) {
+        let emit = |args: fmt::Arguments<'_>| {
+            log::logger().log(
+                &log::Record::builder()
+                    .level(record.level)
+                    .target(record.target)
+                    .module_path(Some(record.target))
+                    .file(record.file)
+                    .line(record.line)
+                    .args(args)
+                    .build(),
+            )
+        };
+
+        match (record.doc_id, record.mutation) {
+            (Some(doc_id), Some(mutation)) => emit(format_args!(
+                "[{}] [{}] {}",
+                doc_id, mutation, record.args
+            )),
+            (Some(doc_id), None) => {
+                emit(format_args!("[{}] {}", doc_id, record.args))
+            }
+            (None, Some(mutation)) => {
+                emit(format_args!("[{}] {}", mutation, record.args))
+            }
+            (None, None) => emit(record.args),
+        }
+    }
+}
+
+static LOG_SINK: RwLock<Option<Box<dyn LogSink>>> = RwLock::new(None);
+
+/// replace the global log sink used by sqlsync
+pub fn set_log_sink(sink: impl LogSink + 'static) {
+    let mut guard = LOG_SINK.write().unwrap_or_else(|e| e.into_inner());
+    *guard = Some(Box::new(sink));
+}
+
+pub(crate) fn dispatch(record: &LogRecord<'_>) {
+    let guard = LOG_SINK.read().unwrap_or_else(|e| e.into_inner());
+    let sink: &dyn LogSink = guard.as_deref().unwrap_or(&LogCrateSink);
+    if sink.enabled(record.level, record.source) {
+        sink.log(record);
+    }
+}
+
+/// GuestLogConfig controls which of a document's reducer logs are forwarded
+/// to the log sink. guest errors are still attached to traps when filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestLogConfig {
+    /// records above this level are dropped
+    pub level: LevelFilter,
+    /// forward at most this many records per second; the rest are dropped,
+    /// and counted in a warning once the second is over
+    pub max_per_second: Option<u32>,
+}
+
+impl Default for GuestLogConfig {
+    fn default() -> Self {
+        Self { level: LevelFilter::Trace, max_per_second: None }
+    }
+}
+
+/// RateLimiter admits up to a limit of records per one second window
+#[derive(Debug, Default)]
+struct RateLimiter {
+    window_start_ms: i64,
+    admitted: u32,
+    suppressed: u64,
+}
+
+impl RateLimiter {
+    /// returns whether to admit a record at now_ms, along with the number of
+    /// records suppressed during the previous window if it just ended
+    fn admit(
+        &mut self,
+        max_per_second: Option<u32>,
+        now_ms: i64,
+    ) -> (bool, u64) {
+        let Some(max) = max_per_second else {
+            return (true, std::mem::take(&mut self.suppressed));
+        };
+        let mut ended = 0;
+        if now_ms - self.window_start_ms >= 1000 {
+            ended = std::mem::take(&mut self.suppressed);
+            self.window_start_ms = now_ms;
+            self.admitted = 0;
+        }
+        if self.admitted < max {
+            self.admitted += 1;
+            (true, ended)
+        } else {
+            self.suppressed += 1;
+            (false, ended)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct GuestLogState {
+    doc_id: Option<JournalId>,
+    mutation: Option<MutationId>,
+    config: GuestLogConfig,
+    limiter: RateLimiter,
+}
+
+/// LogContext is a shared cell holding what reducer log records should be
+/// attributed to, and how they are filtered. It is shared with the reducer's
+/// host_log handler which has no other way to learn which document it is
+/// running in.
+#[derive(Clone, Default)]
+pub(crate) struct LogContext(Arc<Mutex<GuestLogState>>);
+
+impl LogContext {
+    fn state(&self) -> std::sync::MutexGuard<'_, GuestLogState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_doc_id(&self, doc_id: JournalId) {
+        self.state().doc_id = Some(doc_id);
+    }
+
+    pub fn doc_id(&self) -> Option<JournalId> {
+        self.state().doc_id
+    }
+
+    pub fn set_mutation(&self, mutation: Option<MutationId>) {
+        self.state().mutation = mutation;
+    }
+
+    pub fn set_config(&self, config: GuestLogConfig) {
+        self.state().config = config;
+    }
+
+    /// back to the defaults, as if the reducer had just been created
+    pub fn reset(&self) {
+        *self.state() = GuestLogState::default();
+    }
+
+    pub fn log_guest(&self, record: &GuestLogRecord) {
+        let mut state = self.state();
+        if record.level() > state.config.level {
+            return;
+        }
+        let (admit, suppressed) = state
+            .limiter
+            .admit(state.config.max_per_second, unix_timestamp_milliseconds());
+        let (doc_id, mutation) = (state.doc_id, state.mutation);
+        // the sink may log too, so don't hold the lock while it runs
+        drop(state);
+
+        if suppressed > 0 {
+            dispatch(&LogRecord {
+                level: Level::Warn,
+                source: LogSource::Host,
+                doc_id,
+                mutation: None,
+                target: module_path!(),
+                file: Some(file!()),
+                line: Some(line!()),
+                args: format_args!(
+                    "dropped {} reducer log records over the rate limit",
+                    suppressed
+                ),
+            });
+        }
+        if admit {
+            dispatch(&LogRecord {
+                level: record.level(),
+                source: LogSource::Reducer,
+                doc_id,
+                mutation,
+                target: "wasm guest",
+                file: record.file(),
+                line: record.line(),
+                args: format_args!("{}", record.message()),
+            })
+        }
+    }
+}
+
+macro_rules! log_at {
+    ($level:expr, doc = $doc:expr; $($arg:tt)+) => {
+        $crate::logging::dispatch(&$crate::logging::LogRecord {
+            level: $level,
+            source: $crate::logging::LogSource::Host,
+            doc_id: ::core::convert::Into::<
+                ::core::option::Option<$crate::JournalId>,
+            >::into($doc),
+            mutation: None,
+            target: module_path!(),
+            file: Some(file!()),
+            line: Some(line!()),
+            args: format_args!($($arg)+),
+        })
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::dispatch(&$crate::logging::LogRecord {
+            level: $level,
+            source: $crate::logging::LogSource::Host,
+            doc_id: None,
+            mutation: None,
+            target: module_path!(),
+            file: Some(file!()),
+            line: Some(line!()),
+            args: format_args!($($arg)+),
+        })
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::logging::log_at!($crate::logging::Level::Error, $($arg)+)
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::logging::log_at!($crate::logging::Level::Warn, $($arg)+)
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logging::log_at!($crate::logging::Level::Info, $($arg)+)
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logging::log_at!($crate::logging::Level::Debug, $($arg)+)
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::logging::log_at!($crate::logging::Level::Trace, $($arg)+)
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use {debug, error, info, log_at, trace, warn};
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+
+    #[test]
+    fn rate_limits_records() {
+        let mut limiter = RateLimiter::default();
+        assert_eq!(limiter.admit(Some(2), 1000), (true, 0));
+        assert_eq!(limiter.admit(Some(2), 1001), (true, 0));
+        assert_eq!(limiter.admit(Some(2), 1002), (false, 0));
+        assert_eq!(limiter.admit(Some(2), 1999), (false, 0));
+        // the next window reports what the last one dropped
+        assert_eq!(limiter.admit(Some(2), 2000), (true, 2));
+        assert_eq!(limiter.admit(None, 2001), (true, 0));
+    }
+}
@@ -32,6 +32,14 @@ impl LsnRange {
         LsnRange::Empty { nextlsn: 0 }
     }
 
+    /// the range of len lsns starting at first
+    pub fn starting_at(first: Lsn, len: usize) -> Self {
+        match len {
+            0 => LsnRange::Empty { nextlsn: first },
+            len => LsnRange::new(first, first + len as Lsn - 1),
+        }
+    }
+
     pub fn empty_following(range: &LsnRange) -> Self {
         LsnRange::Empty {
             nextlsn: range.next(),
@@ -66,6 +74,13 @@ impl LsnRange {
         }
     }
 
+    pub fn first(&self) -> Option<Lsn> {
+        match self {
+            LsnRange::Empty { .. } => None,
+            LsnRange::NonEmpty { first, .. } => Some(*first),
+        }
+    }
+
     pub fn last(&self) -> Option<Lsn> {
         match self {
             LsnRange::Empty { .. } => None,
@@ -173,6 +188,22 @@ impl LsnRange {
         }
     }
 
+    // returns a new LsnRange with all lsns >= from removed
+    pub fn trim_suffix(&self, from: Lsn) -> LsnRange {
+        match self {
+            LsnRange::Empty { .. } => *self,
+            LsnRange::NonEmpty { first, last } => {
+                if from > *last {
+                    *self
+                } else if from <= *first {
+                    LsnRange::Empty { nextlsn: *first }
+                } else {
+                    LsnRange::new(*first, from - 1)
+                }
+            }
+        }
+    }
+
     /// advance_first increments first
     /// returns self if already empty
     fn advance_first(&self) -> LsnRange {
@@ -294,6 +325,80 @@ impl LsnRange {
         }
     }
 
+    /// subtract removes other from self, returning the parts of self which
+    /// come before and after other. unlike difference, this handles other
+    /// being entirely within self. an empty part's nextlsn is where that part
+    /// would have started
+    pub fn subtract(&self, other: &Self) -> (Self, Self) {
+        match (self, other) {
+            (LsnRange::Empty { .. }, _) => (*self, *self),
+            (&LsnRange::NonEmpty { first, last }, _) if !self.intersects(other) => {
+                match other {
+                    LsnRange::NonEmpty { first: ofirst, .. } if *ofirst <= first => {
+                        (LsnRange::Empty { nextlsn: first }, *self)
+                    }
+                    _ => (*self, LsnRange::Empty { nextlsn: last + 1 }),
+                }
+            }
+            (
+                &LsnRange::NonEmpty { first, last },
+                &LsnRange::NonEmpty {
+                    first: ofirst,
+                    last: olast,
+                },
+            ) => {
+                let before = if ofirst > first {
+                    LsnRange::new(first, ofirst - 1)
+                } else {
+                    LsnRange::Empty { nextlsn: first }
+                };
+                let after = if olast < last {
+                    LsnRange::new(olast + 1, last)
+                } else {
+                    LsnRange::Empty { nextlsn: olast + 1 }
+                };
+                (before, after)
+            }
+            (_, LsnRange::Empty { .. }) => unreachable!("empty ranges never intersect"),
+        }
+    }
+
+    /// returns true if every lsn in other is also in self
+    /// an empty range is contained by every range
+    pub fn contains_range(&self, other: &Self) -> bool {
+        match (self, other) {
+            (_, LsnRange::Empty { .. }) => true,
+            (LsnRange::Empty { .. }, _) => false,
+            (
+                LsnRange::NonEmpty { first, last },
+                LsnRange::NonEmpty {
+                    first: ofirst,
+                    last: olast,
+                },
+            ) => first <= ofirst && olast <= last,
+        }
+    }
+
+    /// gaps_between returns the lsns strictly between self and other, which
+    /// may be given in either order. the result is empty if the ranges
+    /// overlap or are adjacent, in which case its nextlsn is where the gap
+    /// would start
+    pub fn gaps_between(&self, other: &Self) -> Self {
+        let key = |r: &LsnRange| (LsnRange::empty_preceeding(r).next(), r.next());
+        let (lower, upper) = if key(other) < key(self) {
+            (other, self)
+        } else {
+            (self, other)
+        };
+        let start = lower.next();
+        let end = LsnRange::empty_preceeding(upper).next();
+        if start < end {
+            LsnRange::new(start, end - 1)
+        } else {
+            LsnRange::Empty { nextlsn: start }
+        }
+    }
+
     pub fn iter(&self) -> LsnIter {
         LsnIter { range: *self }
     }
@@ -347,7 +452,9 @@ impl DoubleEndedIterator for LsnIter {
 // write some tests for LsnRange
 #[cfg(test)]
 mod tests {
-    use super::LsnRange;
+    use std::collections::BTreeSet;
+
+    use super::{Lsn, LsnRange};
 
     #[test]
     #[should_panic(expected = "first must be <= last")]
@@ -477,6 +584,21 @@ mod tests {
         assert_eq!(range.trim_prefix(20), LsnRange::Empty { nextlsn: 21 });
     }
 
+    #[test]
+    fn lsnrange_trim_suffix() {
+        let range = LsnRange::new(5, 10);
+
+        assert_eq!(range.trim_suffix(11), range);
+        assert_eq!(range.trim_suffix(10), LsnRange::new(5, 9));
+        assert_eq!(range.trim_suffix(6), LsnRange::new(5, 5));
+        assert_eq!(range.trim_suffix(5), LsnRange::Empty { nextlsn: 5 });
+        assert_eq!(range.trim_suffix(0), LsnRange::Empty { nextlsn: 5 });
+        assert_eq!(
+            LsnRange::Empty { nextlsn: 5 }.trim_suffix(0),
+            LsnRange::Empty { nextlsn: 5 }
+        );
+    }
+
     #[test]
     #[should_panic(expected = "len must be > 0")]
     fn lsnrange_extend_invariant() {
@@ -574,6 +696,81 @@ mod tests {
         let _ = LsnRange::new(5, 10).difference(&LsnRange::new(6, 9));
     }
 
+    #[test]
+    fn lsnrange_subtract() {
+        assert_eq!(
+            LsnRange::new(5, 10).subtract(&LsnRange::new(6, 9)),
+            (LsnRange::new(5, 5), LsnRange::new(10, 10))
+        );
+        assert_eq!(
+            LsnRange::new(5, 10).subtract(&LsnRange::new(0, 20)),
+            (LsnRange::Empty { nextlsn: 5 }, LsnRange::Empty { nextlsn: 21 })
+        );
+        assert_eq!(
+            LsnRange::new(5, 10).subtract(&LsnRange::new(0, 4)),
+            (LsnRange::Empty { nextlsn: 5 }, LsnRange::new(5, 10))
+        );
+        assert_eq!(
+            LsnRange::new(5, 10).subtract(&LsnRange::empty()),
+            (LsnRange::new(5, 10), LsnRange::Empty { nextlsn: 11 })
+        );
+    }
+
+    #[test]
+    fn lsnrange_gaps_between() {
+        let range = LsnRange::new(5, 10);
+        assert_eq!(range.gaps_between(&LsnRange::new(13, 20)), LsnRange::new(11, 12));
+        assert_eq!(LsnRange::new(13, 20).gaps_between(&range), LsnRange::new(11, 12));
+        assert_eq!(range.gaps_between(&LsnRange::new(11, 20)), LsnRange::Empty { nextlsn: 11 });
+        assert_eq!(range.gaps_between(&LsnRange::new(8, 20)), LsnRange::Empty { nextlsn: 11 });
+        assert_eq!(range.gaps_between(&LsnRange::Empty { nextlsn: 15 }), LsnRange::new(11, 14));
+    }
+
+    /// every range with lsns below 8, including empty ranges at each nextlsn
+    fn small_ranges() -> Vec<LsnRange> {
+        let mut out = vec![];
+        for first in 0..8 {
+            out.push(LsnRange::Empty { nextlsn: first });
+            for last in first..8 {
+                out.push(LsnRange::new(first, last));
+            }
+        }
+        out
+    }
+
+    fn lsns(range: &LsnRange) -> BTreeSet<Lsn> {
+        range.iter().collect()
+    }
+
+    #[test]
+    fn lsnrange_algebra_matches_sets() {
+        for a in small_ranges() {
+            for b in small_ranges() {
+                let (sa, sb) = (lsns(&a), lsns(&b));
+
+                let intersection: BTreeSet<_> = sa.intersection(&sb).copied().collect();
+                assert_eq!(lsns(&a.intersect(&b)), intersection, "{:?} & {:?}", a, b);
+                assert_eq!(a.intersects(&b), !intersection.is_empty(), "{:?} & {:?}", a, b);
+
+                let (before, after) = a.subtract(&b);
+                let mut difference = lsns(&before);
+                difference.extend(lsns(&after));
+                assert_eq!(difference, &sa - &sb, "{:?} - {:?}", a, b);
+
+                assert_eq!(a.contains_range(&b), sb.is_subset(&sa), "{:?} >= {:?}", a, b);
+
+                let gap = a.gaps_between(&b);
+                assert_eq!(gap, b.gaps_between(&a), "gaps {:?}, {:?}", a, b);
+                if let (Some(&amin), Some(&bmin)) = (sa.first(), sb.first()) {
+                    let (lower, upper) = if amin <= bmin { (&sa, &sb) } else { (&sb, &sa) };
+                    let (lower_last, upper_first) = (*lower.last().unwrap(), *upper.first().unwrap());
+                    let between: BTreeSet<_> = (lower_last + 1..upper_first).collect();
+                    assert_eq!(lsns(&gap), between, "gaps {:?}, {:?}", a, b);
+                }
+            }
+        }
+    }
+
     #[test]
     fn lsnrange_advance_first() {
         assert_eq!(
@@ -0,0 +1,544 @@
+//! Multiplexing replicates many documents over one connection, for apps
+//! which keep a workspace of documents open. Each document is a stream on
+//! the connection: the client opens it with the document id and reducer
+//! digest it would otherwise put in the document's url, and the stream then
+//! carries the same messages a Session would send over its own connection.
+//!
+//! Streams are flow controlled separately. A side may only have a window of
+//! data in flight on each stream, and the other side grants credit back as
+//! the host consumes the data (see Multiplexer::consumed), so a host which
+//! falls behind stops the other side sending rather than buffering without
+//! bound. Data beyond the window, or opening more than the stream limit, is
+//! an error which should end the connection. Queued data is sent round
+//! robin across the streams with credit. That way a document which is
+//! catching up on a cold start can't hold up the others sharing its
+//! connection.
+//!
+//! Streams also have a priority, which either side may change at any time.
+//! Foreground streams are sent first; while any of them has data queued,
+//! background streams trickle a single message per poll, so that the
+//! document on screen syncs first without starving the rest.
+//!
+//! Multiplexer is io free like Session: the host feeds it the messages it
+//! receives and writes the ones returned by poll.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{JournalId, ModuleDigest};
+
+pub type StreamId = u32;
+
+/// how many bytes of data may be in flight on a stream by default
+pub const DEFAULT_WINDOW_BYTES: u32 = 1024 * 1024;
+
+/// how many streams may be open on a connection by default
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash,
+)]
+pub enum Priority {
+    /// the documents the user is looking at; streams open in the foreground
+    #[default]
+    Foreground,
+    /// documents kept in sync in case the user switches to them
+    Background,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MuxMsg {
+    /// start replicating a document on a new stream
+    Open {
+        stream: StreamId,
+        doc: JournalId,
+        reducer: Option<ModuleDigest>,
+    },
+    /// an encoded replication message for the stream's session
+    Data { stream: StreamId, msg: Vec<u8> },
+    /// allow the other side to send this many more bytes on the stream
+    Credit { stream: StreamId, bytes: u32 },
+    /// the stream ended, by either side closing it or its document's
+    /// connection failing
+    Close { stream: StreamId },
+    /// change the stream's priority on both sides
+    Priority {
+        stream: StreamId,
+        priority: Priority,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum MuxError {
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+
+    #[error("stream {0} is already open")]
+    DuplicateStream(StreamId),
+
+    #[error("unknown stream: {0}")]
+    UnknownStream(StreamId),
+
+    #[error("stream {0} received data beyond its window")]
+    WindowExceeded(StreamId),
+
+    #[error("too many streams, at most {0} may be open")]
+    TooManyStreams(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MuxEvent {
+    /// the other side opened a stream
+    Opened {
+        stream: StreamId,
+        doc: JournalId,
+        reducer: Option<ModuleDigest>,
+    },
+    /// a message for the stream's session
+    Data { stream: StreamId, msg: Vec<u8> },
+    /// the other side closed the stream
+    Closed { stream: StreamId },
+}
+
+#[derive(Debug)]
+struct StreamState {
+    // bytes we may still send. a message is sent while this is positive,
+    // so a message larger than the window doesn't wedge the stream
+    credit: i64,
+    queued: VecDeque<Vec<u8>>,
+    // bytes the other side may still send, our copy of its credit
+    receive_credit: i64,
+    // bytes consumed which haven't been granted back yet
+    ungranted: u32,
+    priority: Priority,
+}
+
+#[derive(Debug)]
+pub struct Multiplexer {
+    window_bytes: u32,
+    max_streams: usize,
+    streams: BTreeMap<StreamId, StreamState>,
+    // messages for the other side which don't need credit
+    control: Vec<MuxMsg>,
+    // the stream poll starts sending from, so that streams take turns
+    next_stream: StreamId,
+}
+
+impl Default for Multiplexer {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW_BYTES)
+    }
+}
+
+impl Multiplexer {
+    /// both sides of a connection must use the same window
+    pub fn new(window_bytes: u32) -> Self {
+        Self {
+            window_bytes,
+            max_streams: DEFAULT_MAX_STREAMS,
+            streams: BTreeMap::new(),
+            control: vec![],
+            next_stream: 0,
+        }
+    }
+
+    /// limit how many streams may be open, counting those opened by either
+    /// side
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        self.max_streams = max_streams;
+    }
+
+    pub fn is_open(&self, stream: StreamId) -> bool {
+        self.streams.contains_key(&stream)
+    }
+
+    pub fn priority(&self, stream: StreamId) -> Option<Priority> {
+        self.streams.get(&stream).map(|state| state.priority)
+    }
+
+    /// change a stream's priority, telling the other side on the next poll
+    pub fn set_priority(
+        &mut self,
+        stream: StreamId,
+        priority: Priority,
+    ) -> Result<(), MuxError> {
+        let state = self
+            .streams
+            .get_mut(&stream)
+            .ok_or(MuxError::UnknownStream(stream))?;
+        if state.priority != priority {
+            state.priority = priority;
+            self.control.push(MuxMsg::Priority { stream, priority });
+        }
+        Ok(())
+    }
+
+    /// the open streams, in order
+    pub fn streams(&self) -> impl Iterator<Item = StreamId> + '_ {
+        self.streams.keys().copied()
+    }
+
+    /// open a stream to replicate doc; the Open is sent on the next poll
+    pub fn open(
+        &mut self,
+        stream: StreamId,
+        doc: JournalId,
+        reducer: Option<ModuleDigest>,
+    ) -> Result<(), MuxError> {
+        self.insert(stream)?;
+        self.control.push(MuxMsg::Open { stream, doc, reducer });
+        Ok(())
+    }
+
+    /// close a stream, dropping anything still queued on it. returns false
+    /// if the stream wasn't open
+    pub fn close(&mut self, stream: StreamId) -> bool {
+        if self.streams.remove(&stream).is_none() {
+            return false;
+        }
+        self.control.push(MuxMsg::Close { stream });
+        true
+    }
+
+    /// queue a replication message on a stream
+    pub fn send(
+        &mut self,
+        stream: StreamId,
+        msg: Vec<u8>,
+    ) -> Result<(), MuxError> {
+        self.streams
+            .get_mut(&stream)
+            .ok_or(MuxError::UnknownStream(stream))?
+            .queued
+            .push_back(msg);
+        Ok(())
+    }
+
+    /// bytes queued on a stream which are waiting for credit or a poll
+    pub fn queued_bytes(&self, stream: StreamId) -> usize {
+        self.streams
+            .get(&stream)
+            .map_or(0, |state| state.queued.iter().map(Vec::len).sum())
+    }
+
+    /// grant credit for bytes of a stream's data once the host has handled
+    /// it, e.g. by passing it to the document. the credit is sent in batches
+    /// of half the window
+    pub fn consumed(&mut self, stream: StreamId, bytes: usize) {
+        let Some(state) = self.streams.get_mut(&stream) else {
+            return;
+        };
+        state.ungranted = state
+            .ungranted
+            .saturating_add(u32::try_from(bytes).unwrap_or(u32::MAX));
+        if state.ungranted >= self.window_bytes / 2 {
+            let bytes = std::mem::take(&mut state.ungranted);
+            state.receive_credit += bytes as i64;
+            self.control.push(MuxMsg::Credit { stream, bytes });
+        }
+    }
+
+    /// handle a message from the other side. data for a stream which has
+    /// been closed here is dropped, as the Close is already on its way.
+    /// fails with WindowExceeded if the other side sends data it has no
+    /// credit for, and TooManyStreams if it opens one stream too many
+    pub fn receive(
+        &mut self,
+        msg: &[u8],
+    ) -> Result<Option<MuxEvent>, MuxError> {
+        match bincode::deserialize(msg)? {
+            MuxMsg::Open { stream, doc, reducer } => {
+                self.insert(stream)?;
+                Ok(Some(MuxEvent::Opened { stream, doc, reducer }))
+            }
+            MuxMsg::Data { stream, msg } => {
+                let Some(state) = self.streams.get_mut(&stream) else {
+                    return Ok(None);
+                };
+                // the sender may overshoot with the message which used up
+                // its credit, just like poll does
+                if state.receive_credit <= 0 {
+                    return Err(MuxError::WindowExceeded(stream));
+                }
+                state.receive_credit -= msg.len() as i64;
+                Ok(Some(MuxEvent::Data { stream, msg }))
+            }
+            MuxMsg::Credit { stream, bytes } => {
+                if let Some(state) = self.streams.get_mut(&stream) {
+                    state.credit += bytes as i64;
+                }
+                Ok(None)
+            }
+            MuxMsg::Close { stream } => {
+                if self.streams.remove(&stream).is_none() {
+                    return Ok(None);
+                }
+                Ok(Some(MuxEvent::Closed { stream }))
+            }
+            MuxMsg::Priority { stream, priority } => {
+                if let Some(state) = self.streams.get_mut(&stream) {
+                    state.priority = priority;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// the encoded messages which can be sent now: control messages, then
+    /// queued data from the foreground streams which have credit, one
+    /// message per stream in turn, and then from the background streams
+    pub fn poll(&mut self) -> Result<Vec<Vec<u8>>, MuxError> {
+        let mut out = self
+            .control
+            .drain(..)
+            .map(|msg| bincode::serialize(&msg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.send_queued(Priority::Foreground, usize::MAX, &mut out)?;
+        // foreground data left over is waiting for credit
+        let backlog = self.streams.values().any(|state| {
+            state.priority == Priority::Foreground && !state.queued.is_empty()
+        });
+        let limit = if backlog { 1 } else { usize::MAX };
+        self.send_queued(Priority::Background, limit, &mut out)?;
+        Ok(out)
+    }
+
+    /// send up to limit queued messages from the streams with priority
+    fn send_queued(
+        &mut self,
+        priority: Priority,
+        mut limit: usize,
+        out: &mut Vec<Vec<u8>>,
+    ) -> Result<(), MuxError> {
+        while limit > 0 {
+            let mut sent = false;
+            let turn: Vec<StreamId> = self
+                .streams
+                .range(self.next_stream..)
+                .chain(self.streams.range(..self.next_stream))
+                .filter(|(_, state)| state.priority == priority)
+                .map(|(&stream, _)| stream)
+                .collect();
+            for stream in turn {
+                if limit == 0 {
+                    break;
+                }
+                let state = self.streams.get_mut(&stream).expect("open");
+                if state.credit <= 0 {
+                    continue;
+                }
+                let Some(msg) = state.queued.pop_front() else {
+                    continue;
+                };
+                state.credit -= msg.len() as i64;
+                out.push(bincode::serialize(&MuxMsg::Data { stream, msg })?);
+                self.next_stream = stream.wrapping_add(1);
+                limit -= 1;
+                sent = true;
+            }
+            if !sent {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, stream: StreamId) -> Result<(), MuxError> {
+        if self.streams.contains_key(&stream) {
+            return Err(MuxError::DuplicateStream(stream));
+        }
+        if self.streams.len() >= self.max_streams {
+            return Err(MuxError::TooManyStreams(self.max_streams));
+        }
+        self.streams.insert(
+            stream,
+            StreamState {
+                credit: self.window_bytes as i64,
+                queued: VecDeque::new(),
+                receive_credit: self.window_bytes as i64,
+                ungranted: 0,
+                priority: Priority::default(),
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Multiplexer, MuxError, MuxEvent, MuxMsg, Priority};
+    use crate::JournalId;
+
+    /// deliver every message a polls to b, returning b's events. b consumes
+    /// the data straight away
+    fn deliver(a: &mut Multiplexer, b: &mut Multiplexer) -> Vec<MuxEvent> {
+        let events: Vec<_> = a
+            .poll()
+            .unwrap()
+            .iter()
+            .filter_map(|msg| b.receive(msg).unwrap())
+            .collect();
+        for event in events.iter() {
+            if let MuxEvent::Data { stream, msg } = event {
+                b.consumed(*stream, msg.len());
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn streams_share_the_connection_fairly() {
+        let doc = JournalId::new128(&mut rand::thread_rng());
+        let mut client = Multiplexer::new(100);
+        let mut coordinator = Multiplexer::new(100);
+        client.open(1, doc, None).unwrap();
+        client.open(2, doc, None).unwrap();
+        assert!(client.open(2, doc, None).is_err());
+        let opened = deliver(&mut client, &mut coordinator);
+        assert_eq!(opened.len(), 2);
+        assert!(coordinator.is_open(1) && coordinator.is_open(2));
+
+        // stream 1 has far more to send than its window, stream 2 a little
+        for _ in 0..10 {
+            coordinator.send(1, vec![1; 40]).unwrap();
+        }
+        coordinator.send(2, vec![2; 40]).unwrap();
+        let events = deliver(&mut coordinator, &mut client);
+        // stream 1 stops at its window, without holding up stream 2
+        let data = |events: &[MuxEvent], id| {
+            events
+                .iter()
+                .filter(|event| match event {
+                    MuxEvent::Data { stream, .. } => *stream == id,
+                    _ => false,
+                })
+                .count()
+        };
+        assert_eq!(data(&events, 1), 3);
+        assert_eq!(data(&events, 2), 1);
+        assert_eq!(coordinator.queued_bytes(1), 7 * 40);
+
+        // the credit granted by the client lets the rest through
+        while coordinator.queued_bytes(1) > 0 {
+            assert!(deliver(&mut client, &mut coordinator).is_empty());
+            assert!(!deliver(&mut coordinator, &mut client).is_empty());
+        }
+
+        assert!(client.close(1));
+        assert!(!client.close(1));
+        assert_eq!(
+            deliver(&mut client, &mut coordinator),
+            vec![MuxEvent::Closed { stream: 1 }]
+        );
+        assert!(!coordinator.is_open(1));
+    }
+
+    #[test]
+    fn background_streams_trickle_behind_the_foreground() {
+        let doc = JournalId::new128(&mut rand::thread_rng());
+        let mut client = Multiplexer::new(100);
+        let mut coordinator = Multiplexer::new(100);
+        client.open(1, doc, None).unwrap();
+        client.open(2, doc, None).unwrap();
+        client.set_priority(2, Priority::Background).unwrap();
+        deliver(&mut client, &mut coordinator);
+        assert_eq!(coordinator.priority(2), Some(Priority::Background));
+
+        for _ in 0..10 {
+            coordinator.send(1, vec![1; 40]).unwrap();
+            coordinator.send(2, vec![2; 40]).unwrap();
+        }
+        // the foreground stream fills its window, the background one only
+        // gets a message through while the foreground waits for credit
+        deliver(&mut coordinator, &mut client);
+        assert_eq!(coordinator.queued_bytes(1), 7 * 40);
+        assert_eq!(coordinator.queued_bytes(2), 9 * 40);
+
+        // once the foreground is done the background stream catches up
+        while coordinator.queued_bytes(1) > 0 {
+            deliver(&mut client, &mut coordinator);
+            deliver(&mut coordinator, &mut client);
+        }
+        while coordinator.queued_bytes(2) > 0 {
+            deliver(&mut client, &mut coordinator);
+            assert!(!deliver(&mut coordinator, &mut client).is_empty());
+        }
+    }
+
+    #[test]
+    fn credit_waits_for_data_to_be_consumed() {
+        let doc = JournalId::new128(&mut rand::thread_rng());
+        let mut client = Multiplexer::new(100);
+        let mut coordinator = Multiplexer::new(100);
+        client.open(1, doc, None).unwrap();
+        deliver(&mut client, &mut coordinator);
+
+        for _ in 0..10 {
+            coordinator.send(1, vec![1; 40]).unwrap();
+        }
+        let received: Vec<_> = coordinator
+            .poll()
+            .unwrap()
+            .iter()
+            .filter_map(|msg| client.receive(msg).unwrap())
+            .collect();
+        assert_eq!(received.len(), 3);
+
+        // nothing is granted while the data sits unconsumed
+        assert!(client.poll().unwrap().is_empty());
+        assert!(deliver(&mut coordinator, &mut client).is_empty());
+
+        client.consumed(1, 40);
+        assert!(client.poll().unwrap().is_empty());
+        client.consumed(1, 40);
+        assert!(deliver(&mut client, &mut coordinator).is_empty());
+        assert_eq!(deliver(&mut coordinator, &mut client).len(), 2);
+    }
+
+    #[test]
+    fn data_beyond_the_window_is_refused() {
+        let doc = JournalId::new128(&mut rand::thread_rng());
+        let mut client = Multiplexer::new(100);
+        client.open(1, doc, None).unwrap();
+        let data = |len| {
+            bincode::serialize(&MuxMsg::Data { stream: 1, msg: vec![0; len] })
+                .unwrap()
+        };
+        // a message may overshoot the window, but nothing may follow it
+        // until credit is granted
+        assert!(client.receive(&data(60)).unwrap().is_some());
+        assert!(client.receive(&data(60)).unwrap().is_some());
+        assert!(matches!(
+            client.receive(&data(1)),
+            Err(MuxError::WindowExceeded(1))
+        ));
+
+        client.consumed(1, 120);
+        assert!(client.receive(&data(1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn streams_are_capped() {
+        let doc = JournalId::new128(&mut rand::thread_rng());
+        let mut client = Multiplexer::new(100);
+        let mut coordinator = Multiplexer::new(100);
+        coordinator.set_max_streams(2);
+        for stream in 0..3 {
+            client.open(stream, doc, None).unwrap();
+        }
+        let opens = client.poll().unwrap();
+        assert!(coordinator.receive(&opens[0]).unwrap().is_some());
+        assert!(coordinator.receive(&opens[1]).unwrap().is_some());
+        assert!(matches!(
+            coordinator.receive(&opens[2]),
+            Err(MuxError::TooManyStreams(2))
+        ));
+
+        // closing one makes room
+        coordinator.close(0);
+        assert!(coordinator.receive(&opens[2]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,547 @@
+//! Apply order. A coordinator receives mutations from many client
+//! timelines and applies them one storage frame at a time; ApplyOrder
+//! decides how mutations from different timelines are interleaved.
+//!
+//! Every order applies each timeline's mutations exactly once and in the
+//! order the client made them. Beyond that:
+//!
+//! - Arrival applies mutations in the order they reached the coordinator,
+//!   batching consecutive mutations from the same timeline into one frame.
+//!   A client which sends a burst of mutations delays everyone who sent
+//!   theirs after it.
+//! - RoundRobin lets pending timelines take turns applying up to quantum
+//!   mutations each, multiplied by the timeline's weight (1 unless set with
+//!   CoordinatorDocument::set_timeline_weight). Once a mutation is the
+//!   oldest pending one in its timeline, at most quantum times the sum of
+//!   the other pending timelines' weights mutations are applied before it,
+//!   however many mutations those timelines have queued.
+//! - TimelineFifo only keeps each timeline in order. Timelines are served in
+//!   the order they became pending, and each step applies a timeline's
+//!   whole backlog, which writes the fewest storage frames but gives no
+//!   fairness between clients.
+//!
+//! For a given sequence of received mutations (and weights) each order is
+//! deterministic. The order is persisted in the document, so a restarted
+//! coordinator keeps interleaving mutations the same way. Weights are host
+//! policy, e.g. derived from a client's plan, and are not persisted.
+//!
+//! CoordinatorDocument::metrics reports the queue's depth and how long
+//! mutations wait in it, which shows whether the order keeps up with load.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use rusqlite::{named_params, Connection, OptionalExtension};
+
+use crate::{JournalId, Lsn, LsnRange};
+
+const APPLY_ORDER_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_apply_order (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        policy TEXT NOT NULL,
+        quantum INTEGER
+    ) STRICT
+";
+
+const APPLY_ORDER_READ_SQL: &str = "
+    SELECT policy, quantum FROM __sqlsync_apply_order WHERE id = 0
+";
+
+const APPLY_ORDER_WRITE_SQL: &str = "
+    INSERT INTO __sqlsync_apply_order (id, policy, quantum)
+    VALUES (0, :policy, :quantum)
+    ON CONFLICT (id) DO UPDATE SET policy = :policy, quantum = :quantum
+";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApplyOrder {
+    #[default]
+    Arrival,
+    /// timelines take turns applying up to quantum * weight mutations
+    RoundRobin {
+        quantum: u64,
+    },
+    TimelineFifo,
+}
+
+impl ApplyOrder {
+    /// whether the queue keeps a single entry per timeline
+    fn coalesces(&self) -> bool {
+        !matches!(self, ApplyOrder::Arrival)
+    }
+}
+
+pub(crate) fn run_order_migration(sqlite: &Connection) -> rusqlite::Result<()> {
+    sqlite.execute(APPLY_ORDER_TABLE_SQL, [])?;
+    Ok(())
+}
+
+/// the order persisted in the document, or the default if none has been set
+pub(crate) fn read_apply_order(
+    sqlite: &Connection,
+) -> rusqlite::Result<ApplyOrder> {
+    let row: Option<(String, Option<u64>)> = sqlite
+        .query_row(APPLY_ORDER_READ_SQL, [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    Ok(match row {
+        Some((policy, quantum)) => match (policy.as_str(), quantum) {
+            ("round_robin", Some(quantum)) => {
+                ApplyOrder::RoundRobin { quantum }
+            }
+            ("timeline_fifo", _) => ApplyOrder::TimelineFifo,
+            _ => ApplyOrder::Arrival,
+        },
+        None => ApplyOrder::Arrival,
+    })
+}
+
+pub(crate) fn write_apply_order(
+    sqlite: &Connection,
+    order: ApplyOrder,
+) -> rusqlite::Result<()> {
+    let (policy, quantum) = match order {
+        ApplyOrder::Arrival => ("arrival", None),
+        ApplyOrder::RoundRobin { quantum } => ("round_robin", Some(quantum)),
+        ApplyOrder::TimelineFifo => ("timeline_fifo", None),
+    };
+    sqlite.execute(
+        APPLY_ORDER_WRITE_SQL,
+        named_params! {":policy": policy, ":quantum": quantum},
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReceiveQueueEntry {
+    pub id: JournalId,
+    pub range: LsnRange,
+}
+
+/// how long applied mutations waited between arriving at the coordinator
+/// and being applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueWait {
+    pub mutations: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl QueueWait {
+    pub fn mean(&self) -> Duration {
+        match self.mutations {
+            0 => Duration::ZERO,
+            n => {
+                Duration::from_nanos((self.total.as_nanos() / n as u128) as u64)
+            }
+        }
+    }
+
+    fn record(&mut self, wait: Duration) {
+        self.mutations += 1;
+        self.total += wait;
+        self.max = self.max.max(wait);
+    }
+}
+
+/// the ranges of received mutations waiting to be applied, in the order
+/// they will be applied
+#[derive(Debug)]
+pub(crate) struct ReceiveQueue {
+    order: ApplyOrder,
+    entries: VecDeque<ReceiveQueueEntry>,
+    weights: HashMap<JournalId, u32>,
+    /// when each queued mutation arrived, in unix milliseconds
+    arrivals: HashMap<JournalId, VecDeque<(Lsn, i64)>>,
+    wait: QueueWait,
+}
+
+impl ReceiveQueue {
+    pub fn new(order: ApplyOrder) -> Self {
+        Self {
+            order,
+            entries: VecDeque::new(),
+            weights: HashMap::new(),
+            arrivals: HashMap::new(),
+            wait: QueueWait::default(),
+        }
+    }
+
+    pub fn order(&self) -> ApplyOrder {
+        self.order
+    }
+
+    /// switch to a new order, which applies to mutations already queued
+    pub fn set_order(&mut self, order: ApplyOrder) {
+        self.order = order;
+        if order.coalesces() {
+            for entry in std::mem::take(&mut self.entries) {
+                self.merge(entry, false);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the timelines with queued mutations, possibly repeated
+    pub fn timelines(&self) -> impl Iterator<Item = JournalId> + '_ {
+        self.entries.iter().map(|e| e.id)
+    }
+
+    /// the queued ranges, in the order they will be applied
+    pub fn entries(&self) -> impl Iterator<Item = &ReceiveQueueEntry> + '_ {
+        self.entries.iter()
+    }
+
+    /// RoundRobin turns for the timeline are weight times longer. weights
+    /// of 0 are treated as 1
+    pub fn set_weight(&mut self, id: JournalId, weight: u32) {
+        if weight > 1 {
+            self.weights.insert(id, weight);
+        } else {
+            self.weights.remove(&id);
+        }
+    }
+
+    /// the number of queued mutations
+    pub fn depth(&self) -> usize {
+        self.entries.iter().map(|e| e.range.len()).sum()
+    }
+
+    /// the most mutations queued for any one timeline
+    pub fn max_timeline_depth(&self) -> usize {
+        self.arrivals.values().map(|a| a.len()).max().unwrap_or(0)
+    }
+
+    /// how long the oldest queued mutation has been waiting
+    pub fn oldest_wait(&self, now: i64) -> Duration {
+        self.arrivals
+            .values()
+            .filter_map(|a| a.front())
+            .map(|&(_, at)| millis_between(at, now))
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn wait(&self) -> QueueWait {
+        self.wait
+    }
+
+    /// record that the timeline's mutations up to and including up_to have
+    /// been applied (or skipped as duplicates)
+    pub fn applied(&mut self, id: JournalId, up_to: Lsn, now: i64) {
+        let Some(arrivals) = self.arrivals.get_mut(&id) else {
+            return;
+        };
+        while let Some(&(lsn, at)) = arrivals.front() {
+            if lsn > up_to {
+                break;
+            }
+            arrivals.pop_front();
+            self.wait.record(millis_between(at, now));
+        }
+        if arrivals.is_empty() {
+            self.arrivals.remove(&id);
+        }
+    }
+
+    /// queue the mutation at lsn in the timeline id, which arrived at now
+    pub fn push(&mut self, id: JournalId, lsn: Lsn, now: i64) {
+        let entry = if self.order.coalesces() {
+            self.entries.iter_mut().find(|e| e.id == id)
+        } else {
+            // coalesce this update if the queue already ends with an entry
+            // for this journal
+            self.entries.back_mut().filter(|e| e.id == id)
+        };
+        match entry {
+            Some(entry) if entry.range.contains(lsn) => return,
+            Some(entry) => entry.range = entry.range.append(lsn),
+            None => self.entries.push_back(ReceiveQueueEntry {
+                id,
+                range: LsnRange::new(lsn, lsn),
+            }),
+        }
+        self.arrivals.entry(id).or_default().push_back((lsn, now));
+    }
+
+    /// remove the next range to apply from the queue. under RoundRobin the
+    /// rest of the timeline's range goes to the back of the queue
+    pub fn pop(&mut self) -> Option<ReceiveQueueEntry> {
+        let entry = self.entries.pop_front()?;
+        let ApplyOrder::RoundRobin { quantum } = self.order else {
+            return Some(entry);
+        };
+        let weight = self.weights.get(&entry.id).copied().unwrap_or(1);
+        let first = entry.range.first().expect("queued ranges are non-empty");
+        let last = first + quantum.max(1) * u64::from(weight) - 1;
+        let rest = entry.range.trim_prefix(last);
+        if rest.is_non_empty() {
+            self.entries
+                .push_back(ReceiveQueueEntry { id: entry.id, range: rest });
+        }
+        Some(ReceiveQueueEntry {
+            id: entry.id,
+            range: LsnRange::new(first, last.min(entry.range.last().unwrap())),
+        })
+    }
+
+    /// return the part of a popped range which wasn't applied, so that it's
+    /// resumed by the next step
+    pub fn requeue(&mut self, entry: ReceiveQueueEntry) {
+        if self.order.coalesces() {
+            self.merge(entry, true);
+        } else {
+            self.entries.push_front(entry);
+        }
+    }
+
+    /// extend a popped entry with later ranges of its timeline, skipping
+    /// over other timelines' ranges while commutes holds for every range
+    /// passed, including the ones pulled forward
+    pub fn pull_forward<E>(
+        &mut self,
+        entry: &mut ReceiveQueueEntry,
+        mut commutes: impl FnMut(&ReceiveQueueEntry) -> Result<bool, E>,
+    ) -> Result<(), E> {
+        let mut idx = 0;
+        while idx < self.entries.len() {
+            let next = self.entries[idx];
+            if !commutes(&next)? {
+                break;
+            }
+            match (entry.range.first(), next.range.last()) {
+                (Some(first), Some(last))
+                    if next.id == entry.id
+                        && next.range.first() == Some(entry.range.next()) =>
+                {
+                    entry.range = LsnRange::new(first, last);
+                    self.entries.remove(idx);
+                }
+                _ => idx += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// merge entry into the queue entry for its timeline. with to_front the
+    /// entry precedes the queued range and the merged entry moves to the
+    /// front, otherwise it follows the queued range which keeps its place
+    fn merge(&mut self, entry: ReceiveQueueEntry, to_front: bool) {
+        let existing = self.entries.iter().position(|e| e.id == entry.id);
+        let Some(idx) = existing else {
+            if to_front {
+                self.entries.push_front(entry);
+            } else {
+                self.entries.push_back(entry);
+            }
+            return;
+        };
+        let (a, b) = if to_front {
+            (entry.range, self.entries[idx].range)
+        } else {
+            (self.entries[idx].range, entry.range)
+        };
+        let range = LsnRange::new(
+            a.first().expect("queued ranges are non-empty"),
+            b.last().expect("queued ranges are non-empty"),
+        );
+        if to_front {
+            self.entries.remove(idx);
+            self.entries
+                .push_front(ReceiveQueueEntry { id: entry.id, range });
+        } else {
+            self.entries[idx].range = range;
+        }
+    }
+}
+
+fn millis_between(start: i64, end: i64) -> Duration {
+    Duration::from_millis((end - start).max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use super::{
+        read_apply_order, run_order_migration, write_apply_order, ApplyOrder,
+        QueueWait, ReceiveQueue,
+    };
+    use crate::{JournalId, LsnRange};
+
+    /// the ranges popped until the queue is empty
+    fn drain(queue: &mut ReceiveQueue) -> Vec<(JournalId, LsnRange)> {
+        std::iter::from_fn(|| queue.pop().map(|e| (e.id, e.range))).collect()
+    }
+
+    fn ids() -> (JournalId, JournalId) {
+        let mut rng = rand::thread_rng();
+        (JournalId::new128(&mut rng), JournalId::new128(&mut rng))
+    }
+
+    /// a sends 0..=9, then b sends 0, then a sends 10
+    fn burst(order: ApplyOrder) -> (JournalId, JournalId, ReceiveQueue) {
+        let (a, b) = ids();
+        let mut queue = ReceiveQueue::new(order);
+        for lsn in 0..10 {
+            queue.push(a, lsn, 0);
+        }
+        queue.push(b, 0, 0);
+        queue.push(a, 10, 0);
+        (a, b, queue)
+    }
+
+    #[test]
+    fn arrival_order() {
+        let (a, b, mut queue) = burst(ApplyOrder::Arrival);
+        assert_eq!(
+            drain(&mut queue),
+            vec![
+                (a, LsnRange::new(0, 9)),
+                (b, LsnRange::new(0, 0)),
+                (a, LsnRange::new(10, 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_robin_is_fair() {
+        let (a, b, mut queue) = burst(ApplyOrder::RoundRobin { quantum: 4 });
+        assert_eq!(
+            drain(&mut queue),
+            vec![
+                (a, LsnRange::new(0, 3)),
+                // b waits for at most one quantum of a's burst
+                (b, LsnRange::new(0, 0)),
+                (a, LsnRange::new(4, 7)),
+                (a, LsnRange::new(8, 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn timeline_fifo_batches() {
+        let (a, b, mut queue) = burst(ApplyOrder::TimelineFifo);
+        assert_eq!(
+            drain(&mut queue),
+            vec![(a, LsnRange::new(0, 10)), (b, LsnRange::new(0, 0))]
+        );
+    }
+
+    #[test]
+    fn requeued_ranges_resume_first() {
+        let (a, b, mut queue) = burst(ApplyOrder::RoundRobin { quantum: 4 });
+        let mut entry = queue.pop().unwrap();
+        // preempted after applying lsn 0
+        entry.range = entry.range.trim_prefix(0);
+        queue.requeue(entry);
+        assert_eq!(
+            drain(&mut queue),
+            vec![
+                (a, LsnRange::new(1, 4)),
+                (b, LsnRange::new(0, 0)),
+                (a, LsnRange::new(5, 8)),
+                (a, LsnRange::new(9, 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn pulls_commuting_ranges_forward() {
+        let (a, b, mut queue) = burst(ApplyOrder::Arrival);
+        let mut entry = queue.pop().unwrap();
+        queue
+            .pull_forward(&mut entry, |_| Ok::<_, ()>(true))
+            .unwrap();
+        assert_eq!((entry.id, entry.range), (a, LsnRange::new(0, 10)));
+        assert_eq!(drain(&mut queue), vec![(b, LsnRange::new(0, 0))]);
+
+        // nothing moves past a range which doesn't commute
+        let (_, b, mut queue) = burst(ApplyOrder::Arrival);
+        let mut entry = queue.pop().unwrap();
+        queue
+            .pull_forward(&mut entry, |e| Ok::<_, ()>(e.id != b))
+            .unwrap();
+        assert_eq!(entry.range, LsnRange::new(0, 9));
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[test]
+    fn switching_order_coalesces() {
+        let (a, b, mut queue) = burst(ApplyOrder::Arrival);
+        queue.set_order(ApplyOrder::TimelineFifo);
+        assert_eq!(
+            drain(&mut queue),
+            vec![(a, LsnRange::new(0, 10)), (b, LsnRange::new(0, 0))]
+        );
+    }
+
+    #[test]
+    fn order_is_persisted() {
+        let sqlite = Connection::open_in_memory().unwrap();
+        run_order_migration(&sqlite).unwrap();
+        assert_eq!(read_apply_order(&sqlite).unwrap(), ApplyOrder::Arrival);
+        for order in [
+            ApplyOrder::RoundRobin { quantum: 16 },
+            ApplyOrder::TimelineFifo,
+            ApplyOrder::Arrival,
+        ] {
+            write_apply_order(&sqlite, order).unwrap();
+            assert_eq!(read_apply_order(&sqlite).unwrap(), order);
+        }
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let (a, b, mut queue) = burst(ApplyOrder::RoundRobin { quantum: 2 });
+        queue.set_weight(a, 3);
+        assert_eq!(
+            drain(&mut queue),
+            vec![
+                (a, LsnRange::new(0, 5)),
+                (b, LsnRange::new(0, 0)),
+                (a, LsnRange::new(6, 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn measures_depth_and_wait() {
+        let (a, b) = ids();
+        let mut queue =
+            ReceiveQueue::new(ApplyOrder::RoundRobin { quantum: 1 });
+        for lsn in 0..3 {
+            queue.push(a, lsn, 100);
+        }
+        queue.push(b, 0, 150);
+        // retransmits aren't queued again
+        queue.push(a, 1, 200);
+        assert_eq!(queue.depth(), 4);
+        assert_eq!(queue.max_timeline_depth(), 3);
+        assert_eq!(queue.oldest_wait(300), Duration::from_millis(200));
+
+        let entry = queue.pop().unwrap();
+        queue.applied(entry.id, entry.range.last().unwrap(), 300);
+        let entry = queue.pop().unwrap();
+        queue.applied(entry.id, entry.range.last().unwrap(), 400);
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(
+            queue.wait(),
+            QueueWait {
+                mutations: 2,
+                total: Duration::from_millis(450),
+                max: Duration::from_millis(250),
+            }
+        );
+        assert_eq!(queue.wait().mean(), Duration::from_millis(225));
+        assert_eq!(queue.oldest_wait(400), Duration::from_millis(300));
+    }
+}
@@ -14,6 +14,18 @@ pub const PAGESIZE: usize = 4096;
 pub type PageIdx = u32;
 const PAGE_IDX_SIZE: usize = size_of::<PageIdx>();
 
+/// the largest page index SQLite will ever use; with 4KiB pages this caps a
+/// document at just under 16TiB
+pub const MAX_PAGE_IDX: PageIdx = 4294967294;
+
+/// returns the page containing the byte at pos, or None if pos lies beyond
+/// MAX_PAGE_IDX
+pub fn page_idx_at(pos: u64) -> Option<PageIdx> {
+    PageIdx::try_from(pos / PAGESIZE as u64 + 1)
+        .ok()
+        .filter(|&page_idx| page_idx <= MAX_PAGE_IDX)
+}
+
 pub type Page = [u8; PAGESIZE];
 
 #[derive(Default, Debug, Clone)]
@@ -40,10 +52,23 @@ impl SparsePages {
         self.pages.insert(page_idx, page);
     }
 
+    pub fn get(&self, page_idx: PageIdx) -> Option<&Page> {
+        self.pages.get(&page_idx)
+    }
+
+    pub fn remove(&mut self, page_idx: PageIdx) -> Option<Page> {
+        self.pages.remove(&page_idx)
+    }
+
     pub fn page_idxs(&self) -> impl Iterator<Item = &PageIdx> {
         self.pages.keys()
     }
 
+    /// drop the pages after max_page_idx
+    pub fn truncate(&mut self, max_page_idx: PageIdx) {
+        self.pages.retain(|&page_idx, _| page_idx <= max_page_idx);
+    }
+
     // returns the max page index of this sparse pages object
     pub fn max_page_idx(&self) -> Option<PageIdx> {
         self.pages.keys().max().copied()
@@ -82,9 +107,15 @@ impl Serializable for SparsePages {
 
         Ok(())
     }
+
+    fn serialized_size(&self) -> Option<usize> {
+        Some(self.pages.len() * (PAGE_IDX_SIZE + PAGESIZE))
+    }
 }
 
-/// Binary layout of Serialized Page objects is:
+/// Binary layout of Serialized Page objects is below; offsets within a frame
+/// are usize as a frame is always held in memory, offsets within the database
+/// file are u64.
 /// for each page_idx (sorted desc) [
 ///   page_idx: u32
 /// ]
@@ -165,3 +196,36 @@ impl<R: PositionedReader> SerializedPagesReader<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{page_idx_at, SparsePages, MAX_PAGE_IDX, PAGESIZE};
+    use crate::Serializable;
+
+    #[test]
+    fn page_idx_beyond_4gb() {
+        let page = PAGESIZE as u64;
+        assert_eq!(page_idx_at(0), Some(1));
+        assert_eq!(page_idx_at(page - 1), Some(1));
+        assert_eq!(page_idx_at(page), Some(2));
+
+        // offsets past 4GiB must not wrap
+        let four_gb = 1u64 << 32;
+        assert_eq!(page_idx_at(four_gb), Some((four_gb / page) as u32 + 1));
+
+        let last = (MAX_PAGE_IDX as u64 - 1) * page;
+        assert_eq!(page_idx_at(last), Some(MAX_PAGE_IDX));
+        assert_eq!(page_idx_at(last + page), None);
+        assert_eq!(page_idx_at(u64::MAX), None);
+    }
+
+    #[test]
+    fn serialized_size_is_exact() {
+        let mut pages = SparsePages::new();
+        pages.write(3, [3; PAGESIZE]);
+        pages.write(1, [1; PAGESIZE]);
+        let buf = pages.to_vec().unwrap();
+        assert_eq!(Some(buf.len()), pages.serialized_size());
+        assert_eq!(buf.capacity(), buf.len());
+    }
+}
@@ -0,0 +1,213 @@
+//! PendingPages are the pages written to Storage since its last commit. A
+//! long transaction can write more pages than fit in memory, so they are
+//! kept in a TempFile: past the storage's WAL memory limit they move to disk
+//! just like the WAL does, and commit streams them from there into the
+//! journal's frame. In the browser they move to a spill file (see
+//! crate::set_spill_files), and without one the write fails with
+//! SQLITE_FULL rather than holding more than the limit in memory.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use sqlite_vfs::File;
+
+use crate::{
+    page::{Page, PageIdx, PAGESIZE},
+    vfs::TempFile,
+    Serializable,
+};
+
+fn vfs_err(code: i32) -> io::Error {
+    let kind = match code {
+        libsqlite3_sys::SQLITE_FULL => io::ErrorKind::StorageFull,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("pending pages temp file failed: {}", code))
+}
+
+pub struct PendingPages {
+    // the offset of each page in file
+    slots: BTreeMap<PageIdx, u64>,
+    // the end of the last slot, truncate can leave gaps before it
+    end: u64,
+    // only borrowed for the duration of a read or write
+    file: RefCell<TempFile>,
+}
+
+impl Default for PendingPages {
+    fn default() -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            end: 0,
+            file: RefCell::new(TempFile::Memory(Vec::new())),
+        }
+    }
+}
+
+impl PendingPages {
+    pub fn num_pages(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn page_idxs(&self) -> impl Iterator<Item = &PageIdx> {
+        self.slots.keys()
+    }
+
+    pub fn max_page_idx(&self) -> Option<PageIdx> {
+        self.slots.keys().next_back().copied()
+    }
+
+    /// true once the pages have moved out of memory
+    pub fn is_spilled(&self) -> bool {
+        !matches!(*self.file.borrow(), TempFile::Memory(_))
+    }
+
+    /// drop every page, moving back into memory
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// drop the pages after max_page_idx
+    pub fn truncate(&mut self, max_page_idx: PageIdx) {
+        self.slots.retain(|&page_idx, _| page_idx <= max_page_idx);
+    }
+
+    /// write page_idx, moving the pages out of memory rather than holding
+    /// more than limit bytes of them there. fails with StorageFull if there
+    /// is nowhere to move them to, see TempFile::reserve
+    pub fn write(
+        &mut self,
+        page_idx: PageIdx,
+        page: &Page,
+        limit: usize,
+    ) -> io::Result<()> {
+        let end =
+            self.slots.get(&page_idx).unwrap_or(&self.end) + PAGESIZE as u64;
+        let file = self.file.get_mut();
+        file.reserve(end, limit).map_err(vfs_err)?;
+        let offset = *self.slots.entry(page_idx).or_insert(self.end);
+        file.write(offset, page).map_err(vfs_err)?;
+        self.end = self.end.max(end);
+        Ok(())
+    }
+
+    /// read buf from page_idx at page_offset, returning 0 if the page isn't
+    /// pending
+    pub fn read(
+        &self,
+        page_idx: PageIdx,
+        page_offset: usize,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let Some(&offset) = self.slots.get(&page_idx) else {
+            return Ok(0);
+        };
+        assert!(
+            page_offset + buf.len() <= PAGESIZE,
+            "page offset out of bounds"
+        );
+        self.file
+            .borrow_mut()
+            .read(offset + page_offset as u64, buf)
+            .map_err(vfs_err)
+    }
+}
+
+/// serialized just like SparsePages, so the frame is read back with
+/// SerializedPagesReader
+impl Serializable for PendingPages {
+    fn serialize_into<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        assert!(
+            !self.slots.is_empty(),
+            "cannot serialize empty pending pages"
+        );
+
+        // serialize the page indexes, sorted desc
+        for page_idx in self.slots.keys().rev() {
+            writer.write_all(&page_idx.to_le_bytes())?;
+        }
+
+        // then stream the pages through a single page buffer
+        let mut page: Page = [0; PAGESIZE];
+        for &page_idx in self.slots.keys().rev() {
+            self.read(page_idx, 0, &mut page)?;
+            writer.write_all(&page)?;
+        }
+        Ok(())
+    }
+
+    fn serialized_size(&self) -> Option<usize> {
+        let page_idx_size = std::mem::size_of::<PageIdx>();
+        Some(self.slots.len() * (page_idx_size + PAGESIZE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PendingPages;
+    use crate::{
+        page::{SerializedPagesReader, SparsePages, PAGESIZE},
+        Serializable,
+    };
+
+    #[test]
+    fn serializes_like_sparse_pages() {
+        let mut pending = PendingPages::default();
+        let mut sparse = SparsePages::new();
+        for page_idx in [3, 1, 7, 3] {
+            let page = [page_idx as u8; PAGESIZE];
+            pending.write(page_idx, &page, usize::MAX).unwrap();
+            sparse.write(page_idx, page);
+        }
+        assert_eq!(pending.num_pages(), 3);
+        assert_eq!(pending.max_page_idx(), Some(7));
+        let frame = pending.to_vec().unwrap();
+        assert_eq!(frame, sparse.to_vec().unwrap());
+        assert_eq!(Some(frame.len()), pending.serialized_size());
+
+        let mut buf = [0; 2];
+        let reader = SerializedPagesReader(&frame[..]);
+        assert_eq!(reader.read(7, 10, &mut buf).unwrap(), 2);
+        assert_eq!(buf, [7, 7]);
+        assert_eq!(pending.read(2, 0, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spills_past_the_limit() {
+        let mut pending = PendingPages::default();
+        let limit = 4 * PAGESIZE;
+        for page_idx in 1..=4 {
+            pending
+                .write(page_idx, &[page_idx as u8; PAGESIZE], limit)
+                .unwrap();
+        }
+        assert!(!pending.is_spilled());
+
+        // overwriting a page reuses its slot
+        pending.write(2, &[9; PAGESIZE], limit).unwrap();
+        assert!(!pending.is_spilled());
+        pending.write(5, &[5; PAGESIZE], limit).unwrap();
+        assert!(pending.is_spilled());
+
+        let mut page = [0; PAGESIZE];
+        assert_eq!(pending.read(2, 0, &mut page).unwrap(), PAGESIZE);
+        assert_eq!(page, [9; PAGESIZE]);
+        assert_eq!(pending.read(5, 0, &mut page).unwrap(), PAGESIZE);
+        assert_eq!(page, [5; PAGESIZE]);
+
+        // truncated slots aren't reused
+        pending.truncate(3);
+        assert_eq!(pending.max_page_idx(), Some(3));
+        pending.write(6, &[6; PAGESIZE], limit).unwrap();
+        assert_eq!(pending.read(3, 0, &mut page).unwrap(), PAGESIZE);
+        assert_eq!(page, [3; PAGESIZE]);
+
+        pending.clear();
+        assert!(!pending.is_spilled());
+        assert_eq!(pending.num_pages(), 0);
+    }
+}
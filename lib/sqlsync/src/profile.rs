@@ -0,0 +1,137 @@
+//! Replay profiles. A LocalDocument replays its whole timeline on every
+//! rebase, so a few expensive mutations can make every sync slow.
+//! LocalDocument::replay_profile replays part of the timeline, measuring
+//! each mutation, so developers can find which mutations dominate.
+//!
+//! Durations are measured with a monotonic microsecond clock (see
+//! unixtime::monotonic_microseconds), so even cheap mutations register.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{timeline::FrameMeta, Lsn, LsnRange};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutationProfile {
+    /// the mutation's lsn in the timeline
+    pub lsn: Lsn,
+    pub mutation: Vec<u8>,
+    pub meta: FrameMeta,
+    /// time spent in the reducer, including the sql it ran
+    pub duration: Duration,
+    /// sql statements the reducer executed
+    pub statements: u64,
+    /// database pages written when the mutation committed
+    pub pages_dirtied: u64,
+}
+
+/// the sum of a set of MutationProfiles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProfileTotals {
+    pub mutations: u64,
+    pub duration: Duration,
+    pub statements: u64,
+    pub pages_dirtied: u64,
+}
+
+impl ProfileTotals {
+    fn add(&mut self, profile: &MutationProfile) {
+        self.mutations += 1;
+        self.duration += profile.duration;
+        self.statements += profile.statements;
+        self.pages_dirtied += profile.pages_dirtied;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayProfile {
+    /// the timeline lsns which were profiled
+    pub range: LsnRange,
+    /// in lsn order
+    pub mutations: Vec<MutationProfile>,
+}
+
+impl ReplayProfile {
+    pub fn totals(&self) -> ProfileTotals {
+        let mut totals = ProfileTotals::default();
+        for profile in self.mutations.iter() {
+            totals.add(profile);
+        }
+        totals
+    }
+
+    /// the n most expensive mutations, by duration and then pages dirtied
+    pub fn slowest(&self, n: usize) -> Vec<&MutationProfile> {
+        let mut slowest: Vec<_> = self.mutations.iter().collect();
+        slowest.sort_by(|a, b| {
+            (b.duration, b.pages_dirtied).cmp(&(a.duration, a.pages_dirtied))
+        });
+        slowest.truncate(n);
+        slowest
+    }
+
+    /// aggregate mutations by a key derived from their bytes, usually the
+    /// mutation's type once decoded
+    pub fn group_by<K: Ord>(
+        &self,
+        key: impl Fn(&[u8]) -> K,
+    ) -> BTreeMap<K, ProfileTotals> {
+        let mut groups: BTreeMap<K, ProfileTotals> = BTreeMap::new();
+        for profile in self.mutations.iter() {
+            groups
+                .entry(key(&profile.mutation))
+                .or_default()
+                .add(profile);
+        }
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MutationProfile, ProfileTotals, ReplayProfile};
+    use crate::{timeline::FrameMeta, LsnRange};
+
+    fn profile(lsn: u64, mutation: &[u8], ms: u64) -> MutationProfile {
+        MutationProfile {
+            lsn,
+            mutation: mutation.to_vec(),
+            meta: FrameMeta::default(),
+            duration: Duration::from_millis(ms),
+            statements: 2,
+            pages_dirtied: lsn,
+        }
+    }
+
+    #[test]
+    fn aggregates_mutations() {
+        let replay = ReplayProfile {
+            range: LsnRange::new(0, 2),
+            mutations: vec![
+                profile(0, b"incr", 1),
+                profile(1, b"reset", 5),
+                profile(2, b"incr", 3),
+            ],
+        };
+        assert_eq!(
+            replay.totals(),
+            ProfileTotals {
+                mutations: 3,
+                duration: Duration::from_millis(9),
+                statements: 6,
+                pages_dirtied: 3,
+            }
+        );
+
+        let slowest: Vec<_> = replay.slowest(2).iter().map(|p| p.lsn).collect();
+        assert_eq!(slowest, vec![1, 2]);
+
+        let groups = replay.group_by(|m| m.to_vec());
+        assert_eq!(groups[&b"incr".to_vec()].mutations, 2);
+        assert_eq!(
+            groups[&b"incr".to_vec()].duration,
+            Duration::from_millis(4)
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Storage quotas cap how large a coordinator's document may grow, both the
+//! materialized database and its storage journal (which also holds history
+//! that hasn't been compacted yet). A mutation the coordinator can't apply
+//! within the quota is rejected: its timeline is parked, so that later
+//! mutations from the same client don't apply out of order, and a
+//! QuotaExceeded is reported to the document's QuotaHook and queued for the
+//! offending client (see CoordinatorServer::reject_over_quota).
+//!
+//! Parked timelines are retried whenever the quota changes or the journal is
+//! compacted, or by calling CoordinatorDocument::retry_rejected once space
+//! has been freed some other way. Per tenant quotas are layered on top by
+//! setting each document's quota from TenantRegistry::record_storage.
+
+use std::fmt::{self, Display};
+
+use crate::{replication::ProtocolError, JournalId};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageQuota {
+    /// the most the database may grow to, in bytes
+    pub max_database_bytes: Option<u64>,
+    /// mutations aren't applied once the storage journal is this large, in
+    /// bytes. it shrinks again when compacted
+    pub max_journal_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    Database,
+    Journal,
+}
+
+impl Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKind::Database => f.write_str("database"),
+            QuotaKind::Journal => f.write_str("journal"),
+        }
+    }
+}
+
+/// a mutation which was rejected because applying it would take the
+/// document over its quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub doc: JournalId,
+    /// the timeline of the rejected mutation
+    pub timeline: JournalId,
+    pub kind: QuotaKind,
+    pub limit: u64,
+    /// the size when the mutation was rejected, in bytes
+    pub used: u64,
+}
+
+impl QuotaExceeded {
+    /// the error to send the offending client
+    pub fn protocol_error(&self) -> ProtocolError {
+        ProtocolError::QuotaExceeded(self.to_string())
+    }
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "document {} {} is {} bytes, its quota is {} bytes",
+            self.doc, self.kind, self.used, self.limit
+        )
+    }
+}
+
+/// notified whenever a document rejects a mutation, e.g. to alert an
+/// operator or bill a tenant for more storage
+pub trait QuotaHook: Send {
+    fn quota_exceeded(&mut self, event: &QuotaExceeded);
+}
@@ -106,9 +106,12 @@ impl<P: ToSql> ReactiveQuery<P> {
             // explain rows have the schema:
             // addr, opcode, p1, p2, p3, p4, p5, comment
             // to find root pages, we need to find the OpenRead opcodes
-            // and then look at the p2 column which contains the root page id
+            // and then look at the p2 column which contains the root page id.
+            // p3 is the database; only main is tracked by storage, changes to
+            // attached local tables are reported as StorageChange::Full
             let opcode: String = row.get(1)?;
-            if opcode == "OpenRead" {
+            let db: i64 = row.get(4)?;
+            if opcode == "OpenRead" && db == 0 {
                 let root_page: PageIdx = row.get(3)?;
                 root_pages_sorted.push(root_page);
             }
@@ -0,0 +1,134 @@
+//! Redaction permanently removes data from a document, for compliance
+//! deletes. Deleting rows with a mutation only removes them from the current
+//! state: older storage frames, checkpoints and every client's storage still
+//! hold them. CoordinatorDocument::redact instead runs a purge against the
+//! document with sqlite's secure_delete on, so the freed space is zeroed, and
+//! then folds every storage frame into a single one.
+//!
+//! Each redaction bumps the document's epoch, which is recorded in the
+//! document and sent to clients during the handshake. A client from an older
+//! epoch clears its storage before answering the coordinator's range request,
+//! so it resyncs from the folded frame rather than keeping its history.
+//! Clients which are connected during a redaction have to reconnect, see
+//! CoordinatorServer::redact.
+//!
+//! The purge doesn't reach mutations, which are app defined bytes: timeline
+//! frames the coordinator has applied are already dropped, but hosts must
+//! redact change logs (see cdc) themselves. Published checkpoints, and frames
+//! persisted through server::FrameStorage, have to be replaced with ones
+//! taken after the redaction.
+
+use rusqlite::{Connection, OptionalExtension};
+
+const EPOCH_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_epoch (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        epoch INTEGER NOT NULL
+    ) STRICT
+";
+
+/// PRAGMA auto_vacuum's value in incremental mode
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// the document's epoch, 0 until it's first redacted
+pub(crate) fn read_epoch(sqlite: &Connection) -> rusqlite::Result<u64> {
+    let exists: bool = sqlite.query_row(
+        "SELECT EXISTS (
+            SELECT 1 FROM sqlite_schema WHERE name = '__sqlsync_epoch'
+        )",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    let epoch = sqlite
+        .query_row("SELECT epoch FROM __sqlsync_epoch", [], |row| row.get(0))
+        .optional()?;
+    Ok(epoch.unwrap_or(0))
+}
+
+/// run purge in a transaction with secure_delete on and bump the epoch,
+/// returning the new epoch. the transaction rolls back if purge fails
+pub(crate) fn purge<F>(
+    sqlite: &mut Connection,
+    purge: F,
+) -> rusqlite::Result<u64>
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<()>,
+{
+    sqlite.pragma_update(None, "secure_delete", true)?;
+    let result = (|| {
+        let tx = sqlite.transaction()?;
+        tx.execute(EPOCH_TABLE_SQL, [])?;
+        purge(&tx)?;
+        let epoch = read_epoch(&tx)? + 1;
+        tx.execute(
+            "INSERT OR REPLACE INTO __sqlsync_epoch (id, epoch) VALUES (0, ?)",
+            [epoch],
+        )?;
+        tx.commit()?;
+        // hand the zeroed pages back, so that they don't linger in the file.
+        // documents are created with incremental auto_vacuum (see
+        // db::open_with_vfs), any other database is switched over by a
+        // full vacuum
+        let auto_vacuum: i64 =
+            sqlite.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+        if auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+            sqlite.execute_batch("PRAGMA incremental_vacuum")?;
+        } else {
+            sqlite.execute_batch("PRAGMA auto_vacuum = incremental; VACUUM")?;
+        }
+        Ok(epoch)
+    })();
+    sqlite.pragma_update(None, "secure_delete", false)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{purge, read_epoch};
+
+    #[test]
+    fn purging_bumps_the_epoch() {
+        let mut sqlite = Connection::open_in_memory().unwrap();
+        sqlite
+            .execute_batch("CREATE TABLE t (v); INSERT INTO t VALUES (1), (2);")
+            .unwrap();
+        assert_eq!(read_epoch(&sqlite).unwrap(), 0);
+
+        let delete = |conn: &Connection| {
+            conn.execute("DELETE FROM t WHERE v = 1", []).map(|_| ())
+        };
+        assert_eq!(purge(&mut sqlite, delete).unwrap(), 1);
+        assert_eq!(purge(&mut sqlite, delete).unwrap(), 2);
+        let rows: i64 = sqlite
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+
+        // the database is switched to incremental auto_vacuum, and the
+        // purged pages are gone from it
+        let pragma = |name: &str| -> i64 {
+            sqlite
+                .pragma_query_value(None, name, |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(pragma("auto_vacuum"), 2);
+        assert_eq!(pragma("freelist_count"), 0);
+
+        // a failed purge changes nothing
+        let fail = |conn: &Connection| {
+            conn.execute("DELETE FROM t", [])?;
+            conn.execute("DELETE FROM missing", []).map(|_| ())
+        };
+        assert!(purge(&mut sqlite, fail).is_err());
+        assert_eq!(read_epoch(&sqlite).unwrap(), 2);
+        let rows: i64 = sqlite
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+}
@@ -0,0 +1,836 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{
+    params_from_iter,
+    types::{Value, ValueRef},
+    Connection,
+};
+use sqlsync_reducer::{
+    host_ffi::WasmFFIError,
+    types::{
+        ErrorResponse, ExecResponse, LogRecord as GuestLogRecord,
+        QueryResponse, Request, RequestId, Requests, Row, SqliteValue,
+    },
+};
+use thiserror::Error;
+use wasmi::{errors::LinkerError, Engine, Module};
+
+use crate::{
+    logging::{self, GuestLogConfig, LogContext, MutationId},
+    unixtime::unix_timestamp_milliseconds,
+    JournalId,
+};
+
+mod pool;
+mod runtime;
+mod wasmi_runtime;
+#[cfg(feature = "wasmtime")]
+mod wasmtime_runtime;
+
+pub use pool::{ModuleDigest, ReducerPool};
+pub use runtime::{ReducerRuntime, Response, RuntimeError, RuntimeResult};
+
+use wasmi_runtime::WasmiRuntime;
+#[cfg(feature = "wasmtime")]
+use wasmtime_runtime::WasmtimeRuntime;
+
+#[derive(Error, Debug)]
+pub enum ReducerError {
+    #[error(transparent)]
+    Link(#[from] LinkerError),
+
+    #[error(transparent)]
+    Runtime(#[from] wasmi::Error),
+
+    #[cfg(feature = "wasmtime")]
+    #[error(transparent)]
+    Wasmtime(wasmtime::Error),
+
+    #[error(transparent)]
+    Interface(#[from] WasmFFIError),
+
+    #[error("reducer trapped: {0}")]
+    Trap(Box<ReducerTrap>),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// InFlight describes what the reducer was doing when it trapped
+#[derive(Debug, Clone)]
+pub enum InFlight {
+    /// the reducer was being initialized
+    Init,
+    /// the reducer was handed a new mutation
+    Reduce { mutation_len: usize },
+    /// the reducer was asked to run a scheduled task
+    Task { name: String },
+    /// the reducer was resumed with the responses to these requests
+    Step { requests: Vec<Request> },
+}
+
+impl Display for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InFlight::Init => write!(f, "initializing reducer"),
+            InFlight::Reduce { mutation_len } => {
+                write!(f, "reducing mutation ({} bytes)", mutation_len)
+            }
+            InFlight::Task { name } => write!(f, "running task {}", name),
+            InFlight::Step { requests } => {
+                write!(f, "handling responses to {:?}", requests)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReducerTrap {
+    /// the trap as reported by the wasm runtime
+    pub message: String,
+    /// the last error logged by the guest before it trapped; for reducers
+    /// which install the sqlsync panic hook this is the panic message
+    pub panic: Option<String>,
+    /// wasm frames, innermost first. wasmi doesn't capture backtraces so
+    /// this is only populated by runtimes that do
+    pub backtrace: Vec<String>,
+    pub in_flight: InFlight,
+}
+
+impl Display for ReducerTrap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} while {}", self.message, self.in_flight)?;
+        if let Some(panic) = &self.panic {
+            write!(f, ": {}", panic)?;
+        }
+        Ok(())
+    }
+}
+
+/// GuestHost is the state shared between a Reducer and the host functions
+/// its runtime exposes to the guest
+#[derive(Clone, Default)]
+pub(crate) struct GuestHost {
+    log_context: LogContext,
+    /// the most recent error logged by the guest, attached to traps
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl GuestHost {
+    pub fn handle_log(&self, record: &GuestLogRecord) {
+        if record.level() == log::Level::Error {
+            *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(record.message().to_owned());
+        }
+        self.log_context.log_guest(record)
+    }
+
+    /// forget everything about the document the reducer last ran in
+    fn reset(&self) {
+        self.log_context.reset();
+        self.take_last_error();
+    }
+
+    fn take_last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    fn into_reducer_error(
+        &self,
+        err: RuntimeError,
+        in_flight: InFlight,
+    ) -> ReducerError {
+        match err {
+            RuntimeError::Trap { message, backtrace } => {
+                ReducerError::Trap(Box::new(ReducerTrap {
+                    message,
+                    panic: self.take_last_error(),
+                    backtrace,
+                    in_flight,
+                }))
+            }
+            RuntimeError::Reducer(err) => err,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, ReducerError>;
+type SqlResult<T> = std::result::Result<T, ErrorResponse>;
+
+/// the size of a wasm memory page
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+/// MemoryStats reports how heavy a reducer instance is, see
+/// Reducer::memory_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// the size of the guest's linear memory in wasm pages. linear memory
+    /// never shrinks, so this is also its peak
+    pub memory_pages: u64,
+    /// roughly one unit per wasm instruction executed since the reducer was
+    /// created or last released to a ReducerPool. None unless the reducer
+    /// has a fuel limit, as metering slows the guest down
+    pub fuel_consumed: Option<u64>,
+}
+
+/// engines only meter fuel when it's needed to enforce a fuel limit
+fn new_engine(metered: bool) -> Engine {
+    let mut config = wasmi::Config::default();
+    config.consume_fuel(metered);
+    Engine::new(&config)
+}
+
+/// Reduction is how far Reducer::apply_until got with a mutation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    Complete,
+    /// the reducer was preempted while awaiting a batch of requests, which
+    /// are answered once it's resumed
+    Preempted,
+}
+
+pub struct Reducer {
+    runtime: Box<dyn ReducerRuntime>,
+    host: GuestHost,
+
+    /// set by the ReducerPool for instances it's responsible for
+    module_digest: Option<ModuleDigest>,
+
+    /// set once a mutation fails, after which the guest may be in an
+    /// inconsistent state and must not be reused for another document
+    poisoned: bool,
+
+    /// the requests the guest was awaiting when it was preempted
+    preempted: Option<BTreeMap<RequestId, Request>>,
+
+    /// sql statements run on behalf of the guest, see statements_executed
+    statements: u64,
+
+    /// fuel consumed before the reducer was last released to a pool
+    fuel_released: u64,
+
+    /// set when a statement fails because the document is at its max size,
+    /// see take_storage_full
+    storage_full: bool,
+}
+
+impl Reducer {
+    pub fn new(wasm_bytes: impl std::io::Read) -> Result<Self> {
+        Self::with_fuel_limit(wasm_bytes, None)
+    }
+
+    /// create a reducer whose guest traps once it consumes more than
+    /// fuel_limit during a single mutation or task. fuel is roughly one unit
+    /// per wasm instruction, and is only metered when there's a limit
+    pub fn with_fuel_limit(
+        wasm_bytes: impl std::io::Read,
+        fuel_limit: Option<u64>,
+    ) -> Result<Self> {
+        let engine = new_engine(fuel_limit.is_some());
+        let module = Module::new(&engine, wasm_bytes)?;
+        Self::instantiate(&engine, &module, fuel_limit)
+    }
+
+    /// create a reducer which runs on the Wasmtime JIT rather than the wasmi
+    /// interpreter, with an optional fuel limit as in with_fuel_limit
+    #[cfg(feature = "wasmtime")]
+    pub fn new_wasmtime(
+        wasm_bytes: &[u8],
+        fuel_limit: Option<u64>,
+    ) -> Result<Self> {
+        let host = GuestHost::default();
+        let runtime =
+            WasmtimeRuntime::new(wasm_bytes, host.clone(), fuel_limit);
+        Self::from_runtime(runtime, host)
+    }
+
+    /// engine must meter fuel if fuel_limit is set
+    fn instantiate(
+        engine: &Engine,
+        module: &Module,
+        fuel_limit: Option<u64>,
+    ) -> Result<Self> {
+        let host = GuestHost::default();
+        let runtime =
+            WasmiRuntime::instantiate(engine, module, host.clone(), fuel_limit);
+        Self::from_runtime(runtime, host)
+    }
+
+    fn from_runtime(
+        runtime: RuntimeResult<impl ReducerRuntime + 'static>,
+        host: GuestHost,
+    ) -> Result<Self> {
+        let runtime =
+            runtime.map_err(|e| host.into_reducer_error(e, InFlight::Init))?;
+        Ok(Self {
+            runtime: Box::new(runtime),
+            host,
+            module_digest: None,
+            poisoned: false,
+            preempted: None,
+            statements: 0,
+            fuel_released: 0,
+            storage_full: false,
+        })
+    }
+
+    /// the digest of the wasm this reducer was instantiated from, known when
+    /// it came from a ReducerPool
+    pub fn module_digest(&self) -> Option<ModuleDigest> {
+        self.module_digest
+    }
+
+    /// the version the reducer declared with sqlsync_reducer::reducer_version!
+    pub fn version(&self) -> Option<u32> {
+        self.runtime.version()
+    }
+
+    /// attribute all logs emitted by this reducer to the specified document
+    pub fn set_doc_id(&mut self, doc_id: JournalId) {
+        self.host.log_context.set_doc_id(doc_id);
+    }
+
+    /// filter and rate limit the logs this reducer's guest emits
+    pub fn set_log_config(&mut self, config: GuestLogConfig) {
+        self.host.log_context.set_config(config);
+    }
+
+    /// attribute logs emitted by the guest to mutation until it's unset
+    pub(crate) fn set_log_mutation(&mut self, mutation: Option<MutationId>) {
+        self.host.log_context.set_mutation(mutation);
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            memory_pages: (self.runtime.memory().len() / WASM_PAGE_SIZE) as u64,
+            fuel_consumed: self
+                .runtime
+                .fuel_consumed()
+                .map(|fuel| fuel - self.fuel_released),
+        }
+    }
+
+    /// true if a statement run for the guest failed because the document
+    /// reached its max size since the last call. the guest only sees an
+    /// error response, which it usually turns into a trap
+    pub fn take_storage_full(&mut self) -> bool {
+        std::mem::take(&mut self.storage_full)
+    }
+
+    /// the number of sql statements the reducer has run since it was created
+    pub fn statements_executed(&self) -> u64 {
+        self.statements
+    }
+
+    /// copy the reducer's linear memory
+    fn snapshot_memory(&self) -> Vec<u8> {
+        self.runtime.memory().to_vec()
+    }
+
+    /// restore linear memory from a snapshot taken by snapshot_memory.
+    /// memory can't shrink, so anything the reducer has grown into since
+    /// the snapshot was taken is zeroed
+    fn restore_memory(&mut self, snapshot: &[u8]) {
+        let data = self.runtime.memory_mut();
+        data[..snapshot.len()].copy_from_slice(snapshot);
+        data[snapshot.len()..].fill(0);
+    }
+
+    /// return the reducer to its freshly initialized state, given a snapshot
+    /// of its memory from then, so that another document can use it. along
+    /// with memory this clears what the host tracks for the last document
+    fn reset(&mut self, snapshot: &[u8]) {
+        self.restore_memory(snapshot);
+        self.host.reset();
+        self.statements = 0;
+        self.storage_full = false;
+        self.fuel_released = self.runtime.fuel_consumed().unwrap_or(0);
+    }
+
+    pub fn apply(&mut self, tx: &Connection, mutation: &[u8]) -> Result<()> {
+        self.apply_until(tx, mutation, || false)?;
+        Ok(())
+    }
+
+    /// apply a mutation until should_yield returns true, which is checked
+    /// each time the guest awaits a batch of sql requests after its first.
+    /// a guest which computes for a long time between requests runs until
+    /// its next one. a preempted reducer must be resumed (or abandoned)
+    /// before it's handed another mutation, and tx must stay open until then
+    pub fn apply_until(
+        &mut self,
+        tx: &Connection,
+        mutation: &[u8],
+        should_yield: impl FnMut() -> bool,
+    ) -> Result<Reduction> {
+        assert!(
+            self.preempted.is_none(),
+            "a preempted reducer must be resumed before it's given a mutation"
+        );
+        let result = self.reduce(tx, mutation, should_yield);
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
+    }
+
+    /// continue the mutation the reducer was preempted in, see apply_until
+    pub fn resume(
+        &mut self,
+        tx: &Connection,
+        should_yield: impl FnMut() -> bool,
+    ) -> Result<Reduction> {
+        let requests = self
+            .preempted
+            .take()
+            .expect("only a preempted reducer can be resumed");
+        let result = self.serve(tx, Some(requests), should_yield);
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
+    }
+
+    pub fn is_preempted(&self) -> bool {
+        self.preempted.is_some()
+    }
+
+    /// give up on the mutation the reducer was preempted in, e.g. because
+    /// its transaction rolled back. the guest never finishes it, so the
+    /// reducer is poisoned
+    pub fn abandon(&mut self) {
+        if self.preempted.take().is_some() {
+            self.poisoned = true;
+        }
+    }
+
+    /// run the reducer's task entry point, see init_reducer!
+    pub fn run_task(&mut self, tx: &Connection, name: &str) -> Result<()> {
+        // discard errors logged by the guest during previous mutations
+        self.host.take_last_error();
+        self.runtime.refuel();
+
+        let result = self
+            .runtime
+            .task(name)
+            .map_err(|e| {
+                let in_flight = InFlight::Task { name: name.to_owned() };
+                self.host.into_reducer_error(e, in_flight)
+            })
+            .and_then(|requests| self.serve(tx, requests, || false));
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result.map(|_| ())
+    }
+
+    fn reduce(
+        &mut self,
+        tx: &Connection,
+        mutation: &[u8],
+        should_yield: impl FnMut() -> bool,
+    ) -> Result<Reduction> {
+        // discard errors logged by the guest during previous mutations
+        self.host.take_last_error();
+        self.runtime.refuel();
+
+        // start the reducer
+        let requests = self.runtime.reduce(mutation).map_err(|e| {
+            let in_flight = InFlight::Reduce { mutation_len: mutation.len() };
+            self.host.into_reducer_error(e, in_flight)
+        })?;
+        self.serve(tx, requests, should_yield)
+    }
+
+    /// answer the guest's requests until it stops making them, or until
+    /// should_yield preempts it between two batches
+    fn serve(
+        &mut self,
+        tx: &Connection,
+        mut requests: Requests,
+        mut should_yield: impl FnMut() -> bool,
+    ) -> Result<Reduction> {
+        let mut served = false;
+        while let Some(requests_inner) = requests {
+            // always make progress, even if we were resumed late
+            if served && should_yield() {
+                self.preempted = Some(requests_inner);
+                return Ok(Reduction::Preempted);
+            }
+            served = true;
+
+            // process requests
+            let mut responses = Vec::with_capacity(requests_inner.len());
+            let mut in_flight = Vec::with_capacity(requests_inner.len());
+            for (id, req) in requests_inner {
+                in_flight.push(req.clone());
+                self.statements += 1;
+                let response = match req {
+                    Request::Query { sql, params } => {
+                        Response::Query(self.run_query(tx, &sql, params))
+                    }
+                    Request::Exec { sql, params } => {
+                        Response::Exec(self.run_exec(tx, &sql, params))
+                    }
+                };
+                responses.push((id, response));
+            }
+
+            // step the reactor forward
+            requests = self.runtime.reactor_step(responses).map_err(|e| {
+                let in_flight = InFlight::Step { requests: in_flight };
+                self.host.into_reducer_error(e, in_flight)
+            })?;
+        }
+
+        Ok(Reduction::Complete)
+    }
+
+    fn run_query(
+        &mut self,
+        tx: &Connection,
+        sql: &str,
+        params: Vec<SqliteValue>,
+    ) -> SqlResult<QueryResponse> {
+        logging::info!(
+            doc = self.host.log_context.doc_id();
+            "received query req: {}, {:?}", sql, params
+        );
+        let params =
+            params_from_iter(params.into_iter().map(from_sqlite_value));
+        let mut stmt =
+            tx.prepare(&sql).map_err(rusqlite_err_to_response_err)?;
+
+        let columns: Vec<String> = stmt
+            .column_names()
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        let num_columns = columns.len();
+
+        let start = unix_timestamp_milliseconds();
+
+        let rows = stmt
+            .query_and_then(params, move |row| {
+                (0..num_columns)
+                    .map(|i| Ok(to_sqlite_value(row.get_ref(i)?)))
+                    .collect::<std::result::Result<Row, rusqlite::Error>>()
+            })
+            .map_err(rusqlite_err_to_response_err)?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(rusqlite_err_to_response_err)?;
+
+        let end = unix_timestamp_milliseconds();
+        logging::info!(
+            doc = self.host.log_context.doc_id();
+            "query took {}ms", end - start
+        );
+
+        Ok(QueryResponse { columns, rows })
+    }
+
+    fn run_exec(
+        &mut self,
+        tx: &Connection,
+        sql: &str,
+        params: Vec<SqliteValue>,
+    ) -> SqlResult<ExecResponse> {
+        logging::info!(
+            doc = self.host.log_context.doc_id();
+            "received exec req: {}, {:?}", sql, params
+        );
+        let params =
+            params_from_iter(params.into_iter().map(from_sqlite_value));
+
+        let start = unix_timestamp_milliseconds();
+
+        let changes = tx.execute(&sql, params).map_err(|e| {
+            self.storage_full |=
+                e.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull);
+            rusqlite_err_to_response_err(e)
+        })?;
+
+        let end = unix_timestamp_milliseconds();
+        logging::info!(
+            doc = self.host.log_context.doc_id();
+            "exec took {}ms", end - start
+        );
+
+        Ok(ExecResponse { changes })
+    }
+}
+
+#[inline]
+fn from_sqlite_value(v: SqliteValue) -> Value {
+    match v {
+        SqliteValue::Null => Value::Null,
+        SqliteValue::Integer(i) => Value::Integer(i),
+        SqliteValue::Real(f) => Value::Real(f),
+        SqliteValue::Text(s) => Value::Text(s),
+        SqliteValue::Blob(b) => Value::Blob(b),
+    }
+}
+
+#[inline]
+fn to_sqlite_value(v: ValueRef) -> SqliteValue {
+    match v {
+        ValueRef::Null => SqliteValue::Null,
+        ValueRef::Integer(i) => SqliteValue::Integer(i),
+        ValueRef::Real(f) => SqliteValue::Real(f),
+        r @ ValueRef::Text(_) => {
+            SqliteValue::Text(r.as_str().unwrap().to_owned())
+        }
+        ValueRef::Blob(b) => SqliteValue::Blob(b.to_vec()),
+    }
+}
+
+fn rusqlite_err_to_response_err(e: rusqlite::Error) -> ErrorResponse {
+    match e {
+        rusqlite::Error::SqliteFailure(e, _) => ErrorResponse::SqliteError {
+            code: e.extended_code,
+            message: format!("{}", e),
+        },
+        other => ErrorResponse::Unknown(format!("{}", other)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+
+    use rusqlite::Connection;
+    use sqlsync_reducer::{
+        host_ffi::WasmFFIError,
+        types::{ReducerError as GuestReducerError, Request, Requests},
+    };
+
+    use super::{InFlight, Reducer, ReducerError, Reduction};
+
+    /// a reducer guest which runs the wat instructions in reduce for each
+    /// mutation and then finishes without making any requests. calling
+    /// $host_log with 3072 logs an error with the message "boom"
+    pub(crate) fn guest(reduce: &str) -> Vec<u8> {
+        exec_guest(reduce, "", 0)
+    }
+
+    /// like guest, but the reducer then awaits batches batches of requests,
+    /// each of which execs sql once
+    pub(crate) fn exec_guest(reduce: &str, sql: &str, batches: u32) -> Vec<u8> {
+        exec_guest_with(reduce, sql, batches, "")
+    }
+
+    /// like exec_guest, and declaring version as in reducer_version!
+    pub(crate) fn versioned_guest(sql: &str, version: u32) -> Vec<u8> {
+        let export = format!(
+            r#"(func (export "ffi_reducer_version") (result i32)
+                (i32.const {version}))"#
+        );
+        exec_guest_with("", sql, 1, &export)
+    }
+
+    /// exec_guest with extra module fields
+    fn exec_guest_with(
+        reduce: &str,
+        sql: &str,
+        batches: u32,
+        extra: &str,
+    ) -> Vec<u8> {
+        let hex = |bytes: &[u8]| -> String {
+            bytes.iter().map(|b| format!("\\{:02x}", b)).collect()
+        };
+        let record =
+            bincode::serialize(&("ERROR", "boom", None::<String>, None::<u32>))
+                .unwrap();
+        let request = Request::Exec { sql: sql.to_owned(), params: vec![] };
+        let requests = bincode::serialize(&Ok::<Requests, GuestReducerError>(
+            Some(BTreeMap::from([(0, request)])),
+        ))
+        .unwrap();
+        wat::parse_str(format!(
+            r#"(module
+                (import "env" "host_log" (func $host_log (param i32)))
+                (memory (export "memory") 1)
+                (global $batches (mut i32) (i32.const 0))
+                ;; Ok(None) in bincode: no requests
+                (data (i32.const 2048) "\00\00\00\00\00")
+                (data (i32.const 3072) "{record}")
+                (data (i32.const 4096) "{requests}")
+                (func (export "ffi_buf_allocate") (param i32) (result i32)
+                    i32.const 1024)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32)
+                    (select
+                        (i32.const {record_len})
+                        (select
+                            (i32.const {requests_len})
+                            (i32.const 5)
+                            (i32.eq (local.get 0) (i32.const 4096)))
+                        (i32.eq (local.get 0) (i32.const 3072))))
+                (func (export "ffi_init_reducer"))
+                (func $next (result i32)
+                    (if (result i32) (i32.eqz (global.get $batches))
+                        (then (i32.const 2048))
+                        (else
+                            (global.set $batches
+                                (i32.sub (global.get $batches) (i32.const 1)))
+                            (i32.const 4096))))
+                (func (export "ffi_reduce") (param i32) (result i32)
+                    {reduce}
+                    (global.set $batches (i32.const {batches}))
+                    (call $next))
+                (func (export "ffi_reactor_step") (param i32) (result i32)
+                    (call $next))
+                {extra})"#,
+            record = hex(&record),
+            record_len = record.len(),
+            requests = hex(&requests),
+            requests_len = requests.len(),
+        ))
+        .unwrap()
+    }
+
+    pub(crate) fn apply(
+        reducer: &mut Reducer,
+        mutation: &[u8],
+    ) -> Result<(), ReducerError> {
+        let conn = Connection::open_in_memory().unwrap();
+        reducer.apply(&conn, mutation)
+    }
+
+    #[test]
+    fn traps_report_the_panic_and_the_mutation() {
+        let mut reducer = Reducer::new(&guest("")[..]).unwrap();
+        apply(&mut reducer, b"mutation").unwrap();
+        assert!(!reducer.poisoned);
+
+        let wasm = guest("(call $host_log (i32.const 3072)) unreachable");
+        let mut reducer = Reducer::new(&wasm[..]).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        let ReducerError::Trap(trap) = err else {
+            panic!("expected a trap, got {:?}", err);
+        };
+        assert!(matches!(
+            trap.in_flight,
+            InFlight::Reduce { mutation_len: 8 }
+        ));
+        assert_eq!(trap.panic.as_deref(), Some("boom"));
+        assert!(reducer.poisoned);
+
+        let err = crate::error::Error::from(ReducerError::Trap(trap));
+        assert_eq!(err.reducer_trap().unwrap().panic.as_deref(), Some("boom"));
+    }
+
+    #[test]
+    fn buffers_outside_guest_memory_are_errors() {
+        // ffi_buf_len says the buffer is 5 bytes long, which runs past the
+        // end of the guest's address space
+        let wasm = guest("(return (i32.const -1))");
+        let mut reducer = Reducer::new(&wasm[..]).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ReducerError::Interface(WasmFFIError::OutOfBounds {
+                    ptr: u32::MAX,
+                    len: 5
+                })
+            ),
+            "{:?}",
+            err
+        );
+
+        // and past the end of its memory
+        let wasm = guest("(return (i32.const 65534))");
+        let mut reducer = Reducer::new(&wasm[..]).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ReducerError::Interface(WasmFFIError::OutOfBounds { .. })
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn reducers_may_declare_a_version() {
+        assert_eq!(Reducer::new(&guest("")[..]).unwrap().version(), None);
+        let wasm = versioned_guest("", 7);
+        assert_eq!(Reducer::new(&wasm[..]).unwrap().version(), Some(7));
+    }
+
+    #[test]
+    fn fuel_is_only_metered_with_a_limit() {
+        let wasm = guest("(i32.store8 (i32.const 100) (i32.const 7))");
+        let mut reducer = Reducer::new(&wasm[..]).unwrap();
+        apply(&mut reducer, b"mutation").unwrap();
+        assert_eq!(reducer.memory_stats().fuel_consumed, None);
+
+        let mut reducer =
+            Reducer::with_fuel_limit(&wasm[..], Some(1_000_000)).unwrap();
+        apply(&mut reducer, b"mutation").unwrap();
+        let before = reducer.memory_stats().fuel_consumed.unwrap();
+        apply(&mut reducer, b"mutation").unwrap();
+        let per_mutation =
+            reducer.memory_stats().fuel_consumed.unwrap() - before;
+        assert!(per_mutation > 0);
+
+        // every mutation gets the full limit
+        let mut reducer =
+            Reducer::with_fuel_limit(&wasm[..], Some(per_mutation)).unwrap();
+        for _ in 0..3 {
+            apply(&mut reducer, b"mutation").unwrap();
+        }
+
+        let wasm = guest("(loop $spin (br $spin))");
+        let mut reducer =
+            Reducer::with_fuel_limit(&wasm[..], Some(1_000)).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        assert!(matches!(err, ReducerError::Trap(_)), "{:?}", err);
+        assert!(reducer.poisoned);
+    }
+
+    #[test]
+    fn preempted_reductions_resume_where_they_left_off() {
+        let wasm = exec_guest("", "INSERT INTO t VALUES (1)", 3);
+        let mut reducer = Reducer::new(&wasm[..]).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (x)").unwrap();
+        let count = || -> i64 {
+            conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        // the first batch is always served
+        let reduction = reducer.apply_until(&conn, b"m", || true).unwrap();
+        assert_eq!(reduction, Reduction::Preempted);
+        assert!(reducer.is_preempted());
+        assert_eq!(count(), 1);
+
+        assert_eq!(
+            reducer.resume(&conn, || true).unwrap(),
+            Reduction::Preempted
+        );
+        assert_eq!(count(), 2);
+        assert_eq!(
+            reducer.resume(&conn, || false).unwrap(),
+            Reduction::Complete
+        );
+        assert_eq!(count(), 3);
+        assert!(!reducer.is_preempted());
+
+        // abandoning a preempted mutation poisons the reducer
+        reducer.apply_until(&conn, b"m", || true).unwrap();
+        reducer.abandon();
+        assert!(!reducer.is_preempted());
+        assert!(reducer.poisoned);
+    }
+}
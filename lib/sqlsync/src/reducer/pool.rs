@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use wasmi::{Engine, Module};
+
+use super::{new_engine, Reducer, Result};
+
+pub type ModuleDigest = [u8; 32];
+
+struct PoolEntry {
+    module: Module,
+    /// linear memory of a freshly initialized instance of this module
+    snapshot: Vec<u8>,
+    idle: Vec<Reducer>,
+}
+
+/// ReducerPool allows a coordinator hosting many documents to share the work
+/// of compiling and initializing reducers. Modules are cached by the sha256
+/// digest of their wasm bytes and released instances are reset to their
+/// freshly initialized state and handed out again.
+pub struct ReducerPool {
+    engine: Engine,
+    entries: HashMap<ModuleDigest, PoolEntry>,
+    max_idle_per_module: usize,
+    fuel_limit: Option<u64>,
+}
+
+impl ReducerPool {
+    pub fn new(max_idle_per_module: usize) -> Self {
+        Self::with_fuel_limit(max_idle_per_module, None)
+    }
+
+    /// a pool whose reducers all have fuel_limit, see
+    /// Reducer::with_fuel_limit
+    pub fn with_fuel_limit(
+        max_idle_per_module: usize,
+        fuel_limit: Option<u64>,
+    ) -> Self {
+        Self {
+            engine: new_engine(fuel_limit.is_some()),
+            entries: HashMap::new(),
+            max_idle_per_module,
+            fuel_limit,
+        }
+    }
+
+    pub fn digest(wasm_bytes: &[u8]) -> ModuleDigest {
+        Sha256::digest(wasm_bytes).into()
+    }
+
+    /// compile and cache the module if we haven't seen it before
+    pub fn load(&mut self, wasm_bytes: &[u8]) -> Result<ModuleDigest> {
+        let digest = Self::digest(wasm_bytes);
+        if !self.entries.contains_key(&digest) {
+            let module = Module::new(&self.engine, wasm_bytes)?;
+
+            // run an instance through initialization to capture the memory
+            // state every released instance will be reset to
+            let mut reducer =
+                Reducer::instantiate(&self.engine, &module, self.fuel_limit)?;
+            reducer.module_digest = Some(digest);
+            let snapshot = reducer.snapshot_memory();
+
+            self.entries.insert(
+                digest,
+                PoolEntry { module, snapshot, idle: vec![reducer] },
+            );
+        }
+        Ok(digest)
+    }
+
+    /// acquire a reducer for the provided wasm, reusing an idle instance if
+    /// one is available
+    pub fn acquire(&mut self, wasm_bytes: &[u8]) -> Result<Reducer> {
+        let digest = self.load(wasm_bytes)?;
+        let entry = self
+            .entries
+            .get_mut(&digest)
+            .expect("module was just loaded");
+
+        match entry.idle.pop() {
+            Some(reducer) => Ok(reducer),
+            None => {
+                let mut reducer = Reducer::instantiate(
+                    &self.engine,
+                    &entry.module,
+                    self.fuel_limit,
+                )?;
+                reducer.module_digest = Some(digest);
+                Ok(reducer)
+            }
+        }
+    }
+
+    /// return a reducer to the pool once the document using it has closed.
+    /// reducers which didn't come from this pool, or which failed to apply a
+    /// mutation, are dropped
+    pub fn release(&mut self, mut reducer: Reducer) {
+        if reducer.poisoned {
+            return;
+        }
+        let Some(entry) = reducer
+            .module_digest
+            .and_then(|digest| self.entries.get_mut(&digest))
+        else {
+            return;
+        };
+        if entry.idle.len() < self.max_idle_per_module {
+            reducer.reset(&entry.snapshot);
+            entry.idle.push(reducer);
+        }
+    }
+
+    /// drop idle instances and cached modules
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModuleDigest, ReducerPool};
+    use crate::{
+        reducer::tests::{apply, guest},
+        JournalId,
+    };
+
+    impl ReducerPool {
+        fn idle(&self, digest: &ModuleDigest) -> usize {
+            self.entries.get(digest).map_or(0, |entry| entry.idle.len())
+        }
+    }
+
+    #[test]
+    fn released_reducers_are_reset_and_reused() {
+        let wasm = guest("(i32.store8 (i32.const 100) (i32.const 7))");
+        let digest = ReducerPool::digest(&wasm);
+        let mut pool = ReducerPool::with_fuel_limit(1, Some(1_000_000));
+
+        // loading compiles once and keeps the initialized instance idle
+        assert_eq!(pool.load(&wasm).unwrap(), digest);
+        pool.load(&wasm).unwrap();
+        assert_eq!(pool.idle(&digest), 1);
+
+        let mut reducer = pool.acquire(&wasm).unwrap();
+        assert_eq!(pool.idle(&digest), 0);
+        reducer.set_doc_id(JournalId::new128(&mut rand::thread_rng()));
+        apply(&mut reducer, b"mutation").unwrap();
+        assert_eq!(reducer.runtime.memory()[100], 7);
+        assert_eq!(reducer.statements_executed(), 0);
+
+        pool.release(reducer);
+        let reducer = pool.acquire(&wasm).unwrap();
+        assert_eq!(reducer.module_digest(), Some(digest));
+        assert_eq!(reducer.runtime.memory()[100], 0);
+        assert_eq!(reducer.host.log_context.doc_id(), None);
+        assert_eq!(reducer.memory_stats().fuel_consumed, Some(0));
+
+        // only max_idle_per_module instances are kept
+        let other = pool.acquire(&wasm).unwrap();
+        pool.release(reducer);
+        pool.release(other);
+        assert_eq!(pool.idle(&digest), 1);
+
+        pool.clear();
+        assert_eq!(pool.idle(&digest), 0);
+    }
+
+    #[test]
+    fn poisoned_reducers_are_dropped() {
+        let wasm = guest("unreachable");
+        let mut pool = ReducerPool::new(4);
+        let mut reducer = pool.acquire(&wasm).unwrap();
+        assert!(apply(&mut reducer, b"mutation").is_err());
+        pool.release(reducer);
+        assert_eq!(pool.idle(&ReducerPool::digest(&wasm)), 0);
+    }
+}
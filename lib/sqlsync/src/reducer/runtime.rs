@@ -0,0 +1,101 @@
+use sqlsync_reducer::{
+    host_ffi::WasmFFIError,
+    types::{ErrorResponse, ExecResponse, QueryResponse, RequestId, Requests},
+};
+use wasmi::errors::LinkerError;
+
+use super::ReducerError;
+
+/// Response is the host's answer to a single guest request
+pub enum Response {
+    Query(Result<QueryResponse, ErrorResponse>),
+    Exec(Result<ExecResponse, ErrorResponse>),
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// the guest trapped; the Reducer adds the remaining context before
+    /// surfacing this as a ReducerError::Trap
+    Trap {
+        message: String,
+        backtrace: Vec<String>,
+    },
+
+    Reducer(ReducerError),
+}
+
+impl From<ReducerError> for RuntimeError {
+    fn from(err: ReducerError) -> Self {
+        RuntimeError::Reducer(err)
+    }
+}
+
+impl From<LinkerError> for RuntimeError {
+    fn from(err: LinkerError) -> Self {
+        RuntimeError::Reducer(err.into())
+    }
+}
+
+impl From<wasmi::Error> for RuntimeError {
+    fn from(err: wasmi::Error) -> Self {
+        WasmFFIError::from(err).into()
+    }
+}
+
+impl From<WasmFFIError> for RuntimeError {
+    fn from(err: WasmFFIError) -> Self {
+        match err {
+            WasmFFIError::WasmError(wasmi::Error::Trap(trap))
+                if trap.trap_code().is_some() =>
+            {
+                RuntimeError::Trap {
+                    message: trap.to_string(),
+                    backtrace: Vec::new(),
+                }
+            }
+            other => RuntimeError::Reducer(other.into()),
+        }
+    }
+}
+
+pub type RuntimeResult<T> = Result<T, RuntimeError>;
+
+/// how much memory guests may keep around between calls to reuse as
+/// request and response buffers
+pub(crate) const FFI_BUF_POOL_BYTES: u32 = 256 * 1024;
+
+/// ReducerRuntime abstracts over the wasm engine which executes a reducer
+/// guest. Implementations are responsible for instantiating the guest,
+/// exposing the sqlsync host functions to it, and calling ffi_init_reducer
+/// (followed by ffi_buf_pool, if the guest exports it) before they are
+/// handed to a Reducer.
+pub trait ReducerRuntime: Send {
+    /// hand a mutation to the guest, returning its first batch of requests
+    fn reduce(&mut self, mutation: &[u8]) -> RuntimeResult<Requests>;
+
+    /// run the guest's scheduled task entry point with the given task name
+    fn task(&mut self, name: &str) -> RuntimeResult<Requests>;
+
+    /// resume the guest with responses to the requests it is waiting on
+    fn reactor_step(
+        &mut self,
+        responses: Vec<(RequestId, Response)>,
+    ) -> RuntimeResult<Requests>;
+
+    /// the guest's linear memory
+    fn memory(&self) -> &[u8];
+
+    fn memory_mut(&mut self) -> &mut [u8];
+
+    /// fuel consumed by the guest since it was instantiated, if the runtime
+    /// has a fuel limit
+    fn fuel_consumed(&self) -> Option<u64>;
+
+    /// top the guest's fuel back up to the runtime's fuel limit, if it has
+    /// one, before it starts on a mutation or task
+    fn refuel(&mut self);
+
+    /// the version the guest declared with reducer_version!, read when it
+    /// was instantiated
+    fn version(&self) -> Option<u32>;
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use sqlsync_reducer::{
+    host_ffi::{register_log_handler_with, WasmFFI},
+    types::{RequestId, Requests},
+};
+use wasmi::{Engine, Linker, Memory, Module, Store};
+
+use super::{
+    runtime::FFI_BUF_POOL_BYTES, GuestHost, ReducerRuntime, Response,
+    RuntimeResult,
+};
+
+/// WasmiRuntime runs reducers on the wasmi interpreter, which works
+/// everywhere including inside the browser
+pub(crate) struct WasmiRuntime {
+    store: Store<WasmFFI>,
+    fuel_limit: Option<u64>,
+    /// all the fuel ever added to the store, which wasmi only tracks as
+    /// fuel consumed
+    fuel_added: u64,
+    version: Option<u32>,
+}
+
+impl WasmiRuntime {
+    /// engine must meter fuel if fuel_limit is set
+    pub fn instantiate(
+        engine: &Engine,
+        module: &Module,
+        host: GuestHost,
+        fuel_limit: Option<u64>,
+    ) -> RuntimeResult<Self> {
+        let mut linker = Linker::new(engine);
+        register_log_handler_with(&mut linker, move |record| {
+            host.handle_log(record)
+        })?;
+
+        let mut store = Store::new(engine, WasmFFI::uninitialized());
+        // initialization gets the same budget as a mutation
+        if let Some(limit) = fuel_limit {
+            store
+                .add_fuel(limit)
+                .expect("engines meter fuel for reducers with a fuel limit");
+        }
+        let instance =
+            linker.instantiate(&mut store, module)?.start(&mut store)?;
+
+        // initialize the FFI
+        let ffi = WasmFFI::initialized(&store, &instance)?;
+        (*store.data_mut()) = ffi;
+
+        // initialize the reducer
+        ffi.init_reducer(&mut store)?;
+        ffi.negotiate_buf_pool(&mut store, FFI_BUF_POOL_BYTES)?;
+        let version = ffi.reducer_version(&mut store)?;
+
+        Ok(Self {
+            store,
+            fuel_limit,
+            fuel_added: fuel_limit.unwrap_or(0),
+            version,
+        })
+    }
+
+    fn ffi_memory(&self) -> Option<Memory> {
+        match self.store.data() {
+            WasmFFI::Uninitialized => None,
+            WasmFFI::Initialized { memory, .. } => Some(*memory),
+        }
+    }
+}
+
+impl ReducerRuntime for WasmiRuntime {
+    fn reduce(&mut self, mutation: &[u8]) -> RuntimeResult<Requests> {
+        let ffi = *self.store.data();
+        Ok(ffi.reduce(&mut self.store, mutation)?)
+    }
+
+    fn task(&mut self, name: &str) -> RuntimeResult<Requests> {
+        let ffi = *self.store.data();
+        Ok(ffi.task(&mut self.store, name)?)
+    }
+
+    fn reactor_step(
+        &mut self,
+        responses: Vec<(RequestId, Response)>,
+    ) -> RuntimeResult<Requests> {
+        let ffi = *self.store.data();
+        let mut ptrs = BTreeMap::new();
+        for (id, response) in responses {
+            let ptr = match response {
+                Response::Query(r) => ffi.encode(&mut self.store, &r)?,
+                Response::Exec(r) => ffi.encode(&mut self.store, &r)?,
+            };
+            ptrs.insert(id, ptr);
+        }
+        Ok(ffi.reactor_step(&mut self.store, Some(ptrs))?)
+    }
+
+    fn memory(&self) -> &[u8] {
+        match self.ffi_memory() {
+            Some(memory) => memory.data(&self.store),
+            None => &[],
+        }
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        match self.ffi_memory() {
+            Some(memory) => memory.data_mut(&mut self.store),
+            None => &mut [],
+        }
+    }
+
+    fn fuel_consumed(&self) -> Option<u64> {
+        self.store.fuel_consumed()
+    }
+
+    fn refuel(&mut self) {
+        let (Some(limit), Some(consumed)) =
+            (self.fuel_limit, self.store.fuel_consumed())
+        else {
+            return;
+        };
+        let topup = limit - (self.fuel_added - consumed);
+        self.store
+            .add_fuel(topup)
+            .expect("engines meter fuel for reducers with a fuel limit");
+        self.fuel_added += topup;
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
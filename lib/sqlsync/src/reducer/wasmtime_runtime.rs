@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+use sqlsync_reducer::{
+    host_ffi::{guest_buf, guest_buf_mut, WasmFFIError},
+    types::{
+        LogRecord, ReducerError as GuestReducerError, RequestId, Requests,
+    },
+};
+use wasmtime::{
+    AsContext, AsContextMut, Caller, Config, Engine, Linker, Memory, Module,
+    Store, Trap, TypedFunc, WasmBacktrace,
+};
+
+use super::{
+    runtime::FFI_BUF_POOL_BYTES, GuestHost, ReducerError, ReducerRuntime,
+    Response, RuntimeError, RuntimeResult,
+};
+
+#[derive(Clone, Copy)]
+struct Exports {
+    memory: Memory,
+    ffi_buf_allocate: TypedFunc<u32, u32>,
+    ffi_buf_deallocate: TypedFunc<u32, ()>,
+    ffi_buf_len: TypedFunc<u32, u32>,
+    ffi_buf_pool: Option<TypedFunc<u32, u32>>,
+    ffi_reduce: TypedFunc<u32, u32>,
+    ffi_reactor_step: TypedFunc<u32, u32>,
+    ffi_task: Option<TypedFunc<u32, u32>>,
+}
+
+impl Exports {
+    fn persist(
+        &self,
+        mut store: impl AsContextMut,
+        buf: &[u8],
+    ) -> wasmtime::Result<u32> {
+        let len = buf.len() as u32;
+        let ptr = self.ffi_buf_allocate.call(&mut store, len)?;
+        let mem = self.memory.data_mut(store.as_context_mut());
+        guest_buf_mut(mem, ptr, len)?.copy_from_slice(buf);
+        Ok(ptr)
+    }
+
+    /// decode the buffer in place, then release it back to the guest
+    fn decode<T: DeserializeOwned>(
+        &self,
+        mut store: impl AsContextMut,
+        ptr: u32,
+    ) -> wasmtime::Result<T> {
+        let len = self.ffi_buf_len.call(&mut store, ptr)?;
+        let mem = self.memory.data(store.as_context());
+        let out = bincode::deserialize(guest_buf(mem, ptr, len)?);
+        self.ffi_buf_deallocate.call(&mut store, ptr)?;
+        Ok(out?)
+    }
+
+    /// encode data straight into a buffer allocated by the guest
+    fn encode<T: Serialize>(
+        &self,
+        mut store: impl AsContextMut,
+        data: &T,
+    ) -> wasmtime::Result<u32> {
+        let len = bincode::serialized_size(data)? as u32;
+        let ptr = self.ffi_buf_allocate.call(&mut store, len)?;
+        let mem = self.memory.data_mut(store.as_context_mut());
+        bincode::serialize_into(guest_buf_mut(mem, ptr, len)?, data)?;
+        Ok(ptr)
+    }
+}
+
+struct State {
+    host: GuestHost,
+    exports: Option<Exports>,
+}
+
+/// WasmtimeRuntime runs reducers on the Wasmtime JIT. It's considerably
+/// faster than wasmi for heavy mutations but only available natively.
+pub(crate) struct WasmtimeRuntime {
+    store: Store<State>,
+    exports: Exports,
+    fuel_limit: Option<u64>,
+    /// fuel consumed before the store was last refueled, which is when
+    /// wasmtime forgets it
+    fuel_spent: u64,
+    version: Option<u32>,
+}
+
+impl WasmtimeRuntime {
+    pub fn new(
+        wasm_bytes: &[u8],
+        host: GuestHost,
+        fuel_limit: Option<u64>,
+    ) -> RuntimeResult<Self> {
+        let mut config = Config::new();
+        config.wasm_backtrace(true);
+        config.consume_fuel(fuel_limit.is_some());
+        let engine = Engine::new(&config).map_err(classify)?;
+        let module = Module::new(&engine, wasm_bytes).map_err(classify)?;
+
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "host_log",
+                |mut caller: Caller<'_, State>,
+                 record_ptr: u32|
+                 -> wasmtime::Result<()> {
+                    let exports = caller
+                        .data()
+                        .exports
+                        .ok_or(WasmFFIError::Uninitialized)?;
+                    let record: LogRecord =
+                        exports.decode(&mut caller, record_ptr)?;
+                    caller.data().host.handle_log(&record);
+                    Ok(())
+                },
+            )
+            .map_err(classify)?;
+
+        let mut store = Store::new(&engine, State { host, exports: None });
+        // initialization gets the same budget as a mutation
+        if let Some(limit) = fuel_limit {
+            store.set_fuel(limit).map_err(classify)?;
+        }
+        let instance =
+            linker.instantiate(&mut store, &module).map_err(classify)?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or(WasmFFIError::MemoryNotFound)?;
+        let exports = Exports {
+            memory,
+            ffi_buf_allocate: instance
+                .get_typed_func(&mut store, "ffi_buf_allocate")
+                .map_err(classify)?,
+            ffi_buf_deallocate: instance
+                .get_typed_func(&mut store, "ffi_buf_deallocate")
+                .map_err(classify)?,
+            ffi_buf_len: instance
+                .get_typed_func(&mut store, "ffi_buf_len")
+                .map_err(classify)?,
+            ffi_buf_pool: instance
+                .get_typed_func(&mut store, "ffi_buf_pool")
+                .ok(),
+            ffi_reduce: instance
+                .get_typed_func(&mut store, "ffi_reduce")
+                .map_err(classify)?,
+            ffi_reactor_step: instance
+                .get_typed_func(&mut store, "ffi_reactor_step")
+                .map_err(classify)?,
+            ffi_task: instance.get_typed_func(&mut store, "ffi_task").ok(),
+        };
+        store.data_mut().exports = Some(exports);
+
+        // initialize the reducer
+        instance
+            .get_typed_func::<(), ()>(&mut store, "ffi_init_reducer")
+            .and_then(|init| init.call(&mut store, ()))
+            .map_err(classify)?;
+        if let Some(ffi_buf_pool) = exports.ffi_buf_pool {
+            ffi_buf_pool
+                .call(&mut store, FFI_BUF_POOL_BYTES)
+                .map_err(classify)?;
+        }
+        let version = match instance
+            .get_typed_func::<(), u32>(&mut store, "ffi_reducer_version")
+        {
+            Ok(ffi_reducer_version) => Some(
+                ffi_reducer_version.call(&mut store, ()).map_err(classify)?,
+            ),
+            Err(_) => None,
+        };
+
+        Ok(Self { store, exports, fuel_limit, fuel_spent: 0, version })
+    }
+
+    fn decode_requests(&mut self, ptr: u32) -> RuntimeResult<Requests> {
+        let requests: Result<Requests, GuestReducerError> = self
+            .exports
+            .decode(&mut self.store, ptr)
+            .map_err(classify)?;
+        Ok(requests.map_err(WasmFFIError::from)?)
+    }
+}
+
+impl ReducerRuntime for WasmtimeRuntime {
+    fn reduce(&mut self, mutation: &[u8]) -> RuntimeResult<Requests> {
+        let exports = self.exports;
+        let requests_ptr = exports
+            .persist(&mut self.store, mutation)
+            .and_then(|ptr| exports.ffi_reduce.call(&mut self.store, ptr))
+            .map_err(classify)?;
+        self.decode_requests(requests_ptr)
+    }
+
+    fn task(&mut self, name: &str) -> RuntimeResult<Requests> {
+        let exports = self.exports;
+        let ffi_task = exports.ffi_task.ok_or(WasmFFIError::TaskNotExported)?;
+        let requests_ptr = exports
+            .persist(&mut self.store, name.as_bytes())
+            .and_then(|ptr| ffi_task.call(&mut self.store, ptr))
+            .map_err(classify)?;
+        self.decode_requests(requests_ptr)
+    }
+
+    fn reactor_step(
+        &mut self,
+        responses: Vec<(RequestId, Response)>,
+    ) -> RuntimeResult<Requests> {
+        let exports = self.exports;
+        let mut ptrs = BTreeMap::new();
+        for (id, response) in responses {
+            let ptr = match response {
+                Response::Query(r) => exports.encode(&mut self.store, &r),
+                Response::Exec(r) => exports.encode(&mut self.store, &r),
+            };
+            ptrs.insert(id, ptr.map_err(classify)?);
+        }
+
+        let requests_ptr = exports
+            .encode(&mut self.store, &Some(ptrs))
+            .and_then(|ptr| exports.ffi_reactor_step.call(&mut self.store, ptr))
+            .map_err(classify)?;
+        self.decode_requests(requests_ptr)
+    }
+
+    fn memory(&self) -> &[u8] {
+        self.exports.memory.data(&self.store)
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        self.exports.memory.data_mut(&mut self.store)
+    }
+
+    fn fuel_consumed(&self) -> Option<u64> {
+        let limit = self.fuel_limit?;
+        let remaining = self.store.get_fuel().ok()?;
+        Some(self.fuel_spent + (limit - remaining))
+    }
+
+    fn refuel(&mut self) {
+        let Some(limit) = self.fuel_limit else { return };
+        self.fuel_spent = self.fuel_consumed().unwrap_or(self.fuel_spent);
+        self.store
+            .set_fuel(limit)
+            .expect("engines meter fuel for reducers with a fuel limit");
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+fn classify(err: wasmtime::Error) -> RuntimeError {
+    match err.downcast_ref::<Trap>() {
+        Some(trap) => {
+            let backtrace = err
+                .downcast_ref::<WasmBacktrace>()
+                .map(|bt| {
+                    bt.frames()
+                        .iter()
+                        .map(|frame| match frame.func_name() {
+                            Some(name) => name.to_owned(),
+                            None => {
+                                format!(
+                                    "<wasm function {}>",
+                                    frame.func_index()
+                                )
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            RuntimeError::Trap { message: trap.to_string(), backtrace }
+        }
+        None => RuntimeError::Reducer(ReducerError::Wasmtime(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlsync_reducer::host_ffi::WasmFFIError;
+
+    use crate::reducer::{
+        tests::{apply, guest},
+        InFlight, Reducer, ReducerError,
+    };
+
+    #[test]
+    fn runs_and_traps_like_wasmi() {
+        let wasm = guest("(i32.store8 (i32.const 100) (i32.const 7))");
+        let mut reducer = Reducer::new_wasmtime(&wasm, None).unwrap();
+        apply(&mut reducer, b"mutation").unwrap();
+        assert_eq!(reducer.runtime.memory()[100], 7);
+
+        let wasm = guest("(call $host_log (i32.const 3072)) unreachable");
+        let mut reducer = Reducer::new_wasmtime(&wasm, None).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        let ReducerError::Trap(trap) = err else {
+            panic!("expected a trap, got {:?}", err);
+        };
+        assert!(matches!(trap.in_flight, InFlight::Reduce { .. }));
+        assert_eq!(trap.panic.as_deref(), Some("boom"));
+        // unlike wasmi, wasmtime captures where the guest trapped
+        assert!(!trap.backtrace.is_empty());
+
+        let wasm = guest("(loop $spin (br $spin))");
+        let mut reducer = Reducer::new_wasmtime(&wasm, Some(1_000)).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        assert!(matches!(err, ReducerError::Trap(_)), "{:?}", err);
+        assert!(reducer.memory_stats().fuel_consumed >= Some(1_000));
+    }
+
+    #[test]
+    fn buffers_outside_guest_memory_are_errors() {
+        let wasm = guest("(return (i32.const -1))");
+        let mut reducer = Reducer::new_wasmtime(&wasm, None).unwrap();
+        let err = apply(&mut reducer, b"mutation").unwrap_err();
+        let ReducerError::Wasmtime(err) = err else {
+            panic!("expected a wasmtime error, got {:?}", err);
+        };
+        assert!(matches!(
+            err.downcast_ref::<WasmFFIError>(),
+            Some(WasmFFIError::OutOfBounds { ptr: u32::MAX, len: 5 })
+        ));
+    }
+}
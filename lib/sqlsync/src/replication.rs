@@ -1,14 +1,47 @@
-use std::{cmp, io};
+use std::{
+    cmp,
+    collections::BTreeMap,
+    io::{self, Read},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{lsn::LsnRange, positioned_io::PositionedReader, JournalError, JournalId, Lsn};
+use crate::{
+    config::DocumentConfig,
+    logging,
+    lsn::LsnRange,
+    positioned_io::PositionedReader,
+    reducer::ModuleDigest,
+    resync::{TreeHash, TreeNode},
+    snapshot::Checkpoint,
+    unixtime::HlcTimestamp,
+    Deserializable, JournalError, JournalId, Lsn,
+};
 
-// maximum number of frames we will send without receiving an acknowledgement
+// default number of frames we will send without receiving an acknowledgement
 // note: this does not affect durability, as we keep don't truncate the source journal until rebase
 const MAX_OUTSTANDING_FRAMES: usize = 100;
 
+// upper bound on the window a destination may ask for, and on the number of
+// out of order frames a destination will buffer
+const MAX_WINDOW: usize = 1000;
+
+// the largest frame a destination will accept. a storage frame holds the
+// pages of a commit, so this also caps how much a single commit may write
+pub const MAX_FRAME_BYTES: u64 = 256 * 1024 * 1024;
+
+// upper bound on the bytes of out of order frames a destination will buffer
+const MAX_REORDERED_BYTES: usize = 64 * 1024 * 1024;
+
+// a reducer is sent in chunks of at most this many bytes, keeping each
+// message well under the frame limits of websocket servers and proxies
+pub const REDUCER_CHUNK_BYTES: usize = 256 * 1024;
+
+// the largest reducer a destination will reassemble
+const MAX_REDUCER_BYTES: u64 = 64 * 1024 * 1024;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ReplicationMsg {
     /// request the lsn range of the specified journal
@@ -20,6 +53,87 @@ pub enum ReplicationMsg {
     Range { range: LsnRange },
     /// send one LSN frame from the specified journal
     Frame { id: JournalId, lsn: Lsn, len: u64 },
+    /// sent by a coordinator which is shutting down; the receiver should
+    /// disconnect and reconnect once retry_after_ms has elapsed
+    ReconnectLater { retry_after_ms: u32 },
+    /// sent by the coordinator during the handshake so that the receiver's
+    /// hybrid clock stays within skew of the coordinator's
+    Clock { timestamp: HlcTimestamp },
+    /// liveness probe, must be answered with a Pong carrying the same nonce
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+    /// sent by the coordinator during the handshake, see DocumentConfig
+    Config { config: DocumentConfig },
+    /// sent before the sender closes the connection due to an error
+    Error { error: ProtocolError },
+    /// sent by a destination to change how many unacknowledged frames the
+    /// source may send it; a larger window speeds up the initial sync over
+    /// high latency links, and a window of 0 pauses the source until the
+    /// destination opens it again
+    Window { frames: u32 },
+    /// sent by the coordinator during the handshake, the sha256 digest of
+    /// the wasm its reducer was loaded from and the version the reducer
+    /// declared. a client running an older reducer can't apply new
+    /// mutations the way the coordinator will
+    Reducer { digest: ModuleDigest, version: Option<u32> },
+    /// ask the coordinator for the wasm of the reducer with this digest
+    ReducerRequest { digest: ModuleDigest },
+    /// one chunk of the reply to a ReducerRequest, followed by len bytes of
+    /// wasm from offset. the reducer is total bytes long, and its chunks are
+    /// sent in order
+    ReducerWasm { digest: ModuleDigest, offset: u64, total: u64, len: u64 },
+    /// sent by the coordinator before it starts replicating once its
+    /// document has been redacted. a receiver from an older epoch must drop
+    /// its copy of the document's history, see the redaction module
+    Epoch { epoch: u64 },
+    /// ask the coordinator for the hashes of these nodes of its page tree,
+    /// see resync
+    TreeRequest { nodes: Vec<TreeNode> },
+    /// the reply to a TreeRequest, with the storage lsn the tree is as of
+    TreeHashes { lsn: Option<Lsn>, hashes: Vec<TreeHash> },
+    /// ask the coordinator for the pages in these leaves of its page tree
+    RepairRequest { leaves: Vec<TreeNode> },
+    /// the reply to a RepairRequest, followed by len bytes of Checkpoint
+    /// holding the coordinator's pages in leaves
+    Repair { leaves: Vec<TreeNode>, len: u64 },
+    /// sent by a source in place of frames the destination needs which have
+    /// been compacted away. the destination must drop its copy of the
+    /// journal, and the source continues from its first frame, which holds
+    /// every page as of its lsn (see compaction)
+    Reset { id: JournalId },
+    /// ask the coordinator for the document as of a storage lsn the
+    /// receiver no longer has, see LocalDocument::request_history
+    HistoryRequest { lsn: Lsn },
+    /// the reply to a HistoryRequest, followed by len bytes of Checkpoint.
+    /// len is 0 if the coordinator doesn't retain lsn either
+    History { lsn: Lsn, len: u64 },
+}
+
+/// ProtocolError tells the remote side why its connection is being closed,
+/// and whether reconnecting could help
+#[derive(Error, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("authentication failed")]
+    AuthFailed,
+
+    #[error("document {0} not found")]
+    DocNotFound(JournalId),
+
+    #[error("reducer mismatch: {0}")]
+    ReducerMismatch(String),
+
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// a transient failure, such as an io error on the remote side
+    #[error("{message}")]
+    Retryable { message: String, retry_after_ms: Option<u32> },
+}
+
+impl ProtocolError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ProtocolError::Retryable { .. })
+    }
 }
 
 #[derive(Error, Debug)]
@@ -32,6 +146,9 @@ pub enum ReplicationError {
     #[error("unknown journal id: {0}")]
     UnknownJournal(JournalId),
 
+    #[error("journal {0} is reserved and can't be replicated")]
+    ReservedJournal(JournalId),
+
     #[error(transparent)]
     JournalError(#[from] JournalError),
 
@@ -39,6 +156,28 @@ pub enum ReplicationError {
         "replication must be contiguous, received lsn {received} but expected lsn in range {range}"
     )]
     NonContiguousLsn { received: Lsn, range: LsnRange },
+
+    #[error("received a frame of {len} bytes, over the {max} byte limit")]
+    FrameTooLarge { len: u64, max: u64 },
+
+    #[error("remote asked us to reconnect in {retry_after_ms}ms")]
+    ReconnectLater { retry_after_ms: u32 },
+
+    #[error("remote closed the connection: {0}")]
+    Remote(ProtocolError),
+}
+
+impl ReplicationError {
+    /// false if reconnecting won't help, in which case the error should be
+    /// surfaced to the user
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ReplicationError::Remote(err) => err.is_retryable(),
+            ReplicationError::ReservedJournal(_)
+            | ReplicationError::FrameTooLarge { .. } => false,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -46,11 +185,30 @@ pub struct ReplicationProtocol {
     // outstanding lsn frames sent to the destination but awaiting acknowledgement
     // this is an Option because we need the to initialize it from the initial RangeRequest
     outstanding_range: Option<LsnRange>,
+    // the number of frames we may have outstanding, set by the destination
+    window: usize,
+    // frames which arrived ahead of a gap in the destination journal, written
+    // once the gap is filled. a protocol only receives frames for one journal
+    reordered: BTreeMap<Lsn, Vec<u8>>,
+    // the total length of the reordered frames
+    reordered_bytes: usize,
+    // the journal the remote side is sending us, and its range when it
+    // started replicating
+    remote_source: Option<(JournalId, LsnRange)>,
+    // the chunks of a reducer received so far, and its total length
+    reducer_wasm: Option<(ModuleDigest, u64, Vec<u8>)>,
 }
 
 impl ReplicationProtocol {
     pub fn new() -> Self {
-        Self { outstanding_range: None }
+        Self {
+            outstanding_range: None,
+            window: MAX_OUTSTANDING_FRAMES,
+            reordered: BTreeMap::new(),
+            reordered_bytes: 0,
+            remote_source: None,
+            reducer_wasm: None,
+        }
     }
 
     /// start replication, must be called on both sides of the connection
@@ -66,6 +224,55 @@ impl ReplicationProtocol {
         self.outstanding_range.is_some()
     }
 
+    /// the journal the remote side replicates to us, once it has asked
+    /// which frames we have
+    pub fn remote_id(&self) -> Option<JournalId> {
+        self.remote_source.map(|(id, _)| id)
+    }
+
+    /// whether doc has every frame the remote source had when it started
+    /// replicating to us, false until the remote side has said what it has
+    pub fn caught_up<D: ReplicationDestination>(
+        &self,
+        doc: &mut D,
+    ) -> Result<bool, ReplicationError> {
+        let Some((id, source_range)) = self.remote_source else {
+            return Ok(false);
+        };
+        Ok(source_range.next() <= doc.range(id)?.next())
+    }
+
+    /// the number of frames sent to the destination which it hasn't
+    /// acknowledged yet
+    pub fn unacked_frames(&self) -> usize {
+        self.outstanding_range.map_or(0, |range| range.len())
+    }
+
+    /// the last lsn the destination has acknowledged, None until it has
+    /// told us its range or if it hasn't received anything yet
+    pub fn acked_lsn(&self) -> Option<Lsn> {
+        match self.outstanding_range? {
+            LsnRange::Empty { nextlsn } => nextlsn.checked_sub(1),
+            LsnRange::NonEmpty { first, .. } => first.checked_sub(1),
+        }
+    }
+
+    /// the Reset to send if the destination needs frames which the source
+    /// has compacted away, after which sync continues from the source's
+    /// first frame
+    pub fn reset<D: ReplicationSource>(
+        &mut self,
+        doc: &D,
+    ) -> Option<ReplicationMsg> {
+        let next = self.outstanding_range?.next();
+        let first = doc.source_range().first()?;
+        if next >= first {
+            return None;
+        }
+        self.outstanding_range = Some(LsnRange::Empty { nextlsn: first });
+        Some(ReplicationMsg::Reset { id: doc.source_id() })
+    }
+
     /// sync a frame from the source journal to the destination
     /// the protocol layer will need to send the replication msg
     /// followed by the contents of the reader to the destination
@@ -74,12 +281,22 @@ impl ReplicationProtocol {
         doc: &'a D,
     ) -> Result<Option<(ReplicationMsg, D::Reader<'a>)>, ReplicationError> {
         if let Some(outstanding_range) = self.outstanding_range {
-            if outstanding_range.len() >= MAX_OUTSTANDING_FRAMES {
+            if outstanding_range.len() >= self.window {
                 // we have too many outstanding frames, so we can't send any more
                 return Ok(None);
             }
 
             let lsn = outstanding_range.next();
+            if let LsnRange::NonEmpty { first, .. } = doc.source_range() {
+                // the frames the destination needs next have been compacted
+                // away, it has to be reset first (see reset)
+                if lsn < first {
+                    return Err(ReplicationError::NonContiguousLsn {
+                        received: first,
+                        range: LsnRange::empty_following(&outstanding_range),
+                    });
+                }
+            }
             if let Some(data) = doc.read_lsn(lsn)? {
                 // update outstanding
                 self.outstanding_range = Some(outstanding_range.append(lsn));
@@ -106,12 +323,18 @@ impl ReplicationProtocol {
     ) -> Result<Option<ReplicationMsg>, ReplicationError> {
         match msg {
             ReplicationMsg::RangeRequest { id, source_range } => {
+                self.remote_source = Some((id, source_range));
                 let mut range = doc.range(id)?;
 
                 // if our range is empty, then we should reset to the remote's source range
                 // this is to handle timeline truncation until we have a more reliable mechanism
+                // we never reset backwards though, as an empty range may
+                // still indicate how much of the source we have consumed
                 if range.is_empty() {
-                    range = LsnRange::empty_preceeding(&source_range);
+                    let preceeding = LsnRange::empty_preceeding(&source_range);
+                    if preceeding.next() > range.next() {
+                        range = preceeding;
+                    }
                 }
 
                 Ok(Some(ReplicationMsg::Range { range }))
@@ -130,10 +353,265 @@ impl ReplicationProtocol {
                 Ok(None)
             }
             ReplicationMsg::Frame { id, lsn, len } => {
+                if len > MAX_FRAME_BYTES {
+                    let max = MAX_FRAME_BYTES;
+                    return Err(ReplicationError::FrameTooLarge { len, max });
+                }
                 let mut reader = LimitedReader { limit: len, inner: connection };
+                let range = doc.range(id)?;
+                // an empty destination accepts whichever lsn arrives first
+                if range.is_non_empty() && lsn > range.next() {
+                    let bytes = self.reordered_bytes + len as usize;
+                    if self.reordered.len() >= MAX_WINDOW
+                        || bytes > MAX_REORDERED_BYTES
+                    {
+                        return Err(ReplicationError::NonContiguousLsn {
+                            received: lsn,
+                            range: LsnRange::empty_following(&range),
+                        });
+                    }
+                    // read what arrives, rather than allocating len up front
+                    let mut data = Vec::new();
+                    reader.read_to_end(&mut data)?;
+                    self.reordered_bytes += data.len();
+                    if let Some(replaced) = self.reordered.insert(lsn, data) {
+                        self.reordered_bytes -= replaced.len();
+                    }
+                    return Ok(None);
+                }
                 doc.write_lsn(id, lsn, &mut reader)?;
+
+                // write any buffered frames which are now contiguous
+                while let Some(entry) = self.reordered.first_entry() {
+                    let next = doc.range(id)?.next();
+                    if *entry.key() > next {
+                        break;
+                    }
+                    let (lsn, data) = entry.remove_entry();
+                    self.reordered_bytes -= data.len();
+                    if lsn == next {
+                        doc.write_lsn(id, lsn, &mut &data[..])?;
+                    }
+                }
+
                 Ok(Some(ReplicationMsg::Range { range: doc.range(id)? }))
             }
+            ReplicationMsg::Window { frames } => {
+                self.window = (frames as usize).min(MAX_WINDOW);
+                Ok(None)
+            }
+            ReplicationMsg::ReconnectLater { retry_after_ms } => {
+                Err(ReplicationError::ReconnectLater { retry_after_ms })
+            }
+            ReplicationMsg::Clock { timestamp } => {
+                doc.observe_timestamp(timestamp);
+                Ok(None)
+            }
+            ReplicationMsg::Config { config } => {
+                doc.observe_config(config);
+                Ok(None)
+            }
+            ReplicationMsg::Reducer { digest, version } => {
+                doc.observe_reducer(digest, version);
+                Ok(None)
+            }
+            // the wasm is held by the CoordinatorServer, see
+            // Session::take_reducer_request
+            ReplicationMsg::ReducerRequest { .. } => Ok(None),
+            ReplicationMsg::ReducerWasm { digest, offset, total, len } => {
+                let mut chunk = Vec::new();
+                LimitedReader { limit: len, inner: connection }
+                    .read_to_end(&mut chunk)?;
+                if let Some(wasm) =
+                    self.receive_reducer_chunk(digest, offset, total, chunk)
+                {
+                    doc.receive_reducer(digest, wasm);
+                }
+                Ok(None)
+            }
+            ReplicationMsg::Epoch { epoch } => {
+                doc.observe_epoch(epoch)?;
+                Ok(None)
+            }
+            ReplicationMsg::Reset { id } => {
+                doc.reset(id)?;
+                Ok(None)
+            }
+            // the coordinator's page tree is read by the CoordinatorServer,
+            // see Session::take_resync_request
+            ReplicationMsg::TreeRequest { .. }
+            | ReplicationMsg::RepairRequest { .. }
+            | ReplicationMsg::HistoryRequest { .. } => Ok(None),
+            ReplicationMsg::TreeHashes { lsn, hashes } => {
+                doc.receive_tree_hashes(lsn, hashes)
+            }
+            ReplicationMsg::Repair { leaves, len } => {
+                let mut data = Vec::new();
+                LimitedReader { limit: len, inner: connection }
+                    .read_to_end(&mut data)?;
+                let repair = Checkpoint::deserialize_from(&data[..])?;
+                doc.receive_repair(leaves, repair)?;
+                Ok(None)
+            }
+            ReplicationMsg::History { lsn, len } => {
+                let mut data = Vec::new();
+                LimitedReader { limit: len, inner: connection }
+                    .read_to_end(&mut data)?;
+                let checkpoint = if data.is_empty() {
+                    None
+                } else {
+                    Some(Checkpoint::deserialize_from(&data[..])?)
+                };
+                doc.receive_history(lsn, checkpoint)?;
+                Ok(None)
+            }
+            ReplicationMsg::Error { error } => Err(ReplicationError::Remote(error)),
+            ReplicationMsg::Ping { nonce } => Ok(Some(ReplicationMsg::Pong { nonce })),
+            // any message proves liveness, so the Heartbeat has already
+            // accounted for this
+            ReplicationMsg::Pong { .. } => Ok(None),
+        }
+    }
+
+    /// add a chunk to the reducer being received, returning its wasm once
+    /// every chunk has arrived. a chunk which doesn't continue the reducer
+    /// (because it was started over, or is too large) drops what has been
+    /// received, and the destination can ask again
+    fn receive_reducer_chunk(
+        &mut self,
+        digest: ModuleDigest,
+        offset: u64,
+        total: u64,
+        chunk: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if total > MAX_REDUCER_BYTES {
+            logging::warn!("discarding a reducer of {} bytes", total);
+            self.reducer_wasm = None;
+            return None;
+        }
+        if offset == 0 {
+            let capacity = total.min(REDUCER_CHUNK_BYTES as u64) as usize;
+            self.reducer_wasm =
+                Some((digest, total, Vec::with_capacity(capacity)));
+        }
+        let (_, _, wasm) = match &mut self.reducer_wasm {
+            Some(received)
+                if received.0 == digest
+                    && received.1 == total
+                    && received.2.len() as u64 == offset =>
+            {
+                received
+            }
+            _ => {
+                logging::warn!("discarding an out of order reducer chunk");
+                self.reducer_wasm = None;
+                return None;
+            }
+        };
+        wasm.extend_from_slice(&chunk);
+        if wasm.len() as u64 >= total {
+            return self.reducer_wasm.take().map(|(_, _, wasm)| wasm);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// how long a connection may be idle before we ping the remote side
+    pub interval: Duration,
+    /// how long we wait to hear anything from the remote side before
+    /// considering the connection dead
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        // well below the ~60s idle timeout common to proxies and load balancers
+        Self { interval: Duration::from_secs(15), timeout: Duration::from_secs(45) }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeartbeatAction {
+    /// nothing to do until Heartbeat::deadline
+    Wait,
+    /// send this ping to the remote side
+    Ping(ReplicationMsg),
+    /// the remote side has gone quiet for longer than the timeout; the
+    /// connection should be dropped and re-established
+    TimedOut,
+}
+
+/// Heartbeat detects dead connections. It doesn't own a timer; the network
+/// layer calls received whenever a message arrives and poll once the current
+/// deadline passes. All times are unix milliseconds.
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_received_ms: i64,
+    last_ping_ms: Option<i64>,
+    next_nonce: u64,
+    rtt_ms: Option<i64>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig, now_ms: i64) -> Self {
+        Self {
+            config,
+            last_received_ms: now_ms,
+            last_ping_ms: None,
+            next_nonce: 0,
+            rtt_ms: None,
+        }
+    }
+
+    /// record that a pong arrived, measuring the round trip if it answers
+    /// our outstanding ping. must be called before received
+    pub fn ponged(&mut self, nonce: u64, now_ms: i64) {
+        if let Some(ping_ms) = self.last_ping_ms {
+            if nonce == self.next_nonce {
+                self.rtt_ms = Some(now_ms - ping_ms);
+            }
+        }
+    }
+
+    /// the round trip time of the last answered ping, in milliseconds
+    pub fn rtt_ms(&self) -> Option<i64> {
+        self.rtt_ms
+    }
+
+    /// record that a message (of any kind) arrived from the remote side
+    pub fn received(&mut self, now_ms: i64) {
+        self.last_received_ms = now_ms;
+        self.last_ping_ms = None;
+    }
+
+    /// when poll should next be called
+    pub fn deadline(&self) -> i64 {
+        let timeout_at = self.last_received_ms + self.config.timeout.as_millis() as i64;
+        match self.last_ping_ms {
+            // we are waiting to hear back
+            Some(_) => timeout_at,
+            None => cmp::min(
+                self.last_received_ms + self.config.interval.as_millis() as i64,
+                timeout_at,
+            ),
+        }
+    }
+
+    pub fn poll(&mut self, now_ms: i64) -> HeartbeatAction {
+        let idle_ms = now_ms - self.last_received_ms;
+        if idle_ms >= self.config.timeout.as_millis() as i64 {
+            HeartbeatAction::TimedOut
+        } else if self.last_ping_ms.is_none()
+            && idle_ms >= self.config.interval.as_millis() as i64
+        {
+            self.last_ping_ms = Some(now_ms);
+            self.next_nonce += 1;
+            HeartbeatAction::Ping(ReplicationMsg::Ping { nonce: self.next_nonce })
+        } else {
+            HeartbeatAction::Wait
         }
     }
 }
@@ -165,6 +643,65 @@ pub trait ReplicationDestination {
     ) -> Result<(), ReplicationError>
     where
         R: io::Read;
+
+    /// merge a timestamp from the remote side into the destination's clock
+    fn observe_timestamp(&mut self, _timestamp: HlcTimestamp) {}
+
+    /// adopt a config sent by the remote side
+    fn observe_config(&mut self, _config: DocumentConfig) {}
+
+    /// compare the remote side's reducer with the destination's
+    fn observe_reducer(
+        &mut self,
+        _digest: ModuleDigest,
+        _version: Option<u32>,
+    ) {
+    }
+
+    /// accept the wasm of a reducer requested from the remote side
+    fn receive_reducer(&mut self, _digest: ModuleDigest, _wasm: Vec<u8>) {}
+
+    /// compare the remote side's epoch with the destination's, see the
+    /// redaction module
+    fn observe_epoch(&mut self, _epoch: u64) -> Result<(), ReplicationError> {
+        Ok(())
+    }
+
+    /// drop every frame of journal id, as the remote side has compacted away
+    /// the frames we'd need to catch up. we then accept whichever frame
+    /// arrives next. destinations which can't start over don't support this
+    fn reset(&mut self, id: JournalId) -> Result<(), ReplicationError> {
+        Err(ReplicationError::UnknownJournal(id))
+    }
+
+    /// continue a resync with the hashes the remote side sent for our last
+    /// TreeRequest, returning the next request to send, see resync
+    fn receive_tree_hashes(
+        &mut self,
+        _lsn: Option<Lsn>,
+        _hashes: Vec<TreeHash>,
+    ) -> Result<Option<ReplicationMsg>, ReplicationError> {
+        Ok(None)
+    }
+
+    /// finish a resync by repairing the leaves with the remote side's pages
+    fn receive_repair(
+        &mut self,
+        _leaves: Vec<TreeNode>,
+        _repair: Checkpoint,
+    ) -> Result<(), ReplicationError> {
+        Ok(())
+    }
+
+    /// accept the document as of lsn, in reply to a HistoryRequest. the
+    /// checkpoint is None if the remote side doesn't retain lsn
+    fn receive_history(
+        &mut self,
+        _lsn: Lsn,
+        _checkpoint: Option<Checkpoint>,
+    ) -> Result<(), ReplicationError> {
+        Ok(())
+    }
 }
 
 /// LimitedReader is basically io::Take but over a mutable ref
@@ -185,3 +722,203 @@ impl<'a, R: io::Read> io::Read for LimitedReader<'a, R> {
         Ok(n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use super::{
+        Heartbeat, HeartbeatAction, HeartbeatConfig, ReplicationError,
+        ReplicationMsg, ReplicationProtocol, MAX_FRAME_BYTES,
+        MAX_REORDERED_BYTES,
+    };
+    use crate::{Journal, JournalId, LsnRange, MemoryJournal, Scannable};
+
+    fn config() -> HeartbeatConfig {
+        HeartbeatConfig { interval: Duration::from_millis(100), timeout: Duration::from_millis(300) }
+    }
+
+    #[test]
+    fn heartbeat_pings_when_idle() {
+        let mut hb = Heartbeat::new(config(), 0);
+        assert_eq!(hb.deadline(), 100);
+        assert!(matches!(hb.poll(50), HeartbeatAction::Wait));
+        assert!(matches!(
+            hb.poll(100),
+            HeartbeatAction::Ping(ReplicationMsg::Ping { nonce: 1 })
+        ));
+
+        // only one ping is outstanding at a time
+        assert_eq!(hb.deadline(), 300);
+        assert!(matches!(hb.poll(200), HeartbeatAction::Wait));
+
+        // hearing back resets the clock
+        hb.ponged(1, 250);
+        hb.received(250);
+        assert_eq!(hb.rtt_ms(), Some(150));
+        assert_eq!(hb.deadline(), 350);
+        assert!(matches!(
+            hb.poll(350),
+            HeartbeatAction::Ping(ReplicationMsg::Ping { nonce: 2 })
+        ));
+    }
+
+    #[test]
+    fn heartbeat_times_out() {
+        let mut hb = Heartbeat::new(config(), 0);
+        assert!(matches!(hb.poll(100), HeartbeatAction::Ping(_)));
+        assert!(matches!(hb.poll(299), HeartbeatAction::Wait));
+        assert!(matches!(hb.poll(300), HeartbeatAction::TimedOut));
+    }
+
+    #[test]
+    fn buffers_out_of_order_frames() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut dest = MemoryJournal::open(id).unwrap();
+        let mut protocol = ReplicationProtocol::new();
+
+        let mut frame = |dest: &mut MemoryJournal, lsn: u64| {
+            let data = [lsn as u8];
+            let msg = ReplicationMsg::Frame { id, lsn, len: 1 };
+            protocol.handle(dest, msg, &mut &data[..]).unwrap()
+        };
+
+        assert!(frame(&mut dest, 0).is_some());
+        // 2 and 3 arrive before 1, and are held until it does
+        assert!(frame(&mut dest, 3).is_none());
+        assert!(frame(&mut dest, 2).is_none());
+        assert_eq!(dest.range(), LsnRange::new(0, 0));
+        assert!(matches!(
+            frame(&mut dest, 1),
+            Some(ReplicationMsg::Range { range }) if range == LsnRange::new(0, 3)
+        ));
+
+        for lsn in 0..4 {
+            assert_eq!(dest.get(lsn).unwrap(), Some(&[lsn as u8][..]));
+        }
+    }
+
+    #[test]
+    fn frames_claiming_too_many_bytes_are_refused() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut dest = MemoryJournal::open(id).unwrap();
+        let mut protocol = ReplicationProtocol::new();
+        let mut frame = |dest: &mut MemoryJournal, lsn, len| {
+            let msg = ReplicationMsg::Frame { id, lsn, len };
+            protocol.handle(dest, msg, &mut io::repeat(0))
+        };
+
+        // nothing is read or allocated for a frame over the limit
+        let err = frame(&mut dest, 0, u64::MAX).unwrap_err();
+        assert!(matches!(err, ReplicationError::FrameTooLarge { .. }));
+        assert!(!err.is_retryable());
+
+        // nor is an out of order frame buffered past the reorder limit
+        assert!(frame(&mut dest, 0, 1).unwrap().is_some());
+        assert!(frame(&mut dest, 2, 10).unwrap().is_none());
+        let len = (MAX_REORDERED_BYTES - 5) as u64;
+        assert!(len <= MAX_FRAME_BYTES);
+        assert!(matches!(
+            frame(&mut dest, 3, len),
+            Err(ReplicationError::NonContiguousLsn { received: 3, .. })
+        ));
+        assert_eq!(dest.range(), LsnRange::new(0, 0));
+    }
+
+    #[test]
+    fn empty_ranges_never_reset_backwards() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut protocol = ReplicationProtocol::new();
+        let mut range_reply = |dest: &mut MemoryJournal, source_range| {
+            let msg = ReplicationMsg::RangeRequest { id, source_range };
+            match protocol.handle(dest, msg, &mut io::empty()).unwrap() {
+                Some(ReplicationMsg::Range { range }) => range,
+                other => panic!("expected a range, got {:?}", other),
+            }
+        };
+
+        // an empty destination catches up with a truncated source
+        let mut dest = MemoryJournal::open(id).unwrap();
+        let range = range_reply(&mut dest, LsnRange::new(5, 9));
+        assert_eq!(range, LsnRange::Empty { nextlsn: 5 });
+
+        // but a destination which has consumed frames past the start of the
+        // source only asks for what follows them
+        dest.drop_prefix(6).unwrap();
+        let range = range_reply(&mut dest, LsnRange::new(5, 9));
+        assert_eq!(range, LsnRange::Empty { nextlsn: 7 });
+    }
+
+    #[test]
+    fn empty_window_pauses_sync() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut source = MemoryJournal::open(id).unwrap();
+        source.append(&b"a"[..]).unwrap();
+        let mut protocol = ReplicationProtocol::new();
+
+        let mut handle = |source: &mut MemoryJournal, msg| {
+            protocol.handle(source, msg, &mut io::empty()).unwrap();
+            protocol.sync(&*source).unwrap().is_some()
+        };
+
+        let range = LsnRange::empty();
+        assert!(!handle(&mut source, ReplicationMsg::Window { frames: 0 }));
+        assert!(!handle(&mut source, ReplicationMsg::Range { range }));
+        assert!(handle(&mut source, ReplicationMsg::Window { frames: 1 }));
+    }
+
+    #[test]
+    fn frames_are_unacked_until_the_destination_has_them() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut source = MemoryJournal::open(id).unwrap();
+        source.append(&b"a"[..]).unwrap();
+        source.append(&b"b"[..]).unwrap();
+        let mut protocol = ReplicationProtocol::new();
+
+        fn ack(
+            protocol: &mut ReplicationProtocol,
+            source: &mut MemoryJournal,
+            range: LsnRange,
+        ) {
+            let msg = ReplicationMsg::Range { range };
+            protocol.handle(source, msg, &mut io::empty()).unwrap();
+        }
+        ack(&mut protocol, &mut source, LsnRange::empty());
+        assert_eq!(protocol.unacked_frames(), 0);
+        while protocol.sync(&source).unwrap().is_some() {}
+        assert_eq!(protocol.unacked_frames(), 2);
+
+        ack(&mut protocol, &mut source, LsnRange::new(0, 0));
+        assert_eq!(protocol.unacked_frames(), 1);
+        ack(&mut protocol, &mut source, LsnRange::new(0, 1));
+        assert_eq!(protocol.unacked_frames(), 0);
+        assert_eq!(protocol.acked_lsn(), Some(1));
+    }
+
+    #[test]
+    fn destinations_behind_a_compaction_are_reset() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut source = MemoryJournal::open(id).unwrap();
+        for lsn in 0..5u8 {
+            source.append(&[lsn][..]).unwrap();
+        }
+        // frames 0 to 2 are folded into 2
+        source.replace(2, vec![vec![0xff], vec![3], vec![4]]).unwrap();
+
+        let mut protocol = ReplicationProtocol::new();
+        let range = LsnRange::new(0, 0);
+        protocol
+            .handle(&mut source, ReplicationMsg::Range { range }, &mut io::empty())
+            .unwrap();
+        assert!(protocol.sync(&source).is_err());
+        assert!(matches!(
+            protocol.reset(&source),
+            Some(ReplicationMsg::Reset { id: reset }) if reset == id
+        ));
+        assert!(protocol.reset(&source).is_none());
+        assert!(matches!(
+            protocol.sync(&source).unwrap(),
+            Some((ReplicationMsg::Frame { lsn: 2, .. }, &[0xff]))
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+//! Differential resync. If a client's storage diverges from its coordinator's
+//! (corruption, a bug, a forced compaction) it can repair itself without a
+//! full resync. Both sides build a PageTree, a Merkle tree over runs of
+//! pages. The client walks down from the root with a Resync, asking the
+//! coordinator for the hashes of the children of each node which differs,
+//! which takes one round trip per level. It then fetches only the pages in
+//! the differing leaves (see CoordinatorDocument::repair_pages) and applies
+//! them with LocalDocument::repair.
+//!
+//! Over the wire, LocalDocument::start_resync returns the first
+//! ReplicationMsg::TreeRequest, which the CoordinatorServer answers with
+//! TreeHashes. The client replies with further TreeRequests until it knows
+//! which leaves differ, then sends a RepairRequest, which is answered with a
+//! Repair. Hashes are only comparable between trees built at the same
+//! storage lsn, so every reply carries the coordinator's, and a resync which
+//! sees the lsn move on either side is abandoned and can be started again.
+
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::page::{PageIdx, SparsePages};
+
+pub type TreeHash = [u8; 32];
+
+/// the hash of a node with no pages beneath it
+const EMPTY_HASH: TreeHash = [0; 32];
+
+/// each leaf covers 2^LEAF_BITS pages
+const LEAF_BITS: u32 = 6;
+/// each internal node has 2^FANOUT_BITS children
+const FANOUT_BITS: u32 = 4;
+/// enough levels above the leaves to cover every PageIdx with a single root
+const ROOT_LEVEL: u8 =
+    ((PageIdx::BITS - LEAF_BITS + FANOUT_BITS - 1) / FANOUT_BITS) as u8;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct TreeNode {
+    /// leaves are level 0
+    pub level: u8,
+    pub index: u32,
+}
+
+impl TreeNode {
+    pub const ROOT: TreeNode = TreeNode { level: ROOT_LEVEL, index: 0 };
+
+    fn shift(level: u8) -> u32 {
+        LEAF_BITS + FANOUT_BITS * level as u32
+    }
+
+    fn containing(level: u8, page_idx: PageIdx) -> Self {
+        let index = page_idx.checked_shr(Self::shift(level)).unwrap_or(0);
+        TreeNode { level, index }
+    }
+
+    fn parent(&self) -> Self {
+        TreeNode { level: self.level + 1, index: self.index >> FANOUT_BITS }
+    }
+
+    fn children(self) -> impl Iterator<Item = TreeNode> {
+        assert!(self.level > 0, "leaves have no children");
+        let first = self.index << FANOUT_BITS;
+        (0..1 << FANOUT_BITS)
+            .map(move |i| TreeNode { level: self.level - 1, index: first + i })
+    }
+
+    /// the pages covered by a leaf
+    pub fn pages(&self) -> RangeInclusive<PageIdx> {
+        assert_eq!(self.level, 0, "only leaves cover pages directly");
+        let first = self.index << LEAF_BITS;
+        first..=first + ((1 << LEAF_BITS) - 1)
+    }
+}
+
+/// PageTree only stores nodes with pages beneath them
+#[derive(Debug, Default)]
+pub struct PageTree {
+    nodes: BTreeMap<TreeNode, TreeHash>,
+}
+
+impl PageTree {
+    pub fn build(pages: &SparsePages) -> Self {
+        let mut leaves: BTreeMap<TreeNode, Sha256> = BTreeMap::new();
+        for &page_idx in pages.page_idxs() {
+            let page = pages
+                .get(page_idx)
+                .expect("page_idxs only lists pages we have");
+            let hasher =
+                leaves.entry(TreeNode::containing(0, page_idx)).or_default();
+            hasher.update(page_idx.to_le_bytes());
+            hasher.update(page);
+        }
+        let mut nodes: BTreeMap<TreeNode, TreeHash> = leaves
+            .into_iter()
+            .map(|(node, hasher)| (node, hasher.finalize().into()))
+            .collect();
+
+        for level in 0..ROOT_LEVEL {
+            let mut parents: BTreeMap<TreeNode, Sha256> = BTreeMap::new();
+            let children = nodes.range(
+                TreeNode { level, index: 0 }..=TreeNode {
+                    level,
+                    index: u32::MAX,
+                },
+            );
+            for (child, hash) in children {
+                let hasher = parents.entry(child.parent()).or_default();
+                hasher.update(child.index.to_le_bytes());
+                hasher.update(hash);
+            }
+            nodes.extend(
+                parents
+                    .into_iter()
+                    .map(|(node, hasher)| (node, hasher.finalize().into())),
+            );
+        }
+
+        Self { nodes }
+    }
+
+    pub fn root(&self) -> TreeHash {
+        self.hash(TreeNode::ROOT)
+    }
+
+    pub fn hash(&self, node: TreeNode) -> TreeHash {
+        self.nodes.get(&node).copied().unwrap_or(EMPTY_HASH)
+    }
+
+    pub fn hashes(&self, nodes: &[TreeNode]) -> Vec<TreeHash> {
+        nodes.iter().map(|&node| self.hash(node)).collect()
+    }
+}
+
+/// Resync finds the leaves which differ between a local tree and a remote one
+#[derive(Debug)]
+pub struct Resync {
+    local: PageTree,
+    request: Vec<TreeNode>,
+    differing: Vec<TreeNode>,
+}
+
+impl Resync {
+    pub fn new(local: PageTree) -> Self {
+        Self { local, request: vec![TreeNode::ROOT], differing: vec![] }
+    }
+
+    /// the nodes whose remote hashes we need next; empty once we are done
+    pub fn request(&self) -> &[TreeNode] {
+        &self.request
+    }
+
+    /// the remote hashes of the nodes in request, in the same order
+    pub fn receive(&mut self, hashes: &[TreeHash]) {
+        assert_eq!(
+            hashes.len(),
+            self.request.len(),
+            "expected a hash per requested node"
+        );
+        let mut next = vec![];
+        for (node, hash) in self.request.iter().zip(hashes) {
+            if self.local.hash(*node) == *hash {
+                continue;
+            }
+            if node.level == 0 {
+                self.differing.push(*node);
+            } else {
+                next.extend(node.children());
+            }
+        }
+        self.request = next;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.request.is_empty()
+    }
+
+    /// the leaves whose pages differ, once done
+    pub fn differing(&self) -> &[TreeNode] {
+        &self.differing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PageTree, Resync, TreeNode};
+    use crate::page::{SparsePages, PAGESIZE};
+
+    #[test]
+    fn finds_differing_leaves() {
+        let mut local = SparsePages::new();
+        for page_idx in 1..=300 {
+            local.write(page_idx, [page_idx as u8; PAGESIZE]);
+        }
+        let mut remote = local.clone();
+        remote.write(70, [0; PAGESIZE]);
+        remote.write(299, [0; PAGESIZE]);
+        remote.write(100_000, [1; PAGESIZE]);
+
+        let remote_tree = PageTree::build(&remote);
+        let mut resync = Resync::new(PageTree::build(&local));
+        let mut round_trips = 0;
+        while !resync.is_done() {
+            let hashes = remote_tree.hashes(resync.request());
+            resync.receive(&hashes);
+            round_trips += 1;
+        }
+        assert_eq!(round_trips, TreeNode::ROOT.level as usize + 1);
+
+        let differing: Vec<_> =
+            resync.differing().iter().map(|leaf| leaf.pages()).collect();
+        assert_eq!(differing, vec![64..=127, 256..=319, 99968..=100031]);
+
+        // identical trees are done after comparing their roots
+        let mut resync = Resync::new(PageTree::build(&remote));
+        resync.receive(&remote_tree.hashes(resync.request()));
+        assert!(resync.is_done() && resync.differing().is_empty());
+    }
+}
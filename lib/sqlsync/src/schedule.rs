@@ -0,0 +1,225 @@
+//! Schedule decides when a coordinator should run each of its document's
+//! reducer tasks. Runs are recorded as server mutations (see
+//! CoordinatorDocument::run_task), so only the decision of when to run a task
+//! depends on the clock; what it does is replayed identically everywhere.
+//!
+//! The schedule itself is stored in __sqlsync_schedule, which is only
+//! written by schedule mutations in the server timeline. The coordinator
+//! submits one whenever the schedule changes or tasks run, so a restarted
+//! coordinator picks up where it left off rather than pushing every task
+//! back a full period.
+
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+const SCHEDULE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_schedule (
+        name TEXT PRIMARY KEY NOT NULL,
+        every_ms INTEGER NOT NULL,
+        next_ms INTEGER NOT NULL
+    ) STRICT
+";
+
+/// server timeline mutations which start with this prefix replace the
+/// schedule with the tasks which follow: a little endian u32 count, then
+/// for each task its every_ms u64, next_ms i64 and name length u32 (all
+/// little endian), followed by the name
+const SCHEDULE_MUTATION_MAGIC: &[u8; 8] = b"\0sqssch1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledTask {
+    /// passed to the reducer's task entry point
+    pub name: String,
+    pub every: Duration,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// each task along with the unix time in milliseconds it's next due
+    tasks: Vec<(ScheduledTask, i64)>,
+}
+
+impl Schedule {
+    /// tasks first run one period after now_ms
+    pub fn new(tasks: Vec<ScheduledTask>, now_ms: i64) -> Self {
+        let tasks = tasks
+            .into_iter()
+            .map(|task| {
+                let next = now_ms + task.every.as_millis() as i64;
+                (task, next)
+            })
+            .collect();
+        Self { tasks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// each task along with the unix time in milliseconds it's next due
+    pub fn tasks(&self) -> &[(ScheduledTask, i64)] {
+        &self.tasks
+    }
+
+    /// when the next task is due
+    pub fn deadline(&self) -> Option<i64> {
+        self.tasks.iter().map(|(_, next)| *next).min()
+    }
+
+    /// the names of the tasks which are due. a task which missed several
+    /// periods (e.g. while the coordinator was asleep) is only run once
+    pub fn due(&mut self, now_ms: i64) -> Vec<String> {
+        let mut due = vec![];
+        for (task, next) in self.tasks.iter_mut() {
+            if now_ms >= *next {
+                let every = (task.every.as_millis() as i64).max(1);
+                let missed = (now_ms - *next) / every;
+                *next += (missed + 1) * every;
+                due.push(task.name.clone());
+            }
+        }
+        due
+    }
+}
+
+pub(crate) fn run_schedule_migration(
+    sqlite: &Connection,
+) -> rusqlite::Result<()> {
+    sqlite.execute(SCHEDULE_TABLE_SQL, [])?;
+    Ok(())
+}
+
+/// the schedule as of the last schedule mutation applied to the document
+pub(crate) fn read_schedule(sqlite: &Connection) -> rusqlite::Result<Schedule> {
+    let tasks = sqlite
+        .prepare(
+            "SELECT name, every_ms, next_ms FROM __sqlsync_schedule
+            ORDER BY name",
+        )?
+        .query_map([], |row| {
+            let task = ScheduledTask {
+                name: row.get(0)?,
+                every: Duration::from_millis(row.get::<_, i64>(1)? as u64),
+            };
+            Ok((task, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Schedule { tasks })
+}
+
+pub(crate) fn write_schedule(
+    tx: &Connection,
+    tasks: &[(ScheduledTask, i64)],
+) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM __sqlsync_schedule", [])?;
+    let mut stmt = tx.prepare(
+        "INSERT OR REPLACE INTO __sqlsync_schedule (name, every_ms, next_ms)
+        VALUES (?, ?, ?)",
+    )?;
+    for (task, next_ms) in tasks {
+        let every_ms = task.every.as_millis() as i64;
+        stmt.execute(params![task.name, every_ms, next_ms])?;
+    }
+    Ok(())
+}
+
+/// the server mutation which records schedule as the document's schedule
+pub(crate) fn schedule_mutation(schedule: &Schedule) -> Vec<u8> {
+    let mut out = SCHEDULE_MUTATION_MAGIC.to_vec();
+    out.extend_from_slice(&(schedule.tasks.len() as u32).to_le_bytes());
+    for (task, next_ms) in schedule.tasks.iter() {
+        out.extend_from_slice(&(task.every.as_millis() as u64).to_le_bytes());
+        out.extend_from_slice(&next_ms.to_le_bytes());
+        out.extend_from_slice(&(task.name.len() as u32).to_le_bytes());
+        out.extend_from_slice(task.name.as_bytes());
+    }
+    out
+}
+
+pub(crate) fn parse_schedule_mutation(
+    mutation: &[u8],
+) -> Option<Vec<(ScheduledTask, i64)>> {
+    fn take<'a>(rest: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        let (head, tail) = (rest.get(..n)?, rest.get(n..)?);
+        *rest = tail;
+        Some(head)
+    }
+    fn array<const N: usize>(rest: &mut &[u8]) -> Option<[u8; N]> {
+        take(rest, N)?.try_into().ok()
+    }
+
+    let mut rest = mutation.strip_prefix(SCHEDULE_MUTATION_MAGIC)?;
+    let count = u32::from_le_bytes(array(&mut rest)?);
+    let mut tasks = vec![];
+    for _ in 0..count {
+        let every_ms = u64::from_le_bytes(array(&mut rest)?);
+        let next_ms = i64::from_le_bytes(array(&mut rest)?);
+        let len = u32::from_le_bytes(array(&mut rest)?);
+        let name = String::from_utf8(take(&mut rest, len as usize)?.to_vec());
+        let task = ScheduledTask {
+            name: name.ok()?,
+            every: Duration::from_millis(every_ms),
+        };
+        tasks.push((task, next_ms));
+    }
+    rest.is_empty().then_some(tasks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use super::{
+        parse_schedule_mutation, read_schedule, run_schedule_migration,
+        schedule_mutation, write_schedule, Schedule, ScheduledTask,
+    };
+
+    fn task(name: &str, every_ms: u64) -> ScheduledTask {
+        ScheduledTask {
+            name: name.into(),
+            every: Duration::from_millis(every_ms),
+        }
+    }
+
+    #[test]
+    fn runs_tasks_when_due() {
+        let mut schedule =
+            Schedule::new(vec![task("cleanup", 100), task("expire", 30)], 0);
+        assert_eq!(schedule.deadline(), Some(30));
+        assert!(schedule.due(29).is_empty());
+        assert_eq!(schedule.due(30), vec!["expire"]);
+        assert_eq!(schedule.deadline(), Some(60));
+
+        // missed runs are collapsed into one
+        assert_eq!(schedule.due(125), vec!["cleanup", "expire"]);
+        assert_eq!(schedule.deadline(), Some(150));
+        assert_eq!(schedule.due(150), vec!["expire"]);
+    }
+
+    #[test]
+    fn schedules_round_trip_through_mutations() {
+        let mut schedule =
+            Schedule::new(vec![task("cleanup", 100), task("ünïcode", 30)], 0);
+        schedule.due(40);
+        let mutation = schedule_mutation(&schedule);
+        let tasks = parse_schedule_mutation(&mutation).unwrap();
+        assert_eq!(tasks, schedule.tasks());
+
+        let conn = Connection::open_in_memory().unwrap();
+        run_schedule_migration(&conn).unwrap();
+        assert!(read_schedule(&conn).unwrap().is_empty());
+        write_schedule(&conn, &tasks).unwrap();
+        assert_eq!(read_schedule(&conn).unwrap(), schedule);
+
+        // truncated mutations aren't mistaken for shorter schedules
+        for len in 0..mutation.len() {
+            assert_eq!(parse_schedule_mutation(&mutation[..len]), None);
+        }
+        let empty = schedule_mutation(&Schedule::default());
+        assert_eq!(parse_schedule_mutation(&empty), Some(vec![]));
+    }
+}
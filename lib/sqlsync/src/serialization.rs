@@ -5,6 +5,20 @@ use crate::positioned_io::PositionedReader;
 pub trait Serializable {
     /// serialize the object into the given writer
     fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// the number of bytes serialize_into writes, if it's known up front
+    fn serialized_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// serialize the object into a new buffer. when the size is known the
+    /// buffer is allocated once rather than grown (and copied) while
+    /// serializing, which matters for frames holding many pages
+    fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.serialized_size().unwrap_or(0));
+        self.serialize_into(&mut buf)?;
+        Ok(buf)
+    }
 }
 
 pub trait Deserializable: Sized {
@@ -16,4 +30,8 @@ impl Serializable for &[u8] {
     fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_all(self)
     }
+
+    fn serialized_size(&self) -> Option<usize> {
+        Some(self.len())
+    }
 }
@@ -0,0 +1,1047 @@
+//! CoordinatorServer is the replication half of a coordinator without any
+//! io: the host accepts connections and feeds it their messages, then sends
+//! the ServerOutputs it returns. Frames are made durable through the
+//! FrameStorage trait, whose futures don't assume a runtime. Together they
+//! let a coordinator run on hosts without threads or tokio, such as
+//! Cloudflare Durable Objects on wasm32-unknown-unknown.
+
+use std::{collections::BTreeMap, future::Future, io};
+
+use thiserror::Error;
+
+use crate::{
+    compaction::Compactor,
+    coordinator::CoordinatorDocument,
+    logging,
+    positioned_io::PositionedReader,
+    quota::StorageQuota,
+    replication::{
+        HeartbeatConfig, ProtocolError, ReplicationDestination,
+        ReplicationError, ReplicationMsg, ReplicationSource,
+    },
+    session::{
+        encode, encode_history, encode_reducer, encode_repair, ResyncRequest,
+        Session, SessionError,
+    },
+    tenant::{AuthContext, TenantError, TenantRegistry},
+    Journal, JournalId, Lsn, LsnRange, ModuleDigest, ReducerPool,
+};
+
+pub type ClientId = u64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerOutput {
+    /// send a binary message to the client
+    Send { client: ClientId, msg: Vec<u8> },
+    /// close the client's connection once the messages before this one
+    /// have been sent
+    Close { client: ClientId },
+}
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error(transparent)]
+    Session(#[from] SessionError),
+
+    #[error("unknown client: {0}")]
+    UnknownClient(ClientId),
+
+    #[error(transparent)]
+    Document(#[from] crate::error::Error),
+
+    #[error(transparent)]
+    Tenant(#[from] TenantError),
+}
+
+#[derive(Error, Debug)]
+pub enum StorageError<E> {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Replication(#[from] ReplicationError),
+
+    #[error("storage error: {0}")]
+    Storage(E),
+}
+
+/// durable storage for a coordinator's frames
+pub trait FrameStorage {
+    type Error;
+
+    /// the range of frames which have been written
+    fn range(&self) -> LsnRange;
+
+    fn read_frame(
+        &self,
+        lsn: Lsn,
+    ) -> impl Future<Output = Result<Vec<u8>, Self::Error>>;
+
+    /// write the frame following range, extending it
+    fn write_frame(
+        &mut self,
+        lsn: Lsn,
+        frame: Vec<u8>,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// write any frames which storage is missing from source, returning the
+/// number of frames written
+pub async fn persist<D, S>(
+    source: &D,
+    storage: &mut S,
+) -> Result<usize, StorageError<S::Error>>
+where
+    D: ReplicationSource,
+    S: FrameStorage,
+{
+    let mut written = 0;
+    loop {
+        let lsn = storage.range().next();
+        let Some(frame) = source.read_lsn(lsn)? else {
+            return Ok(written);
+        };
+        storage
+            .write_frame(lsn, frame.read_all()?)
+            .await
+            .map_err(StorageError::Storage)?;
+        written += 1;
+    }
+}
+
+/// write every frame in storage into the destination journal
+pub async fn replay<D, S>(
+    storage: &S,
+    id: JournalId,
+    dest: &mut D,
+) -> Result<(), StorageError<S::Error>>
+where
+    D: ReplicationDestination,
+    S: FrameStorage,
+{
+    for lsn in storage.range().iter() {
+        logging::debug!(doc = id; "replaying lsn {}", lsn);
+        let frame = storage
+            .read_frame(lsn)
+            .await
+            .map_err(StorageError::Storage)?;
+        dest.write_lsn(id, lsn, &mut &frame[..])?;
+    }
+    Ok(())
+}
+
+pub struct CoordinatorServer<J: Journal> {
+    doc: CoordinatorDocument<J>,
+    clients: BTreeMap<ClientId, Session>,
+    next_client_id: ClientId,
+    heartbeat_config: HeartbeatConfig,
+    reducer_wasm: Option<(ModuleDigest, Vec<u8>)>,
+    compactor: Option<Compactor>,
+    // who each client accepted with accept_tenant is authenticated as
+    tenant_clients: BTreeMap<ClientId, AuthContext>,
+}
+
+impl<J> CoordinatorServer<J>
+where
+    J: Journal + ReplicationSource + ReplicationDestination,
+{
+    pub fn new(doc: CoordinatorDocument<J>) -> Self {
+        Self {
+            doc,
+            clients: BTreeMap::new(),
+            next_client_id: 0,
+            heartbeat_config: HeartbeatConfig::default(),
+            reducer_wasm: None,
+            compactor: None,
+            tenant_clients: BTreeMap::new(),
+        }
+    }
+
+    /// applies to clients accepted after this call
+    pub fn set_heartbeat_config(&mut self, config: HeartbeatConfig) {
+        self.heartbeat_config = config;
+    }
+
+    /// send the wasm the document's reducer was loaded from to clients which
+    /// ask for it, so they can run the same reducer without bundling it
+    pub fn serve_reducer(&mut self, wasm: Vec<u8>) {
+        let digest = ReducerPool::digest(&wasm);
+        self.doc.set_reducer_digest(digest);
+        self.reducer_wasm = Some((digest, wasm));
+    }
+
+    /// compact the document's storage whenever compactor's policies ask to,
+    /// see compact
+    pub fn set_compactor(&mut self, compactor: Compactor) {
+        self.compactor = Some(compactor);
+    }
+
+    pub fn compactor(&self) -> Option<&Compactor> {
+        self.compactor.as_ref()
+    }
+
+    /// poll the compactor, folding no further than compaction_limit or
+    /// durable, the last lsn the host has persisted (see persist): a folded
+    /// frame can't be persisted any more. hosts which don't persist frames
+    /// pass the document's last lsn. returns the number of frames removed
+    pub fn compact(
+        &mut self,
+        durable: Option<Lsn>,
+        now_ms: i64,
+    ) -> Result<usize, ServerError> {
+        let limit = self.compaction_limit().zip(durable).map(|(a, b)| a.min(b));
+        let Some(compactor) = self.compactor.as_mut() else {
+            return Ok(0);
+        };
+        Ok(compactor.poll(&mut self.doc, limit, now_ms)?)
+    }
+
+    /// unix milliseconds at which compact should next be called, if no
+    /// frames are written in the meantime
+    pub fn compaction_deadline(&self, now_ms: i64) -> Option<i64> {
+        self.compactor.as_ref()?.deadline(now_ms)
+    }
+
+    pub fn doc(&self) -> &CoordinatorDocument<J> {
+        &self.doc
+    }
+
+    pub fn doc_mut(&mut self) -> &mut CoordinatorDocument<J> {
+        &mut self.doc
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// the last storage lsn which can be compacted without dropping a frame
+    /// a connected client still needs: the lowest lsn every client has
+    /// acked. None while a client has yet to ack anything. clients which
+    /// reconnect once their frames are gone are reset, see
+    /// ReplicationMsg::Reset
+    pub fn compaction_limit(&self) -> Option<Lsn> {
+        let mut limit = self.doc.source_range().last()?;
+        for session in self.clients.values() {
+            limit = limit.min(session.acked_lsn()?);
+        }
+        Some(limit)
+    }
+
+    /// register a new client connection, returning its id and the messages
+    /// which start replication
+    pub fn accept(
+        &mut self,
+        now_ms: i64,
+    ) -> Result<(ClientId, Vec<ServerOutput>), ServerError> {
+        let client = self.next_client_id;
+        self.next_client_id += 1;
+
+        let session = Session::new(self.heartbeat_config, now_ms);
+        let mut out = vec![];
+        // a client from an older epoch clears its storage, which it must do
+        // before it answers our range request
+        if self.doc.epoch() > 0 {
+            let epoch = self.doc.epoch();
+            out.push(send(client, &ReplicationMsg::Epoch { epoch })?);
+        }
+        out.push(ServerOutput::Send { client, msg: session.start(&self.doc)? });
+        for msg in [
+            // let the client line its clock up with ours
+            ReplicationMsg::Clock { timestamp: self.doc.now() },
+            ReplicationMsg::Config { config: self.doc.config().clone() },
+        ] {
+            out.push(send(client, &msg)?);
+        }
+        if let Some(digest) = self.doc.reducer_digest() {
+            let version = self.doc.reducer_version();
+            let msg = ReplicationMsg::Reducer { digest, version };
+            out.push(send(client, &msg)?);
+        }
+
+        self.clients.insert(client, session);
+        Ok((client, out))
+    }
+
+    /// accept a connection authenticated as ctx, counting it against its
+    /// tenant's connection quota until release_tenants sees it closed.
+    /// fails with ServerError::Tenant if the document isn't the tenant's or
+    /// the tenant is at its quota, see the tenant module
+    pub fn accept_tenant(
+        &mut self,
+        registry: &mut TenantRegistry,
+        ctx: AuthContext,
+        now_ms: i64,
+    ) -> Result<(ClientId, Vec<ServerOutput>), ServerError> {
+        registry.connect(&ctx, self.doc.doc_id())?;
+        match self.accept(now_ms) {
+            Ok((client, out)) => {
+                self.tenant_clients.insert(client, ctx);
+                Ok((client, out))
+            }
+            Err(err) => {
+                registry.disconnect(&ctx);
+                Err(err)
+            }
+        }
+    }
+
+    /// stop counting the connections which have closed since the last call
+    /// against their tenants' quotas. should be called after disconnecting
+    /// clients and after sending the ServerOutputs which close them
+    pub fn release_tenants(&mut self, registry: &mut TenantRegistry) {
+        let clients = &self.clients;
+        self.tenant_clients.retain(|client, ctx| {
+            let open = clients.contains_key(client);
+            if !open {
+                registry.disconnect(ctx);
+            }
+            open
+        });
+    }
+
+    /// record the document's size with its tenant, and cap the database at
+    /// the size which keeps the tenant within its storage quota. should be
+    /// called after stepping the document
+    pub fn apply_tenant_quota(
+        &mut self,
+        registry: &mut TenantRegistry,
+    ) -> Result<(), ServerError> {
+        let bytes = self.doc.database_size()?;
+        let limit = registry.record_storage(self.doc.doc_id(), bytes)?;
+        let quota =
+            StorageQuota { max_database_bytes: limit, ..self.doc.quota() };
+        if quota != self.doc.quota() {
+            self.doc.set_quota(quota)?;
+        }
+        Ok(())
+    }
+
+    /// handle a binary message from a client. If the message can't be
+    /// handled, the client is told why and disconnected
+    pub fn receive(
+        &mut self,
+        client: ClientId,
+        msg: &[u8],
+        now_ms: i64,
+    ) -> Vec<ServerOutput> {
+        match self.handle(client, msg, now_ms) {
+            Ok(out) => out,
+            Err(ServerError::UnknownClient(_)) => {
+                logging::warn!(
+                    doc = self.doc.doc_id();
+                    "received message from unknown client {}",
+                    client
+                );
+                vec![]
+            }
+            Err(err) => {
+                logging::error!(
+                    doc = self.doc.doc_id();
+                    "error handling message from {}: {}",
+                    client,
+                    err
+                );
+                self.close_with_error(
+                    client,
+                    ProtocolError::Retryable {
+                        message: err.to_string(),
+                        retry_after_ms: None,
+                    },
+                )
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        client: ClientId,
+        msg: &[u8],
+        now_ms: i64,
+    ) -> Result<Vec<ServerOutput>, ServerError> {
+        let session = self
+            .clients
+            .get_mut(&client)
+            .ok_or(ServerError::UnknownClient(client))?;
+        let mut out: Vec<ServerOutput> = session
+            .receive(&mut self.doc, msg, now_ms)?
+            .map(|msg| ServerOutput::Send { client, msg })
+            .into_iter()
+            .collect();
+        let reducer_request = session.take_reducer_request();
+        let resync_request = session.take_resync_request();
+
+        if let Some(request) = resync_request {
+            let msg = match request {
+                ResyncRequest::Tree(nodes) => {
+                    let tree = self.doc.page_tree()?;
+                    encode(&ReplicationMsg::TreeHashes {
+                        lsn: self.doc.source_range().last(),
+                        hashes: tree.hashes(&nodes),
+                    })?
+                }
+                ResyncRequest::Repair(leaves) => {
+                    let repair = self.doc.repair_pages(&leaves)?;
+                    encode_repair(leaves, &repair)?
+                }
+                ResyncRequest::History(lsn) => {
+                    let checkpoint = self.doc.checkpoint_at(lsn)?;
+                    encode_history(lsn, checkpoint.as_ref())?
+                }
+            };
+            out.push(ServerOutput::Send { client, msg });
+        }
+        if let Some(digest) = reducer_request {
+            match &self.reducer_wasm {
+                Some((ours, wasm)) if *ours == digest => {
+                    for msg in encode_reducer(digest, wasm)? {
+                        out.push(ServerOutput::Send { client, msg });
+                    }
+                }
+                // the client can't run our reducer, and reconnecting won't
+                // change that
+                _ => {
+                    logging::warn!(
+                        doc = self.doc.doc_id();
+                        "client {} requested a reducer we don't serve",
+                        client
+                    );
+                    let error = ProtocolError::ReducerMismatch(format!(
+                        "reducer {} isn't served by this coordinator",
+                        bs58::encode(digest).into_string()
+                    ));
+                    out.extend(self.close_with_error(client, error));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// forget a client whose connection has closed
+    pub fn disconnect(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+    }
+
+    /// send each client the storage frames it's missing; clients which can't
+    /// be synced are disconnected
+    pub fn sync(&mut self) -> Vec<ServerOutput> {
+        let mut out = vec![];
+        let mut failed = vec![];
+        for (&client, session) in self.clients.iter_mut() {
+            match session.sync(&self.doc) {
+                Ok(frames) => out.extend(
+                    frames
+                        .into_iter()
+                        .map(|msg| ServerOutput::Send { client, msg }),
+                ),
+                Err(err) => {
+                    logging::error!(
+                        doc = self.doc.doc_id();
+                        "error syncing client {}: {}",
+                        client,
+                        err
+                    );
+                    failed.push(client);
+                }
+            }
+        }
+        for client in failed {
+            self.clients.remove(&client);
+            out.push(ServerOutput::Close { client });
+        }
+        out
+    }
+
+    /// ping idle clients and disconnect the ones which have stopped
+    /// responding; should be called about once a second
+    pub fn heartbeat(&mut self, now_ms: i64) -> Vec<ServerOutput> {
+        let mut out = vec![];
+        let mut dead = vec![];
+        for (&client, session) in self.clients.iter_mut() {
+            match session.heartbeat(now_ms) {
+                Ok(None) => {}
+                Ok(Some(msg)) => out.push(ServerOutput::Send { client, msg }),
+                Err(SessionError::TimedOut) => {
+                    logging::info!(
+                        doc = self.doc.doc_id();
+                        "client {} heartbeat timed out",
+                        client
+                    );
+                    dead.push(client);
+                }
+                Err(err) => {
+                    logging::error!(
+                        doc = self.doc.doc_id();
+                        "error pinging client {}: {}",
+                        client,
+                        err
+                    );
+                    dead.push(client);
+                }
+            }
+        }
+        for client in dead {
+            self.clients.remove(&client);
+            out.push(ServerOutput::Close { client });
+        }
+        out
+    }
+
+    /// sync every client one last time and then ask them to reconnect after
+    /// retry_after_ms, for when the coordinator is shutting down. Pending
+    /// work should be drained and persisted first
+    pub fn disconnect_all(&mut self, retry_after_ms: u32) -> Vec<ServerOutput> {
+        let mut out = self.sync();
+        let reconnect = ReplicationMsg::ReconnectLater { retry_after_ms };
+        for client in std::mem::take(&mut self.clients).into_keys() {
+            if let Ok(msg) = send(client, &reconnect) {
+                out.push(msg);
+            }
+            out.push(ServerOutput::Close { client });
+        }
+        out
+    }
+
+    /// redact the document (see CoordinatorDocument::redact) and ask every
+    /// client to reconnect right away, as the connected clients still hold
+    /// the redacted history. they resync from the new epoch when they do
+    pub fn redact<F>(
+        &mut self,
+        purge: F,
+    ) -> Result<(u64, Vec<ServerOutput>), crate::error::Error>
+    where
+        F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<()>,
+    {
+        let epoch = self.doc.redact(purge)?;
+        let reconnect = ReplicationMsg::ReconnectLater { retry_after_ms: 0 };
+        let mut out = vec![];
+        for client in std::mem::take(&mut self.clients).into_keys() {
+            if let Ok(msg) = send(client, &reconnect) {
+                out.push(msg);
+            }
+            out.push(ServerOutput::Close { client });
+        }
+        Ok((epoch, out))
+    }
+
+    /// disconnect the clients whose mutations the document has rejected for
+    /// exceeding its StorageQuota (which apply_tenant_quota may have set),
+    /// telling them why. should be called after stepping the document
+    pub fn reject_over_quota(&mut self) -> Vec<ServerOutput> {
+        let mut out = vec![];
+        for rejection in self.doc.take_rejections() {
+            let clients: Vec<ClientId> = self
+                .clients
+                .iter()
+                .filter(|(_, session)| {
+                    session.remote_id() == Some(rejection.timeline)
+                })
+                .map(|(&client, _)| client)
+                .collect();
+            for client in clients {
+                out.extend(
+                    self.close_with_error(client, rejection.protocol_error()),
+                );
+            }
+        }
+        out
+    }
+
+    /// tell the client why it's being disconnected
+    pub fn close_with_error(
+        &mut self,
+        client: ClientId,
+        error: ProtocolError,
+    ) -> Vec<ServerOutput> {
+        self.clients.remove(&client);
+        let mut out = vec![];
+        if let Ok(msg) = send(client, &ReplicationMsg::Error { error }) {
+            out.push(msg);
+        }
+        out.push(ServerOutput::Close { client });
+        out
+    }
+}
+
+fn send(
+    client: ClientId,
+    msg: &ReplicationMsg,
+) -> Result<ServerOutput, ServerError> {
+    Ok(ServerOutput::Send { client, msg: encode(msg)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use super::{
+        persist, replay, CoordinatorServer, FrameStorage, ServerError,
+        ServerOutput,
+    };
+    use crate::{
+        compaction::{Compactor, FrameThreshold},
+        coordinator::tests::{count, counting_doc, counting_guest, frame},
+        coordinator::CoordinatorDocument,
+        error::Error,
+        local::{LocalDocument, NoopSignal, StorageRetention},
+        page::{SparsePages, PAGESIZE},
+        replication::{
+            HeartbeatConfig, ProtocolError, ReplicationDestination,
+            ReplicationMsg, ReplicationSource,
+        },
+        resync::TreeNode,
+        session::{encode, Session},
+        snapshot::{Checkpoint, Snapshot},
+        tenant::{
+            Authenticator, TenantError, TenantId, TenantQuota, TenantRegistry,
+        },
+        Journal, JournalId, LsnRange, MemoryJournal, MemoryJournalFactory,
+        Reducer, Scannable,
+    };
+
+    #[derive(Default)]
+    struct VecStorage(Vec<Vec<u8>>);
+
+    impl FrameStorage for VecStorage {
+        type Error = Infallible;
+
+        fn range(&self) -> LsnRange {
+            match self.0.len() {
+                0 => LsnRange::empty(),
+                len => LsnRange::new(0, len as u64 - 1),
+            }
+        }
+
+        async fn read_frame(&self, lsn: u64) -> Result<Vec<u8>, Infallible> {
+            Ok(self.0[lsn as usize].clone())
+        }
+
+        async fn write_frame(
+            &mut self,
+            lsn: u64,
+            frame: Vec<u8>,
+        ) -> Result<(), Infallible> {
+            assert_eq!(lsn, self.0.len() as u64);
+            self.0.push(frame);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn persists_and_replays_frames() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut source = MemoryJournal::open(id).unwrap();
+        let mut storage = VecStorage::default();
+
+        source.append(&b"a"[..]).unwrap();
+        source.append(&b"b"[..]).unwrap();
+        let written =
+            futures::executor::block_on(persist(&source, &mut storage));
+        assert_eq!(written.unwrap(), 2);
+
+        // only new frames are written
+        source.append(&b"c"[..]).unwrap();
+        let written =
+            futures::executor::block_on(persist(&source, &mut storage));
+        assert_eq!(written.unwrap(), 1);
+        assert_eq!(storage.range(), LsnRange::new(0, 2));
+
+        let mut dest = MemoryJournal::open(id).unwrap();
+        futures::executor::block_on(replay(&storage, id, &mut dest)).unwrap();
+        assert_eq!(dest.range(), LsnRange::new(0, 2));
+        assert_eq!(dest.get(2).unwrap(), Some(&b"c"[..]));
+    }
+
+    #[test]
+    fn unserved_reducers_are_a_mismatch() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        server.serve_reducer(b"ours".to_vec());
+        let (client, _) = server.accept(0).unwrap();
+
+        let request = ReplicationMsg::ReducerRequest { digest: [1; 32] };
+        let out = server.receive(client, &encode(&request).unwrap(), 0);
+        let [ServerOutput::Send { msg, .. }, ServerOutput::Close { .. }] =
+            &out[..]
+        else {
+            panic!("expected an error and a close, got {:?}", out);
+        };
+        let msg: ReplicationMsg = bincode::deserialize(msg).unwrap();
+        assert!(matches!(
+            msg,
+            ReplicationMsg::Error { error: ProtocolError::ReducerMismatch(_) }
+        ));
+        assert_eq!(server.num_clients(), 0);
+    }
+
+    type Local = LocalDocument<MemoryJournal, NoopSignal>;
+
+    /// deliver messages between server and local until neither has
+    /// anything more to send; messages for other clients are dropped
+    fn pump(
+        server: &mut CoordinatorServer<MemoryJournal>,
+        client: u64,
+        session: &mut Session,
+        local: &mut Local,
+        mut to_server: Vec<Vec<u8>>,
+    ) {
+        loop {
+            let mut to_client = vec![];
+            for msg in to_server.drain(..) {
+                to_client.extend(server.receive(client, &msg, 0));
+            }
+            to_client.extend(server.sync());
+            if to_client.is_empty() {
+                return;
+            }
+            for out in to_client {
+                let ServerOutput::Send { client: to, msg } = out else {
+                    panic!("server closed the connection");
+                };
+                if to != client {
+                    continue;
+                }
+                to_server.extend(session.receive(local, &msg, 0).unwrap());
+            }
+            to_server.extend(session.sync(&*local).unwrap());
+        }
+    }
+
+    /// connect a new local document to server and replicate until both
+    /// sides are idle
+    fn connect(
+        server: &mut CoordinatorServer<MemoryJournal>,
+    ) -> (u64, Session, Local) {
+        let mut local = LocalDocument::open(
+            MemoryJournal::open(server.doc().doc_id()).unwrap(),
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap(),
+            Reducer::new(&counting_guest()[..]).unwrap(),
+            NoopSignal,
+            NoopSignal,
+            NoopSignal,
+        )
+        .unwrap();
+        let (client, session) = reconnect(server, &mut local);
+        (client, session, local)
+    }
+
+    /// connect an existing local document to server and replicate until
+    /// both sides are idle
+    fn reconnect(
+        server: &mut CoordinatorServer<MemoryJournal>,
+        local: &mut Local,
+    ) -> (u64, Session) {
+        let (client, out) = server.accept(0).unwrap();
+        let mut session = Session::new(HeartbeatConfig::default(), 0);
+        let mut to_server = vec![session.start(&*local).unwrap()];
+        for out in out {
+            let ServerOutput::Send { msg, .. } = out else {
+                unreachable!()
+            };
+            to_server.extend(session.receive(local, &msg, 0).unwrap());
+        }
+        pump(server, client, &mut session, local, to_server);
+        (client, session)
+    }
+
+    fn decode(out: &ServerOutput) -> (u64, Option<ReplicationMsg>) {
+        match out {
+            ServerOutput::Send { client, msg } => {
+                (*client, Some(bincode::deserialize(msg).unwrap()))
+            }
+            ServerOutput::Close { client } => (*client, None),
+        }
+    }
+
+    fn local_count(local: &Local) -> i64 {
+        local
+            .query(|conn| {
+                conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn replicates_between_clients() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let (a, mut a_session, mut a_local) = connect(&mut server);
+        let (b, mut b_session, mut b_local) = connect(&mut server);
+        assert_ne!(a, b);
+        assert_eq!(server.num_clients(), 2);
+
+        a_local.rebase().unwrap();
+        a_local.mutate(b"m").unwrap();
+        let frames = a_session.sync(&a_local).unwrap();
+        assert_eq!(frames.len(), 1);
+        pump(&mut server, a, &mut a_session, &mut a_local, frames);
+        server.doc_mut().drain().unwrap();
+        assert_eq!(count(server.doc()), 1);
+
+        // the new storage frame reaches the other client on the next sync
+        pump(&mut server, b, &mut b_session, &mut b_local, vec![]);
+        b_local.rebase().unwrap();
+        assert_eq!(local_count(&b_local), 1);
+    }
+
+    #[test]
+    fn clients_behind_a_compaction_are_reset() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let mut compactor = Compactor::new(vec![Box::new(FrameThreshold(1))]);
+        compactor.set_min_interval(Duration::ZERO);
+        server.set_compactor(compactor);
+        let (a, mut a_session, mut a_local) = connect(&mut server);
+        let (b, mut b_session, mut b_local) = connect(&mut server);
+
+        let mut mutate = |server: &mut CoordinatorServer<MemoryJournal>| {
+            a_local.rebase().unwrap();
+            a_local.mutate(b"m").unwrap();
+            let frames = a_session.sync(&a_local).unwrap();
+            pump(server, a, &mut a_session, &mut a_local, frames);
+            server.doc_mut().drain().unwrap();
+            pump(server, a, &mut a_session, &mut a_local, vec![]);
+        };
+
+        // b receives the first mutation and then goes away
+        mutate(&mut server);
+        pump(&mut server, b, &mut b_session, &mut b_local, vec![]);
+        server.disconnect(b);
+        mutate(&mut server);
+        mutate(&mut server);
+
+        // frames which haven't been persisted are never folded
+        assert_eq!(server.compact(None, 0).unwrap(), 0);
+        let durable = server.doc().source_range().last();
+        assert!(server.compact(durable, 0).unwrap() > 0);
+        assert_eq!(server.compactor().unwrap().metrics().runs, 1);
+
+        // b is missing frames which have been folded away, so it starts
+        // over from the folded frame
+        reconnect(&mut server, &mut b_local);
+        b_local.rebase().unwrap();
+        assert_eq!(local_count(&b_local), 3);
+    }
+
+    #[test]
+    fn folded_history_is_fetched_from_the_coordinator() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let (client, mut session, mut local) = connect(&mut server);
+        local.set_storage_retention(Some(StorageRetention {
+            max_frames: 2,
+            keep_frames: 1,
+        }));
+        for _ in 0..4 {
+            local.rebase().unwrap();
+            local.mutate(b"m").unwrap();
+            let frames = session.sync(&local).unwrap();
+            pump(&mut server, client, &mut session, &mut local, frames);
+            server.doc_mut().drain().unwrap();
+            pump(&mut server, client, &mut session, &mut local, vec![]);
+        }
+        local.rebase().unwrap();
+        assert_eq!(local_count(&local), 4);
+
+        let count_at = |local: &Local, lsn| {
+            local.query_at(lsn, |conn| {
+                conn.query_row("SELECT count(*) FROM t", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+            })
+        };
+        let first = server.doc().history_range().first().unwrap();
+        assert!(local.history_range().first() > Some(first));
+        assert!(matches!(
+            count_at(&local, first),
+            Err(Error::LsnNotRetained { .. })
+        ));
+
+        let request = encode(&local.request_history(first)).unwrap();
+        pump(&mut server, client, &mut session, &mut local, vec![request]);
+        assert_eq!(count_at(&local, first).unwrap(), 0);
+
+        // lsns the coordinator doesn't retain either still fail
+        let missing = server.doc().source_range().last().unwrap() + 10;
+        let request = encode(&local.request_history(missing)).unwrap();
+        pump(&mut server, client, &mut session, &mut local, vec![request]);
+        assert!(count_at(&local, missing).is_err());
+        let last = local.history_range().last().unwrap();
+        assert_eq!(count_at(&local, last).unwrap(), 4);
+    }
+
+    #[test]
+    fn bad_messages_close_the_client() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let (client, _) = server.accept(0).unwrap();
+
+        // clients which have already gone are ignored
+        assert!(server.receive(client + 1, b"\xff", 0).is_empty());
+        assert_eq!(server.num_clients(), 1);
+
+        let out = server.receive(client, b"\xff\xff\xff\xff\xff", 0);
+        let msgs: Vec<_> = out.iter().map(decode).collect();
+        let [(c1, Some(ReplicationMsg::Error { error })), (c2, None)] =
+            &msgs[..]
+        else {
+            panic!("expected an error and a close, got {:?}", msgs);
+        };
+        assert_eq!((*c1, *c2), (client, client));
+        assert!(matches!(error, ProtocolError::Retryable { .. }));
+        assert_eq!(server.num_clients(), 0);
+        assert!(server.receive(client, b"\xff", 0).is_empty());
+    }
+
+    #[test]
+    fn heartbeats_ping_and_then_drop_clients() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let config = HeartbeatConfig::default();
+        server.set_heartbeat_config(config);
+        let (client, _) = server.accept(0).unwrap();
+
+        assert!(server.heartbeat(0).is_empty());
+        let interval = config.interval.as_millis() as i64;
+        let out: Vec<_> =
+            server.heartbeat(interval).iter().map(decode).collect();
+        assert!(matches!(
+            &out[..],
+            [(c, Some(ReplicationMsg::Ping { .. }))] if *c == client
+        ));
+
+        let timeout = config.timeout.as_millis() as i64;
+        assert_eq!(
+            server.heartbeat(timeout),
+            vec![ServerOutput::Close { client }]
+        );
+        assert_eq!(server.num_clients(), 0);
+    }
+
+    #[test]
+    fn clients_are_closed_with_a_reason() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let (a, _) = server.accept(0).unwrap();
+        let (b, _) = server.accept(0).unwrap();
+        let (c, _) = server.accept(0).unwrap();
+
+        let error = ProtocolError::Retryable {
+            message: "going away".into(),
+            retry_after_ms: Some(10),
+        };
+        let out: Vec<_> = server
+            .close_with_error(a, error)
+            .iter()
+            .map(decode)
+            .collect();
+        assert!(matches!(
+            &out[..],
+            [
+                (
+                    _,
+                    Some(ReplicationMsg::Error {
+                        error: ProtocolError::Retryable {
+                            retry_after_ms: Some(10),
+                            ..
+                        }
+                    })
+                ),
+                (_, None)
+            ]
+        ));
+
+        server.disconnect(b);
+        assert_eq!(server.num_clients(), 1);
+
+        let out: Vec<_> =
+            server.disconnect_all(500).iter().map(decode).collect();
+        assert!(matches!(
+            &out[..],
+            [
+                (c1, Some(ReplicationMsg::ReconnectLater { retry_after_ms: 500 })),
+                (c2, None)
+            ] if *c1 == c && *c2 == c
+        ));
+        assert_eq!(server.num_clients(), 0);
+    }
+
+    #[test]
+    fn repairs_a_divergent_replica() {
+        let mut server = CoordinatorServer::new(counting_doc());
+        let timeline = JournalId::new128(&mut rand::thread_rng());
+        for lsn in 0..3 {
+            server
+                .doc_mut()
+                .write_lsn(timeline, lsn, &mut &frame(b"m")[..])
+                .unwrap();
+        }
+        server.doc_mut().drain().unwrap();
+
+        let doc_id = server.doc().doc_id();
+        let (client, mut session, mut local) = connect(&mut server);
+        local.rebase().unwrap();
+        let tree = server.doc().page_tree().unwrap();
+        assert_eq!(local.page_tree().unwrap().root(), tree.root());
+
+        // diverge by gaining a page the coordinator doesn't have
+        let lsn = server.doc().source_range().last().unwrap();
+        let mut pages = SparsePages::new();
+        pages.write(1000, [7; PAGESIZE]);
+        let bogus = Checkpoint { doc_id, lsn, snapshot: Snapshot::new(pages) };
+        let leaf = TreeNode { level: 0, index: 1000 >> 6 };
+        local.repair(&[leaf], bogus).unwrap();
+        assert_ne!(local.page_tree().unwrap().root(), tree.root());
+
+        let start = encode(&local.start_resync().unwrap()).unwrap();
+        pump(&mut server, client, &mut session, &mut local, vec![start]);
+        assert_eq!(local.page_tree().unwrap().root(), tree.root());
+        let count: i64 = local
+            .query(|conn| {
+                conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    struct AnyCredentials;
+
+    impl Authenticator for AnyCredentials {
+        fn authenticate(&self, _credentials: &[u8]) -> Option<String> {
+            Some("user".into())
+        }
+    }
+
+    #[test]
+    fn tenant_connections_and_storage_are_counted() {
+        let mut registry = TenantRegistry::new();
+        let acme = TenantId::new("acme").unwrap();
+        let quota = TenantQuota {
+            max_storage_bytes: Some(1 << 20),
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        registry.add_tenant(acme.clone(), quota, Box::new(AnyCredentials));
+        let ctx = registry.authenticate(&acme, b"").unwrap();
+        let doc_id = registry.open_document(&ctx, "doc").unwrap();
+
+        let doc = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id).unwrap(),
+            MemoryJournalFactory,
+            &counting_guest(),
+        )
+        .unwrap();
+        let mut server = CoordinatorServer::new(doc);
+        let (client, _) =
+            server.accept_tenant(&mut registry, ctx.clone(), 0).unwrap();
+        assert!(matches!(
+            server.accept_tenant(&mut registry, ctx.clone(), 0),
+            Err(ServerError::Tenant(TenantError::QuotaExceeded { .. }))
+        ));
+
+        // a closed connection is counted until it's released
+        server.disconnect(client);
+        assert_eq!(registry.usage(&acme).unwrap().connections, 1);
+        server.release_tenants(&mut registry);
+        assert_eq!(registry.usage(&acme).unwrap().connections, 0);
+        server.accept_tenant(&mut registry, ctx, 0).unwrap();
+
+        server.apply_tenant_quota(&mut registry).unwrap();
+        let usage = registry.usage(&acme).unwrap();
+        assert_eq!(usage.storage_bytes, server.doc().database_size().unwrap());
+        assert_eq!(server.doc().quota().max_database_bytes, Some(1 << 20));
+    }
+}
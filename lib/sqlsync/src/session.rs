@@ -0,0 +1,353 @@
+//! Session is one end of a replication connection without any io: the host
+//! feeds it the binary messages it receives and sends the messages it
+//! returns. It wraps a ReplicationProtocol and a Heartbeat along with the
+//! wire encoding, so the same state machine drives a connection whether the
+//! host is a browser worker, a native binding, or a coordinator.
+//!
+//! Sessions never read the clock; every call which cares about time takes
+//! the current unix time in milliseconds.
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::{
+    logging,
+    positioned_io::PositionedReader,
+    replication::{
+        Heartbeat, HeartbeatAction, HeartbeatConfig, ReplicationDestination,
+        ReplicationError, ReplicationMsg, ReplicationProtocol,
+        ReplicationSource, REDUCER_CHUNK_BYTES,
+    },
+    resync::TreeNode,
+    snapshot::Checkpoint,
+    JournalId, Lsn, ModuleDigest, Serializable,
+};
+
+/// the most page tree nodes one resync request may ask for; a tree has
+/// 2^FANOUT_BITS children per node, so a client never needs more than the
+/// children of every differing node on a level
+const MAX_RESYNC_NODES: usize = 1 << 14;
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    Replication(#[from] ReplicationError),
+
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+
+    #[error("connection timed out")]
+    TimedOut,
+
+    #[error("resync request for {0} nodes, the limit is {MAX_RESYNC_NODES}")]
+    ResyncTooLarge(usize),
+}
+
+/// a request for the coordinator's pages, see resync
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResyncRequest {
+    /// the hashes of these nodes of the page tree
+    Tree(Vec<TreeNode>),
+    /// the pages in these leaves
+    Repair(Vec<TreeNode>),
+    /// every page as of this storage lsn
+    History(Lsn),
+}
+
+pub struct Session {
+    protocol: ReplicationProtocol,
+    heartbeat: Heartbeat,
+    last_progress_ms: Option<i64>,
+    reducer_request: Option<ModuleDigest>,
+    resync_request: Option<ResyncRequest>,
+}
+
+impl Session {
+    pub fn new(heartbeat: HeartbeatConfig, now_ms: i64) -> Self {
+        Self {
+            protocol: ReplicationProtocol::new(),
+            heartbeat: Heartbeat::new(heartbeat, now_ms),
+            last_progress_ms: None,
+            reducer_request: None,
+            resync_request: None,
+        }
+    }
+
+    /// the message which starts replicating doc to the remote side
+    pub fn start<D: ReplicationSource>(
+        &self,
+        doc: &D,
+    ) -> Result<Vec<u8>, SessionError> {
+        encode(&self.protocol.start(doc))
+    }
+
+    /// true once the remote side has told us which frames it has
+    pub fn initialized(&self) -> bool {
+        self.protocol.initialized()
+    }
+
+    /// the last lsn the remote side has acknowledged, see
+    /// ReplicationProtocol::acked_lsn
+    pub fn acked_lsn(&self) -> Option<Lsn> {
+        self.protocol.acked_lsn()
+    }
+
+    /// the journal the remote side replicates to us, for a coordinator the
+    /// client's timeline
+    pub fn remote_id(&self) -> Option<JournalId> {
+        self.protocol.remote_id()
+    }
+
+    /// whether doc has caught up with the remote side as of when this
+    /// session started, see ReplicationProtocol::caught_up
+    pub fn caught_up<D: ReplicationDestination>(
+        &self,
+        doc: &mut D,
+    ) -> Result<bool, SessionError> {
+        Ok(self.protocol.caught_up(doc)?)
+    }
+
+    /// the frames we've sent which the remote side hasn't acknowledged
+    pub fn unacked_frames(&self) -> usize {
+        self.protocol.unacked_frames()
+    }
+
+    /// the round trip time to the remote side, measured by the heartbeat
+    pub fn rtt_ms(&self) -> Option<i64> {
+        self.heartbeat.rtt_ms()
+    }
+
+    /// when the remote side last acknowledged our frames or sent us one of
+    /// its own, in unix milliseconds
+    pub fn last_progress_ms(&self) -> Option<i64> {
+        self.last_progress_ms
+    }
+
+    /// the reducer the remote side has asked for since the last call, which
+    /// only a host holding the reducer's wasm can answer
+    pub fn take_reducer_request(&mut self) -> Option<ModuleDigest> {
+        self.reducer_request.take()
+    }
+
+    /// the resync request the remote side has sent since the last call,
+    /// which only a host with the coordinator's document can answer
+    pub fn take_resync_request(&mut self) -> Option<ResyncRequest> {
+        self.resync_request.take()
+    }
+
+    /// handle a binary message from the remote side, returning the response
+    /// to send, if any
+    pub fn receive<D: ReplicationDestination>(
+        &mut self,
+        doc: &mut D,
+        msg: &[u8],
+        now_ms: i64,
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        let mut cursor = io::Cursor::new(msg);
+        let msg: ReplicationMsg = bincode::deserialize_from(&mut cursor)?;
+        logging::debug!("received message: {:?}", msg);
+        match msg {
+            ReplicationMsg::Pong { nonce } => {
+                self.heartbeat.ponged(nonce, now_ms)
+            }
+            ReplicationMsg::Range { .. } | ReplicationMsg::Frame { .. } => {
+                self.last_progress_ms = Some(now_ms)
+            }
+            ReplicationMsg::ReducerRequest { digest } => {
+                self.reducer_request = Some(digest)
+            }
+            ReplicationMsg::TreeRequest { ref nodes }
+            | ReplicationMsg::RepairRequest { leaves: ref nodes }
+                if nodes.len() > MAX_RESYNC_NODES =>
+            {
+                return Err(SessionError::ResyncTooLarge(nodes.len()));
+            }
+            ReplicationMsg::TreeRequest { ref nodes } => {
+                self.resync_request = Some(ResyncRequest::Tree(nodes.clone()))
+            }
+            ReplicationMsg::RepairRequest { ref leaves } => {
+                self.resync_request =
+                    Some(ResyncRequest::Repair(leaves.clone()))
+            }
+            ReplicationMsg::HistoryRequest { lsn } => {
+                self.resync_request = Some(ResyncRequest::History(lsn))
+            }
+            _ => {}
+        }
+        self.heartbeat.received(now_ms);
+
+        match self.protocol.handle(doc, msg, &mut cursor)? {
+            Some(resp) => Ok(Some(encode(&resp)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// the frames the remote side is missing, as many as its window allows.
+    /// a remote side which is missing frames that have been compacted away
+    /// is reset first, see ReplicationProtocol::reset
+    pub fn sync<D: ReplicationSource>(
+        &mut self,
+        doc: &D,
+    ) -> Result<Vec<Vec<u8>>, SessionError> {
+        let mut out = vec![];
+        if let Some(msg) = self.protocol.reset(doc) {
+            out.push(encode(&msg)?);
+        }
+        while let Some((msg, frame)) = self.protocol.sync(doc)? {
+            logging::debug!("sending message: {:?}", msg);
+            let mut buf = bincode::serialize(&msg)?;
+            buf.extend(frame.read_all().map_err(ReplicationError::from)?);
+            out.push(buf);
+        }
+        Ok(out)
+    }
+
+    /// returns a ping to send when the connection has been idle, and
+    /// SessionError::TimedOut once the remote side has stopped responding
+    pub fn heartbeat(
+        &mut self,
+        now_ms: i64,
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        match self.heartbeat.poll(now_ms) {
+            HeartbeatAction::Wait => Ok(None),
+            HeartbeatAction::Ping(msg) => Ok(Some(encode(&msg)?)),
+            HeartbeatAction::TimedOut => Err(SessionError::TimedOut),
+        }
+    }
+
+    /// unix milliseconds at which heartbeat should next be called
+    pub fn deadline(&self) -> i64 {
+        self.heartbeat.deadline()
+    }
+}
+
+/// encode a message for the wire
+pub fn encode(msg: &ReplicationMsg) -> Result<Vec<u8>, SessionError> {
+    logging::debug!("sending message: {:?}", msg);
+    Ok(bincode::serialize(msg)?)
+}
+
+/// encode a reducer's wasm for the wire as a message per chunk, in reply
+/// to a ReducerRequest
+pub fn encode_reducer(
+    digest: ModuleDigest,
+    wasm: &[u8],
+) -> Result<Vec<Vec<u8>>, SessionError> {
+    let total = wasm.len() as u64;
+    let mut msgs = Vec::new();
+    for (i, chunk) in wasm.chunks(REDUCER_CHUNK_BYTES).enumerate() {
+        let offset = (i * REDUCER_CHUNK_BYTES) as u64;
+        let len = chunk.len() as u64;
+        let mut buf = encode(&ReplicationMsg::ReducerWasm {
+            digest,
+            offset,
+            total,
+            len,
+        })?;
+        buf.extend_from_slice(chunk);
+        msgs.push(buf);
+    }
+    Ok(msgs)
+}
+
+/// encode the pages in leaves for the wire, in reply to a RepairRequest
+pub fn encode_repair(
+    leaves: Vec<TreeNode>,
+    repair: &Checkpoint,
+) -> Result<Vec<u8>, SessionError> {
+    let data = repair.to_vec().map_err(ReplicationError::from)?;
+    let len = data.len() as u64;
+    let mut buf = encode(&ReplicationMsg::Repair { leaves, len })?;
+    buf.extend(data);
+    Ok(buf)
+}
+
+/// encode the document as of lsn for the wire, in reply to a HistoryRequest.
+/// None tells the remote side that lsn isn't retained
+pub fn encode_history(
+    lsn: Lsn,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<u8>, SessionError> {
+    let data = match checkpoint {
+        Some(checkpoint) => {
+            checkpoint.to_vec().map_err(ReplicationError::from)?
+        }
+        None => vec![],
+    };
+    let len = data.len() as u64;
+    let mut buf = encode(&ReplicationMsg::History { lsn, len })?;
+    buf.extend(data);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::Session;
+    use crate::{
+        replication::HeartbeatConfig, Journal, JournalId, LsnRange,
+        MemoryJournal, Scannable,
+    };
+
+    #[test]
+    fn replicates_between_sessions() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut source = MemoryJournal::open(id).unwrap();
+        let mut dest = MemoryJournal::open(id).unwrap();
+        source.append(&b"a"[..]).unwrap();
+        source.append(&b"b"[..]).unwrap();
+
+        let config = HeartbeatConfig::default();
+        let mut source_session = Session::new(config, 0);
+        let mut dest_session = Session::new(config, 0);
+        assert!(!dest_session.caught_up(&mut dest).unwrap());
+
+        // messages in flight towards dest and source respectively
+        let mut to_dest =
+            VecDeque::from([source_session.start(&source).unwrap()]);
+        let mut to_source = VecDeque::new();
+
+        while !(to_dest.is_empty() && to_source.is_empty()) {
+            while let Some(msg) = to_dest.pop_front() {
+                let resp = dest_session.receive(&mut dest, &msg, 0).unwrap();
+                to_source.extend(resp);
+            }
+            while let Some(msg) = to_source.pop_front() {
+                let resp =
+                    source_session.receive(&mut source, &msg, 0).unwrap();
+                to_dest.extend(resp);
+            }
+            if source_session.initialized() {
+                to_dest.extend(source_session.sync(&source).unwrap());
+            }
+        }
+
+        assert_eq!(dest.range(), LsnRange::new(0, 1));
+        assert!(dest_session.caught_up(&mut dest).unwrap());
+        assert_eq!(dest.get(1).unwrap(), Some(&b"b"[..]));
+        assert_eq!(source_session.unacked_frames(), 0);
+        assert_eq!(source_session.last_progress_ms(), Some(0));
+    }
+
+    #[test]
+    fn heartbeats_measure_the_round_trip() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = MemoryJournal::open(id).unwrap();
+        let config = HeartbeatConfig::default();
+        let interval = config.interval.as_millis() as i64;
+        let mut session = Session::new(config, 0);
+        let mut remote = Session::new(config, 0);
+        assert_eq!(session.rtt_ms(), None);
+
+        let ping = session.heartbeat(interval).unwrap().unwrap();
+        let pong = remote.receive(&mut journal, &ping, interval).unwrap();
+        session
+            .receive(&mut journal, &pong.unwrap(), interval + 40)
+            .unwrap();
+        assert_eq!(session.rtt_ms(), Some(40));
+        // a pong isn't progress
+        assert_eq!(session.last_progress_ms(), None);
+    }
+}
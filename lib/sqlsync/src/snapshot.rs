@@ -0,0 +1,183 @@
+//! A Snapshot is every page of a document as of some commit, packed into a
+//! single frame. New documents can be created from one (see
+//! CoordinatorDocument::create) to start from a template rather than an
+//! empty database.
+//!
+//! A Checkpoint pairs a Snapshot with the storage lsn it was taken at. A cold
+//! client can download one as a single blob (see
+//! LocalDocument::bootstrap) and then replicate incrementally from the
+//! following lsn, rather than fetching every frame from the coordinator.
+
+use std::io::{self, Read};
+
+use rusqlite::Connection;
+
+use crate::{
+    db::open_readonly,
+    error::Result,
+    page::{SerializedPagesReader, SparsePages, PAGESIZE},
+    positioned_io::PositionedReader,
+    Deserializable, Journal, JournalError, JournalId, Lsn, MemoryJournal,
+    Serializable,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pages: SparsePages,
+}
+
+impl Snapshot {
+    pub(crate) fn new(pages: SparsePages) -> Self {
+        Self { pages }
+    }
+
+    pub(crate) fn into_pages(self) -> SparsePages {
+        self.pages
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.pages.num_pages()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_pages() == 0
+    }
+}
+
+/// a Snapshot serializes to an ordinary storage frame; an empty snapshot
+/// serializes to nothing
+impl Serializable for Snapshot {
+    fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        self.pages.serialize_into(writer)
+    }
+}
+
+impl Deserializable for Snapshot {
+    fn deserialize_from<R: PositionedReader>(reader: R) -> io::Result<Self> {
+        let mut pages = SparsePages::new();
+        if reader.size()? == 0 {
+            return Ok(Self { pages });
+        }
+        let frame = SerializedPagesReader(reader);
+        for page_idx in frame.page_idxs()? {
+            let mut page = [0; PAGESIZE];
+            frame.read(page_idx, 0, &mut page)?;
+            pages.write(page_idx, page);
+        }
+        Ok(Self { pages })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub doc_id: JournalId,
+    /// the last storage lsn included in the snapshot
+    pub lsn: Lsn,
+    pub snapshot: Snapshot,
+}
+
+impl Checkpoint {
+    /// run read-only queries against the document as of the checkpoint
+    pub fn query<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<O>,
+    {
+        let mut journal = MemoryJournal::open(self.doc_id)?;
+        if !self.snapshot.is_empty() {
+            let frame = self
+                .snapshot
+                .to_vec()
+                .map_err(JournalError::SerializationError)?;
+            journal.append(&frame[..])?;
+        }
+        let (sqlite, vfs, storage) = open_readonly(journal)?;
+        let out = f(&sqlite);
+        // the connection reads through storage, so it must close first
+        drop(sqlite);
+        drop(vfs);
+        drop(storage);
+        Ok(out?)
+    }
+}
+
+/// Binary layout of a Checkpoint is:
+/// doc_id_len: u8
+/// doc_id: [u8; doc_id_len]
+/// lsn: u64
+/// snapshot: the rest of the blob, see Snapshot
+impl Serializable for Checkpoint {
+    fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let id = self.doc_id.bytes();
+        writer.write_all(&[id.len() as u8])?;
+        writer.write_all(id)?;
+        writer.write_all(&self.lsn.to_le_bytes())?;
+        self.snapshot.serialize_into(writer)
+    }
+}
+
+impl Deserializable for Checkpoint {
+    fn deserialize_from<R: PositionedReader>(reader: R) -> io::Result<Self> {
+        let data = reader.read_all()?;
+        let mut data = &data[..];
+        let mut id_len = [0; 1];
+        data.read_exact(&mut id_len)?;
+        let mut id = vec![0; id_len[0] as usize];
+        data.read_exact(&mut id)?;
+        let doc_id = JournalId::try_from(id)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut lsn = [0; 8];
+        data.read_exact(&mut lsn)?;
+        let snapshot = Snapshot::deserialize_from(data)?;
+
+        Ok(Self { doc_id, lsn: u64::from_le_bytes(lsn), snapshot })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, Snapshot};
+    use crate::{
+        page::{SparsePages, PAGESIZE},
+        Deserializable, JournalId, Serializable,
+    };
+
+    #[test]
+    fn roundtrip() {
+        let mut pages = SparsePages::new();
+        pages.write(1, [1; PAGESIZE]);
+        pages.write(3, [3; PAGESIZE]);
+        let mut buf = vec![];
+        Snapshot::new(pages).serialize_into(&mut buf).unwrap();
+
+        let snapshot = Snapshot::deserialize_from(&buf[..]).unwrap();
+        assert_eq!(snapshot.num_pages(), 2);
+        let mut page = [0; PAGESIZE];
+        assert_eq!(snapshot.into_pages().read(3, 0, &mut page), PAGESIZE);
+        assert_eq!(page, [3; PAGESIZE]);
+
+        let mut buf = vec![];
+        Snapshot::default().serialize_into(&mut buf).unwrap();
+        assert!(Snapshot::deserialize_from(&buf[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn checkpoint_roundtrip() {
+        let mut pages = SparsePages::new();
+        pages.write(2, [2; PAGESIZE]);
+        let checkpoint = Checkpoint {
+            doc_id: JournalId::new128(&mut rand::thread_rng()),
+            lsn: 42,
+            snapshot: Snapshot::new(pages),
+        };
+        let mut buf = vec![];
+        checkpoint.serialize_into(&mut buf).unwrap();
+
+        let decoded = Checkpoint::deserialize_from(&buf[..]).unwrap();
+        assert_eq!(decoded.doc_id, checkpoint.doc_id);
+        assert_eq!(decoded.lsn, 42);
+        assert_eq!(decoded.snapshot.num_pages(), 1);
+    }
+}
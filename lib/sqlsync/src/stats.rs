@@ -0,0 +1,173 @@
+//! Page stats. Every page of a document's database belongs to one of
+//! sqlite's b-trees, each of which stores a table or an index. Documents run
+//! in incremental auto_vacuum mode, so sqlite keeps a pointer map from every
+//! page back to the root of its b-tree; Storage follows it to attribute
+//! pages, and the frames which wrote them, to b-trees, and sqlite_schema
+//! names each root. Useful for filtered replication, prefetch hints and
+//! capacity planning.
+
+use rusqlite::Connection;
+
+use crate::{
+    error::Result, storage::Storage, Journal, LsnRange, PageIdx, PAGESIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BtreeKind {
+    Table,
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtreeStats {
+    /// the name of the table or index
+    pub name: String,
+    /// the table this b-tree belongs to; for tables this is the same as name
+    pub table: String,
+    pub kind: BtreeKind,
+    pub root_page: PageIdx,
+    pub pages: u64,
+    pub bytes: u64,
+    /// pages written to this b-tree by the frames in PageStats::window
+    pub page_writes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageStats {
+    /// sorted by pages, largest first
+    pub btrees: Vec<BtreeStats>,
+    /// ptrmap, freelist, and other pages which don't belong to a b-tree
+    pub other_pages: u64,
+    /// the storage lsns page_writes were counted over
+    pub window: LsnRange,
+}
+
+impl PageStats {
+    pub fn total_pages(&self) -> u64 {
+        self.btrees.iter().map(|b| b.pages).sum::<u64>() + self.other_pages
+    }
+
+    /// the b-trees belonging to table, the table itself first
+    pub fn table(&self, table: &str) -> Vec<&BtreeStats> {
+        let (mut tables, indexes): (Vec<_>, Vec<_>) = self
+            .btrees
+            .iter()
+            .filter(|b| b.table == table)
+            .partition(|b| b.kind == BtreeKind::Table);
+        tables.extend(indexes);
+        tables
+    }
+
+    /// page writes per lsn in the window, for each table and its indexes
+    pub fn change_frequency(&self, table: &str) -> f64 {
+        if self.window.is_empty() {
+            return 0.0;
+        }
+        let writes: u64 = self.table(table).iter().map(|b| b.page_writes).sum();
+        writes as f64 / self.window.len() as f64
+    }
+}
+
+pub(crate) fn page_stats<J: Journal>(
+    sqlite: &Connection,
+    storage: &Storage<J>,
+    window: LsnRange,
+) -> Result<PageStats> {
+    let mut pages = storage.pages_by_root()?;
+    let mut writes = storage.page_writes_by_root(window)?;
+
+    let mut stmt = sqlite.prepare(
+        "SELECT type, name, tbl_name, rootpage FROM sqlite_schema
+        WHERE rootpage > 0",
+    )?;
+    let schema = stmt
+        .query_map([], |row| {
+            let kind: String = row.get(0)?;
+            let kind = match kind.as_str() {
+                "index" => BtreeKind::Index,
+                _ => BtreeKind::Table,
+            };
+            Ok((kind, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(BtreeKind, String, String, u32)>>>()?;
+
+    // sqlite_schema isn't listed in itself, but is always rooted at page 1
+    let schema_table = (
+        BtreeKind::Table,
+        "sqlite_schema".into(),
+        "sqlite_schema".into(),
+        1,
+    );
+
+    let mut btrees: Vec<BtreeStats> = std::iter::once(schema_table)
+        .chain(schema)
+        .map(|(kind, name, table, root_page)| {
+            let num_pages = pages.remove(&Some(root_page)).unwrap_or(0);
+            BtreeStats {
+                name,
+                table,
+                kind,
+                root_page,
+                pages: num_pages,
+                bytes: num_pages * PAGESIZE as u64,
+                page_writes: writes.remove(&Some(root_page)).unwrap_or(0),
+            }
+        })
+        .collect();
+    btrees.sort_by(|a, b| b.pages.cmp(&a.pages).then(a.name.cmp(&b.name)));
+
+    // anything left over doesn't belong to a b-tree in the schema
+    let other_pages = pages.values().sum();
+
+    let window = window.intersect(&storage.visible_range());
+    Ok(PageStats { btrees, other_pages, window })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page_stats, BtreeKind};
+    use crate::{db::open_with_vfs, JournalId, LsnRange, MemoryJournal};
+
+    #[test]
+    fn attributes_pages_to_tables() {
+        let journal =
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap();
+        let (sqlite, mut storage) = open_with_vfs(journal).unwrap();
+        sqlite
+            .readwrite
+            .execute_batch(
+                "CREATE TABLE t (id INTEGER, body BLOB);
+                CREATE INDEX t_id ON t (id);
+                WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 100)
+                INSERT INTO t SELECT x, randomblob(1000) FROM n;",
+            )
+            .unwrap();
+        storage.commit().unwrap();
+
+        let stats =
+            page_stats(&sqlite.readonly, &storage, LsnRange::empty()).unwrap();
+        let t = stats.table("t");
+        assert_eq!(t.len(), 2);
+        assert_eq!((t[0].name.as_str(), t[0].kind), ("t", BtreeKind::Table));
+        assert_eq!((t[1].name.as_str(), t[1].kind), ("t_id", BtreeKind::Index));
+        assert!(t[0].pages >= 25 && t[0].pages > t[1].pages);
+        assert_eq!(stats.btrees[0].name, "t");
+        // page 2 is always a ptrmap page
+        assert!(stats.other_pages >= 1);
+        assert_eq!(stats.total_pages(), storage.num_pages().unwrap() as u64);
+
+        sqlite
+            .readwrite
+            .execute("UPDATE t SET body = x'00' WHERE id = 1", [])
+            .unwrap();
+        storage.commit().unwrap();
+
+        let last = storage.visible_range().last().unwrap();
+        let window = LsnRange::new(last, last);
+        let stats = page_stats(&sqlite.readonly, &storage, window).unwrap();
+        assert_eq!(stats.window, window);
+        assert!(stats.change_frequency("t") > 0.0);
+        assert!(stats.table("t")[0].page_writes < stats.table("t")[0].pages);
+    }
+}
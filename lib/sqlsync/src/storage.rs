@@ -1,22 +1,40 @@
-use std::{collections::HashSet, fmt::Debug, io};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Debug,
+    io,
+};
 
 use serde::{Deserialize, Serialize};
-use sqlite_vfs::SQLITE_IOERR;
+use sqlite_vfs::{
+    ffi::{
+        SQLITE_FULL, SQLITE_IOCAP_ATOMIC, SQLITE_IOCAP_POWERSAFE_OVERWRITE,
+        SQLITE_IOCAP_SAFE_APPEND, SQLITE_IOCAP_SEQUENTIAL,
+        SQLITE_IOCAP_UNDELETABLE_WHEN_OPEN,
+    },
+    SQLITE_IOERR,
+};
 
 use super::page::{SerializedPagesReader, SparsePages, PAGESIZE};
 use crate::{
-    journal::Journal,
+    journal::{Frames, Journal, JournalId},
+    logging,
     lsn::LsnRange,
-    page::{Page, PageIdx},
-    replication::{ReplicationDestination, ReplicationSource},
-    JournalResult, Lsn,
+    page::{page_idx_at, Page, PageIdx, MAX_PAGE_IDX},
+    pending::PendingPages,
+    positioned_io::PositionedReader,
+    replication::{
+        ReplicationDestination, ReplicationError, ReplicationSource,
+    },
+    JournalError, JournalResult, Lsn, Serializable,
 };
 
 // Useful SQLite header offsets
 // The SQLite header is the first 100 bytes in page 0
 // The following offsets are relative to the start of the header
 
-// We use the file change counter to control SQLite caching
+// SQLite re-reads its file change counter at the start of each transaction
+// and drops its page cache if it has moved, see Storage::file_change_counter
 const FILE_CHANGE_COUNTER_OFFSET: usize = 24;
 
 // The schema cookie is used to determine if the schema has changed
@@ -34,17 +52,70 @@ pub enum StorageChange {
     Tables { root_pages_sorted: Vec<PageIdx> },
 }
 
+/// Durability controls whether Storage::commit waits for the committed frame
+/// to reach durable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// commits return as soon as the frame is appended to the journal; a
+    /// crash may lose recent commits
+    #[default]
+    Relaxed,
+
+    /// commits sync the journal before returning; slower, but a commit is
+    /// never lost once it's been acknowledged
+    Sync,
+}
+
+/// GcStats counts the page versions Storage::collect_garbage reclaimed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// garbage collection passes
+    pub runs: u64,
+    pub frames_rewritten: u64,
+    pub pages_reclaimed: u64,
+    pub bytes_reclaimed: u64,
+}
+
+/// WAL frames, or pages pending a commit, held in memory before they move to
+/// disk
+pub const DEFAULT_WAL_MEMORY_LIMIT: usize = 64 << 20;
+
 pub struct Storage<J> {
     journal: J,
+    durability: Durability,
     visible_lsn_range: LsnRange,
-    pending: SparsePages,
-
+    // the highest page idx in the visible frames (0 if there are none),
+    // maintained as frames become visible so that file_size doesn't scan
+    // every frame. None until it's first needed
+    visible_max_page_idx: Cell<Option<PageIdx>>,
+    // the size of the journal's frames in bytes as of the range, see
+    // journal_size. None until it's first needed
+    journal_size: Cell<Option<(LsnRange, u64)>>,
+    pending: PendingPages,
+    // set once sqlite shrinks the file: committed pages past it read as
+    // missing, and commit folds them out of the journal, see truncate
+    truncated: Option<PageIdx>,
+    max_pages: PageIdx,
+    // how large the WAL may grow in memory, see vfs::TempFile::reserve
+    wal_memory_limit: usize,
+
+    // added to the change counter sqlite keeps in the header, and bumped
+    // whenever what sqlite can see changes underneath it: frames becoming
+    // visible, or pending pages being discarded. sqlite bumps its own counter
+    // when it commits a write, so its cache survives local writes
     file_change_counter: u32,
 
     // the following three fields are reset whenever Storage::changes() is called
     last_schema_cookie: u32,
     changed_root_pages: HashSet<PageIdx>,
     changed_pages: HashSet<PageIdx>,
+
+    // the number of pages sqlite has written, see pages_written
+    pages_written: u64,
+
+    // snapshots before this lsn may be missing page versions which
+    // collect_garbage dropped, see retained_range
+    gc_watermark: Option<Lsn>,
 }
 
 impl<J: Journal> Debug for Storage<J> {
@@ -61,15 +132,180 @@ impl<J: Journal> Storage<J> {
         let visible_lsn_range = journal.range();
         Self {
             journal,
+            durability: Durability::default(),
             visible_lsn_range,
-            pending: SparsePages::new(),
+            visible_max_page_idx: Cell::new(None),
+            journal_size: Cell::new(None),
+            pending: PendingPages::default(),
+            truncated: None,
+            max_pages: MAX_PAGE_IDX,
+            wal_memory_limit: DEFAULT_WAL_MEMORY_LIMIT,
             file_change_counter: 0,
             last_schema_cookie: 0,
             changed_root_pages: HashSet::new(),
             changed_pages: HashSet::new(),
+            pages_written: 0,
+            gc_watermark: None,
+        }
+    }
+
+    pub fn id(&self) -> JournalId {
+        self.journal.id()
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// the maximum size of the database file in bytes
+    pub fn max_size(&self) -> u64 {
+        self.max_pages as u64 * PAGESIZE as u64
+    }
+
+    /// cap the size of the database file, rounded down to a whole page.
+    /// writes beyond the cap fail with SQLITE_FULL
+    pub fn set_max_size(&mut self, max_bytes: u64) {
+        self.max_pages =
+            (max_bytes / PAGESIZE as u64).min(MAX_PAGE_IDX as u64) as PageIdx;
+    }
+
+    pub fn wal_memory_limit(&self) -> usize {
+        self.wal_memory_limit
+    }
+
+    /// cap how much memory the WAL may use. past the cap it moves to a temp
+    /// file, or in the browser to a spill file if the worker set any up
+    /// (see crate::set_spill_files) and otherwise the write fails with
+    /// SQLITE_FULL. the pages pending a commit are capped the same way in
+    /// either journal mode, see PendingPages
+    pub fn set_wal_memory_limit(&mut self, max_bytes: usize) {
+        self.wal_memory_limit = max_bytes;
+    }
+
+    /// the number of pages in the database file
+    pub fn num_pages(&self) -> JournalResult<PageIdx> {
+        let visible = match self.visible_max_page_idx.get() {
+            Some(max_page_idx) => max_page_idx,
+            None => {
+                let max_page_idx =
+                    self.scan_max_page_idx(self.visible_lsn_range)?;
+                self.visible_max_page_idx.set(Some(max_page_idx));
+                max_page_idx
+            }
+        };
+        let visible = self.truncated.map_or(visible, |max| visible.min(max));
+        Ok(visible.max(self.pending.max_page_idx().unwrap_or(0)))
+    }
+
+    fn scan_max_page_idx(&self, range: LsnRange) -> JournalResult<PageIdx> {
+        let mut max_page_idx = 0;
+        let mut cursor = self.journal.scan_range(range);
+        while cursor.advance()? {
+            let pages = SerializedPagesReader(&cursor);
+            max_page_idx = max_page_idx.max(pages.max_page_idx()?);
+        }
+        Ok(max_page_idx)
+    }
+
+    /// account for the frames in range becoming visible
+    fn track_max_page_idx(&self, range: LsnRange) -> JournalResult<()> {
+        if let Some(max_page_idx) = self.visible_max_page_idx.get() {
+            let max_page_idx = max_page_idx.max(self.scan_max_page_idx(range)?);
+            self.visible_max_page_idx.set(Some(max_page_idx));
+        }
+        Ok(())
+    }
+
+    /// every committed page, with later frames overriding earlier ones
+    pub fn snapshot(&self) -> io::Result<SparsePages> {
+        self.snapshot_range(self.visible_lsn_range)
+    }
+
+    /// every page as of the visible frame at lsn, or None if lsn is outside
+    /// retained_range
+    pub fn snapshot_at(&self, lsn: Lsn) -> io::Result<Option<SparsePages>> {
+        let Some(first) = self.visible_lsn_range.first() else {
+            return Ok(None);
+        };
+        if !self.retained_range().contains(lsn) {
+            return Ok(None);
+        }
+        self.snapshot_range(LsnRange::new(first, lsn)).map(Some)
+    }
+
+    /// the visible lsns snapshot_at can read the document as of. compact
+    /// drops the lsns it folds, and collect_garbage the lsns before its
+    /// watermark. the watermark isn't persisted, so a document reopened
+    /// after collecting garbage should only read as of its latest lsn
+    pub fn retained_range(&self) -> LsnRange {
+        match self.gc_watermark {
+            Some(watermark) if watermark > 0 => {
+                self.visible_lsn_range.trim_prefix(watermark - 1)
+            }
+            _ => self.visible_lsn_range,
         }
     }
 
+    fn snapshot_range(&self, range: LsnRange) -> io::Result<SparsePages> {
+        let mut pages = SparsePages::new();
+        let mut cursor = self.journal.scan_range(range);
+        while cursor.advance()? {
+            let frame = SerializedPagesReader(&cursor);
+            for page_idx in frame.page_idxs()? {
+                let mut page: Page = [0; PAGESIZE];
+                frame.read(page_idx, 0, &mut page)?;
+                pages.write(page_idx, page);
+            }
+        }
+        Ok(pages)
+    }
+
+    /// the size of the committed frames in bytes. frames are only scanned
+    /// once, later calls just add the frames committed since
+    pub fn journal_size(&self) -> io::Result<u64> {
+        let range = self.journal.range();
+        let (scan, mut size) = match self.journal_size.get() {
+            Some((cached, size)) if cached == range => return Ok(size),
+            Some((LsnRange::NonEmpty { first, last }, size))
+                if range.first() == Some(first) && range.contains(last) =>
+            {
+                (range.trim_prefix(last), size)
+            }
+            _ => (range, 0),
+        };
+        let mut cursor = self.journal.scan_range(scan);
+        while cursor.advance()? {
+            size += cursor.size()? as u64;
+        }
+        self.journal_size.set(Some((range, size)));
+        Ok(size)
+    }
+
+    /// iterate over committed frames starting at from
+    pub fn frames(&self, from: Lsn) -> Frames<'_, J> {
+        Frames::new(&self.journal, from)
+    }
+
+    /// the number of page writes sqlite has made since storage was opened,
+    /// counting a page once each time it's written
+    pub fn pages_written(&self) -> u64 {
+        self.pages_written
+    }
+
+    /// moves whenever what sqlite can see changes underneath it
+    pub fn file_change_counter(&self) -> u32 {
+        self.file_change_counter
+    }
+
+    /// the committed frames sqlite can currently see
+    pub fn visible_range(&self) -> LsnRange {
+        self.visible_lsn_range
+    }
+
     pub fn last_committed_lsn(&self) -> Option<Lsn> {
         self.journal.range().last()
     }
@@ -83,8 +319,14 @@ impl<J: Journal> Storage<J> {
     }
 
     pub fn commit(&mut self) -> JournalResult<()> {
+        if self.truncated.is_some() {
+            return self.commit_folded();
+        }
         if self.pending.num_pages() > 0 {
             self.journal.append(std::mem::take(&mut self.pending))?;
+            if self.durability == Durability::Sync {
+                self.journal.sync()?;
+            }
 
             // calculate the LsnRange between the current visible range and the committed range
             let new_lsns = self.journal.range().difference(&self.visible_lsn_range);
@@ -92,9 +334,14 @@ impl<J: Journal> Storage<J> {
             self.changed_pages.clear();
 
             // update the visible range
+            self.track_max_page_idx(new_lsns)?;
             self.visible_lsn_range = self.journal.range();
-            // update the file change counter
-            self.file_change_counter = self.file_change_counter.wrapping_add(1);
+            // sqlite has already seen the frame we just committed, but not
+            // any which were received before it
+            if new_lsns.len() > 1 {
+                self.file_change_counter =
+                    self.file_change_counter.wrapping_add(1);
+            }
 
             // update changed root pages in the newly visible range
             self.update_changed_root_pages(new_lsns)?;
@@ -102,20 +349,82 @@ impl<J: Journal> Storage<J> {
         Ok(())
     }
 
+    /// commit by folding every frame and the pending pages into a single
+    /// frame, which drops the older lsns like compact does. the journal is
+    /// replaced atomically (see rewrite_journal), so a crash leaves either
+    /// the old history or none of it. frames can only grow the file, so
+    /// commit does this once sqlite has truncated it
+    pub fn commit_folded(&mut self) -> JournalResult<()> {
+        let mut pages = self.snapshot_range(self.journal.range())?;
+        if let Some(max_page_idx) = self.truncated.take() {
+            pages.truncate(max_page_idx);
+        }
+        if pages.num_pages() == 0 && self.pending.num_pages() == 0 {
+            return Ok(());
+        }
+        let mut page: Page = [0; PAGESIZE];
+        for &page_idx in self.pending.page_idxs() {
+            self.pending.read(page_idx, 0, &mut page)?;
+            pages.write(page_idx, page);
+        }
+        let mut folded = Vec::new();
+        pages
+            .serialize_into(&mut folded)
+            .map_err(JournalError::SerializationError)?;
+
+        let new_lsns = self.journal.range().difference(&self.visible_lsn_range);
+        self.rewrite_journal(self.journal.range().next(), vec![folded])?;
+
+        self.changed_pages.clear();
+        self.pending.clear();
+        self.visible_lsn_range = self.journal.range();
+        self.visible_max_page_idx
+            .set(Some(pages.max_page_idx().unwrap_or(0)));
+        // as in commit, sqlite has only missed the frames received before
+        if new_lsns.is_non_empty() {
+            self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        }
+        self.update_changed_root_pages(self.visible_lsn_range)
+    }
+
+    /// replace the journal's frames with frames, the first of which is at
+    /// first. the journal swaps them in atomically (see Journal::replace),
+    /// so a crash leaves either the old frames or the new ones
+    fn rewrite_journal(
+        &mut self,
+        first: Lsn,
+        frames: Vec<Vec<u8>>,
+    ) -> JournalResult<()> {
+        self.journal.replace(first, frames)?;
+        self.journal.sync()?;
+        self.journal_size.set(None);
+        Ok(())
+    }
+
+    /// flush committed frames to durable storage regardless of durability
+    pub fn sync(&mut self) -> JournalResult<()> {
+        self.journal.sync()
+    }
+
     pub fn reset(&mut self) -> JournalResult<()> {
         // mark every page in pending as changed to ensure that we re-run queries that depended on the results of something in pending
         self.changed_pages = self.pending.page_idxs().copied().collect();
+        let reverted = self.pending.num_pages() > 0 || self.truncated.is_some();
 
         // clear pending to revert uncommitted changes
         self.pending.clear();
+        self.truncated = None;
 
         // calculate the LsnRange between the current visible range and the committed range
         let new_lsns = self.journal.range().difference(&self.visible_lsn_range);
 
         // update the visible range to reveal committed changes
+        self.track_max_page_idx(new_lsns)?;
         self.visible_lsn_range = self.journal.range();
-        // update the file change counter
-        self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        // update the file change counter if sqlite's view has changed
+        if reverted || new_lsns.is_non_empty() {
+            self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        }
 
         // update changed root pages in the newly visible range
         self.update_changed_root_pages(new_lsns)?;
@@ -263,7 +572,8 @@ impl<J: Journal> Storage<J> {
         // check to see if the schema has changed
         let schema_cookie = self.schema_cookie()?;
         if schema_cookie != self.last_schema_cookie {
-            log::info!(
+            logging::info!(
+                doc = self.journal.id();
                 "schema changed: {} -> {}",
                 self.last_schema_cookie,
                 schema_cookie
@@ -291,6 +601,51 @@ impl<J: Journal> Storage<J> {
         Ok(StorageChange::Tables { root_pages_sorted })
     }
 
+    /// count the visible pages in each b-tree, keyed by root page. pages
+    /// which don't belong to a b-tree (ptrmap and freelist pages) are counted
+    /// under None
+    pub fn pages_by_root(
+        &self,
+    ) -> JournalResult<BTreeMap<Option<PageIdx>, u64>> {
+        let mut page_idxs = BTreeSet::new();
+        let mut cursor = self.journal.scan_range(self.visible_lsn_range);
+        while cursor.advance()? {
+            page_idxs.extend(SerializedPagesReader(&cursor).page_idxs()?);
+        }
+
+        let mut counts = BTreeMap::new();
+        for page_idx in page_idxs {
+            let root_page_idx =
+                self.resolve_root_page(self.visible_lsn_range, false, page_idx)?;
+            *counts.entry(root_page_idx).or_default() += 1;
+        }
+        Ok(counts)
+    }
+
+    /// count the pages written to each b-tree by the visible frames in
+    /// range, keyed like pages_by_root
+    pub fn page_writes_by_root(
+        &self,
+        range: LsnRange,
+    ) -> JournalResult<BTreeMap<Option<PageIdx>, u64>> {
+        let mut counts = BTreeMap::new();
+        let range = range.intersect(&self.visible_lsn_range);
+        let mut cursor = self.journal.scan_range(range);
+        while cursor.advance()? {
+            let lsn = cursor.lsn().unwrap();
+            for page_idx in SerializedPagesReader(&cursor).page_idxs()? {
+                // pages may have moved since, so resolve them as of this lsn
+                let root_page_idx = self.resolve_root_page(
+                    LsnRange::new(0, lsn),
+                    false,
+                    page_idx,
+                )?;
+                *counts.entry(root_page_idx).or_default() += 1;
+            }
+        }
+        Ok(counts)
+    }
+
     fn read_at_range(
         &self,
         range: LsnRange,
@@ -298,15 +653,25 @@ impl<J: Journal> Storage<J> {
         pos: u64,
         buf: &mut [u8],
     ) -> io::Result<usize> {
-        let page_idx = ((pos / (PAGESIZE as u64)) + 1) as PageIdx;
-        let page_offset = (pos as usize) % PAGESIZE;
+        let Some(page_idx) = page_idx_at(pos) else {
+            // nothing is stored beyond the last page
+            return Ok(0);
+        };
+        let page_offset = (pos % PAGESIZE as u64) as usize;
 
         // find the page by searching down through pending and then the journal
         let mut n = if include_pending {
-            self.pending.read(page_idx, page_offset, buf)
+            self.pending.read(page_idx, page_offset, buf)?
         } else {
             0
         };
+        if include_pending
+            && n == 0
+            && matches!(self.truncated, Some(max) if page_idx > max)
+        {
+            // truncated, even though the journal still has it
+            return Ok(0);
+        }
 
         let mut cursor = self.journal.scan_range(range).into_rev();
         while n == 0 && cursor.advance()? {
@@ -318,17 +683,24 @@ impl<J: Journal> Storage<J> {
             assert!(n == buf.len(), "read should always fill the buffer");
 
             // if SQLite is potentially reading the file change counter
-            // replace what is in the file with the current value
+            // offset what is in the file by our own counter
             if page_idx == 1
                 && page_offset <= FILE_CHANGE_COUNTER_OFFSET
                 && page_offset + buf.len() >= FILE_CHANGE_COUNTER_OFFSET + 4
             {
                 // if pos = 0, then this should be FILE_CHANGE_COUNTER_OFFSET
                 // if pos = FILE_CHANGE_COUNTER_OFFSET, this this should be 0
-                let file_change_buf_offset = FILE_CHANGE_COUNTER_OFFSET - page_offset;
-
-                buf[file_change_buf_offset..(file_change_buf_offset + 4)]
-                    .copy_from_slice(&self.file_change_counter.to_be_bytes());
+                let file_change_buf_offset =
+                    FILE_CHANGE_COUNTER_OFFSET - page_offset;
+                let counter = &mut buf
+                    [file_change_buf_offset..(file_change_buf_offset + 4)];
+
+                let stored = u32::from_be_bytes(counter.try_into().unwrap());
+                counter.copy_from_slice(
+                    &stored
+                        .wrapping_add(self.file_change_counter)
+                        .to_be_bytes(),
+                );
             }
 
             Ok(buf.len())
@@ -336,6 +708,225 @@ impl<J: Journal> Storage<J> {
             Ok(0)
         }
     }
+
+    /// write pages as if sqlite had, so that they're committed along with
+    /// whatever sqlite writes next. used to seed a new document from a
+    /// template before sqlite opens it
+    pub(crate) fn stage(&mut self, pages: &SparsePages) -> io::Result<()> {
+        for &page_idx in pages.page_idxs() {
+            let page = pages.get(page_idx).expect("page_idxs are present");
+            self.write_page(page_idx, 0, page)?;
+        }
+        Ok(())
+    }
+
+    /// write buf into page_idx at page_offset. partial writes are applied on
+    /// top of the page as sqlite currently sees it
+    fn write_page(
+        &mut self,
+        page_idx: PageIdx,
+        page_offset: usize,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        logging::debug!(
+            doc = self.journal.id();
+            "writing {} bytes to page {} at offset {}",
+            buf.len(),
+            page_idx,
+            page_offset
+        );
+        let mut page: Page = [0; PAGESIZE];
+        if buf.len() < PAGESIZE {
+            // missing pages read as zeros
+            self.read_at_range(
+                self.visible_lsn_range,
+                true,
+                (page_idx as u64 - 1) * PAGESIZE as u64,
+                &mut page,
+            )?;
+        }
+        page[page_offset..page_offset + buf.len()].copy_from_slice(buf);
+
+        if page_idx == 1 {
+            // store sqlite's change counter without our offset, so that it
+            // reads back as written
+            let counter = &mut page
+                [FILE_CHANGE_COUNTER_OFFSET..FILE_CHANGE_COUNTER_OFFSET + 4];
+            let written = u32::from_be_bytes((&*counter).try_into().unwrap());
+            counter.copy_from_slice(
+                &written.wrapping_sub(self.file_change_counter).to_be_bytes(),
+            );
+        }
+        self.pending.write(page_idx, &page, self.wal_memory_limit)?;
+
+        // mark the page as changed
+        self.changed_pages.insert(page_idx);
+        self.pages_written += 1;
+        Ok(())
+    }
+}
+
+impl<J: Journal + ReplicationDestination> Storage<J> {
+    /// replace every committed frame with a single frame at lsn holding
+    /// pages, discarding anything pending. used to repair a journal which has
+    /// diverged from its source; changed lists the pages which differ from
+    /// what was visible before
+    pub fn replace(
+        &mut self,
+        lsn: Lsn,
+        pages: SparsePages,
+        changed: impl IntoIterator<Item = PageIdx>,
+    ) -> Result<(), ReplicationError> {
+        let mut frame = Vec::new();
+        pages
+            .serialize_into(&mut frame)
+            .map_err(JournalError::SerializationError)?;
+
+        if let Some(last) = self.journal.range().last() {
+            self.journal.drop_prefix(last)?;
+        }
+        let id = self.journal.id();
+        self.journal.write_lsn(id, lsn, &mut &frame[..])?;
+
+        self.changed_pages =
+            self.pending.page_idxs().copied().chain(changed).collect();
+        self.pending.clear();
+        self.truncated = None;
+        self.visible_lsn_range = self.journal.range();
+        self.visible_max_page_idx
+            .set(Some(pages.max_page_idx().unwrap_or(0)));
+        self.journal_size.set(None);
+        self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        self.update_changed_root_pages(LsnRange::empty())?;
+        Ok(())
+    }
+
+    /// drop every frame and anything pending, leaving the journal empty so
+    /// that it accepts whichever lsn its source sends next. used when the
+    /// source's history has been rewritten, see redaction
+    pub fn clear(&mut self) -> Result<(), ReplicationError> {
+        let num_pages = self.num_pages()?;
+        self.journal.drop_prefix(self.journal.range().next())?;
+        self.journal.sync()?;
+
+        self.changed_pages = (1..=num_pages).collect();
+        self.pending.clear();
+        self.truncated = None;
+        self.visible_lsn_range = self.journal.range();
+        self.visible_max_page_idx.set(Some(0));
+        self.journal_size.set(None);
+        self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        self.update_changed_root_pages(LsnRange::empty())?;
+        Ok(())
+    }
+
+    /// fold the visible frames up to and including up_to into a single frame
+    /// at up_to holding every page as of up_to. frames after up_to are kept
+    /// as is, so sqlite sees no change. returns the number of frames removed.
+    ///
+    /// the journal is replaced atomically, see rewrite_journal
+    pub fn compact(&mut self, up_to: Lsn) -> Result<usize, ReplicationError> {
+        let LsnRange::NonEmpty { first, last } = self.visible_lsn_range else {
+            return Ok(0);
+        };
+        let up_to = up_to.min(last);
+        if up_to <= first {
+            return Ok(0);
+        }
+
+        let mut folded = Vec::new();
+        self.snapshot_range(LsnRange::new(first, up_to))?
+            .serialize_into(&mut folded)
+            .map_err(JournalError::SerializationError)?;
+
+        // frames after up_to, including any which aren't visible yet
+        let mut frames = vec![folded];
+        let mut cursor = self
+            .journal
+            .scan_range(LsnRange::new(up_to + 1, self.journal.range().next()));
+        while cursor.advance()? {
+            frames.push(cursor.read_all()?);
+        }
+
+        self.rewrite_journal(up_to, frames)?;
+
+        self.visible_lsn_range = LsnRange::new(up_to, last);
+        Ok((up_to - first) as usize)
+    }
+
+    /// drop the page versions in visible frames up to and including up_to
+    /// which a later visible frame overwrites, as reads at the visible lsn
+    /// never reach them. every lsn is kept, but reads as of an lsn before the
+    /// overwrite (such as page_writes_by_root over an old window) may see the
+    /// newer version. a frame which is entirely overwritten keeps its highest
+    /// page, as frames can't be empty
+    pub fn collect_garbage(
+        &mut self,
+        up_to: Lsn,
+    ) -> Result<GcStats, ReplicationError> {
+        let mut stats = GcStats { runs: 1, ..GcStats::default() };
+        let Some(last) = self.visible_lsn_range.last() else {
+            return Ok(stats);
+        };
+        let up_to = up_to.min(last);
+
+        // walk the journal newest first, tracking which pages later frames
+        // have overwritten
+        let mut overwritten = HashSet::new();
+        let mut frames = vec![];
+        let mut cursor =
+            self.journal.scan_range(self.journal.range()).into_rev();
+        while cursor.advance()? {
+            let lsn = cursor.lsn().unwrap();
+            let frame = SerializedPagesReader(&cursor);
+            let page_idxs = frame.page_idxs()?;
+            let mut live: Vec<PageIdx> = page_idxs
+                .iter()
+                .copied()
+                .filter(|i| lsn > up_to || !overwritten.contains(i))
+                .collect();
+            if live.is_empty() {
+                // page_idxs are sorted desc
+                live.push(page_idxs[0]);
+            }
+            if live.len() == page_idxs.len() {
+                // frames which aren't visible yet don't hide anything
+                if lsn <= last {
+                    overwritten.extend(page_idxs);
+                }
+                frames.push(cursor.read_all()?);
+                continue;
+            }
+
+            let mut pages = SparsePages::new();
+            for &page_idx in live.iter() {
+                let mut page: Page = [0; PAGESIZE];
+                frame.read(page_idx, 0, &mut page)?;
+                pages.write(page_idx, page);
+            }
+            let mut data = Vec::new();
+            pages
+                .serialize_into(&mut data)
+                .map_err(JournalError::SerializationError)?;
+
+            stats.frames_rewritten += 1;
+            stats.pages_reclaimed += (page_idxs.len() - live.len()) as u64;
+            stats.bytes_reclaimed += (cursor.size()? - data.len()) as u64;
+            overwritten.extend(page_idxs);
+            frames.push(data);
+        }
+
+        if let Some(first) = self.journal.range().first() {
+            if stats.frames_rewritten > 0 {
+                frames.reverse();
+                self.rewrite_journal(first, frames)?;
+                // a dropped version is missed by snapshots from before the
+                // frame which overwrote it, which is visible and so <= last
+                self.gc_watermark = self.gc_watermark.max(Some(last));
+            }
+        }
+        Ok(stats)
+    }
 }
 
 impl<J: ReplicationSource> ReplicationSource for Storage<J> {
@@ -378,42 +969,73 @@ impl<J: ReplicationDestination> ReplicationDestination for Storage<J> {
 }
 
 impl<J: Journal> sqlite_vfs::File for Storage<J> {
-    fn file_size(&self) -> sqlite_vfs::VfsResult<u64> {
-        let mut max_page_idx = self.pending.max_page_idx();
+    /// storage is written a page at a time
+    fn sector_size(&self) -> usize {
+        PAGESIZE
+    }
 
-        // if we have visible lsns in storage, then we need to scan them
-        // to find the max page idx
-        let mut cursor = self.journal.scan_range(self.visible_lsn_range);
-        while cursor.advance().map_err(|_| SQLITE_IOERR)? {
-            let pages = SerializedPagesReader(&cursor);
-            max_page_idx = max_page_idx.max(Some(pages.max_page_idx().map_err(|_| SQLITE_IOERR)?));
-        }
+    fn device_characteristics(&self) -> i32 {
+        // writes land in pending and only reach the journal, all at once,
+        // when storage is committed. so every write is atomic, nothing
+        // around a write can be disturbed by it, and writes (including
+        // appends) can't be observed out of order. storage is never deleted
+        // while sqlite has it open
+        SQLITE_IOCAP_ATOMIC
+            | SQLITE_IOCAP_POWERSAFE_OVERWRITE
+            | SQLITE_IOCAP_SAFE_APPEND
+            | SQLITE_IOCAP_SEQUENTIAL
+            | SQLITE_IOCAP_UNDELETABLE_WHEN_OPEN
+    }
 
-        Ok(max_page_idx
-            .map(|n| (n as u64) * (PAGESIZE as u64))
-            .unwrap_or(0))
+    fn file_size(&self) -> sqlite_vfs::VfsResult<u64> {
+        let num_pages = self.num_pages().map_err(|_| SQLITE_IOERR)?;
+        Ok(num_pages as u64 * PAGESIZE as u64)
     }
 
-    fn truncate(&mut self, _size: u64) -> sqlite_vfs::VfsResult<()> {
-        // for now we panic
-        panic!("truncate not implemented")
+    /// sqlite shrinks the file when a vacuum frees pages at its end. the
+    /// pages past size are dropped from pending, and from the journal once
+    /// storage is committed
+    fn truncate(&mut self, size: u64) -> sqlite_vfs::VfsResult<()> {
+        let max_page_idx = (size / PAGESIZE as u64) as PageIdx;
+        if max_page_idx >= self.num_pages().map_err(|_| SQLITE_IOERR)? {
+            return Ok(());
+        }
+        self.pending.truncate(max_page_idx);
+        self.truncated = Some(max_page_idx);
+        Ok(())
     }
 
     fn write(&mut self, pos: u64, buf: &[u8]) -> sqlite_vfs::VfsResult<usize> {
-        let page_idx = ((pos / (PAGESIZE as u64)) + 1) as PageIdx;
-        log::debug!("writing page {}", page_idx);
-
-        // for now we panic if we attempt to write less than a full page
-        assert!(buf.len() == PAGESIZE);
-
-        let page: Page = buf.try_into().unwrap();
-        self.pending.write(page_idx, page);
-
-        // update the file change counter
-        self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        let end = pos + buf.len().max(1) as u64 - 1;
+        let Some(last_page_idx) =
+            page_idx_at(end).filter(|&page_idx| page_idx <= self.max_pages)
+        else {
+            logging::warn!(
+                doc = self.journal.id();
+                "refusing to write at offset {}, storage is limited to {} bytes",
+                end,
+                self.max_size()
+            );
+            return Err(SQLITE_FULL);
+        };
 
-        // mark the page as changed
-        self.changed_pages.insert(page_idx);
+        // split the write at page boundaries, sqlite usually writes exactly
+        // one page but may write less (or span pages) in some modes
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = pos + written as u64;
+            let page_idx = page_idx_at(pos).unwrap();
+            debug_assert!(page_idx <= last_page_idx);
+            let page_offset = (pos % PAGESIZE as u64) as usize;
+            let len = (PAGESIZE - page_offset).min(buf.len() - written);
+            let chunk = &buf[written..written + len];
+            self.write_page(page_idx, page_offset, chunk)
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::StorageFull => SQLITE_FULL,
+                    _ => SQLITE_IOERR,
+                })?;
+            written += len;
+        }
 
         Ok(buf.len())
     }
@@ -427,3 +1049,356 @@ impl<J: Journal> sqlite_vfs::File for Storage<J> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::File;
+
+    use super::{Storage, FILE_CHANGE_COUNTER_OFFSET};
+    use crate::{
+        page::{SparsePages, PAGESIZE},
+        replication::ReplicationDestination,
+        Journal, JournalId, LsnRange, MemoryJournal, Serializable,
+    };
+
+    /// file_size must agree with what reads return: the last page exists
+    /// and nothing follows it
+    fn assert_consistent(storage: &mut Storage<MemoryJournal>) {
+        let size = storage.file_size().unwrap();
+        let uncached = storage
+            .scan_max_page_idx(storage.visible_lsn_range)
+            .unwrap()
+            .max(storage.pending.max_page_idx().unwrap_or(0));
+        assert_eq!(size, uncached as u64 * PAGESIZE as u64);
+
+        let mut page = [0; PAGESIZE];
+        if size > 0 {
+            let last = size - PAGESIZE as u64;
+            assert_eq!(storage.read(last, &mut page).unwrap(), PAGESIZE);
+        }
+        assert_eq!(storage.read(size, &mut page).unwrap(), 0);
+    }
+
+    fn read_counter(storage: &mut Storage<MemoryJournal>) -> u32 {
+        let mut buf = [0; 4];
+        storage
+            .read(FILE_CHANGE_COUNTER_OFFSET as u64, &mut buf)
+            .unwrap();
+        u32::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn change_counter_only_moves_underneath_sqlite() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        let mut page = [0; PAGESIZE];
+        page[FILE_CHANGE_COUNTER_OFFSET + 3] = 7;
+        storage.write(0, &page).unwrap();
+        storage.commit().unwrap();
+        // sqlite reads back the counter it wrote
+        assert_eq!(read_counter(&mut storage), 7);
+
+        // writes made by sqlite don't move it any further
+        storage.write(PAGESIZE as u64, &[1; PAGESIZE]).unwrap();
+        assert_eq!(read_counter(&mut storage), 7);
+
+        // but discarding them does
+        storage.reset().unwrap();
+        let counter = read_counter(&mut storage);
+        assert_ne!(counter, 7);
+
+        // as do frames arriving from elsewhere, once they're visible
+        storage.write_lsn(id, 1, &mut &frame(&[2])[..]).unwrap();
+        assert_eq!(read_counter(&mut storage), counter);
+        storage.reset().unwrap();
+        assert_ne!(read_counter(&mut storage), counter);
+
+        // nothing changed, so sqlite's cache is still good
+        let counter = read_counter(&mut storage);
+        storage.reset().unwrap();
+        assert_eq!(read_counter(&mut storage), counter);
+    }
+
+    #[test]
+    fn partial_writes() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        let mut page = [1; PAGESIZE];
+        page[FILE_CHANGE_COUNTER_OFFSET..FILE_CHANGE_COUNTER_OFFSET + 4]
+            .copy_from_slice(&7u32.to_be_bytes());
+        storage.write(0, &page).unwrap();
+        storage.commit().unwrap();
+
+        // a header-only write keeps the rest of the committed page, and
+        // the change counter it holds reads back as written
+        let mut header = [2; 100];
+        header[FILE_CHANGE_COUNTER_OFFSET..FILE_CHANGE_COUNTER_OFFSET + 4]
+            .copy_from_slice(&9u32.to_be_bytes());
+        storage.write(0, &header).unwrap();
+        assert_eq!(read_counter(&mut storage), 9);
+        let mut buf = [0; PAGESIZE];
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(&buf[..100], &header[..]);
+        assert!(buf[100..].iter().all(|&b| b == 1));
+
+        // writes may start partway through a page and span into the next,
+        // which reads as zeros where it wasn't written
+        let pos = PAGESIZE as u64 - 10;
+        storage.write(pos, &[3; 20]).unwrap();
+        assert_eq!(storage.num_pages().unwrap(), 2);
+        storage.read(PAGESIZE as u64, &mut buf).unwrap();
+        assert!(buf[..10].iter().all(|&b| b == 3));
+        assert!(buf[10..].iter().all(|&b| b == 0));
+
+        storage.commit().unwrap();
+        let mut buf = [0; 20];
+        storage.read(pos, &mut buf).unwrap();
+        assert_eq!(buf, [3; 20]);
+        assert_eq!(read_counter(&mut storage), 9);
+    }
+
+    fn frame(page_idxs: &[u32]) -> Vec<u8> {
+        let mut pages = SparsePages::new();
+        for &page_idx in page_idxs {
+            pages.write(page_idx, [1; PAGESIZE]);
+        }
+        let mut frame = Vec::new();
+        crate::Serializable::serialize_into(&pages, &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn long_transactions_spill_out_of_memory() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        storage.set_wal_memory_limit(2 * PAGESIZE);
+        for page_idx in 0..5u8 {
+            let pos = page_idx as u64 * PAGESIZE as u64;
+            storage.write(pos, &[page_idx; PAGESIZE]).unwrap();
+        }
+        assert!(storage.pending.is_spilled());
+
+        // the spilled pages read back before and after the commit
+        let mut page = [0; PAGESIZE];
+        storage.read(4 * PAGESIZE as u64, &mut page).unwrap();
+        assert_eq!(page, [4; PAGESIZE]);
+        storage.commit().unwrap();
+        assert!(!storage.pending.is_spilled());
+        assert_eq!(storage.journal.range(), LsnRange::new(0, 0));
+        storage.read(3 * PAGESIZE as u64, &mut page).unwrap();
+        assert_eq!(page, [3; PAGESIZE]);
+        assert_consistent(&mut storage);
+    }
+
+    #[test]
+    fn file_size_tracks_visible_frames() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        assert_consistent(&mut storage);
+
+        // pending pages count straight away
+        storage.write(2 * PAGESIZE as u64, &[1; PAGESIZE]).unwrap();
+        assert_consistent(&mut storage);
+        storage.commit().unwrap();
+        assert_consistent(&mut storage);
+
+        // received frames only count once they're visible
+        storage.write_lsn(id, 1, &mut &frame(&[5])[..]).unwrap();
+        assert_consistent(&mut storage);
+        assert_eq!(storage.num_pages().unwrap(), 3);
+        storage.reset().unwrap();
+        assert_consistent(&mut storage);
+        assert_eq!(storage.num_pages().unwrap(), 5);
+
+        // and shrink when storage is replaced
+        let mut pages = SparsePages::new();
+        pages.write(1, [1; PAGESIZE]);
+        storage.replace(4, pages, [1]).unwrap();
+        assert_consistent(&mut storage);
+        assert_eq!(storage.num_pages().unwrap(), 1);
+    }
+
+    #[test]
+    fn truncating_folds_the_journal() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        for page_idx in 1..=4 {
+            let pos = (page_idx - 1) * PAGESIZE as u64;
+            storage.write(pos, &[page_idx as u8; PAGESIZE]).unwrap();
+            storage.commit().unwrap();
+        }
+
+        // growing is a no-op, and shrinking is undone by a reset
+        storage.truncate(8 * PAGESIZE as u64).unwrap();
+        assert_eq!(storage.num_pages().unwrap(), 4);
+        storage.truncate(2 * PAGESIZE as u64).unwrap();
+        assert_eq!(storage.num_pages().unwrap(), 2);
+        let mut page = [0; PAGESIZE];
+        assert_eq!(storage.read(2 * PAGESIZE as u64, &mut page).unwrap(), 0);
+        storage.reset().unwrap();
+        assert_consistent(&mut storage);
+        assert_eq!(storage.num_pages().unwrap(), 4);
+
+        // pages written after the truncation are kept
+        storage.truncate(2 * PAGESIZE as u64).unwrap();
+        storage.write(2 * PAGESIZE as u64, &[9; PAGESIZE]).unwrap();
+        storage.commit().unwrap();
+        assert_consistent(&mut storage);
+        assert_eq!(storage.num_pages().unwrap(), 3);
+        assert_eq!(storage.visible_range(), LsnRange::new(4, 4));
+        assert_eq!(
+            storage.read(2 * PAGESIZE as u64, &mut page).unwrap(),
+            PAGESIZE
+        );
+        assert_eq!(page, [9; PAGESIZE]);
+        assert_eq!(storage.read(0, &mut page).unwrap(), PAGESIZE);
+        assert_eq!(page, [1; PAGESIZE]);
+    }
+
+    #[test]
+    fn journal_size_follows_the_journal() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = Storage::new(MemoryJournal::open(id).unwrap());
+        assert_eq!(storage.journal_size().unwrap(), 0);
+
+        let (a, b) = (frame(&[1]), frame(&[1, 2]));
+        storage.write_lsn(id, 0, &mut &a[..]).unwrap();
+        assert_eq!(storage.journal_size().unwrap(), a.len() as u64);
+        storage.write_lsn(id, 1, &mut &b[..]).unwrap();
+        assert_eq!(storage.journal_size().unwrap(), (a.len() + b.len()) as u64);
+
+        // compacting folds both frames into one which looks just like b
+        storage.reset().unwrap();
+        assert_eq!(storage.compact(1).unwrap(), 1);
+        assert_eq!(storage.journal_size().unwrap(), b.len() as u64);
+    }
+
+    #[test]
+    fn collects_overwritten_pages() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = MemoryJournal::open(id).unwrap();
+        for page_idxs in [&[1, 2][..], &[3], &[2, 3], &[4]] {
+            let mut pages = SparsePages::new();
+            for &page_idx in page_idxs {
+                pages.write(page_idx, [journal.range().len() as u8; PAGESIZE]);
+            }
+            journal.append(pages).unwrap();
+        }
+        let mut storage = Storage::new(journal);
+        let before = storage.snapshot().unwrap();
+
+        // frame 2 overwrites page 2 from frame 0 and all of frame 1, which
+        // keeps page 3 as frames can't be empty
+        let stats = storage.collect_garbage(2).unwrap();
+        assert_eq!(stats.frames_rewritten, 1);
+        assert_eq!(stats.pages_reclaimed, 1);
+        assert_eq!(storage.frames(0).count(), 4);
+        let after = storage.snapshot().unwrap();
+        for page_idx in 1..=4 {
+            assert_eq!(before.get(page_idx), after.get(page_idx));
+        }
+
+        // every overwritten page that can go is gone
+        assert_eq!(storage.collect_garbage(3).unwrap().frames_rewritten, 0);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn collected_garbage_survives_reopening_a_file_journal() {
+        use crate::FileJournal;
+
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let dir = std::env::temp_dir().join(format!("sqlsync-gc-{}", id));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage");
+
+        let mut journal = FileJournal::open(&path, id).unwrap();
+        for page_idxs in [&[1, 2][..], &[2], &[1, 3]] {
+            let mut pages = SparsePages::new();
+            for &page_idx in page_idxs {
+                pages.write(page_idx, [journal.range().len() as u8; PAGESIZE]);
+            }
+            journal.append(pages).unwrap();
+        }
+        let mut storage = Storage::new(journal);
+        let before = storage.snapshot().unwrap();
+        let stats = storage.collect_garbage(2).unwrap();
+        assert_eq!(stats.frames_rewritten, 1);
+        drop(storage);
+
+        // the rewritten journal replaced the old one in place, so a reopen
+        // sees every frame with the garbage gone
+        let journal = FileJournal::open(&path, id).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(0, 2));
+        let mut storage = Storage::new(journal);
+        let after = storage.snapshot().unwrap();
+        for page_idx in 1..=3 {
+            assert_eq!(before.get(page_idx), after.get(page_idx));
+        }
+        assert_eq!(storage.collect_garbage(2).unwrap().frames_rewritten, 0);
+        drop(storage);
+
+        // nothing is left behind next to the journal
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clear_accepts_any_next_lsn() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = MemoryJournal::open(id).unwrap();
+        for _ in 0..3 {
+            let mut pages = SparsePages::new();
+            pages.write(1, [1; PAGESIZE]);
+            journal.append(pages).unwrap();
+        }
+        let mut storage = Storage::new(journal);
+
+        storage.clear().unwrap();
+        assert!(!storage.has_committed_pages());
+        assert_eq!(storage.num_pages().unwrap(), 0);
+        assert_consistent(&mut storage);
+
+        // the source's history was folded into a frame at lsn 7
+        let mut frame = Vec::new();
+        let mut pages = SparsePages::new();
+        pages.write(2, [2; PAGESIZE]);
+        pages.serialize_into(&mut frame).unwrap();
+        storage.write_lsn(id, 7, &mut &frame[..]).unwrap();
+        storage.reset().unwrap();
+        assert_eq!(storage.visible_range(), LsnRange::new(7, 7));
+        assert_eq!(storage.num_pages().unwrap(), 2);
+    }
+
+    #[test]
+    fn snapshots_as_of_retained_lsns() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = MemoryJournal::open(id).unwrap();
+        for lsn in 0..4u8 {
+            let mut pages = SparsePages::new();
+            pages.write(1, [lsn; PAGESIZE]);
+            pages.write(2 + lsn as u32, [lsn; PAGESIZE]);
+            journal.append(pages).unwrap();
+        }
+        let mut storage = Storage::new(journal);
+
+        let at = storage.snapshot_at(1).unwrap().unwrap();
+        assert_eq!(at.get(1), Some(&[1; PAGESIZE]));
+        assert_eq!(at.num_pages(), 3);
+        assert!(storage.snapshot_at(4).unwrap().is_none());
+
+        // compacted lsns are gone, but the one they were folded into stays
+        storage.compact(2).unwrap();
+        assert!(storage.snapshot_at(1).unwrap().is_none());
+        assert_eq!(storage.snapshot_at(2).unwrap().unwrap().num_pages(), 4);
+        assert_eq!(storage.snapshot_at(3).unwrap().unwrap().num_pages(), 5);
+
+        // garbage collection drops page 1's old versions, so the lsns
+        // before the last are no longer retained
+        assert!(storage.collect_garbage(2).unwrap().frames_rewritten > 0);
+        assert_eq!(storage.retained_range(), LsnRange::new(3, 3));
+        assert!(storage.snapshot_at(2).unwrap().is_none());
+        let at = storage.snapshot_at(3).unwrap().unwrap();
+        assert_eq!(at.get(1), Some(&[3; PAGESIZE]));
+    }
+}
@@ -0,0 +1,547 @@
+//! Multi-tenancy, for one deployment hosting many customers' documents. A
+//! TenantRegistry namespaces document ids by tenant, authenticates
+//! connections with each tenant's own Authenticator, and enforces each
+//! tenant's quota on documents, storage bytes and connections.
+//!
+//! Like the rest of the server side the registry has no io. Hosts
+//! authenticate a connection and look up its document, then accept it with
+//! CoordinatorServer::accept_tenant, and reject it with the ProtocolError of
+//! any TenantError. Storage quotas are enforced through each document's max
+//! size: hosts report a document's size after it changes and apply the
+//! limit they get back as the document's StorageQuota::max_database_bytes,
+//! which CoordinatorServer::apply_tenant_quota does for them.
+//!
+//! Which tenant owns each document, and how large it is, must survive a
+//! restart: hosts save the RegistryState returned by take_changed, and
+//! restore the registry from it before adding their tenants.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{replication::ProtocolError, JournalId};
+
+const MAX_TENANT_ID_LEN: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TenantError {
+    #[error("invalid tenant id {0:?}")]
+    InvalidTenantId(String),
+
+    #[error("unknown tenant {0}")]
+    UnknownTenant(TenantId),
+
+    #[error("authentication failed")]
+    AuthFailed,
+
+    /// the document doesn't exist, or belongs to another tenant. the two
+    /// aren't told apart so that tenants can't probe each other's ids
+    #[error("document {0} not found")]
+    DocNotFound(JournalId),
+
+    #[error("tenant {tenant} is over its {resource} quota of {limit}")]
+    QuotaExceeded {
+        tenant: TenantId,
+        resource: &'static str,
+        limit: u64,
+    },
+}
+
+impl TenantError {
+    /// the error to send the client before closing its connection
+    pub fn protocol_error(&self) -> ProtocolError {
+        match self {
+            TenantError::InvalidTenantId(_)
+            | TenantError::UnknownTenant(_)
+            | TenantError::AuthFailed => ProtocolError::AuthFailed,
+            TenantError::DocNotFound(doc) => ProtocolError::DocNotFound(*doc),
+            TenantError::QuotaExceeded { .. } => {
+                ProtocolError::QuotaExceeded(self.to_string())
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, TenantError>;
+
+/// a tenant's id: 1 to 64 ascii letters, digits, '-' or '_'
+#[derive(
+    Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct TenantId(String);
+
+impl TenantId {
+    pub fn new(id: impl Into<String>) -> Result<Self> {
+        let id = id.into();
+        let valid = (1..=MAX_TENANT_ID_LEN).contains(&id.len())
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(TenantError::InvalidTenantId(id));
+        }
+        Ok(Self(id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// the id of the tenant's document called name. documents of different
+    /// tenants never share an id, whatever they are called
+    pub fn doc_id(&self, name: &str) -> JournalId {
+        let mut hasher = Sha256::new();
+        hasher.update(b"sqlsync-tenant\0");
+        hasher.update(self.0.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(name.as_bytes());
+        let digest = hasher.finalize();
+        JournalId::try_from(&digest[..16]).expect("16 bytes is a valid id")
+    }
+}
+
+impl TryFrom<String> for TenantId {
+    type Error = TenantError;
+
+    fn try_from(id: String) -> Result<Self> {
+        Self::new(id)
+    }
+}
+
+impl From<TenantId> for String {
+    fn from(id: TenantId) -> Self {
+        id.0
+    }
+}
+
+impl Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Debug for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TenantId({})", self.0)
+    }
+}
+
+/// the most a tenant may use, None meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantQuota {
+    pub max_documents: Option<usize>,
+    /// summed over every document, see TenantRegistry::record_storage
+    pub max_storage_bytes: Option<u64>,
+    /// open connections, summed over every document
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub documents: usize,
+    pub storage_bytes: u64,
+    pub connections: usize,
+}
+
+/// checks the credentials a client connects with, typically a token signed
+/// with a key which belongs to the tenant
+pub trait Authenticator: Send {
+    /// the subject the credentials identify, or None if they are invalid
+    fn authenticate(&self, credentials: &[u8]) -> Option<String>;
+}
+
+/// who a connection has been authenticated as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthContext {
+    pub tenant: TenantId,
+    pub subject: String,
+}
+
+struct Tenant {
+    quota: TenantQuota,
+    auth: Box<dyn Authenticator>,
+    /// the size of each of the tenant's documents
+    documents: HashMap<JournalId, u64>,
+    connections: usize,
+}
+
+impl Tenant {
+    fn usage(&self) -> TenantUsage {
+        TenantUsage {
+            documents: self.documents.len(),
+            storage_bytes: self.documents.values().sum(),
+            connections: self.connections,
+        }
+    }
+}
+
+/// what a TenantRegistry keeps across restarts. quotas and authenticators
+/// are the host's config, and connections don't outlive the process
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryState {
+    pub documents: Vec<OwnedDocument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedDocument {
+    pub doc: JournalId,
+    pub tenant: TenantId,
+    /// the size last recorded with record_storage
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct TenantRegistry {
+    tenants: HashMap<TenantId, Tenant>,
+    owners: HashMap<JournalId, TenantId>,
+    /// the restored documents of tenants which haven't been added yet
+    restored: HashMap<TenantId, HashMap<JournalId, u64>>,
+    /// whether there's state to save, see take_changed
+    changed: bool,
+}
+
+impl TenantRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a registry with the documents in state. each tenant's documents
+    /// count against its quota once it's added
+    pub fn restore(state: RegistryState) -> Self {
+        let mut registry = Self::new();
+        for owned in state.documents {
+            registry.owners.insert(owned.doc, owned.tenant.clone());
+            registry
+                .restored
+                .entry(owned.tenant)
+                .or_default()
+                .insert(owned.doc, owned.bytes);
+        }
+        registry
+    }
+
+    /// every document's owner and size, to restore the registry from
+    pub fn state(&self) -> RegistryState {
+        let mut documents: Vec<_> = self
+            .owners
+            .iter()
+            .map(|(&doc, tenant)| {
+                let sizes = match self.tenants.get(tenant) {
+                    Some(tenant) => Some(&tenant.documents),
+                    None => self.restored.get(tenant),
+                };
+                let bytes = sizes.and_then(|s| s.get(&doc).copied());
+                OwnedDocument {
+                    doc,
+                    tenant: tenant.clone(),
+                    bytes: bytes.unwrap_or(0),
+                }
+            })
+            .collect();
+        documents.sort_by_key(|owned| owned.doc.bytes().to_vec());
+        RegistryState { documents }
+    }
+
+    /// the state to save if documents have been opened, removed or resized
+    /// since the last call
+    pub fn take_changed(&mut self) -> Option<RegistryState> {
+        std::mem::take(&mut self.changed).then(|| self.state())
+    }
+
+    /// add a tenant, or replace the quota and authenticator of an existing
+    /// one. lowering a quota below a tenant's usage only stops it from
+    /// growing further
+    pub fn add_tenant(
+        &mut self,
+        tenant: TenantId,
+        quota: TenantQuota,
+        auth: Box<dyn Authenticator>,
+    ) {
+        match self.tenants.get_mut(&tenant) {
+            Some(existing) => {
+                existing.quota = quota;
+                existing.auth = auth;
+            }
+            None => {
+                let documents =
+                    self.restored.remove(&tenant).unwrap_or_default();
+                self.tenants.insert(
+                    tenant,
+                    Tenant { quota, auth, documents, connections: 0 },
+                );
+            }
+        }
+    }
+
+    /// remove a tenant, returning the ids of its documents for the host to
+    /// close and delete
+    pub fn remove_tenant(&mut self, tenant: &TenantId) -> Vec<JournalId> {
+        let documents = match self.tenants.remove(tenant) {
+            Some(removed) => removed.documents,
+            None => self.restored.remove(tenant).unwrap_or_default(),
+        };
+        let docs: Vec<JournalId> = documents.into_keys().collect();
+        for doc in docs.iter() {
+            self.owners.remove(doc);
+        }
+        self.changed |= !docs.is_empty();
+        docs
+    }
+
+    pub fn usage(&self, tenant: &TenantId) -> Option<TenantUsage> {
+        self.tenants.get(tenant).map(Tenant::usage)
+    }
+
+    /// the tenant a document belongs to
+    pub fn owner(&self, doc: JournalId) -> Option<&TenantId> {
+        self.owners.get(&doc)
+    }
+
+    /// check credentials with the tenant's authenticator
+    pub fn authenticate(
+        &self,
+        tenant: &TenantId,
+        credentials: &[u8],
+    ) -> Result<AuthContext> {
+        let subject = self
+            .tenant(tenant)?
+            .auth
+            .authenticate(credentials)
+            .ok_or(TenantError::AuthFailed)?;
+        Ok(AuthContext { tenant: tenant.clone(), subject })
+    }
+
+    /// the id of the tenant's document called name, registering it if it's
+    /// new. fails if the tenant already has as many documents as its quota
+    /// allows
+    pub fn open_document(
+        &mut self,
+        ctx: &AuthContext,
+        name: &str,
+    ) -> Result<JournalId> {
+        let doc = ctx.tenant.doc_id(name);
+        let tenant = self.tenant_mut(&ctx.tenant)?;
+        if tenant.documents.contains_key(&doc) {
+            return Ok(doc);
+        }
+        if let Some(limit) = tenant.quota.max_documents {
+            if tenant.documents.len() >= limit {
+                return Err(quota_exceeded(ctx, "documents", limit as u64));
+            }
+        }
+        tenant.documents.insert(doc, 0);
+        self.owners.insert(doc, ctx.tenant.clone());
+        self.changed = true;
+        Ok(doc)
+    }
+
+    /// forget a document which the host has deleted
+    pub fn remove_document(&mut self, doc: JournalId) {
+        if let Some(tenant) = self.owners.remove(&doc) {
+            if let Some(tenant) = self.tenants.get_mut(&tenant) {
+                tenant.documents.remove(&doc);
+            } else if let Some(restored) = self.restored.get_mut(&tenant) {
+                restored.remove(&doc);
+            }
+            self.changed = true;
+        }
+    }
+
+    /// count a new connection to doc, before accepting it. fails if doc
+    /// isn't the tenant's or the tenant is at its connection quota
+    pub fn connect(&mut self, ctx: &AuthContext, doc: JournalId) -> Result<()> {
+        if self.owners.get(&doc) != Some(&ctx.tenant) {
+            return Err(TenantError::DocNotFound(doc));
+        }
+        let tenant = self.tenant_mut(&ctx.tenant)?;
+        if let Some(limit) = tenant.quota.max_connections {
+            if tenant.connections >= limit {
+                return Err(quota_exceeded(ctx, "connections", limit as u64));
+            }
+        }
+        tenant.connections += 1;
+        Ok(())
+    }
+
+    /// stop counting a connection once it has closed
+    pub fn disconnect(&mut self, ctx: &AuthContext) {
+        if let Some(tenant) = self.tenants.get_mut(&ctx.tenant) {
+            tenant.connections = tenant.connections.saturating_sub(1);
+        }
+    }
+
+    /// record the size of doc's storage in bytes, returning the size it may
+    /// grow to without taking its tenant over quota (to pass to
+    /// StorageQuota::max_database_bytes), or None if storage is unlimited.
+    /// the limit shrinks as the tenant's other documents grow, so hosts
+    /// should record sizes after every change
+    pub fn record_storage(
+        &mut self,
+        doc: JournalId,
+        bytes: u64,
+    ) -> Result<Option<u64>> {
+        let owner =
+            self.owners.get(&doc).ok_or(TenantError::DocNotFound(doc))?;
+        let tenant = self
+            .tenants
+            .get_mut(owner)
+            .ok_or_else(|| TenantError::UnknownTenant(owner.clone()))?;
+        if tenant.documents.insert(doc, bytes) != Some(bytes) {
+            self.changed = true;
+        }
+        let Some(limit) = tenant.quota.max_storage_bytes else {
+            return Ok(None);
+        };
+        let others = tenant.usage().storage_bytes - bytes;
+        Ok(Some(limit.saturating_sub(others)))
+    }
+
+    fn tenant(&self, tenant: &TenantId) -> Result<&Tenant> {
+        self.tenants
+            .get(tenant)
+            .ok_or_else(|| TenantError::UnknownTenant(tenant.clone()))
+    }
+
+    fn tenant_mut(&mut self, tenant: &TenantId) -> Result<&mut Tenant> {
+        self.tenants
+            .get_mut(tenant)
+            .ok_or_else(|| TenantError::UnknownTenant(tenant.clone()))
+    }
+}
+
+fn quota_exceeded(
+    ctx: &AuthContext,
+    resource: &'static str,
+    limit: u64,
+) -> TenantError {
+    TenantError::QuotaExceeded { tenant: ctx.tenant.clone(), resource, limit }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AuthContext, Authenticator, RegistryState, TenantError, TenantId,
+        TenantQuota, TenantRegistry,
+    };
+
+    /// accepts credentials equal to the tenant's secret
+    struct Secret(&'static [u8]);
+
+    impl Authenticator for Secret {
+        fn authenticate(&self, credentials: &[u8]) -> Option<String> {
+            (credentials == self.0).then(|| "user".to_string())
+        }
+    }
+
+    fn registry(quota: TenantQuota) -> (TenantRegistry, AuthContext) {
+        let mut registry = TenantRegistry::new();
+        let acme = TenantId::new("acme").unwrap();
+        registry.add_tenant(acme.clone(), quota, Box::new(Secret(b"a")));
+        registry.add_tenant(
+            TenantId::new("globex").unwrap(),
+            quota,
+            Box::new(Secret(b"g")),
+        );
+        let ctx = registry.authenticate(&acme, b"a").unwrap();
+        (registry, ctx)
+    }
+
+    #[test]
+    fn tenant_ids() {
+        assert!(TenantId::new("").is_err());
+        assert!(TenantId::new("a/b").is_err());
+        assert!(TenantId::new("a".repeat(65)).is_err());
+        let acme = TenantId::new("acme").unwrap();
+        let globex = TenantId::new("globex").unwrap();
+        assert_eq!(acme.doc_id("doc"), acme.doc_id("doc"));
+        assert_ne!(acme.doc_id("doc"), globex.doc_id("doc"));
+    }
+
+    #[test]
+    fn isolates_tenants() {
+        let (mut registry, acme) = registry(TenantQuota::default());
+        let globex = TenantId::new("globex").unwrap();
+        assert_eq!(
+            registry.authenticate(&globex, b"a"),
+            Err(TenantError::AuthFailed)
+        );
+        let globex = registry.authenticate(&globex, b"g").unwrap();
+
+        let doc = registry.open_document(&acme, "doc").unwrap();
+        assert_eq!(registry.owner(doc), Some(&acme.tenant));
+        registry.connect(&acme, doc).unwrap();
+        assert_eq!(
+            registry.connect(&globex, doc),
+            Err(TenantError::DocNotFound(doc))
+        );
+    }
+
+    #[test]
+    fn enforces_quotas() {
+        let (mut registry, ctx) = registry(TenantQuota {
+            max_documents: Some(2),
+            max_storage_bytes: Some(1000),
+            max_connections: Some(1),
+        });
+        let a = registry.open_document(&ctx, "a").unwrap();
+        let b = registry.open_document(&ctx, "b").unwrap();
+        // reopening an existing document doesn't count against the quota
+        assert_eq!(registry.open_document(&ctx, "a").unwrap(), a);
+        assert!(matches!(
+            registry.open_document(&ctx, "c"),
+            Err(TenantError::QuotaExceeded { resource: "documents", .. })
+        ));
+
+        registry.connect(&ctx, a).unwrap();
+        assert!(registry.connect(&ctx, b).is_err());
+        registry.disconnect(&ctx);
+        registry.connect(&ctx, b).unwrap();
+
+        assert_eq!(registry.record_storage(a, 600).unwrap(), Some(1000));
+        assert_eq!(registry.record_storage(b, 300).unwrap(), Some(400));
+        assert_eq!(registry.record_storage(a, 600).unwrap(), Some(700));
+        let usage = registry.usage(&ctx.tenant).unwrap();
+        assert_eq!(usage.storage_bytes, 900);
+        assert_eq!(usage.connections, 1);
+
+        registry.remove_document(a);
+        assert_eq!(registry.usage(&ctx.tenant).unwrap().documents, 1);
+        assert_eq!(registry.remove_tenant(&ctx.tenant), vec![b]);
+        assert_eq!(registry.owner(b), None);
+    }
+
+    #[test]
+    fn restores_documents_after_a_restart() {
+        let quota =
+            TenantQuota { max_documents: Some(1), ..Default::default() };
+        let (mut registry, ctx) = registry(quota);
+        assert_eq!(registry.take_changed(), None);
+        let doc = registry.open_document(&ctx, "doc").unwrap();
+        registry.record_storage(doc, 100).unwrap();
+        let state = registry.take_changed().unwrap();
+        // recording the same size again leaves nothing new to save
+        registry.record_storage(doc, 100).unwrap();
+        assert_eq!(registry.take_changed(), None);
+
+        let saved = bincode::serialize(&state).unwrap();
+        let state: RegistryState = bincode::deserialize(&saved).unwrap();
+        let mut restored = TenantRegistry::restore(state.clone());
+        assert_eq!(restored.owner(doc), Some(&ctx.tenant));
+        assert_eq!(restored.state(), state);
+
+        // the tenant's documents count against its quota once it's added
+        restored.add_tenant(ctx.tenant.clone(), quota, Box::new(Secret(b"a")));
+        let usage = restored.usage(&ctx.tenant).unwrap();
+        assert_eq!((usage.documents, usage.storage_bytes), (1, 100));
+        assert!(matches!(
+            restored.open_document(&ctx, "other"),
+            Err(TenantError::QuotaExceeded { resource: "documents", .. })
+        ));
+    }
+}
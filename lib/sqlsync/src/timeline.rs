@@ -1,14 +1,26 @@
-use std::io;
+use std::{borrow::Cow, fmt, io, str::FromStr, time::Duration};
 
-use rusqlite::{named_params, Connection, Transaction};
+use rand::Rng;
+use rusqlite::{named_params, Connection, OptionalExtension, Transaction};
 use thiserror::Error;
 
 use crate::{
-    journal::Journal,
+    coordinator::SERVER_TIMELINE_ID,
+    effects::{delete_effects, parse_ack_mutation, run_effects_migration},
+    hints::{content_digest, run_hints_migration, MutationHints, RepeatFilter},
+    journal::{Journal, JournalId},
+    logging::{self, MutationId},
     lsn::{Lsn, LsnRange},
+    order::run_order_migration,
     positioned_io::PositionedReader,
-    reducer::{Reducer, ReducerError},
-    JournalError,
+    profile::MutationProfile,
+    reducer::{Reducer, ReducerError, Reduction},
+    schedule::{
+        parse_schedule_mutation, run_schedule_migration, write_schedule,
+    },
+    ttl::{expire_rows, parse_expiry_mutation, run_ttl_migration},
+    unixtime::{monotonic_microseconds, unix_timestamp_milliseconds},
+    JournalError, Serializable,
 };
 
 const TIMELINES_TABLE_SQL: &str = "
@@ -30,6 +42,473 @@ const TIMELINES_UPDATE_LSN_SQL: &str = "
     ON CONFLICT (id) DO UPDATE SET lsn = :lsn
 ";
 
+const IDEMPOTENCY_KEYS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_idempotency_keys (
+        timeline_id BLOB NOT NULL,
+        key BLOB NOT NULL,
+        lsn INTEGER NOT NULL,
+        PRIMARY KEY (timeline_id, key)
+    ) STRICT
+";
+
+const IDEMPOTENCY_KEYS_CHECK_SQL: &str = "
+    SELECT lsn
+    FROM __sqlsync_idempotency_keys
+    WHERE timeline_id = :id AND key = :key
+";
+
+const IDEMPOTENCY_KEYS_INSERT_SQL: &str = "
+    INSERT INTO __sqlsync_idempotency_keys (timeline_id, key, lsn)
+    VALUES (:id, :key, :lsn)
+";
+
+const IDEMPOTENCY_KEYS_PRUNE_SQL: &str = "
+    DELETE FROM __sqlsync_idempotency_keys
+    WHERE timeline_id = :id AND lsn < :min_lsn
+";
+
+const CANCELLED_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_cancelled (
+        timeline_id BLOB NOT NULL,
+        lsn INTEGER NOT NULL,
+        PRIMARY KEY (timeline_id, lsn)
+    ) STRICT
+";
+
+const CANCELLED_CHECK_SQL: &str = "
+    SELECT 1
+    FROM __sqlsync_cancelled
+    WHERE timeline_id = :id AND lsn = :lsn
+";
+
+const CANCELLED_INSERT_SQL: &str = "
+    INSERT OR IGNORE INTO __sqlsync_cancelled (timeline_id, lsn)
+    VALUES (:id, :lsn)
+";
+
+const CANCELLED_PRUNE_SQL: &str = "
+    DELETE FROM __sqlsync_cancelled
+    WHERE timeline_id = :id AND lsn < :min_lsn
+";
+
+/// a key is remembered for this many lsns after its mutation is applied;
+/// retries which arrive later than that will be applied again. cancelled
+/// mutations are remembered for as long, see observes_token
+pub const IDEMPOTENCY_WINDOW: u64 = 1024;
+
+/// server timeline mutations which start with this prefix run the reducer
+/// task named by the rest of the mutation
+const TASK_MUTATION_MAGIC: &[u8; 8] = b"\0sqstsk1";
+
+/// the server mutation which runs the named reducer task. recording task
+/// runs in the server timeline keeps them deterministic across replicas
+pub fn task_mutation(name: &str) -> Vec<u8> {
+    [&TASK_MUTATION_MAGIC[..], name.as_bytes()].concat()
+}
+
+/// IdempotencyKey is generated by the client for each logical mutation and
+/// reused for any retries of it, allowing the coordinator to drop duplicates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey([u8; 16]);
+
+impl IdempotencyKey {
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.gen())
+    }
+
+    pub fn bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for IdempotencyKey {
+    type Error = TimelineError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        value
+            .try_into()
+            .map(Self)
+            .map_err(|_| TimelineError::InvalidIdempotencyKey(value.len()))
+    }
+}
+
+/// every timeline frame starts with the version of its layout, then its
+/// kind and a flags byte, the fields the flags select (in FRAME_HAS_* order)
+/// and the mutation prefixed with its u32 length. a frame must end with its
+/// mutation, so a mutation can't be mistaken for a frame header or the
+/// other way around
+const FRAME_VERSION: u8 = 1;
+
+const FRAME_HAS_KEY: u8 = 1 << 0;
+const FRAME_HAS_TIMESTAMP: u8 = 1 << 1;
+const FRAME_HAS_TAG: u8 = 1 << 2;
+const FRAME_IS_LZ4: u8 = 1 << 3;
+const FRAME_FLAGS: u8 =
+    FRAME_HAS_KEY | FRAME_HAS_TIMESTAMP | FRAME_HAS_TAG | FRAME_IS_LZ4;
+
+/// lz4 can't expand its input by more than this, so an lz4 size prefix
+/// claiming more is a lie
+const MAX_LZ4_RATIO: u64 = 255;
+
+/// FrameKind is what a timeline frame holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FrameKind {
+    /// a mutation for the reducer
+    #[default]
+    Mutation,
+    /// a mutation which was cancelled, see cancel_mutation and
+    /// cancelled_frame. it has no effect, and keeps its lsn so that later
+    /// mutations keep theirs
+    Cancelled,
+}
+
+impl FrameKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameKind::Mutation => 0,
+            FrameKind::Cancelled => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameKind::Mutation),
+            1 => Some(FrameKind::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Codec is how the mutation bytes of a timeline frame are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Raw,
+    /// an lz4 block, prefixed with the uncompressed length
+    Lz4,
+}
+
+impl Codec {
+    fn flags(self) -> u8 {
+        match self {
+            Codec::Raw => 0,
+            Codec::Lz4 => FRAME_IS_LZ4,
+        }
+    }
+}
+
+/// lz4 compress a mutation larger than threshold bytes, unless that doesn't
+/// make it any smaller
+pub(crate) fn compress_mutation(
+    mutation: &[u8],
+    threshold: Option<u64>,
+) -> Option<Vec<u8>> {
+    if mutation.len() as u64 <= threshold? {
+        return None;
+    }
+    let compressed = lz4_flex::compress_prepend_size(mutation);
+    (compressed.len() < mutation.len()).then_some(compressed)
+}
+
+/// ConsistencyToken is returned by a mutation so that readers can make sure
+/// they observe it, even in a document with a different timeline such as a
+/// coordinator or another client. it's written as timeline_id@lsn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsistencyToken {
+    pub timeline_id: JournalId,
+    pub lsn: Lsn,
+}
+
+impl fmt::Display for ConsistencyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.timeline_id, self.lsn)
+    }
+}
+
+impl FromStr for ConsistencyToken {
+    type Err = TimelineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || TimelineError::InvalidConsistencyToken(s.to_owned());
+        let (timeline_id, lsn) = s.split_once('@').ok_or_else(invalid)?;
+        Ok(Self {
+            timeline_id: JournalId::try_from(timeline_id)
+                .map_err(|_| invalid())?,
+            lsn: lsn.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// whether the database includes the mutation the token was returned for,
+/// as applied by the coordinator. fails with MutationCancelled if the
+/// mutation was cancelled instead, and so will never be observed
+pub fn observes_token(
+    sqlite: &Connection,
+    token: ConsistencyToken,
+) -> Result<bool> {
+    let cancelled = sqlite
+        .query_row(
+            CANCELLED_CHECK_SQL,
+            named_params! {":id": token.timeline_id, ":lsn": token.lsn},
+            |_| Ok(()),
+        )
+        .optional()?;
+    if cancelled.is_some() {
+        return Err(TimelineError::MutationCancelled(token));
+    }
+    Ok(applied_lsn(sqlite, token.timeline_id)?
+        .is_some_and(|applied| applied >= token.lsn))
+}
+
+/// FrameMeta is optional information a client attaches to a mutation, which
+/// is surfaced alongside it (see cdc::Change) so that activity feeds can be
+/// built without decoding mutations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameMeta {
+    /// the client's wall-clock time when the mutation was made, in unix
+    /// milliseconds. clients' clocks may be skewed, so don't order by it
+    pub timestamp_ms: Option<i64>,
+    /// an app-defined tag, e.g. the kind of mutation or the user it acts on
+    pub tag: Option<u32>,
+}
+
+impl FrameMeta {
+    /// metadata stamped with the current time
+    pub fn now() -> Self {
+        Self { timestamp_ms: Some(unix_timestamp_milliseconds()), tag: None }
+    }
+
+    pub fn with_tag(self, tag: u32) -> Self {
+        Self { tag: Some(tag), ..self }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timestamp_ms.is_none() && self.tag.is_none()
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.timestamp_ms.is_some() {
+            flags |= FRAME_HAS_TIMESTAMP;
+        }
+        if self.tag.is_some() {
+            flags |= FRAME_HAS_TAG;
+        }
+        flags
+    }
+
+    fn fields_size(&self) -> usize {
+        self.timestamp_ms.map_or(0, |_| 8) + self.tag.map_or(0, |_| 4)
+    }
+
+    /// write the metadata as a flags byte followed by the fields present
+    pub(crate) fn serialize_into<W: io::Write>(
+        &self,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        writer.write_all(&[self.flags()])?;
+        self.serialize_fields(writer)
+    }
+
+    fn serialize_fields<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        if let Some(timestamp_ms) = self.timestamp_ms {
+            writer.write_all(&timestamp_ms.to_le_bytes())?;
+        }
+        if let Some(tag) = self.tag {
+            writer.write_all(&tag.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// read metadata written by serialize_into
+    pub(crate) fn deserialize_from<R: io::Read>(
+        reader: &mut R,
+    ) -> io::Result<Self> {
+        let mut flags = [0; 1];
+        reader.read_exact(&mut flags)?;
+        Self::deserialize_fields(flags[0], reader)
+    }
+
+    fn deserialize_fields<R: io::Read>(
+        flags: u8,
+        reader: &mut R,
+    ) -> io::Result<Self> {
+        let mut meta = Self::default();
+        if flags & FRAME_HAS_TIMESTAMP != 0 {
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf)?;
+            meta.timestamp_ms = Some(i64::from_le_bytes(buf));
+        }
+        if flags & FRAME_HAS_TAG != 0 {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            meta.tag = Some(u32::from_le_bytes(buf));
+        }
+        Ok(meta)
+    }
+}
+
+/// TimelineFrame is a mutation as it's stored in a timeline journal
+#[derive(Debug, Clone, Copy)]
+pub struct TimelineFrame<'a> {
+    pub kind: FrameKind,
+    pub key: Option<IdempotencyKey>,
+    pub meta: FrameMeta,
+    pub codec: Codec,
+    /// the mutation encoded with codec, see decompressed
+    pub mutation: &'a [u8],
+}
+
+impl<'a> TimelineFrame<'a> {
+    pub fn decode(frame: &'a [u8]) -> Result<Self> {
+        let mut reader = FrameReader(frame);
+        let version = reader.byte()?;
+        if version != FRAME_VERSION {
+            return Err(TimelineError::UnsupportedFrameVersion(version));
+        }
+        let kind = FrameKind::from_byte(reader.byte()?)
+            .ok_or(TimelineError::MalformedFrame("unknown kind"))?;
+        let flags = reader.byte()?;
+        if flags & !FRAME_FLAGS != 0 {
+            return Err(TimelineError::MalformedFrame("unknown flags"));
+        }
+        let key = if flags & FRAME_HAS_KEY != 0 {
+            Some(IdempotencyKey(reader.array()?))
+        } else {
+            None
+        };
+        let meta = FrameMeta {
+            timestamp_ms: (flags & FRAME_HAS_TIMESTAMP != 0)
+                .then(|| reader.array().map(i64::from_le_bytes))
+                .transpose()?,
+            tag: (flags & FRAME_HAS_TAG != 0)
+                .then(|| reader.array().map(u32::from_le_bytes))
+                .transpose()?,
+        };
+        let codec = if flags & FRAME_IS_LZ4 != 0 {
+            Codec::Lz4
+        } else {
+            Codec::Raw
+        };
+        let len = u32::from_le_bytes(reader.array()?) as usize;
+        let mutation = reader.take(len)?;
+        if !reader.0.is_empty() {
+            return Err(TimelineError::MalformedFrame(
+                "trailing bytes after the mutation",
+            ));
+        }
+        Ok(Self { kind, key, meta, codec, mutation })
+    }
+
+    /// the mutation as the reducer expects it. the buffer is only allocated
+    /// once mutation_size has checked that the lz4 size prefix is plausible,
+    /// so a frame can't claim a huge mutation to exhaust memory
+    pub fn decompressed(&self) -> Result<Cow<'a, [u8]>> {
+        self.mutation_size()?;
+        match self.codec {
+            Codec::Raw => Ok(Cow::Borrowed(self.mutation)),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(self.mutation)
+                .map(Cow::Owned)
+                .map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, err).into()
+                }),
+        }
+    }
+
+    /// the size of the mutation once decompressed, read from the lz4 size
+    /// prefix so that checking it doesn't allocate. fails with
+    /// ImplausibleMutationSize if the prefix claims more than lz4 could
+    /// decompress the rest of the mutation to
+    pub fn mutation_size(&self) -> Result<u64> {
+        match self.codec {
+            Codec::Raw => Ok(self.mutation.len() as u64),
+            Codec::Lz4 => {
+                let prefix = FrameReader(self.mutation).array()?;
+                let size = u32::from_le_bytes(prefix) as u64;
+                let compressed = self.mutation.len() as u64;
+                if size > compressed.saturating_mul(MAX_LZ4_RATIO) {
+                    return Err(TimelineError::ImplausibleMutationSize {
+                        size,
+                        compressed,
+                    });
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    /// this frame with its mutation replaced by an uncompressed one
+    pub fn with_raw_mutation<'b>(
+        &self,
+        mutation: &'b [u8],
+    ) -> TimelineFrame<'b> {
+        TimelineFrame {
+            kind: self.kind,
+            key: self.key,
+            meta: self.meta,
+            codec: Codec::Raw,
+            mutation,
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        self.key.map_or(0, |_| FRAME_HAS_KEY)
+            | self.meta.flags()
+            | self.codec.flags()
+    }
+}
+
+/// reads the fields of a frame, failing if it ends early
+struct FrameReader<'a>(&'a [u8]);
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(TimelineError::MalformedFrame("truncated"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+}
+
+impl Serializable for TimelineFrame<'_> {
+    fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let len = u32::try_from(self.mutation.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "mutation too large")
+        })?;
+        writer.write_all(&[
+            FRAME_VERSION,
+            self.kind.to_byte(),
+            self.flags(),
+        ])?;
+        if let Some(key) = self.key {
+            writer.write_all(key.bytes())?;
+        }
+        self.meta.serialize_fields(writer)?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(self.mutation)
+    }
+
+    fn serialized_size(&self) -> Option<usize> {
+        // version, kind, flags, then the fields and the mutation's length
+        let fields = self.key.map_or(0, |_| 16) + self.meta.fields_size();
+        Some(3 + fields + 4 + self.mutation.len())
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum TimelineError {
     #[error("io error: {0}")]
@@ -43,53 +522,399 @@ pub enum TimelineError {
 
     #[error(transparent)]
     ReducerError(#[from] ReducerError),
+
+    #[error("idempotency keys must be 16 bytes, got {0}")]
+    InvalidIdempotencyKey(usize),
+
+    #[error("invalid consistency token: {0}")]
+    InvalidConsistencyToken(String),
+
+    #[error("unsupported timeline frame version {0}")]
+    UnsupportedFrameVersion(u8),
+
+    #[error("malformed timeline frame: {0}")]
+    MalformedFrame(&'static str),
+
+    #[error(
+        "compressed mutation claims to be {size} bytes, which {compressed} \
+        bytes can't decompress to"
+    )]
+    ImplausibleMutationSize { size: u64, compressed: u64 },
+
+    #[error("mutation {0} was cancelled")]
+    MutationCancelled(ConsistencyToken),
 }
 
 type Result<T> = std::result::Result<T, TimelineError>;
 
-fn run_in_tx<F>(sqlite: &mut Connection, f: F) -> Result<()>
+fn run_in_tx<F, T>(sqlite: &mut Connection, f: F) -> Result<T>
 where
-    F: FnOnce(&mut Transaction) -> Result<()>,
+    F: FnOnce(&mut Transaction) -> Result<T>,
 {
     let mut txn = sqlite.transaction()?;
-    f(&mut txn)?; // will cause a rollback on failure
+    let out = f(&mut txn)?; // will cause a rollback on failure
     txn.commit()?;
-    Ok(())
+    Ok(out)
 }
 
 pub fn run_timeline_migration(sqlite: &mut Connection) -> Result<()> {
     sqlite.execute(TIMELINES_TABLE_SQL, [])?;
+    sqlite.execute(IDEMPOTENCY_KEYS_TABLE_SQL, [])?;
+    sqlite.execute(CANCELLED_TABLE_SQL, [])?;
+    run_ttl_migration(sqlite)?;
+    run_effects_migration(sqlite)?;
+    run_order_migration(sqlite)?;
+    run_hints_migration(sqlite)?;
+    run_schedule_migration(sqlite)?;
+    Ok(())
+}
+
+/// apply a timeline frame at lsn, unless it carries an idempotency key which
+/// has already been applied from this timeline. returns None if the frame
+/// was skipped as a duplicate.
+fn apply_frame(
+    tx: &Connection,
+    reducer: &mut Reducer,
+    timeline_id: JournalId,
+    lsn: Lsn,
+    frame: TimelineFrame,
+    should_yield: impl FnMut() -> bool,
+) -> Result<Option<Reduction>> {
+    if !record_key(tx, timeline_id, lsn, frame)? {
+        return Ok(None);
+    }
+    if frame.kind == FrameKind::Cancelled {
+        record_cancelled(tx, timeline_id, lsn)?;
+        return Ok(Some(Reduction::Complete));
+    }
+
+    // attribute anything the reducer logs to this mutation
+    reducer.set_log_mutation(Some(MutationId { timeline_id, lsn }));
+    let mutation = frame.decompressed()?;
+    let result = reduce(tx, reducer, timeline_id, &mutation, should_yield);
+    reducer.set_log_mutation(None);
+    Ok(Some(result?))
+}
+
+/// continue the mutation at lsn, which the reducer was preempted in
+fn resume_frame(
+    tx: &Connection,
+    reducer: &mut Reducer,
+    timeline_id: JournalId,
+    lsn: Lsn,
+    should_yield: impl FnMut() -> bool,
+) -> Result<Reduction> {
+    reducer.set_log_mutation(Some(MutationId { timeline_id, lsn }));
+    let result = reducer.resume(tx, should_yield);
+    reducer.set_log_mutation(None);
+    Ok(result?)
+}
+
+/// record the frame's idempotency key, if it has one, returning false if
+/// the key has already been applied from this timeline
+fn record_key(
+    tx: &Connection,
+    timeline_id: JournalId,
+    lsn: Lsn,
+    frame: TimelineFrame,
+) -> Result<bool> {
+    if let Some(key) = frame.key {
+        let applied_at: Option<Lsn> = tx
+            .query_row(
+                IDEMPOTENCY_KEYS_CHECK_SQL,
+                named_params! {":id": timeline_id, ":key": &key.bytes()[..]},
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(applied_at) = applied_at {
+            logging::info!(
+                "skipping duplicate mutation at lsn {} from timeline {} (first applied at lsn {})",
+                lsn,
+                timeline_id,
+                applied_at
+            );
+            return Ok(false);
+        }
+
+        tx.execute(
+            IDEMPOTENCY_KEYS_INSERT_SQL,
+            named_params! {":id": timeline_id, ":key": &key.bytes()[..], ":lsn": lsn},
+        )?;
+        tx.execute(
+            IDEMPOTENCY_KEYS_PRUNE_SQL,
+            named_params! {
+                ":id": timeline_id,
+                ":min_lsn": lsn.saturating_sub(IDEMPOTENCY_WINDOW),
+            },
+        )?;
+    }
+    Ok(true)
+}
+
+/// remember that the mutation at lsn was cancelled, so that its token
+/// reports it rather than being observed, see observes_token
+fn record_cancelled(
+    tx: &Connection,
+    timeline_id: JournalId,
+    lsn: Lsn,
+) -> Result<()> {
+    tx.execute(
+        CANCELLED_INSERT_SQL,
+        named_params! {":id": timeline_id, ":lsn": lsn},
+    )?;
+    tx.execute(
+        CANCELLED_PRUNE_SQL,
+        named_params! {
+            ":id": timeline_id,
+            ":min_lsn": lsn.saturating_sub(IDEMPOTENCY_WINDOW),
+        },
+    )?;
+    Ok(())
+}
+
+/// skip a back to back repeat of an idempotent mutation (see the hints
+/// module), only recording its idempotency key. debug builds still apply it
+/// in a savepoint and check that the document didn't change
+fn skip_repeat(
+    tx: &Connection,
+    reducer: &mut Reducer,
+    timeline_id: JournalId,
+    lsn: Lsn,
+    frame: TimelineFrame,
+) -> Result<()> {
+    logging::debug!(
+        "skipping repeated idempotent mutation at lsn {} from timeline {}",
+        lsn,
+        timeline_id
+    );
+    if cfg!(debug_assertions) {
+        let before = content_digest(tx)?;
+        tx.execute_batch("SAVEPOINT sqlsync_verify_repeat")?;
+        let result =
+            apply_frame(tx, reducer, timeline_id, lsn, frame, || false)
+                .and_then(|_| Ok(content_digest(tx)?));
+        tx.execute_batch(
+            "ROLLBACK TO sqlsync_verify_repeat; RELEASE sqlsync_verify_repeat",
+        )?;
+        assert_eq!(
+            before, result?,
+            "mutation at lsn {} from timeline {} is hinted idempotent, but \
+            applying it again changed the document",
+            lsn, timeline_id
+        );
+    }
+    record_key(tx, timeline_id, lsn, frame)?;
     Ok(())
 }
 
+/// apply the ranges in arrival order and then in the order the coordinator
+/// chose, each in a transaction which is rolled back, and check that the
+/// document ends up the same. used in debug builds to check commute hints,
+/// see the hints module
+pub(crate) fn verify_commuting<J: Journal>(
+    sqlite: &mut Connection,
+    reducer: &mut Reducer,
+    arrival: &[(&J, LsnRange)],
+    reordered: &[(&J, LsnRange)],
+) -> Result<()> {
+    let mut digest = |ranges: &[(&J, LsnRange)]| -> Result<u64> {
+        // dropping the transaction rolls it back
+        let tx = sqlite.transaction()?;
+        // never preempted, since nothing yields
+        for &(timeline, range) in ranges {
+            apply_range_in_tx(
+                timeline,
+                &tx,
+                reducer,
+                range,
+                false,
+                || false,
+                |_, _| {},
+            )?;
+        }
+        Ok(content_digest(&tx)?)
+    };
+    let expected = digest(arrival)?;
+    assert_eq!(
+        expected,
+        digest(reordered)?,
+        "mutations are hinted to commute, but applying them out of arrival \
+        order changed the document"
+    );
+    Ok(())
+}
+
+fn reduce(
+    tx: &Connection,
+    reducer: &mut Reducer,
+    timeline_id: JournalId,
+    mutation: &[u8],
+    should_yield: impl FnMut() -> bool,
+) -> Result<Reduction> {
+    // only the coordinator may run or schedule tasks, expire rows, or ack
+    // effects
+    if timeline_id == SERVER_TIMELINE_ID {
+        if let Some(name) = mutation.strip_prefix(TASK_MUTATION_MAGIC) {
+            reducer.run_task(tx, &String::from_utf8_lossy(name))?;
+            return Ok(Reduction::Complete);
+        }
+        if let Some(cutoff_ms) = parse_expiry_mutation(mutation) {
+            let deleted = expire_rows(tx, cutoff_ms)?;
+            logging::debug!("expired {} rows at {}", deleted, cutoff_ms);
+            return Ok(Reduction::Complete);
+        }
+        if let Some(ids) = parse_ack_mutation(mutation) {
+            delete_effects(tx, &ids)?;
+            return Ok(Reduction::Complete);
+        }
+        if let Some(tasks) = parse_schedule_mutation(mutation) {
+            write_schedule(tx, &tasks)?;
+            return Ok(Reduction::Complete);
+        }
+    }
+    Ok(reducer.apply_until(tx, mutation, should_yield)?)
+}
+
 pub fn apply_mutation<J: Journal>(
     timeline: &mut J,
     sqlite: &mut Connection,
     reducer: &mut Reducer,
     mutation: &[u8],
 ) -> Result<()> {
-    run_in_tx(sqlite, |tx| Ok(reducer.apply(tx, &mutation)?))?;
-    timeline.append(mutation)?;
+    apply_keyed_mutation(
+        timeline,
+        sqlite,
+        reducer,
+        mutation,
+        None,
+        FrameMeta::default(),
+        None,
+    )?;
     Ok(())
 }
 
-pub fn rebase_timeline<J: Journal>(
+/// like apply_mutation, but records the mutation's idempotency key so that
+/// retries with the same key are skipped here and on the coordinator, along
+/// with its metadata. mutations larger than compress_over bytes are stored
+/// in the timeline compressed. returns false if the mutation was a duplicate
+/// and thus not applied.
+pub fn apply_keyed_mutation<J: Journal>(
     timeline: &mut J,
     sqlite: &mut Connection,
     reducer: &mut Reducer,
+    mutation: &[u8],
+    key: Option<IdempotencyKey>,
+    meta: FrameMeta,
+    compress_over: Option<u64>,
+) -> Result<bool> {
+    let frame = TimelineFrame {
+        kind: FrameKind::Mutation,
+        key,
+        meta,
+        codec: Codec::Raw,
+        mutation,
+    };
+    let lsn = timeline.range().next();
+    let timeline_id = timeline.id();
+    let applied = run_in_tx(sqlite, |tx| {
+        apply_frame(tx, reducer, timeline_id, lsn, frame, || false)
+    })?
+    .is_some();
+    if applied {
+        match compress_mutation(mutation, compress_over) {
+            Some(compressed) => timeline.append(TimelineFrame {
+                codec: Codec::Lz4,
+                mutation: &compressed,
+                ..frame
+            })?,
+            None => timeline.append(frame)?,
+        }
+    }
+    Ok(applied)
+}
+
+/// apply a draft mutation (see LocalDocument::mutate_draft), which isn't in
+/// the timeline and so has no lsn or idempotency key
+pub(crate) fn apply_draft(
+    sqlite: &mut Connection,
+    reducer: &mut Reducer,
+    timeline_id: JournalId,
+    mutation: &[u8],
 ) -> Result<()> {
-    let applied_lsn: Option<Lsn> = sqlite
+    run_in_tx(sqlite, |tx| {
+        reduce(tx, reducer, timeline_id, mutation, || false)?;
+        Ok(())
+    })
+}
+
+/// replace the mutation at lsn with a cancelled frame, which has no effect.
+/// the timeline is rewritten with Journal::replace, so a crash leaves
+/// either the mutation or its cancellation. the caller must make sure the
+/// frame hasn't been sent anywhere, and rebase to roll back the mutation's
+/// effects. returns false if lsn isn't in the timeline or has already been
+/// cancelled
+pub fn cancel_mutation<J: Journal>(timeline: &mut J, lsn: Lsn) -> Result<bool> {
+    let range = timeline.range();
+    let Some(first) = range.first().filter(|_| range.contains(lsn)) else {
+        return Ok(false);
+    };
+    let mut frames = Vec::with_capacity(range.len());
+    let mut cursor = timeline.scan();
+    while cursor.advance()? {
+        frames.push(cursor.read_all()?);
+    }
+    let cancelled = &mut frames[(lsn - first) as usize];
+    if TimelineFrame::decode(cancelled)?.kind == FrameKind::Cancelled {
+        return Ok(false);
+    }
+    *cancelled = cancelled_frame().to_vec()?;
+    timeline.replace(first, frames)?;
+    Ok(true)
+}
+
+/// whether the mutation at lsn in the timeline has been cancelled
+pub fn is_cancelled<J: Journal>(timeline: &J, lsn: Lsn) -> Result<bool> {
+    let mut cursor = timeline.scan_range(LsnRange::new(lsn, lsn));
+    if !cursor.advance()? {
+        return Ok(false);
+    }
+    let frame = cursor.read_all()?;
+    Ok(TimelineFrame::decode(&frame)?.kind == FrameKind::Cancelled)
+}
+
+/// a frame which has no effect, which the coordinator stores in place of a
+/// mutation it refuses so that the timeline's later lsns are unchanged
+pub fn cancelled_frame() -> TimelineFrame<'static> {
+    TimelineFrame {
+        kind: FrameKind::Cancelled,
+        key: None,
+        meta: FrameMeta::default(),
+        codec: Codec::Raw,
+        mutation: &[],
+    }
+}
+
+/// the last lsn from the timeline which has been applied to this database.
+/// the watermark is stored in the database itself, so it's committed
+/// atomically with the effects of the mutations it covers.
+pub fn applied_lsn(sqlite: &Connection, timeline_id: JournalId) -> Result<Option<Lsn>> {
+    Ok(sqlite
         .query_row(
             TIMELINES_READ_LSN_SQL,
-            named_params! {":id": timeline.id()},
+            named_params! {":id": timeline_id},
             |row| row.get(0),
         )
-        .or_else(|err| match err {
-            rusqlite::Error::QueryReturnedNoRows => Ok(None),
-            err => Err(err),
-        })?;
+        .optional()?)
+}
+
+pub fn rebase_timeline<J: Journal>(
+    timeline: &mut J,
+    sqlite: &mut Connection,
+    reducer: &mut Reducer,
+) -> Result<()> {
+    let applied_lsn = applied_lsn(sqlite, timeline.id())?;
 
-    log::info!("rebase timeline ({:?}) to lsn {:?}", timeline, applied_lsn);
+    logging::info!("rebase timeline ({:?}) to lsn {:?}", timeline, applied_lsn);
 
     // remove mutations from the journal that have already been applied
     if let Some(applied_lsn) = applied_lsn {
@@ -97,11 +922,19 @@ pub fn rebase_timeline<J: Journal>(
     }
 
     // reapply remaining mutations in the journal
+    let timeline_id = timeline.id();
     run_in_tx(sqlite, |tx| {
+        let mut repeats = RepeatFilter::new(MutationHints::read(tx)?);
         let mut cursor = timeline.scan();
         while cursor.advance()? {
-            let mutation = cursor.read_all()?;
-            reducer.apply(tx, &mutation)?;
+            let frame = cursor.read_all()?;
+            let lsn = cursor.lsn().expect("cursor must have an lsn");
+            let frame = TimelineFrame::decode(&frame)?;
+            if repeats.is_repeat(&frame) {
+                skip_repeat(tx, reducer, timeline_id, lsn, frame)?;
+            } else {
+                apply_frame(tx, reducer, timeline_id, lsn, frame, || false)?;
+            }
         }
         Ok(())
     })?;
@@ -109,63 +942,422 @@ pub fn rebase_timeline<J: Journal>(
     Ok(())
 }
 
+/// replay the timeline like rebase_timeline, but commit each mutation on
+/// its own and measure it. mutations before range are applied but not
+/// measured, and replay stops at the end of range. the caller must reset
+/// storage and rebase afterwards to discard the replayed mutations.
+/// pages_written reads Storage::pages_written
+pub(crate) fn profile_timeline<J: Journal>(
+    timeline: &J,
+    sqlite: &mut Connection,
+    reducer: &mut Reducer,
+    range: LsnRange,
+    pages_written: impl Fn() -> u64,
+) -> Result<Vec<MutationProfile>> {
+    let mut profiles = vec![];
+    let Some(last) = range.last() else {
+        return Ok(profiles);
+    };
+    let timeline_id = timeline.id();
+    let applied = applied_lsn(sqlite, timeline_id)?;
+    let mut cursor = timeline.scan();
+    while cursor.advance()? {
+        let lsn = cursor.lsn().expect("cursor must have an lsn");
+        if lsn > last {
+            break;
+        }
+        // mutations which storage already includes aren't replayed
+        if applied.is_some_and(|applied| lsn <= applied) {
+            continue;
+        }
+        let bytes = cursor.read_all()?;
+        let frame = TimelineFrame::decode(&bytes)?;
+        let mutation = frame.decompressed()?;
+        let frame = frame.with_raw_mutation(&mutation);
+
+        let (statements, pages) =
+            (reducer.statements_executed(), pages_written());
+        let start = monotonic_microseconds();
+        run_in_tx(sqlite, |tx| {
+            apply_frame(tx, reducer, timeline_id, lsn, frame, || false)
+        })?;
+        let elapsed = monotonic_microseconds().saturating_sub(start);
+
+        if range.contains(lsn) {
+            profiles.push(MutationProfile {
+                lsn,
+                mutation: frame.mutation.to_vec(),
+                meta: frame.meta,
+                duration: Duration::from_micros(elapsed),
+                statements: reducer.statements_executed() - statements,
+                pages_dirtied: pages_written() - pages,
+            });
+        }
+    }
+    Ok(profiles)
+}
+
 pub fn apply_timeline_range<J: Journal>(
     timeline: &J,
     sqlite: &mut Connection,
     reducer: &mut Reducer,
     range: LsnRange,
 ) -> Result<()> {
-    // nothing to apply, optimistically return
-    if range.is_empty() {
-        return Ok(());
-    }
+    apply_timeline_range_until(
+        timeline,
+        sqlite,
+        reducer,
+        range,
+        || false,
+        |_, _| {},
+    )?;
+    Ok(())
+}
 
-    run_in_tx(sqlite, |tx| {
-        // we first need to potentially trim the range if some or all of it has already been applied
-        let range = tx
-            .query_row(
-                TIMELINES_READ_LSN_SQL,
-                named_params! {":id": timeline.id()},
-                |row| row.get(0),
-            )
-            // trim the range to ensure we don't double apply a mutation
-            .map(|applied_lsn: u64| range.trim_prefix(applied_lsn))
-            .or_else(|err| match err {
-                rusqlite::Error::QueryReturnedNoRows => Ok(range),
-                _ => Err(err),
-            })?;
+/// Applied is how far apply_timeline_range_until got through a range
+#[derive(Debug, Clone, Copy)]
+pub struct Applied {
+    /// the part of the range which hasn't been applied
+    pub remaining: LsnRange,
+    /// the reducer was preempted part way through the first mutation of
+    /// remaining. the transaction is left open, and the next call (which
+    /// must be given remaining) finishes the mutation
+    pub preempted: bool,
+}
 
+/// apply mutations in range until should_yield returns true, returning the
+/// part of the range which has not been applied yet. should_yield is checked
+/// after each mutation and whenever the reducer awaits a batch of requests,
+/// but at least one mutation or batch is applied per call. a mutation the
+/// reducer is preempted in keeps the transaction open until it's resumed,
+/// so the sqlite connection mustn't be used for anything else until then.
+///
+/// on_applied is called with the lsn and frame of every mutation which is
+/// applied (as opposed to skipped as a duplicate or a repeat, see the hints
+/// module). it runs inside the transaction, so anything it records must be
+/// discarded if this fails. if this fails the whole transaction rolls back,
+/// including the mutations applied before the reducer was preempted.
+pub fn apply_timeline_range_until<J: Journal, F, A>(
+    timeline: &J,
+    sqlite: &mut Connection,
+    reducer: &mut Reducer,
+    range: LsnRange,
+    mut should_yield: F,
+    mut on_applied: A,
+) -> Result<Applied>
+where
+    F: FnMut() -> bool,
+    A: FnMut(Lsn, TimelineFrame),
+{
+    let resuming = reducer.is_preempted();
+    if !resuming {
+        // nothing to apply, optimistically return
         if range.is_empty() {
-            // nothing to apply, optimistically return
-            Ok(())
-        } else {
-            log::debug!("applying range: {:?}", range);
+            return Ok(Applied { remaining: range, preempted: false });
+        }
+        sqlite.execute_batch("BEGIN")?;
+    }
 
-            // ok, some or all of the provided range needs to be applied so let's do that
-            let mut cursor = timeline.scan_range(range);
-            while cursor.advance()? {
-                let mutation = cursor.read_all()?;
-                reducer.apply(tx, &mutation)?;
-            }
+    let result = apply_range_in_tx(
+        timeline,
+        sqlite,
+        reducer,
+        range,
+        resuming,
+        &mut should_yield,
+        &mut on_applied,
+    )
+    .and_then(|applied| {
+        if !applied.preempted {
+            sqlite.execute_batch("COMMIT")?;
+        }
+        Ok(applied)
+    });
+    if result.is_err() {
+        reducer.abandon();
+        if !sqlite.is_autocommit() {
+            sqlite.execute_batch("ROLLBACK")?;
+        }
+    }
+    result
+
+    // TODO: once the above tx commits we can GC applied entries in the timeline
+}
 
-            log::debug!(
-                "updating timeline {} to lsn {:?}",
+fn apply_range_in_tx<J: Journal>(
+    timeline: &J,
+    tx: &Connection,
+    reducer: &mut Reducer,
+    range: LsnRange,
+    mut resuming: bool,
+    mut should_yield: impl FnMut() -> bool,
+    mut on_applied: impl FnMut(Lsn, TimelineFrame),
+) -> Result<Applied> {
+    // we first need to potentially trim the range if some or all of it has
+    // already been applied, to ensure we don't double apply a mutation
+    let range = match applied_lsn(tx, timeline.id())? {
+        Some(applied_lsn) => range.trim_prefix(applied_lsn),
+        None => range,
+    };
+    if range.is_empty() {
+        // nothing to apply, optimistically return
+        return Ok(Applied { remaining: range, preempted: false });
+    }
+    logging::debug!("applying range: {:?}", range);
+
+    // ok, some or all of the provided range needs to be applied so let's do that
+    let mut applied_lsn = None;
+    let mut preempted = false;
+    let mut repeats = RepeatFilter::new(MutationHints::read(tx)?);
+    let mut cursor = timeline.scan_range(range);
+    while cursor.advance()? {
+        let frame = cursor.read_all()?;
+        let lsn = cursor.lsn().expect("cursor must have an lsn");
+        let frame = TimelineFrame::decode(&frame)?;
+        // decompress once for both the reducer and on_applied
+        let mutation = frame.decompressed()?;
+        let frame = frame.with_raw_mutation(&mutation);
+        let repeat = repeats.is_repeat(&frame);
+        let reduction = if std::mem::take(&mut resuming) {
+            Some(resume_frame(
+                tx,
+                reducer,
                 timeline.id(),
-                range.last()
-            );
+                lsn,
+                &mut should_yield,
+            )?)
+        } else if repeat {
+            skip_repeat(tx, reducer, timeline.id(), lsn, frame)?;
+            None
+        } else {
+            apply_frame(
+                tx,
+                reducer,
+                timeline.id(),
+                lsn,
+                frame,
+                &mut should_yield,
+            )?
+        };
+        match reduction {
+            Some(Reduction::Preempted) => {
+                preempted = true;
+                break;
+            }
+            Some(Reduction::Complete) => on_applied(lsn, frame),
+            None => {}
+        }
+        applied_lsn = Some(lsn);
+        if should_yield() {
+            break;
+        }
+    }
 
-            // if we successfully apply all the above mutations update
-            // the cursor in the db
-            tx.execute(
-                TIMELINES_UPDATE_LSN_SQL,
-                rusqlite::named_params! {
-                    ":id": timeline.id(),
-                    ":lsn": &range.last(),
-                },
-            )?;
-            Ok(())
+    let Some(applied_lsn) = applied_lsn else {
+        return Ok(Applied { remaining: range, preempted });
+    };
+
+    // the watermark only moves once the transaction is about to commit, so
+    // that nothing reads a watermark which may still roll back
+    if !preempted {
+        logging::debug!(
+            "updating timeline {} to lsn {:?}",
+            timeline.id(),
+            applied_lsn
+        );
+        tx.execute(
+            TIMELINES_UPDATE_LSN_SQL,
+            rusqlite::named_params! {
+                ":id": timeline.id(),
+                ":lsn": &applied_lsn,
+            },
+        )?;
+    }
+    Ok(Applied { remaining: range.trim_prefix(applied_lsn), preempted })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cancel_mutation, compress_mutation, is_cancelled, Codec, FrameKind,
+        FrameMeta, IdempotencyKey, TimelineFrame, MAX_LZ4_RATIO,
+    };
+    use crate::{
+        journal::Scannable, Journal, JournalId, MemoryJournal, Serializable,
+    };
+
+    #[test]
+    fn frames_round_trip() {
+        let key = Some(IdempotencyKey::new([9; 16]));
+        let meta = FrameMeta { timestamp_ms: Some(-1), tag: Some(42) };
+        for (kind, key, meta) in [
+            (FrameKind::Mutation, None, FrameMeta::default()),
+            (FrameKind::Mutation, key, FrameMeta::default()),
+            (FrameKind::Mutation, None, meta),
+            (FrameKind::Mutation, key, FrameMeta { tag: None, ..meta }),
+            (FrameKind::Cancelled, None, FrameMeta::default()),
+            (FrameKind::Cancelled, key, meta),
+        ] {
+            let frame = TimelineFrame {
+                kind,
+                key,
+                meta,
+                codec: Codec::Raw,
+                mutation: b"mutation",
+            };
+            let bytes = frame.to_vec().unwrap();
+            assert_eq!(Some(bytes.len()), frame.serialized_size());
+            let decoded = TimelineFrame::decode(&bytes).unwrap();
+            assert_eq!(decoded.kind, kind);
+            assert_eq!(decoded.key, key);
+            assert_eq!(decoded.meta, meta);
+            assert_eq!(decoded.mutation, b"mutation");
         }
-    })
+    }
 
-    // TODO: once the above tx commits we can GC applied entries in the timeline
+    #[test]
+    fn compressed_frames_round_trip() {
+        let mutation = b"abcd".repeat(256);
+        assert_eq!(compress_mutation(&mutation, None), None);
+        assert_eq!(compress_mutation(&mutation, Some(1024)), None);
+        // incompressible mutations are stored as is
+        assert_eq!(compress_mutation(b"abcdefgh", Some(0)), None);
+
+        let compressed = compress_mutation(&mutation, Some(512)).unwrap();
+        let frame = TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: Some(IdempotencyKey::new([9; 16])),
+            meta: FrameMeta::default(),
+            codec: Codec::Lz4,
+            mutation: &compressed,
+        };
+        let bytes = frame.to_vec().unwrap();
+        assert_eq!(Some(bytes.len()), frame.serialized_size());
+        let decoded = TimelineFrame::decode(&bytes).unwrap();
+        assert_eq!(decoded.codec, Codec::Lz4);
+        assert_eq!(decoded.key, frame.key);
+        assert_eq!(decoded.decompressed().unwrap(), &mutation[..]);
+    }
+
+    #[test]
+    fn cancelled_mutations_keep_their_lsn() {
+        let mut timeline =
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap();
+        for mutation in [b"a", b"b", b"c"] {
+            timeline.append(raw_frame(mutation)).unwrap();
+        }
+        assert!(cancel_mutation(&mut timeline, 1).unwrap());
+        assert!(!cancel_mutation(&mut timeline, 1).unwrap());
+        assert!(!cancel_mutation(&mut timeline, 3).unwrap());
+        assert!(is_cancelled(&timeline, 1).unwrap());
+        assert!(!is_cancelled(&timeline, 2).unwrap());
+
+        let mut frames = vec![];
+        let mut cursor = timeline.scan();
+        while cursor.advance().unwrap() {
+            let frame = cursor.read_all().unwrap();
+            let frame = TimelineFrame::decode(&frame).unwrap();
+            frames.push((frame.kind, frame.mutation.to_vec()));
+        }
+        assert_eq!(
+            frames,
+            [
+                (FrameKind::Mutation, b"a".to_vec()),
+                (FrameKind::Cancelled, vec![]),
+                (FrameKind::Mutation, b"c".to_vec()),
+            ]
+        );
+
+        // a mutation which happens to hold the bytes of a cancelled frame is
+        // still a mutation
+        let lookalike = super::cancelled_frame().to_vec().unwrap();
+        let bytes = raw_frame(&lookalike).to_vec().unwrap();
+        let decoded = TimelineFrame::decode(&bytes).unwrap();
+        assert_eq!(decoded.kind, FrameKind::Mutation);
+        assert_eq!(decoded.mutation, &lookalike[..]);
+    }
+
+    fn raw_frame(mutation: &[u8]) -> TimelineFrame<'_> {
+        TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: None,
+            meta: FrameMeta::default(),
+            codec: Codec::Raw,
+            mutation,
+        }
+    }
+
+    #[test]
+    fn mutations_are_never_mistaken_for_frame_headers() {
+        // a mutation which looks like a frame header (or an old magic
+        // prefix) stays a mutation
+        let header = raw_frame(b"").to_vec().unwrap();
+        let prefix = b"\0sqsidk1aaaaaaaaaaaaaaaa";
+        for mutation in [&header[..], &prefix[..], &b""[..]] {
+            let key = Some(IdempotencyKey::new([1; 16]));
+            let bytes = TimelineFrame { key, ..raw_frame(mutation) }
+                .to_vec()
+                .unwrap();
+            let decoded = TimelineFrame::decode(&bytes).unwrap();
+            assert_eq!(decoded.key, key);
+            assert_eq!(decoded.mutation, mutation);
+        }
+
+        // bytes which aren't a whole frame don't decode
+        let bytes = raw_frame(b"mutation").to_vec().unwrap();
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let mut future = bytes.clone();
+        future[0] += 1;
+        for bad in [
+            &b"mutation"[..],
+            &bytes[..bytes.len() - 1],
+            &trailing[..],
+            &future[..],
+        ] {
+            assert!(TimelineFrame::decode(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_frames_never_decode() {
+        let mutation = b"abcd".repeat(256);
+        let compressed = compress_mutation(&mutation, Some(0)).unwrap();
+        let frame = TimelineFrame {
+            kind: FrameKind::Mutation,
+            key: Some(IdempotencyKey::new([9; 16])),
+            meta: FrameMeta { timestamp_ms: Some(1700), tag: Some(7) },
+            codec: Codec::Lz4,
+            mutation: &compressed,
+        };
+        let bytes = frame.to_vec().unwrap();
+        for len in 0..bytes.len() {
+            assert!(
+                TimelineFrame::decode(&bytes[..len]).is_err(),
+                "decoded a frame truncated at {}",
+                len
+            );
+        }
+
+        // nor do compressed mutations cut short by a well formed frame
+        for len in 0..compressed.len() {
+            let truncated =
+                TimelineFrame { mutation: &compressed[..len], ..frame };
+            let bytes = truncated.to_vec().unwrap();
+            let decoded = TimelineFrame::decode(&bytes).unwrap();
+            match decoded.mutation_size() {
+                Ok(size) => assert_eq!(size, mutation.len() as u64),
+                Err(_) => assert!(
+                    len < 4
+                        || len as u64 * MAX_LZ4_RATIO < mutation.len() as u64
+                ),
+            }
+            assert!(
+                decoded.decompressed().is_err(),
+                "decompressed a mutation truncated at {}",
+                len
+            );
+        }
+    }
 }
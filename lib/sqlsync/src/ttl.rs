@@ -0,0 +1,160 @@
+//! Row expiry. A document declares which of its tables expire by inserting
+//! into __sqlsync_ttl from its reducer:
+//!
+//! ```sql
+//! INSERT OR REPLACE INTO __sqlsync_ttl (table_name, column_name)
+//! VALUES ('sessions', 'expires_at')
+//! ```
+//!
+//! where the column holds the unix time in milliseconds after which the row
+//! may be deleted. Rather than every replica deleting rows as its own clock
+//! sees fit, the coordinator periodically submits an expiry mutation (see
+//! CoordinatorDocument::expire_rows) carrying its current time. Replaying it
+//! deletes the same rows everywhere.
+//!
+//! A ttl naming a table or column which doesn't exist is skipped with a
+//! warning rather than failing the mutation, which would otherwise stop the
+//! coordinator from applying anything after it. Every replica has the same
+//! schema, so they all skip the same ttls.
+
+use rusqlite::Connection;
+
+use crate::logging;
+
+const TTL_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_ttl (
+        table_name TEXT PRIMARY KEY NOT NULL,
+        column_name TEXT NOT NULL
+    ) STRICT
+";
+
+/// server timeline mutations which start with this prefix are followed by
+/// the cutoff as a little endian i64
+const EXPIRY_MUTATION_MAGIC: &[u8; 8] = b"\0sqsttl1";
+
+pub(crate) fn run_ttl_migration(sqlite: &Connection) -> rusqlite::Result<()> {
+    sqlite.execute(TTL_TABLE_SQL, [])?;
+    Ok(())
+}
+
+/// whether any row has expired at or before cutoff_ms, so that the
+/// coordinator only submits expiry mutations which delete something
+pub(crate) fn has_expired_rows(
+    sqlite: &Connection,
+    cutoff_ms: i64,
+) -> rusqlite::Result<bool> {
+    for (table, column) in ttls(sqlite)? {
+        let sql = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE {} <= ?)",
+            quote(&table),
+            quote(&column)
+        );
+        // malformed ttls are skipped, expire_rows warns about them
+        if let Ok(true) = sqlite.query_row(&sql, [cutoff_ms], |row| row.get(0))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// the server mutation which deletes every row which expired at or before
+/// cutoff_ms
+pub(crate) fn expiry_mutation(cutoff_ms: i64) -> Vec<u8> {
+    [&EXPIRY_MUTATION_MAGIC[..], &cutoff_ms.to_le_bytes()].concat()
+}
+
+pub(crate) fn parse_expiry_mutation(mutation: &[u8]) -> Option<i64> {
+    let cutoff = mutation.strip_prefix(EXPIRY_MUTATION_MAGIC)?;
+    Some(i64::from_le_bytes(cutoff.try_into().ok()?))
+}
+
+/// delete expired rows from every table with a ttl, returning the number of
+/// rows deleted
+pub(crate) fn expire_rows(
+    tx: &Connection,
+    cutoff_ms: i64,
+) -> rusqlite::Result<usize> {
+    let mut deleted = 0;
+    for (table, column) in ttls(tx)? {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} <= ?",
+            quote(&table),
+            quote(&column)
+        );
+        // a failed statement is rolled back on its own, leaving the rest of
+        // the mutation intact
+        match tx.execute(&sql, [cutoff_ms]) {
+            Ok(n) => deleted += n,
+            Err(e) => {
+                logging::warn!("skipping ttl on {}.{}: {}", table, column, e)
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+fn ttls(sqlite: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    sqlite
+        .prepare("SELECT table_name, column_name FROM __sqlsync_ttl")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{
+        expire_rows, expiry_mutation, has_expired_rows, parse_expiry_mutation,
+    };
+
+    #[test]
+    fn expires_rows() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::run_ttl_migration(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (id INTEGER, expires_at INTEGER);
+            INSERT INTO sessions VALUES (1, 100), (2, 200), (3, NULL);
+            INSERT INTO __sqlsync_ttl VALUES ('sessions', 'expires_at');",
+        )
+        .unwrap();
+
+        let cutoff = parse_expiry_mutation(&expiry_mutation(150)).unwrap();
+        assert!(!has_expired_rows(&conn, 50).unwrap());
+        assert!(has_expired_rows(&conn, cutoff).unwrap());
+        let tx = conn.transaction().unwrap();
+        assert_eq!(expire_rows(&tx, cutoff).unwrap(), 1);
+        assert!(!has_expired_rows(&tx, cutoff).unwrap());
+        let remaining: i64 = tx
+            .query_row("SELECT count(*) FROM sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(remaining, 2);
+
+        assert_eq!(parse_expiry_mutation(b"not an expiry"), None);
+    }
+
+    #[test]
+    fn malformed_ttls_are_skipped() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::run_ttl_migration(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (id INTEGER, expires_at INTEGER);
+            INSERT INTO sessions VALUES (1, 100), (2, 200);
+            INSERT INTO __sqlsync_ttl VALUES
+                ('missing', 'expires_at'),
+                ('sessions', 'missing'),
+                ('sessions', 'expires_at');",
+        )
+        .unwrap();
+
+        assert!(has_expired_rows(&conn, 150).unwrap());
+        let tx = conn.transaction().unwrap();
+        assert_eq!(expire_rows(&tx, 150).unwrap(), 1);
+        assert!(!has_expired_rows(&tx, 150).unwrap());
+    }
+}
@@ -1,3 +1,10 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging;
+
 #[cfg(not(target_family = "wasm"))]
 pub fn unix_timestamp_milliseconds() -> i64 {
     std::time::SystemTime::now()
@@ -10,3 +17,220 @@ pub fn unix_timestamp_milliseconds() -> i64 {
 pub fn unix_timestamp_milliseconds() -> i64 {
     js_sys::Date::now() as i64
 }
+
+/// microseconds since an arbitrary point, for timing work too short for
+/// the wall clock's milliseconds. never goes backwards
+#[cfg(not(target_family = "wasm"))]
+pub fn monotonic_microseconds() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> =
+        std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_micros() as u64
+}
+
+/// Instant isn't available on wasm32-unknown-unknown, so this reads
+/// performance.now, which windows and workers both have, falling back to
+/// the wall clock
+#[cfg(target_family = "wasm")]
+pub fn monotonic_microseconds() -> u64 {
+    use js_sys::{Function, Reflect};
+
+    let now_ms = Reflect::get(&js_sys::global(), &"performance".into())
+        .ok()
+        .and_then(|performance| {
+            let now = Reflect::get(&performance, &"now".into()).ok()?;
+            Function::from(now).call0(&performance).ok()?.as_f64()
+        })
+        .unwrap_or_else(js_sys::Date::now);
+    (now_ms * 1000.0) as u64
+}
+
+/// HlcTimestamp is a hybrid logical clock timestamp. The upper 48 bits hold
+/// milliseconds since the unix epoch and the lower 16 bits a logical counter,
+/// so timestamps compare correctly as plain integers (including in SQL).
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct HlcTimestamp(u64);
+
+const COUNTER_BITS: u32 = 16;
+const COUNTER_MASK: u64 = (1 << COUNTER_BITS) - 1;
+
+impl HlcTimestamp {
+    pub fn new(physical_ms: i64, counter: u16) -> Self {
+        Self(((physical_ms.max(0) as u64) << COUNTER_BITS) | counter as u64)
+    }
+
+    pub fn from_u64(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub fn physical_ms(&self) -> i64 {
+        (self.0 >> COUNTER_BITS) as i64
+    }
+
+    pub fn counter(&self) -> u16 {
+        (self.0 & COUNTER_MASK) as u16
+    }
+
+    /// the smallest timestamp greater than this one
+    fn tick(&self) -> Self {
+        // when the counter overflows we borrow a millisecond from the future
+        Self(self.0 + 1)
+    }
+}
+
+impl Display for HlcTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.physical_ms(), self.counter())
+    }
+}
+
+/// remote timestamps more than this far ahead of our wall clock are clamped
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// HybridClock hands out monotonic timestamps which stay close to wall clock
+/// time, and which move forward whenever we observe a timestamp from another
+/// replica. Documents observe the coordinator's clock during the replication
+/// handshake, so timestamps from every replica of a document are ordered
+/// consistently to within the skew between their wall clocks.
+#[derive(Debug, Clone)]
+pub struct HybridClock {
+    last: HlcTimestamp,
+    max_skew_ms: i64,
+}
+
+impl Default for HybridClock {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CLOCK_SKEW)
+    }
+}
+
+impl HybridClock {
+    pub fn new(max_skew: Duration) -> Self {
+        Self {
+            last: HlcTimestamp::default(),
+            max_skew_ms: max_skew.as_millis() as i64,
+        }
+    }
+
+    /// the most recent timestamp issued or observed by this clock
+    pub fn last(&self) -> HlcTimestamp {
+        self.last
+    }
+
+    /// return a timestamp greater than every timestamp this clock has issued
+    /// or observed
+    pub fn now(&mut self) -> HlcTimestamp {
+        self.now_at(unix_timestamp_milliseconds())
+    }
+
+    /// merge a timestamp from another replica into this clock
+    pub fn observe(&mut self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.observe_at(remote, unix_timestamp_milliseconds())
+    }
+
+    fn now_at(&mut self, wall_ms: i64) -> HlcTimestamp {
+        self.last = if wall_ms > self.last.physical_ms() {
+            HlcTimestamp::new(wall_ms, 0)
+        } else {
+            self.last.tick()
+        };
+        self.last
+    }
+
+    fn observe_at(
+        &mut self,
+        remote: HlcTimestamp,
+        wall_ms: i64,
+    ) -> HlcTimestamp {
+        let max_ms = wall_ms + self.max_skew_ms;
+        let remote = if remote.physical_ms() > max_ms {
+            logging::warn!(
+                "clamping remote timestamp {} which is {}ms ahead of the local clock",
+                remote,
+                remote.physical_ms() - wall_ms
+            );
+            HlcTimestamp::new(max_ms, 0)
+        } else {
+            remote
+        };
+
+        self.last = if wall_ms > self.last.physical_ms()
+            && wall_ms > remote.physical_ms()
+        {
+            HlcTimestamp::new(wall_ms, 0)
+        } else {
+            self.last.max(remote).tick()
+        };
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hlc_is_monotonic() {
+        let mut clock = HybridClock::default();
+        let a = clock.now_at(100);
+        let b = clock.now_at(100);
+        let c = clock.now_at(50);
+        let d = clock.now_at(200);
+        assert_eq!(a, HlcTimestamp::new(100, 0));
+        assert_eq!(b, HlcTimestamp::new(100, 1));
+        assert_eq!(c, HlcTimestamp::new(100, 2));
+        assert_eq!(d, HlcTimestamp::new(200, 0));
+    }
+
+    #[test]
+    fn hlc_observe_moves_forward() {
+        let mut clock = HybridClock::default();
+        clock.now_at(100);
+
+        // a remote ahead of us drags the clock forward
+        let a = clock.observe_at(HlcTimestamp::new(150, 3), 100);
+        assert_eq!(a, HlcTimestamp::new(150, 4));
+        assert!(clock.now_at(100) > a);
+
+        // a remote behind us still advances the counter
+        let b = clock.observe_at(HlcTimestamp::new(10, 0), 100);
+        assert!(b > a);
+
+        // once the wall clock catches up the counter resets
+        assert_eq!(
+            clock.observe_at(HlcTimestamp::new(10, 0), 500),
+            HlcTimestamp::new(500, 0)
+        );
+    }
+
+    #[test]
+    fn hlc_bounds_skew() {
+        let mut clock = HybridClock::new(Duration::from_millis(1000));
+        let ts = clock.observe_at(HlcTimestamp::new(1_000_000, 0), 100);
+        assert_eq!(ts, HlcTimestamp::new(1100, 1));
+    }
+
+    #[test]
+    fn hlc_counter_overflow() {
+        let ts = HlcTimestamp::new(7, u16::MAX).tick();
+        assert_eq!(ts, HlcTimestamp::new(8, 0));
+    }
+}
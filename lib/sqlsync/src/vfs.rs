@@ -1,21 +1,155 @@
-use libsqlite3_sys::SQLITE_IOERR;
-use log::{debug, trace};
-use sqlite_vfs::{File, FilePtr, OpenKind, Vfs, VfsResult};
+use std::{cell::RefCell, io, rc::Rc};
 
-use crate::{journal::Journal, storage::Storage, unixtime::unix_timestamp_milliseconds};
+use crate::logging::{debug, trace, warn};
+use libsqlite3_sys::{
+    SQLITE_CANTOPEN, SQLITE_IOERR, SQLITE_IOERR_SHMLOCK, SQLITE_IOERR_SHMMAP,
+    SQLITE_IOERR_SHORT_READ,
+};
+use sqlite_vfs::{File, FilePtr, OpenKind, ShmLock, Vfs, VfsResult};
+
+use crate::{
+    journal::Journal,
+    storage::Storage,
+    unixtime::unix_timestamp_milliseconds,
+    wal_index::{ShmHandle, WalIndex},
+};
 
 pub struct StorageVfs<J: Journal> {
     storage: FilePtr<Storage<J>>,
+    // in WAL mode every connection to storage shares one WAL and wal-index
+    wal: Option<Rc<RefCell<TempFile>>>,
+    wal_index: Rc<RefCell<WalIndex>>,
+    next_handle: ShmHandle,
 }
 
 impl<J: Journal> StorageVfs<J> {
     pub fn new(storage: FilePtr<Storage<J>>) -> Self {
-        Self { storage }
+        Self { storage, wal: None, wal_index: Rc::default(), next_handle: 0 }
+    }
+}
+
+pub enum VfsFile<J: Journal> {
+    Storage {
+        storage: FilePtr<Storage<J>>,
+        wal_index: Rc<RefCell<WalIndex>>,
+        handle: ShmHandle,
+    },
+    Wal {
+        storage: FilePtr<Storage<J>>,
+        wal: Rc<RefCell<TempFile>>,
+    },
+    Temp(TempFile),
+}
+
+impl<J: Journal> File for VfsFile<J> {
+    fn sector_size(&self) -> usize {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.sector_size(),
+            VfsFile::Wal { wal: f, .. } => f.borrow().sector_size(),
+            VfsFile::Temp(f) => f.sector_size(),
+        }
+    }
+
+    fn device_characteristics(&self) -> i32 {
+        match self {
+            VfsFile::Storage { storage, .. } => {
+                storage.device_characteristics()
+            }
+            VfsFile::Wal { wal: f, .. } => f.borrow().device_characteristics(),
+            VfsFile::Temp(f) => f.device_characteristics(),
+        }
+    }
+
+    fn file_size(&self) -> VfsResult<u64> {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.file_size(),
+            VfsFile::Wal { wal: f, .. } => f.borrow().file_size(),
+            VfsFile::Temp(f) => f.file_size(),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> VfsResult<()> {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.truncate(size),
+            VfsFile::Wal { wal: f, .. } => f.borrow_mut().truncate(size),
+            VfsFile::Temp(f) => f.truncate(size),
+        }
+    }
+
+    fn write(&mut self, pos: u64, buf: &[u8]) -> VfsResult<usize> {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.write(pos, buf),
+            VfsFile::Wal { storage, wal } => {
+                let mut wal = wal.borrow_mut();
+                wal.reserve(
+                    pos + buf.len() as u64,
+                    storage.wal_memory_limit(),
+                )?;
+                wal.write(pos, buf)
+            }
+            VfsFile::Temp(f) => f.write(pos, buf),
+        }
+    }
+
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.read(pos, buf),
+            VfsFile::Wal { wal: f, .. } => f.borrow_mut().read(pos, buf),
+            VfsFile::Temp(f) => f.read(pos, buf),
+        }
+    }
+
+    fn sync(&mut self) -> VfsResult<()> {
+        match self {
+            VfsFile::Storage { storage, .. } => storage.sync(),
+            VfsFile::Wal { wal: f, .. } => f.borrow_mut().sync(),
+            VfsFile::Temp(f) => f.sync(),
+        }
+    }
+
+    fn shm_map(
+        &mut self,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<*mut u8>> {
+        match self {
+            VfsFile::Storage { wal_index, handle, .. } => {
+                wal_index.borrow_mut().map(*handle, region, size, extend)
+            }
+            _ => Err(SQLITE_IOERR_SHMMAP),
+        }
+    }
+
+    fn shm_lock(
+        &mut self,
+        offset: usize,
+        n: usize,
+        lock: ShmLock,
+    ) -> VfsResult<()> {
+        match self {
+            VfsFile::Storage { storage, wal_index, handle } => {
+                let mut wal_index = wal_index.borrow_mut();
+                if matches!(lock, ShmLock::LockShared | ShmLock::LockExclusive)
+                {
+                    wal_index.observe(storage.file_change_counter());
+                }
+                wal_index.lock(*handle, offset, n, lock)
+            }
+            _ => Err(SQLITE_IOERR_SHMLOCK),
+        }
+    }
+
+    fn shm_unmap(&mut self, delete: bool) -> VfsResult<()> {
+        if let VfsFile::Storage { wal_index, handle, .. } = self {
+            wal_index.borrow_mut().unmap(*handle, delete);
+        }
+        Ok(())
     }
 }
 
 impl<J: Journal> Vfs for StorageVfs<J> {
-    type File = FilePtr<Storage<J>>;
+    type File = VfsFile<J>;
 
     fn open(
         &mut self,
@@ -24,17 +158,57 @@ impl<J: Journal> Vfs for StorageVfs<J> {
     ) -> VfsResult<Self::File> {
         let path = path.to_str().map_err(|_err| SQLITE_IOERR)?;
         debug!("open {} {:?}", path, opts);
-        assert!(
-            opts.kind == OpenKind::MainDb,
-            "only main.db is supported, got {:?}",
-            opts
-        );
-        Ok(self.storage.clone())
+        match opts.kind {
+            OpenKind::MainDb => {
+                self.next_handle += 1;
+                Ok(VfsFile::Storage {
+                    storage: self.storage.clone(),
+                    wal_index: self.wal_index.clone(),
+                    handle: self.next_handle,
+                })
+            }
+
+            OpenKind::Wal => {
+                // the WAL starts out in memory, see TempFile::reserve
+                let wal = self.wal.get_or_insert_with(|| {
+                    Rc::new(RefCell::new(TempFile::Memory(Vec::new())))
+                });
+                Ok(VfsFile::Wal {
+                    storage: self.storage.clone(),
+                    wal: wal.clone(),
+                })
+            }
+
+            // sorter spill, temp tables, statement and vacuum journals
+            OpenKind::TempDb
+            | OpenKind::TempJournal
+            | OpenKind::TransientDb
+            | OpenKind::SubJournal => Ok(VfsFile::Temp(TempFile::new())),
+
+            // the main journal is kept in memory by sqlite (journal_mode =
+            // memory, see db::journal_mode_allowed)
+            kind => {
+                warn!("refusing to open {:?}: {}", kind, path);
+                Err(SQLITE_CANTOPEN)
+            }
+        }
     }
 
     fn delete(&mut self, path: &std::ffi::CStr) -> VfsResult<()> {
         let path = path.to_str().map_err(|_err| SQLITE_IOERR)?;
         debug!("delete {}", path);
+        if path.ends_with("-wal") {
+            // sqlite deletes the WAL when it thinks it's closing the last
+            // connection, which it can't tell without file locks. so keep it
+            // while another connection has it open
+            if self
+                .wal
+                .as_ref()
+                .is_some_and(|wal| Rc::strong_count(wal) == 1)
+            {
+                self.wal = None;
+            }
+        }
         Ok(())
     }
 
@@ -43,6 +217,7 @@ impl<J: Journal> Vfs for StorageVfs<J> {
         trace!("exists {}", path);
         Ok(match path {
             "main.db" => self.storage.file_size().unwrap_or(0) > 0,
+            "main.db-wal" => self.wal.is_some(),
             _ => false,
         })
     }
@@ -59,3 +234,361 @@ impl<J: Journal> Vfs for StorageVfs<J> {
         ((2440587.5 + now / 864.0e5) * 864.0e5) as i64
     }
 }
+
+/// SpillFile is somewhere outside of memory for a temp file to move to, on
+/// targets without a temp dir. the browser worker backs them with OPFS sync
+/// access handles
+pub trait SpillFile {
+    fn size(&self) -> io::Result<u64>;
+    fn set_size(&mut self, size: u64) -> io::Result<()>;
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<()>;
+    /// returns how many bytes were read, fewer than buf at the end of the
+    /// file
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// natively documents move between threads, and their temp files with them
+#[cfg(not(target_arch = "wasm32"))]
+pub type BoxedSpillFile = Box<dyn SpillFile + Send>;
+#[cfg(target_arch = "wasm32")]
+pub type BoxedSpillFile = Box<dyn SpillFile>;
+
+type OpenSpillFile = Box<dyn FnMut() -> Option<BoxedSpillFile>>;
+
+thread_local! {
+    static SPILL_FILES: RefCell<Option<OpenSpillFile>> =
+        const { RefCell::new(None) };
+}
+
+/// set where temp files move to once they outgrow their memory limit, see
+/// TempFile::reserve. open returns None when it has no file to give, in which
+/// case the write which needed one fails with SQLITE_FULL. natively temp
+/// files move to the platform temp dir instead, and open is only called if
+/// that fails
+pub fn set_spill_files(open: impl FnMut() -> Option<BoxedSpillFile> + 'static) {
+    SPILL_FILES.with(|files| *files.borrow_mut() = Some(Box::new(open)));
+}
+
+fn open_spill_file() -> Option<TempFile> {
+    SPILL_FILES.with(|files| {
+        let file = files.borrow_mut().as_mut().and_then(|open| open())?;
+        Some(TempFile::Spilled(file))
+    })
+}
+
+/// TempFile holds one of sqlite's temporary files for as long as sqlite keeps
+/// it open. natively they live in the platform temp dir so that large sorts
+/// aren't bound by memory, in the browser they are kept in memory. the WAL
+/// and pending pages move out of memory past a limit, see reserve.
+pub enum TempFile {
+    Memory(Vec<u8>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Disk {
+        file: std::fs::File,
+        path: std::path::PathBuf,
+    },
+    Spilled(BoxedSpillFile),
+}
+
+impl TempFile {
+    #[cfg(target_arch = "wasm32")]
+    fn new() -> Self {
+        TempFile::Memory(Vec::new())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn new() -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("sqlsync-{:016x}.tmp", rand::random::<u64>()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        match file {
+            Ok(file) => TempFile::Disk { file, path },
+            Err(err) => {
+                warn!(
+                    "failed to create temp file {}, keeping it in memory: {}",
+                    path.display(),
+                    err
+                );
+                TempFile::Memory(Vec::new())
+            }
+        }
+    }
+}
+
+impl TempFile {
+    /// make room for the file to grow to end bytes. a file held in memory
+    /// moves to disk rather than growing past limit bytes; in the browser
+    /// it moves to a spill file (see set_spill_files), and if there's none
+    /// the write fails with SQLITE_FULL instead of running out of memory
+    pub(crate) fn reserve(&mut self, end: u64, limit: usize) -> VfsResult<()> {
+        if matches!(self, TempFile::Memory(_)) && end > limit as u64 {
+            self.spill(end, limit)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn spill(&mut self, end: u64, limit: usize) -> VfsResult<()> {
+        match open_spill_file() {
+            Some(file) => self.move_to(file),
+            None => {
+                warn!(
+                    "temp file would grow to {} bytes, past {}, and there is no spill file to move it to",
+                    end, limit
+                );
+                Err(libsqlite3_sys::SQLITE_FULL)
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spill(&mut self, _end: u64, _limit: usize) -> VfsResult<()> {
+        // if the file can't be created TempFile::new has already warned,
+        // and the file stays in memory unless there's a spill file
+        match TempFile::new() {
+            disk @ TempFile::Disk { .. } => self.move_to(disk),
+            _ => match open_spill_file() {
+                Some(file) => self.move_to(file),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// move the contents of a file held in memory into file
+    fn move_to(&mut self, mut file: TempFile) -> VfsResult<()> {
+        let TempFile::Memory(data) = &*self else {
+            return Ok(());
+        };
+        // spill files may be reused, so may not be empty
+        file.truncate(0)?;
+        file.write(0, data)?;
+        debug!("moved a {} byte temp file out of memory", data.len());
+        *self = file;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let TempFile::Disk { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn io_err(err: std::io::Error) -> i32 {
+    warn!("temp file error: {}", err);
+    SQLITE_IOERR
+}
+
+impl File for TempFile {
+    fn file_size(&self) -> VfsResult<u64> {
+        match self {
+            TempFile::Memory(data) => Ok(data.len() as u64),
+            #[cfg(not(target_arch = "wasm32"))]
+            TempFile::Disk { file, .. } => {
+                Ok(file.metadata().map_err(io_err)?.len())
+            }
+            TempFile::Spilled(file) => file.size().map_err(io_err),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> VfsResult<()> {
+        match self {
+            TempFile::Memory(data) => data.truncate(size as usize),
+            #[cfg(not(target_arch = "wasm32"))]
+            TempFile::Disk { file, .. } => {
+                file.set_len(size).map_err(io_err)?
+            }
+            TempFile::Spilled(file) => file.set_size(size).map_err(io_err)?,
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, pos: u64, buf: &[u8]) -> VfsResult<usize> {
+        match self {
+            TempFile::Memory(data) => {
+                let end = pos as usize + buf.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[pos as usize..end].copy_from_slice(buf);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            TempFile::Disk { file, .. } => {
+                use std::io::{Seek, SeekFrom, Write};
+                file.seek(SeekFrom::Start(pos)).map_err(io_err)?;
+                file.write_all(buf).map_err(io_err)?;
+            }
+            TempFile::Spilled(file) => {
+                file.write_at(pos, buf).map_err(io_err)?
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let n = match self {
+            TempFile::Memory(data) => {
+                let start = (pos as usize).min(data.len());
+                let end = (start + buf.len()).min(data.len());
+                buf[..end - start].copy_from_slice(&data[start..end]);
+                end - start
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            TempFile::Disk { file, .. } => {
+                use std::io::{Read, Seek, SeekFrom};
+                file.seek(SeekFrom::Start(pos)).map_err(io_err)?;
+                let mut n = 0;
+                while n < buf.len() {
+                    match file.read(&mut buf[n..]) {
+                        Ok(0) => break,
+                        Ok(read) => n += read,
+                        Err(e)
+                            if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(io_err(e)),
+                    }
+                }
+                n
+            }
+            TempFile::Spilled(file) => {
+                file.read_at(pos, buf).map_err(io_err)?
+            }
+        };
+
+        // sqlite expects reads past the end of the file to be zero filled
+        if n < buf.len() {
+            buf[n..].fill(0);
+            return Err(SQLITE_IOERR_SHORT_READ);
+        }
+        Ok(n)
+    }
+
+    fn sync(&mut self) -> VfsResult<()> {
+        // temp files don't need to survive a crash
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use libsqlite3_sys::SQLITE_IOERR_SHORT_READ;
+    use rusqlite::Connection;
+    use sqlite_vfs::File;
+
+    use super::{
+        open_spill_file, set_spill_files, BoxedSpillFile, SpillFile, TempFile,
+    };
+    use crate::{db::open_with_vfs, JournalId, MemoryJournal};
+
+    struct SharedSpillFile(Rc<RefCell<Vec<u8>>>);
+
+    impl SpillFile for SharedSpillFile {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.borrow().len() as u64)
+        }
+
+        fn set_size(&mut self, size: u64) -> io::Result<()> {
+            self.0.borrow_mut().resize(size as usize, 0);
+            Ok(())
+        }
+
+        fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<()> {
+            let mut data = self.0.borrow_mut();
+            let end = pos as usize + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[pos as usize..end].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.0.borrow();
+            let start = (pos as usize).min(data.len());
+            let end = (start + buf.len()).min(data.len());
+            buf[..end - start].copy_from_slice(&data[start..end]);
+            Ok(end - start)
+        }
+    }
+
+    fn big_sort(conn: &Connection) -> rusqlite::Result<i64> {
+        conn.query_row(
+            "with recursive n(x) as (select 1 union all select x + 1 from n where x < 200000)
+            select sum(x) from (select x from n order by randomblob(x % 64 + 16))",
+            [],
+            |r| r.get(0),
+        )
+    }
+
+    #[test]
+    fn sorts_spill_to_temp_files() {
+        let journal =
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))
+                .unwrap();
+        let (sqlite, _storage) = open_with_vfs(journal).unwrap();
+
+        // a tiny cache forces the sorter out of memory and into temp files
+        sqlite
+            .readwrite
+            .pragma_update(None, "temp_store", "file")
+            .unwrap();
+        sqlite
+            .readwrite
+            .pragma_update(None, "cache_size", 8)
+            .unwrap();
+
+        assert_eq!(big_sort(&sqlite.readwrite).unwrap(), 200000 * 200001 / 2);
+        // the readonly connection spills with the default cache size
+        assert_eq!(big_sort(&sqlite.readonly).unwrap(), 200000 * 200001 / 2);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn memory_files_spill_past_their_limit() {
+        let mut file = TempFile::Memory(Vec::new());
+        file.reserve(8, 16).unwrap();
+        file.write(0, b"spilled!").unwrap();
+        assert!(matches!(file, TempFile::Memory(_)));
+
+        file.reserve(24, 16).unwrap();
+        assert!(matches!(file, TempFile::Disk { .. }));
+        let mut buf = [0; 8];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"spilled!");
+    }
+
+    #[test]
+    fn memory_files_move_to_spill_files() {
+        assert!(open_spill_file().is_none());
+
+        // spill files may be handed out again, so may hold stale data
+        let spilled = Rc::new(RefCell::new(b"stale data".to_vec()));
+        let opened = spilled.clone();
+        set_spill_files(move || {
+            Some(Box::new(SharedSpillFile(opened.clone())) as BoxedSpillFile)
+        });
+
+        let mut file = TempFile::Memory(b"spilled!".to_vec());
+        file.move_to(open_spill_file().unwrap()).unwrap();
+        assert!(matches!(file, TempFile::Spilled(_)));
+        assert_eq!(&*spilled.borrow(), b"spilled!");
+
+        file.write(8, b"more").unwrap();
+        assert_eq!(file.file_size().unwrap(), 12);
+        let mut buf = [0; 12];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"spilled!more");
+
+        // reads past the end are zero filled
+        assert_eq!(file.read(8, &mut buf), Err(SQLITE_IOERR_SHORT_READ));
+        assert_eq!(&buf, b"more\0\0\0\0\0\0\0\0");
+    }
+}
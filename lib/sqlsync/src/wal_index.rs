@@ -0,0 +1,235 @@
+//! In WAL mode sqlite coordinates its connections through the wal-index, a
+//! shared memory file which every connection maps, plus a set of locks
+//! within it. A document's connections all live in one process and share one
+//! Storage, so its wal-index is held in memory and shared by every handle
+//! the vfs opens on the document's main database.
+
+use std::collections::BTreeSet;
+
+use libsqlite3_sys::{
+    SQLITE_BUSY, SQLITE_IOERR, SQLITE_IOERR_SHMMAP, SQLITE_SHM_NLOCK,
+};
+use sqlite_vfs::{ShmLock, VfsResult};
+
+use crate::logging::warn;
+
+/// one of the handles sqlite has opened on a document's main database. each
+/// holds its own locks
+pub type ShmHandle = u64;
+
+// sqlite keeps two copies of the wal-index header at the start of the first
+// region, see WalIndexHdr in sqlite's wal.c. fields are in native byte order
+const HEADER_SIZE: usize = 48;
+const CHANGE_OFFSET: usize = 8;
+const IS_INIT_OFFSET: usize = 12;
+const MAX_FRAME_OFFSET: usize = 16;
+const NUM_PAGES_OFFSET: usize = 20;
+const CHECKSUM_OFFSET: usize = 40;
+
+#[derive(Debug, Default)]
+struct Slot {
+    shared: BTreeSet<ShmHandle>,
+    exclusive: Option<ShmHandle>,
+}
+
+#[derive(Debug, Default)]
+pub struct WalIndex {
+    regions: Vec<Box<[u8]>>,
+    slots: [Slot; SQLITE_SHM_NLOCK as usize],
+    mapped: BTreeSet<ShmHandle>,
+    // the storage change counter as of the last lock, see observe
+    seen_change_counter: Option<u32>,
+}
+
+impl WalIndex {
+    pub fn map(
+        &mut self,
+        handle: ShmHandle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<*mut u8>> {
+        self.mapped.insert(handle);
+        while self.regions.len() <= region {
+            if !extend {
+                return Ok(None);
+            }
+            self.regions.push(vec![0; size].into_boxed_slice());
+        }
+        let region = &mut self.regions[region];
+        if region.len() != size {
+            warn!("wal-index region is {} bytes, not {}", region.len(), size);
+            return Err(SQLITE_IOERR_SHMMAP);
+        }
+        Ok(Some(region.as_mut_ptr()))
+    }
+
+    /// take or release locks on slots offset..offset+n. taking a lock either
+    /// takes it on every slot or fails with SQLITE_BUSY. slots past the
+    /// SQLITE_SHM_NLOCK sqlite has fail with SQLITE_IOERR
+    pub fn lock(
+        &mut self,
+        handle: ShmHandle,
+        offset: usize,
+        n: usize,
+        lock: ShmLock,
+    ) -> VfsResult<()> {
+        let slots = offset
+            .checked_add(n)
+            .and_then(|end| self.slots.get_mut(offset..end));
+        let Some(slots) = slots else {
+            warn!("wal-index lock on slots {}+{} is out of range", offset, n);
+            return Err(SQLITE_IOERR);
+        };
+        match lock {
+            ShmLock::LockShared => {
+                if slots
+                    .iter()
+                    .any(|s| s.exclusive.is_some_and(|h| h != handle))
+                {
+                    return Err(SQLITE_BUSY);
+                }
+                for slot in slots {
+                    slot.shared.insert(handle);
+                }
+            }
+            ShmLock::LockExclusive => {
+                let held_elsewhere = |s: &Slot| {
+                    s.exclusive.is_some_and(|h| h != handle)
+                        || s.shared.iter().any(|&h| h != handle)
+                };
+                if slots.iter().any(held_elsewhere) {
+                    return Err(SQLITE_BUSY);
+                }
+                for slot in slots {
+                    slot.exclusive = Some(handle);
+                }
+            }
+            ShmLock::UnlockShared => {
+                for slot in slots {
+                    slot.shared.remove(&handle);
+                }
+            }
+            ShmLock::UnlockExclusive => {
+                for slot in slots {
+                    if slot.exclusive == Some(handle) {
+                        slot.exclusive = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// release everything handle holds. the wal-index is dropped once no
+    /// handle has it mapped, if sqlite asks for it to be deleted
+    pub fn unmap(&mut self, handle: ShmHandle, delete: bool) {
+        for slot in &mut self.slots {
+            slot.shared.remove(&handle);
+            if slot.exclusive == Some(handle) {
+                slot.exclusive = None;
+            }
+        }
+        self.mapped.remove(&handle);
+        if delete && self.mapped.is_empty() {
+            self.regions.clear();
+        }
+    }
+
+    /// sqlite only drops a connection's page cache in WAL mode when the
+    /// wal-index header changes. so whenever storage changes underneath
+    /// sqlite (which it tracks with its change counter), the header is
+    /// changed too. called before any lock is taken, which is how every
+    /// transaction starts
+    pub fn observe(&mut self, change_counter: u32) {
+        let seen = self.seen_change_counter.replace(change_counter);
+        if seen.is_some_and(|seen| seen != change_counter) {
+            self.invalidate();
+        }
+    }
+
+    fn invalidate(&mut self) {
+        let Some(region) = self.regions.first_mut() else {
+            return;
+        };
+        let mut header: [u8; HEADER_SIZE] =
+            region[..HEADER_SIZE].try_into().unwrap();
+        if header[IS_INIT_OFFSET] == 0 {
+            // sqlite rebuilds an uninitialized header anyway
+            return;
+        }
+        let get = |header: &[u8; HEADER_SIZE], at: usize| {
+            u32::from_ne_bytes(header[at..at + 4].try_into().unwrap())
+        };
+        let set = |header: &mut [u8; HEADER_SIZE], at: usize, v: u32| {
+            header[at..at + 4].copy_from_slice(&v.to_ne_bytes())
+        };
+
+        set(
+            &mut header,
+            CHANGE_OFFSET,
+            get(&header, CHANGE_OFFSET).wrapping_add(1),
+        );
+        if get(&header, MAX_FRAME_OFFSET) == 0 {
+            // every frame has been checkpointed and the database's size in
+            // the header may be stale, zero makes sqlite ask storage instead
+            set(&mut header, NUM_PAGES_OFFSET, 0);
+        } else {
+            warn!("storage changed while the WAL holds frames");
+        }
+
+        // the header is checksummed in native byte order
+        let (mut s1, mut s2) = (0u32, 0u32);
+        for at in (0..CHECKSUM_OFFSET).step_by(8) {
+            s1 = s1.wrapping_add(get(&header, at)).wrapping_add(s2);
+            s2 = s2.wrapping_add(get(&header, at + 4)).wrapping_add(s1);
+        }
+        set(&mut header, CHECKSUM_OFFSET, s1);
+        set(&mut header, CHECKSUM_OFFSET + 4, s2);
+
+        // sqlite writes the second copy first, so readers which see the
+        // first copy change know the second is complete
+        region[HEADER_SIZE..2 * HEADER_SIZE].copy_from_slice(&header);
+        region[..HEADER_SIZE].copy_from_slice(&header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsqlite3_sys::{SQLITE_BUSY, SQLITE_IOERR, SQLITE_SHM_NLOCK};
+    use sqlite_vfs::ShmLock;
+
+    use super::WalIndex;
+
+    #[test]
+    fn locks_conflict_between_handles() {
+        let mut index = WalIndex::default();
+        index.lock(1, 3, 1, ShmLock::LockShared).unwrap();
+        index.lock(2, 3, 1, ShmLock::LockShared).unwrap();
+        assert_eq!(
+            index.lock(2, 3, 1, ShmLock::LockExclusive),
+            Err(SQLITE_BUSY)
+        );
+
+        index.lock(1, 3, 1, ShmLock::UnlockShared).unwrap();
+        index.lock(2, 3, 1, ShmLock::LockExclusive).unwrap();
+        assert_eq!(index.lock(1, 0, 8, ShmLock::LockShared), Err(SQLITE_BUSY));
+        // a failed lock takes nothing
+        index.lock(2, 0, 1, ShmLock::LockExclusive).unwrap();
+
+        index.unmap(2, false);
+        index.lock(1, 3, 1, ShmLock::LockShared).unwrap();
+    }
+
+    #[test]
+    fn locks_out_of_range_are_io_errors() {
+        let mut index = WalIndex::default();
+        let nlock = SQLITE_SHM_NLOCK as usize;
+        index.lock(1, 0, nlock, ShmLock::LockShared).unwrap();
+        for (offset, n) in [(nlock, 1), (nlock - 1, 2), (1, usize::MAX)] {
+            for lock in [ShmLock::LockShared, ShmLock::UnlockExclusive] {
+                assert_eq!(index.lock(1, offset, n, lock), Err(SQLITE_IOERR));
+            }
+        }
+    }
+}
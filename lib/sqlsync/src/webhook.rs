@@ -0,0 +1,131 @@
+//! Webhooks let serverless backends react to document changes without
+//! holding a sync connection. The coordinator tells a Webhooks whenever its
+//! storage journal advances, and Webhooks decides when each configured url
+//! should be called. Like Heartbeat, it doesn't do any io or own a timer; all
+//! times are unix milliseconds.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{JournalId, Lsn};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// urls to POST a WebhookEvent to when the document changes
+    pub urls: Vec<String>,
+    /// how long to wait after a change before calling the webhooks; any
+    /// changes made while waiting are folded into the same call
+    pub debounce: Duration,
+}
+
+/// WebhookEvent is the body sent to every webhook url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEvent {
+    pub doc_id: JournalId,
+    /// the last lsn in the document's storage journal
+    pub lsn: Lsn,
+}
+
+impl WebhookEvent {
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"doc_id":"{}","lsn":{}}}"#,
+            self.doc_id.to_base58(),
+            self.lsn
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Webhooks {
+    doc_id: JournalId,
+    config: WebhookConfig,
+    /// the last lsn webhooks were called with
+    notified_lsn: Option<Lsn>,
+    /// the lsn and deadline of the call we are waiting to make
+    pending: Option<(Lsn, i64)>,
+}
+
+impl Webhooks {
+    pub fn new(doc_id: JournalId, config: WebhookConfig) -> Self {
+        Self { doc_id, config, notified_lsn: None, pending: None }
+    }
+
+    pub fn config(&self) -> &WebhookConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: WebhookConfig) {
+        if config.urls.is_empty() {
+            self.pending = None;
+        }
+        self.config = config;
+    }
+
+    /// record that storage now ends at lsn
+    pub fn storage_advanced(&mut self, lsn: Lsn, now_ms: i64) {
+        if self.config.urls.is_empty()
+            || matches!(self.notified_lsn, Some(n) if n >= lsn)
+        {
+            return;
+        }
+        let deadline = match self.pending {
+            Some((_, deadline)) => deadline,
+            None => now_ms + self.config.debounce.as_millis() as i64,
+        };
+        self.pending = Some((lsn, deadline));
+    }
+
+    /// when poll should next be called, if a call is pending
+    pub fn deadline(&self) -> Option<i64> {
+        self.pending.map(|(_, deadline)| deadline)
+    }
+
+    /// returns the event to send to every configured url once the debounce
+    /// period has passed
+    pub fn poll(&mut self, now_ms: i64) -> Option<WebhookEvent> {
+        match self.pending {
+            Some((lsn, deadline)) if now_ms >= deadline => {
+                self.pending = None;
+                self.notified_lsn = Some(lsn);
+                Some(WebhookEvent { doc_id: self.doc_id, lsn })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{WebhookConfig, WebhookEvent, Webhooks};
+    use crate::JournalId;
+
+    #[test]
+    fn debounces_changes() {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut hooks = Webhooks::new(
+            doc_id,
+            WebhookConfig {
+                urls: vec!["https://example.com/hook".into()],
+                debounce: Duration::from_millis(100),
+            },
+        );
+        assert_eq!(hooks.deadline(), None);
+
+        hooks.storage_advanced(0, 0);
+        hooks.storage_advanced(1, 50);
+        assert_eq!(hooks.deadline(), Some(100));
+        assert_eq!(hooks.poll(99), None);
+        assert_eq!(hooks.poll(100), Some(WebhookEvent { doc_id, lsn: 1 }));
+        assert_eq!(hooks.poll(200), None);
+
+        // lsns we've already notified about are ignored
+        hooks.storage_advanced(1, 300);
+        assert_eq!(hooks.deadline(), None);
+        hooks.storage_advanced(2, 300);
+        assert_eq!(hooks.poll(400), Some(WebhookEvent { doc_id, lsn: 2 }));
+    }
+}